@@ -0,0 +1,40 @@
+// Parsing a 200k-row, four-column CSV (about 9MB) on pools of 1 to 8 threads, to check that
+// ingestion scales with cores. Build with the default `parallel` feature; without it every
+// thread count parses on one thread.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+use collie::csv::CsvReader;
+use collie::datagen::Rng;
+use collie::*;
+
+fn input(rows: usize) -> Vec<u8> {
+    let mut rng = Rng::new(7);
+    let mut out = String::from("id,price,name,active\n");
+    for i in 0 .. rows {
+        let name = format!("customer {}", rng.below(5000));
+        let name = if i % 10 == 0 { format!("\"{}, \"\"vip\"\"\"", name) } else { name };
+        out.push_str(&format!("{},{:.2},{},{}\n", i, rng.next_f64() * 1000.0, name, rng.below(2) == 1));
+    }
+    out.into_bytes()
+}
+
+fn bench_threads(c: &mut Criterion) {
+    let data = input(200_000);
+    let schema = Schema::from(vec![
+        ("id", Datatype::Entity), ("price", Datatype::Num), ("name", Datatype::Str), ("active", Datatype::Bool)
+    ]);
+    let mut group = c.benchmark_group("csv_parse");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for threads in [1, 2, 4, 8].iter() {
+        let reader = CsvReader::new(schema.clone()).with_chunk_size(256 * 1024).with_threads(*threads);
+        group.bench_with_input(BenchmarkId::new("threads", threads), &reader, |b, reader| {
+            b.iter(|| black_box(reader.parse(&data).unwrap().rows()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_threads);
+criterion_main!(benches);
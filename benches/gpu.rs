@@ -0,0 +1,60 @@
+// The GPU kernels (see src/gpu.rs) against the CPU ones, on Num columns of 16k to 16M rows,
+// to find the size from which offloading pays - what Gpu::with_min_rows should be set to on
+// this machine. Uploading each column is part of every GPU iteration, as it is in a query.
+// Without a GPU there's nothing to compare, and the benchmark says so and stops.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+use collie::datagen::Rng;
+use collie::gpu::{self, Gpu};
+use collie::*;
+
+fn bench_crossover(c: &mut Criterion) {
+    let gpu = match Gpu::new() {
+        Some(gpu) => gpu.with_min_rows(0),
+        None => {
+            eprintln!("gpu: no adapter found, nothing to compare");
+            return;
+        }
+    };
+    let mut rng = Rng::new(7);
+    let mut group = c.benchmark_group(format!("gpu ({})", gpu.name()));
+    group.sample_size(10);
+    for rows in [1 << 14, 1 << 16, 1 << 18, 1 << 20, 1 << 22, 1 << 24] {
+        let col = Column::from((0 .. rows).map(|_| rng.below(1000) as f64).collect::<Vec<f64>>());
+        assert_eq!(gpu.filter_eq(&col, Scalar::Num(7.0)).unwrap(), col.filter(Scalar::Num(7.0)).unwrap());
+        group.throughput(Throughput::Elements(rows as u64));
+
+        group.bench_with_input(BenchmarkId::new("filter_eq/cpu", rows), &col, |b, col| {
+            b.iter(|| black_box(col.filter(Scalar::Num(7.0)).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("filter_eq/gpu", rows), &col, |b, col| {
+            b.iter(|| black_box(gpu.filter_eq(col, Scalar::Num(7.0)).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("filter_range/cpu", rows), &col, |b, col| {
+            b.iter(|| black_box(col.filter_range(Scalar::Num(100.0), Scalar::Num(200.0)).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("filter_range/gpu", rows), &col, |b, col| {
+            b.iter(|| black_box(gpu.filter_range(col, Scalar::Num(100.0), Scalar::Num(200.0)).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("max/cpu", rows), &col, |b, col| {
+            b.iter(|| black_box(compare::max(col)))
+        });
+        group.bench_with_input(BenchmarkId::new("max/gpu", rows), &col, |b, col| {
+            b.iter(|| black_box(gpu.max(col)))
+        });
+        if gpu.has_f64() {
+            group.bench_with_input(BenchmarkId::new("sum/cpu", rows), &col, |b, col| {
+                b.iter(|| black_box(gpu::sum_cpu(col).unwrap()))
+            });
+            group.bench_with_input(BenchmarkId::new("sum/gpu", rows), &col, |b, col| {
+                b.iter(|| black_box(gpu.sum(col).unwrap()))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_crossover);
+criterion_main!(benches);
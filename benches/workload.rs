@@ -0,0 +1,25 @@
+// Each workload query (see src/workload.rs) over 10k customers, ~40k orders and ~160k line
+// items. Answers are checked once up front, so a fast wrong query can't pass for a win.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+
+use collie::workload::{self, Workload};
+
+fn bench_queries(c: &mut Criterion) {
+    let w = Workload::generate(10_000, 42);
+    for outcome in workload::check(&w) {
+        if let Err(e) = outcome.result {
+            panic!("{} gives the wrong answer: {}", outcome.query, e);
+        }
+    }
+    let mut group = c.benchmark_group("workload");
+    group.sample_size(20);
+    for q in workload::queries() {
+        group.bench_function(q.name, |b| b.iter(|| black_box(q.run(&w).unwrap().rows())));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_queries);
+criterion_main!(benches);
@@ -0,0 +1,143 @@
+// Random programs over random columns, through the disassembler, the optimizer and the VM.
+// Any outcome but a panic is fine: a program that doesn't fit its columns should come back
+// as a VMError.
+//
+// Half the cases are run as generated - column indices past the end, UDF ids nobody
+// registered, ops popping an empty stack. The other half are made to fit first (see
+// `conform`), so they get past the first instruction and into the kernels.
+//
+// Every program is also put through verify::validate first. One it passes mustn't then fail
+// on its shape: pop an empty stack, load a column that isn't there, or hand an op a type the
+// checker could see it can't take (see `false_accept`).
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use collie::udf::Accumulator;
+use collie::*;
+
+#[derive(Debug, Arbitrary)]
+struct Case {
+    columns: Vec<Column>,
+    code: Vec<Op>,
+    conform: bool,
+    slots: bool,
+    window: Option<(u8, u8)>,
+    nulls: collie::nulls::NullSemantics,
+    nfc: bool
+}
+
+// Registered with every VM, as function ids 0 .. 3
+const UDFS: usize = 3;
+
+struct Count(u64);
+
+impl Accumulator for Count {
+    fn update(&mut self, _: &[Scalar]) {
+        self.0 += 1;
+    }
+
+    fn state(&self) -> Vec<Scalar> {
+        vec![Scalar::Entity(self.0)]
+    }
+
+    fn merge(&mut self, state: &[Scalar]) {
+        if let Some(Scalar::Entity(n)) = state.first() {
+            self.0 += n;
+        }
+    }
+
+    fn finish(&self) -> Scalar {
+        Scalar::Entity(self.0)
+    }
+}
+
+// Point every Col at a real column and every call at a registered function, and load
+// operands ahead of any op that would pop more than the stack holds
+fn conform(code: Vec<Op>, ncols: usize) -> Vec<Op> {
+    let mut out = Vec::with_capacity(code.len());
+    let mut depth = 0;
+    for (i, mut op) in code.into_iter().enumerate() {
+        match &mut op {
+            Op::Col(table, idx) => {
+                *table = 0;
+                *idx %= ncols.max(1);
+            },
+            Op::ColByName(name) => *name = format!("c{}", name.len() % ncols.max(1)),
+            Op::CallUdf(id, arity) | Op::CallUdaf(id, arity) => {
+                *id %= UDFS;
+                *arity %= 4;
+            },
+            Op::ScalarSubquery(code) => *code = conform(std::mem::take(code), ncols),
+            _ => {}
+        }
+        let (pops, pushes) = op.stack_effect();
+        while depth < pops {
+            out.push(match ncols {
+                0 => Op::Lit(Scalar::Num(i as f64)),
+                _ => Op::Col(0, (i + depth) % ncols)
+            });
+            depth += 1;
+        }
+        depth = depth - pops + pushes;
+        out.push(op);
+    }
+    out
+}
+
+// Whether the VM failing with `err` means validate shouldn't have passed `code`. Errors of
+// stack depth and column indices always do; type errors only where every slot's type was
+// known up front - a UDF's result, a column loaded by name, a list's items or a Min's
+// value is taken on trust - and only the ones validate checks for, not those of the data.
+fn false_accept(code: &[Op], types: &[Datatype], err: &VMError) -> bool {
+    let typed = || {
+        !types.contains(&Datatype::List) && !code.iter().any(|op| matches!(op,
+            Op::ColByName(_) | Op::Shared(_) | Op::CallUdf(..) | Op::CallUdaf(..) | Op::Field(_) |
+            Op::ScalarSubquery(_) | Op::GroupBy(_) | Op::Min | Op::Max))
+    };
+    const SHAPES: &[&str] = &[
+        "expected a scalar value", "expected a column value", "Type error: expected a boolean column",
+        "Expected a Json column", "Expected row numbers", "Can't take the ", "Can't look ", "Can't extract ",
+        "Can't choose between ", "Can't make a column of", "Can only take the top values", "Can only join on"
+    ];
+    match err {
+        VMError::StackUnderflow { .. } | VMError::ColumnIndexOutOfRange { .. } | VMError::TableIndexOutOfRange { .. } => true,
+        VMError::TypeError(msg) => typed() && SHAPES.iter().any(|s| msg.starts_with(s)),
+        _ => false
+    }
+}
+
+fuzz_target!(|case: Case| {
+    let Case { mut columns, mut code, conform: fit, slots, window, nulls, nfc } = case;
+    if fit {
+        let rows = columns.iter().map(|c| c.len()).min().unwrap_or(0);
+        columns = columns.iter().map(|c| c.slice(0, rows)).collect();
+        code = conform(code, columns.len());
+    }
+    let types: Vec<Datatype> = columns.iter().map(|c| c.datatype()).collect();
+    let checked = collie::verify::validate(&code, &types).is_ok();
+    let schema = Schema::new((0 .. columns.len()).map(|i| Field::new(&format!("c{}", i), columns[i].datatype())).collect());
+    let _ = collie::disasm::disassemble(&code, &schema);
+    let _ = collie::optimizer::optimize(code.clone());
+
+    let mode = if slots { ColumnMode::Slots } else { ColumnMode::Rc };
+    // ragged columns are turned away up front
+    let Ok(table) = Table::with_schema(schema, columns) else { return };
+    let mut vm = VM::with_column_mode(table, mode);
+    vm.set_null_semantics(nulls);
+    vm.set_nfc(nfc);
+    vm.set_window(window.map(|(offset, len)| (offset as usize, len as usize)));
+    vm.register_udf("first", |args| args.first().cloned().unwrap_or(Scalar::Bool(false)));
+    vm.register_batch_udf("same", |cols| {
+        cols.first().map(|c| (*c).clone()).ok_or_else(|| VMError::TypeError("same takes an argument".to_string()))
+    });
+    vm.register_udaf("count", || Box::new(Count(0)));
+    vm.record_trace();
+    vm.enable_profiling();
+    if let Err(err) = vm.run(code.clone()) {
+        assert!(!(checked && false_accept(&code, &types, &err)), "validate passed {:?}, which failed with {:?}", code, err);
+    }
+    let _ = vm.snapshot().to_string();
+});
@@ -0,0 +1,217 @@
+// Built-in aggregates: reductions of a whole column to one value, for the Sum, Count, Min,
+// Max and Mean opcodes. As in SQL, nulls (and NaN, in a Num column) are left out of
+// everything but Count, which counts rows like COUNT(*) as an Int, and an aggregate of no
+// numbers is NaN (null, for an Int or Duration Sum). Ints and Durations are summed exactly,
+// failing with VMError::Overflow past i64's range.
+//
+// A List column, as GroupBy makes, is reduced a list at a time instead, to a column with a
+// row per list.
+
+use crate::column::{Column, Scalar};
+use crate::compare::{self, Comparator};
+use crate::conditional;
+use crate::duration::Nanos;
+use crate::core::prelude::*;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::list::ListColumn;
+use crate::nulls::NullableColumn;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+
+use core::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Agg {
+    Sum,    // of a Num, Int or Duration column
+    Count,  // of any column
+    Min,    // of a column whose values have an order (see ordered)
+    Max,
+    Mean    // of a Num or Int column, as a Num
+}
+
+pub fn reduce(col: &Column, agg: Agg) -> Result<Scalar, VMError> {
+    if agg == Agg::Count {
+        return Ok(Scalar::Int(col.len() as i64));
+    }
+    let plain = encoding::plain(col);
+    let xs = match plain.as_ref() {
+        Column::Num(c) => c.values(),
+        Column::Int(c) if matches!(agg, Agg::Sum | Agg::Mean) => return reduce_ints(c.values(), agg),
+        Column::Duration(c) if agg == Agg::Sum => {
+            let nanos: Vec<i64> = c.data.iter().map(|d| d.0).collect();
+            return reduce_ints(&nanos, agg).map(|sum| match sum {
+                Scalar::Int(n) => Scalar::Duration(Nanos(n)),
+                other => other
+            });
+        },
+        Column::Nullable(c) => return reduce(&c.present(), agg),
+        other => return reduce_ordered(other, agg)
+    };
+    let present = || xs.iter().copied().filter(|x| !x.is_nan());
+    let n = present().count();
+    let res = match agg {
+        _ if n == 0 => f64::NAN,
+        Agg::Sum => present().sum(),
+        Agg::Mean => present().sum::<f64>() / n as f64,
+        Agg::Min => present().fold(f64::INFINITY, f64::min),
+        Agg::Max => present().fold(f64::NEG_INFINITY, f64::max),
+        Agg::Count => unreachable!("counted above")
+    };
+    Ok(Scalar::Num(res))
+}
+
+// Sum and Mean of Ints: an exact sum, and the mean of that
+fn reduce_ints(xs: &[i64], agg: Agg) -> Result<Scalar, VMError> {
+    if agg == Agg::Mean {
+        let sum: i128 = xs.iter().map(|x| *x as i128).sum();
+        return Ok(Scalar::Num(if xs.is_empty() { f64::NAN } else { sum as f64 / xs.len() as f64 }));
+    }
+    if xs.is_empty() {
+        return Ok(Scalar::Null);
+    }
+    xs.iter().try_fold(0i64, |sum, x| sum.checked_add(*x)).map(Scalar::Int).ok_or(VMError::Overflow)
+}
+
+// Whether Min and Max take `dtype` values: points, vectors, JSON documents and records sort
+// (see compare.rs), but none of them is the smallest in any useful sense
+pub fn ordered(dtype: Datatype) -> bool {
+    !matches!(dtype, Datatype::Point | Datatype::Vector | Datatype::Json | Datatype::Record)
+}
+
+// Min and Max of a column other than Num
+fn reduce_ordered(col: &Column, agg: Agg) -> Result<Scalar, VMError> {
+    let found = match agg {
+        _ if !ordered(col.datatype()) => return Err(VMError::TypeError(format!("Can't take the {:?} of a {} column", agg, col.datatype()))),
+        Agg::Min => compare::min(col),
+        Agg::Max => compare::max(col),
+        _ => return Err(VMError::TypeError(format!("Can't take the {:?} of a {} column", agg, col.datatype())))
+    };
+    found.ok_or_else(|| VMError::TypeError(format!("The {:?} of no {} values has no null to be", agg, col.datatype())))
+}
+
+// The aggregate of each list: Count is an Int column, Sum and Mean Num ones (Sum of Int
+// items an Int one), and Min and Max columns of the items' type
+pub fn reduce_lists(lists: &ListColumn, agg: Agg) -> Result<Column, VMError> {
+    if agg == Agg::Count {
+        return Ok(Column::from((0 .. lists.len()).map(|i| lists.list_len(i) as i64).collect::<Vec<i64>>()));
+    }
+    if lists.item_type() == Datatype::Int && agg == Agg::Sum {
+        // an empty list's sum is null
+        let sums = lists.iter().map(|list| match reduce(&list, agg)? {
+            Scalar::Int(x) => Ok(Some(x)),
+            _ => Ok(None)
+        }).collect::<Result<Vec<Option<i64>>, VMError>>()?;
+        let values = Column::from(sums.iter().map(|x| x.unwrap_or(0)).collect::<Vec<i64>>());
+        let nulls: Vec<u32> = (0 .. sums.len()).filter(|i| sums[*i].is_none()).map(|i| i as u32).collect();
+        return match nulls.is_empty() {
+            true => Ok(values),
+            false => Ok(Column::Nullable(NullableColumn::with_nulls(values, &Selection::from_positions(nulls, sums.len()))?))
+        };
+    }
+    if lists.item_type() == Datatype::Int && agg == Agg::Mean {
+        let xs = lists.iter().map(|list| match reduce(&list, agg)? {
+            Scalar::Num(x) => Ok(x),
+            other => unreachable!("a mean is a number, not {:?}", other)
+        });
+        return Ok(Column::from(xs.collect::<Result<Vec<f64>, VMError>>()?));
+    }
+    if lists.item_type() == Datatype::Num {
+        let xs = lists.iter().map(|list| match reduce(&list, agg)? {
+            Scalar::Num(x) => Ok(x),
+            other => unreachable!("numbers reduce to a number, not {:?}", other)
+        });
+        return Ok(Column::from(xs.collect::<Result<Vec<f64>, VMError>>()?));
+    }
+    let keep = match agg {
+        _ if !ordered(lists.item_type()) => return Err(VMError::TypeError(format!("Can't take the {:?} of lists of {} values", agg, lists.item_type()))),
+        Agg::Min => Ordering::Less,
+        Agg::Max => Ordering::Greater,
+        _ => return Err(VMError::TypeError(format!("Can't take the {:?} of lists of {} values", agg, lists.item_type())))
+    };
+    // the row of the items each list's value is at
+    let (items, offsets) = (lists.items(), lists.offsets());
+    let cmp = Comparator::new(items);
+    let rows = (0 .. lists.len()).map(|i| match offsets[i] .. offsets[i + 1] {
+        rows if rows.is_empty() => Err(VMError::TypeError(format!("The {:?} of no {} values has no null to be", agg, lists.item_type()))),
+        rows => Ok(rows.clone().fold(rows.start, |best, j| if cmp.cmp(j, best) == keep { j } else { best }))
+    });
+    conditional::take(items, &rows.collect::<Result<Vec<usize>, VMError>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoding;
+    use crate::geo::Point;
+
+    use core::convert::TryFrom;
+
+    fn num(x: Scalar) -> f64 {
+        match x {
+            Scalar::Num(x) => x,
+            x => panic!("expected a number, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn reduces_numbers_skipping_nulls() {
+        let xs = Column::from(vec![2.0, f64::NAN, -1.0, 5.0]);
+        assert_eq!(num(reduce(&xs, Agg::Sum).unwrap()), 6.0);
+        assert_eq!(num(reduce(&xs, Agg::Mean).unwrap()), 2.0);
+        assert_eq!(num(reduce(&xs, Agg::Min).unwrap()), -1.0);
+        assert_eq!(num(reduce(&xs, Agg::Max).unwrap()), 5.0);
+        assert_eq!(reduce(&xs, Agg::Count).unwrap(), Scalar::Int(4));
+        let rle = encoding::encode(Column::from(vec![3.0; 10]), Encoding::Rle).unwrap();
+        assert_eq!(num(reduce(&rle, Agg::Sum).unwrap()), 30.0);
+    }
+
+    #[test]
+    fn no_values_are_null() {
+        for xs in [Column::from(Vec::<f64>::new()), Column::from(vec![f64::NAN])] {
+            for agg in [Agg::Sum, Agg::Mean, Agg::Min, Agg::Max] {
+                assert!(num(reduce(&xs, agg).unwrap()).is_nan(), "{:?} of {:?}", agg, xs);
+            }
+        }
+        assert_eq!(reduce(&Column::from(Vec::<f64>::new()), Agg::Count).unwrap(), Scalar::Int(0));
+    }
+
+    #[test]
+    fn ints_sum_exactly() {
+        let xs = Column::from(vec![9_007_199_254_740_993i64, 2, -1]);
+        assert_eq!(reduce(&xs, Agg::Sum).unwrap(), Scalar::Int(9_007_199_254_740_994));
+        assert_eq!(num(reduce(&Column::from(vec![1i64, 2]), Agg::Mean).unwrap()), 1.5);
+        assert_eq!(reduce(&xs, Agg::Max).unwrap(), Scalar::Int(9_007_199_254_740_993));
+        assert_eq!(reduce(&Column::from(Vec::<i64>::new()), Agg::Sum).unwrap(), Scalar::Null);
+        assert!(matches!(reduce(&Column::from(vec![i64::MIN, -1]), Agg::Sum), Err(VMError::Overflow)));
+        // an empty list's sum is null
+        let lists = ListColumn::new(vec![0, 2, 2], Column::from(vec![4i64, 5])).unwrap();
+        let sums = reduce_lists(&lists, Agg::Sum).unwrap();
+        assert_eq!((sums.get(0), sums.get(1)), (Some(Scalar::Int(9)), Some(Scalar::Null)));
+    }
+
+    #[test]
+    fn min_and_max_of_other_types() {
+        let names = Column::from(vec!["bob", "al", "cy"]);
+        assert_eq!(reduce(&names, Agg::Min).unwrap(), Scalar::Str("al".to_string()));
+        assert_eq!(reduce(&names, Agg::Max).unwrap(), Scalar::Str("cy".to_string()));
+        assert_eq!(reduce(&Column::from(vec![7u64, 3]), Agg::Min).unwrap(), Scalar::Entity(3));
+        assert!(matches!(reduce(&names, Agg::Sum), Err(VMError::TypeError(_))));
+        assert!(matches!(reduce(&Column::from(vec![Point { x: 1.0, y: 2.0 }]), Agg::Min), Err(VMError::TypeError(_))));
+        assert!(matches!(reduce(&Column::from(Vec::<u64>::new()), Agg::Max), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn lists_reduce_one_at_a_time() {
+        let nums = ListColumn::new(vec![0, 2, 2, 5], Column::from(vec![1.0, 3.0, f64::NAN, 4.0, -2.0])).unwrap();
+        assert_eq!(reduce_lists(&nums, Agg::Count).unwrap(), Column::from(vec![2i64, 0, 3]));
+        assert_eq!(reduce_lists(&nums, Agg::Max).unwrap(), Column::from(vec![3.0, f64::NAN, 4.0]));
+        let sums = Vec::<f64>::try_from(&reduce_lists(&nums.slice(1, 2), Agg::Sum).unwrap()).unwrap();
+        assert!(sums[0].is_nan() && sums[1] == 2.0);
+        let names = ListColumn::new(vec![0, 2, 3], Column::from(vec!["bob", "al", "cy"])).unwrap();
+        assert_eq!(reduce_lists(&names, Agg::Min).unwrap(), Column::from(vec!["al", "cy"]));
+        assert!(matches!(reduce_lists(&names, Agg::Mean), Err(VMError::TypeError(_))));
+        let empty = ListColumn::new(vec![0, 0], Column::from(Vec::<&str>::new())).unwrap();
+        assert!(matches!(reduce_lists(&empty, Agg::Max), Err(VMError::TypeError(_))));
+    }
+}
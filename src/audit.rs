@@ -0,0 +1,135 @@
+// Auditing queries: a hook a Db calls once for every query it runs, with what was run, over
+// what, what came of it and who asked - so a server can keep the log its operators are
+// required to without wrapping every call site. Failed queries are reported too, as are
+// ones answered from the result cache.
+//
+// The hook runs on the querying thread, after the query and before its result is returned,
+// so it should be quick: hand the event off (to a channel, a buffered writer) rather than
+// doing I/O inline.
+
+use crate::errors::VMError;
+use crate::opcode::Op;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// What the caller knows about a query that collie doesn't: the text it was written as, and
+// anything else the log should carry - user, session, request id
+#[derive(Debug, Clone, Default)]
+pub struct QueryContext {
+    pub text: Option<String>,
+    pub values: BTreeMap<String, String>
+}
+
+impl QueryContext {
+    pub fn new() -> Self {
+        QueryContext::default()
+    }
+
+    // The SQL (or other source) the program was compiled from
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+
+    pub fn with_value(mut self, key: &str, value: &str) -> Self {
+        self.values.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditEvent<'a> {
+    pub program: &'a [Op],
+    pub tables: Vec<&'a str>,           // the tables it read
+    pub version: u64,                   // of the table, as of the query
+    pub rows_scanned: usize,            // rows in the tables it read
+    pub rows_returned: usize,           // 0 for a failed query
+    pub duration: Duration,
+    pub cached: bool,                   // answered from the result cache, without running
+    pub error: Option<&'a VMError>,
+    pub context: &'a QueryContext
+}
+
+pub type AuditHook = Arc<dyn Fn(&AuditEvent) + Send + Sync>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Column;
+    use crate::db::Db;
+    use crate::result::ResultSet;
+
+    use std::sync::Mutex;
+
+    // What the hook saw of each event, since events borrow from the query
+    #[derive(Debug, PartialEq)]
+    struct Seen {
+        tables: Vec<String>,
+        version: u64,
+        rows_scanned: usize,
+        rows_returned: usize,
+        cached: bool,
+        failed: bool,
+        text: Option<String>,
+        user: Option<String>
+    }
+
+    fn audited_db() -> (Db, Arc<Mutex<Vec<Seen>>>) {
+        let mut db = Db::new();
+        let mut data = ResultSet::new();
+        data.push("x", Column::from(vec![1u64, 2, 3]));
+        db.create_table("t", data).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        db.set_audit_hook(move |e| log.lock().unwrap().push(Seen {
+            tables: e.tables.iter().map(|t| t.to_string()).collect(),
+            version: e.version,
+            rows_scanned: e.rows_scanned,
+            rows_returned: e.rows_returned,
+            cached: e.cached,
+            failed: e.error.is_some(),
+            text: e.context.text.clone(),
+            user: e.context.values.get("user").cloned()
+        }));
+        (db, seen)
+    }
+
+    #[test]
+    fn contexts_carry_text_and_values() {
+        let context = QueryContext::new().with_text("SELECT x FROM t").with_value("user", "ann").with_value("user", "bo");
+        assert_eq!(context.text.as_deref(), Some("SELECT x FROM t"));
+        assert_eq!(context.values.get("user").map(|s| s.as_str()), Some("bo"));
+        assert!(QueryContext::new().text.is_none());
+    }
+
+    #[test]
+    fn every_query_is_reported() {
+        let (db, seen) = audited_db();
+        let context = QueryContext::new().with_text("SELECT x FROM t").with_value("user", "ann");
+        db.query_in("t", &[Op::Col(0, 0)], &["x"], &context).unwrap();
+        assert!(db.query("t", &[Op::Col(0, 7)], &["x"]).is_err());
+        // no table, so nothing ran
+        assert!(db.query("nobody", &[Op::Col(0, 0)], &["x"]).is_err());
+
+        let seen = seen.lock().unwrap();
+        let ok = Seen {
+            tables: vec!["t".to_string()], version: 0, rows_scanned: 3, rows_returned: 3, cached: false, failed: false,
+            text: Some("SELECT x FROM t".to_string()), user: Some("ann".to_string())
+        };
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0], ok);
+        assert_eq!(seen[1], Seen { rows_returned: 0, failed: true, text: None, user: None, ..ok });
+    }
+
+    #[test]
+    fn cache_hits_are_reported_as_cached() {
+        let (mut db, seen) = audited_db();
+        db.enable_result_cache(4);
+        db.query("t", &[Op::Col(0, 0)], &["x"]).unwrap();
+        db.query("t", &[Op::Col(0, 0)], &["x"]).unwrap();
+        let cached: Vec<bool> = seen.lock().unwrap().iter().map(|s| s.cached).collect();
+        assert_eq!(cached, vec![false, true]);
+    }
+}
@@ -0,0 +1,177 @@
+// Running a batch of related queries together, as a dashboard does: several queries that
+// start from the same filtered rows, say, each with an aggregation of their own. The common
+// parts are given as shared sub-plans - programs that each leave one value, a mask or a
+// column or a scalar - and the queries push their values with Op::Shared(id) rather than
+// computing them again. Sub-plans may use the ones shared before them, so together they
+// form a DAG; each is run once, before the queries, and only if something uses it.
+
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::vm::{Value, VM};
+
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    shared: Vec<Vec<Op>>,   // indexed by sub-plan id
+    queries: Vec<Vec<Op>>
+}
+
+// The sub-plans a program pushes with Op::Shared, its subqueries included
+fn uses(code: &[Op], used: &mut [bool]) {
+    for op in code {
+        match op {
+            Op::Shared(id) if *id < used.len() => used[*id] = true,
+            Op::ScalarSubquery(sub) => uses(sub, used),
+            _ => {}
+        }
+    }
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    // Add a sub-plan for the queries to share, under the id returned. It may push the
+    // sub-plans shared before it, but not later ones.
+    pub fn share(&mut self, code: Vec<Op>) -> usize {
+        self.shared.push(code);
+        self.shared.len() - 1
+    }
+
+    pub fn add_query(&mut self, code: Vec<Op>) -> usize {
+        self.queries.push(code);
+        self.queries.len() - 1
+    }
+
+    pub fn queries(&self) -> usize {
+        self.queries.len()
+    }
+
+    // Which sub-plans have to run: those the queries use, and those they use in turn
+    pub fn needed(&self) -> Vec<bool> {
+        let mut used = vec![false; self.shared.len()];
+        self.queries.iter().for_each(|q| uses(q, &mut used));
+        for id in (0 .. self.shared.len()).rev() {
+            if used[id] {
+                uses(&self.shared[id], &mut used);
+            }
+        }
+        used
+    }
+
+    // Run the batch on `vm`: the needed sub-plans, in order, then each query. Returns what
+    // each query left on the stack, as VM::take_stack would. A sub-plan that pushes one
+    // shared later than itself fails with VMError::UnknownShared.
+    pub fn run(&self, vm: &mut VM) -> Result<Vec<Vec<Value>>, VMError> {
+        let res = self.run_all(vm);
+        vm.set_shared(Vec::new());
+        res
+    }
+
+    fn run_all(&self, vm: &mut VM) -> Result<Vec<Vec<Value>>, VMError> {
+        let needed = self.needed();
+        let mut values: Vec<Option<Value>> = Vec::with_capacity(self.shared.len());
+        for (id, code) in self.shared.iter().enumerate() {
+            if !needed[id] {
+                values.push(None);
+                continue;
+            }
+            vm.set_shared(core::mem::take(&mut values));
+            let res = vm.run(code.clone());
+            values = vm.take_shared();
+            res?;
+            let mut stack = vm.take_stack();
+            if stack.len() != 1 {
+                return Err(VMError::TypeError(format!("Shared sub-plan {} must leave one value, left: {:?}", id, stack)));
+            }
+            values.push(stack.pop());
+        }
+        vm.set_shared(values);
+        let mut results = Vec::with_capacity(self.queries.len());
+        for code in &self.queries {
+            vm.run(code.clone())?;
+            results.push(vm.take_stack());
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::Table;
+    use crate::column::{Column, Scalar};
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use alloc::sync::Arc;
+
+    fn vm() -> VM {
+        VM::new(Table::from_columns(vec![
+            Column::from(vec!["a", "b", "a", "a"]),
+            Column::from(vec![1.0, 2.0, 3.0, 4.0]),
+            Column::from(vec![10u64, 11, 12, 13])
+        ]).unwrap())
+    }
+
+    fn columns(vm: &VM, values: &[Value]) -> Vec<Column> {
+        values.iter().map(|v| vm.column_of(v).unwrap().clone()).collect()
+    }
+
+    #[test]
+    fn shared_masks_feed_every_query() {
+        let mut vm = vm();
+        let mut batch = Batch::new();
+        let is_a = batch.share(vec![Op::Col(0, 0), Op::Lit(Scalar::Str("a".to_string())), Op::FilterEq]);
+        batch.add_query(vec![Op::Shared(is_a), Op::Col(0, 1), Op::Select(1)]);
+        batch.add_query(vec![Op::Shared(is_a), Op::Col(0, 2), Op::Select(1)]);
+        let res = batch.run(&mut vm).unwrap();
+        assert_eq!(columns(&vm, &res[0]), vec![Column::from(vec![1.0, 3.0, 4.0])]);
+        assert_eq!(columns(&vm, &res[1]), vec![Column::from(vec![10u64, 12, 13])]);
+    }
+
+    #[test]
+    fn each_sub_plan_runs_once_and_only_if_used() {
+        let mut vm = vm();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let id = vm.register_batch_udf("count", move |args| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(args[0].clone())
+        });
+        let mut batch = Batch::new();
+        // the second sub-plan builds on the first; the third is never used
+        let base = batch.share(vec![Op::Col(0, 0), Op::Lit(Scalar::Str("a".to_string())), Op::FilterEq, Op::CallUdf(id, 1)]);
+        let ids = batch.share(vec![Op::Shared(base), Op::Col(0, 2), Op::Select(1), Op::CallUdf(id, 1)]);
+        batch.share(vec![Op::Col(0, 1), Op::CallUdf(id, 1)]);
+        for _ in 0 .. 3 {
+            batch.add_query(vec![Op::Shared(ids), Op::Lit(Scalar::Entity(12)), Op::FilterEq]);
+        }
+        assert_eq!(batch.needed(), vec![true, true, false]);
+        let res = batch.run(&mut vm).unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn sub_plans_must_leave_one_value_and_come_first() {
+        let mut batch = Batch::new();
+        let two = batch.share(vec![Op::Col(0, 0), Op::Col(0, 1)]);
+        batch.add_query(vec![Op::Shared(two)]);
+        assert!(matches!(batch.run(&mut vm()), Err(VMError::TypeError(_))));
+
+        let mut batch = Batch::new();
+        let early = batch.share(vec![Op::Shared(1)]);
+        batch.share(vec![Op::Lit(Scalar::Num(1.0))]);
+        batch.add_query(vec![Op::Shared(early)]);
+        assert!(matches!(batch.run(&mut vm()), Err(VMError::UnknownShared(1))));
+
+        // nothing is left shared once the batch is done
+        let mut vm = vm();
+        let mut batch = Batch::new();
+        let one = batch.share(vec![Op::Lit(Scalar::Num(1.0))]);
+        batch.add_query(vec![Op::Shared(one)]);
+        batch.run(&mut vm).unwrap();
+        assert!(matches!(vm.run(vec![Op::Shared(one)]), Err(VMError::UnknownShared(0))));
+    }
+}
@@ -1,53 +1,163 @@
-use std::fmt;
+use core::fmt;
 
-#[derive(Debug, Clone)]
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use crate::core::prelude::*;
+
+// Bitmaps with at least this many words (~4M rows) are combined and counted on the
+// rayon thread pool; below it, the serial loop is faster than the coordination.
+#[cfg(feature = "parallel")]
+const PARALLEL_THRESHOLD: usize = 1 << 16;
+
+fn map_words<F: Fn(u64) -> u64 + Sync>(words: &[u64], f: F) -> Vec<u64> {
+    #[cfg(feature = "parallel")]
+    {
+        if words.len() >= PARALLEL_THRESHOLD {
+            return words.par_iter().map(|x| f(*x)).collect();
+        }
+    }
+    words.iter().map(|x| f(*x)).collect()
+}
+
+fn zip_words<F: Fn(u64, u64) -> u64 + Sync>(a: &[u64], b: &[u64], f: F) -> Vec<u64> {
+    #[cfg(feature = "parallel")]
+    {
+        if a.len() >= PARALLEL_THRESHOLD {
+            return a.par_iter().zip(b.par_iter()).map(|(x, y)| f(*x, *y)).collect();
+        }
+    }
+    a.iter().zip(b.iter()).map(|(x, y)| f(*x, *y)).collect()
+}
+
+#[derive(Debug, Clone, Hash)]
 pub struct BitIndex {
-    data: Vec<u64>
+    data: Vec<u64>,
+    len: usize      // number of bits in use; the tail of the last block is always zero
 }
 
 impl BitIndex {
     pub fn for_col_len(len: usize) -> Self {
-        BitIndex { data: vec![0; len / 64 + 1] }
+        BitIndex { data: vec![0; len / 64 + 1], len }
+    }
+
+    // `data` must hold exactly len / 64 + 1 words, with no bits set past `len`
+    pub fn from_words(data: Vec<u64>, len: usize) -> Self {
+        debug_assert_eq!(data.len(), len / 64 + 1);
+        BitIndex { data, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Heap bytes owned by the bitmap
+    pub fn memory_usage(&self) -> usize {
+        self.data.capacity() * core::mem::size_of::<u64>()
+    }
+
+    pub fn count_ones(&self) -> usize {
+        #[cfg(feature = "parallel")]
+        {
+            if self.data.len() >= PARALLEL_THRESHOLD {
+                return self.data.par_iter().map(|x| x.count_ones() as usize).sum();
+            }
+        }
+        self.data.iter().map(|x| x.count_ones() as usize).sum()
     }
 
-    pub fn set(&mut self, idx: usize) -> () {
+    pub fn get(&self, idx: usize) -> bool {
+        idx < self.len && self.data[idx >> 6] & (1 << (idx % 64)) != 0
+    }
+
+    pub fn set(&mut self, idx: usize) {
         let block = (idx as u64) >> 6;
         let bit = (idx as u64) % 64;
         self.data[block as usize] |= 1 << bit;
     }
 
+    // Set bits lo .. hi, a word at a time
+    pub fn set_range(&mut self, lo: usize, hi: usize) {
+        if lo >= hi {
+            return;
+        }
+        let (first, last) = (lo >> 6, (hi - 1) >> 6);
+        let head = !0u64 << (lo % 64);
+        let tail = !0u64 >> (63 - (hi - 1) % 64);
+        if first == last {
+            self.data[first] |= head & tail;
+        } else {
+            self.data[first] |= head;
+            self.data[first + 1 .. last].iter_mut().for_each(|w| *w = !0);
+            self.data[last] |= tail;
+        }
+    }
+
+    // Set the bits of `other`, shifted up by `offset` rows; for assembling a mask chunk by chunk
+    pub fn or_at(&mut self, offset: usize, other: &BitIndex) {
+        assert!(offset + other.len <= self.len, "bitmap doesn't fit at offset {}", offset);
+        let (w, s) = (offset >> 6, offset % 64);
+        for (i, x) in other.data.iter().enumerate().filter(|(_, x)| **x != 0) {
+            self.data[w + i] |= x << s;
+            if s > 0 {
+                // only non-empty when other's bits reach past the end of this word
+                if let Some(next) = self.data.get_mut(w + i + 1) {
+                    *next |= x >> (64 - s);
+                }
+            }
+        }
+    }
+
+    // Both operands must cover the same number of rows.
+    pub fn and(&self, other: &BitIndex) -> BitIndex {
+        assert_eq!(self.len, other.len, "bitmaps cover different numbers of rows");
+        BitIndex { data: zip_words(&self.data, &other.data, |x, y| x & y), len: self.len }
+    }
+
+    pub fn or(&self, other: &BitIndex) -> BitIndex {
+        assert_eq!(self.len, other.len, "bitmaps cover different numbers of rows");
+        BitIndex { data: zip_words(&self.data, &other.data, |x, y| x | y), len: self.len }
+    }
+
     pub fn inverted(&self) -> BitIndex {
-        BitIndex { data: self.data.iter().map(|x| !*x).collect() }
+        let mut data: Vec<u64> = map_words(&self.data, |x| !x);
+        // keep bits past `len` cleared, or select() would read past the end of the column
+        let last = data.len() - 1;
+        data[last] &= (1u64 << (self.len % 64)) - 1;
+        BitIndex { data, len: self.len }
     }
 
-    pub fn select<T>(&self, col: &Vec<T>) -> Vec<T> where T: Clone {
+    pub fn select<T>(&self, col: &[T]) -> Vec<T> where T: Clone {
         // Is it faster to do this (iter over set bits)
         // or iter over all indices in `col` and check bit at that index?
         let mut res = Vec::new();
         for block_idx in 0 .. self.data.len() {
             let mut block = self.data[block_idx];
             while block != 0 {
-                let mask = (block as i64) & -(block as i64);    // set all bits to 0 except lowest 1
+                let mask = block & block.wrapping_neg();    // set all bits to 0 except lowest 1
                 let tz = block.trailing_zeros();
                 let idx = block_idx * 64 + (tz as usize);
                 res.push(col[idx].clone());
-                block ^= mask as u64;
+                block ^= mask;
             }
         }
         res
     }
 
-    pub fn for_each<F>(&self, mut callback: F) -> ()
-        where F: FnMut(usize) -> () {
+    pub fn for_each<F>(&self, mut callback: F)
+        where F: FnMut(usize) {
 
         for block_idx in 0 .. self.data.len() {
             let mut block = self.data[block_idx];
             while block != 0 {
-                let mask = (block as i64) & -(block as i64);    // set all bits to 0 except lowest 1
+                let mask = block & block.wrapping_neg();    // set all bits to 0 except lowest 1
                 let tz = block.trailing_zeros();
                 let idx = block_idx * 64 + (tz as usize);
                 callback(idx);
-                block ^= mask as u64;
+                block ^= mask;
             }
         }
     }
@@ -61,4 +171,82 @@ impl fmt::Display for BitIndex {
         }
         write!(f, "]")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(b: &BitIndex) -> Vec<usize> {
+        let mut out = Vec::new();
+        b.for_each(|i| out.push(i));
+        out
+    }
+
+    #[test]
+    fn inverting_stays_within_the_length() {
+        for &len in &[0, 1, 5, 63, 64, 65, 128, 130] {
+            let mut b = BitIndex::for_col_len(len);
+            if len > 0 {
+                b.set(len - 1);
+            }
+            let inv = b.inverted();
+            assert_eq!(inv.len(), len);
+            assert_eq!(bits(&inv), (0 .. len.saturating_sub(1)).collect::<Vec<_>>(), "{} bits", len);
+            let col: Vec<usize> = (0 .. len).collect();
+            assert_eq!(inv.select(&col), bits(&inv));
+        }
+    }
+
+    fn every(step: usize, len: usize) -> BitIndex {
+        let mut b = BitIndex::for_col_len(len);
+        (0 .. len).step_by(step).for_each(|i| b.set(i));
+        b
+    }
+
+    #[test]
+    fn and_or_combine_rowwise() {
+        let (twos, threes) = (every(2, 130), every(3, 130));
+        assert_eq!(bits(&twos.and(&threes)), (0 .. 130).step_by(6).collect::<Vec<_>>());
+        assert_eq!(bits(&twos.or(&threes)), (0 .. 130).filter(|i| i % 2 == 0 || i % 3 == 0).collect::<Vec<_>>());
+        assert_eq!(twos.and(&twos.inverted()).count_ones(), 0);
+        assert_eq!(twos.or(&twos.inverted()).count_ones(), 130);
+    }
+
+    #[test]
+    fn large_bitmaps_agree_with_small_ones() {
+        // big enough to take the parallel path, when it's enabled
+        let len = 64 * (1 << 16) + 3;
+        let (twos, threes) = (every(2, len), every(3, len));
+        assert_eq!(twos.count_ones(), len.div_ceil(2));
+        assert_eq!(twos.and(&threes).count_ones(), len.div_ceil(6));
+        assert_eq!(twos.or(&threes).count_ones(), len.div_ceil(2) + len.div_ceil(3) - len.div_ceil(6));
+        let inv = twos.inverted();
+        assert_eq!((inv.len(), inv.count_ones()), (len, len / 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "different numbers of rows")]
+    fn combining_different_lengths_panics() {
+        every(2, 10).and(&every(2, 11));
+    }
+
+    #[test]
+    fn set_range_sets_exactly_the_range() {
+        for (lo, hi) in [(0, 0), (3, 3), (0, 1), (5, 64), (60, 70), (0, 130), (63, 128), (64, 129), (1, 129)] {
+            let mut b = BitIndex::for_col_len(130);
+            b.set_range(lo, hi);
+            assert_eq!(bits(&b), (lo .. hi).collect::<Vec<_>>(), "{}..{}", lo, hi);
+        }
+    }
+
+    #[test]
+    fn or_at_shifts_into_place() {
+        for offset in [0, 1, 63, 64, 70] {
+            let mut b = every(5, 200);
+            b.or_at(offset, &every(2, 130));
+            let expected: Vec<usize> = (0 .. 200).filter(|i| i % 5 == 0 || (*i >= offset && *i < offset + 130 && (i - offset) % 2 == 0)).collect();
+            assert_eq!(bits(&b), expected, "at {}", offset);
+        }
+    }
+}
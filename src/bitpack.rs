@@ -0,0 +1,386 @@
+// Bit-packed storage for small-domain integers: each value takes `width` bits, so a column of
+// 0..=7 codes needs 3 bits a row instead of 64. Every 64 values fill exactly `width` words,
+// which is the batch size the scan kernels unpack at a time - one batch produces one word of
+// the result mask.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Column, EntityColumn, NumColumn, Scalar};
+use crate::core::fract;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+
+// Wider than this and packing doesn't save enough to be worth the unpacking
+pub const MAX_WIDTH: u32 = 32;
+
+#[derive(Debug, Clone)]
+pub struct BitPacked {
+    words: Buffer<u64>,
+    width: u32,
+    offset: usize,      // rows skipped at the start of `words` (after slicing)
+    len: usize
+}
+
+// Bits needed for values up to `max`
+pub fn width_for(max: u64) -> u32 {
+    64 - max.leading_zeros()
+}
+
+impl BitPacked {
+    pub fn pack(values: &[u64], width: u32) -> BitPacked {
+        debug_assert!(values.iter().all(|x| width == 64 || *x >> width == 0));
+        let batches = values.len().div_ceil(64);
+        let mut words = vec![0u64; batches * width as usize];
+        // width 0 - every value is 0, and there's nothing to store
+        for (i, x) in values.iter().enumerate().filter(|_| width > 0) {
+            let bit = i * width as usize;
+            let (w, s) = (bit / 64, bit % 64);
+            words[w] |= x << s;
+            if s + width as usize > 64 {
+                words[w + 1] |= x >> (64 - s);
+            }
+        }
+        BitPacked { words: Buffer::from(words), width, offset: 0, len: values.len() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.words.memory_usage()
+    }
+
+    fn value_mask(&self) -> u64 {
+        if self.width == 64 { !0 } else { (1 << self.width) - 1 }
+    }
+
+    pub fn get(&self, idx: usize) -> u64 {
+        if self.width == 0 {
+            return 0;
+        }
+        let bit = (self.offset + idx) * self.width as usize;
+        let (w, s) = (bit / 64, bit % 64);
+        let mut x = self.words[w] >> s;
+        if s + self.width as usize > 64 {
+            x |= self.words[w + 1] << (64 - s);
+        }
+        x & self.value_mask()
+    }
+
+    // Unpack the 64 values of (physical) batch `b` into `out`
+    fn unpack_batch(&self, b: usize, out: &mut [u64; 64]) {
+        if self.width == 0 {
+            *out = [0; 64];
+            return;
+        }
+        let width = self.width as usize;
+        let words = &self.words[b * width .. (b + 1) * width];
+        let mask = self.value_mask();
+        for (k, x) in out.iter_mut().enumerate() {
+            let bit = k * width;
+            let (w, s) = (bit / 64, bit % 64);
+            let mut v = words[w] >> s;
+            if s + width > 64 {
+                v |= words[w + 1] << (64 - s);
+            }
+            *x = v & mask;
+        }
+    }
+
+    // Evaluate `pred` on every value, a batch of 64 at a time
+    pub fn mask_by<F: Fn(u64) -> bool>(&self, pred: F) -> BitIndex {
+        if self.len == 0 {
+            return BitIndex::for_col_len(0);
+        }
+        let (first, last) = (self.offset / 64, (self.offset + self.len - 1) / 64);
+        let mut batch = [0u64; 64];
+        let mut words: Vec<u64> = (first ..= last).map(|b| {
+            self.unpack_batch(b, &mut batch);
+            batch.iter().enumerate().fold(0u64, |word, (k, x)| word | ((pred(*x) as u64) << k))
+        }).collect();
+        // line the mask up with row 0 of the slice, and clear the bits past its end
+        let shift = self.offset % 64;
+        if shift > 0 {
+            for j in 0 .. words.len() {
+                let next = words.get(j + 1).copied().unwrap_or(0);
+                words[j] = (words[j] >> shift) | (next << (64 - shift));
+            }
+        }
+        words.resize(self.len / 64 + 1, 0);
+        let last = words.len() - 1;
+        words[last] &= (1u64 << (self.len % 64)) - 1;
+        BitIndex::from_words(words, self.len)
+    }
+
+    // Rows offset .. offset + len, which must be in bounds. Shares `words`.
+    pub fn slice(&self, offset: usize, len: usize) -> BitPacked {
+        BitPacked { words: self.words.clone(), width: self.width, offset: self.offset + offset, len }
+    }
+
+    pub fn unpack(&self) -> Vec<u64> {
+        (0 .. self.len).map(|i| self.get(i)).collect()
+    }
+}
+
+// A Num or Entity column stored as bit-packed offsets from its minimum
+#[derive(Debug, Clone)]
+pub struct PackedColumn {
+    dtype: Datatype,
+    base: u64,          // the minimum: an Entity, or a Num as i64 bits
+    codes: BitPacked
+}
+
+impl PackedColumn {
+    // None unless the column is Num (integers only) or Entity, with a range small enough to
+    // pack into at most MAX_WIDTH bits
+    pub fn encode(col: &Column) -> Option<PackedColumn> {
+        let (dtype, base, codes): (Datatype, u64, Vec<u64>) = match col {
+            Column::Num(c) => {
+                // -0.0 would come back as 0.0
+                let exact = |x: &f64| fract(*x) == 0.0 && x.abs() <= (1u64 << 53) as f64 && !(*x == 0.0 && x.is_sign_negative());
+                if !c.data.iter().all(exact) {
+                    return None;
+                }
+                let min = c.data.iter().copied().fold(f64::INFINITY, f64::min);
+                let min = if min.is_finite() { min as i64 } else { 0 };
+                (Datatype::Num, min as u64, c.data.iter().map(|x| (*x as i64 - min) as u64).collect())
+            },
+            Column::Entity(c) => {
+                let min = c.data.iter().copied().min().unwrap_or(0);
+                (Datatype::Entity, min, c.data.iter().map(|x| x - min).collect())
+            },
+            _ => return None
+        };
+        let width = width_for(codes.iter().copied().max().unwrap_or(0));
+        if width > MAX_WIDTH {
+            return None;
+        }
+        Some(PackedColumn { dtype, base, codes: BitPacked::pack(&codes, width) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn width(&self) -> u32 {
+        self.codes.width()
+    }
+
+    pub fn datatype(&self) -> Datatype {
+        self.dtype
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.codes.memory_usage()
+    }
+
+    // The code for `val`, if it's in the column's domain
+    fn code_of(&self, val: &Scalar) -> Result<Option<u64>, VMError> {
+        let code = match (self.dtype, val) {
+            (Datatype::Num, Scalar::Num(x)) => {
+                if fract(*x) != 0.0 || x.abs() > (1u64 << 53) as f64 {
+                    return Ok(None);
+                }
+                (*x as i64).checked_sub(self.base as i64).filter(|c| *c >= 0).map(|c| c as u64)
+            },
+            (Datatype::Entity, Scalar::Entity(x)) => x.checked_sub(self.base),
+            (Datatype::Num, _) => return Err(VMError::TypeError(format!("Expected a numeric value, got: {:?}", val))),
+            _ => return Err(VMError::TypeError(format!("Expected an entity-id value, got: {:?}", val)))
+        };
+        Ok(code.filter(|c| width_for(*c) <= self.width()))
+    }
+
+    // First code whose value is >= `bound`, saturating at the ends of the domain
+    fn lower_code(&self, bound: &Scalar) -> Result<u64, VMError> {
+        match (self.dtype, bound) {
+            (Datatype::Num, Scalar::Num(x)) => {
+                let c = libm::ceil(*x) - self.base as i64 as f64;
+                Ok(if c.is_nan() || c <= 0.0 { 0 } else { c.min(u64::MAX as f64) as u64 })
+            },
+            (Datatype::Entity, Scalar::Entity(x)) => Ok(x.saturating_sub(self.base)),
+            _ => Err(VMError::TypeError(format!("Expected a {} bound, got: {:?}", self.dtype, bound)))
+        }
+    }
+
+    fn value_of(&self, code: u64) -> u64 {
+        self.base.wrapping_add(code)
+    }
+
+    fn to_column(&self, codes: impl Iterator<Item=u64>) -> Column {
+        match self.dtype {
+            Datatype::Num => Column::Num(NumColumn { data: codes.map(|c| self.value_of(c) as i64 as f64).collect() }),
+            _ => Column::Entity(EntityColumn { data: codes.map(|c| self.value_of(c)).collect() })
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Scalar> {
+        if idx >= self.len() {
+            return None;
+        }
+        let value = self.value_of(self.codes.get(idx));
+        Some(match self.dtype {
+            Datatype::Num => Scalar::Num(value as i64 as f64),
+            _ => Scalar::Entity(value)
+        })
+    }
+
+    // Back to the plain layout
+    pub fn decode(&self) -> Column {
+        self.to_column(self.codes.unpack().into_iter())
+    }
+
+    pub fn slice(&self, offset: usize, len: usize) -> PackedColumn {
+        PackedColumn { dtype: self.dtype, base: self.base, codes: self.codes.slice(offset, len) }
+    }
+
+    pub fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        let mask = match self.code_of(&val)? {
+            Some(code) => self.codes.mask_by(|c| c == code),
+            None => BitIndex::for_col_len(self.len())
+        };
+        Ok(BoolColumn::from_mask(mask))
+    }
+
+    // lo <= x < hi
+    pub fn filter_range(&self, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        if let (Scalar::Num(lo), Scalar::Num(hi)) = (&lo, &hi) {
+            if lo.is_nan() || hi.is_nan() {
+                return Ok(BoolColumn::from_mask(BitIndex::for_col_len(self.len())));
+            }
+        }
+        let (lo, hi) = (self.lower_code(&lo)?, self.lower_code(&hi)?);
+        Ok(BoolColumn::from_mask(self.codes.mask_by(|c| lo <= c && c < hi)))
+    }
+
+    // The selected rows, still packed at the same width
+    pub fn select(&self, sel: &Selection) -> PackedColumn {
+        let mut codes = Vec::with_capacity(sel.count_ones());
+        sel.for_each(|idx| codes.push(self.codes.get(idx)));
+        PackedColumn { dtype: self.dtype, base: self.base, codes: BitPacked::pack(&codes, self.width()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+    use crate::datagen::Rng;
+
+    fn codes(n: usize, width: u32, seed: u64) -> Vec<u64> {
+        let mut rng = Rng::new(seed);
+        (0 .. n).map(|_| if width == 0 { 0 } else { rng.next_u64() >> (64 - width) }).collect()
+    }
+
+    fn columns() -> Vec<Column> {
+        let ids: Vec<u64> = codes(300, 5, 1).iter().map(|c| c + 1_000_000).collect();
+        let nums: Vec<f64> = codes(300, 9, 2).iter().map(|c| *c as f64 - 200.0).collect();
+        vec![Column::from(ids), Column::from(nums), Column::from(vec![4.0; 70])]
+    }
+
+    #[test]
+    fn packs_and_unpacks_every_width() {
+        for width in [0, 1, 3, 7, 8, 13, 31, 32, 63, 64] {
+            let values = codes(200, width, width as u64);
+            let packed = BitPacked::pack(&values, width);
+            assert_eq!(packed.unpack(), values, "width {}", width);
+            assert_eq!(packed.memory_usage(), (200usize.div_ceil(64) * width as usize * 8).div_ceil(64) * 64);
+            let slice = packed.slice(70, 100);
+            assert_eq!(slice.unpack(), values[70 .. 170].to_vec());
+        }
+        assert_eq!((width_for(0), width_for(1), width_for(7), width_for(8), width_for(u64::MAX)), (0, 1, 3, 4, 64));
+    }
+
+    #[test]
+    fn batch_masks_line_up_with_slices() {
+        let values = codes(300, 3, 4);
+        let packed = BitPacked::pack(&values, 3);
+        for (offset, len) in [(0, 300), (0, 64), (1, 63), (5, 128), (64, 1), (250, 50), (10, 0)] {
+            let mask = packed.slice(offset, len).mask_by(|c| c == 5);
+            let mut found = Vec::new();
+            mask.for_each(|i| found.push(i));
+            let expected: Vec<usize> = (0 .. len).filter(|i| values[offset + i] == 5).collect();
+            assert_eq!((mask.len(), found), (len, expected), "{}..+{}", offset, len);
+        }
+    }
+
+    #[test]
+    fn round_trips_columns() {
+        for col in columns() {
+            let packed = PackedColumn::encode(&col).unwrap();
+            assert_eq!((packed.len(), packed.datatype()), (col.len(), col.datatype()));
+            assert_eq!(packed.decode().fingerprint(), col.fingerprint());
+            assert!(packed.memory_usage() < col.memory_usage());
+        }
+        assert_eq!(PackedColumn::encode(&columns()[0]).unwrap().width(), 5);
+        assert_eq!(PackedColumn::encode(&columns()[2]).unwrap().width(), 0);
+        let empty = PackedColumn::encode(&Column::from(Vec::<f64>::new())).unwrap();
+        assert!(empty.is_empty() && empty.decode().is_empty());
+    }
+
+    #[test]
+    fn refuses_what_it_cant_encode() {
+        assert!(PackedColumn::encode(&Column::from(vec![1.0, 1.5])).is_none());
+        assert!(PackedColumn::encode(&Column::from(vec![-0.0, 1.0])).is_none());
+        assert!(PackedColumn::encode(&Column::from(vec![1.0, f64::NAN])).is_none());
+        assert!(PackedColumn::encode(&Column::from(vec![0u64, 1 << 33])).is_none());
+        assert!(PackedColumn::encode(&Column::from(vec!["a"])).is_none());
+    }
+
+    #[test]
+    fn filters_like_the_plain_column() {
+        for col in columns() {
+            let packed = Column::Packed(PackedColumn::encode(&col).unwrap());
+            let probes: Vec<Scalar> = match col.datatype() {
+                Datatype::Num => [-201.0, -200.0, -199.0, 0.0, -0.0, 4.0, 311.0, 312.0, 2.5, f64::NAN].iter().copied().map(Scalar::Num).collect(),
+                _ => [0, 999_999, 1_000_000, 1_000_007, 1_000_031, 1_000_032].iter().copied().map(Scalar::Entity).collect()
+            };
+            for val in &probes {
+                assert_eq!(packed.filter(val.clone()).unwrap().selection().positions(), col.filter(val.clone()).unwrap().selection().positions(), "{:?}", val);
+            }
+            for (lo, hi) in probes.iter().zip(probes.iter().rev()) {
+                let expected = col.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions();
+                assert_eq!(packed.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions(), expected, "{:?}..{:?}", lo, hi);
+            }
+        }
+        let nums = Column::Packed(PackedColumn::encode(&columns()[1]).unwrap());
+        assert!(nums.filter(Scalar::Entity(1)).is_err());
+        assert!(nums.filter_range(Scalar::Entity(1), Scalar::Entity(2)).is_err());
+        let range = |lo: f64, hi: f64| nums.filter_range(Scalar::Num(lo), Scalar::Num(hi)).unwrap().selection().positions().len();
+        assert_eq!(range(f64::NEG_INFINITY, f64::INFINITY), 300);
+        assert_eq!(range(-200.5, -199.5), range(-200.0, -199.0));
+    }
+
+    #[test]
+    fn slices_and_selects_like_the_plain_column() {
+        for col in columns() {
+            let packed = Column::Packed(PackedColumn::encode(&col).unwrap());
+            let n = col.len();
+            for (offset, len) in [(0, n), (3, 60), (n - 1, 1), (5, 0)] {
+                let (slice, plain) = (packed.slice(offset, len), col.slice(offset, len));
+                assert_eq!(slice.fingerprint(), plain.fingerprint());
+                let mask = plain.filter_range(Scalar::Num(-150.0), Scalar::Num(50.0))
+                    .or_else(|_| plain.filter_range(Scalar::Entity(1_000_004), Scalar::Entity(1_000_020))).unwrap();
+                assert_eq!(slice.filter_range(Scalar::Num(-150.0), Scalar::Num(50.0))
+                    .or_else(|_| slice.filter_range(Scalar::Entity(1_000_004), Scalar::Entity(1_000_020))).unwrap().selection().positions(), mask.selection().positions());
+                let picked = slice.select(&mask);
+                assert!(matches!(picked, Column::Packed(_)));
+                assert_eq!(picked.fingerprint(), plain.select(&mask).fingerprint());
+            }
+        }
+    }
+}
@@ -0,0 +1,361 @@
+// `collie browse`: a terminal UI for looking around loaded data before writing queries.
+// Tables are listed on the left; the right shows the selected table's columns (type,
+// encoding, size) above a scrollable view of its rows. Quick filters narrow the rows with
+// FilterEq on a column, and stack until cleared.
+//
+// Keys: Tab switches between the table list and the rows; Up/Down/PgUp/PgDn/Home/End move;
+// `/` starts a filter (`column = value`), Enter applies it and Esc cancels; Esc clears the
+// filters; q quits.
+
+use crate::column::{ColumnT, Scalar};
+use crate::duration::Nanos;
+use crate::geo::Point;
+use crate::json;
+use crate::result::{self, ResultSet};
+use crate::schema::Datatype;
+use crate::timestamp::Micros;
+use crate::vector;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Cell, List, ListItem, ListState, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Focus {
+    Tables,
+    Rows
+}
+
+struct Browser {
+    tables: Vec<(String, ResultSet)>,
+    selected: usize,
+    focus: Focus,
+    view: ResultSet,            // the selected table, after filters
+    filters: Vec<String>,
+    input: Option<String>,      // a filter being typed
+    status: String,
+    top: usize,                 // first row shown
+    page: usize                 // rows that fit on screen, as of the last draw
+}
+
+// `text` as a value for a column of type `dtype`
+fn parse_value(text: &str, dtype: Datatype) -> Result<Scalar, String> {
+    let bad = || format!("'{}' isn't a {} value", text, dtype);
+    match dtype {
+        Datatype::Bool => text.parse().map(Scalar::Bool).map_err(|_| bad()),
+        Datatype::Num => text.parse().map(Scalar::Num).map_err(|_| bad()),
+        Datatype::Entity => text.trim_start_matches('#').parse().map(Scalar::Entity).map_err(|_| bad()),
+        Datatype::Int => text.parse().map(Scalar::Int).map_err(|_| bad()),
+        Datatype::Duration => Nanos::parse(text).map(Scalar::Duration).ok_or_else(bad),
+        Datatype::Timestamp => Micros::parse(text).map(Scalar::Timestamp).ok_or_else(bad),
+        Datatype::Point => Point::parse(text).map(Scalar::Point).ok_or_else(bad),
+        Datatype::Ipv4 => text.parse().map(Scalar::Ipv4).map_err(|_| bad()),
+        Datatype::Ipv6 => text.parse().map(Scalar::Ipv6).map_err(|_| bad()),
+        Datatype::Json => json::validate(text).map(|_| Scalar::Json(text.to_string())).map_err(|_| bad()),
+        Datatype::Vector => vector::parse(text).map(Scalar::Vector).ok_or_else(bad),
+        Datatype::List | Datatype::Record => Err(format!("Can't search {} values", dtype)),
+        Datatype::Str | Datatype::Categorical => Ok(Scalar::Str(text.trim_matches('"').to_string()))
+    }
+}
+
+// The rows of `rs` where `column = value`
+fn apply_filter(rs: &ResultSet, filter: &str) -> Result<ResultSet, String> {
+    let (name, value) = filter.split_once('=').ok_or("filters look like: column = value")?;
+    let (name, value) = (name.trim(), value.trim());
+    let col = rs.column(name).ok_or_else(|| format!("no column named '{}'", name))?;
+    let mask = col.filter(parse_value(value, col.datatype())?).map_err(|e| format!("{:?}", e))?;
+    let mut res = ResultSet::new();
+    for (name, col) in rs.iter() {
+        if col.len() != mask.selection().len() {
+            return Err(format!("column '{}' has {} rows, expected {}", name, col.len(), mask.selection().len()));
+        }
+        res.push(name, col.select(&mask));
+    }
+    Ok(res)
+}
+
+impl Browser {
+    fn new(tables: Vec<(String, ResultSet)>) -> Self {
+        let view = tables.first().map(|(_, rs)| rs.clone()).unwrap_or_default();
+        Browser {
+            tables, selected: 0, focus: Focus::Tables, view, filters: Vec::new(), input: None,
+            status: String::new(), top: 0, page: 1
+        }
+    }
+
+    fn select_table(&mut self, idx: usize) {
+        if let Some((_, rs)) = self.tables.get(idx) {
+            self.selected = idx;
+            self.view = rs.clone();
+            self.filters.clear();
+            self.top = 0;
+            self.status.clear();
+        }
+    }
+
+    fn scroll(&mut self, by: isize) {
+        let last = self.view.rows().saturating_sub(1);
+        self.top = (self.top as isize + by).clamp(0, last as isize) as usize;
+    }
+
+    fn add_filter(&mut self, filter: String) {
+        match apply_filter(&self.view, &filter) {
+            Ok(view) => {
+                self.view = view;
+                self.filters.push(filter);
+                self.top = 0;
+                self.status.clear();
+            },
+            Err(e) => self.status = e
+        }
+    }
+
+    // false once it's time to quit
+    fn on_key(&mut self, key: KeyCode) -> bool {
+        if let Some(input) = &mut self.input {
+            match key {
+                KeyCode::Enter => {
+                    let filter = std::mem::take(input);
+                    self.input = None;
+                    self.add_filter(filter);
+                },
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => { input.pop(); },
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return true;
+        }
+        let page = self.page as isize;
+        match (key, self.focus) {
+            (KeyCode::Char('q'), _) => return false,
+            (KeyCode::Esc, _) if self.filters.is_empty() => return false,
+            (KeyCode::Esc, _) => self.select_table(self.selected),
+            (KeyCode::Tab, Focus::Tables) => self.focus = Focus::Rows,
+            (KeyCode::Tab, Focus::Rows) => self.focus = Focus::Tables,
+            (KeyCode::Char('/'), _) => self.input = Some(String::new()),
+            (KeyCode::Up, Focus::Tables) => self.select_table(self.selected.saturating_sub(1)),
+            (KeyCode::Down, Focus::Tables) => self.select_table(self.selected + 1),
+            (KeyCode::Up, Focus::Rows) => self.scroll(-1),
+            (KeyCode::Down, Focus::Rows) => self.scroll(1),
+            (KeyCode::PageUp, _) => self.scroll(-page),
+            (KeyCode::PageDown, _) => self.scroll(page),
+            (KeyCode::Home, _) => self.top = 0,
+            (KeyCode::End, _) => self.top = self.view.rows().saturating_sub(self.page),
+            _ => {}
+        }
+        true
+    }
+
+    fn block(&self, title: String, focus: Option<Focus>) -> Block<'static> {
+        let block = Block::default().borders(Borders::ALL).title(title);
+        if focus == Some(self.focus) {
+            block.border_style(Style::default().add_modifier(Modifier::BOLD))
+        } else {
+            block
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, footer] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Length(24), Constraint::Min(0)]).areas(main);
+        let schema_height = self.view.len() as u16 + 3;
+        let [schema, rows] = Layout::vertical([Constraint::Length(schema_height), Constraint::Min(0)]).areas(right);
+
+        let items: Vec<ListItem> = self.tables.iter()
+            .map(|(name, rs)| ListItem::new(format!("{} ({})", name, rs.rows())))
+            .collect();
+        let list = List::new(items)
+            .block(self.block("Tables".to_string(), Some(Focus::Tables)))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, left, &mut ListState::default().with_selected(Some(self.selected)));
+
+        self.draw_schema(frame, schema);
+        self.draw_rows(frame, rows);
+
+        let footer_text = match &self.input {
+            Some(input) => format!("filter: {}_", input),
+            None if !self.status.is_empty() => self.status.clone(),
+            None => "Tab: switch pane  /: filter  Esc: clear filters  q: quit".to_string()
+        };
+        frame.render_widget(Paragraph::new(footer_text), footer);
+    }
+
+    fn draw_schema(&self, frame: &mut Frame, area: Rect) {
+        let table = match self.tables.get(self.selected) {
+            Some((_, rs)) => rs,
+            None => return
+        };
+        let rows = table.iter().map(|(name, col)| Row::new(vec![
+            name.to_string(),
+            col.datatype().to_string(),
+            col.kind().to_string(),
+            col.len().to_string(),
+            format!("{} B", col.memory_usage())
+        ]));
+        let widths = [Constraint::Min(12), Constraint::Length(8), Constraint::Length(10), Constraint::Length(10), Constraint::Length(14)];
+        let header = Row::new(vec!["column", "type", "encoding", "rows", "memory"]).style(Style::default().add_modifier(Modifier::BOLD));
+        frame.render_widget(Table::new(rows, widths).header(header).block(self.block("Schema".to_string(), None)), area);
+    }
+
+    fn draw_rows(&mut self, frame: &mut Frame, area: Rect) {
+        // borders and the header row
+        self.page = (area.height as usize).saturating_sub(3).max(1);
+        let format = result::TableFormat::default();
+        let end = (self.top + self.page).min(self.view.rows());
+        let rows = (self.top .. end).map(|i| Row::new(self.view.columns.iter().map(|col| {
+            let text = col.get(i).map(|v| result::cell_text(v, format.max_width)).unwrap_or_default();
+            match col.datatype() {
+                Datatype::Num | Datatype::Int | Datatype::Duration => Cell::from(Line::from(text).right_aligned()),
+                _ => Cell::from(text)
+            }
+        }).collect::<Vec<_>>()));
+        let widths: Vec<Constraint> = self.view.names.iter().map(|_| Constraint::Min(8)).collect();
+        let header = Row::new(self.view.names.clone()).style(Style::default().add_modifier(Modifier::BOLD));
+        let mut title = format!("Rows {}-{} of {}", (self.top + 1).min(end), end, self.view.rows());
+        if !self.filters.is_empty() {
+            title += &format!(" where {}", self.filters.join(" and "));
+        }
+        frame.render_widget(Table::new(rows, widths).header(header).block(self.block(title, Some(Focus::Rows))), area);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.on_key(key.code) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Browse `tables` until the user quits. Takes over the terminal in the meantime.
+pub fn browse(tables: Vec<(String, ResultSet)>) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let res = Browser::new(tables).run(&mut terminal);
+    ratatui::restore();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Column;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn people() -> ResultSet {
+        ResultSet::from(vec![
+            ("name", Column::from((0 .. 100).map(|i| format!("person {}", i)).collect::<Vec<_>>())),
+            ("age", Column::from((0 .. 100).map(|i| (20 + i % 5) as f64).collect::<Vec<_>>())),
+            ("active", Column::from((0 .. 100).map(|i| i % 2 == 0).collect::<Vec<_>>()))
+        ])
+    }
+
+    fn browser() -> Browser {
+        let pets = ResultSet::from(vec![("species", Column::from(vec!["cat", "dog"]))]);
+        Browser::new(vec![("people".to_string(), people()), ("pets".to_string(), pets)])
+    }
+
+    // What a draw puts on an 80 x 24 screen, a line of text per row
+    fn screen(b: &mut Browser) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|frame| b.draw(frame)).unwrap();
+        let buf = terminal.backend().buffer();
+        (0 .. buf.area.height).map(|y| {
+            (0 .. buf.area.width).map(|x| buf[(x, y)].symbol()).collect::<String>() + "\n"
+        }).collect()
+    }
+
+    fn type_filter(b: &mut Browser, filter: &str) {
+        assert!(b.on_key(KeyCode::Char('/')));
+        filter.chars().for_each(|c| { b.on_key(KeyCode::Char(c)); });
+        assert!(b.on_key(KeyCode::Enter));
+    }
+
+    #[test]
+    fn scrolls_the_rows() {
+        let mut b = browser();
+        assert!(screen(&mut b).contains("Rows 1-"));
+        b.on_key(KeyCode::Tab);
+        b.on_key(KeyCode::Down);
+        b.on_key(KeyCode::Down);
+        assert_eq!(b.top, 2);
+        b.on_key(KeyCode::PageDown);
+        assert_eq!(b.top, 2 + b.page);
+        b.on_key(KeyCode::End);
+        assert_eq!(b.top, 100 - b.page);
+        assert!(screen(&mut b).contains("person 99"));
+        b.on_key(KeyCode::Home);
+        b.on_key(KeyCode::Up);
+        assert_eq!(b.top, 0);
+    }
+
+    #[test]
+    fn stacks_filters_until_cleared() {
+        let mut b = browser();
+        type_filter(&mut b, "age = 21");
+        assert_eq!(b.view.rows(), 20);
+        type_filter(&mut b, "active = true");
+        assert_eq!(b.view.rows(), 10);
+        type_filter(&mut b, "name = nobody");
+        assert_eq!(b.view.rows(), 0);
+        assert_eq!(b.filters, vec!["age = 21", "active = true", "name = nobody"]);
+        assert!(screen(&mut b).contains("Rows 0-0 of 0 where age = 21 and"));
+
+        // Esc clears the filters, then quits
+        assert!(b.on_key(KeyCode::Esc));
+        assert_eq!(b.view.rows(), 100);
+        assert!(b.filters.is_empty());
+        assert!(!b.on_key(KeyCode::Esc));
+    }
+
+    #[test]
+    fn reports_bad_filters() {
+        let mut b = browser();
+        type_filter(&mut b, "height = 2");
+        assert!(b.status.contains("no column named 'height'"));
+        type_filter(&mut b, "age = old");
+        assert!(b.status.contains("isn't a Num value"));
+        type_filter(&mut b, "age");
+        assert!(b.filters.is_empty());
+        assert!(screen(&mut b).contains("filters look like"));
+
+        // Esc while typing drops the filter, not the browser
+        b.on_key(KeyCode::Char('/'));
+        b.on_key(KeyCode::Char('x'));
+        assert!(screen(&mut b).contains("filter: x_"));
+        assert!(b.on_key(KeyCode::Esc));
+        assert!(b.input.is_none());
+    }
+
+    #[test]
+    fn switches_tables() {
+        let mut b = browser();
+        type_filter(&mut b, "age = 20");
+        b.on_key(KeyCode::Down);
+        assert_eq!(b.selected, 1);
+        assert!(b.filters.is_empty());
+        assert!(screen(&mut b).contains("species"));
+        b.on_key(KeyCode::Down);
+        assert_eq!(b.selected, 1, "there's no third table");
+        assert!(!b.on_key(KeyCode::Char('q')));
+    }
+
+    #[test]
+    fn browses_nothing() {
+        let mut b = Browser::new(Vec::new());
+        assert!(screen(&mut b).contains("Rows 0-0 of 0"));
+        b.on_key(KeyCode::Tab);
+        b.on_key(KeyCode::Down);
+        b.on_key(KeyCode::End);
+        assert_eq!(b.top, 0);
+    }
+}
@@ -0,0 +1,323 @@
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use alloc::sync::Arc;
+use crate::core::prelude::*;
+
+// Column storage with Arrow's memory layout guarantees: the allocation is 64-byte aligned
+// and its size is padded to a multiple of 64 bytes (with the padding zeroed), so SIMD
+// kernels can always load full aligned vectors, including the last partial one.
+// (Slices starting partway into an allocation are, of course, only aligned to T.)
+//
+// Only for plain-data element types - the buffer never runs destructors on its elements.
+
+pub const ALIGNMENT: usize = 64;
+
+// The allocation itself. Buffers share one of these when sliced.
+struct RawBuffer<T: Copy> {
+    ptr: NonNull<T>,
+    len: usize,
+    cap: usize      // in elements; cap * size_of::<T>() is always a multiple of ALIGNMENT
+}
+
+impl<T: Copy> RawBuffer<T> {
+    fn new() -> Self {
+        RawBuffer { ptr: NonNull::dangling(), len: 0, cap: 0 }
+    }
+
+    fn layout(cap: usize) -> Layout {
+        let bytes = cap.checked_mul(core::mem::size_of::<T>()).expect("capacity overflow");
+        Layout::from_size_align(bytes, ALIGNMENT).expect("capacity overflow")
+    }
+
+    // Round `n` elements up to a whole number of ALIGNMENT-sized blocks. Panics, as Vec does,
+    // if that many bytes don't fit in a usize: a size that wrapped would be a short allocation.
+    fn padded_cap(n: usize) -> usize {
+        let size = core::mem::size_of::<T>().max(1);
+        let bytes = n.checked_mul(size)
+            .and_then(|bytes| bytes.div_ceil(ALIGNMENT).checked_mul(ALIGNMENT))
+            .expect("capacity overflow");
+        bytes / size
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.len.checked_add(additional).expect("capacity overflow");
+        if needed <= self.cap || core::mem::size_of::<T>() == 0 { return; }
+        let new_cap = RawBuffer::<T>::padded_cap(needed.max(self.cap.saturating_mul(2)));
+        let new_layout = RawBuffer::<T>::layout(new_cap);
+        // SAFETY: new_layout has non-zero size (needed > cap >= 0 and T isn't zero-sized).
+        // The new allocation is zeroed, so the padding past `len` is too; we copy over
+        // the `len` initialized elements and free the old allocation with its own layout.
+        unsafe {
+            let new_ptr = alloc_zeroed(new_layout) as *mut T;
+            let new_ptr = match NonNull::new(new_ptr) {
+                Some(p) => p,
+                None => handle_alloc_error(new_layout)
+            };
+            if self.cap > 0 {
+                core::ptr::copy_nonoverlapping(self.ptr.as_ptr(), new_ptr.as_ptr(), self.len);
+                dealloc(self.ptr.as_ptr() as *mut u8, RawBuffer::<T>::layout(self.cap));
+            }
+            self.ptr = new_ptr;
+        }
+        self.cap = new_cap;
+    }
+
+    fn extend_from_slice(&mut self, xs: &[T]) {
+        self.reserve(xs.len());
+        // SAFETY: reserve made room for xs.len() more elements; xs can't alias our allocation
+        // while we hold &mut self
+        unsafe { core::ptr::copy_nonoverlapping(xs.as_ptr(), self.ptr.as_ptr().add(self.len), xs.len()); }
+        self.len += xs.len();
+    }
+
+    fn as_slice(&self) -> &[T] {
+        // SAFETY: the first `len` elements are initialized; ptr is dangling-but-aligned if len == 0
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        // SAFETY: as above, and we have unique access
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy> Drop for RawBuffer<T> {
+    fn drop(&mut self) {
+        if self.cap > 0 && core::mem::size_of::<T>() > 0 {
+            // SAFETY: allocated in reserve() with exactly this layout
+            unsafe { dealloc(self.ptr.as_ptr() as *mut u8, RawBuffer::<T>::layout(self.cap)); }
+        }
+    }
+}
+
+// SAFETY: RawBuffer owns its elements outright, like a Vec
+unsafe impl<T: Copy + Send> Send for RawBuffer<T> {}
+unsafe impl<T: Copy + Sync> Sync for RawBuffer<T> {}
+
+// A window of `len` elements starting at `offset` into a shared allocation. Slicing is
+// zero-copy; mutating a buffer that shares its allocation (or only covers part of it)
+// copies its own window out first.
+pub struct Buffer<T: Copy> {
+    raw: Arc<RawBuffer<T>>,
+    offset: usize,
+    len: usize
+}
+
+impl<T: Copy> Buffer<T> {
+    pub fn new() -> Self {
+        Buffer { raw: Arc::new(RawBuffer::new()), offset: 0, len: 0 }
+    }
+
+    pub fn with_capacity(cap: usize) -> Self {
+        let mut raw = RawBuffer::new();
+        raw.reserve(cap);
+        Buffer { raw: Arc::new(raw), offset: 0, len: 0 }
+    }
+
+    // A buffer sharing this one's allocation. Panics if the range is out of bounds.
+    pub fn slice(&self, offset: usize, len: usize) -> Buffer<T> {
+        assert!(offset + len <= self.len, "slice {}..{} out of bounds for buffer of length {}", offset, offset + len, self.len);
+        Buffer { raw: self.raw.clone(), offset: self.offset + offset, len }
+    }
+
+    // Unique access to an allocation holding exactly this buffer's elements, copying if needed
+    fn make_mut(&mut self) -> &mut RawBuffer<T> {
+        let whole = self.offset == 0 && self.len == self.raw.len;
+        if !whole || Arc::get_mut(&mut self.raw).is_none() {
+            let mut raw = RawBuffer::new();
+            raw.extend_from_slice(self.as_slice());
+            self.raw = Arc::new(raw);
+            self.offset = 0;
+        }
+        // can't fail: either we were already the unique owner, or we just made a fresh one
+        Arc::get_mut(&mut self.raw).unwrap()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.make_mut().reserve(additional);
+    }
+
+    pub fn push(&mut self, x: T) {
+        self.extend_from_slice(&[x]);
+    }
+
+    pub fn extend_from_slice(&mut self, xs: &[T]) {
+        self.make_mut().extend_from_slice(xs);
+        self.len += xs.len();
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.raw.as_slice()[self.offset .. self.offset + self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        self.make_mut().as_mut_slice()
+    }
+
+    // Allocated bytes, including padding. Slices report their whole (shared) allocation.
+    pub fn memory_usage(&self) -> usize {
+        self.raw.cap * core::mem::size_of::<T>()
+    }
+
+    // Whether other buffers share this one's allocation
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.raw) > 1
+    }
+}
+
+// Whether `data` (e.g. a region of an mmap'd file) could back a Buffer without copying
+pub fn is_aligned<T>(data: &[T]) -> bool {
+    (data.as_ptr() as usize).is_multiple_of(ALIGNMENT)
+}
+
+impl<T: Copy> Default for Buffer<T> {
+    fn default() -> Self {
+        Buffer::new()
+    }
+}
+
+impl<T: Copy> Deref for Buffer<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: Copy> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+// Cheap: clones share the allocation until one of them is mutated
+impl<T: Copy> Clone for Buffer<T> {
+    fn clone(&self) -> Self {
+        Buffer { raw: self.raw.clone(), offset: self.offset, len: self.len }
+    }
+}
+
+impl<T: Copy> From<&[T]> for Buffer<T> {
+    fn from(xs: &[T]) -> Self {
+        let mut buf = Buffer::with_capacity(xs.len());
+        buf.extend_from_slice(xs);
+        buf
+    }
+}
+
+impl<T: Copy> From<Vec<T>> for Buffer<T> {
+    fn from(xs: Vec<T>) -> Self {
+        Buffer::from(xs.as_slice())
+    }
+}
+
+impl<T: Copy> core::iter::FromIterator<T> for Buffer<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut buf = Buffer::with_capacity(iter.size_hint().0);
+        iter.for_each(|x| buf.push(x));
+        buf
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Buffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+impl<T: Copy + PartialEq> PartialEq for Buffer<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Copy + Hash> Hash for Buffer<T> {
+    fn hash<H: Hasher>(&self, h: &mut H) {
+        self.as_slice().hash(h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_aligned_and_padded_as_it_grows() {
+        let mut buf: Buffer<f64> = Buffer::new();
+        assert_eq!((buf.len(), buf.memory_usage()), (0, 0));
+        for i in 0 .. 100 {
+            buf.push(i as f64);
+            assert!(is_aligned(buf.as_slice()));
+            assert!(buf.memory_usage().is_multiple_of(ALIGNMENT) && buf.memory_usage() >= buf.len() * 8);
+        }
+        assert_eq!(buf.as_slice(), (0 .. 100).map(|i| i as f64).collect::<Vec<_>>().as_slice());
+    }
+
+    #[test]
+    fn padding_is_zeroed() {
+        let buf: Buffer<u8> = Buffer::from(&[7u8, 7, 7][..]);
+        assert_eq!(buf.memory_usage(), ALIGNMENT);
+        // SAFETY: the allocation is ALIGNMENT bytes, all initialized by alloc_zeroed or extend
+        let whole = unsafe { core::slice::from_raw_parts(buf.as_ptr(), ALIGNMENT) };
+        assert_eq!(&whole[.. 3], &[7, 7, 7]);
+        assert!(whole[3 ..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn behaves_like_a_vec() {
+        let mut a: Buffer<u64> = (0 .. 20).collect();
+        a.extend_from_slice(&[100, 200]);
+        a[0] = 9;
+        let b = a.clone();
+        assert_eq!(a, b);
+        assert_eq!(format!("{:?}", Buffer::from(vec![1u64, 2])), "[1, 2]");
+
+        use crate::core::StableHasher;
+        let hash = |xs: &[u64]| { let mut h = StableHasher::default(); xs.hash(&mut h); h.finish() };
+        let mut h = StableHasher::default();
+        b.hash(&mut h);
+        assert_eq!(h.finish(), hash(&a));
+        assert_eq!((a.len(), a[21], a[0]), (22, 200, 9));
+    }
+
+    #[test]
+    fn slices_and_clones_share_until_written() {
+        let whole: Buffer<u32> = (0 .. 100).collect();
+        let part = whole.slice(10, 5);
+        assert_eq!(part.as_slice(), &[10, 11, 12, 13, 14]);
+        assert_eq!(part.slice(1, 2).as_slice(), &[11, 12]);
+        assert!(whole.is_shared() && part.is_shared());
+        assert_eq!(part.memory_usage(), whole.memory_usage());
+
+        let mut copy = whole.clone();
+        assert_eq!(copy.as_ptr(), whole.as_ptr());
+        copy[0] = 99;
+        assert_ne!(copy.as_ptr(), whole.as_ptr());
+        assert_eq!((copy[0], whole[0]), (99, 0));
+
+        // writing through a slice copies out just the slice, realigned
+        let mut part = part;
+        part.push(7);
+        assert_eq!(part.as_slice(), &[10, 11, 12, 13, 14, 7]);
+        assert!(is_aligned(part.as_slice()) && whole.slice(3, 0).is_empty());
+        assert_eq!(whole[14 .. 16], [14, 15]);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    #[should_panic(expected = "capacity overflow")]
+    fn reserving_past_usize_panics() {
+        // (2^61 - 9) + 10 u64s is 2^64 bytes, which would wrap to 0
+        let mut buf: Buffer<u64> = (0 .. 10).collect();
+        buf.reserve((1 << 61) - 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn slicing_past_the_end_panics() {
+        Buffer::from(vec![1u8, 2, 3]).slice(2, 2);
+    }
+}
@@ -0,0 +1,483 @@
+// Building columns a row at a time, for loaders that don't know how many rows they'll get.
+// The typed builders - NumColumnBuilder, InlineStrColumnBuilder and so on - take values of
+// their column's type and grow the same buffers the column will hold, so finish() hands them
+// over without a copy. AnyColumnBuilder takes Scalars for a column whose type is only known
+// at runtime, from a Datatype or a schema Field, and makes it a NullableColumn if any row
+// was null.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::categorical::{Categories, CategoricalColumn};
+use crate::column::{str_prefix, BoolColumn, Column, InlineStrColumn, Scalar, StrColumn};
+use crate::core::prelude::*;
+use crate::duration::Nanos;
+use crate::errors::VMError;
+use crate::geo::Point;
+use crate::ip::{Ipv4Addr, Ipv6Addr};
+use crate::json::{self, JsonColumn};
+use crate::list::ListColumn;
+use crate::nulls::NullableColumn;
+use crate::primitive::{Native, PrimitiveColumn};
+use crate::record_column::RecordColumn;
+use crate::schema::{Datatype, Field};
+use crate::timestamp::Micros;
+use crate::vector::VectorColumn;
+
+use alloc::sync::Arc;
+
+#[derive(Debug, Clone, Default)]
+pub struct PrimitiveBuilder<T: Native> {
+    data: Buffer<T>
+}
+
+pub type NumColumnBuilder = PrimitiveBuilder<f64>;
+pub type IntColumnBuilder = PrimitiveBuilder<i64>;
+pub type EntityColumnBuilder = PrimitiveBuilder<u64>;
+pub type DurationColumnBuilder = PrimitiveBuilder<Nanos>;
+pub type TimestampColumnBuilder = PrimitiveBuilder<Micros>;
+
+impl<T: Native> PrimitiveBuilder<T> {
+    pub fn new() -> Self {
+        PrimitiveBuilder { data: Buffer::new() }
+    }
+
+    pub fn with_capacity(rows: usize) -> Self {
+        PrimitiveBuilder { data: Buffer::with_capacity(rows) }
+    }
+
+    pub fn push(&mut self, x: T) {
+        self.data.push(x);
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn finish(self) -> PrimitiveColumn<T> {
+        PrimitiveColumn::new(self.data)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BoolColumnBuilder {
+    trues: Vec<usize>,      // the rows pushed as true
+    len: usize
+}
+
+impl BoolColumnBuilder {
+    pub fn new() -> Self {
+        BoolColumnBuilder::default()
+    }
+
+    pub fn push(&mut self, x: bool) {
+        if x {
+            self.trues.push(self.len);
+        }
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn finish(self) -> BoolColumn {
+        let mut mask = BitIndex::for_col_len(self.len);
+        self.trues.into_iter().for_each(|i| mask.set(i));
+        BoolColumn::from_mask(mask)
+    }
+}
+
+// For the legacy boxed-String layout; InlineStrColumnBuilder is what a loader wants
+#[derive(Debug, Clone, Default)]
+pub struct StrColumnBuilder {
+    data: Vec<String>
+}
+
+impl StrColumnBuilder {
+    pub fn new() -> Self {
+        StrColumnBuilder::default()
+    }
+
+    pub fn push(&mut self, s: &str) {
+        self.data.push(s.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn finish(self) -> StrColumn {
+        StrColumn { data: self.data }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InlineStrColumnBuilder {
+    data: Buffer<u8>,
+    offsets: Buffer<usize>,
+    prefixes: Buffer<u32>
+}
+
+impl Default for InlineStrColumnBuilder {
+    fn default() -> Self {
+        InlineStrColumnBuilder { data: Buffer::new(), offsets: Buffer::from(vec![0]), prefixes: Buffer::new() }
+    }
+}
+
+impl InlineStrColumnBuilder {
+    pub fn new() -> Self {
+        InlineStrColumnBuilder::default()
+    }
+
+    pub fn push(&mut self, s: &str) {
+        self.data.extend_from_slice(s.as_bytes());
+        self.offsets.push(self.data.len());
+        self.prefixes.push(str_prefix(s.as_bytes()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    pub fn finish(self) -> InlineStrColumn {
+        InlineStrColumn { data: self.data, offsets: self.offsets, prefixes: self.prefixes }
+    }
+}
+
+// A column of any type, built from Scalars. Nulls are held by a placeholder in the values
+// (as csv.rs does) and marked invalid; see NullableColumn.
+#[derive(Debug, Clone)]
+pub struct AnyColumnBuilder {
+    values: Values,
+    nulls: Vec<usize>       // the rows pushed as null
+}
+
+#[derive(Debug, Clone)]
+enum Values {
+    Bool(BoolColumnBuilder),
+    Num(NumColumnBuilder),
+    Int(IntColumnBuilder),
+    Entity(EntityColumnBuilder),
+    Duration(DurationColumnBuilder),
+    Timestamp(TimestampColumnBuilder),
+    Point(PrimitiveBuilder<Point>),
+    Ipv4(PrimitiveBuilder<Ipv4Addr>),
+    Ipv6(PrimitiveBuilder<Ipv6Addr>),
+    Str(InlineStrColumnBuilder),
+    Json(InlineStrColumnBuilder),
+    Categorical(Arc<Categories>, Vec<u32>),     // ranks
+    Vector(usize, Vec<f32>),                    // dim, then the rows' values back to back
+    List(Vec<usize>, Box<AnyColumnBuilder>),    // offsets into the items
+    Record(Vec<Datatype>, Vec<Vec<Scalar>>)
+}
+
+impl AnyColumnBuilder {
+    // A builder for a `dtype` column. Categorical and Vector columns need more than their
+    // type (see for_field), as do Lists and Records (see list and record).
+    pub fn new(dtype: Datatype) -> Result<Self, VMError> {
+        let values = match dtype {
+            Datatype::Bool => Values::Bool(BoolColumnBuilder::new()),
+            Datatype::Num => Values::Num(PrimitiveBuilder::new()),
+            Datatype::Int => Values::Int(PrimitiveBuilder::new()),
+            Datatype::Entity => Values::Entity(PrimitiveBuilder::new()),
+            Datatype::Duration => Values::Duration(PrimitiveBuilder::new()),
+            Datatype::Timestamp => Values::Timestamp(PrimitiveBuilder::new()),
+            Datatype::Point => Values::Point(PrimitiveBuilder::new()),
+            Datatype::Ipv4 => Values::Ipv4(PrimitiveBuilder::new()),
+            Datatype::Ipv6 => Values::Ipv6(PrimitiveBuilder::new()),
+            Datatype::Str => Values::Str(InlineStrColumnBuilder::new()),
+            Datatype::Json => Values::Json(InlineStrColumnBuilder::new()),
+            other => return Err(VMError::TypeError(format!("A {} column needs more than its type to build", other)))
+        };
+        Ok(AnyColumnBuilder { values, nulls: Vec::new() })
+    }
+
+    // A builder for the column `field` describes
+    pub fn for_field(field: &Field) -> Result<Self, VMError> {
+        let values = match field.dtype {
+            Datatype::Categorical => match &field.categories {
+                Some(categories) => Values::Categorical(categories.clone(), Vec::new()),
+                None => return Err(VMError::TypeError(format!("Categorical column '{}' has no categories", field.name)))
+            },
+            Datatype::Vector => match field.dim {
+                Some(dim) if dim > 0 => Values::Vector(dim, Vec::new()),
+                _ => return Err(VMError::TypeError(format!("Vector column '{}' has no size", field.name)))
+            },
+            dtype => return AnyColumnBuilder::new(dtype)
+        };
+        Ok(AnyColumnBuilder { values, nulls: Vec::new() })
+    }
+
+    // A builder for lists whose items are `items` values
+    pub fn list(items: AnyColumnBuilder) -> Self {
+        AnyColumnBuilder { values: Values::List(vec![0], Box::new(items)), nulls: Vec::new() }
+    }
+
+    // A builder for records of `fields`, which must be types a RecordColumn can hold
+    pub fn record(fields: Vec<Datatype>) -> Result<Self, VMError> {
+        // checks the fields up front, rather than at finish()
+        RecordColumn::from_records(fields.clone(), &[])?;
+        Ok(AnyColumnBuilder { values: Values::Record(fields, Vec::new()), nulls: Vec::new() })
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.values {
+            Values::Bool(b) => b.len(),
+            Values::Num(b) => b.len(),
+            Values::Int(b) => b.len(),
+            Values::Entity(b) => b.len(),
+            Values::Duration(b) => b.len(),
+            Values::Timestamp(b) => b.len(),
+            Values::Point(b) => b.len(),
+            Values::Ipv4(b) => b.len(),
+            Values::Ipv6(b) => b.len(),
+            Values::Str(b) | Values::Json(b) => b.len(),
+            Values::Categorical(_, v) => v.len(),
+            Values::Vector(dim, v) => v.len() / dim,
+            Values::List(offsets, _) => offsets.len() - 1,
+            Values::Record(_, v) => v.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Adds a row; a value of the wrong type is an error, and leaves the builder as it was
+    pub fn push(&mut self, val: Scalar) -> Result<(), VMError> {
+        if let Scalar::Null = val {
+            let placeholder = self.placeholder()?;
+            self.nulls.push(self.len());
+            return self.push(placeholder);
+        }
+        match &mut self.values {
+            Values::Bool(b) => match val {
+                Scalar::Bool(x) => b.push(x),
+                other => return Err(expected("a boolean value", &other))
+            },
+            Values::Num(b) => push_native(b, val)?,
+            Values::Int(b) => push_native(b, val)?,
+            Values::Entity(b) => push_native(b, val)?,
+            Values::Duration(b) => push_native(b, val)?,
+            Values::Timestamp(b) => push_native(b, val)?,
+            Values::Point(b) => push_native(b, val)?,
+            Values::Ipv4(b) => push_native(b, val)?,
+            Values::Ipv6(b) => push_native(b, val)?,
+            Values::Str(b) => match val {
+                Scalar::Str(s) => b.push(&s),
+                other => return Err(expected("a string value", &other))
+            },
+            Values::Json(b) => match val {
+                Scalar::Json(doc) => {
+                    json::validate(&doc).map_err(|msg| VMError::TypeError(format!("'{}' isn't valid JSON: {}", doc, msg)))?;
+                    b.push(&doc)
+                },
+                other => return Err(expected("a JSON value", &other))
+            },
+            Values::Categorical(categories, v) => match val {
+                Scalar::Str(s) => v.push(categories.rank(&s).ok_or_else(|| VMError::TypeError(format!("'{}' isn't one of the categories", s)))?),
+                other => return Err(expected("a string value", &other))
+            },
+            Values::Vector(dim, v) => match val {
+                Scalar::Vector(x) if x.len() == *dim => v.extend_from_slice(&x),
+                Scalar::Vector(x) => return Err(VMError::LengthMismatch { expected: *dim, found: x.len() }),
+                other => return Err(expected("a vector value", &other))
+            },
+            Values::List(offsets, items) => match val {
+                Scalar::List(xs) => {
+                    // tried on an empty builder first, so a bad item doesn't leave half a list
+                    let mut scratch = items.empty_like();
+                    xs.iter().try_for_each(|x| scratch.push(x.clone()))?;
+                    xs.into_iter().for_each(|x| items.push(x).expect("checked above"));
+                    offsets.push(items.len());
+                },
+                other => return Err(expected("a list value", &other))
+            },
+            Values::Record(fields, v) => match val {
+                Scalar::Record(xs) => {
+                    // from_records has the rules for what a record field can hold
+                    RecordColumn::from_records(fields.clone(), core::slice::from_ref(&xs))?;
+                    v.push(xs)
+                },
+                other => return Err(expected("a record value", &other))
+            }
+        }
+        Ok(())
+    }
+
+    // A builder for the same type, with no rows
+    fn empty_like(&self) -> AnyColumnBuilder {
+        let values = match &self.values {
+            Values::Bool(_) => Values::Bool(BoolColumnBuilder::new()),
+            Values::Num(_) => Values::Num(PrimitiveBuilder::new()),
+            Values::Int(_) => Values::Int(PrimitiveBuilder::new()),
+            Values::Entity(_) => Values::Entity(PrimitiveBuilder::new()),
+            Values::Duration(_) => Values::Duration(PrimitiveBuilder::new()),
+            Values::Timestamp(_) => Values::Timestamp(PrimitiveBuilder::new()),
+            Values::Point(_) => Values::Point(PrimitiveBuilder::new()),
+            Values::Ipv4(_) => Values::Ipv4(PrimitiveBuilder::new()),
+            Values::Ipv6(_) => Values::Ipv6(PrimitiveBuilder::new()),
+            Values::Str(_) => Values::Str(InlineStrColumnBuilder::new()),
+            Values::Json(_) => Values::Json(InlineStrColumnBuilder::new()),
+            Values::Categorical(categories, _) => Values::Categorical(categories.clone(), Vec::new()),
+            Values::Vector(dim, _) => Values::Vector(*dim, Vec::new()),
+            Values::List(_, items) => Values::List(vec![0], Box::new(items.empty_like())),
+            Values::Record(fields, _) => Values::Record(fields.clone(), Vec::new())
+        };
+        AnyColumnBuilder { values, nulls: Vec::new() }
+    }
+
+    // What a null row holds in the values: NaN for Num (which nulls.rs counts as null
+    // anyway), and false, 0, "", the first category and so on otherwise
+    fn placeholder(&self) -> Result<Scalar, VMError> {
+        Ok(match &self.values {
+            Values::Bool(_) => Scalar::Bool(false),
+            Values::Num(_) => Scalar::Num(f64::NAN),
+            Values::Int(_) => Scalar::Int(0),
+            Values::Entity(_) => Scalar::Entity(0),
+            Values::Duration(_) => Scalar::Duration(Nanos(0)),
+            Values::Timestamp(_) => Scalar::Timestamp(Micros(0)),
+            Values::Point(_) => Scalar::Point(Point::new(f64::NAN, f64::NAN)),
+            Values::Ipv4(_) => Scalar::Ipv4(Ipv4Addr::UNSPECIFIED),
+            Values::Ipv6(_) => Scalar::Ipv6(Ipv6Addr::UNSPECIFIED),
+            Values::Str(_) => Scalar::Str(String::new()),
+            Values::Json(_) => Scalar::Json("null".to_string()),
+            Values::Categorical(categories, _) if categories.is_empty() => {
+                return Err(VMError::TypeError("Null in a column with no categories".to_string()));
+            },
+            Values::Categorical(categories, _) => Scalar::Str(categories.name(0).to_string()),
+            Values::Vector(dim, _) => Scalar::Vector(vec![f32::NAN; *dim]),
+            Values::List(_, _) => Scalar::List(Vec::new()),
+            Values::Record(fields, _) => {
+                let fields = fields.iter().map(|f| AnyColumnBuilder::new(*f)?.placeholder()).collect::<Result<_, _>>()?;
+                Scalar::Record(fields)
+            }
+        })
+    }
+
+    pub fn finish(self) -> Result<Column, VMError> {
+        let len = self.len();
+        let values = match self.values {
+            Values::Bool(b) => Column::Bool(b.finish()),
+            Values::Num(b) => Column::from(b.finish()),
+            Values::Int(b) => Column::from(b.finish()),
+            Values::Entity(b) => Column::from(b.finish()),
+            Values::Duration(b) => Column::from(b.finish()),
+            Values::Timestamp(b) => Column::from(b.finish()),
+            Values::Point(b) => Column::from(b.finish()),
+            Values::Ipv4(b) => Column::from(b.finish()),
+            Values::Ipv6(b) => Column::from(b.finish()),
+            Values::Str(b) => Column::InlineStr(b.finish()),
+            Values::Json(b) => Column::Json(JsonColumn { docs: b.finish() }),
+            Values::Categorical(categories, v) => Column::Categorical(CategoricalColumn::with_codes(categories, Buffer::from(v))),
+            Values::Vector(dim, v) => Column::Vector(VectorColumn::new(dim, v)?),
+            Values::List(offsets, items) => Column::List(ListColumn::new(offsets, items.finish()?)?),
+            Values::Record(fields, v) => Column::Record(RecordColumn::from_records(fields, &v)?)
+        };
+        if self.nulls.is_empty() {
+            return Ok(values);
+        }
+        let mut validity = BitIndex::for_col_len(len);
+        let mut nulls = self.nulls.iter().peekable();
+        for i in 0 .. len {
+            match nulls.peek() {
+                Some(null) if **null == i => { nulls.next(); },
+                _ => validity.set(i)
+            }
+        }
+        Ok(Column::Nullable(NullableColumn::new(values, validity)?))
+    }
+}
+
+fn push_native<T: Native>(b: &mut PrimitiveBuilder<T>, val: Scalar) -> Result<(), VMError> {
+    b.push(T::from_scalar(&val).ok_or_else(|| expected(T::DESCRIPTION, &val))?);
+    Ok(())
+}
+
+fn expected(what: &str, val: &Scalar) -> VMError {
+    VMError::TypeError(format!("Expected {}, got: {:?}", what, val))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+
+    #[test]
+    fn typed_builders_finish_into_their_columns() {
+        let mut nums = NumColumnBuilder::with_capacity(2);
+        nums.push(1.5);
+        nums.push(-2.0);
+        assert_eq!(Column::from(nums.finish()), Column::from(vec![1.5, -2.0]));
+
+        let mut strs = InlineStrColumnBuilder::new();
+        ["", "abcdef", "ab"].iter().for_each(|s| strs.push(s));
+        let strs = strs.finish();
+        assert_eq!(strs, InlineStrColumn::from_strs(vec!["", "abcdef", "ab"]));
+        // prefixes are filled in, so filters work on a built column
+        assert_eq!(Column::InlineStr(strs).filter(Scalar::Str("abcdef".to_string())).unwrap().count_ones(), 1);
+
+        let mut bools = BoolColumnBuilder::new();
+        [true, false, true].iter().for_each(|x| bools.push(*x));
+        assert_eq!(Column::Bool(bools.finish()), Column::from(vec![true, false, true]));
+    }
+
+    #[test]
+    fn any_builder_takes_scalars_and_nulls() {
+        let mut b = AnyColumnBuilder::new(Datatype::Int).unwrap();
+        b.push(Scalar::Int(3)).unwrap();
+        assert!(matches!(b.push(Scalar::Num(1.0)), Err(VMError::TypeError(_))));
+        b.push(Scalar::Null).unwrap();
+        b.push(Scalar::Int(-1)).unwrap();
+        let col = b.finish().unwrap();
+        assert!(matches!(col, Column::Nullable(_)));
+        assert_eq!((0 .. 3).map(|i| col.get(i).unwrap()).collect::<Vec<_>>(), vec![Scalar::Int(3), Scalar::Null, Scalar::Int(-1)]);
+
+        // no nulls, no NullableColumn
+        let mut b = AnyColumnBuilder::new(Datatype::Str).unwrap();
+        b.push(Scalar::Str("x".to_string())).unwrap();
+        assert_eq!(b.finish().unwrap(), Column::from(vec!["x"]));
+        assert!(AnyColumnBuilder::new(Datatype::Vector).is_err());
+    }
+
+    #[test]
+    fn any_builder_builds_the_nested_types() {
+        let field = Field::categorical("size", Categories::new(&["S", "M", "L"]).unwrap());
+        let mut sizes = AnyColumnBuilder::for_field(&field).unwrap();
+        sizes.push(Scalar::Str("L".to_string())).unwrap();
+        assert!(sizes.push(Scalar::Str("XL".to_string())).is_err());
+        assert_eq!((sizes.len(), sizes.finish().unwrap().get(0)), (1, Some(Scalar::Str("L".to_string()))));
+
+        let mut lists = AnyColumnBuilder::list(AnyColumnBuilder::new(Datatype::Entity).unwrap());
+        lists.push(Scalar::List(vec![Scalar::Entity(1), Scalar::Entity(2)])).unwrap();
+        // a bad item leaves the earlier ones alone
+        assert!(lists.push(Scalar::List(vec![Scalar::Entity(3), Scalar::Bool(true)])).is_err());
+        lists.push(Scalar::Null).unwrap();
+        let lists = lists.finish().unwrap();
+        assert_eq!(lists.get(0), Some(Scalar::List(vec![Scalar::Entity(1), Scalar::Entity(2)])));
+        assert_eq!((lists.len(), lists.get(1)), (2, Some(Scalar::Null)));
+
+        let mut records = AnyColumnBuilder::record(vec![Datatype::Num, Datatype::Str]).unwrap();
+        records.push(Scalar::Record(vec![Scalar::Num(1.0), Scalar::Str("a".to_string())])).unwrap();
+        assert!(records.push(Scalar::Record(vec![Scalar::Num(1.0)])).is_err());
+        records.push(Scalar::Null).unwrap();
+        let records = records.finish().unwrap();
+        assert_eq!((records.datatype(), records.len(), records.get(1)), (Datatype::Record, 2, Some(Scalar::Null)));
+    }
+}
@@ -0,0 +1,453 @@
+// Compiled programs as bytes, so a program can be compiled once, stored, and shipped to a VM
+// elsewhere. The format is a magic number and a version, then the program: its op count and
+// each op as a tag byte followed by its operands. Operands such as column ids and limits are
+// 8-byte words; counts, strings and datatypes are as in storage.rs. A subquery is a program of
+// its own, nested in place, and a literal is a scalar tag and its value, laid out as a
+// column's rows are.
+//
+// Decoding checks as it goes: a truncated, corrupt or newer program is an error, never a panic
+// or an allocation sized by whatever the bytes say. Subqueries may nest MAX_DEPTH deep, and so
+// may the records and lists in each literal, counted apart; encoding refuses anything deeper,
+// so every program that encodes decodes.
+
+use crate::column::Scalar;
+use crate::duration::Nanos;
+use crate::errors::VMError;
+use crate::geo::Point;
+use crate::ip::{Ipv4Addr, Ipv6Addr};
+use crate::opcode::Op;
+use crate::storage::{self, invalid};
+use crate::timestamp::Micros;
+use crate::vector::Metric;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"COLLIEBC";
+const FORMAT_VERSION: u32 = 1;
+
+// How deeply subqueries may nest, and records and lists within a literal, so corrupt input
+// can't overflow the stack
+const MAX_DEPTH: usize = 64;
+
+pub fn encode(code: &[Op]) -> Result<Vec<u8>, VMError> {
+    let mut buf = Vec::new();
+    write_program(&mut buf, code).map_err(VMError::Io)?;
+    Ok(buf)
+}
+
+// The program in `bytes`, which must hold nothing else
+pub fn decode(bytes: &[u8]) -> Result<Vec<Op>, VMError> {
+    let mut r = bytes;
+    let code = read_program(&mut r).map_err(VMError::Io)?;
+    if !r.is_empty() {
+        return Err(VMError::Io(invalid(format!("{} bytes after the program", r.len()))));
+    }
+    Ok(code)
+}
+
+pub fn write_program<W: Write>(w: &mut W, code: &[Op]) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    storage::write_u32(w, FORMAT_VERSION)?;
+    write_ops(w, code, 0)
+}
+
+pub fn read_program<R: Read>(r: &mut R) -> io::Result<Vec<Op>> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a collie program".to_string()));
+    }
+    let version = storage::read_u32(r)?;
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(invalid(format!("program format version {}, expected {}", version, FORMAT_VERSION)));
+    }
+    read_ops(r, 0)
+}
+
+fn write_ops<W: Write>(w: &mut W, code: &[Op], depth: usize) -> io::Result<()> {
+    if depth > MAX_DEPTH {
+        return Err(invalid(format!("subqueries nested more than {} deep", MAX_DEPTH)));
+    }
+    storage::write_len(w, code.len())?;
+    code.iter().try_for_each(|op| write_op(w, op, depth))
+}
+
+fn read_ops<R: Read>(r: &mut R, depth: usize) -> io::Result<Vec<Op>> {
+    if depth > MAX_DEPTH {
+        return Err(invalid(format!("subqueries nested more than {} deep", MAX_DEPTH)));
+    }
+    let len = storage::read_u32(r)?;
+    // grown as ops arrive, as in storage::read_str
+    let mut code = Vec::new();
+    for _ in 0 .. len {
+        code.push(read_op(r, depth)?);
+    }
+    Ok(code)
+}
+
+fn write_op<W: Write>(w: &mut W, op: &Op, depth: usize) -> io::Result<()> {
+    w.write_all(&[op_tag(op)])?;
+    match op {
+        Op::Lit(s) => write_scalar(w, s, 0),
+        Op::Col(t, c) | Op::CallUdf(t, c) | Op::CallUdaf(t, c) => {
+            write_usize(w, *t)?;
+            write_usize(w, *c)
+        },
+        Op::ColByName(name) => storage::write_str(w, name),
+        Op::Select(n) | Op::Field(n) | Op::GroupBy(n) | Op::Limit(n) | Op::TopK(n) | Op::Shared(n) => write_usize(w, *n),
+        Op::ScalarSubquery(code) => write_ops(w, code, depth + 1),
+        Op::JsonExtract(path, dtype) => {
+            storage::write_str(w, path)?;
+            w.write_all(&[storage::datatype_tag(*dtype)])
+        },
+        Op::FilterInCidr(prefix) => w.write_all(&[*prefix]),
+        Op::VectorDistance(metric) => w.write_all(&[metric_tag(*metric)]),
+        Op::Nearest(k, metric) => {
+            write_usize(w, *k)?;
+            w.write_all(&[metric_tag(*metric)])
+        },
+        _ => Ok(())
+    }
+}
+
+fn read_op<R: Read>(r: &mut R, depth: usize) -> io::Result<Op> {
+    Ok(match read_u8(r)? {
+        0 => Op::Lit(read_scalar(r, 0)?),
+        1 => Op::Col(read_usize(r)?, read_usize(r)?),
+        2 => Op::ColByName(storage::read_str(r)?),
+        3 => Op::Select(read_usize(r)?),
+        4 => Op::FilterEq,
+        5 => Op::FilterLt,
+        6 => Op::FilterLe,
+        7 => Op::FilterGt,
+        8 => Op::FilterGe,
+        9 => Op::FilterNe,
+        10 => Op::And,
+        11 => Op::Or,
+        12 => Op::Not,
+        13 => Op::FilterSelect,
+        14 => Op::FilterIn,
+        15 => Op::ScalarSubquery(read_ops(r, depth + 1)?),
+        16 => Op::CallUdf(read_usize(r)?, read_usize(r)?),
+        17 => Op::CallUdaf(read_usize(r)?, read_usize(r)?),
+        18 => Op::Field(read_usize(r)?),
+        19 => Op::FilterWithinBBox,
+        20 => Op::DistanceTo,
+        21 => Op::JsonExtract(storage::read_str(r)?, storage::datatype_of_tag(read_u8(r)?)?),
+        22 => Op::FilterInCidr(read_u8(r)?),
+        23 => Op::IfElse,
+        24 => Op::VectorDistance(read_metric(r)?),
+        25 => Op::Nearest(read_usize(r)?, read_metric(r)?),
+        26 => Op::ListLen,
+        27 => Op::FilterContains,
+        28 => Op::Sum,
+        29 => Op::Count,
+        30 => Op::Min,
+        31 => Op::Max,
+        32 => Op::Mean,
+        33 => Op::GroupBy(read_usize(r)?),
+        34 => Op::HashJoin,
+        35 => Op::ArgSort,
+        36 => Op::SortBy,
+        37 => Op::Take,
+        38 => Op::Limit(read_usize(r)?),
+        39 => Op::TopK(read_usize(r)?),
+        40 => Op::Shared(read_usize(r)?),
+        41 => Op::AddVs,
+        42 => Op::SubVs,
+        43 => Op::MulVs,
+        44 => Op::DivVs,
+        45 => Op::AddVv,
+        46 => Op::SubVv,
+        47 => Op::MulVv,
+        48 => Op::DivVv,
+        tag => return Err(invalid(format!("unknown op tag {}", tag)))
+    })
+}
+
+// In declaration order; a tag, once given out, keeps its meaning
+fn op_tag(op: &Op) -> u8 {
+    match op {
+        Op::Lit(_) => 0,
+        Op::Col(..) => 1,
+        Op::ColByName(_) => 2,
+        Op::Select(_) => 3,
+        Op::FilterEq => 4,
+        Op::FilterLt => 5,
+        Op::FilterLe => 6,
+        Op::FilterGt => 7,
+        Op::FilterGe => 8,
+        Op::FilterNe => 9,
+        Op::And => 10,
+        Op::Or => 11,
+        Op::Not => 12,
+        Op::FilterSelect => 13,
+        Op::FilterIn => 14,
+        Op::ScalarSubquery(_) => 15,
+        Op::CallUdf(..) => 16,
+        Op::CallUdaf(..) => 17,
+        Op::Field(_) => 18,
+        Op::FilterWithinBBox => 19,
+        Op::DistanceTo => 20,
+        Op::JsonExtract(..) => 21,
+        Op::FilterInCidr(_) => 22,
+        Op::IfElse => 23,
+        Op::VectorDistance(_) => 24,
+        Op::Nearest(..) => 25,
+        Op::ListLen => 26,
+        Op::FilterContains => 27,
+        Op::Sum => 28,
+        Op::Count => 29,
+        Op::Min => 30,
+        Op::Max => 31,
+        Op::Mean => 32,
+        Op::GroupBy(_) => 33,
+        Op::HashJoin => 34,
+        Op::ArgSort => 35,
+        Op::SortBy => 36,
+        Op::Take => 37,
+        Op::Limit(_) => 38,
+        Op::TopK(_) => 39,
+        Op::Shared(_) => 40,
+        Op::AddVs => 41,
+        Op::SubVs => 42,
+        Op::MulVs => 43,
+        Op::DivVs => 44,
+        Op::AddVv => 45,
+        Op::SubVv => 46,
+        Op::MulVv => 47,
+        Op::DivVv => 48
+    }
+}
+
+fn write_scalar<W: Write>(w: &mut W, s: &Scalar, depth: usize) -> io::Result<()> {
+    if depth > MAX_DEPTH {
+        return Err(invalid(format!("values nested more than {} deep", MAX_DEPTH)));
+    }
+    match s {
+        Scalar::Bool(x) => w.write_all(&[0, *x as u8]),
+        Scalar::Num(x) => {
+            w.write_all(&[1])?;
+            storage::write_u64(w, x.to_bits())
+        },
+        Scalar::Int(x) => {
+            w.write_all(&[2])?;
+            storage::write_u64(w, *x as u64)
+        },
+        Scalar::Str(x) => {
+            w.write_all(&[3])?;
+            storage::write_str(w, x)
+        },
+        Scalar::Entity(x) => {
+            w.write_all(&[4])?;
+            storage::write_u64(w, *x)
+        },
+        Scalar::Duration(x) => {
+            w.write_all(&[5])?;
+            storage::write_u64(w, x.0 as u64)
+        },
+        Scalar::Timestamp(x) => {
+            w.write_all(&[6])?;
+            storage::write_u64(w, x.0 as u64)
+        },
+        Scalar::Point(p) => {
+            w.write_all(&[7])?;
+            storage::write_u64(w, p.x.to_bits())?;
+            storage::write_u64(w, p.y.to_bits())
+        },
+        Scalar::Ipv4(x) => {
+            w.write_all(&[8])?;
+            w.write_all(&x.octets())
+        },
+        Scalar::Ipv6(x) => {
+            w.write_all(&[9])?;
+            w.write_all(&x.octets())
+        },
+        Scalar::Json(x) => {
+            w.write_all(&[10])?;
+            storage::write_str(w, x)
+        },
+        Scalar::Record(fields) => {
+            w.write_all(&[11])?;
+            storage::write_len(w, fields.len())?;
+            fields.iter().try_for_each(|f| write_scalar(w, f, depth + 1))
+        },
+        Scalar::Vector(xs) => {
+            w.write_all(&[12])?;
+            storage::write_len(w, xs.len())?;
+            xs.iter().try_for_each(|x| storage::write_u32(w, x.to_bits()))
+        },
+        Scalar::List(items) => {
+            w.write_all(&[13])?;
+            storage::write_len(w, items.len())?;
+            items.iter().try_for_each(|i| write_scalar(w, i, depth + 1))
+        },
+        Scalar::Null => w.write_all(&[14])
+    }
+}
+
+fn read_scalar<R: Read>(r: &mut R, depth: usize) -> io::Result<Scalar> {
+    if depth > MAX_DEPTH {
+        return Err(invalid(format!("values nested more than {} deep", MAX_DEPTH)));
+    }
+    Ok(match read_u8(r)? {
+        0 => Scalar::Bool(read_u8(r)? != 0),
+        1 => Scalar::Num(f64::from_bits(storage::read_u64(r)?)),
+        2 => Scalar::Int(storage::read_u64(r)? as i64),
+        3 => Scalar::Str(storage::read_str(r)?),
+        4 => Scalar::Entity(storage::read_u64(r)?),
+        5 => Scalar::Duration(Nanos(storage::read_u64(r)? as i64)),
+        6 => Scalar::Timestamp(Micros(storage::read_u64(r)? as i64)),
+        7 => Scalar::Point(Point::new(f64::from_bits(storage::read_u64(r)?), f64::from_bits(storage::read_u64(r)?))),
+        8 => {
+            let mut b = [0; 4];
+            r.read_exact(&mut b)?;
+            Scalar::Ipv4(Ipv4Addr::from(b))
+        },
+        9 => {
+            let mut b = [0; 16];
+            r.read_exact(&mut b)?;
+            Scalar::Ipv6(Ipv6Addr::from(b))
+        },
+        10 => Scalar::Json(storage::read_str(r)?),
+        11 => Scalar::Record(read_scalars(r, depth)?),
+        12 => {
+            let len = storage::read_u32(r)?;
+            let mut xs = Vec::new();
+            for _ in 0 .. len {
+                xs.push(f32::from_bits(storage::read_u32(r)?));
+            }
+            Scalar::Vector(xs)
+        },
+        13 => Scalar::List(read_scalars(r, depth)?),
+        14 => Scalar::Null,
+        tag => return Err(invalid(format!("unknown scalar tag {}", tag)))
+    })
+}
+
+fn read_scalars<R: Read>(r: &mut R, depth: usize) -> io::Result<Vec<Scalar>> {
+    let len = storage::read_u32(r)?;
+    let mut items = Vec::new();
+    for _ in 0 .. len {
+        items.push(read_scalar(r, depth + 1)?);
+    }
+    Ok(items)
+}
+
+fn metric_tag(metric: Metric) -> u8 {
+    match metric {
+        Metric::Cosine => 0,
+        Metric::L2 => 1
+    }
+}
+
+fn read_metric<R: Read>(r: &mut R) -> io::Result<Metric> {
+    match read_u8(r)? {
+        0 => Ok(Metric::Cosine),
+        1 => Ok(Metric::L2),
+        tag => Err(invalid(format!("unknown distance metric tag {}", tag)))
+    }
+}
+
+fn write_usize<W: Write>(w: &mut W, x: usize) -> io::Result<()> {
+    storage::write_u64(w, x as u64)
+}
+
+// One written on a 64-bit machine may not fit on a 32-bit one
+fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    let x = storage::read_u64(r)?;
+    usize::try_from(x).map_err(|_| invalid(format!("{} is too large for this machine", x)))
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut b = [0; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Column;
+    use crate::schema::Datatype;
+    use crate::table::Table;
+    use crate::vm::VM;
+
+    // Every op, and every kind of literal
+    fn program() -> Vec<Op> {
+        let scalars = vec![
+            Scalar::Bool(true), Scalar::Num(-1.5), Scalar::Int(i64::MIN), Scalar::Str("h\u{e9}llo".to_string()),
+            Scalar::Entity(u64::MAX), Scalar::Duration(Nanos(-5)), Scalar::Timestamp(Micros(1_709_296_200_000_000)),
+            Scalar::Point(Point::new(1.0, -2.0)), Scalar::Ipv4(Ipv4Addr::from([10, 0, 0, 1])), Scalar::Ipv6(Ipv6Addr::from([0xfe; 16])),
+            Scalar::Json("{\"a\": 1}".to_string()), Scalar::Record(vec![Scalar::Num(1.0), Scalar::Null]),
+            Scalar::Vector(vec![0.5, -0.0]), Scalar::List(vec![Scalar::List(vec![Scalar::Str("x".to_string())])]), Scalar::Null
+        ];
+        let mut code: Vec<Op> = scalars.into_iter().map(Op::Lit).collect();
+        code.extend(vec![
+            Op::Col(1, 2), Op::ColByName("t.x".to_string()), Op::Select(1), Op::FilterEq, Op::FilterLt, Op::FilterLe,
+            Op::FilterGt, Op::FilterGe, Op::FilterNe, Op::And, Op::Or, Op::Not, Op::FilterSelect, Op::FilterIn,
+            Op::ScalarSubquery(vec![Op::Col(0, 0), Op::ScalarSubquery(vec![]), Op::Sum]), Op::CallUdf(3, 2), Op::CallUdaf(4, 1),
+            Op::Field(5), Op::FilterWithinBBox, Op::DistanceTo, Op::JsonExtract("a.b".to_string(), Datatype::Int),
+            Op::FilterInCidr(24), Op::IfElse, Op::VectorDistance(Metric::L2), Op::Nearest(10, Metric::Cosine),
+            Op::ListLen, Op::FilterContains, Op::Sum, Op::Count, Op::Min, Op::Max, Op::Mean, Op::GroupBy(6),
+            Op::HashJoin, Op::ArgSort, Op::SortBy, Op::Take, Op::Limit(7), Op::TopK(8), Op::Shared(9),
+            Op::AddVs, Op::SubVs, Op::MulVs, Op::DivVs, Op::AddVv, Op::SubVv, Op::MulVv, Op::DivVv
+        ]);
+        code
+    }
+
+    #[test]
+    fn programs_round_trip() {
+        let code = program();
+        assert_eq!(decode(&encode(&code).unwrap()).unwrap(), code);
+        assert_eq!(decode(&encode(&[]).unwrap()).unwrap(), vec![]);
+        // and run where they're decoded
+        let bytes = encode(&[Op::Col(0, 0), Op::Lit(Scalar::Num(2.0)), Op::FilterGt, Op::Col(0, 0), Op::Select(1), Op::Sum]).unwrap();
+        let mut vm = VM::new(Table::from_columns(vec![Column::from(vec![1.0, 3.0, 5.0])]).unwrap());
+        vm.run(decode(&bytes).unwrap()).unwrap();
+        assert!(matches!(vm.take_result().unwrap(), crate::vm::Value::Scalar(Scalar::Num(x)) if x == 8.0));
+    }
+
+    #[test]
+    fn rejects_corrupt_programs() {
+        let buf = encode(&program()).unwrap();
+        for len in 0 .. buf.len() {
+            assert!(decode(&buf[.. len]).is_err(), "cut to {} bytes", len);
+        }
+        let mut bad = buf.clone();
+        bad[0] = b'X';
+        assert!(decode(&bad).is_err());
+        let mut bad = buf.clone();
+        bad[8 .. 12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(decode(&bad).is_err());
+        let mut bad = buf.clone();
+        bad.push(0);
+        assert!(decode(&bad).is_err());
+        // an op tag past the last
+        let mut bad = encode(&[Op::Sum]).unwrap();
+        *bad.last_mut().unwrap() = 200;
+        assert!(matches!(decode(&bad), Err(VMError::Io(_))));
+    }
+
+    #[test]
+    fn nesting_is_limited_both_ways() {
+        let nest = |n| (0 .. n).fold(vec![], |code, _| vec![Op::ScalarSubquery(code)]);
+        let list = |n| (0 .. n).fold(Scalar::Null, |s, _| Scalar::List(vec![s]));
+        assert!(matches!(encode(&nest(MAX_DEPTH + 1)), Err(VMError::Io(_))));
+        assert!(matches!(encode(&[Op::Lit(list(MAX_DEPTH + 1))]), Err(VMError::Io(_))));
+        // a literal's nesting counts apart from the subqueries it's in
+        let deepest = (0 .. MAX_DEPTH).fold(vec![Op::Lit(list(MAX_DEPTH))], |code, _| vec![Op::ScalarSubquery(code)]);
+        assert_eq!(decode(&encode(&deepest).unwrap()).unwrap(), deepest);
+
+        // and decoding turns away what encoding wouldn't make
+        let mut bytes = encode(&[Op::Lit(list(MAX_DEPTH))]).unwrap();
+        let at = bytes.len() - 1;
+        bytes.splice(at .. at, [13, 1, 0, 0, 0]);
+        assert!(matches!(decode(&bytes), Err(VMError::Io(e)) if e.to_string().contains("nested")));
+        let mut bytes = encode(&nest(MAX_DEPTH)).unwrap();
+        let at = bytes.len() - 4;
+        bytes.splice(at .. at, [1, 0, 0, 0, 15]);
+        assert!(matches!(decode(&bytes), Err(VMError::Io(e)) if e.to_string().contains("nested")));
+    }
+}
@@ -0,0 +1,232 @@
+// Caching query results. A result is keyed by the program that produced it, the names given
+// to its columns, and the table and table version it ran against, so re-running a query
+// returns the stored ResultSet until the table changes - a commit makes a new version, and
+// the next run misses. Results for a version older than the table's latest can never be
+// hit again, and are dropped as soon as a newer one is cached. Hits and misses are counted
+// here, and also in a Metrics if one is attached.
+
+use crate::errors::VMError;
+use crate::metrics::Metrics;
+use crate::opcode::Op;
+use crate::result::ResultSet;
+use crate::shared::{SharedTable, Version};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    program: u64,       // hash of the code and names; checked against the entry on a hit
+    table: String,
+    version: u64
+}
+
+struct Entry {
+    code: Vec<Op>,
+    names: Vec<String>,
+    result: Arc<ResultSet>
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    order: VecDeque<Key>        // oldest first, for eviction
+}
+
+pub struct ResultCache {
+    capacity: usize,            // results kept at most
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    metrics: Option<Arc<Metrics>>
+}
+
+fn program_hash(code: &[Op], names: &[String]) -> u64 {
+    let mut h = DefaultHasher::new();
+    code.hash(&mut h);
+    names.hash(&mut h);
+    h.finish()
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize) -> Self {
+        ResultCache { capacity, entries: Mutex::new(Entries::default()), hits: AtomicU64::new(0), misses: AtomicU64::new(0), metrics: None }
+    }
+
+    // Count lookups in `metrics` too, e.g. for a metrics::serve endpoint
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_cache_lookup(hit);
+        }
+    }
+
+    // The result of `code` over the latest version of `table` (named `table_name`), from the
+    // cache if it's there. Queries run without holding the cache, so concurrent misses on the
+    // same query may each run it.
+    pub fn get_or_run(&self, table_name: &str, table: &SharedTable, code: &[Op], names: &[String]) -> Result<Arc<ResultSet>, VMError> {
+        Ok(self.get_or_run_version(table_name, &table.snapshot(), code, names)?.0)
+    }
+
+    // get_or_run, over `version` of the table, and whether it was a hit
+    pub(crate) fn get_or_run_version(&self, table_name: &str, version: &Version, code: &[Op], names: &[String]) -> Result<(Arc<ResultSet>, bool), VMError> {
+        let key = Key { program: program_hash(code, names), table: table_name.to_string(), version: version.number };
+        if let Some(entry) = self.lock().map.get(&key) {
+            if entry.code == code && entry.names == names {
+                self.record(true);
+                return Ok((entry.result.clone(), true));
+            }
+        }
+        self.record(false);
+        let result = Arc::new(version.query(code, None, names)?);
+        self.insert(key, Entry { code: code.to_vec(), names: names.to_vec(), result: result.clone() });
+        Ok((result, false))
+    }
+
+    fn insert(&self, key: Key, entry: Entry) {
+        let mut entries = self.lock();
+        let stale = |k: &Key| k.table == key.table && k.version < key.version;
+        entries.map.retain(|k, _| !stale(k));
+        entries.order.retain(|k| !stale(k));
+        if entries.map.insert(key.clone(), entry).is_none() {
+            entries.order.push_back(key);
+        }
+        while entries.map.len() > self.capacity {
+            match entries.order.pop_front() {
+                Some(oldest) => { entries.map.remove(&oldest); },
+                None => break
+            }
+        }
+    }
+
+    // (hits, misses) so far
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        *self.lock() = Entries::default();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Column;
+    use crate::Scalar;
+
+    fn table() -> SharedTable {
+        let mut rs = ResultSet::new();
+        rs.push("id", Column::from(vec![1u64, 2, 3]));
+        rs.push("x", Column::from(vec![1.0, 2.0, 3.0]));
+        SharedTable::new(rs).unwrap()
+    }
+
+    fn pick(id: u64) -> Vec<Op> {
+        vec![Op::Col(0, 0), Op::Lit(Scalar::Entity(id)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn hits_need_the_same_code_names_and_version() {
+        let (cache, table) = (ResultCache::new(10), table());
+        let first = cache.get_or_run("t", &table, &pick(2), &names(&["x"])).unwrap();
+        crate::assert_columns_eq!(first.column("x").unwrap(), &Column::from(vec![2.0]));
+        let again = cache.get_or_run("t", &table, &pick(2), &names(&["x"])).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!(cache.stats(), (1, 1));
+
+        cache.get_or_run("t", &table, &pick(3), &names(&["x"])).unwrap();
+        cache.get_or_run("t", &table, &pick(2), &names(&["y"])).unwrap();
+        cache.get_or_run("u", &table, &pick(2), &names(&["x"])).unwrap();
+        assert_eq!((cache.stats(), cache.len()), ((1, 4), 4));
+    }
+
+    #[test]
+    fn commits_invalidate() {
+        let (cache, table) = (ResultCache::new(10), table());
+        let before = cache.get_or_run("t", &table, &pick(2), &names(&["x"])).unwrap();
+        let mut writer = table.write();
+        writer.update("x", &[1], Column::from(vec![20.0])).unwrap();
+        writer.commit().unwrap();
+
+        let after = cache.get_or_run("t", &table, &pick(2), &names(&["x"])).unwrap();
+        crate::assert_columns_eq!(before.column("x").unwrap(), &Column::from(vec![2.0]));
+        crate::assert_columns_eq!(after.column("x").unwrap(), &Column::from(vec![20.0]));
+        assert_eq!(cache.stats(), (0, 2));
+        // the result for the old version is gone
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn a_colliding_hash_with_other_code_misses() {
+        let (cache, table) = (ResultCache::new(10), table());
+        let (code, names) = (pick(2), names(&["x"]));
+        let stale = Arc::new(ResultSet::new());
+        let key = Key { program: program_hash(&code, &names), table: "t".to_string(), version: 0 };
+        cache.insert(key, Entry { code: pick(3), names: names.clone(), result: stale.clone() });
+
+        let res = cache.get_or_run("t", &table, &code, &names).unwrap();
+        assert!(!Arc::ptr_eq(&res, &stale));
+        crate::assert_columns_eq!(res.column("x").unwrap(), &Column::from(vec![2.0]));
+        assert_eq!(cache.stats(), (0, 1));
+    }
+
+    #[test]
+    fn evicts_the_oldest_past_capacity() {
+        let (cache, table) = (ResultCache::new(2), table());
+        for id in 1 ..= 3 {
+            cache.get_or_run("t", &table, &pick(id), &names(&["x"])).unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+        cache.get_or_run("t", &table, &pick(3), &names(&["x"])).unwrap();
+        cache.get_or_run("t", &table, &pick(1), &names(&["x"])).unwrap();
+        assert_eq!(cache.stats(), (1, 4));
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let (cache, table) = (ResultCache::new(10), table());
+        assert!(cache.get_or_run("t", &table, &[Op::Col(0, 5)], &[]).is_err());
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats(), (0, 1));
+    }
+
+    #[test]
+    fn reports_lookups_to_metrics() {
+        let (mut cache, table) = (ResultCache::new(10), table());
+        let metrics = Arc::new(Metrics::new());
+        cache.set_metrics(metrics.clone());
+        for _ in 0 .. 3 {
+            cache.get_or_run("t", &table, &pick(1), &names(&["x"])).unwrap();
+        }
+        let text = metrics.render();
+        assert!(text.contains("collie_cache_hits_total 2\n"), "{}", text);
+        assert!(text.contains("collie_cache_misses_total 1\n"), "{}", text);
+    }
+}
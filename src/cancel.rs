@@ -0,0 +1,29 @@
+// Stopping a query from outside. A CancelToken is shared between the VM running a query and
+// whoever may want it stopped - a UI's cancel button, a server dropping a connection; the VM
+// checks it between instructions and gives up with VMError::Cancelled once it's set.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::sync::Arc;
+
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    // Stop whatever query is checking this token. Can be called from any thread.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // Make the token usable for another query
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,128 @@
+// The tables a VM is loaded with, by name. Op::Col(t, c) loads column c of table t, the
+// tables numbered in the order they were added; Op::ColByName takes "table.column". Each
+// table's columns have its own number of rows, so a program can join and union across
+// tables of different sizes.
+
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::table::Table;
+
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    tables: Vec<(String, Table)>
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Catalog::default()
+    }
+
+    // Add `table` under `name`, which mustn't be taken, and return its id
+    pub fn add(&mut self, name: &str, table: Table) -> Result<usize, VMError> {
+        if self.table_id(name).is_some() {
+            return Err(VMError::TypeError(format!("Two tables are named '{}'", name)));
+        }
+        self.tables.push((name.to_string(), table));
+        Ok(self.tables.len() - 1)
+    }
+
+    pub fn table_id(&self, name: &str) -> Option<usize> {
+        self.tables.iter().position(|(n, _)| n == name)
+    }
+
+    pub fn table(&self, id: usize) -> Option<&Table> {
+        self.tables.get(id).map(|(_, t)| t)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Table> {
+        self.table_id(name).and_then(|id| self.table(id))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.tables.iter().map(|(n, _)| n.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &Table)> {
+        self.tables.iter().map(|(n, t)| (n.as_str(), t))
+    }
+
+    pub fn into_tables(self) -> Vec<(String, Table)> {
+        self.tables
+    }
+
+    // Number of tables
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+}
+
+// A catalog of just `table`, named t0
+impl From<Table> for Catalog {
+    fn from(table: Table) -> Catalog {
+        Catalog { tables: vec![("t0".to_string(), table)] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{Column, Scalar};
+    use crate::opcode::Op;
+    use crate::vm::{ColumnMode, VM};
+
+    use core::convert::TryFrom;
+
+    fn catalog() -> Catalog {
+        let mut catalog = Catalog::new();
+        let people = Table::new(vec![
+            ("id".to_string(), Column::from(vec![1u64, 2, 3])),
+            ("name".to_string(), Column::from(vec!["ann", "bo", "cy"]))
+        ]).unwrap();
+        let orders = Table::new(vec![
+            ("id".to_string(), Column::from(vec![10u64, 11, 12, 13, 14])),
+            ("person".to_string(), Column::from(vec![2u64, 3, 2, 1, 2]))
+        ]).unwrap();
+        assert_eq!(catalog.add("people", people).unwrap(), 0);
+        assert_eq!(catalog.add("orders", orders).unwrap(), 1);
+        catalog
+    }
+
+    #[test]
+    fn tables_are_found_by_name() {
+        let mut catalog = catalog();
+        assert_eq!((catalog.len(), catalog.table_id("orders")), (2, Some(1)));
+        assert_eq!(catalog.get("people").map(|t| t.rows()), Some(3));
+        assert_eq!(catalog.names().collect::<Vec<_>>(), vec!["people", "orders"]);
+        assert!(catalog.add("people", Table::from_columns(vec![]).unwrap()).is_err());
+        assert_eq!(Catalog::from(Table::from_columns(vec![]).unwrap()).table_id("t0"), Some(0));
+    }
+
+    #[test]
+    fn programs_join_across_tables() {
+        for mode in [ColumnMode::Rc, ColumnMode::Slots] {
+            let mut vm = VM::with_catalog(catalog(), mode);
+            // the ids of bo's orders
+            vm.run(vec![
+                Op::Col(1, 1), Op::Col(0, 1), Op::Lit(Scalar::Str("bo".to_string())), Op::FilterEq, Op::Col(0, 0), Op::Select(1),
+                Op::FilterIn, Op::ColByName("orders.id".to_string()), Op::Select(1)
+            ]).unwrap();
+            let ids = Vec::<u64>::try_from(vm.column_of(&vm.stack()[0]).unwrap()).unwrap();
+            assert_eq!(ids, vec![10, 12, 14]);
+        }
+    }
+
+    #[test]
+    fn loads_outside_the_catalog_are_errors() {
+        let mut vm = VM::with_catalog(catalog(), ColumnMode::Rc);
+        assert!(matches!(vm.run(vec![Op::Col(2, 0)]), Err(VMError::TableIndexOutOfRange { idx: 2, ntables: 2 })));
+        assert!(matches!(vm.run(vec![Op::Col(0, 2)]), Err(VMError::ColumnIndexOutOfRange { idx: 2, ncols: 2 })));
+        // an unqualified name has to be in just one table
+        assert!(vm.run(vec![Op::ColByName("person".to_string())]).is_ok());
+        assert!(matches!(vm.run(vec![Op::ColByName("id".to_string())]), Err(VMError::TypeError(_))));
+        assert!(matches!(vm.run(vec![Op::ColByName("people.person".to_string())]), Err(VMError::UnknownColumn(_))));
+    }
+}
@@ -0,0 +1,352 @@
+// Categorical strings: a column whose values come from a fixed list of categories declared
+// up front, in the schema, in an order that means something - log levels, t-shirt sizes,
+// ratings. Rows hold u32 codes like a dictionary-encoded column, but the code of a category
+// is its position in the declared list, so comparing codes compares by that order: sorting,
+// grouping, min/max and range filters go debug < info < warn < error, not alphabetically.
+//
+// Dict is an encoding, picked by the loader for any string column and invisible to queries;
+// a categorical column is a type of its own (Datatype::Categorical), and a value outside its
+// categories is an error when loading rather than a new dictionary entry.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Cmp, Column, ColumnT, Scalar};
+use crate::conditional::Branch;
+use crate::core::prelude::*;
+use crate::dict::Dictionary;
+use crate::errors::VMError;
+use crate::kernels;
+
+use alloc::sync::Arc;
+use core::cmp::Ordering;
+
+// The categories of a column, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Categories(Dictionary);
+
+impl Categories {
+    pub fn new(names: &[&str]) -> Result<Categories, VMError> {
+        let mut dict = Dictionary::new();
+        for name in names {
+            if dict.code_of(name).is_some() {
+                return Err(VMError::TypeError(format!("Category '{}' is listed twice", name)));
+            }
+            dict.intern(name);
+        }
+        Ok(Categories(dict))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Position of `name` in the order; None if it isn't a category
+    pub fn rank(&self, name: &str) -> Option<u32> {
+        self.0.code_of(name)
+    }
+
+    pub fn name(&self, rank: u32) -> &str {
+        self.0.value(rank)
+    }
+
+    pub fn names(&self) -> &[String] {
+        self.0.values()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.0.memory_usage()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CategoricalColumn {
+    categories: Arc<Categories>,
+    codes: Buffer<u32>
+}
+
+impl CategoricalColumn {
+    // Fails on a value that isn't one of the categories
+    pub fn from_strs<'a, I: IntoIterator<Item=&'a str>>(categories: Arc<Categories>, strs: I) -> Result<Self, VMError> {
+        let codes = strs.into_iter()
+            .map(|s| categories.rank(s).ok_or_else(|| not_a_category(&categories, s)))
+            .collect::<Result<_, _>>()?;
+        Ok(CategoricalColumn { categories, codes })
+    }
+
+    // `codes` must all be ranks in `categories`
+    pub fn with_codes(categories: Arc<Categories>, codes: Buffer<u32>) -> Self {
+        assert!(codes.iter().all(|c| (*c as usize) < categories.len()), "code out of range for categories");
+        CategoricalColumn { categories, codes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn categories(&self) -> &Arc<Categories> {
+        &self.categories
+    }
+
+    // Each row's rank among the categories
+    pub fn codes(&self) -> &[u32] {
+        &self.codes
+    }
+
+    pub fn value(&self, idx: usize) -> &str {
+        self.categories.name(self.codes[idx])
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Scalar> {
+        self.codes.get(idx).map(|c| Scalar::Str(self.categories.name(*c).to_string()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&str> + '_ {
+        self.codes.iter().map(move |c| self.categories.name(*c))
+    }
+
+    // Codes plus the categories, which are usually shared with other columns
+    pub fn memory_usage(&self) -> usize {
+        self.codes.memory_usage() + self.categories.memory_usage()
+    }
+
+    pub fn slice(&self, offset: usize, len: usize) -> Self {
+        CategoricalColumn { categories: self.categories.clone(), codes: self.codes.slice(offset, len) }
+    }
+
+    // One column holding the rows of all `parts`, which must have equal categories
+    pub fn concat(parts: &[&CategoricalColumn]) -> Result<Self, VMError> {
+        let categories = match parts.first() {
+            Some(first) => first.categories.clone(),
+            None => return Err(VMError::TypeError("Can't concatenate no columns".to_string()))
+        };
+        if parts.iter().any(|c| c.categories != categories) {
+            return Err(VMError::TypeError("Can't concatenate categorical columns with different categories".to_string()));
+        }
+        let mut codes = Buffer::with_capacity(parts.iter().map(|c| c.len()).sum());
+        parts.iter().for_each(|c| codes.extend_from_slice(&c.codes));
+        Ok(CategoricalColumn { categories, codes })
+    }
+
+    // The rank of the category named by `val`
+    fn rank_of(&self, val: &Scalar) -> Result<u32, VMError> {
+        match val {
+            Scalar::Str(x) => self.categories.rank(x).ok_or_else(|| not_a_category(&self.categories, x)),
+            _ => Err(VMError::TypeError(format!("Expected a category name, got: {:?}", val)))
+        }
+    }
+
+    // Rows whose value compares to `val`, in category order, in a way `keep` accepts: for
+    // `severity >= 'warn'`, keep Greater and Equal. `val` must be one of the categories.
+    pub fn filter_cmp<F: Fn(Ordering) -> bool>(&self, val: &Scalar, keep: F) -> Result<BoolColumn, VMError> {
+        let rank = self.rank_of(val)?;
+        Ok(BoolColumn::from_mask(kernels::mask_by(&self.codes, |c| keep(c.cmp(&rank)))))
+    }
+
+    // lo <= x < hi, in category order
+    pub fn filter_range(&self, lo: &Scalar, hi: &Scalar) -> Result<BoolColumn, VMError> {
+        let (lo, hi) = (self.rank_of(lo)?, self.rank_of(hi)?);
+        Ok(BoolColumn::from_mask(kernels::mask_by(&self.codes, |c| lo <= c && c < hi)))
+    }
+
+    // Row rows[i], for each i
+    pub fn take(&self, rows: &[usize]) -> Self {
+        CategoricalColumn { categories: self.categories.clone(), codes: rows.iter().map(|i| self.codes[*i]).collect() }
+    }
+}
+
+impl ColumnT for CategoricalColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    // Matches nothing for a name that isn't one of the categories, like a string column would
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        match &val {
+            Scalar::Str(x) => {
+                let code = self.categories.rank(x).unwrap_or(self.categories.len() as u32);
+                Ok(BoolColumn::from_mask(kernels::eq_u32(&self.codes, code)))
+            },
+            _ => Err(VMError::TypeError(format!("Expected a category name, got: {:?}", val)))
+        }
+    }
+
+    fn select(&self, mask: &BoolColumn) -> Self {
+        let mut codes = Buffer::with_capacity(mask.count_ones());
+        mask.selection().for_each(|idx| codes.push(self.codes[idx]));
+        CategoricalColumn { categories: self.categories.clone(), codes }
+    }
+
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        CategoricalColumn::filter_cmp(self, &val, |o| cmp.holds(o))
+    }
+}
+
+// Equal if they hold the same values, in equal categories
+impl PartialEq for CategoricalColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.categories == other.categories && self.codes == other.codes
+    }
+}
+
+fn not_a_category(categories: &Categories, name: &str) -> VMError {
+    VMError::TypeError(format!("'{}' isn't one of the categories ({})", name, categories.names().join(", ")))
+}
+
+// A branch of if_else for a categorical column: another column with equal categories, or the
+// name of one of them
+enum Codes<'a> {
+    Values(&'a [u32]),
+    Const(u32)
+}
+
+impl<'a> Codes<'a> {
+    fn of(branch: Branch<'a>, categories: &Categories) -> Result<Self, VMError> {
+        match branch {
+            Branch::Column(Column::Categorical(c)) if *c.categories == *categories => Ok(Codes::Values(&c.codes)),
+            Branch::Scalar(Scalar::Str(x)) => categories.rank(x).map(Codes::Const).ok_or_else(|| not_a_category(categories, x)),
+            _ => Err(VMError::TypeError(format!("Expected values of the categories ({}), got: {:?}", categories.names().join(", "), branch)))
+        }
+    }
+
+    fn at(&self, i: usize) -> u32 {
+        match self {
+            Codes::Values(codes) => codes[i],
+            Codes::Const(c) => *c
+        }
+    }
+}
+
+// Per row: `then` where `cond` is set, `els` where it isn't, where at least one of them is a
+// categorical column; the result has its categories
+pub(crate) fn choose(cond: &BitIndex, then: Branch, els: Branch) -> Result<Column, VMError> {
+    let categories = match (then, els) {
+        (Branch::Column(Column::Categorical(c)), _) | (_, Branch::Column(Column::Categorical(c))) => c.categories.clone(),
+        _ => return Err(VMError::TypeError(format!("Expected a Categorical column, got: {:?} and {:?}", then, els)))
+    };
+    let (then, els) = (Codes::of(then, &categories)?, Codes::of(els, &categories)?);
+    let codes = (0 .. cond.len()).map(|i| if cond.get(i) { then.at(i) } else { els.at(i) }).collect();
+    Ok(Column::Categorical(CategoricalColumn { categories: categories.clone(), codes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::argsort;
+    use crate::conditional::if_else;
+    use crate::nulls::NullSemantics;
+    use crate::opcode::Op;
+    use crate::selection::Selection;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    fn levels() -> Arc<Categories> {
+        Arc::new(Categories::new(&["debug", "info", "warn", "error"]).unwrap())
+    }
+
+    fn col(values: &[&str]) -> CategoricalColumn {
+        CategoricalColumn::from_strs(levels(), values.iter().copied()).unwrap()
+    }
+
+    fn name(s: &str) -> Scalar {
+        Scalar::Str(s.to_string())
+    }
+
+    #[test]
+    fn categories_are_ranked_as_listed() {
+        let cats = levels();
+        assert_eq!((cats.rank("debug"), cats.rank("error"), cats.rank("fatal")), (Some(0), Some(3), None));
+        assert_eq!(cats.name(2), "warn");
+        assert_eq!(cats.names(), &["debug", "info", "warn", "error"]);
+        assert!(matches!(Categories::new(&["a", "b", "a"]), Err(VMError::TypeError(_))));
+        assert!(Categories::new(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn values_must_be_categories() {
+        let c = col(&["warn", "debug", "error"]);
+        assert_eq!(c.codes(), &[2, 0, 3]);
+        assert_eq!(c.iter().collect::<Vec<_>>(), vec!["warn", "debug", "error"]);
+        match CategoricalColumn::from_strs(levels(), vec!["info", "Info"]) {
+            Err(VMError::TypeError(msg)) => assert_eq!(msg, "'Info' isn't one of the categories (debug, info, warn, error)"),
+            res => panic!("{:?}", res)
+        }
+    }
+
+    #[test]
+    fn comparisons_follow_the_declared_order() {
+        let c = col(&["error", "debug", "warn", "info", "warn"]);
+        assert_eq!(c.filter_cmp(&name("warn"), |o| o != Ordering::Less).unwrap().selection().positions(), vec![0, 2, 4]);
+        assert_eq!(c.filter_cmp(&name("info"), |o| o == Ordering::Less).unwrap().selection().positions(), vec![1]);
+        assert_eq!(c.filter_range(&name("info"), &name("error")).unwrap().selection().positions(), vec![2, 3, 4]);
+        assert_eq!(Column::Categorical(c.clone()).filter_range(name("debug"), name("info")).unwrap().selection().positions(), vec![1]);
+        // alphabetically, "error" < "info" < "warn"
+        assert_eq!(argsort(&Column::Categorical(c.clone())), vec![1, 3, 2, 4, 0]);
+        assert!(matches!(c.filter_cmp(&name("fatal"), |o| o == Ordering::Equal), Err(VMError::TypeError(_))));
+        assert!(matches!(c.filter_range(&Scalar::Num(0.0), &name("warn")), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn equality_filters_match_names() {
+        let c = Column::Categorical(col(&["info", "warn", "info"]));
+        assert_eq!(c.filter(name("info")).unwrap().selection().positions(), vec![0, 2]);
+        // like a string column, a name that isn't a category matches nothing
+        assert!(c.filter(name("fatal")).unwrap().selection().positions().is_empty());
+        assert!(matches!(c.filter(Scalar::Num(1.0)), Err(VMError::TypeError(_))));
+        assert_eq!(c.filter_in(&Column::Categorical(col(&["warn", "error"])), NullSemantics::default()).unwrap().selection().positions(), vec![1]);
+        assert!(matches!(c.filter_in(&Column::from(vec!["warn"]), NullSemantics::default()), Err(VMError::TypeError(_))));
+
+        let mut vm = VM::new(Table::from_columns(vec![c]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Lit(name("warn")), Op::FilterEq, Op::Col(0, 0), Op::Select(1)]).unwrap();
+        crate::assert_columns_eq!(vm.column_of(vm.stack().last().unwrap()).unwrap(), &Column::Categorical(col(&["warn"])));
+    }
+
+    #[test]
+    fn selecting_and_slicing_keep_the_categories() {
+        let c = col(&["debug", "info", "warn", "error"]);
+        let picked = c.select(&c.filter_cmp(&name("info"), |o| o == Ordering::Greater).unwrap());
+        assert_eq!(picked, col(&["warn", "error"]));
+        assert!(Arc::ptr_eq(picked.categories(), c.categories()));
+        assert_eq!(c.slice(1, 2), col(&["info", "warn"]));
+        assert_eq!(c.take(&[3, 3, 0]), col(&["error", "error", "debug"]));
+        assert_eq!(c.get(3), Some(name("error")));
+        assert!(c.get(4).is_none());
+    }
+
+    #[test]
+    fn concat_needs_equal_categories() {
+        let (a, b) = (col(&["info"]), col(&["error", "debug"]));
+        assert_eq!(CategoricalColumn::concat(&[&a, &b]).unwrap(), col(&["info", "error", "debug"]));
+        // equal, not the same Arc
+        let other = CategoricalColumn::from_strs(levels(), vec!["warn"]).unwrap();
+        assert_eq!(CategoricalColumn::concat(&[&a, &other]).unwrap().len(), 2);
+        let reordered = CategoricalColumn::from_strs(Arc::new(Categories::new(&["info", "debug", "warn", "error"]).unwrap()), vec!["info"]).unwrap();
+        assert!(matches!(CategoricalColumn::concat(&[&a, &reordered]), Err(VMError::TypeError(_))));
+        // same values, but ranked differently, so not equal
+        assert_ne!(a, reordered);
+        assert!(CategoricalColumn::concat(&[]).is_err());
+    }
+
+    #[test]
+    fn if_else_takes_names_and_columns_of_the_same_categories() {
+        let cond = Selection::from_positions(vec![0, 2], 3);
+        let c = Column::Categorical(col(&["debug", "info", "warn"]));
+        let res = if_else(&cond, Branch::Scalar(&name("error")), Branch::Column(&c)).unwrap();
+        crate::assert_columns_eq!(res, Column::Categorical(col(&["error", "info", "error"])));
+        let other = Column::Categorical(col(&["info", "info", "info"]));
+        let res = if_else(&cond, Branch::Column(&c), Branch::Column(&other)).unwrap();
+        crate::assert_columns_eq!(res, Column::Categorical(col(&["debug", "info", "warn"])));
+
+        assert!(matches!(if_else(&cond, Branch::Scalar(&name("fatal")), Branch::Column(&c)), Err(VMError::TypeError(_))));
+        let sizes = Arc::new(Categories::new(&["s", "m", "l"]).unwrap());
+        let sizes = Column::Categorical(CategoricalColumn::from_strs(sizes, vec!["s", "m", "l"]).unwrap());
+        assert!(matches!(if_else(&cond, Branch::Column(&sizes), Branch::Column(&c)), Err(VMError::TypeError(_))));
+    }
+}
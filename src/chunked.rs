@@ -0,0 +1,254 @@
+// Chunked columns: a column's rows held as a list of chunks, each a Column of its own, rather
+// than one contiguous buffer. Appending fills the last chunk up to the chunk size and then
+// starts new ones, so a column grows a batch at a time without copying the rows it already
+// has - the layout for incremental ingestion, and for loads too big to reallocate as they
+// grow. Filters and selects run a chunk at a time, and chunks() hands the chunks out for
+// running a program over one batch at a time.
+//
+// Chunks are full-size as they're built, but slicing or selecting can leave some short, so a
+// row is found by binary search over where each chunk starts.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Cmp, Column, ColumnT, Scalar};
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+
+use alloc::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct ChunkedColumn {
+    chunk_rows: usize,          // the size new chunks are filled to
+    chunks: Vec<Arc<Column>>,   // never empty; only an empty column has an empty chunk
+    starts: Vec<usize>          // the first row of each chunk, then the number of rows
+}
+
+impl ChunkedColumn {
+    // The rows of `col`, in chunks of `chunk_rows` rows each. The chunks share its buffers.
+    pub fn new(col: Column, chunk_rows: usize) -> Result<ChunkedColumn, VMError> {
+        if chunk_rows == 0 {
+            return Err(VMError::TypeError("Chunks must hold at least one row".to_string()));
+        }
+        let chunks = match col.is_empty() {
+            true => vec![Arc::new(col)],
+            false => col.chunks(chunk_rows).into_iter().map(Arc::new).collect()
+        };
+        Ok(ChunkedColumn::of_chunks(chunk_rows, chunks))
+    }
+
+    fn of_chunks(chunk_rows: usize, chunks: Vec<Arc<Column>>) -> ChunkedColumn {
+        let mut starts = Vec::with_capacity(chunks.len() + 1);
+        starts.push(0);
+        chunks.iter().for_each(|c| starts.push(starts[starts.len() - 1] + c.len()));
+        ChunkedColumn { chunk_rows, chunks, starts }
+    }
+
+    // Add `rows` to the end: the last chunk is topped up, and the rest go in new chunks
+    pub fn append(&mut self, rows: &Column) -> Result<(), VMError> {
+        if rows.datatype() != self.datatype() {
+            return Err(VMError::TypeError(format!("Can't append {} rows to a {} column", rows.datatype(), self.datatype())));
+        }
+        let last = self.chunks.len() - 1;
+        let room = self.chunk_rows - self.chunks[last].len().min(self.chunk_rows);
+        let fill = room.min(rows.len());
+        if fill > 0 {
+            let topped = Column::concat(&[self.chunks[last].as_ref().clone(), rows.slice(0, fill)])?;
+            self.chunks[last] = Arc::new(topped);
+            self.starts[last + 1] += fill;
+        }
+        let rest = rows.slice(fill, rows.len() - fill);
+        for chunk in rest.chunks(self.chunk_rows) {
+            self.starts.push(self.len() + chunk.len());
+            self.chunks.push(Arc::new(chunk));
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.starts[self.starts.len() - 1]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn chunk_rows(&self) -> usize {
+        self.chunk_rows
+    }
+
+    pub fn chunks(&self) -> &[Arc<Column>] {
+        &self.chunks
+    }
+
+    pub fn datatype(&self) -> Datatype {
+        self.chunks[0].datatype()
+    }
+
+    // The chunk row i is in, which must be in bounds
+    fn chunk_of(&self, i: usize) -> usize {
+        self.starts.partition_point(|start| *start <= i) - 1
+    }
+
+    pub fn get(&self, i: usize) -> Option<Scalar> {
+        if i >= self.len() {
+            return None;
+        }
+        let chunk = self.chunk_of(i);
+        self.chunks[chunk].get(i - self.starts[chunk])
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.chunks.iter().map(|c| c.memory_usage()).sum()
+    }
+
+    // Back to one contiguous column
+    pub fn decode(&self) -> Column {
+        match self.chunks.len() {
+            1 => self.chunks[0].as_ref().clone(),
+            _ => Column::concat(&self.chunks.iter().map(|c| c.as_ref().clone()).collect::<Vec<_>>())
+                .expect("chunks of one type")
+        }
+    }
+
+    // Rows offset .. offset + len, which must be in bounds. Chunks wholly inside are shared,
+    // and the ones at either end sliced.
+    pub fn slice(&self, offset: usize, len: usize) -> ChunkedColumn {
+        if len == 0 {
+            return ChunkedColumn::of_chunks(self.chunk_rows, vec![Arc::new(self.chunks[0].slice(0, 0))]);
+        }
+        let (first, last) = (self.chunk_of(offset), self.chunk_of(offset + len - 1));
+        let chunks = (first ..= last).map(|k| {
+            let start = offset.max(self.starts[k]);
+            let end = (offset + len).min(self.starts[k + 1]);
+            match end - start == self.chunks[k].len() {
+                true => self.chunks[k].clone(),
+                false => Arc::new(self.chunks[k].slice(start - self.starts[k], end - start))
+            }
+        }).collect();
+        ChunkedColumn::of_chunks(self.chunk_rows, chunks)
+    }
+
+    // lo <= x < hi, a chunk at a time
+    pub fn filter_range(&self, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        self.mask_chunks(|c| c.filter_range(lo.clone(), hi.clone()))
+    }
+
+    // Each chunk's mask, put side by side
+    fn mask_chunks<F>(&self, mask_of: F) -> Result<BoolColumn, VMError>
+        where F: Fn(&Column) -> Result<BoolColumn, VMError> {
+
+        let mut bits = BitIndex::for_col_len(self.len());
+        for (chunk, start) in self.chunks.iter().zip(&self.starts) {
+            mask_of(chunk)?.selection().for_each(|i| bits.set(start + i));
+        }
+        Ok(BoolColumn::from_mask(bits))
+    }
+
+    // The rows of `sel` split up by chunk, counting from the start of each
+    fn split(&self, sel: &Selection) -> Vec<Vec<u32>> {
+        let mut parts = vec![Vec::new(); self.chunks.len()];
+        let mut k = 0;
+        sel.for_each(|i| {
+            while self.starts[k + 1] <= i {
+                k += 1;
+            }
+            parts[k].push((i - self.starts[k]) as u32);
+        });
+        parts
+    }
+}
+
+impl ColumnT for ChunkedColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        self.mask_chunks(|c| c.filter(val.clone()))
+    }
+
+    // Each chunk's selected rows, as a chunk of the result; chunks with none left go
+    fn select(&self, mask: &BoolColumn) -> Self {
+        let parts = self.split(mask.selection());
+        let mut chunks: Vec<Arc<Column>> = self.chunks.iter().zip(parts)
+            .filter(|(_, rows)| !rows.is_empty())
+            .map(|(chunk, rows)| match rows.len() == chunk.len() {
+                true => chunk.clone(),
+                false => Arc::new(chunk.select(&BoolColumn::from_selection(Selection::from_positions(rows, chunk.len()))))
+            })
+            .collect();
+        if chunks.is_empty() {
+            chunks.push(Arc::new(self.chunks[0].slice(0, 0)));
+        }
+        ChunkedColumn::of_chunks(self.chunk_rows, chunks)
+    }
+
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        self.mask_chunks(|c| c.filter_cmp(cmp, val.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+    use core::convert::TryFrom;
+
+    fn lens(col: &ChunkedColumn) -> Vec<usize> {
+        col.chunks().iter().map(|c| c.len()).collect()
+    }
+
+    #[test]
+    fn appends_fill_the_last_chunk_then_start_new_ones() {
+        let mut col = ChunkedColumn::new(Column::from(vec![0u64, 1, 2, 3, 4]), 4).unwrap();
+        assert_eq!(lens(&col), vec![4, 1]);
+        col.append(&Column::from(vec![5u64, 6, 7, 8, 9, 10, 11, 12])).unwrap();
+        assert_eq!(lens(&col), vec![4, 4, 4, 1]);
+        assert_eq!(col.decode(), Column::from((0 .. 13).collect::<Vec<u64>>()));
+        assert_eq!((col.len(), col.get(9), col.get(13)), (13, Some(Scalar::Entity(9)), None));
+        assert!(matches!(col.append(&Column::from(vec![1.0])), Err(VMError::TypeError(_))));
+        // an empty column takes rows like any other
+        let mut empty = ChunkedColumn::new(Column::from(Vec::<f64>::new()), 2).unwrap();
+        empty.append(&Column::from(vec![1.0, 2.0, 3.0])).unwrap();
+        assert_eq!(lens(&empty), vec![2, 1]);
+        assert!(ChunkedColumn::new(Column::from(vec![1.0]), 0).is_err());
+    }
+
+    #[test]
+    fn filters_and_selects_a_chunk_at_a_time() {
+        let values: Vec<f64> = (0 .. 10).map(|x| (x % 3) as f64).collect();
+        let col = ChunkedColumn::new(Column::from(values.clone()), 4).unwrap();
+        let plain = Column::from(values);
+        assert_eq!(col.filter(Scalar::Num(1.0)).unwrap().selection().positions(), plain.filter(Scalar::Num(1.0)).unwrap().selection().positions());
+        assert_eq!(col.filter_cmp(Cmp::Gt, Scalar::Num(0.0)).unwrap().selection().positions(), plain.filter_cmp(Cmp::Gt, Scalar::Num(0.0)).unwrap().selection().positions());
+        assert_eq!(col.filter_range(Scalar::Num(1.0), Scalar::Num(2.0)).unwrap().selection().positions(), vec![1, 4, 7]);
+        let mask = col.filter(Scalar::Num(2.0)).unwrap();
+        let picked = col.select(&mask);
+        assert_eq!((picked.decode(), lens(&picked)), (Column::from(vec![2.0, 2.0, 2.0]), vec![1, 1, 1]));
+        let none = col.select(&col.filter(Scalar::Num(9.0)).unwrap());
+        assert_eq!((none.len(), none.datatype()), (0, Datatype::Num));
+    }
+
+    #[test]
+    fn slices_share_the_chunks_inside() {
+        let col = ChunkedColumn::new(Column::from((0 .. 10).collect::<Vec<u64>>()), 4).unwrap();
+        let mid = col.slice(3, 6);
+        assert_eq!(lens(&mid), vec![1, 4, 1]);
+        assert!(Arc::ptr_eq(&mid.chunks()[1], &col.chunks()[1]));
+        assert_eq!(mid.decode(), Column::from(vec![3u64, 4, 5, 6, 7, 8]));
+        assert_eq!(mid.slice(1, 4).get(0), Some(Scalar::Entity(4)));
+        assert_eq!(col.slice(10, 0).len(), 0);
+    }
+
+    #[test]
+    fn the_vm_runs_over_chunked_columns() {
+        let prices = ChunkedColumn::new(Column::from(vec![5.0, 12.0, 7.0, 30.0, 1.0]), 2).unwrap();
+        let ids = ChunkedColumn::new(Column::from(vec![10u64, 11, 12, 13, 14]), 2).unwrap();
+        let mut vm = VM::new(Table::from_columns(vec![Column::Chunked(prices), Column::Chunked(ids)]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Num(6.0)), Op::FilterGt, Op::Col(0, 1), Op::Select(1)]).unwrap();
+        assert_eq!(Vec::<u64>::try_from(vm.column_of(vm.stack().last().unwrap()).unwrap()).unwrap(), vec![11, 12, 13]);
+    }
+}
@@ -0,0 +1,171 @@
+// Collations: orders for strings other than bytewise. Byte order puts "Zoe" before "adam"
+// and "Émile" after "zoe", which is what an index wants but rarely what a person reading a
+// sorted list of names expects. A Collation is passed to whatever orders strings - sorting,
+// min/max (see compare.rs) and range filters - and Binary, the default, is the byte order
+// those use on their own.
+//
+// Collations only order; equality (FilterEq, joins, grouping) stays bytewise.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, Scalar};
+use crate::core::prelude::*;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::kernels;
+
+use core::cmp::Ordering;
+use core::fmt;
+
+#[cfg(feature = "icu")]
+use alloc::sync::Arc;
+
+#[derive(Clone, Default)]
+pub enum Collation {
+    #[default]
+    Binary,
+    // by the lowercase form of each character, so "apple" and "Apple" are equal and both come
+    // before "Banana"
+    CaseInsensitive,
+    // the rules of a locale, as ICU has them - accents, case and punctuation weighed the way
+    // its speakers expect
+    #[cfg(feature = "icu")]
+    Locale(String, Arc<icu_collator::Collator>)
+}
+
+impl Collation {
+    // The collation of a BCP 47 locale such as "en", "de-DE" or "sv"
+    #[cfg(feature = "icu")]
+    pub fn locale(tag: &str) -> Result<Collation, VMError> {
+        let locale: icu_locid::Locale = tag.parse()
+            .map_err(|e| VMError::TypeError(format!("Not a locale: {} ({:?})", tag, e)))?;
+        let collator = icu_collator::Collator::try_new(&(&locale).into(), Default::default())
+            .map_err(|e| VMError::TypeError(format!("No collation for locale {} ({:?})", tag, e)))?;
+        Ok(Collation::Locale(tag.to_string(), Arc::new(collator)))
+    }
+
+    pub fn cmp(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            Collation::CaseInsensitive => a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase)),
+            #[cfg(feature = "icu")]
+            Collation::Locale(_, collator) => collator.compare(a, b)
+        }
+    }
+}
+
+impl fmt::Debug for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Collation::Binary => write!(f, "Binary"),
+            Collation::CaseInsensitive => write!(f, "CaseInsensitive"),
+            #[cfg(feature = "icu")]
+            Collation::Locale(tag, _) => write!(f, "Locale({:?})", tag)
+        }
+    }
+}
+
+// lo <= x < hi under `collation`, for a string column. Dict columns compare each dictionary
+// value once, rather than once per row.
+pub fn filter_range(col: &Column, lo: &Scalar, hi: &Scalar, collation: &Collation) -> Result<BoolColumn, VMError> {
+    let (lo, hi) = match (lo, hi) {
+        (Scalar::Str(lo), Scalar::Str(hi)) => (lo.as_str(), hi.as_str()),
+        _ => return Err(VMError::TypeError(format!("Expected Str bounds, got: {:?} and {:?}", lo, hi)))
+    };
+    let in_range = |x: &str| collation.cmp(lo, x) != Ordering::Greater && collation.cmp(x, hi) == Ordering::Less;
+    let mask = match col {
+        Column::Dict(c) => {
+            let hits: Vec<bool> = c.dictionary().values().iter().map(|v| in_range(v)).collect();
+            kernels::mask_by(c.codes(), |code| hits[code as usize])
+        },
+        _ => {
+            let col = encoding::plain(col);
+            let values: Box<dyn Iterator<Item=&str>> = match col.as_ref() {
+                Column::Str(c) => Box::new(c.data.iter().map(|s| s.as_str())),
+                Column::InlineStr(c) => Box::new(c.iter()),
+                other => return Err(VMError::TypeError(format!("Expected a Str column, found a {} column", other.datatype())))
+            };
+            let mut mask = BitIndex::for_col_len(col.len());
+            values.enumerate().filter(|(_, x)| in_range(x)).for_each(|(i, _)| mask.set(i));
+            mask
+        }
+    };
+    Ok(BoolColumn::from_mask(mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::{argsort, argsort_collated, max_collated, min, min_collated};
+    use crate::encoding::Encoding;
+
+    fn names() -> Column {
+        Column::from(vec!["bob", "Zoe", "adam", "Émile", "Adam", "zoe"])
+    }
+
+    fn s(x: &str) -> Scalar {
+        Scalar::Str(x.to_string())
+    }
+
+    #[test]
+    fn case_insensitive_ignores_case_only() {
+        let ci = Collation::CaseInsensitive;
+        assert_eq!(ci.cmp("apple", "Apple"), Ordering::Equal);
+        assert_eq!(ci.cmp("Apple", "banana"), Ordering::Less);
+        assert_eq!(ci.cmp("ÉMILE", "émile"), Ordering::Equal);
+        assert_eq!(ci.cmp("émile", "zoe"), Ordering::Greater);
+        assert_eq!(Collation::Binary.cmp("Zoe", "adam"), Ordering::Less);
+        assert_eq!(format!("{:?}", ci), "CaseInsensitive");
+    }
+
+    #[test]
+    fn sorts_and_picks_extremes_by_the_collation() {
+        let col = names();
+        assert_eq!(argsort(&col), vec![4, 1, 2, 0, 5, 3]);
+        assert_eq!(argsort_collated(&col, &Collation::Binary), argsort(&col));
+        // equal under the collation, so they keep their row order
+        assert_eq!(argsort_collated(&col, &Collation::CaseInsensitive), vec![2, 4, 0, 1, 5, 3]);
+        let dict = encoding::encode(col.clone(), Encoding::Dict).unwrap();
+        assert_eq!(argsort_collated(&dict, &Collation::CaseInsensitive), vec![2, 4, 0, 1, 5, 3]);
+
+        assert_eq!(min(&col), Some(s("Adam")));
+        assert_eq!(min_collated(&col, &Collation::CaseInsensitive), Some(s("adam")));
+        assert_eq!(max_collated(&col, &Collation::CaseInsensitive), Some(s("Émile")));
+        assert_eq!(min_collated(&Column::from(Vec::<&str>::new()), &Collation::CaseInsensitive), None);
+        // numbers aren't affected
+        assert_eq!(argsort_collated(&Column::from(vec![2.0, 1.0]), &Collation::CaseInsensitive), vec![1, 0]);
+    }
+
+    #[test]
+    fn filters_ranges_by_the_collation() {
+        let col = names();
+        let dict = encoding::encode(col.clone(), Encoding::Dict).unwrap();
+        for c in [&col, &dict] {
+            // bytewise, uppercase comes first
+            assert_eq!(filter_range(c, &s("A"), &s("a"), &Collation::Binary).unwrap().selection().positions(), vec![1, 4]);
+            assert_eq!(c.filter_range(s("A"), s("a")).unwrap().selection().positions(), vec![1, 4]);
+            assert_eq!(filter_range(c, &s("a"), &s("c"), &Collation::CaseInsensitive).unwrap().selection().positions(), vec![0, 2, 4]);
+            // the upper bound is exclusive
+            assert_eq!(filter_range(c, &s("ADAM"), &s("bob"), &Collation::CaseInsensitive).unwrap().selection().positions(), vec![2, 4]);
+        }
+    }
+
+    #[test]
+    fn filter_range_needs_strings() {
+        let ci = Collation::CaseInsensitive;
+        assert!(matches!(filter_range(&names(), &s("a"), &Scalar::Num(1.0), &ci), Err(VMError::TypeError(_))));
+        assert!(matches!(filter_range(&Column::from(vec![1.0]), &s("a"), &s("b"), &ci), Err(VMError::TypeError(_))));
+    }
+
+    #[cfg(feature = "icu")]
+    #[test]
+    fn locales_order_accents_and_letters_their_own_way() {
+        let en = Collation::locale("en").unwrap();
+        assert_eq!(argsort_collated(&names(), &en), vec![2, 4, 0, 3, 5, 1]);
+        assert_eq!(format!("{:?}", en), "Locale(\"en\")");
+        // in Swedish, ö is a letter of its own after z
+        let words = Column::from(vec!["öl", "zebra", "ost"]);
+        assert_eq!(argsort_collated(&words, &Collation::locale("sv").unwrap()), vec![2, 1, 0]);
+        assert_eq!(argsort_collated(&words, &en), vec![0, 2, 1]);
+        assert!(matches!(Collation::locale("not a locale!"), Err(VMError::TypeError(_))));
+    }
+}
@@ -1,66 +1,323 @@
 use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::compare;
+use crate::core::prelude::*;
+use crate::core::StableHasher;
 use crate::errors::VMError;
+use crate::primitive::{match_primitive, Native, PrimitiveColumn};
+use crate::bitpack::PackedColumn;
+use crate::categorical::CategoricalColumn;
+use crate::collation::{self, Collation};
+use crate::delta::DeltaColumn;
+use crate::dict::DictStrColumn;
+use crate::duration::{DurationColumn, Nanos};
+use crate::geo::{Point, PointColumn};
+use crate::ip::{Ipv4Addr, Ipv4Column, Ipv6Addr, Ipv6Column};
+use crate::json::JsonColumn;
+use crate::frame_of_ref::ForColumn;
+use crate::encoding;
+use crate::kernels;
+use crate::list::ListColumn;
+use crate::nulls::{self, NullableColumn, NullSemantics};
+use crate::record_column::RecordColumn;
+use crate::chunked::ChunkedColumn;
+use crate::rle::RleColumn;
+use crate::schema::Datatype;
+use crate::selection::{FilterPlan, Selection};
+use crate::timestamp::{Micros, TimestampColumn};
+use crate::vector::VectorColumn;
 
-use std::fmt;
+use alloc::borrow::Cow;
+use core::cmp::Ordering;
+use crate::core::HashSet;
+use core::convert::TryFrom;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::iter::FromIterator;
 
 type EntityT = u64;
 
 // todo: Rc<String> ?
-#[derive(Debug, Clone, PartialEq)]
+// Equality and ordering follow compare.rs: NaN == NaN, -0.0 == 0.0, and values of different
+// types are never equal.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Scalar {
     Bool(bool),
     Num(f64),
+    Int(i64),
     Str(String),
     Entity(EntityT),
-    Record(Vec<Scalar>)
+    Duration(Nanos),
+    Timestamp(Micros),
+    Point(Point),
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Json(String),   // a JSON document
+    Record(Vec<Scalar>),
+    Vector(Vec<f32>),
+    List(Vec<Scalar>),
+    Null
+}
+
+impl Scalar {
+    pub fn fingerprint(&self) -> u64 {
+        let mut h = StableHasher::default();
+        self.hash_into(&mut h);
+        h.finish()
+    }
+
+    fn hash_into<H: Hasher>(&self, h: &mut H) {
+        match self {
+            Scalar::Bool(x) => { 0u8.hash(h); x.hash(h) },
+            Scalar::Num(x) => { 1u8.hash(h); x.to_bits().hash(h) },
+            Scalar::Str(x) => { 2u8.hash(h); x.hash(h) },
+            Scalar::Entity(x) => { 3u8.hash(h); x.hash(h) },
+            Scalar::Record(xs) => { 4u8.hash(h); xs.iter().for_each(|x| x.hash_into(h)) },
+            Scalar::Duration(x) => { 5u8.hash(h); x.hash(h) },
+            Scalar::Point(p) => { 6u8.hash(h); p.x.to_bits().hash(h); p.y.to_bits().hash(h) },
+            Scalar::Ipv4(x) => { 7u8.hash(h); u32::from(*x).hash(h) },
+            Scalar::Ipv6(x) => { 8u8.hash(h); u128::from(*x).hash(h) },
+            Scalar::Json(x) => { 9u8.hash(h); x.hash(h) },
+            Scalar::Vector(xs) => { 10u8.hash(h); xs.iter().for_each(|x| x.to_bits().hash(h)) },
+            Scalar::List(xs) => { 11u8.hash(h); xs.len().hash(h); xs.iter().for_each(|x| x.hash_into(h)) },
+            Scalar::Int(x) => { 12u8.hash(h); x.hash(h) },
+            Scalar::Null => 13u8.hash(h),
+            Scalar::Timestamp(x) => { 14u8.hash(h); x.hash(h) }
+        }
+    }
+}
+
+impl PartialEq for Scalar {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scalar {}
+
+impl PartialOrd for Scalar {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scalar {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare::cmp_scalar(self, other)
+    }
+}
+
+// Consistent with Eq, so Scalars can key hash maps
+impl Hash for Scalar {
+    fn hash<H: Hasher>(&self, h: &mut H) {
+        match self {
+            Scalar::Num(x) if *x == 0.0 => Scalar::Num(0.0).hash_into(h),
+            Scalar::Num(x) if x.is_nan() => Scalar::Num(f64::NAN).hash_into(h),
+            Scalar::Record(xs) => { 4u8.hash(h); xs.iter().for_each(|x| x.hash(h)) },
+            Scalar::Vector(xs) => {
+                10u8.hash(h);
+                xs.iter().for_each(|x| Scalar::Num(*x as f64).hash(h))
+            },
+            Scalar::List(xs) => { 11u8.hash(h); xs.len().hash(h); xs.iter().for_each(|x| x.hash(h)) },
+            other => other.hash_into(h)
+        }
+    }
 }
 
 pub trait ColumnT {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError>;
     fn select(&self, mask: &BoolColumn) -> Self;
+
+    // Rows x for which `x cmp val` holds, for the types with an order
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        Err(VMError::TypeError(format!("Can't filter on {:?} {:?}: the column's values have no order", cmp, val)))
+    }
 }
 
-#[derive(Debug)]
-pub struct BoolColumn {
-    data: BitIndex
+// The comparisons other than equality, for FilterLt and friends. As in SQL, a comparison with
+// null (NaN) never holds, not even Ne.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Ne
+}
+
+impl Cmp {
+    // Whether x cmp y, given how x orders against y
+    pub fn holds(self, ord: Ordering) -> bool {
+        match self {
+            Cmp::Lt => ord == Ordering::Less,
+            Cmp::Le => ord != Ordering::Greater,
+            Cmp::Gt => ord == Ordering::Greater,
+            Cmp::Ge => ord != Ordering::Less,
+            Cmp::Ne => ord != Ordering::Equal
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct NumColumn {
-    data: Vec<f64>
+#[derive(Debug, Clone)]
+pub struct BoolColumn {
+    pub(crate) data: Selection
 }
 
-#[derive(Debug)]
+pub type NumColumn = PrimitiveColumn<f64>;
+
+// Strings boxed one by one. Kept for compatibility; InlineStrColumn is what
+// Column::from builds for string data.
+#[derive(Debug, Clone)]
 pub struct StrColumn {
-    data: Vec<String>
+    pub(crate) data: Vec<String>
 }
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct InlineStrColumn {
     // c.f. Arrow's "Variable Binary" layout
-    data: Vec<u8>,
-    offsets: Vec<usize>
+    pub(crate) data: Buffer<u8>,
+    pub(crate) offsets: Buffer<usize>,     // absolute positions into `data`, so slices can share it
+    // first 4 bytes of each value, zero padded (c.f. Umbra's string headers):
+    // together with the length from `offsets`, rules out most mismatches without touching `data`
+    pub(crate) prefixes: Buffer<u32>
+}
+
+impl BoolColumn {
+    // Wrap a filter result, switching to a position list if it's sparse
+    pub fn from_mask(mask: BitIndex) -> Self {
+        BoolColumn { data: Selection::adaptive(mask) }
+    }
+
+    pub fn from_selection(sel: Selection) -> Self {
+        BoolColumn { data: sel }
+    }
+
+    pub fn selection(&self) -> &Selection {
+        &self.data
+    }
+
+    pub fn into_selection(self) -> Selection {
+        self.data
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.data.count_ones()
+    }
+}
+
+impl StrColumn {
+    pub fn new(data: Vec<String>) -> Self {
+        StrColumn { data }
+    }
 }
 
 impl InlineStrColumn {
     pub fn from_strs(strs: Vec<&str>) -> Self {
+        strs.into_iter().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn value(&self, i: usize) -> &str {
+        // only ever built from whole &strs, so this can't fail
+        core::str::from_utf8(&self.data[self.offsets[i] .. self.offsets[i+1]]).expect("InlineStrColumn holds valid UTF-8")
+    }
+
+    pub fn get(&self, i: usize) -> Option<&str> {
+        if i < self.len() { Some(self.value(i)) } else { None }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&str> + '_ {
+        (0 .. self.len()).map(move |i| self.value(i))
+    }
+
+    // Rows offset .. offset + len, which must be in bounds; shares the string bytes
+    pub fn slice(&self, offset: usize, len: usize) -> Self {
+        InlineStrColumn {
+            data: self.data.clone(),
+            offsets: self.offsets.slice(offset, len + 1),
+            prefixes: self.prefixes.slice(offset, len)
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.data.memory_usage() + self.offsets.memory_usage() + self.prefixes.memory_usage()
+    }
+
+    fn value_eq(&self, i: usize, needle: &[u8], needle_prefix: u32) -> bool {
+        let (start, end) = (self.offsets[i], self.offsets[i+1]);
+        // equal length and prefix means equal, for strings of up to 4 bytes
+        end - start == needle.len()
+            && self.prefixes[i] == needle_prefix
+            && (needle.len() <= 4 || self.data[start + 4 .. end] == needle[4..])
+    }
+}
+
+impl<'a> FromIterator<&'a str> for InlineStrColumn {
+    fn from_iter<I: IntoIterator<Item=&'a str>>(strs: I) -> Self {
         let mut data = Vec::new();
         let mut offsets = vec![0];
         for s in strs {
             data.extend(s.as_bytes());
-            // safe - we know offsets is non-empty, we just initialized it 2 lines ago
-            offsets.push(offsets.last().unwrap() + s.len());
+            offsets.push(data.len());
         }
-        InlineStrColumn { data, offsets }
+        let prefixes = strs_prefixes(&data, &offsets);
+        InlineStrColumn { data: Buffer::from(data), offsets: Buffer::from(offsets), prefixes }
+    }
+}
+
+impl From<Vec<String>> for InlineStrColumn {
+    fn from(v: Vec<String>) -> Self {
+        v.iter().map(|s| s.as_str()).collect()
+    }
+}
+
+// Equal if they hold the same strings, however their buffers are laid out (slices share
+// `data` with the column they came from, so the raw bytes can differ)
+impl PartialEq for InlineStrColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for InlineStrColumn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("InlineStrColumn").field(&self.iter().collect::<Vec<_>>()).finish()
     }
 }
 
-#[derive(Debug)]
-pub struct EntityColumn {
-    data: Vec<EntityT>
+pub(crate) fn str_prefix(bytes: &[u8]) -> u32 {
+    let mut p = [0u8; 4];
+    let n = bytes.len().min(4);
+    p[..n].copy_from_slice(&bytes[..n]);
+    u32::from_le_bytes(p)
+}
+
+fn strs_prefixes(data: &[u8], offsets: &[usize]) -> Buffer<u32> {
+    offsets.windows(2).map(|w| str_prefix(&data[w[0] .. w[1]])).collect()
+}
+
+pub type EntityColumn = PrimitiveColumn<EntityT>;
+pub type IntColumn = PrimitiveColumn<i64>;
+
+fn vec_bytes<T>(v: &Vec<T>) -> usize {
+    v.capacity() * core::mem::size_of::<T>()
 }
 
-fn _filter_eq<T: PartialEq>(col: &Vec<T>, val: T) -> Vec<EntityT> {
+fn _filter_eq<T: PartialEq>(col: &[T], val: T) -> Vec<EntityT> {
     // Find occurrences of `val` and return positions at which they occur.
     // todo: accept arbitrary predicates?
     col.iter()
@@ -70,17 +327,33 @@ fn _filter_eq<T: PartialEq>(col: &Vec<T>, val: T) -> Vec<EntityT> {
         .collect()
 }
 
-fn _filter_eq_bool<T: PartialEq>(col: &Vec<T>, val: T) -> BoolColumn {
+fn _filter_eq_bool<T: PartialEq>(col: &[T], val: T) -> BoolColumn {
     // Find occurences of `val` in `col` and return a boolean mask
     let mut positions = BitIndex::for_col_len(col.len());
     col.iter()
         .enumerate()
         .filter(|(_i, x)| **x == val)
         .for_each(|(i, _x)| positions.set(i));
-    BoolColumn { data: positions }
+    BoolColumn::from_mask(positions)
+}
+
+fn _filter_eq_at<T: PartialEq>(col: &[T], val: &T, sel: &Selection) -> BoolColumn {
+    // Like _filter_eq_bool, but only looks at the rows in `sel`,
+    // and bit i of the mask refers to the i'th selected row
+    let mut positions = BitIndex::for_col_len(sel.count_ones());
+    let mut i = 0;
+    sel.for_each(|idx| {
+        if col[idx] == *val { positions.set(i); }
+        i += 1;
+    });
+    BoolColumn::from_mask(positions)
 }
 
 impl ColumnT for BoolColumn {
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
     fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
         if let Scalar::Bool(x) = val {
             match x {
@@ -92,27 +365,22 @@ impl ColumnT for BoolColumn {
         }
     }
 
-    fn select(&self, _mask: &BoolColumn) -> BoolColumn {
-        unimplemented!()    // this one's a bit of a special case
+    fn select(&self, mask: &BoolColumn) -> BoolColumn {
+        let mut bits = BitIndex::for_col_len(mask.count_ones());
+        let mut i = 0;
+        mask.selection().for_each(|idx| {
+            if self.data.contains(idx) { bits.set(i); }
+            i += 1;
+        });
+        BoolColumn::from_mask(bits)
     }
 }
 
-impl ColumnT for NumColumn {
-    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
-        if let Scalar::Num(x) = val {
-            Ok(_filter_eq_bool(&self.data, x))
-        } else {
-            Err(VMError::TypeError(format!("Expected a numeric value, got: {:?}", val)))
-        }
-    }
-
-    fn select(&self, mask: &BoolColumn) -> Self {
-        let res = mask.data.select(&self.data);
-        Self { data: res }
+impl ColumnT for StrColumn {
+    fn len(&self) -> usize {
+        self.data.len()
     }
-}
 
-impl ColumnT for StrColumn {
     fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
         if let Scalar::Str(x) = val {
             Ok(_filter_eq_bool(&self.data, x))
@@ -125,107 +393,1156 @@ impl ColumnT for StrColumn {
         let res = mask.data.select(&self.data);
         Self { data: res }
     }
-}
 
-impl ColumnT for EntityColumn {
-    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
-        if let Scalar::Entity(x) = val {
-            Ok(_filter_eq_bool(&self.data, x))
-        } else {
-            Err(VMError::TypeError(format!("Expected an entity-id value, got: {:?}", val)))
+    // bytewise (see collation.rs for other orders)
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        match val {
+            Scalar::Str(x) => {
+                let mut positions = BitIndex::for_col_len(self.data.len());
+                self.data.iter().enumerate().filter(|(_, s)| cmp.holds(s.as_str().cmp(&x))).for_each(|(i, _)| positions.set(i));
+                Ok(BoolColumn::from_mask(positions))
+            },
+            _ => Err(VMError::TypeError(format!("Expected a string value, got: {:?}", val)))
         }
     }
-
-    fn select(&self, mask: &BoolColumn) -> Self {
-        let res = mask.data.select(&self.data);
-        Self { data: res }
-    }
 }
 
 
 impl ColumnT for InlineStrColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
     fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
         if let Scalar::Str(x) = val {
             let scalar_bytes = x.into_bytes();
-            let mut positions = BitIndex::for_col_len(self.offsets.len());
-            for i in 0 .. self.offsets.len() - 1 {
-                let bytes = &self.data[self.offsets[i] .. self.offsets[i+1]];
-                if scalar_bytes == bytes {
+            let prefix = str_prefix(&scalar_bytes);
+            let mut positions = BitIndex::for_col_len(self.len());
+            for i in 0 .. self.len() {
+                if self.value_eq(i, &scalar_bytes, prefix) {
                     positions.set(i);
                 }
             }
-            Ok(BoolColumn { data: positions })
+            Ok(BoolColumn::from_mask(positions))
         } else {
             Err(VMError::TypeError(format!("Expected a string value, got: {:?}", val)))
         }
     }
 
     fn select(&self, mask: &BoolColumn) -> Self {
-        let mut data = Vec::new();
-        let mut offsets = vec![0];
+        let mut data = Buffer::new();
+        let mut offsets = Buffer::from(vec![0]);
+        let mut prefixes = Buffer::new();
         mask.data.for_each(|idx| {
             let bytes = &self.data[self.offsets[idx] .. self.offsets[idx+1]];
-            data.extend(bytes);
-            offsets.push(offsets[idx] + bytes.len());
+            data.extend_from_slice(bytes);
+            offsets.push(data.len());
+            prefixes.push(self.prefixes[idx]);
         });
-        InlineStrColumn { data: data, offsets }
+        InlineStrColumn { data, offsets, prefixes }
+    }
+
+    // bytewise, as for StrColumn
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        match val {
+            Scalar::Str(x) => {
+                let mut positions = BitIndex::for_col_len(self.len());
+                self.iter().enumerate().filter(|(_, s)| cmp.holds(s.cmp(&x.as_str()))).for_each(|(i, _)| positions.set(i));
+                Ok(BoolColumn::from_mask(positions))
+            },
+            _ => Err(VMError::TypeError(format!("Expected a string value, got: {:?}", val)))
+        }
+    }
+}
+
+// Elementwise arithmetic on numbers, for the *Vs and *Vv opcodes. As in IEEE floating point, dividing
+// by zero gives an infinity, or NaN (null) for 0 / 0. Ints add, subtract and multiply exactly,
+// failing with VMError::Overflow past i64's range; they divide as Nums, and mixed with Nums
+// become Nums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div
+}
+
+impl Arith {
+    pub fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            Arith::Add => a + b,
+            Arith::Sub => a - b,
+            Arith::Mul => a * b,
+            Arith::Div => a / b
+        }
+    }
+
+    // `self` on two Ints, or None if the result is out of range
+    fn apply_int(self, a: i64, b: i64) -> Option<i64> {
+        match self {
+            Arith::Add => a.checked_add(b),
+            Arith::Sub => a.checked_sub(b),
+            Arith::Mul => a.checked_mul(b),
+            Arith::Div => unreachable!("Ints divide as Nums")
+        }
+    }
+}
+
+// The values of a Num or Int column as f64s, for arithmetic mixing the two
+fn nums(col: &Column) -> Option<Cow<'_, [f64]>> {
+    match col {
+        Column::Num(c) => Some(Cow::Borrowed(c.data.as_slice())),
+        Column::Int(c) => Some(Cow::Owned(c.data.iter().map(|x| *x as f64).collect())),
+        _ => None
     }
 }
 
-#[derive(Debug)]
+fn int_arith<I: Iterator<Item=(i64, i64)>>(op: Arith, pairs: I) -> Result<Column, VMError> {
+    let data = pairs.map(|(x, y)| op.apply_int(x, y)).collect::<Option<Vec<i64>>>().ok_or(VMError::Overflow)?;
+    Ok(Column::from(data))
+}
+
+// Arithmetic on times, where it means something: a Timestamp plus or minus a Duration is a
+// Timestamp, and the difference of two Timestamps, or the sum or difference of two
+// Durations, is a Duration. None for any other operands.
+fn time_arith(op: Arith, a: &Column, b: &Column) -> Option<Result<Column, VMError>> {
+    let res = match (a, b, op) {
+        (Column::Timestamp(a), Column::Duration(b), Arith::Add | Arith::Sub) => a.data.iter().zip(b.data.iter())
+            .map(|(t, d)| if op == Arith::Add { Some(*d) } else { d.checked_neg() }.and_then(|d| d.after(*t)))
+            .collect::<Option<Vec<Micros>>>().map(Column::from),
+        (Column::Duration(a), Column::Timestamp(b), Arith::Add) => a.data.iter().zip(b.data.iter())
+            .map(|(d, t)| d.after(*t))
+            .collect::<Option<Vec<Micros>>>().map(Column::from),
+        (Column::Timestamp(a), Column::Timestamp(b), Arith::Sub) => a.data.iter().zip(b.data.iter())
+            .map(|(x, y)| Nanos::between(*y, *x))
+            .collect::<Option<Vec<Nanos>>>().map(Column::from),
+        (Column::Duration(a), Column::Duration(b), Arith::Add | Arith::Sub) => a.data.iter().zip(b.data.iter())
+            .map(|(x, y)| if op == Arith::Add { x.checked_add(*y) } else { x.checked_sub(*y) })
+            .collect::<Option<Vec<Nanos>>>().map(Column::from),
+        _ => return None
+    };
+    Some(res.ok_or(VMError::Overflow))
+}
+
+#[derive(Debug, Clone)]
 pub enum Column {
     Bool(BoolColumn),
     Num(NumColumn),
+    Int(IntColumn),
     Str(StrColumn),
     Entity(EntityColumn),
-    InlineStr(InlineStrColumn)
+    Duration(DurationColumn),
+    Timestamp(TimestampColumn),
+    Point(PointColumn),
+    Ipv4(Ipv4Column),
+    Ipv6(Ipv6Column),
+    InlineStr(InlineStrColumn),
+    Json(JsonColumn),
+    Vector(VectorColumn),
+    List(ListColumn),
+    Record(RecordColumn),
+    Chunked(ChunkedColumn),
+    Nullable(NullableColumn),
+    Categorical(CategoricalColumn),
+    Rle(RleColumn),
+    Delta(DeltaColumn),
+    Packed(PackedColumn),
+    For(ForColumn),
+    Dict(DictStrColumn)
+}
+
+impl Column {
+    pub fn len(&self) -> usize {
+        match_primitive!(self, col => col.len(),
+            Column::Bool(col)   => col.data.len(),
+            Column::Str(col)    => col.data.len(),
+            Column::InlineStr(col) => col.len(),
+            Column::Json(col) => col.len(),
+            Column::Vector(col) => col.len(),
+            Column::List(col) => col.len(),
+            Column::Record(col) => col.len(),
+            Column::Chunked(col) => col.len(),
+            Column::Nullable(col) => col.len(),
+            Column::Categorical(col) => col.len(),
+            Column::Rle(col) => col.len(),
+            Column::Delta(col) => col.len(),
+            Column::Packed(col) => col.len(),
+            Column::For(col) => col.len(),
+            Column::Dict(col) => col.len()
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Bytes resident for this column: the enum itself plus every heap allocation it owns
+    // (string bodies, offsets, bitmap words). Counts capacity, not length, since that's
+    // what's actually allocated.
+    pub fn memory_usage(&self) -> usize {
+        let heap = match_primitive!(self, col => col.memory_usage(),
+            Column::Bool(col)   => col.data.memory_usage(),
+            Column::Str(col)    => vec_bytes(&col.data) + col.data.iter().map(|s| s.capacity()).sum::<usize>(),
+            Column::InlineStr(col) => col.memory_usage(),
+            Column::Json(col) => col.memory_usage(),
+            Column::Vector(col) => col.memory_usage(),
+            Column::List(col) => col.memory_usage(),
+            Column::Record(col) => col.memory_usage(),
+            Column::Chunked(col) => col.memory_usage(),
+            Column::Nullable(col) => col.memory_usage(),
+            Column::Categorical(col) => col.memory_usage(),
+            Column::Rle(col) => col.memory_usage(),
+            Column::Delta(col) => col.memory_usage(),
+            Column::Packed(col) => col.memory_usage(),
+            Column::For(col) => col.memory_usage(),
+            Column::Dict(col) => col.memory_usage()
+        );
+        core::mem::size_of::<Column>() + heap
+    }
+
+    // Name of the physical representation, for diagnostics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Column::Bool(_)   => "Bool",
+            Column::Num(_)    => "Num",
+            Column::Int(_)    => "Int",
+            Column::Str(_)    => "Str",
+            Column::Entity(_) => "Entity",
+            Column::Duration(_) => "Duration",
+            Column::Timestamp(_) => "Timestamp",
+            Column::Point(_) => "Point",
+            Column::Ipv4(_) => "Ipv4",
+            Column::Ipv6(_) => "Ipv6",
+            Column::InlineStr(_) => "InlineStr",
+            Column::Json(_) => "Json",
+            Column::Vector(_) => "Vector",
+            Column::List(_) => "List",
+            Column::Record(_) => "Record",
+            Column::Chunked(_) => "Chunked",
+            Column::Nullable(_) => "Nullable",
+            Column::Categorical(_) => "Categorical",
+            Column::Rle(_) => "Rle",
+            Column::Delta(_) => "Delta",
+            Column::Packed(_) => "Packed",
+            Column::For(_) => "For",
+            Column::Dict(_) => "Dict"
+        }
+    }
+
+    // A hash of the column's contents, stable across runs (see core::StableHasher) - good
+    // enough for comparing execution traces.
+    pub fn fingerprint(&self) -> u64 {
+        let mut h = StableHasher::default();
+        self.datatype().hash(&mut h);
+        match self {
+            Column::Bool(col)   => col.data.hash(&mut h),
+            Column::Num(col)    => col.data.iter().for_each(|x| x.to_bits().hash(&mut h)),
+            Column::Str(col)    => col.data.iter().for_each(|s| s.hash(&mut h)),
+            Column::Entity(col) => col.data.hash(&mut h),
+            Column::Int(col) => col.data.hash(&mut h),
+            Column::Duration(col) => col.data.hash(&mut h),
+            Column::Timestamp(col) => col.data.hash(&mut h),
+            Column::Point(col) => col.data.iter().for_each(|p| { p.x.to_bits().hash(&mut h); p.y.to_bits().hash(&mut h) }),
+            Column::Ipv4(col) => col.data.iter().for_each(|x| u32::from(*x).hash(&mut h)),
+            Column::Ipv6(col) => col.data.iter().for_each(|x| u128::from(*x).hash(&mut h)),
+            // hashed per value, so that a Str and InlineStr with the same contents agree
+            Column::InlineStr(col) => col.iter().for_each(|s| s.hash(&mut h)),
+            Column::Json(col) => col.iter().for_each(|s| s.hash(&mut h)),
+            Column::Vector(col) => { col.dim.hash(&mut h); col.data.iter().for_each(|x| x.to_bits().hash(&mut h)) },
+            Column::List(col) => col.iter().for_each(|list| { list.len().hash(&mut h); list.fingerprint().hash(&mut h) }),
+            Column::Record(col) => (0 .. col.len()).for_each(|i| col.get(i).hash(&mut h)),
+            Column::Nullable(col) => (0 .. col.len()).for_each(|i| col.get(i).hash(&mut h)),
+            // by name, so columns with different categories but the same values agree
+            Column::Categorical(col) => col.iter().for_each(|s| s.hash(&mut h)),
+            // same as the plain column, so encoding doesn't show up as a trace divergence
+            Column::Chunked(col) => return col.decode().fingerprint(),
+            Column::Rle(col) => return col.decode().fingerprint(),
+            Column::Delta(col) => return col.decode().fingerprint(),
+            Column::Packed(col) => return col.decode().fingerprint(),
+            Column::For(col) => return col.decode().fingerprint(),
+            Column::Dict(col) => return col.decode().fingerprint()
+        }
+        h.finish()
+    }
+
+    // Rows offset .. offset + len, clamped to the column's bounds. Shares the underlying
+    // buffers rather than copying, except for Bool (bitmaps are small) and the legacy
+    // boxed-String StrColumn.
+    pub fn slice(&self, offset: usize, len: usize) -> Column {
+        let offset = offset.min(self.len());
+        let len = len.min(self.len() - offset);
+        match_primitive!(self, col => Column::from(col.slice(offset, len)),
+            Column::Bool(col) => Column::Bool(BoolColumn { data: col.data.slice(offset, len) }),
+            Column::Str(col) => Column::Str(StrColumn { data: col.data[offset .. offset + len].to_vec() }),
+            Column::InlineStr(col) => Column::InlineStr(col.slice(offset, len)),
+            Column::Json(col) => Column::Json(col.slice(offset, len)),
+            Column::Vector(col) => Column::Vector(col.slice(offset, len)),
+            Column::List(col) => Column::List(col.slice(offset, len)),
+            Column::Record(col) => Column::Record(col.slice(offset, len)),
+            Column::Chunked(col) => Column::Chunked(col.slice(offset, len)),
+            Column::Nullable(col) => Column::Nullable(col.slice(offset, len)),
+            Column::Categorical(col) => Column::Categorical(col.slice(offset, len)),
+            Column::Rle(col) => Column::Rle(col.slice(offset, len)),
+            Column::Delta(col) => Column::Delta(col.slice(offset, len)),
+            Column::Packed(col) => Column::Packed(col.slice(offset, len)),
+            Column::For(col) => Column::For(col.slice(offset, len)),
+            Column::Dict(col) => Column::Dict(col.slice(offset, len))
+        )
+    }
+
+    // Consecutive slices of (at most) `chunk_len` rows
+    pub fn chunks(&self, chunk_len: usize) -> Vec<Column> {
+        assert!(chunk_len > 0, "chunk_len must be positive");
+        (0 .. self.len()).step_by(chunk_len).map(|offset| self.slice(offset, chunk_len)).collect()
+    }
+
+    // The rows of `parts` one after another, as a plain column (or a Dict one, if the parts
+    // are all Dict). The parts must have the same datatype; a Str result is InlineStr.
+    pub fn concat(parts: &[Column]) -> Result<Column, VMError> {
+        let dtype = match parts.first() {
+            Some(first) => first.datatype(),
+            None => return Err(VMError::TypeError("Can't concatenate no columns".to_string()))
+        };
+        if let Some(other) = parts.iter().find(|c| c.datatype() != dtype) {
+            return Err(VMError::TypeError(format!("Can't concatenate {} and {} columns", dtype, other.datatype())));
+        }
+        if parts.iter().any(|c| matches!(c, Column::Nullable(_))) {
+            let rows = parts.iter().map(|c| c.len()).sum();
+            let mut validity = BitIndex::for_col_len(rows);
+            let mut offset = 0;
+            for c in parts {
+                match c {
+                    Column::Nullable(c) => validity.or_at(offset, c.validity()),
+                    c => validity.set_range(offset, offset + c.len())
+                }
+                offset += c.len();
+            }
+            let values: Vec<Column> = parts.iter().map(|c| nulls::values_of(c).clone()).collect();
+            return Ok(Column::Nullable(NullableColumn::new(Column::concat(&values)?, validity)?));
+        }
+        let dicts: Option<Vec<DictStrColumn>> = parts.iter()
+            .map(|c| if let Column::Dict(d) = c { Some(d.clone()) } else { None })
+            .collect();
+        if let Some(dicts) = dicts {
+            return Ok(Column::Dict(DictStrColumn::concat(&dicts)));
+        }
+        if dtype == Datatype::Categorical {
+            let cats: Vec<&CategoricalColumn> = parts.iter()
+                .map(|c| if let Column::Categorical(c) = c { c } else { unreachable!("Categorical columns aren't encoded") })
+                .collect();
+            return Ok(Column::Categorical(CategoricalColumn::concat(&cats)?));
+        }
+        let parts: Vec<_> = parts.iter().map(encoding::plain).collect();
+        let rows = parts.iter().map(|c| c.len()).sum();
+        Ok(match dtype {
+            Datatype::Bool => {
+                let mut bits = BitIndex::for_col_len(rows);
+                let mut offset = 0;
+                for c in &parts {
+                    if let Column::Bool(b) = c.as_ref() {
+                        bits.or_at(offset, &b.data.to_bitmap());
+                    }
+                    offset += c.len();
+                }
+                Column::Bool(BoolColumn::from_mask(bits))
+            },
+            Datatype::Num => concat_native::<f64>(&parts, rows),
+            Datatype::Entity => concat_native::<u64>(&parts, rows),
+            Datatype::Int => concat_native::<i64>(&parts, rows),
+            Datatype::Duration => concat_native::<Nanos>(&parts, rows),
+            Datatype::Timestamp => concat_native::<Micros>(&parts, rows),
+            Datatype::Point => concat_native::<Point>(&parts, rows),
+            Datatype::Ipv4 => concat_native::<Ipv4Addr>(&parts, rows),
+            Datatype::Ipv6 => concat_native::<Ipv6Addr>(&parts, rows),
+            Datatype::Json => Column::Json(JsonColumn { docs: parts.iter().flat_map(|c| str_iter(c)).collect() }),
+            Datatype::Vector => {
+                let vectors: Vec<&VectorColumn> = parts.iter()
+                    .map(|c| if let Column::Vector(c) = c.as_ref() { c } else { unreachable!("Vector columns aren't encoded") })
+                    .collect();
+                let dim = vectors.first().map(|c| c.dim()).unwrap_or(1);
+                if let Some(other) = vectors.iter().find(|c| c.dim() != dim) {
+                    return Err(VMError::LengthMismatch { expected: dim, found: other.dim() });
+                }
+                Column::Vector(VectorColumn::new(dim, vectors.iter().flat_map(|c| c.data.iter().copied()).collect())?)
+            },
+            Datatype::List => {
+                let lists: Vec<&ListColumn> = parts.iter()
+                    .map(|c| if let Column::List(c) = c.as_ref() { c } else { unreachable!("List columns aren't encoded") })
+                    .collect();
+                let mut offsets = vec![0];
+                let mut items = Vec::with_capacity(lists.len());
+                for list in lists.iter().filter(|c| !c.is_empty()) {
+                    let (start, end) = (list.offsets()[0], list.offsets()[list.len()]);
+                    let base = offsets[offsets.len() - 1];
+                    offsets.extend(list.offsets()[1 ..].iter().map(|o| base + o - start));
+                    items.push(list.items().slice(start, end - start));
+                }
+                match items.is_empty() {
+                    true => parts[0].slice(0, 0),
+                    false => Column::List(ListColumn::new(offsets, Column::concat(&items)?)?)
+                }
+            },
+            Datatype::Record => {
+                let records: Vec<&RecordColumn> = parts.iter()
+                    .map(|c| if let Column::Record(c) = c.as_ref() { c } else { unreachable!("Record columns aren't encoded") })
+                    .collect();
+                Column::Record(RecordColumn::concat(&records)?)
+            },
+            Datatype::Categorical => unreachable!("handled above"),
+            Datatype::Str => Column::InlineStr(parts.iter().flat_map(|c| match c.as_ref() {
+                Column::Str(c) => c.data.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+                Column::InlineStr(c) => c.iter().collect(),
+                _ => unreachable!("plain string columns are Str or InlineStr")
+            }).collect())
+        })
+    }
+
+    // FilterEq on self, then Select from `target`, in a single pass and without building a mask
+    pub fn filter_select(&self, val: Scalar, target: &Column) -> Result<Column, VMError> {
+        if self.len() != target.len() {
+            return Err(VMError::LengthMismatch { expected: self.len(), found: target.len() });
+        }
+        if let Scalar::Null = val {
+            return Ok(target.gather_where(|_| false));
+        }
+        match_primitive!(self, col => {
+                let x = col.native(&val)?;
+                Ok(target.gather_where(|i| col.data[i] == x))
+            },
+            Column::Str(col) => match &val {
+                Scalar::Str(x) => Ok(target.gather_where(|i| col.data[i] == *x)),
+                _ => Ok(target.select(&self.filter(val)?))
+            },
+            Column::InlineStr(col) => match &val {
+                Scalar::Str(x) => {
+                    let (x, prefix) = (x.as_bytes(), str_prefix(x.as_bytes()));
+                    Ok(target.gather_where(|i| col.value_eq(i, x, prefix)))
+                },
+                _ => Ok(target.select(&self.filter(val)?))
+            },
+            // bool and encoded columns: fall back to the two-step version
+            _ => Ok(target.select(&self.filter(val)?))
+        )
+    }
+
+    // The rows i for which pred(i) holds
+    fn gather_where<F: Fn(usize) -> bool>(&self, pred: F) -> Column {
+        let n = self.len();
+        match_primitive!(self, col => Column::from(col.gather_where(&pred)),
+            Column::Bool(col) => {
+                let keep: Vec<usize> = (0 .. n).filter(|i| pred(*i)).collect();
+                let mut bits = BitIndex::for_col_len(keep.len());
+                keep.iter().enumerate().filter(|(_, i)| col.data.contains(**i)).for_each(|(j, _)| bits.set(j));
+                Column::Bool(BoolColumn::from_mask(bits))
+            },
+            Column::Str(col) => Column::Str(StrColumn { data: (0 .. n).filter(|i| pred(*i)).map(|i| col.data[i].clone()).collect() }),
+            Column::InlineStr(col) => {
+                let mut data = Buffer::new();
+                let mut offsets = Buffer::from(vec![0]);
+                let mut prefixes = Buffer::new();
+                for i in (0 .. n).filter(|i| pred(*i)) {
+                    data.extend_from_slice(&col.data[col.offsets[i] .. col.offsets[i+1]]);
+                    offsets.push(data.len());
+                    prefixes.push(col.prefixes[i]);
+                }
+                Column::InlineStr(InlineStrColumn { data, offsets, prefixes })
+            },
+            // encoded columns: let select pick the rows out without decoding the rest
+            Column::Json(_) | Column::Vector(_) | Column::List(_) | Column::Record(_) | Column::Chunked(_) | Column::Nullable(_) | Column::Categorical(_) | Column::Rle(_) | Column::Delta(_) | Column::Packed(_) | Column::For(_) | Column::Dict(_) => {
+                let keep: Vec<u32> = (0 .. n).filter(|i| pred(*i)).map(|i| i as u32).collect();
+                self.select(&BoolColumn::from_selection(Selection::from_positions(keep, n)))
+            }
+        )
+    }
+
+    // FilterEq over just the rows in `sel`, without gathering them first. The result is
+    // indexed by position within the selection, same as filtering the gathered column.
+    pub fn filter_at(&self, val: Scalar, sel: &Selection) -> Result<BoolColumn, VMError> {
+        if let Scalar::Null = val {
+            return Ok(no_rows(sel.count_ones()));
+        }
+        match_primitive!(self, col => col.filter_at(&val, sel),
+            Column::Str(col) => match &val {
+                Scalar::Str(x) => Ok(_filter_eq_at(&col.data, x, sel)),
+                _ => col.filter(val)
+            },
+            Column::InlineStr(col) => match &val {
+                Scalar::Str(x) => {
+                    let (x, prefix) = (x.as_bytes(), str_prefix(x.as_bytes()));
+                    let mut positions = BitIndex::for_col_len(sel.count_ones());
+                    let mut i = 0;
+                    sel.for_each(|idx| {
+                        if col.value_eq(idx, x, prefix) { positions.set(i); }
+                        i += 1;
+                    });
+                    Ok(BoolColumn::from_mask(positions))
+                },
+                _ => col.filter(val)
+            },
+            Column::Rle(col) => col.filter_at(val, sel),
+            Column::Dict(col) => col.filter_at(val, sel),
+            // bool columns, and the other encodings: gather first and let the regular kernel handle it
+            _ => self.select(&BoolColumn::from_selection(sel.clone())).filter(val)
+        )
+    }
+
+    // The value at row `idx`, or None past the end. Encoded columns decode just what they
+    // need to: a binary search for Rle, one block for Delta, a single code otherwise.
+    pub fn get(&self, idx: usize) -> Option<Scalar> {
+        match_primitive!(self, col => col.get(idx),
+            Column::Bool(col) => if idx < col.data.len() { Some(Scalar::Bool(col.data.contains(idx))) } else { None },
+            Column::Str(col) => col.data.get(idx).map(|x| Scalar::Str(x.clone())),
+            Column::InlineStr(col) => col.get(idx).map(|x| Scalar::Str(x.to_string())),
+            Column::Json(col) => col.get(idx).map(|x| Scalar::Json(x.to_string())),
+            Column::Vector(col) => col.get(idx).map(|x| Scalar::Vector(x.to_vec())),
+            Column::List(col) => col.get(idx),
+            Column::Record(col) => col.get(idx),
+            Column::Chunked(col) => col.get(idx),
+            Column::Nullable(col) => col.get(idx),
+            Column::Categorical(col) => col.get(idx),
+            Column::Rle(col) => col.get(idx),
+            Column::Delta(col) => col.get(idx),
+            Column::Packed(col) => col.get(idx),
+            Column::For(col) => col.get(idx),
+            Column::Dict(col) => col.get(idx)
+        )
+    }
+
+    pub fn datatype(&self) -> Datatype {
+        match_primitive!(self, col => col.datatype(),
+            Column::Bool(_)   => Datatype::Bool,
+            Column::Str(_) | Column::InlineStr(_) | Column::Dict(_) => Datatype::Str,
+            Column::Json(_) => Datatype::Json,
+            Column::Vector(_) => Datatype::Vector,
+            Column::List(_) => Datatype::List,
+            Column::Record(_) => Datatype::Record,
+            Column::Chunked(col) => col.datatype(),
+            Column::Nullable(col) => col.values().datatype(),
+            Column::Categorical(_) => Datatype::Categorical,
+            Column::Rle(col) => col.datatype(),
+            Column::Delta(col) => col.datatype(),
+            Column::Packed(col) => col.datatype(),
+            Column::For(col) => col.datatype()
+        )
+    }
+
+    // Rows whose value appears anywhere in `set` - a semi-join, as for `x IN (SELECT ...)`.
+    // Values match as they would for FilterEq: -0.0 matches 0.0, and NaN (null) matches
+    // nothing unless `nulls` says nulls match.
+    pub fn filter_in(&self, set: &Column, nulls: NullSemantics) -> Result<BoolColumn, VMError> {
+        if self.datatype() != set.datatype() {
+            return Err(VMError::TypeError(format!("Can't look {} values up in a {} column", self.datatype(), set.datatype())));
+        }
+        match (self, set) {
+            (Column::Nullable(col), _) => return col.filter_in(set, nulls),
+            (_, Column::Nullable(s)) => {
+                let found = self.filter_in(&s.present(), nulls)?;
+                return match nulls.nulls_match() && s.null_count() > 0 {
+                    true => Ok(BoolColumn::from_mask(found.selection().to_bitmap().or(&nulls::filter_eq(self, Scalar::Null, nulls)?.selection().to_bitmap()))),
+                    false => Ok(found)
+                };
+            },
+            _ => {}
+        }
+        let (col, set) = (encoding::plain(self), encoding::plain(set));
+        // -0.0 and 0.0 get the same key, and NaNs are left out of the set
+        let num_key = |x: f64| if x == 0.0 { 0.0f64.to_bits() } else { x.to_bits() };
+        let mask = match (col.as_ref(), set.as_ref()) {
+            (Column::Num(c), Column::Num(s)) => {
+                let keys: HashSet<u64> = s.data.iter().filter(|x| !x.is_nan()).map(|x| num_key(*x)).collect();
+                let nan = nulls.nulls_match() && s.data.iter().any(|x| x.is_nan());
+                kernels::mask_by(&c.data, |x| if x.is_nan() { nan } else { keys.contains(&num_key(x)) })
+            },
+            (Column::Entity(c), Column::Entity(s)) => {
+                let keys: HashSet<u64> = s.data.iter().copied().collect();
+                kernels::mask_by(&c.data, |x| keys.contains(&x))
+            },
+            (Column::Int(c), Column::Int(s)) => {
+                let keys: HashSet<i64> = s.data.iter().copied().collect();
+                kernels::mask_by(&c.data, |x| keys.contains(&x))
+            },
+            (Column::Duration(c), Column::Duration(s)) => {
+                let keys: HashSet<Nanos> = s.data.iter().copied().collect();
+                kernels::mask_by(&c.data, |x| keys.contains(&x))
+            },
+            (Column::Timestamp(c), Column::Timestamp(s)) => {
+                let keys: HashSet<Micros> = s.data.iter().copied().collect();
+                kernels::mask_by(&c.data, |x| keys.contains(&x))
+            },
+            (Column::Ipv4(c), Column::Ipv4(s)) => {
+                let keys: HashSet<Ipv4Addr> = s.data.iter().copied().collect();
+                kernels::mask_by(&c.data, |x| keys.contains(&x))
+            },
+            (Column::Ipv6(c), Column::Ipv6(s)) => {
+                let keys: HashSet<Ipv6Addr> = s.data.iter().copied().collect();
+                kernels::mask_by(&c.data, |x| keys.contains(&x))
+            },
+            (Column::Bool(c), Column::Bool(s)) => {
+                let (has_true, has_false) = (s.count_ones() > 0, s.count_ones() < s.data.len());
+                let mut mask = BitIndex::for_col_len(c.data.len());
+                (0 .. c.data.len()).filter(|i| if c.data.contains(*i) { has_true } else { has_false }).for_each(|i| mask.set(i));
+                mask
+            },
+            (Column::Vector(c), Column::Vector(s)) => {
+                // as for Num, one key for -0.0 and 0.0; a vector with a NaN in it is only
+                // found if nulls match
+                let key = |row: &[f32]| row.iter()
+                    .map(|x| if *x == 0.0 { 0 } else if x.is_nan() { f32::NAN.to_bits() } else { x.to_bits() })
+                    .collect::<Vec<u32>>();
+                let nan = |row: &[f32]| row.iter().any(|x| x.is_nan());
+                let keys: HashSet<Vec<u32>> = s.iter().filter(|row| nulls.nulls_match() || !nan(row)).map(key).collect();
+                let mut mask = BitIndex::for_col_len(c.len());
+                c.iter().enumerate().filter(|(_, row)| keys.contains(&key(row))).for_each(|(i, _)| mask.set(i));
+                mask
+            },
+            // JSON documents match by their exact text and categories by name, as for FilterEq
+            (c, s) if matches!(c.datatype(), Datatype::Str | Datatype::Json | Datatype::Categorical) => {
+                let keys: HashSet<&str> = str_iter(s).collect();
+                let mut mask = BitIndex::for_col_len(c.len());
+                str_iter(c).enumerate().filter(|(_, x)| keys.contains(x)).for_each(|(i, _)| mask.set(i));
+                mask
+            },
+            _ => return Err(VMError::TypeError(format!("Can't look up {} values", self.datatype())))
+        };
+        Ok(BoolColumn::from_mask(mask))
+    }
+
+    // lo <= x < hi, for primitive columns, Categorical ones in category order, and strings
+    // bytewise (see collation::filter_range for other orders)
+    pub fn filter_range(&self, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        match_primitive!(self, col => col.filter_range(&lo, &hi),
+            Column::Str(_) | Column::InlineStr(_) | Column::Dict(_) => collation::filter_range(self, &lo, &hi, &Collation::Binary),
+            Column::Rle(col) => col.filter_range(lo, hi),
+            Column::Delta(col) => col.filter_range(lo, hi),
+            Column::Packed(col) => col.filter_range(lo, hi),
+            Column::For(col) => col.filter_range(lo, hi),
+            Column::Categorical(col) => col.filter_range(&lo, &hi),
+            Column::Nullable(col) => col.filter_range(lo, hi),
+            Column::Chunked(col) => col.filter_range(lo, hi),
+            _ => Err(VMError::TypeError(format!("Expected {} bounds, got: {:?} and {:?}", self.datatype(), lo, hi)))
+        )
+    }
+
+    // `op` applied to each row and `val`, row first (so Sub is x - val), for Num and Int columns
+    // and times (see time_arith)
+    pub fn arith_scalar(&self, op: Arith, val: &Scalar) -> Result<Column, VMError> {
+        // null in, null out
+        if let Column::Nullable(c) = self {
+            return Ok(Column::Nullable(NullableColumn::new(c.values().arith_scalar(op, val)?, c.validity().clone())?));
+        }
+        if let Scalar::Null = val {
+            // of the type an Int operand would give (a Duration one, for times), and null in every row
+            let zero = match self.datatype() {
+                Datatype::Timestamp | Datatype::Duration => Scalar::Duration(Nanos(0)),
+                _ => Scalar::Int(0)
+            };
+            let res = self.arith_scalar(op, &zero)?;
+            return Ok(Column::Nullable(NullableColumn::new(res, BitIndex::for_col_len(self.len()))?));
+        }
+        // a time, as a column of it in every row
+        match val {
+            Scalar::Duration(d) => return self.arith(op, &Column::from(vec![*d; self.len()])),
+            Scalar::Timestamp(t) => return self.arith(op, &Column::from(vec![*t; self.len()])),
+            _ => ()
+        }
+        let y = match val {
+            Scalar::Num(y) => *y,
+            Scalar::Int(y) => *y as f64,
+            _ => return Err(VMError::TypeError(format!("Expected a numeric value, got: {:?}", val)))
+        };
+        let plain = encoding::plain(self);
+        match (plain.as_ref(), val) {
+            (Column::Int(col), Scalar::Int(y)) if op != Arith::Div => int_arith(op, col.data.iter().map(|x| (*x, *y))),
+            (col, _) => match nums(col) {
+                Some(xs) => Ok(Column::Num(NumColumn::new(xs.iter().map(|x| op.apply(*x, y)).collect()))),
+                None => Err(VMError::TypeError(format!("Can't do arithmetic on a {} column", col.datatype())))
+            }
+        }
+    }
+
+    // `op` applied to each row of self and the same row of `other`, for Num and Int columns
+    // of the same length - derived columns like price * qty - and times (see time_arith)
+    pub fn arith(&self, op: Arith, other: &Column) -> Result<Column, VMError> {
+        if self.len() != other.len() {
+            return Err(VMError::LengthMismatch { expected: self.len(), found: other.len() });
+        }
+        if let Some(validity) = nulls::validity_of(&[self, other]) {
+            let res = nulls::values_of(self).arith(op, nulls::values_of(other))?;
+            return Ok(Column::Nullable(NullableColumn::new(res, validity)?));
+        }
+        let (a, b) = (encoding::plain(self), encoding::plain(other));
+        if let Some(res) = time_arith(op, a.as_ref(), b.as_ref()) {
+            return res;
+        }
+        match (a.as_ref(), b.as_ref()) {
+            (Column::Num(a), Column::Num(b)) => Ok(Column::Num(binary(&a.data, &b.data, |x, y| op.apply(x, y)))),
+            (Column::Int(a), Column::Int(b)) if op != Arith::Div => int_arith(op, a.data.iter().copied().zip(b.data.iter().copied())),
+            (a, b) => match (nums(a), nums(b)) {
+                (Some(xs), Some(ys)) => Ok(Column::Num(binary(&xs, &ys, |x, y| op.apply(x, y)))),
+                _ => Err(VMError::TypeError(format!("Can't do arithmetic on {} and {} columns", a.datatype(), b.datatype())))
+            }
+        }
+    }
+
+    // FilterEq, and how it went about it. Primitive and Dict columns decide between a bitmap
+    // and positions from a sample (see kernels::filter_adaptive); a sorted column's filter is
+    // a binary search, the one index lookup there is. The rest scan into whichever
+    // representation Selection::adaptive picks.
+    pub fn filter_planned(&self, val: Scalar) -> Result<(BoolColumn, FilterPlan), VMError> {
+        if let Scalar::Null = val {
+            let mask = no_rows(self.len());
+            let plan = FilterPlan::scanned(mask.selection());
+            return Ok((mask, plan));
+        }
+        match_primitive!(self, col => col.filter_planned(&val),
+            Column::Dict(col) => col.filter_planned(val),
+            Column::Delta(col) => Ok((col.filter(val)?, FilterPlan::IndexLookup)),
+            other => {
+                let mask = other.filter(val)?;
+                let plan = FilterPlan::scanned(mask.selection());
+                Ok((mask, plan))
+            }
+        )
+    }
+
+    // Re-encode a plain column in whichever encoding suits its contents best (see
+    // encoding::choose); already-encoded columns are returned unchanged.
+    pub fn auto_encode(self) -> Column {
+        encoding::auto(self)
+    }
+}
+
+// The mask of a filter by null: as in SQL, nothing equals or compares with a null
+fn no_rows(len: usize) -> BoolColumn {
+    BoolColumn::from_mask(BitIndex::for_col_len(len))
 }
 
 impl ColumnT for Column {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
     fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
-        match self {
+        if let Scalar::Null = val {
+            return Ok(no_rows(self.len()));
+        }
+        match_primitive!(self, col => col.filter(val),
             Column::Bool(col)   => col.filter(val),
-            Column::Num(col)    => col.filter(val),
             Column::Str(col)    => col.filter(val),
-            Column::Entity(col) => col.filter(val),
-            Column::InlineStr(col) => col.filter(val)
-        }
+            Column::InlineStr(col) => col.filter(val),
+            Column::Json(col) => col.filter(val),
+            Column::Vector(col) => col.filter(val),
+            Column::List(col) => col.filter(val),
+            Column::Record(col) => col.filter(val),
+            Column::Chunked(col) => col.filter(val),
+            Column::Nullable(col) => col.filter(val),
+            Column::Categorical(col) => col.filter(val),
+            Column::Rle(col) => col.filter(val),
+            Column::Delta(col) => col.filter(val),
+            Column::Packed(col) => col.filter(val),
+            Column::For(col) => col.filter(val),
+            Column::Dict(col) => col.filter(val)
+        )
     }
 
     fn select(&self, mask: &BoolColumn) -> Self {
-        match self {
+        match_primitive!(self, col => Column::from(col.select(mask)),
             Column::Bool(col)   => Column::Bool(col.select(mask)),
-            Column::Num(col)    => Column::Num(col.select(mask)),
             Column::Str(col)    => Column::Str(col.select(mask)),
-            Column::Entity(col) => Column::Entity(col.select(mask)),
-            Column::InlineStr(col) => Column::InlineStr(col.select(mask))
+            Column::InlineStr(col) => Column::InlineStr(col.select(mask)),
+            Column::Json(col) => Column::Json(col.select(mask)),
+            Column::Vector(col) => Column::Vector(col.select(mask)),
+            Column::List(col) => Column::List(col.select(mask)),
+            Column::Record(col) => Column::Record(col.select(mask)),
+            Column::Chunked(col) => Column::Chunked(col.select(mask)),
+            Column::Nullable(col) => Column::Nullable(col.select(mask)),
+            Column::Categorical(col) => Column::Categorical(col.select(mask)),
+            Column::Rle(col) => Column::Rle(col.select(mask)),
+            // sorted input stays sorted, but results are usually small enough to leave plain
+            Column::Delta(col) => col.gather(mask.selection()),
+            Column::Packed(col) => Column::Packed(col.select(mask.selection())),
+            Column::For(col) => col.gather(mask.selection()),
+            Column::Dict(col) => Column::Dict(col.select(mask.selection()))
+        )
+    }
+
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        if let Scalar::Null = val {
+            return Ok(no_rows(self.len()));
         }
+        match_primitive!(self, col => col.filter_cmp(cmp, val),
+            Column::Str(col)    => col.filter_cmp(cmp, val),
+            Column::InlineStr(col) => col.filter_cmp(cmp, val),
+            Column::Categorical(col) => ColumnT::filter_cmp(col, cmp, val),
+            Column::Nullable(col) => col.filter_cmp(cmp, val),
+            Column::Chunked(col) => col.filter_cmp(cmp, val),
+            Column::Rle(_) | Column::Delta(_) | Column::Packed(_) | Column::For(_) | Column::Dict(_) => encoding::plain(self).filter_cmp(cmp, val),
+            _ => Err(VMError::TypeError(format!("Can't filter {} values on {:?}: they have no order", self.datatype(), cmp)))
+        )
     }
 }
 
 impl From<Vec<f64>> for Column {
     fn from(v: Vec<f64>) -> Self {
-        Column::Num(NumColumn { data: v })
+        Column::Num(NumColumn { data: Buffer::from(v) })
     }
 }
 
 impl From<Vec<String>> for Column {
     fn from(v: Vec<String>) -> Self {
-        Column::Str(StrColumn { data: v })
+        Column::InlineStr(InlineStrColumn::from(v))
     }
 }
 
-// convenience?
 impl From<Vec<&str>> for Column {
     fn from(v: Vec<&str>) -> Self {
-        let v = v.iter().map(|s| s.to_string()).collect();
-        Column::Str(StrColumn { data: v })
+        Column::InlineStr(InlineStrColumn::from_strs(v))
     }
 }
 
 impl From<Vec<EntityT>> for Column {
     fn from(v: Vec<EntityT>) -> Self {
-        Column::Entity(EntityColumn { data: v })
+        Column::Entity(EntityColumn { data: Buffer::from(v) })
+    }
+}
+
+impl From<Vec<i64>> for Column {
+    fn from(v: Vec<i64>) -> Self {
+        Column::Int(IntColumn { data: Buffer::from(v) })
+    }
+}
+
+impl From<Vec<Nanos>> for Column {
+    fn from(v: Vec<Nanos>) -> Self {
+        Column::Duration(DurationColumn { data: Buffer::from(v) })
+    }
+}
+
+impl From<Vec<Micros>> for Column {
+    fn from(v: Vec<Micros>) -> Self {
+        Column::Timestamp(TimestampColumn { data: Buffer::from(v) })
+    }
+}
+
+impl From<Vec<Point>> for Column {
+    fn from(v: Vec<Point>) -> Self {
+        Column::Point(PointColumn { data: Buffer::from(v) })
+    }
+}
+
+impl From<Vec<Ipv4Addr>> for Column {
+    fn from(v: Vec<Ipv4Addr>) -> Self {
+        Column::Ipv4(Ipv4Column { data: Buffer::from(v) })
+    }
+}
+
+impl From<Vec<Ipv6Addr>> for Column {
+    fn from(v: Vec<Ipv6Addr>) -> Self {
+        Column::Ipv6(Ipv6Column { data: Buffer::from(v) })
+    }
+}
+
+impl From<Vec<bool>> for Column {
+    fn from(v: Vec<bool>) -> Self {
+        let mut mask = BitIndex::for_col_len(v.len());
+        v.iter().enumerate().filter(|(_, x)| **x).for_each(|(i, _)| mask.set(i));
+        Column::Bool(BoolColumn::from_mask(mask))
+    }
+}
+
+// A column of any type, in any of the encodings that can hold its values
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Column {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let col = match Datatype::arbitrary(u)? {
+            Datatype::Bool => Column::from(Vec::<bool>::arbitrary(u)?),
+            Datatype::Num => Column::from(Vec::<f64>::arbitrary(u)?),
+            Datatype::Str => Column::from(Vec::<String>::arbitrary(u)?),
+            Datatype::Entity => Column::from(Vec::<u64>::arbitrary(u)?),
+            Datatype::Int => Column::from(Vec::<i64>::arbitrary(u)?),
+            Datatype::Duration => Column::from(Vec::<Nanos>::arbitrary(u)?),
+            Datatype::Timestamp => Column::from(Vec::<Micros>::arbitrary(u)?),
+            Datatype::Point => Column::from(Vec::<Point>::arbitrary(u)?),
+            Datatype::Ipv4 => Column::from(Vec::<Ipv4Addr>::arbitrary(u)?),
+            Datatype::Ipv6 => Column::from(Vec::<Ipv6Addr>::arbitrary(u)?),
+            Datatype::Json => {
+                let docs: Vec<String> = Vec::<(String, f64, bool)>::arbitrary(u)?.iter()
+                    .map(|(s, x, b)| format!("{{\"s\":{},\"x\":{},\"b\":{}}}", crate::snapshot::json_str(s), if x.is_finite() { *x } else { 0.0 }, b))
+                    .collect();
+                Column::Json(JsonColumn::parse(docs.iter().map(|d| d.as_str())).map_err(|_| arbitrary::Error::IncorrectFormat)?)
+            },
+            // named so that rank order is the reverse of alphabetical order
+            Datatype::Categorical => {
+                let n = u.int_in_range(1 ..= 8u32)?;
+                let names: Vec<String> = (0 .. n).map(|i| format!("c{}", n - i)).collect();
+                let categories = crate::categorical::Categories::new(&names.iter().map(|s| s.as_str()).collect::<Vec<_>>()).expect("names are distinct");
+                let codes = Vec::<u32>::arbitrary(u)?.iter().map(|x| x % n).collect();
+                Column::Categorical(CategoricalColumn::with_codes(alloc::sync::Arc::new(categories), codes))
+            },
+            Datatype::Vector => {
+                let dim = u.int_in_range(1 ..= 4usize)?;
+                let mut data = Vec::<f32>::arbitrary(u)?;
+                data.truncate(data.len() / dim * dim);
+                Column::Vector(VectorColumn::new(dim, data).expect("a whole number of rows"))
+            },
+            // lists of up to 4 numbers each
+            Datatype::List => {
+                let items = Vec::<f64>::arbitrary(u)?;
+                let mut offsets = vec![0];
+                while offsets[offsets.len() - 1] < items.len() {
+                    let next = offsets[offsets.len() - 1] + u.int_in_range(1 ..= 4usize)?;
+                    offsets.push(next.min(items.len()));
+                }
+                Column::List(ListColumn::new(offsets, Column::from(items)).expect("offsets within the items"))
+            },
+            // (x, label) records
+            Datatype::Record => {
+                let records: Vec<Vec<Scalar>> = Vec::<(f64, String)>::arbitrary(u)?.into_iter()
+                    .map(|(x, label)| vec![Scalar::Num(x), Scalar::Str(label)])
+                    .collect();
+                Column::Record(RecordColumn::from_records(vec![Datatype::Num, Datatype::Str], &records).expect("records of the fields"))
+            }
+        };
+        let encoding = encoding::Encoding::arbitrary(u)?;
+        Ok(encoding::encode(col.clone(), encoding).unwrap_or(col))
+    }
+}
+
+// A column from a list of values, typed by Column::from: floats make a Num column, strings
+// an InlineStr one, bools a Bool one, u64s (write `1u64`) an Entity one and i64s an Int one.
+//     col![18.0, 42.0]        col!["alice", "bob"]        col![false; 100]
+#[macro_export]
+macro_rules! col {
+    ($x:expr; $n:expr) => {
+        $crate::Column::from($crate::core::__vec![$x; $n])
+    };
+    ($($x:expr),+ $(,)?) => {
+        $crate::Column::from($crate::core::__vec![$($x),+])
+    };
+}
+
+// Pulling values out into native types. The owned conversions decode encoded columns; the
+// slice ones borrow, so they only work on columns whose plain layout holds exactly that slice.
+
+fn extract_error(col: &Column, wanted: &str) -> VMError {
+    VMError::TypeError(format!("Can't read {} column as {}", col.kind(), wanted))
+}
+
+impl TryFrom<&Column> for Vec<f64> {
+    type Error = VMError;
+
+    fn try_from(col: &Column) -> Result<Self, VMError> {
+        match encoding::plain(col).as_ref() {
+            Column::Num(c) => Ok(c.data.to_vec()),
+            _ => Err(extract_error(col, "Vec<f64>"))
+        }
+    }
+}
+
+impl TryFrom<&Column> for Vec<u64> {
+    type Error = VMError;
+
+    fn try_from(col: &Column) -> Result<Self, VMError> {
+        match encoding::plain(col).as_ref() {
+            Column::Entity(c) => Ok(c.data.to_vec()),
+            _ => Err(extract_error(col, "Vec<u64>"))
+        }
+    }
+}
+
+impl TryFrom<&Column> for Vec<i64> {
+    type Error = VMError;
+
+    fn try_from(col: &Column) -> Result<Self, VMError> {
+        match encoding::plain(col).as_ref() {
+            Column::Int(c) => Ok(c.data.to_vec()),
+            _ => Err(extract_error(col, "Vec<i64>"))
+        }
+    }
+}
+
+impl TryFrom<&Column> for Vec<String> {
+    type Error = VMError;
+
+    fn try_from(col: &Column) -> Result<Self, VMError> {
+        match encoding::plain(col).as_ref() {
+            Column::Str(c) => Ok(c.data.clone()),
+            Column::InlineStr(c) => Ok(c.iter().map(|s| s.to_string()).collect()),
+            _ => Err(extract_error(col, "Vec<String>"))
+        }
+    }
+}
+
+impl TryFrom<&Column> for Vec<bool> {
+    type Error = VMError;
+
+    fn try_from(col: &Column) -> Result<Self, VMError> {
+        match encoding::plain(col).as_ref() {
+            Column::Bool(c) => Ok((0 .. c.data.len()).map(|i| c.data.contains(i)).collect()),
+            _ => Err(extract_error(col, "Vec<bool>"))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Column> for &'a [f64] {
+    type Error = VMError;
+
+    fn try_from(col: &'a Column) -> Result<Self, VMError> {
+        match col {
+            Column::Num(c) => Ok(c.data.as_slice()),
+            _ => Err(extract_error(col, "&[f64]"))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Column> for &'a [u64] {
+    type Error = VMError;
+
+    fn try_from(col: &'a Column) -> Result<Self, VMError> {
+        match col {
+            Column::Entity(c) => Ok(c.data.as_slice()),
+            _ => Err(extract_error(col, "&[u64]"))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Column> for &'a [i64] {
+    type Error = VMError;
+
+    fn try_from(col: &'a Column) -> Result<Self, VMError> {
+        match col {
+            Column::Int(c) => Ok(c.data.as_slice()),
+            _ => Err(extract_error(col, "&[i64]"))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Column> for &'a [String] {
+    type Error = VMError;
+
+    fn try_from(col: &'a Column) -> Result<Self, VMError> {
+        match col {
+            Column::Str(c) => Ok(&c.data),
+            _ => Err(extract_error(col, "&[String]"))
+        }
+    }
+}
+
+// f(a[i], b[i]) for each row of two columns of the same length
+fn binary<T: Native, U: Native, F: Fn(T, T) -> U>(a: &[T], b: &[T], f: F) -> PrimitiveColumn<U> {
+    PrimitiveColumn::new(a.iter().zip(b.iter()).map(|(x, y)| f(*x, *y)).collect())
+}
+
+// The values of a plain string or categorical column, or the documents of a JSON one
+fn str_iter(col: &Column) -> Box<dyn Iterator<Item=&str> + '_> {
+    match col {
+        Column::Str(c) => Box::new(c.data.iter().map(|s| s.as_str())),
+        Column::InlineStr(c) => Box::new(c.iter()),
+        Column::Json(c) => Box::new(c.iter()),
+        Column::Categorical(c) => Box::new(c.iter()),
+        _ => Box::new(core::iter::empty())
+    }
+}
+
+// Float comparison policy, for column equality: values are equal if they're == (so
+// -0.0 equals 0.0) or both NaN. That makes equality reflexive, which is what tests comparing
+// query output want, even though it isn't IEEE semantics.
+pub fn num_eq(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
+
+impl PartialEq for BoolColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+
+impl PartialEq for StrColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+
+// Logical equality: same type, same values in the same order, regardless of physical layout -
+// a Str equals an InlineStr or Dict column with the same strings, and an encoded column
+// equals its decoded form. Numbers follow num_eq.
+impl PartialEq for Column {
+    fn eq(&self, other: &Self) -> bool {
+        if self.datatype() != other.datatype() || self.len() != other.len() {
+            return false;
+        }
+        // nulls equal nulls here, as NaNs do
+        if matches!(self, Column::Nullable(_)) || matches!(other, Column::Nullable(_)) {
+            return (0 .. self.len()).all(|i| self.get(i) == other.get(i));
+        }
+        let (a, b) = (encoding::plain(self), encoding::plain(other));
+        match (a.as_ref(), b.as_ref()) {
+            (Column::Bool(a), Column::Bool(b)) => a == b,
+            (Column::Num(a), Column::Num(b)) => a == b,
+            (Column::Entity(a), Column::Entity(b)) => a == b,
+            (Column::Int(a), Column::Int(b)) => a == b,
+            (Column::Duration(a), Column::Duration(b)) => a == b,
+            (Column::Timestamp(a), Column::Timestamp(b)) => a == b,
+            (Column::Point(a), Column::Point(b)) => a == b,
+            (Column::Ipv4(a), Column::Ipv4(b)) => a == b,
+            (Column::Ipv6(a), Column::Ipv6(b)) => a == b,
+            (Column::Str(a), Column::Str(b)) => a == b,
+            (Column::InlineStr(a), Column::InlineStr(b)) => a == b,
+            (Column::Json(a), Column::Json(b)) => a == b,
+            (Column::Vector(a), Column::Vector(b)) => a == b,
+            (Column::List(a), Column::List(b)) => a == b,
+            (Column::Record(a), Column::Record(b)) => a == b,
+            (Column::Chunked(a), Column::Chunked(b)) => a.decode() == b.decode(),
+            (Column::Categorical(a), Column::Categorical(b)) => a == b,
+            (Column::Str(a), Column::InlineStr(b)) | (Column::InlineStr(b), Column::Str(a)) =>
+                a.data.iter().map(|s| s.as_str()).eq(b.iter()),
+            _ => false
+        }
+    }
+}
+
+// Plain primitive columns of T, one after another
+fn concat_native<T: Native>(parts: &[Cow<Column>], rows: usize) -> Column {
+    let mut data = Buffer::with_capacity(rows);
+    for c in parts {
+        data.extend_from_slice(&T::unwrap(c).expect("parts share a datatype").data);
+    }
+    T::wrap(PrimitiveColumn::new(data))
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scalar::Bool(x) => write!(f, "{}", x),
+            Scalar::Num(x) => write!(f, "{}", x),
+            Scalar::Int(x) => write!(f, "{}", x),
+            Scalar::Str(x) => write!(f, "{:?}", x),
+            Scalar::Entity(x) => write!(f, "#{}", x),
+            Scalar::Duration(x) => write!(f, "{}", x),
+            Scalar::Timestamp(x) => write!(f, "{}", x),
+            Scalar::Point(p) => write!(f, "{}", p),
+            Scalar::Ipv4(x) => write!(f, "{}", x),
+            Scalar::Ipv6(x) => write!(f, "{}", x),
+            Scalar::Json(x) => write!(f, "{}", x),
+            Scalar::Record(xs) => {
+                write!(f, "(")?;
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, ")")
+            },
+            Scalar::Vector(xs) => write!(f, "{:?}", xs),
+            Scalar::Null => write!(f, "null"),
+            Scalar::List(xs) => {
+                write!(f, "[")?;
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, "]")
+            }
+        }
     }
 }
 
@@ -235,9 +1552,303 @@ impl fmt::Display for Column {
             Column::Bool(c) => write!(f, "Bool[{}]", c.data),
             Column::Num(c) => write!(f, "Num[{:?}]", c.data),
             Column::Str(c) => write!(f, "Str[{:?}]", c.data),
-            Column::InlineStr(c) => write!(f, "Str[{:?}]", c.data),
-            Column::Entity(c) => write!(f, "Entity[{:?}]", c.data)
+            Column::InlineStr(c) => write!(f, "Str[{:?}]", c.iter().collect::<Vec<_>>()),
+            Column::Json(c) => write!(f, "Json[{}]", c.iter().collect::<Vec<_>>().join(", ")),
+            Column::Vector(c) => write!(f, "Vector[{:?}]", c.iter().collect::<Vec<_>>()),
+            Column::List(c) => write!(f, "List[{}]", c.iter().map(|list| list.to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Record(c) => write!(f, "Record[{}]", (0 .. c.len()).map(|i| c.get(i).expect("row in bounds").to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Chunked(c) => write!(f, "Chunked[{}]", c.chunks().iter().map(|chunk| chunk.to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Nullable(c) => write!(f, "Nullable[{}]", (0 .. c.len()).map(|i| c.get(i).unwrap().to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Categorical(c) => write!(f, "Categorical[{}]", c.iter().collect::<Vec<_>>().join(", ")),
+            Column::Entity(c) => write!(f, "Entity[{:?}]", c.data),
+            Column::Int(c) => write!(f, "Int[{:?}]", c.data),
+            Column::Duration(c) => write!(f, "Duration[{}]", c.data.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Timestamp(c) => write!(f, "Timestamp[{}]", c.data.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Point(c) => write!(f, "Point[{}]", c.data.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Ipv4(c) => write!(f, "Ipv4[{}]", c.data.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Ipv6(c) => write!(f, "Ipv6[{}]", c.data.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+            Column::Rle(c) => write!(f, "Rle({})", c.decode()),
+            Column::Delta(c) => write!(f, "Delta({})", c.decode()),
+            Column::Packed(c) => write!(f, "Packed({})", c.decode()),
+            Column::For(c) => write!(f, "For({})", c.decode()),
+            Column::Dict(c) => write!(f, "Dict({})", c.decode())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_columns_are_well_formed() {
+        use arbitrary::{Arbitrary, Unstructured};
+        let mut rng = crate::datagen::Rng::new(969);
+        let mut kinds = crate::core::HashSet::new();
+        for _ in 0 .. 500 {
+            let bytes: Vec<u8> = (0 .. 256).map(|_| rng.below(256) as u8).collect();
+            let col = Column::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+            kinds.insert(col.kind());
+            for i in 0 .. col.len() {
+                assert!(col.get(i).is_some(), "{} row {} of {}", col.kind(), i, col.len());
+            }
+            assert_eq!(encoding::plain(&col).len(), col.len());
+        }
+        assert!(kinds.len() > 4, "{:?}", kinds);
+    }
+    #[test]
+    fn memory_usage_counts_heap_allocations() {
+        let base = size_of::<Column>();
+        // numeric data lives in 64-byte padded buffers
+        assert_eq!(Column::from(vec![1.0, 2.0]).memory_usage(), base + 64);
+        assert_eq!(Column::from(vec![7 as EntityT; 10]).memory_usage(), base + 128);
+
+        let boxed = Column::Str(StrColumn::new(vec!["ab".to_string(), "cde".to_string()]));
+        assert_eq!(boxed.memory_usage(), base + 2 * size_of::<String>() + 5);
+        // bytes, offsets and prefixes, a padded buffer each
+        assert_eq!(Column::from(vec!["ab", "cde"]).memory_usage(), base + 3 * 64);
+
+        // a mask of 130 rows takes three words
+        let mask = Column::Bool(Column::from(vec![1.0; 130]).filter(Scalar::Num(1.0)).unwrap());
+        assert_eq!(mask.memory_usage(), base + 3 * size_of::<u64>());
+    }
+
+    #[test]
+    fn vm_memory_usage_sums_its_columns() {
+        let columns = vec![Column::from((0 .. 50).map(f64::from).collect::<Vec<_>>()), Column::from(vec!["x"; 50])];
+        let expected: usize = columns.iter().map(|c| c.memory_usage()).sum();
+        assert_eq!(crate::vm::VM::new(crate::table::Table::from_columns(columns).unwrap()).memory_usage(), expected);
+        assert_eq!(crate::vm::VM::new(crate::table::Table::from_columns(vec![]).unwrap()).memory_usage(), 0);
+    }
+
+    #[test]
+    fn inline_str_equality_checks_the_whole_value() {
+        // values sharing a length, a prefix or both with the needle, and zero bytes that
+        // look like the prefix padding
+        let values = vec!["", "abcd", "abcde", "abcdf", "abce", "ab", "ab\0", "ab\0\0\0", "xbcde", "abcdefgh"];
+        let col = InlineStrColumn::from_strs(values.clone());
+        for needle in ["", "ab", "ab\0", "abcd", "abcde", "abcdefgh", "abcdefgi", "zz"] {
+            let mask = col.filter(Scalar::Str(needle.to_string())).unwrap();
+            let mut found = Vec::new();
+            mask.selection().for_each(|i| found.push(values[i]));
+            let expected: Vec<&str> = values.iter().copied().filter(|v| *v == needle).collect();
+            assert_eq!(found, expected, "needle {:?}", needle);
+        }
+    }
+
+    #[test]
+    fn selecting_inline_strs_keeps_their_prefixes() {
+        let col = InlineStrColumn::from_strs(vec!["apple", "kiwi", "banana", "kiwi"]);
+        let mask = col.filter(Scalar::Str("kiwi".to_string())).unwrap().selection().inverted();
+        let picked = col.select(&BoolColumn::from_selection(mask));
+        let mask = picked.filter(Scalar::Str("banana".to_string())).unwrap();
+        assert_eq!((mask.selection().len(), mask.count_ones()), (2, 1));
+        assert_eq!(Column::InlineStr(picked).fingerprint(), Column::from(vec!["apple", "banana"]).fingerprint());
+    }
+
+    #[test]
+    fn slices_match_the_rows_they_cover() {
+        let strs = vec!["a", "bb", "", "dddd", "eeeee", "f"];
+        let cols = [
+            (Column::from(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]), Column::from(vec![3.0, 4.0, 5.0])),
+            (Column::from(vec![1u64, 2, 3, 4, 5, 6]), Column::from(vec![3u64, 4, 5])),
+            (Column::from(strs.clone()), Column::from(vec!["", "dddd", "eeeee"])),
+            (Column::InlineStr(InlineStrColumn::from_strs(strs)), Column::from(vec!["", "dddd", "eeeee"])),
+            (Column::from(vec![0.0, 1.0, 1.0, 0.0, 1.0, 0.0]).filter(Scalar::Num(1.0)).map(Column::Bool).unwrap(),
+             Column::from(vec![1.0, 0.0, 1.0]).filter(Scalar::Num(1.0)).map(Column::Bool).unwrap()),
+        ];
+        for (col, expected) in &cols {
+            let slice = col.slice(2, 3);
+            assert_eq!(slice.len(), 3);
+            assert_eq!(slice.fingerprint(), expected.fingerprint(), "{}", col.kind());
+            // out of range bounds are clamped
+            assert_eq!((col.slice(4, 10).len(), col.slice(10, 1).len()), (2, 0));
+        }
+
+        // slices keep working on the shared data: filter, select, and slice again
+        let inline = cols[3].0.slice(1, 4);
+        let mask = inline.filter(Scalar::Str("eeeee".to_string())).unwrap();
+        assert_eq!(mask.count_ones(), 1);
+        assert_eq!(inline.select(&mask).fingerprint(), Column::from(vec!["eeeee"]).fingerprint());
+        assert_eq!(inline.slice(1, 1).fingerprint(), Column::from(vec![""]).fingerprint());
+    }
+
+    #[test]
+    fn chunks_cover_the_column() {
+        let col = Column::from((0 .. 10).map(|i| i as f64).collect::<Vec<_>>());
+        let chunks = col.chunks(4);
+        assert_eq!(chunks.iter().map(|c| c.len()).collect::<Vec<_>>(), vec![4, 4, 2]);
+        assert_eq!(chunks[2].fingerprint(), Column::from(vec![8.0, 9.0]).fingerprint());
+        assert!(Column::from(Vec::<f64>::new()).chunks(3).is_empty());
+    }
+
+    #[test]
+    fn string_data_is_stored_inline() {
+        let col = Column::from(vec!["x", "", "yz"]);
+        assert!(matches!(&col, Column::InlineStr(c) if c.iter().collect::<Vec<_>>() == vec!["x", "", "yz"]));
+        let owned = Column::from(vec!["x".to_string(), "".to_string(), "yz".to_string()]);
+        assert!(matches!(owned, Column::InlineStr(_)));
+        assert_eq!(owned.fingerprint(), col.fingerprint());
+        assert_eq!(col.to_string(), "Str[[\"x\", \"\", \"yz\"]]");
+
+        let inline: InlineStrColumn = vec!["a", "bc", "d"].into_iter().collect();
+        assert_eq!((inline.len(), inline.value(1), inline.is_empty()), (3, "bc", false));
+        assert_eq!(format!("{:?}", inline), "InlineStrColumn([\"a\", \"bc\", \"d\"])");
+    }
+
+    #[test]
+    fn inline_strs_compare_by_value() {
+        let whole = InlineStrColumn::from_strs(vec!["a", "bc", "d", "bc"]);
+        let slice = match Column::InlineStr(InlineStrColumn::from_strs(vec!["a", "bc", "d", "bc"])).slice(1, 2) {
+            Column::InlineStr(c) => c,
+            _ => unreachable!()
+        };
+        assert_eq!(slice, InlineStrColumn::from_strs(vec!["bc", "d"]));
+        assert_ne!(slice, whole);
+        assert_ne!(InlineStrColumn::from_strs(vec!["ab", ""]), InlineStrColumn::from_strs(vec!["a", "b"]));
+        let mask = whole.filter(Scalar::Str("bc".to_string())).unwrap();
+        assert_eq!(whole.select(&mask), InlineStrColumn::from(vec!["bc".to_string(), "bc".to_string()]));
+    }
+
+    #[test]
+    fn converts_into_native_vectors() {
+        let nums = Column::from(vec![1.5, 2.0]);
+        assert_eq!(Vec::<f64>::try_from(&nums).unwrap(), vec![1.5, 2.0]);
+        assert_eq!(Vec::<u64>::try_from(&Column::from(vec![3 as EntityT, 4])).unwrap(), vec![3, 4]);
+        assert_eq!(Vec::<String>::try_from(&Column::from(vec!["a", "bc"])).unwrap(), vec!["a", "bc"]);
+        assert_eq!(Vec::<String>::try_from(&Column::Str(StrColumn::new(vec!["d".to_string()]))).unwrap(), vec!["d"]);
+        let mask = Column::Bool(Column::from(vec![1.0, 0.0, 1.0]).filter(Scalar::Num(1.0)).unwrap());
+        assert_eq!(Vec::<bool>::try_from(&mask).unwrap(), vec![true, false, true]);
+
+        // encoded columns decode on the way out
+        let rle = encoding::encode(Column::from(vec![5.0; 100]), encoding::Encoding::Rle).unwrap();
+        assert_eq!(Vec::<f64>::try_from(&rle).unwrap(), vec![5.0; 100]);
+        let dict = encoding::encode(Column::from(vec!["x"; 100]), encoding::Encoding::Dict).unwrap();
+        assert_eq!(Vec::<String>::try_from(&dict).unwrap(), vec!["x"; 100]);
+    }
+
+    #[test]
+    fn slices_borrow_only_the_matching_plain_layout() {
+        let nums = Column::from(vec![1.5, 2.0]);
+        assert_eq!(<&[f64]>::try_from(&nums).unwrap(), &[1.5, 2.0]);
+        let ids = Column::from(vec![3 as EntityT]);
+        assert_eq!(<&[u64]>::try_from(&ids).unwrap(), &[3]);
+        let boxed = Column::Str(StrColumn::new(vec!["d".to_string()]));
+        assert_eq!(<&[String]>::try_from(&boxed).unwrap(), &["d".to_string()]);
+
+        // inline strings and encoded columns don't hold a slice to lend
+        assert!(matches!(<&[String]>::try_from(&Column::from(vec!["d"])), Err(VMError::TypeError(_))));
+        let rle = encoding::encode(Column::from(vec![5.0; 100]), encoding::Encoding::Rle).unwrap();
+        assert!(matches!(<&[f64]>::try_from(&rle), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn mismatched_types_are_type_errors() {
+        let nums = Column::from(vec![1.5]);
+        assert!(matches!(Vec::<u64>::try_from(&nums), Err(VMError::TypeError(_))));
+        assert!(matches!(Vec::<String>::try_from(&nums), Err(VMError::TypeError(_))));
+        assert!(matches!(Vec::<bool>::try_from(&nums), Err(VMError::TypeError(_))));
+        assert!(matches!(Vec::<f64>::try_from(&Column::from(vec!["a"])), Err(VMError::TypeError(_))));
+        assert!(matches!(<&[u64]>::try_from(&nums), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn selecting_from_bool_columns_keeps_their_values() {
+        let flags = Column::from(vec![true, false, true, true, false]);
+        let mask = Column::from(vec![1.0, 1.0, 0.0, 1.0, 1.0]).filter(Scalar::Num(1.0)).unwrap();
+        assert_eq!(Vec::<bool>::try_from(&flags.select(&mask)).unwrap(), vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn concat_joins_rows_of_one_type() {
+        let nums = Column::concat(&[Column::from(vec![1.0]), encoding::encode(Column::from(vec![2.0; 100]), encoding::Encoding::Rle).unwrap()]).unwrap();
+        assert_eq!(nums.kind(), "Num");
+        assert_eq!(Vec::<f64>::try_from(&nums).unwrap().len(), 101);
+        let strs = Column::concat(&[Column::from(vec!["a"]), Column::Str(StrColumn::new(vec!["b".to_string()]))]).unwrap();
+        assert_eq!(strs, Column::from(vec!["a", "b"]));
+        assert_eq!(strs.kind(), "InlineStr");
+        let flags = Column::concat(&[Column::from(vec![true, false]), Column::from(vec![true])]).unwrap();
+        assert_eq!(Vec::<bool>::try_from(&flags).unwrap(), vec![true, false, true]);
+
+        let dict = |v| encoding::encode(Column::from(vec![v; 100]), encoding::Encoding::Dict).unwrap();
+        assert_eq!(Column::concat(&[dict("x"), dict("y")]).unwrap().kind(), "Dict");
+
+        assert!(matches!(Column::concat(&[]), Err(VMError::TypeError(_))));
+        assert!(matches!(Column::concat(&[Column::from(vec![1.0]), Column::from(vec![1u64])]), Err(VMError::TypeError(_))));
+    }
+
+    fn rows_in(col: &Column, set: &Column) -> Vec<usize> {
+        let mask = col.filter_in(set, NullSemantics::default()).unwrap();
+        let mut res = vec![];
+        mask.selection().for_each(|i| res.push(i));
+        res
+    }
+
+    #[test]
+    fn filter_in_matches_values_as_filter_eq_does() {
+        let nums = Column::from(vec![0.0, f64::NAN, 2.0, -0.0, 3.0]);
+        assert_eq!(rows_in(&nums, &Column::from(vec![-0.0, f64::NAN, 3.0])), vec![0, 3, 4]);
+        let ids = Column::from(vec![5u64, 6, 5, 7]);
+        assert_eq!(rows_in(&ids, &Column::from(vec![5u64, 9])), vec![0, 2]);
+        assert_eq!(rows_in(&ids, &Column::from(Vec::<u64>::new())), Vec::<usize>::new());
+
+        let flags = Column::from(vec![true, false, true]);
+        assert_eq!(rows_in(&flags, &Column::from(vec![false])), vec![1]);
+        assert_eq!(rows_in(&flags, &Column::from(vec![true, false])), vec![0, 1, 2]);
+
+        // string layouts and encodings mix freely
+        let names = Column::from(vec!["ann", "bo", "cy", "bo"]);
+        let boxed = Column::Str(StrColumn::new(vec!["bo".to_string(), "dee".to_string()]));
+        assert_eq!(rows_in(&names, &boxed), vec![1, 3]);
+        let dict = encoding::encode(Column::from(vec!["cy"; 100]), encoding::Encoding::Dict).unwrap();
+        assert_eq!(rows_in(&names, &dict), vec![2]);
+        let rle = encoding::encode(Column::from(vec![5u64; 100]), encoding::Encoding::Rle).unwrap();
+        assert_eq!(rows_in(&rle, &ids).len(), 100);
+    }
+
+    #[test]
+    fn filter_in_finds_vectors_row_by_row() {
+        let rows = VectorColumn::from_rows(2, &[[0.0, 1.0], [-0.0, 1.0], [f32::NAN, 1.0], [1.0, 0.0]]).unwrap();
+        let set = VectorColumn::from_rows(2, &[[0.0, 1.0], [f32::NAN, 1.0]]).unwrap();
+        let (rows, set) = (Column::Vector(rows), Column::Vector(set));
+        assert_eq!(rows_in(&rows, &set), vec![0, 1]);
+        let mut matched = vec![];
+        rows.filter_in(&set, NullSemantics::NullEqualsNull).unwrap().selection().for_each(|i| matched.push(i));
+        assert_eq!(matched, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn filter_in_needs_matching_types() {
+        let err = Column::from(vec![1.0]).filter_in(&Column::from(vec![1u64]), NullSemantics::default()).unwrap_err();
+        assert!(matches!(err, VMError::TypeError(_)));
+        let err = Column::from(vec!["a"]).filter_in(&Column::from(vec![1.0]), NullSemantics::default()).unwrap_err();
+        assert!(matches!(err, VMError::TypeError(_)));
+    }
+
+    #[test]
+    fn filter_planned_says_how_it_filtered() {
+        let ids = Column::from((0 .. 10_000u64).map(|i| i % 1000).collect::<Vec<_>>());
+        let sorted = Column::from((0 .. 10_000u64).map(|i| i / 10).collect::<Vec<_>>());
+        let words = Column::from((0 .. 10_000).map(|i| if i % 2 == 0 { "even" } else { "odd" }).collect::<Vec<_>>());
+        let dict = encoding::encode(words.clone(), encoding::Encoding::Dict).unwrap();
+        let delta = encoding::encode(sorted.clone(), encoding::Encoding::Delta).unwrap();
+        let odd = Scalar::Str("odd".to_string());
+        let cases = [
+            (&ids, Scalar::Entity(5), "scan to positions (0.1% of 8192 sampled)"),
+            (&dict, odd.clone(), "scan to bitmap (50.0% of 8192 sampled)"),
+            (&delta, Scalar::Entity(5), "index lookup"),
+            (&words, odd, "scan to bitmap (50.0% of 10000 sampled)")
+        ];
+        for (col, val, plan) in cases.iter() {
+            let (mask, found) = col.filter_planned(val.clone()).unwrap();
+            assert_eq!(found.to_string(), *plan);
+            assert_eq!(mask.selection(), col.filter(val.clone()).unwrap().selection());
         }
+        assert!(ids.filter_planned(Scalar::Num(1.0)).is_err());
     }
 }
 
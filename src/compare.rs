@@ -0,0 +1,305 @@
+// Ordering of values, for sorting, grouping and min/max. The rules, shared by Scalar's Ord
+// and the column comparators here:
+//  - numbers: -0.0 equals 0.0, and NaN equals NaN and sorts after every other number
+//    (as in Postgres), so NaNs collect at the end of an ascending sort
+//  - strings: bytewise, i.e. by code point, unless a Comparator is given a collation
+//  - values of different types order by type: Bool < Num < Str < Entity < Record < Vector <
+//    List < Int < Timestamp, and Null comes after every value (as in Postgres, ascending)
+//  - records: field by field, then the shorter one first, and vectors and lists likewise
+
+use crate::collation::Collation;
+use crate::column::{Column, Scalar};
+use crate::core::prelude::*;
+use crate::encoding;
+use crate::primitive::match_primitive;
+
+use alloc::borrow::Cow;
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering;
+
+pub fn cmp_f64(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap()
+    }
+}
+
+fn type_rank(s: &Scalar) -> u8 {
+    match s {
+        Scalar::Bool(_) => 0,
+        Scalar::Num(_) => 1,
+        Scalar::Str(_) => 2,
+        Scalar::Entity(_) => 3,
+        Scalar::Duration(_) => 4,
+        Scalar::Point(_) => 5,
+        Scalar::Ipv4(_) => 6,
+        Scalar::Ipv6(_) => 7,
+        Scalar::Json(_) => 8,
+        Scalar::Record(_) => 9,
+        Scalar::Vector(_) => 10,
+        Scalar::List(_) => 11,
+        Scalar::Int(_) => 12,
+        Scalar::Timestamp(_) => 13,
+        Scalar::Null => 14
+    }
+}
+
+pub fn cmp_scalar(a: &Scalar, b: &Scalar) -> Ordering {
+    match (a, b) {
+        (Scalar::Bool(x), Scalar::Bool(y)) => x.cmp(y),
+        (Scalar::Num(x), Scalar::Num(y)) => cmp_f64(*x, *y),
+        (Scalar::Str(x), Scalar::Str(y)) => x.cmp(y),
+        (Scalar::Entity(x), Scalar::Entity(y)) => x.cmp(y),
+        (Scalar::Int(x), Scalar::Int(y)) => x.cmp(y),
+        (Scalar::Duration(x), Scalar::Duration(y)) => x.cmp(y),
+        (Scalar::Timestamp(x), Scalar::Timestamp(y)) => x.cmp(y),
+        (Scalar::Point(x), Scalar::Point(y)) => cmp_f64(x.x, y.x).then(cmp_f64(x.y, y.y)),
+        (Scalar::Ipv4(x), Scalar::Ipv4(y)) => x.cmp(y),
+        (Scalar::Ipv6(x), Scalar::Ipv6(y)) => x.cmp(y),
+        (Scalar::Json(x), Scalar::Json(y)) => x.cmp(y),
+        (Scalar::Record(xs), Scalar::Record(ys)) | (Scalar::List(xs), Scalar::List(ys)) => xs.iter().zip(ys.iter())
+            .map(|(x, y)| cmp_scalar(x, y))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| xs.len().cmp(&ys.len())),
+        (Scalar::Vector(xs), Scalar::Vector(ys)) => cmp_vectors(xs, ys),
+        _ => type_rank(a).cmp(&type_rank(b))
+    }
+}
+
+fn cmp_vectors(xs: &[f32], ys: &[f32]) -> Ordering {
+    xs.iter().zip(ys)
+        .map(|(x, y)| cmp_f64(*x as f64, *y as f64))
+        .find(|o| *o != Ordering::Equal)
+        .unwrap_or_else(|| xs.len().cmp(&ys.len()))
+}
+
+// Compares rows of one column without building a Scalar per comparison. Encoded columns are
+// decoded once, up front.
+pub struct Comparator<'a> {
+    col: Cow<'a, Column>,
+    collation: Option<&'a Collation>    // for strings; None is bytewise
+}
+
+impl<'a> Comparator<'a> {
+    pub fn new(col: &'a Column) -> Self {
+        Comparator { col: encoding::plain(col), collation: None }
+    }
+
+    // Ordering strings by `collation`
+    pub fn with_collation(col: &'a Column, collation: &'a Collation) -> Self {
+        Comparator { col: encoding::plain(col), collation: Some(collation) }
+    }
+
+    fn cmp_str(&self, a: &str, b: &str) -> Ordering {
+        match self.collation {
+            Some(collation) => collation.cmp(a, b),
+            None => a.cmp(b)
+        }
+    }
+
+    // Rows i and j, which must be in bounds
+    pub fn cmp(&self, i: usize, j: usize) -> Ordering {
+        match_primitive!(self.col.as_ref(), c => c.cmp_rows(i, j),
+            Column::Bool(c) => c.selection().contains(i).cmp(&c.selection().contains(j)),
+            Column::Str(c) => self.cmp_str(&c.data[i], &c.data[j]),
+            Column::InlineStr(c) => self.cmp_str(c.value(i), c.value(j)),
+            Column::Json(c) => c.docs.value(i).cmp(c.docs.value(j)),
+            Column::Vector(c) => cmp_vectors(c.row(i), c.row(j)),
+            Column::List(c) => cmp_scalar(&c.get(i).unwrap(), &c.get(j).unwrap()),
+            Column::Record(c) => cmp_scalar(&c.get(i).unwrap(), &c.get(j).unwrap()),
+            // nulls after every value
+            Column::Nullable(c) => match (c.is_valid(i), c.is_valid(j)) {
+                (true, true) => Comparator { col: encoding::plain(c.values()), collation: self.collation }.cmp(i, j),
+                (a, b) => b.cmp(&a)
+            },
+            // by rank, so sorting and grouping follow the declared order
+            Column::Categorical(c) => c.codes()[i].cmp(&c.codes()[j]),
+            _ => unreachable!("plain() returns plain columns")
+        )
+    }
+}
+
+// Row numbers in ascending order of value; equal values keep their row order
+pub fn argsort(col: &Column) -> Vec<usize> {
+    sort_rows(Comparator::new(col), col.len())
+}
+
+// argsort, with strings in `collation` order
+pub fn argsort_collated(col: &Column, collation: &Collation) -> Vec<usize> {
+    sort_rows(Comparator::with_collation(col, collation), col.len())
+}
+
+fn sort_rows(cmp: Comparator, rows: usize) -> Vec<usize> {
+    let mut idx: Vec<usize> = (0 .. rows).collect();
+    idx.sort_by(|i, j| cmp.cmp(*i, *j));
+    idx
+}
+
+// The rows of the `k` largest numbers, largest first, and of equal numbers the first rows.
+// NaN (null) rows are left out, as SQL leaves nulls to the end. One pass, keeping the best k
+// seen so far in a heap, so it's cheaper than a sort when k is small.
+pub fn top_k(xs: &[f64], k: usize) -> Vec<usize> {
+    top_k_by(xs.iter().copied().enumerate().filter(|(_, x)| !x.is_nan()), k, cmp_f64)
+}
+
+// top_k, of integers
+pub fn top_k_int(xs: &[i64], k: usize) -> Vec<usize> {
+    top_k_by(xs.iter().copied().enumerate(), k, |a, b| a.cmp(&b))
+}
+
+fn top_k_by<T: Copy, I: Iterator<Item=(usize, T)>>(rows: I, k: usize, cmp: fn(T, T) -> Ordering) -> Vec<usize> {
+    let mut heap: BinaryHeap<Ranked<T>> = BinaryHeap::with_capacity(k.min(rows.size_hint().0) + 1);
+    for (row, x) in rows {
+        let r = Ranked(x, row, cmp);
+        if heap.len() < k {
+            heap.push(r);
+        } else if heap.peek().is_some_and(|worst| r < *worst) {
+            heap.pop();
+            heap.push(r);
+        }
+    }
+    heap.into_sorted_vec().into_iter().map(|Ranked(_, row, _)| row).collect()
+}
+
+// A number and its row, ordered best first: larger numbers, then earlier rows. The heap's
+// top is then the worst of those kept, the one to drop.
+struct Ranked<T>(T, usize, fn(T, T) -> Ordering);
+
+impl<T: Copy> PartialEq for Ranked<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Copy> Eq for Ranked<T> {}
+
+impl<T: Copy> Ord for Ranked<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.2)(other.0, self.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl<T: Copy> PartialOrd for Ranked<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn extreme(col: &Column, cmp: Comparator, keep: Ordering) -> Option<Scalar> {
+    let best = (1 .. col.len()).fold(0, |best, i| if cmp.cmp(i, best) == keep { i } else { best });
+    col.get(best)
+}
+
+// The smallest value; None for an empty column
+pub fn min(col: &Column) -> Option<Scalar> {
+    extreme(col, Comparator::new(col), Ordering::Less)
+}
+
+// The largest value, which is NaN if the column holds any
+pub fn max(col: &Column) -> Option<Scalar> {
+    extreme(col, Comparator::new(col), Ordering::Greater)
+}
+
+// min and max, with strings in `collation` order; of equal strings, the first one
+pub fn min_collated(col: &Column, collation: &Collation) -> Option<Scalar> {
+    extreme(col, Comparator::with_collation(col, collation), Ordering::Less)
+}
+
+pub fn max_collated(col: &Column, collation: &Collation) -> Option<Scalar> {
+    extreme(col, Comparator::with_collation(col, collation), Ordering::Greater)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duration::Nanos;
+    use crate::geo::Point;
+    use crate::ip::{Ipv4Addr, Ipv6Addr};
+    use crate::encoding::Encoding;
+
+    use crate::core::StableHasher;
+    use core::hash::{Hash, Hasher};
+
+    fn hash(s: &Scalar) -> u64 {
+        let mut h = StableHasher::default();
+        s.hash(&mut h);
+        h.finish()
+    }
+
+    #[test]
+    fn zeros_and_nans_are_equal_and_hash_alike() {
+        assert_eq!(Scalar::Num(-0.0), Scalar::Num(0.0));
+        assert_eq!(hash(&Scalar::Num(-0.0)), hash(&Scalar::Num(0.0)));
+        assert_eq!(Scalar::Num(f64::NAN), Scalar::Num(-f64::NAN));
+        assert_eq!(hash(&Scalar::Num(f64::NAN)), hash(&Scalar::Num(-f64::NAN)));
+        let rec = |x| Scalar::Record(vec![Scalar::Num(x)]);
+        assert_eq!(hash(&rec(-0.0)), hash(&rec(0.0)));
+        assert_ne!(Scalar::Num(1.0), Scalar::Entity(1));
+    }
+
+    #[test]
+    fn nan_sorts_after_every_number() {
+        let mut xs: Vec<Scalar> = [f64::NAN, 1.0, f64::INFINITY, -f64::INFINITY, -0.0].iter().copied().map(Scalar::Num).collect();
+        xs.sort();
+        assert_eq!(xs.iter().map(|x| format!("{:?}", x)).collect::<Vec<_>>(),
+                   vec!["Num(-inf)", "Num(-0.0)", "Num(1.0)", "Num(inf)", "Num(NaN)"]);
+    }
+
+    #[test]
+    fn types_strings_and_records_order_as_documented() {
+        let mut xs = [
+            Scalar::Vector(vec![]), Scalar::Record(vec![]), Scalar::Json("{}".to_string()), Scalar::Ipv6(Ipv6Addr::LOCALHOST), Scalar::Ipv4(Ipv4Addr::LOCALHOST),
+            Scalar::Point(Point::new(0.0, 0.0)), Scalar::Duration(Nanos(-1)), Scalar::Entity(0), Scalar::Str("a".to_string()),
+            Scalar::Num(5.0), Scalar::Bool(true)
+        ];
+        xs.sort();
+        assert_eq!(xs.iter().map(type_rank).collect::<Vec<_>>(), (0 ..= 10).collect::<Vec<_>>());
+        // bytewise: uppercase before lowercase, and 'é' after 'z'
+        assert!(Scalar::Str("Z".to_string()) < Scalar::Str("a".to_string()));
+        assert!(Scalar::Str("z".to_string()) < Scalar::Str("é".to_string()));
+
+        let rec = |xs: &[f64]| Scalar::Record(xs.iter().copied().map(Scalar::Num).collect());
+        assert!(rec(&[1.0, 2.0]) < rec(&[1.0, 3.0]));
+        assert!(rec(&[1.0]) < rec(&[1.0, 0.0]));
+        assert!(rec(&[2.0]) > rec(&[1.0, 9.0]));
+        let vec = |xs: &[f32]| Scalar::Vector(xs.to_vec());
+        assert!(vec(&[1.0, 2.0]) < vec(&[1.0, f32::NAN]));
+        assert!(vec(&[1.0]) < vec(&[1.0, 0.0]));
+        assert_eq!(cmp_scalar(&vec(&[-0.0]), &vec(&[0.0])), Ordering::Equal);
+    }
+
+    #[test]
+    fn top_k_keeps_the_largest_first_rows_first() {
+        let xs = [3.0, f64::NAN, 7.0, 3.0, -1.0, 7.0];
+        assert_eq!(top_k(&xs, 3), vec![2, 5, 0]);
+        assert_eq!(top_k(&xs, 10), vec![2, 5, 0, 3, 4]);
+        assert!(top_k(&xs, 0).is_empty());
+    }
+
+    #[test]
+    fn argsort_is_stable_and_matches_scalar_order() {
+        let col = Column::from(vec![2.0, f64::NAN, 1.0, 2.0, -0.0, 0.0]);
+        assert_eq!(argsort(&col), vec![4, 5, 2, 0, 3, 1]);
+        let strs = Column::from(vec!["b", "a", "b", "A"]);
+        assert_eq!(argsort(&strs), vec![3, 1, 0, 2]);
+        // encoded columns sort like their plain form
+        let keys: Vec<u64> = (0 .. 300).map(|i| (i * 7919) % 13).collect();
+        let plain = Column::from(keys);
+        let packed = encoding::encode(plain.clone(), Encoding::Packed).unwrap();
+        assert_eq!(argsort(&packed), argsort(&plain));
+        let sorted: Vec<Scalar> = argsort(&plain).into_iter().map(|i| plain.get(i).unwrap()).collect();
+        assert!(sorted.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn min_and_max() {
+        let col = Column::from(vec![3.0, f64::NAN, -1.0]);
+        assert_eq!(min(&col), Some(Scalar::Num(-1.0)));
+        assert_eq!(max(&col), Some(Scalar::Num(f64::NAN)));
+        let rle = encoding::encode(Column::from(vec![4u64; 100]), Encoding::Rle).unwrap();
+        assert_eq!((min(&rle), max(&rle)), (Some(Scalar::Entity(4)), Some(Scalar::Entity(4))));
+        assert_eq!(min(&Column::from(Vec::<f64>::new())), None);
+    }
+}
@@ -0,0 +1,476 @@
+// Choosing values row by row, for CASE WHEN: each output row comes from one of two branches,
+// according to a mask. A branch is a column, with a value per row, or one value for every
+// row. Branches are decoded once up front, and the output is plain.
+//
+// Filling in missing values is built on the same pieces, with the rows that are missing given
+// as a mask alongside the column - a Nullable column's are its validity bitmap, inverted.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::categorical;
+use crate::column::{BoolColumn, Column, InlineStrColumn, Scalar};
+use crate::duration::Nanos;
+use crate::geo::Point;
+use crate::ip::{Ipv4Addr, Ipv6Addr};
+use crate::json::{self, JsonColumn};
+use crate::nulls::{self, NullableColumn};
+use crate::core::prelude::*;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::primitive::{Native, PrimitiveColumn};
+use crate::schema::Datatype;
+use crate::selection::Selection;
+use crate::timestamp::Micros;
+use crate::vector::VectorColumn;
+
+use alloc::borrow::Cow;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Branch<'a> {
+    Column(&'a Column),
+    Scalar(&'a Scalar)
+}
+
+impl Branch<'_> {
+    fn datatype(&self) -> Result<Datatype, VMError> {
+        match self {
+            Branch::Column(c) => Ok(c.datatype()),
+            Branch::Scalar(Scalar::Bool(_)) => Ok(Datatype::Bool),
+            Branch::Scalar(Scalar::Num(_)) => Ok(Datatype::Num),
+            Branch::Scalar(Scalar::Int(_)) => Ok(Datatype::Int),
+            Branch::Scalar(Scalar::Str(_)) => Ok(Datatype::Str),
+            Branch::Scalar(Scalar::Entity(_)) => Ok(Datatype::Entity),
+            Branch::Scalar(Scalar::Duration(_)) => Ok(Datatype::Duration),
+            Branch::Scalar(Scalar::Timestamp(_)) => Ok(Datatype::Timestamp),
+            Branch::Scalar(Scalar::Point(_)) => Ok(Datatype::Point),
+            Branch::Scalar(Scalar::Ipv4(_)) => Ok(Datatype::Ipv4),
+            Branch::Scalar(Scalar::Ipv6(_)) => Ok(Datatype::Ipv6),
+            Branch::Scalar(Scalar::Json(_)) => Ok(Datatype::Json),
+            Branch::Scalar(Scalar::Vector(_)) => Ok(Datatype::Vector),
+            Branch::Scalar(s) => Err(VMError::TypeError(format!("Can't make a column of {:?}", s)))
+        }
+    }
+
+    fn mismatch(&self, dtype: Datatype) -> VMError {
+        VMError::TypeError(format!("Expected {} values, got: {:?}", dtype, self))
+    }
+}
+
+// Per row: `then` where `cond` is set, `els` where it isn't. Column branches must have a row
+// per row of `cond`, and both branches the same datatype; a null scalar goes with any.
+pub fn if_else(cond: &Selection, then: Branch, els: Branch) -> Result<Column, VMError> {
+    if has_nulls(then) || has_nulls(els) {
+        return if_else_nullable(cond, then, els);
+    }
+    let dtype = match (then.datatype()?, els.datatype()?) {
+        (a, b) if a == b => a,
+        // a category name goes with a categorical column
+        (Datatype::Categorical, Datatype::Str) | (Datatype::Str, Datatype::Categorical) => Datatype::Categorical,
+        (a, b) => return Err(VMError::TypeError(format!("Can't choose between {} and {} values", a, b)))
+    };
+    for branch in [then, els] {
+        if let Branch::Column(c) = branch {
+            if c.len() != cond.len() {
+                return Err(VMError::LengthMismatch { expected: cond.len(), found: c.len() });
+            }
+        }
+    }
+    let cond = cond.to_bitmap();
+    match dtype {
+        Datatype::Bool => {
+            let (then, els) = (bits_of(then, cond.len())?, bits_of(els, cond.len())?);
+            Ok(Column::Bool(BoolColumn::from_mask(cond.and(&then).or(&cond.inverted().and(&els)))))
+        },
+        Datatype::Num => choose::<f64>(&cond, then, els),
+        Datatype::Int => choose::<i64>(&cond, then, els),
+        Datatype::Entity => choose::<u64>(&cond, then, els),
+        Datatype::Duration => choose::<Nanos>(&cond, then, els),
+        Datatype::Timestamp => choose::<Micros>(&cond, then, els),
+        Datatype::Point => choose::<Point>(&cond, then, els),
+        Datatype::Ipv4 => choose::<Ipv4Addr>(&cond, then, els),
+        Datatype::Ipv6 => choose::<Ipv6Addr>(&cond, then, els),
+        Datatype::Str | Datatype::Json => {
+            let (then, els) = (Strs::of(then, dtype)?, Strs::of(els, dtype)?);
+            let res: InlineStrColumn = (0 .. cond.len()).map(|i| if cond.get(i) { then.at(i) } else { els.at(i) }).collect();
+            Ok(match dtype {
+                Datatype::Json => Column::Json(JsonColumn { docs: res }),
+                _ => Column::InlineStr(res)
+            })
+        },
+        Datatype::Categorical => categorical::choose(&cond, then, els),
+        Datatype::Vector => {
+            let (then, els) = (Vectors::of(then)?, Vectors::of(els)?);
+            if then.dim() != els.dim() {
+                return Err(VMError::LengthMismatch { expected: then.dim(), found: els.dim() });
+            }
+            let mut data = Vec::with_capacity(cond.len() * then.dim());
+            (0 .. cond.len()).for_each(|i| data.extend_from_slice(if cond.get(i) { then.at(i) } else { els.at(i) }));
+            Ok(Column::Vector(VectorColumn::new(then.dim(), data)?))
+        },
+        // both branches are columns, a list or record scalar having no column type to go with
+        Datatype::List | Datatype::Record => match (then, els) {
+            (Branch::Column(then), Branch::Column(els)) => {
+                let both = Column::concat(&[then.clone(), els.clone()])?;
+                let rows: Vec<usize> = (0 .. cond.len()).map(|i| if cond.get(i) { i } else { cond.len() + i }).collect();
+                take(&both, &rows)
+            },
+            _ => unreachable!("list and record scalars have no datatype")
+        }
+    }
+}
+
+fn has_nulls(branch: Branch) -> bool {
+    matches!(branch, Branch::Column(Column::Nullable(_)) | Branch::Scalar(Scalar::Null))
+}
+
+// if_else on the branches' values, nulls aside, then on their validity the same way
+fn if_else_nullable(cond: &Selection, then: Branch, els: Branch) -> Result<Column, VMError> {
+    let rows = cond.len();
+    let validity = |branch: Branch| -> BitIndex {
+        match branch {
+            Branch::Column(Column::Nullable(c)) => c.validity().clone(),
+            Branch::Scalar(Scalar::Null) => BitIndex::for_col_len(rows),
+            _ => BitIndex::for_col_len(rows).inverted()
+        }
+    };
+    let res = if_else(cond, placeholders(then, els), placeholders(els, then))?;
+    let cond = cond.to_bitmap();
+    let valid = cond.and(&validity(then)).or(&cond.inverted().and(&validity(els)));
+    Ok(Column::Nullable(NullableColumn::new(res, valid)?))
+}
+
+// A branch's values, nulls aside. A null scalar takes its placeholders from the other branch,
+// or if both are null, is a Num.
+fn placeholders<'a>(branch: Branch<'a>, other: Branch<'a>) -> Branch<'a> {
+    match (branch, other) {
+        (Branch::Column(c), _) => Branch::Column(nulls::values_of(c)),
+        (Branch::Scalar(Scalar::Null), Branch::Column(c)) => Branch::Column(nulls::values_of(c)),
+        (Branch::Scalar(Scalar::Null), Branch::Scalar(Scalar::Null)) => Branch::Scalar(&Scalar::Num(f64::NAN)),
+        (Branch::Scalar(Scalar::Null), other) => other,
+        (branch, _) => branch
+    }
+}
+
+// How fill_missing fills a missing row
+#[derive(Debug, Clone, PartialEq)]
+pub enum FillStrategy {
+    Value(Scalar),
+    Forward,        // the nearest present value before it
+    Backward        // the nearest present value after it
+}
+
+// `fallback` for the rows of `col` set in `missing`, `col` for the rest
+pub fn coalesce(col: &Column, missing: &Selection, fallback: Branch) -> Result<Column, VMError> {
+    if_else(missing, fallback, Branch::Column(col))
+}
+
+// `col` with the rows set in `missing` filled in. A missing row with no present value to
+// fill from, such as a leading one with Forward, keeps its value and stays missing.
+pub fn fill_missing(col: &Column, missing: &Selection, strategy: &FillStrategy) -> Result<Column, VMError> {
+    if col.len() != missing.len() {
+        return Err(VMError::LengthMismatch { expected: col.len(), found: missing.len() });
+    }
+    let rows: Vec<usize> = match strategy {
+        FillStrategy::Value(x) => return coalesce(col, missing, Branch::Scalar(x)),
+        FillStrategy::Forward => {
+            let mut last = None;
+            (0 .. col.len()).map(|i| if missing.contains(i) { last.unwrap_or(i) } else { *last.insert(i) }).collect()
+        },
+        FillStrategy::Backward => {
+            let mut next = None;
+            let mut rows: Vec<usize> = (0 .. col.len()).rev()
+                .map(|i| if missing.contains(i) { next.unwrap_or(i) } else { *next.insert(i) })
+                .collect();
+            rows.reverse();
+            rows
+        }
+    };
+    take(col, &rows)
+}
+
+// Row rows[i] of `col`, for each i; rows may repeat, and must be in bounds
+pub fn take(col: &Column, rows: &[usize]) -> Result<Column, VMError> {
+    if let Column::Nullable(c) = col {
+        return Ok(Column::Nullable(c.take(rows)?));
+    }
+    let branch = Branch::Column(col);
+    match col.datatype() {
+        Datatype::Bool => {
+            let src = bits_of(branch, col.len())?;
+            let mut bits = BitIndex::for_col_len(rows.len());
+            rows.iter().enumerate().filter(|(_, r)| src.get(**r)).for_each(|(i, _)| bits.set(i));
+            Ok(Column::Bool(BoolColumn::from_mask(bits)))
+        },
+        Datatype::Num => take_native::<f64>(branch, rows),
+        Datatype::Int => take_native::<i64>(branch, rows),
+        Datatype::Entity => take_native::<u64>(branch, rows),
+        Datatype::Duration => take_native::<Nanos>(branch, rows),
+        Datatype::Timestamp => take_native::<Micros>(branch, rows),
+        Datatype::Point => take_native::<Point>(branch, rows),
+        Datatype::Ipv4 => take_native::<Ipv4Addr>(branch, rows),
+        Datatype::Ipv6 => take_native::<Ipv6Addr>(branch, rows),
+        Datatype::Str => {
+            let src = Strs::of(branch, Datatype::Str)?;
+            Ok(Column::InlineStr(rows.iter().map(|r| src.at(*r)).collect()))
+        },
+        Datatype::Json => {
+            let src = Strs::of(branch, Datatype::Json)?;
+            Ok(Column::Json(JsonColumn { docs: rows.iter().map(|r| src.at(*r)).collect() }))
+        },
+        Datatype::Categorical => match col {
+            Column::Categorical(c) => Ok(Column::Categorical(c.take(rows))),
+            _ => unreachable!("only CategoricalColumn is Categorical")
+        },
+        Datatype::Vector => {
+            let src = Vectors::of(branch)?;
+            let mut data = Vec::with_capacity(rows.len() * src.dim());
+            rows.iter().for_each(|r| data.extend_from_slice(src.at(*r)));
+            Ok(Column::Vector(VectorColumn::new(src.dim(), data)?))
+        },
+        Datatype::List => match col {
+            Column::List(c) => Ok(Column::List(c.take(rows)?)),
+            _ => unreachable!("only ListColumn is List")
+        },
+        Datatype::Record => match col {
+            Column::Record(c) => Ok(Column::Record(c.take(rows))),
+            _ => unreachable!("only RecordColumn is Record")
+        }
+    }
+}
+
+fn take_native<T: Native>(branch: Branch, rows: &[usize]) -> Result<Column, VMError> {
+    let src = Natives::<T>::of(branch)?;
+    Ok(T::wrap(PrimitiveColumn::new(rows.iter().map(|r| src.at(*r)).collect())))
+}
+
+// A boolean branch as a bitmap of `rows` rows
+fn bits_of(branch: Branch, rows: usize) -> Result<BitIndex, VMError> {
+    match branch {
+        Branch::Scalar(Scalar::Bool(x)) => {
+            let mut bits = BitIndex::for_col_len(rows);
+            if *x { bits.set_range(0, rows); }
+            Ok(bits)
+        },
+        Branch::Column(c) => match encoding::plain(c).as_ref() {
+            Column::Bool(b) => Ok(b.selection().to_bitmap()),
+            _ => Err(branch.mismatch(Datatype::Bool))
+        },
+        _ => Err(branch.mismatch(Datatype::Bool))
+    }
+}
+
+fn choose<T: Native>(cond: &BitIndex, then: Branch, els: Branch) -> Result<Column, VMError> {
+    let (then, els) = (Natives::<T>::of(then)?, Natives::<T>::of(els)?);
+    let data: Buffer<T> = (0 .. cond.len()).map(|i| if cond.get(i) { then.at(i) } else { els.at(i) }).collect();
+    Ok(T::wrap(PrimitiveColumn::new(data)))
+}
+
+enum Natives<T: Native> {
+    Values(Buffer<T>),
+    Const(T)
+}
+
+impl<T: Native> Natives<T> {
+    fn of(branch: Branch) -> Result<Self, VMError> {
+        let values = match branch {
+            Branch::Scalar(s) => T::from_scalar(s).map(Natives::Const),
+            Branch::Column(c) => T::unwrap(&encoding::plain(c)).map(|c| Natives::Values(c.data.clone()))
+        };
+        values.ok_or_else(|| branch.mismatch(T::DATATYPE))
+    }
+
+    fn at(&self, i: usize) -> T {
+        match self {
+            Natives::Values(data) => data[i],
+            Natives::Const(x) => *x
+        }
+    }
+}
+
+// The values of a Str branch, or the documents of a Json one
+enum Strs<'a> {
+    Values(Cow<'a, Column>),
+    Const(&'a str)
+}
+
+impl<'a> Strs<'a> {
+    fn of(branch: Branch<'a>, dtype: Datatype) -> Result<Self, VMError> {
+        match (branch, dtype) {
+            (Branch::Scalar(Scalar::Str(s)), Datatype::Str) => Ok(Strs::Const(s)),
+            (Branch::Scalar(Scalar::Json(s)), Datatype::Json) => match json::validate(s) {
+                Ok(()) => Ok(Strs::Const(s)),
+                Err(msg) => Err(VMError::TypeError(format!("Not a valid JSON document ({}): {}", msg, s)))
+            },
+            (Branch::Column(c), _) if c.datatype() == dtype => Ok(Strs::Values(encoding::plain(c))),
+            _ => Err(branch.mismatch(dtype))
+        }
+    }
+
+    fn at(&self, i: usize) -> &str {
+        match self {
+            Strs::Const(s) => s,
+            Strs::Values(c) => match c.as_ref() {
+                Column::Str(c) => &c.data[i],
+                Column::InlineStr(c) => c.value(i),
+                Column::Json(c) => c.docs.value(i),
+                _ => unreachable!("plain() returns plain columns")
+            }
+        }
+    }
+}
+
+// The rows of a Vector branch
+enum Vectors<'a> {
+    Values(&'a VectorColumn),
+    Const(&'a [f32])
+}
+
+impl<'a> Vectors<'a> {
+    fn of(branch: Branch<'a>) -> Result<Self, VMError> {
+        match branch {
+            Branch::Scalar(Scalar::Vector(x)) if !x.is_empty() => Ok(Vectors::Const(x)),
+            Branch::Column(Column::Vector(c)) => Ok(Vectors::Values(c)),
+            _ => Err(branch.mismatch(Datatype::Vector))
+        }
+    }
+
+    fn dim(&self) -> usize {
+        match self {
+            Vectors::Values(c) => c.dim(),
+            Vectors::Const(x) => x.len()
+        }
+    }
+
+    fn at(&self, i: usize) -> &[f32] {
+        match self {
+            Vectors::Values(c) => c.row(i),
+            Vectors::Const(x) => x
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoding;
+
+    use core::convert::TryFrom;
+
+    // rows 0 and 2 of 4
+    fn cond() -> Selection {
+        Selection::from_positions(vec![0, 2], 4)
+    }
+
+    #[test]
+    fn picks_from_columns_and_scalars() {
+        let then = Column::from(vec![1.0, 2.0, 3.0, 4.0]);
+        let zero = Scalar::Num(0.0);
+        assert_eq!(if_else(&cond(), Branch::Column(&then), Branch::Scalar(&zero)).unwrap(), Column::from(vec![1.0, 0.0, 3.0, 0.0]));
+        assert_eq!(if_else(&cond(), Branch::Scalar(&zero), Branch::Column(&then)).unwrap(), Column::from(vec![0.0, 2.0, 0.0, 4.0]));
+
+        let ids = Column::from(vec![10u64, 11, 12, 13]);
+        let none = Scalar::Entity(0);
+        assert_eq!(if_else(&cond(), Branch::Scalar(&none), Branch::Column(&ids)).unwrap(), Column::from(vec![0u64, 11, 0, 13]));
+    }
+
+    #[test]
+    fn bools_combine_bitwise() {
+        let flags = Column::from(vec![false, true, true, false]);
+        let yes = Scalar::Bool(true);
+        let res = if_else(&cond(), Branch::Scalar(&yes), Branch::Column(&flags)).unwrap();
+        assert_eq!(Vec::<bool>::try_from(&res).unwrap(), vec![true, true, true, false]);
+        let no = Scalar::Bool(false);
+        let res = if_else(&cond(), Branch::Column(&flags), Branch::Scalar(&no)).unwrap();
+        assert_eq!(Vec::<bool>::try_from(&res).unwrap(), vec![false, false, true, false]);
+    }
+
+    #[test]
+    fn strings_come_out_inline_whatever_the_branches() {
+        let dict = encoding::encode(Column::from(vec!["d"; 4]), Encoding::Dict).unwrap();
+        let other = Scalar::Str("other".to_string());
+        let res = if_else(&cond(), Branch::Column(&dict), Branch::Scalar(&other)).unwrap();
+        assert_eq!(res.kind(), "InlineStr");
+        assert_eq!(res, Column::from(vec!["d", "other", "d", "other"]));
+    }
+
+    #[test]
+    fn encoded_branches_are_decoded() {
+        let rle = encoding::encode(Column::from(vec![7u64; 4]), Encoding::Rle).unwrap();
+        let ids = Column::from(vec![10u64, 11, 12, 13]);
+        assert_eq!(if_else(&cond(), Branch::Column(&ids), Branch::Column(&rle)).unwrap(), Column::from(vec![10u64, 7, 12, 7]));
+    }
+
+    #[test]
+    fn branches_must_agree_with_each_other_and_the_mask() {
+        let nums = Column::from(vec![1.0; 4]);
+        let s = Scalar::Str("a".to_string());
+        assert!(matches!(if_else(&cond(), Branch::Column(&nums), Branch::Scalar(&s)), Err(VMError::TypeError(_))));
+        let short = Column::from(vec![1.0; 3]);
+        let zero = Scalar::Num(0.0);
+        assert!(matches!(if_else(&cond(), Branch::Scalar(&zero), Branch::Column(&short)),
+                         Err(VMError::LengthMismatch { expected: 4, found: 3 })));
+        let rec = Scalar::Record(vec![]);
+        assert!(matches!(if_else(&cond(), Branch::Scalar(&rec), Branch::Scalar(&rec)), Err(VMError::TypeError(_))));
+    }
+
+    // rows 0, 2, 3, 5 and 6 of 7 are missing
+    fn gaps() -> (Column, Selection) {
+        (Column::from(vec![0.0, 1.0, 0.0, 0.0, 4.0, 0.0, 0.0]), Selection::from_positions(vec![0, 2, 3, 5, 6], 7))
+    }
+
+    #[test]
+    fn fills_forward_and_backward() {
+        let (col, missing) = gaps();
+        // the leading gap has nothing before it, and the trailing one nothing after
+        assert_eq!(fill_missing(&col, &missing, &FillStrategy::Forward).unwrap(), Column::from(vec![0.0, 1.0, 1.0, 1.0, 4.0, 4.0, 4.0]));
+        assert_eq!(fill_missing(&col, &missing, &FillStrategy::Backward).unwrap(), Column::from(vec![1.0, 1.0, 4.0, 4.0, 4.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn fills_with_a_value() {
+        let (col, missing) = gaps();
+        let res = fill_missing(&col, &missing, &FillStrategy::Value(Scalar::Num(-1.0))).unwrap();
+        assert_eq!(res, Column::from(vec![-1.0, 1.0, -1.0, -1.0, 4.0, -1.0, -1.0]));
+        assert!(matches!(fill_missing(&col, &missing, &FillStrategy::Value(Scalar::Entity(1))), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn fills_nothing_from_nothing() {
+        let col = Column::from(vec!["a", "b"]);
+        for strategy in &[FillStrategy::Forward, FillStrategy::Backward] {
+            let all = Selection::from_positions(vec![0, 1], 2);
+            assert_eq!(fill_missing(&col, &all, strategy).unwrap(), col);
+            let none = Selection::from_positions(vec![], 2);
+            assert_eq!(fill_missing(&col, &none, strategy).unwrap(), col);
+            let empty = Column::from(Vec::<f64>::new());
+            assert!(fill_missing(&empty, &Selection::from_positions(vec![], 0), strategy).unwrap().is_empty());
+        }
+        assert!(matches!(fill_missing(&col, &Selection::from_positions(vec![], 3), &FillStrategy::Forward),
+                         Err(VMError::LengthMismatch { expected: 2, found: 3 })));
+    }
+
+    #[test]
+    fn fills_every_type() {
+        let missing = Selection::from_positions(vec![1], 3);
+        let flags = Column::from(vec![true, false, false]);
+        let filled = fill_missing(&flags, &missing, &FillStrategy::Forward).unwrap();
+        assert_eq!(Vec::<bool>::try_from(&filled).unwrap(), vec![true, true, false]);
+        let ids = encoding::encode(Column::from(vec![5u64, 0, 7]), Encoding::Packed).unwrap();
+        assert_eq!(fill_missing(&ids, &missing, &FillStrategy::Backward).unwrap(), Column::from(vec![5u64, 7, 7]));
+        let strs = Column::from(vec!["a", "", "c"]);
+        assert_eq!(fill_missing(&strs, &missing, &FillStrategy::Forward).unwrap(), Column::from(vec!["a", "a", "c"]));
+    }
+
+    #[test]
+    fn coalesces_from_a_column_or_a_scalar() {
+        let (col, missing) = gaps();
+        let fallback = Column::from(vec![10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0]);
+        assert_eq!(coalesce(&col, &missing, Branch::Column(&fallback)).unwrap(),
+                   Column::from(vec![10.0, 1.0, 30.0, 40.0, 4.0, 60.0, 70.0]));
+        let zero = Scalar::Num(9.0);
+        assert_eq!(coalesce(&col, &missing, Branch::Scalar(&zero)).unwrap(),
+                   Column::from(vec![9.0, 1.0, 9.0, 9.0, 4.0, 9.0, 9.0]));
+        let short = Column::from(vec![1.0]);
+        assert!(coalesce(&col, &missing, Branch::Column(&short)).is_err());
+    }
+}
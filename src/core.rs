@@ -0,0 +1,101 @@
+// The engine without the standard library. With the default `std` feature off, the crate
+// builds as `no_std` + `alloc`: the VM and its opcodes, columns and their encodings, bitmaps,
+// UDFs, and the tools that only look at programs (disasm, explain, optimizer) - enough to run
+// queries inside a WASM runtime or an embedded appliance that hands over columns already in
+// memory. What needs an OS is left out of such builds: files (csv, storage, Db snapshots,
+// spilling joins, saving traces), threads and locks (shared tables, views, the result cache,
+// the metrics server) and clocks (VM timeouts, op timings - which read as zero).
+//
+// This module gathers that portable surface, so code meant to build either way can depend on
+// collie::core alone. It also holds the few stand-ins the portable modules need for things
+// std would otherwise provide.
+
+pub use crate::bitindex::BitIndex;
+pub use crate::cancel::CancelToken;
+pub use crate::catalog::Catalog;
+pub use crate::column::*;
+pub use crate::errors::VMError;
+pub use crate::opcode::Op;
+pub use crate::result::ResultSet;
+pub use crate::schema::{Datatype, Field, Schema};
+pub use crate::selection::Selection;
+pub use crate::table::Table;
+pub use crate::udf::{Accumulator, Udf};
+pub use crate::vm::{ColumnMode, ColumnSlot, Value, VM};
+
+// For the exported macros, which can't assume the caller has `alloc` in scope
+#[doc(hidden)]
+pub use alloc::vec as __vec;
+
+// What the std prelude would bring into scope
+pub(crate) mod prelude {
+    pub use alloc::boxed::Box;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec::Vec;
+    pub use alloc::{format, vec};
+}
+
+// The fractional part of `x`, as f64::fract (which needs std) computes it
+pub(crate) fn fract(x: f64) -> f64 {
+    x - libm::trunc(x)
+}
+
+// hashbrown is what std's maps are built on; using it directly works with or without std
+pub(crate) use hashbrown::{HashMap, HashSet};
+
+// FNV-1a. Unlike std's DefaultHasher it needs no std, and it's specified, so fingerprints
+// are stable across runs, platforms and Rust versions.
+pub(crate) struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl core::hash::Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::prelude::*;
+    use crate::table::Table;
+    use core::hash::Hasher;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn fract_agrees_with_std() {
+        for x in [0.0, -0.0, 2.75, -2.75, 1e300, f64::INFINITY, f64::NAN, f64::MIN_POSITIVE].iter() {
+            assert_eq!(fract(*x).to_bits(), x.fract().to_bits(), "{}", x);
+        }
+    }
+
+    #[test]
+    fn stable_hasher_is_fnv_1a() {
+        let hash = |bytes: &[u8]| {
+            let mut h = StableHasher::default();
+            h.write(bytes);
+            h.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn runs_queries_through_the_portable_surface() {
+        let mut vm = VM::new(Table::from_columns(vec![Column::from(vec![1.0, 2.0, 3.0])]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Num(2.0)), Op::FilterEq, Op::Col(0, 0), Op::Select(1)]).unwrap();
+        crate::assert_columns_eq!(vm.column_of(vm.stack().last().unwrap()).unwrap(), &Column::from(vec![2.0]));
+    }
+}
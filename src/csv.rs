@@ -0,0 +1,1064 @@
+// Loading CSV (RFC 4180: comma separated, fields optionally double-quoted with "" as an
+// escaped quote, and quoted fields free to contain commas and newlines). Exports that stray
+// from it - semicolons, backslash escapes, decimal commas, "NA" for null - are read with a
+// Dialect, and what to do with a row that doesn't parse is up to MalformedRows.
+//
+// The input is cut into byte ranges of roughly `chunk_size` bytes, each ending just after a
+// record's newline, and the ranges are parsed independently - on the rayon thread pool, with
+// the `parallel` feature - into one set of column builders per range, which are then
+// concatenated in order. Finding the cuts is the only serial pass: it follows the quotes that
+// open and close fields to know whether a newline is inside a quoted field, which is far
+// cheaper than parsing.
+//
+// A null field is loaded as null: a column with any is a Nullable one (see nulls.rs), over
+// placeholders - NaN, false, 0, "", the first category and so on - and its rows are reported
+// as missing too, alongside the columns.
+
+use crate::buffer::Buffer;
+use crate::categorical::{Categories, CategoricalColumn};
+use crate::column::Column;
+use crate::duration::Nanos;
+use crate::geo::Point;
+use crate::ip::{Ipv4Addr, Ipv6Addr};
+use crate::json::{self, JsonColumn};
+use crate::normalize;
+use crate::nulls::NullableColumn;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::result::ResultSet;
+use crate::schema::{Datatype, Field, Schema};
+use crate::selection::Selection;
+use crate::storage::invalid;
+use crate::timestamp::Micros;
+use crate::vector::{self, VectorColumn};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+pub struct CsvReader {
+    schema: Schema,
+    header: bool,
+    chunk_size: usize,          // bytes per parsed range, approximately
+    threads: Option<usize>,     // None: the global rayon pool
+    dialect: Dialect,
+    malformed: MalformedRows
+}
+
+// How the text is laid out. The default is RFC 4180. The delimiter, quote and escape must be
+// distinct ASCII characters other than a newline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dialect {
+    pub delimiter: u8,
+    pub quote: Option<u8>,          // None: fields are never quoted
+    pub escape: Option<u8>,         // makes the next character literal, as in \" or \,
+    pub decimal: u8,                // separates the fraction in a Num: b'.' or b','
+    pub null_tokens: Vec<String>    // unquoted fields that mean null, such as "", "NA" or "\N"
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect { delimiter: b',', quote: Some(b'"'), escape: None, decimal: b'.', null_tokens: Vec::new() }
+    }
+}
+
+impl Dialect {
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn with_quote(mut self, quote: Option<u8>) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    pub fn with_escape(mut self, escape: Option<u8>) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    pub fn with_decimal(mut self, decimal: u8) -> Self {
+        self.decimal = decimal;
+        self
+    }
+
+    pub fn with_null_tokens(mut self, tokens: &[&str]) -> Self {
+        self.null_tokens = tokens.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    fn check(&self) -> Result<(), VMError> {
+        let specials: Vec<u8> = [Some(self.delimiter), self.quote, self.escape].iter().flatten().copied().collect();
+        let fail = |msg: &str| Err(VMError::TypeError(format!("Bad CSV dialect: {}", msg)));
+        if specials.iter().chain([self.decimal].iter()).any(|c| !c.is_ascii() || *c == b'\n' || *c == b'\r') {
+            return fail("delimiter, quote, escape and decimal separator must be ASCII, and not newlines");
+        }
+        if specials.iter().enumerate().any(|(i, c)| specials[i + 1 ..].contains(c)) {
+            return fail("delimiter, quote and escape must differ");
+        }
+        if self.decimal == self.delimiter {
+            return fail("the decimal separator can't be the delimiter");
+        }
+        Ok(())
+    }
+
+    fn is_null(&self, field: &str, quoted: bool) -> bool {
+        !quoted && self.null_tokens.iter().any(|t| t == field)
+    }
+}
+
+// What to do with a row that has the wrong number of fields, or a field that doesn't parse
+// as its column's type. A row whose quoting is broken always fails, since there's no telling
+// where it ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MalformedRows {
+    // stop, with an error giving its line
+    #[default]
+    Fail,
+    // leave it out, and count it
+    Skip,
+    // load it, with null for each field that doesn't parse or is missing from the end; fields
+    // past the last column are ignored
+    NullFill
+}
+
+// A parse, with what the values alone don't say
+#[derive(Debug)]
+pub struct CsvLoad {
+    pub result: ResultSet,
+    pub missing: Vec<Selection>,    // per column, the rows that are null
+    pub skipped: usize              // rows left out by MalformedRows::Skip
+}
+
+// What parsing one range of the input gives
+struct Part {
+    builders: Vec<Builder>,
+    missing: Vec<Vec<u32>>,         // per column, the rows that are null
+    rows: usize,
+    skipped: usize
+}
+
+impl Part {
+    fn append(&mut self, other: Part) {
+        let rows = self.rows as u32;
+        for (missing, other) in self.missing.iter_mut().zip(other.missing) {
+            missing.extend(other.into_iter().map(|row| row + rows));
+        }
+        for (builder, other) in self.builders.iter_mut().zip(other.builders) {
+            builder.append(other);
+        }
+        self.rows += other.rows;
+        self.skipped += other.skipped;
+    }
+
+    // Drops anything pushed for rows from `rows` on
+    fn truncate(&mut self, rows: usize) {
+        self.builders.iter_mut().for_each(|b| b.truncate(rows));
+        self.missing.iter_mut().for_each(|m| m.retain(|row| (*row as usize) < rows));
+    }
+}
+
+// Values of one column, parsed from one range of the input
+enum Builder {
+    Bool(Vec<bool>),
+    Num(Vec<f64>),
+    Str(Vec<String>),
+    Entity(Vec<u64>),
+    Int(Vec<i64>),
+    Duration(Vec<Nanos>),
+    Timestamp(Vec<Micros>),
+    Point(Vec<Point>),
+    Ipv4(Vec<Ipv4Addr>),
+    Ipv6(Vec<Ipv6Addr>),
+    Json(Vec<String>),
+    Categorical(Arc<Categories>, Vec<u32>),    // ranks
+    Vector(usize, Vec<f32>)     // dim, then the rows' values back to back
+}
+
+impl Builder {
+    fn new(field: &Field) -> Result<Self, VMError> {
+        Ok(match field.dtype {
+            Datatype::Bool => Builder::Bool(Vec::new()),
+            Datatype::Num => Builder::Num(Vec::new()),
+            Datatype::Str => Builder::Str(Vec::new()),
+            Datatype::Entity => Builder::Entity(Vec::new()),
+            Datatype::Int => Builder::Int(Vec::new()),
+            Datatype::Duration => Builder::Duration(Vec::new()),
+            Datatype::Timestamp => Builder::Timestamp(Vec::new()),
+            Datatype::Point => Builder::Point(Vec::new()),
+            Datatype::Ipv4 => Builder::Ipv4(Vec::new()),
+            Datatype::Ipv6 => Builder::Ipv6(Vec::new()),
+            Datatype::Json => Builder::Json(Vec::new()),
+            Datatype::Categorical => match &field.categories {
+                Some(categories) => Builder::Categorical(categories.clone(), Vec::new()),
+                None => return Err(VMError::TypeError(format!("Categorical column '{}' has no categories", field.name)))
+            },
+            Datatype::Vector => match field.dim {
+                Some(dim) if dim > 0 => Builder::Vector(dim, Vec::new()),
+                _ => return Err(VMError::TypeError(format!("Vector column '{}' has no size", field.name)))
+            },
+            Datatype::List | Datatype::Record => {
+                return Err(VMError::TypeError(format!("{} column '{}' can't be read from CSV", field.dtype, field.name)));
+            }
+        })
+    }
+
+    fn push(&mut self, field: &str, dialect: &Dialect) -> Result<(), String> {
+        match self {
+            Builder::Bool(v) => v.push(match field.trim() {
+                "1" => true,
+                "0" => false,
+                s if s.eq_ignore_ascii_case("true") => true,
+                s if s.eq_ignore_ascii_case("false") => false,
+                _ => return Err(format!("can't parse '{}' as Bool", field))
+            }),
+            Builder::Num(v) => {
+                let bad = || format!("can't parse '{}' as Num", field);
+                let x = match dialect.decimal {
+                    b'.' => field.trim().parse(),
+                    _ if field.contains('.') => return Err(bad()),
+                    sep => field.trim().replace(sep as char, ".").parse()
+                };
+                v.push(x.map_err(|_| bad())?)
+            },
+            Builder::Str(v) => v.push(field.to_string()),
+            Builder::Entity(v) => v.push(field.trim().parse().map_err(|_| format!("can't parse '{}' as Entity", field))?),
+            Builder::Int(v) => v.push(field.trim().parse().map_err(|_| format!("can't parse '{}' as Int", field))?),
+            Builder::Duration(v) => v.push(Nanos::parse(field).ok_or_else(|| format!("can't parse '{}' as Duration", field))?),
+            Builder::Timestamp(v) => v.push(Micros::parse(field).ok_or_else(|| format!("can't parse '{}' as Timestamp", field))?),
+            Builder::Point(v) => v.push(Point::parse(field).ok_or_else(|| format!("can't parse '{}' as Point", field))?),
+            Builder::Ipv4(v) => v.push(field.trim().parse().map_err(|_| format!("can't parse '{}' as Ipv4", field))?),
+            Builder::Ipv6(v) => v.push(field.trim().parse().map_err(|_| format!("can't parse '{}' as Ipv6", field))?),
+            Builder::Json(v) => {
+                json::validate(field).map_err(|msg| format!("'{}' isn't valid JSON: {}", field, msg))?;
+                v.push(field.to_string())
+            },
+            Builder::Categorical(categories, v) => {
+                v.push(categories.rank(field).ok_or_else(|| format!("'{}' isn't one of the categories", field))?)
+            },
+            Builder::Vector(dim, v) => match vector::parse(field) {
+                Some(x) if x.len() == *dim => v.extend_from_slice(&x),
+                Some(x) => return Err(format!("'{}' has {} values, not {}", field, x.len(), dim)),
+                None => return Err(format!("can't parse '{}' as a Vector", field))
+            }
+        }
+        Ok(())
+    }
+
+    // A placeholder for a null field
+    fn push_null(&mut self) -> Result<(), String> {
+        match self {
+            Builder::Bool(v) => v.push(false),
+            Builder::Num(v) => v.push(f64::NAN),
+            Builder::Str(v) => v.push(String::new()),
+            Builder::Entity(v) => v.push(0),
+            Builder::Int(v) => v.push(0),
+            Builder::Duration(v) => v.push(Nanos(0)),
+            Builder::Timestamp(v) => v.push(Micros(0)),
+            Builder::Point(v) => v.push(Point::new(f64::NAN, f64::NAN)),
+            Builder::Ipv4(v) => v.push(Ipv4Addr::UNSPECIFIED),
+            Builder::Ipv6(v) => v.push(Ipv6Addr::UNSPECIFIED),
+            Builder::Json(v) => v.push("null".to_string()),
+            Builder::Categorical(categories, _) if categories.is_empty() => return Err("null in a column with no categories".to_string()),
+            Builder::Categorical(_, v) => v.push(0),
+            Builder::Vector(dim, v) => v.resize(v.len() + *dim, f32::NAN)
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        match self {
+            Builder::Bool(v) => v.truncate(len),
+            Builder::Num(v) => v.truncate(len),
+            Builder::Str(v) => v.truncate(len),
+            Builder::Entity(v) => v.truncate(len),
+            Builder::Int(v) => v.truncate(len),
+            Builder::Duration(v) => v.truncate(len),
+            Builder::Timestamp(v) => v.truncate(len),
+            Builder::Point(v) => v.truncate(len),
+            Builder::Ipv4(v) => v.truncate(len),
+            Builder::Ipv6(v) => v.truncate(len),
+            Builder::Json(v) => v.truncate(len),
+            Builder::Categorical(_, v) => v.truncate(len),
+            Builder::Vector(dim, v) => v.truncate(len * *dim)
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Builder::Bool(v) => v.len(),
+            Builder::Num(v) => v.len(),
+            Builder::Str(v) => v.len(),
+            Builder::Entity(v) => v.len(),
+            Builder::Int(v) => v.len(),
+            Builder::Duration(v) => v.len(),
+            Builder::Timestamp(v) => v.len(),
+            Builder::Point(v) => v.len(),
+            Builder::Ipv4(v) => v.len(),
+            Builder::Ipv6(v) => v.len(),
+            Builder::Json(v) => v.len(),
+            Builder::Categorical(_, v) => v.len(),
+            Builder::Vector(dim, v) => v.len() / dim
+        }
+    }
+
+    fn append(&mut self, other: Builder) {
+        match (self, other) {
+            (Builder::Bool(a), Builder::Bool(mut b)) => a.append(&mut b),
+            (Builder::Num(a), Builder::Num(mut b)) => a.append(&mut b),
+            (Builder::Str(a), Builder::Str(mut b)) => a.append(&mut b),
+            (Builder::Entity(a), Builder::Entity(mut b)) => a.append(&mut b),
+            (Builder::Int(a), Builder::Int(mut b)) => a.append(&mut b),
+            (Builder::Duration(a), Builder::Duration(mut b)) => a.append(&mut b),
+            (Builder::Timestamp(a), Builder::Timestamp(mut b)) => a.append(&mut b),
+            (Builder::Point(a), Builder::Point(mut b)) => a.append(&mut b),
+            (Builder::Ipv4(a), Builder::Ipv4(mut b)) => a.append(&mut b),
+            (Builder::Ipv6(a), Builder::Ipv6(mut b)) => a.append(&mut b),
+            (Builder::Json(a), Builder::Json(mut b)) => a.append(&mut b),
+            (Builder::Categorical(_, a), Builder::Categorical(_, mut b)) => a.append(&mut b),
+            (Builder::Vector(_, a), Builder::Vector(_, mut b)) => a.append(&mut b),
+            _ => unreachable!("builders for a column share its datatype")
+        }
+    }
+
+    fn finish(self) -> Column {
+        match self {
+            Builder::Bool(v) => Column::from(v),
+            Builder::Num(v) => Column::from(v),
+            Builder::Str(v) => Column::from(v),
+            Builder::Entity(v) => Column::from(v),
+            Builder::Int(v) => Column::from(v),
+            Builder::Duration(v) => Column::from(v),
+            Builder::Timestamp(v) => Column::from(v),
+            Builder::Point(v) => Column::from(v),
+            Builder::Ipv4(v) => Column::from(v),
+            Builder::Ipv6(v) => Column::from(v),
+            Builder::Json(v) => Column::Json(JsonColumn { docs: v.iter().map(|d| d.as_str()).collect() }),
+            Builder::Categorical(categories, v) => Column::Categorical(CategoricalColumn::with_codes(categories, Buffer::from(v))),
+            Builder::Vector(dim, v) => Column::Vector(VectorColumn::new(dim, v).expect("rows of dim values each"))
+        }
+    }
+}
+
+// Splits the record at the front of `s` into `fields`, each with whether it was quoted,
+// returning what follows it
+fn next_record<'a>(s: &'a str, dialect: &Dialect, fields: &mut Vec<(Cow<'a, str>, bool)>) -> Result<&'a str, String> {
+    let b = s.as_bytes();
+    let mut i = 0;
+    fields.clear();
+    loop {
+        if let Some(quote) = dialect.quote.filter(|q| b.get(i) == Some(q)) {
+            let mut value = String::new();
+            let mut j = i + 1;
+            loop {
+                let from = j;
+                match b[j ..].iter().position(|&c| c == quote || Some(c) == dialect.escape) {
+                    None => return Err("unterminated quoted field".to_string()),
+                    Some(p) => {
+                        j += p;
+                        value.push_str(&s[from .. j]);
+                        if b[j] != quote {
+                            j = escaped(s, j, &mut value)?;
+                        } else if b.get(j + 1) == Some(&quote) {
+                            value.push(quote as char);
+                            j += 2;
+                        } else {
+                            j += 1;
+                            break;
+                        }
+                    }
+                }
+            }
+            fields.push((Cow::Owned(value), true));
+            i = j;
+            match (b.get(i), b.get(i + 1)) {
+                (Some(&c), _) if c == dialect.delimiter => i += 1,
+                (Some(b'\n'), _) => return Ok(&s[i + 1 ..]),
+                (Some(b'\r'), Some(b'\n')) => return Ok(&s[i + 2 ..]),
+                (None, _) => return Ok(&s[i ..]),
+                _ => return Err("unexpected character after a quoted field".to_string())
+            }
+        } else {
+            // escapes make an owned copy; fields without any are borrowed
+            let mut value: Option<String> = None;
+            let mut from = i;
+            let end = loop {
+                let end = b[from ..].iter()
+                    .position(|&c| c == dialect.delimiter || c == b'\n' || Some(c) == dialect.escape)
+                    .map_or(b.len(), |p| from + p);
+                if end == b.len() || Some(b[end]) != dialect.escape {
+                    break end;
+                }
+                let v = value.get_or_insert_with(String::new);
+                v.push_str(&s[from .. end]);
+                from = escaped(s, end, v)?;
+            };
+            let last = b.get(end) != Some(&dialect.delimiter);
+            let tail = &s[from .. end];
+            let tail = if last { tail.strip_suffix('\r').unwrap_or(tail) } else { tail };
+            let field = match value {
+                None => Cow::Borrowed(tail),
+                Some(mut v) => {
+                    v.push_str(tail);
+                    Cow::Owned(v)
+                }
+            };
+            fields.push((field, false));
+            if last {
+                return Ok(&s[(end + 1).min(b.len()) ..]);
+            }
+            i = end + 1;
+        }
+    }
+}
+
+// Appends the character after the escape at `s[at]` to `value`, returning where what follows
+// it starts
+fn escaped(s: &str, at: usize, value: &mut String) -> Result<usize, String> {
+    match s[at + 1 ..].chars().next() {
+        Some(c) => {
+            value.push(c);
+            Ok(at + 1 + c.len_utf8())
+        },
+        None => Err("nothing after an escape character".to_string())
+    }
+}
+
+// Where a scan through the input is, as next_record would see it. A quote only opens a quoted
+// field at the start of a field; anywhere else in an unquoted one it's just a character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Place {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    AfterQuote      // a quote inside a quoted field: its end, or the first of a "" pair
+}
+
+struct Scan {
+    place: Place,
+    escaped: bool
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Scan { place: Place::FieldStart, escaped: false }
+    }
+}
+
+impl Scan {
+    // Moves past `c`, returning whether it's a newline that ends a record
+    fn step(&mut self, c: u8, dialect: &Dialect) -> bool {
+        let quote = Some(c) == dialect.quote;
+        if self.escaped {
+            self.escaped = false;
+            return false;
+        }
+        if Some(c) == dialect.escape {
+            self.escaped = true;
+            return false;
+        }
+        self.place = match self.place {
+            Place::Quoted if quote => Place::AfterQuote,
+            Place::Quoted => return false,
+            Place::AfterQuote | Place::FieldStart if quote => Place::Quoted,
+            _ if c == dialect.delimiter => Place::FieldStart,
+            _ if c == b'\n' => {
+                self.place = Place::FieldStart;
+                return true;
+            },
+            _ => Place::Unquoted
+        };
+        false
+    }
+
+    // Moves past `bytes` without looking for the end of a record
+    fn skip(&mut self, bytes: &[u8], dialect: &Dialect) {
+        if dialect.quote.is_some() || dialect.escape.is_some() {
+            bytes.iter().for_each(|&c| { self.step(c, dialect); });
+        }
+    }
+}
+
+// Byte ranges of `input`, each at least `chunk_size` long (but the last) and ending just after
+// a newline that's outside any quoted field
+fn split(input: &[u8], chunk_size: usize, dialect: &Dialect) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    let mut scan = Scan::default();
+    while start < input.len() {
+        let skip_to = (start + chunk_size).min(input.len());
+        scan.skip(&input[pos .. skip_to], dialect);
+        pos = skip_to;
+        while pos < input.len() && !scan.step(input[pos], dialect) {
+            pos += 1;
+        }
+        pos = (pos + 1).min(input.len());
+        ranges.push((start, pos));
+        start = pos;
+    }
+    ranges
+}
+
+impl CsvReader {
+    pub fn new(schema: Schema) -> Self {
+        CsvReader {
+            schema, header: true, chunk_size: DEFAULT_CHUNK_SIZE, threads: None,
+            dialect: Dialect::default(), malformed: MalformedRows::default()
+        }
+    }
+
+    // Whether the first record names the columns (and so isn't data). On by default.
+    pub fn with_header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    // Parse on a pool of `n` threads rather than the global one. Ignored without the
+    // `parallel` feature, where parsing is always on the calling thread.
+    pub fn with_threads(mut self, n: usize) -> Self {
+        self.threads = Some(n.max(1));
+        self
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn with_malformed_rows(mut self, policy: MalformedRows) -> Self {
+        self.malformed = policy;
+        self
+    }
+
+    pub fn read_path<P: AsRef<Path>>(&self, path: P) -> Result<ResultSet, VMError> {
+        Ok(self.load_path(path)?.result)
+    }
+
+    // Columns are named after the schema's fields, and encoded as they say
+    pub fn parse(&self, input: &[u8]) -> Result<ResultSet, VMError> {
+        Ok(self.load(input)?.result)
+    }
+
+    pub fn load_path<P: AsRef<Path>>(&self, path: P) -> Result<CsvLoad, VMError> {
+        self.load(&fs::read(path).map_err(VMError::Io)?)
+    }
+
+    // parse, plus which rows are null and how many were skipped
+    pub fn load(&self, input: &[u8]) -> Result<CsvLoad, VMError> {
+        self.dialect.check()?;
+        let body = if self.header { self.skip_header(input)? } else { 0 };
+        let ranges: Vec<_> = split(&input[body ..], self.chunk_size, &self.dialect).into_iter()
+            .map(|(from, to)| (body + from, body + to))
+            .collect();
+        let parse_range = |&(from, to): &(usize, usize)| self.parse_range(input, from, to);
+
+        #[cfg(feature = "parallel")]
+        let parts: Result<Vec<_>, VMError> = {
+            let run = || ranges.par_iter().map(parse_range).collect();
+            match self.threads {
+                Some(n) => rayon::ThreadPoolBuilder::new().num_threads(n).build()
+                    .map_err(|e| VMError::TypeError(format!("Can't start CSV parsing threads: {}", e)))?
+                    .install(run),
+                None => run()
+            }
+        };
+        #[cfg(not(feature = "parallel"))]
+        let parts: Result<Vec<_>, VMError> = ranges.iter().map(parse_range).collect();
+
+        let mut parts = parts?.into_iter();
+        let mut all = match parts.next() {
+            Some(first) => first,
+            None => self.part()?
+        };
+        for part in parts {
+            all.append(part);
+        }
+        let columns = all.builders.into_iter().zip(&self.schema.fields)
+            .map(|(b, field)| if field.nfc { normalize::nfc_column(&b.finish()) } else { b.finish() })
+            .collect();
+        let columns = encoding::apply(&self.schema, columns)?;
+        let rows = all.rows;
+        let missing: Vec<Selection> = all.missing.into_iter().map(|nulls| Selection::from_positions(nulls, rows)).collect();
+        let mut result = ResultSet::new();
+        for ((field, col), nulls) in self.schema.fields.iter().zip(columns).zip(&missing) {
+            let col = match nulls.count_ones() {
+                0 => col,
+                _ => Column::Nullable(NullableColumn::with_nulls(col, nulls)?)
+            };
+            result.push(&field.name, col);
+        }
+        Ok(CsvLoad { result, missing, skipped: all.skipped })
+    }
+
+    // Checks the header has a field per column, returning where the data starts
+    fn skip_header(&self, input: &[u8]) -> Result<usize, VMError> {
+        let end = split(input, 1, &self.dialect).first().map_or(0, |&(_, to)| to);
+        let line = self.text(input, 0, end)?;
+        let mut fields = Vec::new();
+        next_record(line, &self.dialect, &mut fields).map_err(|msg| error(input, 0, &msg))?;
+        if fields.len() != self.schema.len() {
+            let msg = format!("header has {} fields, but the schema has {} columns", fields.len(), self.schema.len());
+            return Err(error(input, 0, &msg));
+        }
+        Ok(end)
+    }
+
+    fn part(&self) -> Result<Part, VMError> {
+        Ok(Part {
+            builders: self.schema.fields.iter().map(Builder::new).collect::<Result<_, _>>()?,
+            missing: vec![Vec::new(); self.schema.len()],
+            rows: 0,
+            skipped: 0
+        })
+    }
+
+    fn parse_range(&self, input: &[u8], from: usize, to: usize) -> Result<Part, VMError> {
+        let mut part = self.part()?;
+        let text = self.text(input, from, to)?;
+        let mut rest = text;
+        let mut fields = Vec::new();
+        while !rest.is_empty() {
+            let offset = from + text.len() - rest.len();
+            if rest.starts_with('\n') || rest.starts_with("\r\n") {
+                rest = &rest[rest.find('\n').unwrap_or(0) + 1 ..];
+                continue;
+            }
+            rest = next_record(rest, &self.dialect, &mut fields).map_err(|msg| error(input, offset, &msg))?;
+            match self.push_record(&mut part, &fields) {
+                Ok(()) => part.rows += 1,
+                Err(_) if self.malformed == MalformedRows::Skip => {
+                    part.truncate(part.rows);
+                    part.skipped += 1;
+                },
+                Err(msg) => return Err(error(input, offset, &msg))
+            }
+        }
+        debug_assert!(part.builders.iter().all(|b| b.len() == part.rows));
+        Ok(part)
+    }
+
+    // Adds a row to `part` (or some of one, on an error)
+    fn push_record(&self, part: &mut Part, fields: &[(Cow<str>, bool)]) -> Result<(), String> {
+        let fill = self.malformed == MalformedRows::NullFill;
+        if fields.len() != self.schema.len() && !fill {
+            return Err(format!("expected {} fields, found {}", self.schema.len(), fields.len()));
+        }
+        let row = part.rows as u32;
+        for (i, (builder, column)) in part.builders.iter_mut().zip(&self.schema.fields).enumerate() {
+            // None for a null token, or a field missing from the end of a short row
+            let value = fields.get(i).filter(|(field, quoted)| !self.dialect.is_null(field, *quoted));
+            if let Some((field, _)) = value {
+                match builder.push(field, &self.dialect) {
+                    Ok(()) => continue,
+                    Err(msg) if !fill => return Err(format!("column '{}': {}", column.name, msg)),
+                    Err(_) => {}
+                }
+            }
+            builder.push_null().map_err(|msg| format!("column '{}': {}", column.name, msg))?;
+            part.missing[i].push(row);
+        }
+        Ok(())
+    }
+
+    fn text<'a>(&self, input: &'a [u8], from: usize, to: usize) -> Result<&'a str, VMError> {
+        std::str::from_utf8(&input[from .. to])
+            .map_err(|e| error(input, from + e.valid_up_to(), "invalid UTF-8"))
+    }
+}
+
+// An error at byte `offset`, reported by (1-based) line
+fn error(input: &[u8], offset: usize, msg: &str) -> VMError {
+    let line = input[.. offset].iter().filter(|&&c| c == b'\n').count() + 1;
+    VMError::Io(invalid(format!("CSV line {}: {}", line, msg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::{self, Agg};
+    use crate::column::{Column, ColumnT, Scalar};
+    use crate::compare::argsort;
+    use crate::encoding::Encoding;
+
+    fn schema() -> Schema {
+        Schema::from(vec![("id", Datatype::Entity), ("name", Datatype::Str), ("price", Datatype::Num), ("ok", Datatype::Bool)])
+    }
+
+    fn strs(values: &[&str]) -> Column {
+        Column::from(values.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    fn message(e: VMError) -> String {
+        match e {
+            VMError::Io(e) => e.to_string(),
+            e => panic!("expected an Io error, got {:?}", e)
+        }
+    }
+
+    const QUOTED: &str = "id,name,price,ok\r\n\
+        1,plain,1.5,true\r\n\
+        2,\"a, b\",2,0\n\
+        \n\
+        3,\"two\nlines\",-3e2,FALSE\n\
+        4,\"say \"\"hi\"\"\",0,1\n\
+        5,\"\",.5,True";
+
+    #[test]
+    fn parses_quotes_escapes_and_newlines() {
+        let res = CsvReader::new(schema()).parse(QUOTED.as_bytes()).unwrap();
+        crate::assert_columns_eq!(res.column("id").unwrap(), &Column::from(vec![1u64, 2, 3, 4, 5]));
+        crate::assert_columns_eq!(res.column("name").unwrap(), &strs(&["plain", "a, b", "two\nlines", "say \"hi\"", ""]));
+        crate::assert_columns_eq!(res.column("price").unwrap(), &Column::from(vec![1.5, 2.0, -300.0, 0.0, 0.5]));
+        crate::assert_columns_eq!(res.column("ok").unwrap(), &Column::from(vec![true, false, false, true, true]));
+    }
+
+    #[test]
+    fn every_chunk_size_gives_the_same_result() {
+        let whole = CsvReader::new(schema()).parse(QUOTED.as_bytes()).unwrap();
+        // small chunks end inside quoted fields, between "" escapes and inside \r\n
+        for chunk_size in 1 .. QUOTED.len() + 2 {
+            let res = CsvReader::new(schema()).with_chunk_size(chunk_size).with_threads(3).parse(QUOTED.as_bytes()).unwrap();
+            for (a, b) in whole.columns.iter().zip(res.columns.iter()) {
+                crate::assert_columns_eq!(a, b, "chunk size {}", chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn quotes_inside_unquoted_fields_are_text_at_every_chunk_size() {
+        // x"y's quote doesn't open a quoted field, so the newline in the next field is quoted
+        let input = "a,b\nx\"y,\"multi\nline\"\nz,\"w\"\"\"\n";
+        let schema = Schema::from(vec![("a", Datatype::Str), ("b", Datatype::Str)]);
+        for chunk_size in 1 .. input.len() + 2 {
+            let res = CsvReader::new(schema.clone()).with_chunk_size(chunk_size).parse(input.as_bytes()).unwrap();
+            crate::assert_columns_eq!(res.column("a").unwrap(), &strs(&["x\"y", "z"]), "chunk size {}", chunk_size);
+            crate::assert_columns_eq!(res.column("b").unwrap(), &strs(&["multi\nline", "w\""]), "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn splits_only_outside_quotes() {
+        let input = b"\"a\nb\",1\n\"c\"\"\nd\",2\n";
+        for chunk_size in 1 .. input.len() {
+            for (from, to) in split(input, chunk_size, &Dialect::default()) {
+                assert!([0, 8].contains(&from) && [8, input.len()].contains(&to), "chunk size {}: {} .. {}", chunk_size, from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn without_a_header_every_record_is_data() {
+        let res = CsvReader::new(schema()).with_header(false).parse(b"7,x,1,1\n8,y,2,0").unwrap();
+        crate::assert_columns_eq!(res.column("id").unwrap(), &Column::from(vec![7u64, 8]));
+        let empty = CsvReader::new(schema()).parse(b"id,name,price,ok\n").unwrap();
+        assert_eq!(empty.rows(), 0);
+        assert_eq!(empty.names, vec!["id", "name", "price", "ok"]);
+    }
+
+    #[test]
+    fn reports_errors_by_line() {
+        let cases: [(&[u8], &str); 7] = [
+            (b"id,name\n", "CSV line 1: header has 2 fields"),
+            (b"id,name,price,ok\n1,a,1,1\n2,b,1\n", "CSV line 3: expected 4 fields, found 3"),
+            (b"id,name,price,ok\n1,\"a\nb\",1,1\nx,b,1,1\n", "CSV line 4: column 'id': can't parse 'x' as Entity"),
+            (b"id,name,price,ok\n1,a,1,maybe\n", "CSV line 2: column 'ok': can't parse 'maybe' as Bool"),
+            (b"id,name,price,ok\n1,\"a,1,1\n", "CSV line 2: unterminated quoted field"),
+            (b"id,name,price,ok\n1,\"a\"b,1,1\n", "CSV line 2: unexpected character after a quoted field"),
+            (b"id,name,price,ok\n1,\xff,1,1\n", "CSV line 2: invalid UTF-8")
+        ];
+        for (input, expected) in cases.iter() {
+            for chunk_size in [1, 4, DEFAULT_CHUNK_SIZE].iter() {
+                let msg = message(CsvReader::new(schema()).with_chunk_size(*chunk_size).parse(input).unwrap_err());
+                assert!(msg.starts_with(expected), "{} (chunk size {})", msg, chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        let schema = Schema::from(vec![("d", Datatype::Duration)]);
+        let res = CsvReader::new(schema.clone()).with_header(false).parse(b"1h30m\n-250ms\n 42\n").unwrap();
+        crate::assert_columns_eq!(res.column("d").unwrap(), &Column::from(vec![Nanos(90 * 60_000_000_000), Nanos(-250_000_000), Nanos(42)]));
+        let msg = message(CsvReader::new(schema).with_header(false).parse(b"1s\n1 s\n").unwrap_err());
+        assert_eq!(msg, "CSV line 2: column 'd': can't parse '1 s' as Duration");
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let schema = Schema::from(vec![("v4", Datatype::Ipv4), ("v6", Datatype::Ipv6)]);
+        let res = CsvReader::new(schema.clone()).with_header(false).parse(b"10.0.0.1, ::1\n255.255.255.255,2001:db8::\n").unwrap();
+        crate::assert_columns_eq!(res.column("v4").unwrap(), &Column::from(vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::BROADCAST]));
+        crate::assert_columns_eq!(res.column("v6").unwrap(), &Column::from(vec![Ipv6Addr::LOCALHOST, "2001:db8::".parse::<Ipv6Addr>().unwrap()]));
+        let msg = message(CsvReader::new(schema).with_header(false).parse(b"10.0.0.256,::\n").unwrap_err());
+        assert_eq!(msg, "CSV line 1: column 'v4': can't parse '10.0.0.256' as Ipv4");
+    }
+
+    #[test]
+    fn parses_json() {
+        let schema = Schema::from(vec![("doc", Datatype::Json), ("n", Datatype::Num)]);
+        let res = CsvReader::new(schema.clone()).with_header(false).parse(b"\"{\"\"a\"\": [1, 2]}\",1\n null ,2\n").unwrap();
+        let docs = JsonColumn::parse(vec!["{\"a\": [1, 2]}", " null "]).unwrap();
+        crate::assert_columns_eq!(res.column("doc").unwrap(), &Column::Json(docs));
+        let msg = message(CsvReader::new(schema).with_header(false).parse(b"1,1\n{a},2\n").unwrap_err());
+        assert!(msg.starts_with("CSV line 2: column 'doc': '{a}' isn't valid JSON"), "{}", msg);
+    }
+
+    #[test]
+    fn parses_categories() {
+        let levels = Categories::new(&["low", "high"]).unwrap();
+        let schema = Schema::new(vec![Field::categorical("level", levels)]);
+        let res = CsvReader::new(schema.clone()).with_header(false).parse(b"high\nlow\nhigh\n").unwrap();
+        assert_eq!(res.column("level").unwrap().datatype(), Datatype::Categorical);
+        assert_eq!(argsort(res.column("level").unwrap()), vec![1, 0, 2]);
+        let msg = message(CsvReader::new(schema).with_header(false).parse(b"low\nmedium\n").unwrap_err());
+        assert_eq!(msg, "CSV line 2: column 'level': 'medium' isn't one of the categories");
+        // a Categorical field needs its categories
+        let schema = Schema::from(vec![("level", Datatype::Categorical)]);
+        assert!(matches!(CsvReader::new(schema).parse(b"level\nlow\n"), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn parses_vectors() {
+        let schema = Schema::new(vec![Field::vector("v", 3)]);
+        let res = CsvReader::new(schema.clone()).with_header(false).parse(b"\"[1, 2.5, -3]\"\n0 0 1\n").unwrap();
+        let expected = VectorColumn::from_rows(3, &[[1.0, 2.5, -3.0], [0.0, 0.0, 1.0]]).unwrap();
+        crate::assert_columns_eq!(res.column("v").unwrap(), &Column::Vector(expected));
+        let msg = message(CsvReader::new(schema.clone()).with_header(false).parse(b"1 2 3\n1 2\n").unwrap_err());
+        assert_eq!(msg, "CSV line 2: column 'v': '1 2' has 2 values, not 3");
+        let msg = message(CsvReader::new(schema.clone()).with_header(false).parse(b"1 x 3\n").unwrap_err());
+        assert_eq!(msg, "CSV line 1: column 'v': can't parse '1 x 3' as a Vector");
+        let load = CsvReader::new(schema).with_header(false).with_malformed_rows(MalformedRows::NullFill).load(b"1 2 3\nbad\n").unwrap();
+        assert_eq!(load.result.column("v").unwrap().get(1), Some(Scalar::Null));
+        assert!(matches!(CsvReader::new(Schema::from(vec![("v", Datatype::Vector)])).parse(b"v\n"), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn normalizes_strings_if_the_schema_says() {
+        let input = "cafe\u{301}\ncaf\u{e9}\n";
+        let schema = Schema::new(vec![Field::new("s", Datatype::Str).with_nfc(true)]);
+        let res = CsvReader::new(schema).with_header(false).parse(input.as_bytes()).unwrap();
+        crate::assert_columns_eq!(res.column("s").unwrap(), &Column::from(vec!["caf\u{e9}", "caf\u{e9}"]));
+        let res = CsvReader::new(Schema::from(vec![("s", Datatype::Str)])).with_header(false).parse(input.as_bytes()).unwrap();
+        crate::assert_columns_eq!(res.column("s").unwrap(), &Column::from(vec!["cafe\u{301}", "caf\u{e9}"]));
+    }
+
+    #[test]
+    fn encodes_as_the_schema_says() {
+        let schema = Schema::new(vec![Field::new("n", Datatype::Entity).with_encoding(Encoding::Rle)]);
+        let res = CsvReader::new(schema).with_header(false).parse(b"1\n1\n1\n2\n").unwrap();
+        assert!(matches!(res.column("n").unwrap(), Column::Rle(_)));
+        crate::assert_columns_eq!(res.column("n").unwrap(), &Column::from(vec![1u64, 1, 1, 2]));
+    }
+
+    #[test]
+    fn reads_files() {
+        let path = std::env::temp_dir().join(format!("collie-csv-{}.csv", std::process::id()));
+        fs::write(&path, QUOTED).unwrap();
+        let res = CsvReader::new(schema()).read_path(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap().rows(), 5);
+        assert!(matches!(CsvReader::new(schema()).read_path(&path), Err(VMError::Io(_))));
+    }
+
+    fn nulls(missing: &[Selection]) -> Vec<Vec<usize>> {
+        missing.iter().map(Selection::positions).collect()
+    }
+
+    fn values(col: &Column) -> Vec<Scalar> {
+        (0 .. col.len()).map(|i| col.get(i).unwrap()).collect()
+    }
+
+    #[test]
+    fn splits_around_escapes() {
+        let dialect = Dialect::default().with_escape(Some(b'\\'));
+        let input = b"a\\\nb,1\n\"c\\\"\nd\",2\n";
+        for chunk_size in 1 .. input.len() {
+            for (from, to) in split(input, chunk_size, &dialect) {
+                assert!([0, 7].contains(&from) && [7, input.len()].contains(&to), "chunk size {}: {} .. {}", chunk_size, from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_other_delimiters_and_decimal_separators() {
+        let dialect = Dialect::default().with_delimiter(b';').with_decimal(b',');
+        let input = b"1;a;1,5;true\n2;\"x;y\";-2,25;0\n3;z; 7 ;1\n";
+        let res = CsvReader::new(schema()).with_header(false).with_dialect(dialect.clone()).parse(input).unwrap();
+        crate::assert_columns_eq!(res.column("name").unwrap(), &strs(&["a", "x;y", "z"]));
+        crate::assert_columns_eq!(res.column("price").unwrap(), &Column::from(vec![1.5, -2.25, 7.0]));
+        // a '.' is neither the separator nor a digit
+        let msg = message(CsvReader::new(schema()).with_header(false).with_dialect(dialect).parse(b"1;a;1.5;1\n").unwrap_err());
+        assert_eq!(msg, "CSV line 1: column 'price': can't parse '1.5' as Num");
+    }
+
+    #[test]
+    fn reads_unquoted_fields_literally_without_a_quote() {
+        let dialect = Dialect::default().with_quote(None);
+        let res = CsvReader::new(schema()).with_header(false).with_dialect(dialect).parse(b"1,\"a,1,1\n2,b\"\",2,0\n").unwrap();
+        crate::assert_columns_eq!(res.column("name").unwrap(), &strs(&["\"a", "b\"\""]));
+        crate::assert_columns_eq!(res.column("price").unwrap(), &Column::from(vec![1.0, 2.0]));
+    }
+
+    #[test]
+    fn reads_escapes_in_and_out_of_quotes() {
+        let reader = CsvReader::new(schema()).with_header(false).with_dialect(Dialect::default().with_escape(Some(b'\\')));
+        let input = "1,a\\,b,1,1\n2,\"say \\\"hi\\\" \"\"twice\"\"\",2,0\n3,two\\\nlines,3,1\n4,\\\\\\é,4,0\n";
+        let res = reader.parse(input.as_bytes()).unwrap();
+        crate::assert_columns_eq!(res.column("name").unwrap(), &strs(&["a,b", "say \"hi\" \"twice\"", "two\nlines", "\\é"]));
+        crate::assert_columns_eq!(res.column("id").unwrap(), &Column::from(vec![1u64, 2, 3, 4]));
+        for input in [&b"1,a,1,1\n2,b,2,1\\"[..], &b"1,a,1,1\n2,\"b\\"[..]] {
+            let msg = message(reader.parse(input).unwrap_err());
+            assert_eq!(msg, "CSV line 2: nothing after an escape character");
+        }
+    }
+
+    #[test]
+    fn every_chunk_size_reads_a_dialect_alike() {
+        let dialect = Dialect::default().with_delimiter(b'\t').with_quote(Some(b'\'')).with_escape(Some(b'\\')).with_decimal(b',');
+        let input = "1\t'a\tb'\t1,5\t1\n2\tc\\\nd\t2\t0\r\n3\t'e\\'\n''f'\t-3\ttrue\n4\tg\\\t\t,25\t0";
+        let reader = |chunk_size| CsvReader::new(schema()).with_header(false).with_dialect(dialect.clone()).with_chunk_size(chunk_size);
+        let whole = reader(DEFAULT_CHUNK_SIZE).parse(input.as_bytes()).unwrap();
+        crate::assert_columns_eq!(whole.column("name").unwrap(), &strs(&["a\tb", "c\nd", "e'\n'f", "g\t"]));
+        crate::assert_columns_eq!(whole.column("price").unwrap(), &Column::from(vec![1.5, 2.0, -3.0, 0.25]));
+        for chunk_size in 1 .. input.len() + 2 {
+            let res = reader(chunk_size).with_threads(3).parse(input.as_bytes()).unwrap();
+            for (a, b) in whole.columns.iter().zip(res.columns.iter()) {
+                crate::assert_columns_eq!(a, b, "chunk size {}", chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn null_tokens_are_null_unless_quoted() {
+        let dialect = Dialect::default().with_null_tokens(&["NA", ""]);
+        let input = b"id,name,price,ok\n1,NA,NA,\n2,\"NA\",2,1\n3,\"\",3,NA\n";
+        let load = CsvReader::new(schema()).with_dialect(dialect).load(input).unwrap();
+        let str = |s: &str| Scalar::Str(s.to_string());
+        assert_eq!(values(load.result.column("name").unwrap()), vec![Scalar::Null, str("NA"), str("")]);
+        assert_eq!(values(load.result.column("price").unwrap()), vec![Scalar::Null, Scalar::Num(2.0), Scalar::Num(3.0)]);
+        assert_eq!(values(load.result.column("ok").unwrap()), vec![Scalar::Null, Scalar::Bool(true), Scalar::Null]);
+        assert_eq!(nulls(&load.missing), vec![vec![], vec![0], vec![0], vec![0, 2]]);
+        // and filters and aggregates pass over them
+        let price = load.result.column("price").unwrap();
+        assert_eq!(price.filter(Scalar::Num(0.0)).unwrap().selection().count_ones(), 0);
+        assert_eq!(aggregate::reduce(price, Agg::Sum).unwrap(), Scalar::Num(5.0));
+        assert!(matches!(load.result.column("id").unwrap(), Column::Entity(_)));
+        assert_eq!(load.skipped, 0);
+        // without null tokens, an empty field is just empty
+        let load = CsvReader::new(schema()).load(b"id,name,price,ok\n1,,1,1\n").unwrap();
+        assert_eq!(nulls(&load.missing), vec![Vec::<usize>::new(); 4]);
+        assert!(CsvReader::new(schema()).load(b"id,name,price,ok\n1,a,,1\n").is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_dialects() {
+        let bad = [
+            Dialect::default().with_delimiter(b'"'),
+            Dialect::default().with_escape(Some(b',')),
+            Dialect::default().with_quote(Some(b'\\')).with_escape(Some(b'\\')),
+            Dialect::default().with_delimiter(b'\n'),
+            Dialect::default().with_escape(Some(b'\r')),
+            Dialect::default().with_delimiter(0xa7),
+            Dialect::default().with_decimal(b',')
+        ];
+        for dialect in bad.iter() {
+            let res = CsvReader::new(schema()).with_dialect(dialect.clone()).parse(b"");
+            assert!(matches!(res, Err(VMError::TypeError(ref msg)) if msg.starts_with("Bad CSV dialect")), "{:?}", dialect);
+        }
+        let fine = Dialect::default().with_delimiter(b';').with_decimal(b',').with_quote(None).with_escape(Some(b'"'));
+        assert!(CsvReader::new(schema()).with_header(false).with_dialect(fine).parse(b"").is_ok());
+    }
+
+    // a short row, a field that doesn't parse and a long one, with a quoted newline before it
+    const MALFORMED: &str = "id,name,price,ok\n\
+        1,a,1,1\n\
+        2,b,2\n\
+        3,c,x,1\n\
+        4,\"d\ne\",4,1,extra\n\
+        5,f,5,0\n";
+
+    #[test]
+    fn malformed_rows_fail_at_their_line() {
+        let cases: [(&[u8], &str); 3] = [
+            (MALFORMED.as_bytes(), "CSV line 3: expected 4 fields, found 3"),
+            (b"id,name,price,ok\n1,a,1,1\n3,c,x,1\n", "CSV line 3: column 'price': can't parse 'x' as Num"),
+            (b"id,name,price,ok\n1,\"a\nb\",1,1\n4,d,4,1,extra\n", "CSV line 4: expected 4 fields, found 5")
+        ];
+        for (input, expected) in cases.iter() {
+            for chunk_size in [1, 4, DEFAULT_CHUNK_SIZE].iter() {
+                let res = CsvReader::new(schema()).with_chunk_size(*chunk_size).load(input);
+                assert_eq!(message(res.unwrap_err()), *expected, "chunk size {}", chunk_size);
+            }
+        }
+        assert_eq!(MalformedRows::default(), MalformedRows::Fail);
+    }
+
+    #[test]
+    fn malformed_rows_can_be_skipped() {
+        for chunk_size in 1 .. MALFORMED.len() + 2 {
+            let reader = CsvReader::new(schema()).with_malformed_rows(MalformedRows::Skip).with_chunk_size(chunk_size);
+            let load = reader.load(MALFORMED.as_bytes()).unwrap();
+            crate::assert_columns_eq!(load.result.column("id").unwrap(), &Column::from(vec![1u64, 5]), "chunk size {}", chunk_size);
+            crate::assert_columns_eq!(load.result.column("name").unwrap(), &strs(&["a", "f"]));
+            assert_eq!(load.skipped, 3, "chunk size {}", chunk_size);
+            assert_eq!(nulls(&load.missing), vec![Vec::<usize>::new(); 4]);
+        }
+    }
+
+    #[test]
+    fn malformed_rows_can_be_filled_with_nulls() {
+        for chunk_size in 1 .. MALFORMED.len() + 2 {
+            let reader = CsvReader::new(schema()).with_malformed_rows(MalformedRows::NullFill).with_chunk_size(chunk_size);
+            let load = reader.load(MALFORMED.as_bytes()).unwrap();
+            let res = &load.result;
+            crate::assert_columns_eq!(res.column("id").unwrap(), &Column::from(vec![1u64, 2, 3, 4, 5]), "chunk size {}", chunk_size);
+            crate::assert_columns_eq!(res.column("name").unwrap(), &strs(&["a", "b", "c", "d\ne", "f"]));
+            // the short row's 'ok' and the unparsed price are null; the extra field is dropped
+            let price = values(res.column("price").unwrap());
+            assert_eq!(price, vec![Scalar::Num(1.0), Scalar::Num(2.0), Scalar::Null, Scalar::Num(4.0), Scalar::Num(5.0)]);
+            let ok = values(res.column("ok").unwrap());
+            assert_eq!(ok, vec![Scalar::Bool(true), Scalar::Null, Scalar::Bool(true), Scalar::Bool(true), Scalar::Bool(false)]);
+            assert_eq!(nulls(&load.missing), vec![vec![], vec![], vec![2], vec![1]], "chunk size {}", chunk_size);
+            assert_eq!(load.skipped, 0);
+        }
+    }
+
+    #[test]
+    fn broken_quoting_fails_whatever_the_policy() {
+        let cases: [(&[u8], &str); 2] = [
+            (b"id,name,price,ok\n1,a,1,1\n2,\"b\"c,2,1\n", "CSV line 3: unexpected character after a quoted field"),
+            (b"id,name,price,ok\n1,a,1,1\n2,\"b,2,1\n", "CSV line 3: unterminated quoted field")
+        ];
+        for policy in [MalformedRows::Fail, MalformedRows::Skip, MalformedRows::NullFill] {
+            for (input, expected) in cases.iter() {
+                let res = CsvReader::new(schema()).with_malformed_rows(policy).load(input);
+                assert_eq!(message(res.unwrap_err()), *expected, "{:?}", policy);
+            }
+        }
+    }
+
+    #[test]
+    fn null_fill_needs_a_category_to_stand_for_null() {
+        let schema = Schema::new(vec![Field::categorical("level", Categories::new(&["low"]).unwrap())]);
+        let load = CsvReader::new(schema).with_header(false).with_malformed_rows(MalformedRows::NullFill).load(b"low\nhigh\n").unwrap();
+        assert_eq!(nulls(&load.missing), vec![vec![1]]);
+        assert_eq!(load.result.rows(), 2);
+        let schema = Schema::new(vec![Field::categorical("level", Categories::new(&[]).unwrap())]);
+        let res = CsvReader::new(schema).with_header(false).with_malformed_rows(MalformedRows::NullFill).load(b"low\n");
+        assert_eq!(message(res.unwrap_err()), "CSV line 1: column 'level': null in a column with no categories");
+    }
+
+}
@@ -0,0 +1,156 @@
+// Paging through query results. A Cursor runs a row-by-row program (see Op::is_row_local)
+// over the VM's columns one window of rows at a time, and hands out the output in batches of
+// a fixed number of rows - so a server or UI can fetch page N without the whole result ever
+// being materialized. Only the rows of the current batch, plus at most one window's worth
+// of leftovers, are held at any time.
+
+use crate::column::Column;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::result::ResultSet;
+use crate::vm::VM;
+
+// Input rows per VM run, unless set with with_window_rows
+const WINDOW_ROWS: usize = 16 * 1024;
+
+pub struct Cursor {
+    vm: VM,
+    code: Vec<Op>,
+    batch_rows: usize,
+    window_rows: usize,
+    next_row: usize,                // first input row of the next window
+    pending: Vec<Vec<Column>>,      // per window run: output rows not yet handed out
+    pending_rows: usize,
+    batches: usize,                 // batches handed out so far
+    names: Vec<String>              // for the result columns; "#i" for the i'th stack value otherwise
+}
+
+fn rows_of(columns: &[Column]) -> usize {
+    columns.iter().map(|c| c.len()).max().unwrap_or(0)
+}
+
+impl Cursor {
+    // Fails if `code` holds an op that isn't row-local, or batches would be empty
+    pub fn new(vm: VM, code: Vec<Op>, batch_rows: usize) -> Result<Cursor, VMError> {
+        if batch_rows == 0 {
+            return Err(VMError::TypeError("A cursor's batches must hold at least one row".to_string()));
+        }
+        if let Some(op) = code.iter().find(|op| !op.is_row_local()) {
+            return Err(VMError::TypeError(format!("{} can't run a window at a time, so it can't be paged", op.mnemonic())));
+        }
+        Ok(Cursor { vm, code, batch_rows, window_rows: WINDOW_ROWS, next_row: 0, pending: Vec::new(), pending_rows: 0, batches: 0,
+            names: Vec::new() })
+    }
+
+    // A window of 0 rows is taken as 1
+    pub fn with_window_rows(mut self, window_rows: usize) -> Self {
+        self.window_rows = window_rows.max(1);
+        self
+    }
+
+    pub fn with_names(mut self, names: &[&str]) -> Self {
+        self.names = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    // Batches handed out so far, i.e. the number of the next page
+    pub fn position(&self) -> usize {
+        self.batches
+    }
+
+    // Run the program over the next window, returning its result columns; None past the end
+    fn run_window(&mut self) -> Result<Option<Vec<Column>>, VMError> {
+        if self.next_row >= self.vm.rows() {
+            return Ok(None);
+        }
+        self.vm.set_window(Some((self.next_row, self.window_rows)));
+        self.next_row += self.window_rows;
+        let res = self.vm.run(self.code.clone());
+        self.vm.set_window(None);
+        let stack = self.vm.take_stack();
+        res?;
+        let mut columns = Vec::with_capacity(stack.len());
+        for v in &stack {
+            match self.vm.column_of(v) {
+                Some(c) => columns.push(c.clone()),
+                None => return Err(VMError::TypeError(format!("Paged results must be columns, found: {:?}", v)))
+            }
+        }
+        Ok(Some(columns))
+    }
+
+    // The next `batch_rows` rows of output (fewer for the last batch), or None once the
+    // results are used up
+    pub fn next_batch(&mut self) -> Result<Option<ResultSet>, VMError> {
+        while self.pending_rows < self.batch_rows {
+            let window = match self.run_window()? {
+                Some(window) => window,
+                None => break
+            };
+            if let Some(first) = self.pending.first() {
+                if first.len() != window.len() {
+                    return Err(VMError::LengthMismatch { expected: first.len(), found: window.len() });
+                }
+            }
+            self.pending_rows += rows_of(&window);
+            self.pending.push(window);
+        }
+        if self.pending_rows == 0 {
+            return Ok(None);
+        }
+        // join up the windows' output, once per batch
+        let parts = core::mem::take(&mut self.pending);
+        let width = parts[0].len();
+        let joined: Vec<Column> = (0 .. width).map(|i| match parts.len() {
+            1 => Ok(parts[0][i].clone()),
+            _ => Column::concat(&parts.iter().map(|w| w[i].clone()).collect::<Vec<_>>())
+        }).collect::<Result<_, _>>()?;
+
+        let n = self.batch_rows;
+        let mut batch = ResultSet::new();
+        for (i, c) in joined.iter().enumerate() {
+            match self.names.get(i) {
+                Some(name) => batch.push(name, c.slice(0, n)),
+                None => batch.push(&format!("#{}", i), c.slice(0, n))
+            }
+        }
+        self.pending_rows -= self.pending_rows.min(n);
+        if self.pending_rows > 0 {
+            self.pending.push(joined.iter().map(|c| c.slice(n, c.len())).collect());
+        }
+        self.batches += 1;
+        Ok(Some(batch))
+    }
+
+    // Start over from the first row
+    pub fn rewind(&mut self) {
+        self.next_row = 0;
+        self.pending.clear();
+        self.pending_rows = 0;
+        self.batches = 0;
+    }
+
+    // Page `n` (counting from 0): the rows a sequence of next_batch calls would return as its
+    // n+1'th batch. Pages before it are computed and dropped, and going backwards starts over
+    // from the beginning.
+    pub fn page(&mut self, n: usize) -> Result<Option<ResultSet>, VMError> {
+        if n < self.batches {
+            self.rewind();
+        }
+        while self.batches < n {
+            if self.next_batch()?.is_none() {
+                return Ok(None);
+            }
+        }
+        self.next_batch()
+    }
+}
+
+impl Iterator for Cursor {
+    type Item = Result<ResultSet, VMError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch().transpose()
+    }
+}
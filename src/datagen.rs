@@ -0,0 +1,201 @@
+use crate::column::Column;
+use crate::core::prelude::*;
+use crate::schema::{Datatype, Schema};
+
+// Reproducible synthetic columns for benchmarks and examples.
+// Everything is driven by an explicit seed, so the same call always gives the same data.
+
+// SplitMix64: tiny, fast, and plenty random enough for test data.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // uniform in [0, n); n must be positive
+    pub fn below(&mut self, n: u64) -> u64 {
+        // multiply-shift; the bias is negligible for test data
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+// Numbers drawn uniformly from [lo, hi)
+pub fn uniform_num(rows: usize, lo: f64, hi: f64, seed: u64) -> Column {
+    let mut rng = Rng::new(seed);
+    Column::from((0 .. rows).map(|_| lo + rng.next_f64() * (hi - lo)).collect::<Vec<f64>>())
+}
+
+// Integers 1..=n_distinct (as Num), zipf-distributed with exponent `s`:
+// value k turns up with probability proportional to 1 / k^s, so a few values dominate.
+pub fn zipf_num(rows: usize, n_distinct: usize, s: f64, seed: u64) -> Column {
+    let mut cdf: Vec<f64> = Vec::with_capacity(n_distinct);
+    let mut total = 0.0;
+    for k in 1 ..= n_distinct {
+        total += 1.0 / libm::pow(k as f64, s);
+        cdf.push(total);
+    }
+    let mut rng = Rng::new(seed);
+    let data = (0 .. rows).map(|_| {
+        let u = rng.next_f64() * total;
+        let k = cdf.partition_point(|c| *c < u);
+        (k.min(n_distinct - 1) + 1) as f64
+    }).collect::<Vec<f64>>();
+    Column::from(data)
+}
+
+// Strings "v0" .. "v{cardinality - 1}", picked uniformly. Use a small cardinality for
+// category-like columns and roughly `rows` for near-unique ones.
+pub fn strings(rows: usize, cardinality: usize, seed: u64) -> Column {
+    let mut rng = Rng::new(seed);
+    let values: Vec<String> = (0 .. rows).map(|_| format!("v{}", rng.below(cardinality as u64))).collect();
+    Column::from(values)
+}
+
+// Primary keys 0 .. rows, shuffled
+pub fn entity_keys(rows: usize, seed: u64) -> Column {
+    let mut keys: Vec<u64> = (0 .. rows as u64).collect();
+    let mut rng = Rng::new(seed);
+    // Fisher-Yates
+    for i in (1 .. keys.len()).rev() {
+        let j = rng.below(i as u64 + 1) as usize;
+        keys.swap(i, j);
+    }
+    Column::from(keys)
+}
+
+// Foreign keys into a table built with entity_keys(key_space, ..): each row references
+// an existing key with probability `match_fraction`, and otherwise a key that doesn't
+// exist - so joining the two gives roughly rows * match_fraction results.
+pub fn foreign_keys(rows: usize, key_space: usize, match_fraction: f64, seed: u64) -> Column {
+    let mut rng = Rng::new(seed);
+    let keys = (0 .. rows).map(|_| {
+        if rng.next_f64() < match_fraction {
+            rng.below(key_space as u64)
+        } else {
+            key_space as u64 + rng.below(key_space.max(1) as u64)
+        }
+    }).collect::<Vec<u64>>();
+    Column::from(keys)
+}
+
+// A small "people" table, handy for examples:
+// id (Entity), name (near-unique Str), age (uniform Num), city (low-cardinality Str), score (zipf Num)
+pub fn people(rows: usize, seed: u64) -> (Schema, Vec<Column>) {
+    let schema = Schema::from(vec![
+        ("id", Datatype::Entity),
+        ("name", Datatype::Str),
+        ("age", Datatype::Num),
+        ("city", Datatype::Str),
+        ("score", Datatype::Num)
+    ]);
+    let columns = vec![
+        entity_keys(rows, seed),
+        strings(rows, rows.max(1), seed.wrapping_add(1)),
+        uniform_num(rows, 18.0, 90.0, seed.wrapping_add(2)),
+        strings(rows, 20, seed.wrapping_add(3)),
+        zipf_num(rows, 100, 1.1, seed.wrapping_add(4))
+    ];
+    (schema, columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{ColumnT, Scalar};
+
+    fn count_eq(col: &Column, val: Scalar) -> usize {
+        match col {
+            Column::Num(c) => c.filter(val).unwrap().count_ones(),
+            Column::Entity(c) => c.filter(val).unwrap().count_ones(),
+            Column::InlineStr(c) => c.filter(val).unwrap().count_ones(),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_data() {
+        let gens: [fn(u64) -> Column; 5] = [
+            |seed| uniform_num(100, 0.0, 1.0, seed),
+            |seed| zipf_num(100, 10, 1.0, seed),
+            |seed| strings(100, 10, seed),
+            |seed| entity_keys(100, seed),
+            |seed| foreign_keys(100, 10, 0.5, seed),
+        ];
+        for gen in gens {
+            assert_eq!(gen(7).fingerprint(), gen(7).fingerprint());
+            assert_ne!(gen(7).fingerprint(), gen(8).fingerprint());
+            assert_eq!(gen(7).len(), 100);
+        }
+    }
+
+    #[test]
+    fn rng_stays_in_range() {
+        let mut rng = Rng::new(1);
+        for _ in 0 .. 10_000 {
+            let x = rng.next_f64();
+            assert!((0.0 .. 1.0).contains(&x));
+            assert!(rng.below(7) < 7);
+        }
+        assert_eq!(Rng::new(3).below(1), 0);
+    }
+
+    #[test]
+    fn zipf_favours_small_values() {
+        let col = zipf_num(10_000, 10, 1.0, 5);
+        let counts: Vec<usize> = (1 ..= 10).map(|k| count_eq(&col, Scalar::Num(k as f64))).collect();
+        assert_eq!(counts.iter().sum::<usize>(), 10_000);
+        // 1/H(10) ~ 34% of rows are 1s, and ~3.4% are 10s
+        assert!(counts[0] > 3000 && counts[0] < 3800, "{:?}", counts);
+        assert!(counts[0] > counts[4] && counts[4] > counts[9]);
+    }
+
+    #[test]
+    fn entity_keys_are_a_permutation() {
+        let col = entity_keys(200, 9);
+        assert!((0 .. 200).all(|k| count_eq(&col, Scalar::Entity(k)) == 1));
+        assert_ne!(col.fingerprint(), Column::from((0 .. 200).collect::<Vec<u64>>()).fingerprint());
+    }
+
+    #[test]
+    fn foreign_keys_match_about_the_requested_fraction() {
+        let col = foreign_keys(4000, 100, 0.25, 11);
+        let matched: usize = (0 .. 100).map(|k| count_eq(&col, Scalar::Entity(k))).sum();
+        assert!(matched > 850 && matched < 1150, "{} of 4000 matched", matched);
+        assert_eq!(foreign_keys(50, 100, 1.0, 11).fingerprint(), foreign_keys(50, 100, 1.0, 11).fingerprint());
+        assert_eq!((0 .. 100).map(|k| count_eq(&foreign_keys(50, 100, 0.0, 1), Scalar::Entity(k))).sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn strings_draw_from_the_cardinality() {
+        let col = strings(1000, 4, 2);
+        let counts: Vec<usize> = (0 .. 4).map(|i| count_eq(&col, Scalar::Str(format!("v{}", i)))).collect();
+        assert_eq!(counts.iter().sum::<usize>(), 1000);
+        assert!(counts.iter().all(|c| *c > 150), "{:?}", counts);
+    }
+
+    #[test]
+    fn people_columns_follow_the_schema() {
+        let (schema, columns) = people(30, 1);
+        assert_eq!(schema.len(), columns.len());
+        for (i, col) in columns.iter().enumerate() {
+            assert_eq!(col.len(), 30);
+            assert_eq!(col.datatype(), schema.field(i).unwrap().dtype);
+        }
+        assert_eq!(schema.index_of("city"), Some(3));
+    }
+}
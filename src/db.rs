@@ -0,0 +1,504 @@
+// A database: named SharedTables, which can be saved to and restored from a single snapshot
+// file - for backups, cloning an environment, or shipping test fixtures.
+//
+// Tables and their columns can carry metadata (see schema::Metadata), which is saved with them.
+//
+// Snapshot layout: the magic bytes "COLLIEDB" and a format version (u32), then the table
+// count (u32) and per table its name, metadata, column count (u32) and per column its name,
+// datatype tag and metadata, followed by the table's columns in storage.rs layout and its row
+// policy: a 0 byte if it has none, else a 1 and the policy (see policy.rs). Metadata is an
+// entry count (u32) then each key and value; version 1 snapshots, from before there was any,
+// are read as having none, and snapshots before version 3 as having no policies. Each table
+// is saved as of one version; tables written to during an export may be caught at different
+// moments. Columns are re-encoded on import, as on any load.
+
+use crate::audit::{AuditEvent, AuditHook, QueryContext};
+use crate::cache::ResultCache;
+use crate::column::Column;
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::policy::RowPolicy;
+use crate::result::ResultSet;
+use crate::schema::{Field, Metadata, Schema};
+use crate::shared::SharedTable;
+use crate::storage::{self, invalid};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+const MAGIC: &[u8; 8] = b"COLLIEDB";
+const FORMAT_VERSION: u32 = 3;
+
+#[derive(Default)]
+pub struct Db {
+    tables: Vec<Entry>,
+    cache: Option<ResultCache>,
+    audit: Option<AuditHook>
+}
+
+struct Entry {
+    name: String,
+    table: SharedTable,
+    metadata: Metadata,
+    columns: BTreeMap<String, Metadata>,    // by column name; columns without any are left out
+    policy: Option<RowPolicy>
+}
+
+impl Db {
+    pub fn new() -> Self {
+        Db::default()
+    }
+
+    pub fn create_table(&mut self, name: &str, data: ResultSet) -> Result<(), VMError> {
+        if self.table(name).is_some() {
+            return Err(VMError::TypeError(format!("There's already a table named '{}'", name)));
+        }
+        let entry = Entry { name: name.to_string(), table: SharedTable::new(data)?, metadata: Metadata::new(), columns: BTreeMap::new(), policy: None };
+        self.tables.push(entry);
+        Ok(())
+    }
+
+    // create_table, with the metadata of `schema`'s fields on the columns they name
+    pub fn create_table_with_schema(&mut self, name: &str, data: ResultSet, schema: &Schema) -> Result<(), VMError> {
+        if let Some(field) = schema.fields.iter().find(|f| !data.names.contains(&f.name)) {
+            return Err(VMError::TypeError(format!("No column named '{}'", field.name)));
+        }
+        self.create_table(name, data)?;
+        let entry = self.entry_mut(name)?;
+        for field in schema.fields.iter().filter(|f| !f.metadata.is_empty()) {
+            entry.columns.insert(field.name.clone(), field.metadata.clone());
+        }
+        Ok(())
+    }
+
+    pub fn table(&self, name: &str) -> Option<&SharedTable> {
+        self.entry(name).map(|e| &e.table)
+    }
+
+    pub fn table_names(&self) -> Vec<&str> {
+        self.tables.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    // The latest version's columns, as fields carrying their metadata
+    pub fn schema(&self, table: &str) -> Option<Schema> {
+        let entry = self.entry(table)?;
+        let version = entry.table.snapshot();
+        let fields = version.names.iter().zip(version.columns.iter()).map(|(name, col)| Field {
+            metadata: entry.columns.get(name).cloned().unwrap_or_default(),
+            dim: if let Column::Vector(c) = col.as_ref() { Some(c.dim()) } else { None },
+            ..Field::new(name, col.datatype())
+        });
+        Some(Schema::new(fields.collect()))
+    }
+
+    pub fn table_metadata(&self, table: &str) -> Option<&Metadata> {
+        self.entry(table).map(|e| &e.metadata)
+    }
+
+    pub fn set_table_metadata(&mut self, table: &str, key: &str, value: &str) -> Result<(), VMError> {
+        self.entry_mut(table)?.metadata.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    // None if there's no such table or column
+    pub fn column_metadata(&self, table: &str, column: &str) -> Option<Metadata> {
+        let entry = self.entry(table)?;
+        if !entry.table.snapshot().names.iter().any(|n| n == column) {
+            return None;
+        }
+        Some(entry.columns.get(column).cloned().unwrap_or_default())
+    }
+
+    pub fn set_column_metadata(&mut self, table: &str, column: &str, key: &str, value: &str) -> Result<(), VMError> {
+        let entry = self.entry_mut(table)?;
+        if !entry.table.snapshot().names.iter().any(|n| n == column) {
+            return Err(VMError::TypeError(format!("No column named '{}' in table '{}'", column, table)));
+        }
+        entry.columns.entry(column.to_string()).or_default().insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    // Show queries of `table` only the rows `policy` lets their context see; see policy.rs.
+    // Replaces any policy it had.
+    pub fn set_row_policy(&mut self, table: &str, policy: RowPolicy) -> Result<(), VMError> {
+        self.entry_mut(table)?.policy = Some(policy);
+        Ok(())
+    }
+
+    pub fn clear_row_policy(&mut self, table: &str) -> Result<(), VMError> {
+        self.entry_mut(table)?.policy = None;
+        Ok(())
+    }
+
+    // (table, column) for every column tagged `key` = `value`, e.g. ("pii", "true")
+    pub fn columns_tagged(&self, key: &str, value: &str) -> Vec<(&str, &str)> {
+        self.tables.iter()
+            .flat_map(|e| e.columns.iter()
+                .filter(|(_, metadata)| metadata.get(key).map(|v| v.as_str()) == Some(value))
+                .map(move |(column, _)| (e.name.as_str(), column.as_str())))
+            .collect()
+    }
+
+    fn entry(&self, name: &str) -> Option<&Entry> {
+        self.tables.iter().find(|e| e.name == name)
+    }
+
+    fn entry_mut(&mut self, name: &str) -> Result<&mut Entry, VMError> {
+        self.tables.iter_mut().find(|e| e.name == name).ok_or_else(|| VMError::TypeError(format!("No table named '{}'", name)))
+    }
+
+    // Keep the results of up to `capacity` queries, for query() to return while their table
+    // is unchanged
+    pub fn enable_result_cache(&mut self, capacity: usize) {
+        self.cache = Some(ResultCache::new(capacity));
+    }
+
+    pub fn result_cache(&self) -> Option<&ResultCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn result_cache_mut(&mut self) -> Option<&mut ResultCache> {
+        self.cache.as_mut()
+    }
+
+    // Call `hook` with every query from now on; see audit.rs
+    pub fn set_audit_hook<F>(&mut self, hook: F)
+        where F: Fn(&AuditEvent) + Send + Sync + 'static {
+        self.audit = Some(Arc::new(hook));
+    }
+
+    // Run `code` over the latest version of table `table`, naming the result columns `names`
+    pub fn query(&self, table: &str, code: &[Op], names: &[&str]) -> Result<Arc<ResultSet>, VMError> {
+        self.query_in(table, code, names, &QueryContext::default())
+    }
+
+    // query, as the session `context` describes: its rows are those the table's row policy
+    // lets it see, and it's passed on to the audit hook
+    pub fn query_in(&self, table: &str, code: &[Op], names: &[&str], context: &QueryContext) -> Result<Arc<ResultSet>, VMError> {
+        let entry = self.entry(table).ok_or_else(|| VMError::TypeError(format!("No table named '{}'", table)))?;
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let start = Instant::now();
+        let version = entry.table.snapshot();
+        let res = match (&entry.policy, &self.cache) {
+            // what a query returns depends on who asks, so those results aren't cached
+            (Some(policy), _) => policy.apply(&version, context)
+                .and_then(|visible| visible.query(code, None, &names))
+                .map(|r| (Arc::new(r), false)),
+            (None, Some(cache)) => cache.get_or_run_version(table, &version, code, &names),
+            (None, None) => version.query(code, None, &names).map(|r| (Arc::new(r), false))
+        };
+        if let Some(hook) = &self.audit {
+            hook(&AuditEvent {
+                program: code,
+                tables: vec![table],
+                version: version.number,
+                rows_scanned: version.rows(),
+                rows_returned: res.as_ref().map_or(0, |(r, _)| r.rows()),
+                duration: start.elapsed(),
+                cached: res.as_ref().is_ok_and(|(_, hit)| *hit),
+                error: res.as_ref().err(),
+                context
+            });
+        }
+        Ok(res?.0)
+    }
+
+    // Write every table to `path`. The file is written beside it and renamed into place, so
+    // `path` never holds half a snapshot.
+    pub fn export_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), VMError> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let res = File::create(&tmp).and_then(|f| {
+            let mut w = BufWriter::new(f);
+            self.write_snapshot(&mut w)?;
+            w.into_inner().map_err(|e| e.into_error())?.sync_all()
+        }).and_then(|_| fs::rename(&tmp, path));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res.map_err(VMError::Io)
+    }
+
+    fn write_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        storage::write_u32(w, FORMAT_VERSION)?;
+        storage::write_len(w, self.tables.len())?;
+        for entry in &self.tables {
+            let version = entry.table.snapshot();
+            storage::write_str(w, &entry.name)?;
+            write_metadata(w, &entry.metadata)?;
+            storage::write_len(w, version.columns.len())?;
+            for (col_name, col) in version.names.iter().zip(version.columns.iter()) {
+                storage::write_str(w, col_name)?;
+                w.write_all(&[storage::datatype_tag(col.datatype())])?;
+                write_metadata(w, entry.columns.get(col_name).unwrap_or(&Metadata::new()))?;
+            }
+            version.columns.iter().try_for_each(|col| storage::write_column(w, col))?;
+            match &entry.policy {
+                Some(policy) => {
+                    w.write_all(&[1])?;
+                    policy.write(w)?;
+                },
+                None => w.write_all(&[0])?
+            }
+        }
+        Ok(())
+    }
+
+    // A database with the tables saved in the snapshot at `path`
+    pub fn import_snapshot<P: AsRef<Path>>(path: P) -> Result<Db, VMError> {
+        let mut r = BufReader::new(File::open(path).map_err(VMError::Io)?);
+        Db::read_snapshot(&mut r).map_err(VMError::Io)
+    }
+
+    fn read_snapshot<R: Read>(r: &mut R) -> io::Result<Db> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a collie snapshot".to_string()));
+        }
+        let version = storage::read_u32(r)?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(invalid(format!("snapshot format version {}, expected {}", version, FORMAT_VERSION)));
+        }
+        let mut db = Db::new();
+        for _ in 0 .. storage::read_u32(r)? {
+            let name = storage::read_str(r)?;
+            let metadata = if version > 1 { read_metadata(r)? } else { Metadata::new() };
+            let mut fields = Vec::new();
+            for _ in 0 .. storage::read_u32(r)? {
+                let col_name = storage::read_str(r)?;
+                let mut tag = [0; 1];
+                r.read_exact(&mut tag)?;
+                let mut field = Field::new(&col_name, storage::datatype_of_tag(tag[0])?);
+                if version > 1 {
+                    field.metadata = read_metadata(r)?;
+                }
+                fields.push(field);
+            }
+            let mut data = ResultSet::new();
+            for field in &fields {
+                data.push(&field.name, storage::read_column(r, field.dtype)?);
+            }
+            db.create_table_with_schema(&name, data, &Schema::new(fields)).map_err(|e| invalid(format!("{:?}", e)))?;
+            let entry = db.entry_mut(&name).map_err(|e| invalid(format!("{:?}", e)))?;
+            entry.metadata = metadata;
+            if version > 2 {
+                let mut flag = [0; 1];
+                r.read_exact(&mut flag)?;
+                entry.policy = match flag[0] {
+                    0 => None,
+                    1 => Some(RowPolicy::read(r)?),
+                    other => return Err(invalid(format!("row policy flag {}", other)))
+                };
+            }
+        }
+        Ok(db)
+    }
+}
+
+fn write_metadata<W: Write>(w: &mut W, metadata: &Metadata) -> io::Result<()> {
+    storage::write_len(w, metadata.len())?;
+    metadata.iter().try_for_each(|(key, value)| {
+        storage::write_str(w, key)?;
+        storage::write_str(w, value)
+    })
+}
+
+fn read_metadata<R: Read>(r: &mut R) -> io::Result<Metadata> {
+    (0 .. storage::read_u32(r)?).map(|_| Ok((storage::read_str(r)?, storage::read_str(r)?))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{Column, Scalar};
+    use crate::schema::Datatype;
+
+    use std::convert::TryFrom;
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("collie-{}-{}.db", name, std::process::id()))
+    }
+
+    fn db() -> Db {
+        let mut db = Db::new();
+        let mut people = ResultSet::new();
+        people.push("name", Column::from(vec!["ann".to_string(), "bo".to_string(), "cy".to_string()]));
+        people.push("age", Column::from(vec![31u64, 42, 27]));
+        people.push("score", Column::from(vec![0.5, f64::NAN, -1.0]));
+        db.create_table("people", people).unwrap();
+        let mut empty = ResultSet::new();
+        empty.push("x", Column::from(Vec::<bool>::new()));
+        db.create_table("empty", empty).unwrap();
+        db.set_table_metadata("people", "owner", "data team").unwrap();
+        db.set_column_metadata("people", "age", "unit", "years").unwrap();
+        db
+    }
+
+    #[test]
+    fn round_trips_a_snapshot() {
+        let (db, path) = (db(), path("round-trip"));
+        db.export_snapshot(&path).unwrap();
+        let res = Db::import_snapshot(&path);
+        fs::remove_file(&path).unwrap();
+        let res = res.unwrap();
+
+        assert_eq!(res.table_names(), db.table_names());
+        for name in db.table_names() {
+            assert_eq!(res.schema(name), db.schema(name));
+            assert_eq!(res.table_metadata(name), db.table_metadata(name));
+            let (a, b) = (db.table(name).unwrap().snapshot(), res.table(name).unwrap().snapshot());
+            assert_eq!(a.names, b.names);
+            for (x, y) in a.columns.iter().zip(b.columns.iter()) {
+                crate::assert_columns_eq!(x.as_ref(), y.as_ref(), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_row_policies() {
+        let mut db = db();
+        let code = vec![Op::Col(0, 1), Op::Lit(Scalar::Entity(0)), Op::FilterGt];
+        db.set_row_policy("people", RowPolicy::new(code).unwrap().with_param(1, "min_age", Datatype::Entity).unwrap()).unwrap();
+        let mut buf = Vec::new();
+        db.write_snapshot(&mut buf).unwrap();
+        let res = Db::read_snapshot(&mut buf.as_slice()).unwrap();
+
+        let context = QueryContext::new().with_value("min_age", "30");
+        let ages = res.query_in("people", &[Op::Col(0, 1)], &["age"], &context).unwrap();
+        assert_eq!(Vec::<u64>::try_from(ages.column("age").unwrap()).unwrap(), vec![31, 42]);
+        // still refused without the parameter, and the unguarded table is still open
+        assert!(res.query_in("people", &[Op::Col(0, 1)], &["age"], &QueryContext::new()).is_err());
+        assert!(res.query_in("empty", &[Op::Col(0, 0)], &["x"], &QueryContext::new()).is_ok());
+    }
+
+    #[test]
+    fn rejects_corrupt_snapshots() {
+        let mut db = db();
+        db.set_row_policy("empty", RowPolicy::new(vec![Op::Col(0, 0)]).unwrap()).unwrap();
+        let mut buf = Vec::new();
+        db.write_snapshot(&mut buf).unwrap();
+        assert!(Db::read_snapshot(&mut buf.as_slice()).is_ok());
+        for len in 0 .. buf.len() {
+            assert!(Db::read_snapshot(&mut &buf[.. len]).is_err(), "cut to {} bytes", len);
+        }
+        let mut bad = buf.clone();
+        bad[0] = b'X';
+        assert!(Db::read_snapshot(&mut bad.as_slice()).is_err());
+        let mut bad = buf;
+        bad[8 .. 12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(Db::read_snapshot(&mut bad.as_slice()).is_err());
+    }
+
+    #[test]
+    fn reads_version_1_snapshots_as_untagged() {
+        let mut buf = MAGIC.to_vec();
+        storage::write_u32(&mut buf, 1).unwrap();
+        storage::write_u32(&mut buf, 1).unwrap();
+        storage::write_str(&mut buf, "t").unwrap();
+        storage::write_u32(&mut buf, 1).unwrap();
+        storage::write_str(&mut buf, "x").unwrap();
+        buf.push(storage::datatype_tag(Datatype::Num));
+        storage::write_column(&mut buf, &Column::from(vec![1.0, 2.0])).unwrap();
+
+        let db = Db::read_snapshot(&mut buf.as_slice()).unwrap();
+        assert_eq!(db.table_names(), vec!["t"]);
+        assert_eq!(db.table_metadata("t"), Some(&Metadata::new()));
+        assert_eq!(db.column_metadata("t", "x"), Some(Metadata::new()));
+        crate::assert_columns_eq!(db.table("t").unwrap().snapshot().columns[0].as_ref(), &Column::from(vec![1.0, 2.0]));
+    }
+
+    #[test]
+    fn tags_tables_and_columns() {
+        let mut db = db();
+        assert_eq!(db.table_metadata("people").unwrap().get("owner").map(|s| s.as_str()), Some("data team"));
+        assert_eq!(db.table_metadata("nobody"), None);
+        assert_eq!(db.column_metadata("people", "name"), Some(Metadata::new()));
+        assert_eq!(db.column_metadata("people", "nothing"), None);
+        assert_eq!(db.column_metadata("nobody", "name"), None);
+        assert!(db.set_table_metadata("nobody", "k", "v").is_err());
+        assert!(db.set_column_metadata("people", "nothing", "k", "v").is_err());
+        assert!(db.set_column_metadata("nobody", "age", "k", "v").is_err());
+
+        // a second value for a key replaces the first
+        db.set_column_metadata("people", "age", "unit", "days").unwrap();
+        let schema = db.schema("people").unwrap();
+        assert_eq!(schema.fields[1].metadata("unit"), Some("days"));
+        assert_eq!(schema.fields[1].dtype, Datatype::Entity);
+        assert!(schema.fields[0].metadata.is_empty());
+        assert_eq!(db.schema("nobody"), None);
+
+        // projecting keeps the metadata
+        let projected = schema.project(&["age", "name"]).unwrap();
+        assert_eq!(projected.fields, vec![schema.fields[1].clone(), schema.fields[0].clone()]);
+        assert!(matches!(schema.project(&["age", "nothing"]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn finds_columns_by_tag() {
+        let mut db = db();
+        let mut data = ResultSet::new();
+        data.push("email", Column::from(vec!["a@b.c".to_string()]));
+        data.push("n", Column::from(vec![1.0]));
+        let schema = Schema::new(vec![Field::new("email", Datatype::Str).with_metadata("pii", "true")]);
+        db.create_table_with_schema("users", data, &schema).unwrap();
+        db.set_column_metadata("people", "name", "pii", "true").unwrap();
+        db.set_column_metadata("people", "score", "pii", "false").unwrap();
+        assert_eq!(db.columns_tagged("pii", "true"), vec![("people", "name"), ("users", "email")]);
+        assert_eq!(db.columns_tagged("pii", "maybe"), vec![]);
+
+        // the schema can only tag columns the data has
+        let schema = Schema::new(vec![Field::new("phone", Datatype::Str).with_metadata("pii", "true")]);
+        assert!(db.create_table_with_schema("more", ResultSet::new(), &schema).is_err());
+        assert_eq!(db.table_names(), vec!["people", "empty", "users"]);
+    }
+
+    #[test]
+    fn table_names_are_unique() {
+        let mut db = db();
+        assert!(db.create_table("people", ResultSet::new()).is_err());
+        assert_eq!(db.table_names(), vec!["people", "empty"]);
+    }
+
+    #[test]
+    fn tables_must_be_rectangular_with_distinct_column_names() {
+        let mut db = db();
+        let mut ragged = ResultSet::new();
+        ragged.push("id", Column::from(vec![1u64, 2, 3]));
+        ragged.push("x", Column::from(vec![1.0]));
+        assert!(matches!(db.create_table("ragged", ragged), Err(VMError::LengthMismatch { expected: 3, found: 1 })));
+        let mut twice = ResultSet::new();
+        twice.push("x", Column::from(vec![1.0]));
+        twice.push("x", Column::from(vec![2.0]));
+        assert!(matches!(db.create_table("twice", twice), Err(VMError::TypeError(_))));
+        assert_eq!(db.table_names(), vec!["people", "empty"]);
+    }
+
+    #[test]
+    fn queries_go_through_the_cache_once_enabled() {
+        let mut db = db();
+        let code = [Op::Col(0, 1), Op::Col(0, 1)];
+        let first = db.query("people", &code, &["age"]).unwrap();
+        assert_eq!(first.names, vec!["age", "#1"]);
+        assert!(!Arc::ptr_eq(&first, &db.query("people", &code, &["age"]).unwrap()));
+        assert!(db.query("nobody", &code, &[]).is_err());
+
+        db.enable_result_cache(4);
+        let first = db.query("people", &code, &["age"]).unwrap();
+        assert!(Arc::ptr_eq(&first, &db.query("people", &code, &["age"]).unwrap()));
+        assert_eq!(db.result_cache().unwrap().stats(), (1, 1));
+    }
+
+    #[test]
+    fn export_to_a_missing_directory_fails() {
+        let path = std::env::temp_dir().join(format!("collie-missing-{}", std::process::id())).join("x.db");
+        assert!(db().export_snapshot(&path).is_err());
+        assert!(!path.exists());
+    }
+}
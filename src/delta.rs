@@ -0,0 +1,449 @@
+// Delta encoding for sorted Num and Entity columns (timestamps, ids). Values are stored as
+// differences from their predecessor - or, for regularly spaced data, as differences between
+// consecutive differences - in the narrowest integer width that fits. Every BLOCK rows the
+// full value is stored again, so a lookup only ever decodes one block, and since the column
+// is sorted, range filters binary search the block bases instead of scanning.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Column, EntityColumn, NumColumn, Scalar};
+use crate::core::fract;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+
+const BLOCK: usize = 128;
+
+// Num values are mapped onto u64 keys, flipping the sign bit so that key order matches
+// numeric order. Only integers of up to 53 bits round-trip exactly.
+const SIGN: u64 = 1 << 63;
+const MAX_EXACT: f64 = (1u64 << 53) as f64;
+
+pub(crate) fn num_key(x: f64) -> Option<u64> {
+    if fract(x) != 0.0 || x.abs() > MAX_EXACT || (x == 0.0 && x.is_sign_negative()) {
+        return None;
+    }
+    Some((x as i64 as u64) ^ SIGN)
+}
+
+pub(crate) fn key_num(k: u64) -> f64 {
+    (k ^ SIGN) as i64 as f64
+}
+
+// Smallest key k with key_num(k) >= x (not NaN)
+pub(crate) fn num_lower_key(x: f64) -> u64 {
+    let x = libm::ceil(x).clamp(-MAX_EXACT - 1.0, MAX_EXACT + 1.0);
+    (x as i64 as u64) ^ SIGN
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeltaOrder {
+    Delta,
+    DeltaOfDelta
+}
+
+// One residual per row, at the narrowest width that holds the largest of them
+#[derive(Debug, Clone)]
+pub enum Residuals {
+    U8(Buffer<u8>),
+    U16(Buffer<u16>),
+    U32(Buffer<u32>)
+}
+
+impl Residuals {
+    fn pack(xs: &[u64]) -> Option<Residuals> {
+        let max = xs.iter().copied().max().unwrap_or(0);
+        if max <= u8::MAX as u64 {
+            Some(Residuals::U8(xs.iter().map(|x| *x as u8).collect()))
+        } else if max <= u16::MAX as u64 {
+            Some(Residuals::U16(xs.iter().map(|x| *x as u16).collect()))
+        } else if max <= u32::MAX as u64 {
+            Some(Residuals::U32(xs.iter().map(|x| *x as u32).collect()))
+        } else {
+            None
+        }
+    }
+
+    fn get(&self, i: usize) -> u64 {
+        match self {
+            Residuals::U8(xs) => xs[i] as u64,
+            Residuals::U16(xs) => xs[i] as u64,
+            Residuals::U32(xs) => xs[i] as u64
+        }
+    }
+
+    fn slice(&self, offset: usize, len: usize) -> Residuals {
+        match self {
+            Residuals::U8(xs) => Residuals::U8(xs.slice(offset, len)),
+            Residuals::U16(xs) => Residuals::U16(xs.slice(offset, len)),
+            Residuals::U32(xs) => Residuals::U32(xs.slice(offset, len))
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        match self {
+            Residuals::U8(xs) => xs.memory_usage(),
+            Residuals::U16(xs) => xs.memory_usage(),
+            Residuals::U32(xs) => xs.memory_usage()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeltaColumn {
+    dtype: Datatype,
+    order: DeltaOrder,
+    bases: Buffer<u64>,         // key of the first row of each block
+    first_deltas: Buffer<u64>,  // DeltaOfDelta only: each block's first delta, which its residuals are relative to
+    residuals: Residuals,       // one per row, starting at a block boundary
+    skip: usize,                // rows of the first block before this column starts (after slicing)
+    len: usize
+}
+
+impl DeltaColumn {
+    // Encode a sorted (non-decreasing) Num or Entity column, using whichever order comes out
+    // smaller. None if the column isn't sorted, holds non-integral numbers, or its gaps are
+    // too large to be worth it.
+    pub fn encode(col: &Column) -> Option<DeltaColumn> {
+        let delta = DeltaColumn::encode_with(col, DeltaOrder::Delta);
+        let dod = DeltaColumn::encode_with(col, DeltaOrder::DeltaOfDelta);
+        match (delta, dod) {
+            (Some(a), Some(b)) => Some(if b.memory_usage() < a.memory_usage() { b } else { a }),
+            (a, b) => a.or(b)
+        }
+    }
+
+    pub fn encode_with(col: &Column, order: DeltaOrder) -> Option<DeltaColumn> {
+        let (dtype, keys): (Datatype, Vec<u64>) = match col {
+            Column::Num(c) => (Datatype::Num, c.data.iter().map(|x| num_key(*x)).collect::<Option<_>>()?),
+            Column::Entity(c) => (Datatype::Entity, c.data.to_vec()),
+            _ => return None
+        };
+        if keys.windows(2).any(|w| w[0] > w[1]) {
+            return None;
+        }
+        let mut bases = Buffer::with_capacity(keys.len() / BLOCK + 1);
+        let mut first_deltas = Buffer::new();
+        let mut residuals = Vec::with_capacity(keys.len());
+        for block in keys.chunks(BLOCK) {
+            bases.push(block[0]);
+            residuals.push(0);
+            // seeding with the block's own first delta makes evenly spaced blocks all zeros
+            let mut prev_delta = if block.len() > 1 { block[1] - block[0] } else { 0 };
+            if order == DeltaOrder::DeltaOfDelta {
+                first_deltas.push(prev_delta);
+            }
+            for w in block.windows(2) {
+                let delta = w[1] - w[0];
+                residuals.push(match order {
+                    DeltaOrder::Delta => delta,
+                    DeltaOrder::DeltaOfDelta => zigzag((delta as i64).wrapping_sub(prev_delta as i64))
+                });
+                prev_delta = delta;
+            }
+        }
+        let residuals = Residuals::pack(&residuals)?;
+        Some(DeltaColumn { dtype, order, bases, first_deltas, residuals, skip: 0, len: keys.len() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn datatype(&self) -> Datatype {
+        self.dtype
+    }
+
+    pub fn order(&self) -> DeltaOrder {
+        self.order
+    }
+
+    // Heap bytes for the block bases, first deltas and residuals
+    pub fn memory_usage(&self) -> usize {
+        self.bases.memory_usage() + self.first_deltas.memory_usage() + self.residuals.memory_usage()
+    }
+
+    // Keys of every row in (physical) block `b`
+    fn decode_block(&self, b: usize) -> Vec<u64> {
+        let start = b * BLOCK;
+        let end = (start + BLOCK).min(self.skip + self.len);
+        let mut keys = Vec::with_capacity(end - start);
+        let mut key = self.bases[b];
+        let mut delta = if self.order == DeltaOrder::DeltaOfDelta { self.first_deltas[b] } else { 0 };
+        keys.push(key);
+        for i in start + 1 .. end {
+            let r = self.residuals.get(i);
+            delta = match self.order {
+                DeltaOrder::Delta => r,
+                DeltaOrder::DeltaOfDelta => (delta as i64).wrapping_add(unzigzag(r)) as u64
+            };
+            key = key.wrapping_add(delta);
+            keys.push(key);
+        }
+        keys
+    }
+
+    fn keys(&self) -> Vec<u64> {
+        let mut keys: Vec<u64> = (0 .. self.bases.len()).flat_map(|b| self.decode_block(b)).collect();
+        keys.drain(.. self.skip);
+        keys
+    }
+
+    fn to_column(&self, keys: Vec<u64>) -> Column {
+        match self.dtype {
+            Datatype::Num => Column::Num(NumColumn { data: keys.into_iter().map(key_num).collect() }),
+            _ => Column::Entity(EntityColumn { data: Buffer::from(keys) })
+        }
+    }
+
+    fn key_scalar(&self, key: u64) -> Scalar {
+        match self.dtype {
+            Datatype::Num => Scalar::Num(key_num(key)),
+            _ => Scalar::Entity(key)
+        }
+    }
+
+    // The value at `row`; decodes the block holding it
+    pub fn get(&self, row: usize) -> Option<Scalar> {
+        if row >= self.len {
+            return None;
+        }
+        let (b, i) = ((row + self.skip) / BLOCK, (row + self.skip) % BLOCK);
+        Some(self.key_scalar(self.decode_block(b)[i]))
+    }
+
+    // Back to the plain layout
+    pub fn decode(&self) -> Column {
+        self.to_column(self.keys())
+    }
+
+    // The selected rows, decoding each block they touch once
+    pub fn gather(&self, sel: &Selection) -> Column {
+        let mut keys = Vec::with_capacity(sel.count_ones());
+        let mut block: Option<(usize, Vec<u64>)> = None;
+        sel.for_each(|idx| {
+            let (b, i) = ((idx + self.skip) / BLOCK, (idx + self.skip) % BLOCK);
+            if block.as_ref().map(|(cur, _)| *cur) != Some(b) {
+                block = Some((b, self.decode_block(b)));
+            }
+            keys.push(block.as_ref().unwrap().1[i]);
+        });
+        self.to_column(keys)
+    }
+
+    // Rows offset .. offset + len, which must be in bounds. Shares the encoded buffers.
+    pub fn slice(&self, offset: usize, len: usize) -> DeltaColumn {
+        let start = self.skip + offset;
+        // an empty slice keeps no blocks, so it can't skip into one either
+        let (first, blocks, skip) = if len == 0 {
+            (0, 0, 0)
+        } else {
+            (start / BLOCK, (start + len - 1) / BLOCK + 1 - start / BLOCK, start % BLOCK)
+        };
+        DeltaColumn {
+            dtype: self.dtype,
+            order: self.order,
+            bases: self.bases.slice(first, blocks),
+            first_deltas: if self.order == DeltaOrder::DeltaOfDelta { self.first_deltas.slice(first, blocks) } else { Buffer::new() },
+            residuals: self.residuals.slice(first * BLOCK, skip + len),
+            skip,
+            len
+        }
+    }
+
+    // The first row whose key doesn't satisfy `before` - which must hold for a prefix of the
+    // column. Binary searches the block bases, then decodes a single block.
+    fn partition_point<F: Fn(u64) -> bool>(&self, before: F) -> usize {
+        let b = self.bases.partition_point(|k| before(*k));
+        let row = if b == 0 {
+            0
+        } else {
+            (b - 1) * BLOCK + self.decode_block(b - 1).partition_point(|k| before(*k))
+        };
+        row.max(self.skip).min(self.skip + self.len) - self.skip
+    }
+
+    fn mask_rows(&self, lo: usize, hi: usize) -> BoolColumn {
+        let mut bits = BitIndex::for_col_len(self.len);
+        bits.set_range(lo, hi);
+        BoolColumn::from_mask(bits)
+    }
+
+    pub fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        let key = match (self.dtype, &val) {
+            // -0.0 == 0.0, but only the latter has a key
+            (Datatype::Num, Scalar::Num(x)) => num_key(if *x == 0.0 { 0.0 } else { *x }),
+            (Datatype::Entity, Scalar::Entity(x)) => Some(*x),
+            (Datatype::Num, _) => return Err(VMError::TypeError(format!("Expected a numeric value, got: {:?}", val))),
+            _ => return Err(VMError::TypeError(format!("Expected an entity-id value, got: {:?}", val)))
+        };
+        match key {
+            Some(key) => Ok(self.mask_rows(self.partition_point(|k| k < key), self.partition_point(|k| k <= key))),
+            // not an integer, so it can't be in the column
+            None => Ok(self.mask_rows(0, 0))
+        }
+    }
+
+    // lo <= x < hi, as a single contiguous run of rows
+    pub fn filter_range(&self, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        let (lo, hi) = match (self.dtype, &lo, &hi) {
+            (Datatype::Num, Scalar::Num(lo), Scalar::Num(hi)) => {
+                if lo.is_nan() || hi.is_nan() {
+                    return Ok(self.mask_rows(0, 0));
+                }
+                (num_lower_key(*lo), num_lower_key(*hi))
+            },
+            (Datatype::Entity, Scalar::Entity(lo), Scalar::Entity(hi)) => (*lo, *hi),
+            _ => return Err(VMError::TypeError(format!("Expected {} bounds, got: {:?} and {:?}", self.dtype, lo, hi)))
+        };
+        let start = self.partition_point(|k| k < lo);
+        Ok(self.mask_rows(start, self.partition_point(|k| k < hi).max(start)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+    use crate::datagen::Rng;
+
+    // sorted, with repeats and gaps of up to `max_gap`, spanning several blocks
+    fn sorted_ids(n: usize, max_gap: u64, seed: u64) -> Vec<u64> {
+        let mut rng = Rng::new(seed);
+        let mut x = 1000;
+        (0 .. n).map(|_| { x += rng.below(max_gap + 1); x }).collect()
+    }
+
+    fn columns() -> Vec<Column> {
+        let ids = sorted_ids(700, 5, 1);
+        let nums: Vec<f64> = sorted_ids(700, 300, 2).iter().map(|x| *x as f64 - 50_000.0).collect();
+        let regular: Vec<f64> = (0 .. 700).map(|i| 1_600_000_000.0 + i as f64 * 1000.0).collect();
+        vec![Column::from(ids), Column::from(nums), Column::from(regular)]
+    }
+
+    fn probes(col: &Column) -> Vec<Scalar> {
+        let (lo, hi) = match col { Column::Entity(c) => (c.data[0] as f64, c.data[c.data.len() - 1] as f64),
+                                   Column::Num(c) => (c.data[0], c.data[c.data.len() - 1]), _ => unreachable!() };
+        let mut rng = Rng::new(3);
+        let mut xs: Vec<f64> = (0 .. 40).map(|_| (lo - 10.0 + rng.next_f64() * (hi - lo + 20.0)).floor()).collect();
+        xs.extend([lo, hi, lo - 1.0, hi + 1.0]);
+        xs.into_iter().map(|x| match col { Column::Entity(_) => Scalar::Entity(x.max(0.0) as u64), _ => Scalar::Num(x) }).collect()
+    }
+
+    #[test]
+    fn round_trips_in_either_order() {
+        for col in columns() {
+            for order in [DeltaOrder::Delta, DeltaOrder::DeltaOfDelta] {
+                let enc = DeltaColumn::encode_with(&col, order).unwrap();
+                assert_eq!((enc.len(), enc.order(), enc.datatype()), (col.len(), order, col.datatype()));
+                assert_eq!(enc.decode().fingerprint(), col.fingerprint());
+                assert!(enc.memory_usage() < col.memory_usage());
+            }
+        }
+        let empty = DeltaColumn::encode(&Column::from(Vec::<u64>::new())).unwrap();
+        assert!(empty.is_empty() && empty.decode().is_empty());
+    }
+
+    #[test]
+    fn picks_the_smaller_order() {
+        // evenly spaced: every delta-of-delta is 0, while deltas of 1000 need two bytes
+        let regular = DeltaColumn::encode(&columns()[2]).unwrap();
+        assert_eq!(regular.order(), DeltaOrder::DeltaOfDelta);
+        assert!(matches!(regular.residuals, Residuals::U8(_)));
+        // irregular gaps of 0..5 fit a byte as deltas, and deltas of deltas need the sign
+        let ids = DeltaColumn::encode(&columns()[0]).unwrap();
+        assert!(matches!(ids.residuals, Residuals::U8(_)));
+        let spaced: Vec<u64> = (0 .. 300).map(|i| i * 100_000).collect();
+        let spaced = DeltaColumn::encode(&Column::from(spaced)).unwrap();
+        assert_eq!(spaced.order(), DeltaOrder::DeltaOfDelta);
+        assert!(matches!(spaced.residuals, Residuals::U8(_)));
+        // wider gaps need wider residuals
+        let wide = DeltaColumn::encode_with(&Column::from(vec![0u64, 1 << 20, 1 << 21]), DeltaOrder::Delta).unwrap();
+        assert!(matches!(wide.residuals, Residuals::U32(_)));
+    }
+
+    #[test]
+    fn refuses_what_it_cant_encode() {
+        assert!(DeltaColumn::encode(&Column::from(vec![3u64, 2])).is_none());
+        assert!(DeltaColumn::encode(&Column::from(vec![1.0, 1.5])).is_none());
+        assert!(DeltaColumn::encode(&Column::from(vec![-0.0, 1.0])).is_none());
+        assert!(DeltaColumn::encode(&Column::from(vec![1.0, f64::NAN])).is_none());
+        assert!(DeltaColumn::encode(&Column::from(vec![0u64, 1 << 40, (1 << 41) + (1 << 35)])).is_none());
+        assert!(DeltaColumn::encode(&Column::from(vec!["a", "b"])).is_none());
+    }
+
+    #[test]
+    fn filters_like_the_plain_column() {
+        for col in columns() {
+            let enc = Column::Delta(DeltaColumn::encode(&col).unwrap());
+            for val in probes(&col) {
+                assert_eq!(enc.filter(val.clone()).unwrap().selection().positions(), col.filter(val.clone()).unwrap().selection().positions(), "{:?}", val);
+            }
+        }
+        let nums = Column::Delta(DeltaColumn::encode(&Column::from(vec![-1.0, 0.0, 0.0, 2.0])).unwrap());
+        assert_eq!(nums.filter(Scalar::Num(-0.0)).unwrap().selection().positions(), vec![1, 2]);
+        assert_eq!(nums.filter(Scalar::Num(0.5)).unwrap().selection().positions(), Vec::<usize>::new());
+        assert!(nums.filter(Scalar::Entity(0)).is_err());
+    }
+
+    #[test]
+    fn range_filters_like_the_plain_column() {
+        for col in columns() {
+            let enc = Column::Delta(DeltaColumn::encode(&col).unwrap());
+            let ps = probes(&col);
+            for (lo, hi) in ps.iter().zip(ps.iter().rev()) {
+                let expected = col.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions();
+                assert_eq!(enc.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions(), expected, "{:?}..{:?}", lo, hi);
+            }
+        }
+        let nums = Column::Delta(DeltaColumn::encode(&Column::from(vec![-3.0, -1.0, 0.0, 2.0, 5.0])).unwrap());
+        let range = |lo, hi| nums.filter_range(Scalar::Num(lo), Scalar::Num(hi)).unwrap().selection().positions();
+        assert_eq!(range(-1.5, 2.0), vec![1, 2]);
+        assert_eq!(range(-1.0, 2.5), vec![1, 2, 3]);
+        assert_eq!(range(f64::NEG_INFINITY, f64::INFINITY), vec![0, 1, 2, 3, 4]);
+        assert_eq!(range(f64::NAN, 5.0), Vec::<usize>::new());
+        assert!(nums.filter_range(Scalar::Entity(0), Scalar::Entity(1)).is_err());
+    }
+
+    #[test]
+    fn slices_and_gathers_like_the_plain_column() {
+        for col in columns() {
+            let enc = Column::Delta(DeltaColumn::encode(&col).unwrap());
+            for (offset, len) in [(0, 700), (0, 0), (127, 2), (100, 300), (129, 128), (699, 1)] {
+                let (slice, plain) = (enc.slice(offset, len), col.slice(offset, len));
+                assert_eq!(slice.fingerprint(), plain.fingerprint(), "{}..+{}", offset, len);
+                // and the slice still searches right, including slices of slices
+                for val in probes(&col).into_iter().take(10) {
+                    assert_eq!(slice.filter(val.clone()).unwrap().selection().positions(), plain.filter(val).unwrap().selection().positions());
+                }
+                assert_eq!(slice.slice(len / 2, len / 4).fingerprint(), plain.slice(len / 2, len / 4).fingerprint());
+            }
+            let mask = col.filter_range(probes(&col)[0].clone(), probes(&col)[1].clone()).unwrap();
+            let every_third = BoolColumn::from_selection(Selection::from_positions((0 .. 700).step_by(3).collect(), 700));
+            for m in [mask, every_third] {
+                assert_eq!(enc.select(&m).fingerprint(), col.select(&m).fingerprint());
+            }
+        }
+    }
+
+    #[test]
+    fn zigzag_round_trips() {
+        for n in [0, 1, -1, 2, -2, i64::MAX, i64::MIN] {
+            assert_eq!(unzigzag(zigzag(n)), n);
+        }
+        assert_eq!((zigzag(0), zigzag(-1), zigzag(1)), (0, 1, 2));
+    }
+}
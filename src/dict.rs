@@ -0,0 +1,357 @@
+// Dictionary-encoded strings: each distinct value is stored once, and rows hold u32 codes
+// into the dictionary. Dictionaries are behind an Arc so that every chunk of a table's
+// column can share one - then codes mean the same thing in every chunk, and concatenating
+// chunks, grouping or joining on the column can work on the codes alone. Chunks that were
+// built with their own dictionaries get their codes remapped onto a shared one by `unify`.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Column, Scalar};
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::kernels;
+use crate::selection::{FilterPlan, Selection};
+
+use crate::core::HashMap;
+use alloc::sync::Arc;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Dictionary {
+    values: Vec<String>,
+    codes: HashMap<String, u32>
+}
+
+impl Dictionary {
+    pub fn new() -> Self {
+        Dictionary::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // The code for `s`, adding it if it's new
+    pub fn intern(&mut self, s: &str) -> u32 {
+        if let Some(code) = self.codes.get(s) {
+            return *code;
+        }
+        let code = self.values.len() as u32;
+        self.values.push(s.to_string());
+        self.codes.insert(s.to_string(), code);
+        code
+    }
+
+    pub fn code_of(&self, s: &str) -> Option<u32> {
+        self.codes.get(s).copied()
+    }
+
+    pub fn value(&self, code: u32) -> &str {
+        &self.values[code as usize]
+    }
+
+    pub fn values(&self) -> &[String] {
+        &self.values
+    }
+
+    // Add the values of `other`, returning where each of its codes ended up
+    pub fn merge(&mut self, other: &Dictionary) -> Vec<u32> {
+        other.values.iter().map(|s| self.intern(s)).collect()
+    }
+
+    // Heap bytes: the strings are held twice, once in `values` and once as map keys
+    pub fn memory_usage(&self) -> usize {
+        let strings: usize = self.values.iter().map(|s| s.capacity()).sum();
+        self.values.capacity() * core::mem::size_of::<String>()
+            + self.codes.capacity() * (core::mem::size_of::<String>() + core::mem::size_of::<u32>())
+            + 2 * strings
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DictStrColumn {
+    dict: Arc<Dictionary>,
+    codes: Buffer<u32>
+}
+
+impl DictStrColumn {
+    // Encode with a dictionary of its own
+    pub fn from_strs<'a, I: IntoIterator<Item=&'a str>>(strs: I) -> Self {
+        let mut dict = Dictionary::new();
+        let codes = strs.into_iter().map(|s| dict.intern(s)).collect();
+        DictStrColumn { dict: Arc::new(dict), codes }
+    }
+
+    // Codes for `strs` in `dict`, which gets any values it doesn't have yet. Encode every chunk
+    // of a column this way, then wrap the codes up with_codes: the chunks share one dictionary
+    // from the start and never need remapping.
+    pub fn encode_into<'a, I: IntoIterator<Item=&'a str>>(strs: I, dict: &mut Dictionary) -> Buffer<u32> {
+        strs.into_iter().map(|s| dict.intern(s)).collect()
+    }
+
+    // `codes` must all be valid in `dict`
+    pub fn with_codes(dict: Arc<Dictionary>, codes: Buffer<u32>) -> Self {
+        assert!(codes.iter().all(|c| (*c as usize) < dict.len()), "code out of range for dictionary");
+        DictStrColumn { dict, codes }
+    }
+
+    // None for anything but string columns
+    pub fn encode(col: &Column) -> Option<Self> {
+        match col {
+            Column::Str(c) => Some(DictStrColumn::from_strs(c.data.iter().map(|s| s.as_str()))),
+            Column::InlineStr(c) => Some(DictStrColumn::from_strs(c.iter())),
+            Column::Dict(c) => Some(c.clone()),
+            _ => None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn dictionary(&self) -> &Arc<Dictionary> {
+        &self.dict
+    }
+
+    // Equal codes mean equal strings, within one dictionary
+    pub fn codes(&self) -> &[u32] {
+        &self.codes
+    }
+
+    pub fn shares_dictionary(&self, other: &DictStrColumn) -> bool {
+        Arc::ptr_eq(&self.dict, &other.dict)
+    }
+
+    pub fn value(&self, idx: usize) -> &str {
+        self.dict.value(self.codes[idx])
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Scalar> {
+        self.codes.get(idx).map(|c| Scalar::Str(self.dict.value(*c).to_string()))
+    }
+
+    // Codes plus the dictionary, which may well be shared with other columns
+    pub fn memory_usage(&self) -> usize {
+        self.codes.memory_usage() + self.dict.memory_usage()
+    }
+
+    // The same rows in terms of `shared`; None if `shared` lacks some of this column's values
+    pub fn remap(&self, shared: &Arc<Dictionary>) -> Option<DictStrColumn> {
+        if Arc::ptr_eq(&self.dict, shared) {
+            return Some(self.clone());
+        }
+        let table: Vec<u32> = self.dict.values.iter().map(|s| shared.code_of(s)).collect::<Option<_>>()?;
+        let codes = self.codes.iter().map(|c| table[*c as usize]).collect();
+        Some(DictStrColumn { dict: shared.clone(), codes })
+    }
+
+    // Re-express `chunks` over a single shared dictionary. The first chunk's dictionary is
+    // extended rather than rebuilt, so its codes (and those of any chunk already sharing it)
+    // are kept as they are; every other chunk gets its codes remapped.
+    pub fn unify(chunks: &[DictStrColumn]) -> Vec<DictStrColumn> {
+        let first = match chunks.first() {
+            Some(c) => c,
+            None => return Vec::new()
+        };
+        if chunks.iter().all(|c| c.shares_dictionary(first)) {
+            return chunks.to_vec();
+        }
+        let mut shared = (*first.dict).clone();
+        let tables: Vec<Option<Vec<u32>>> = chunks.iter()
+            .map(|c| if c.shares_dictionary(first) { None } else { Some(shared.merge(&c.dict)) })
+            .collect();
+        let shared = Arc::new(shared);
+        chunks.iter().zip(tables).map(|(c, table)| DictStrColumn {
+            dict: shared.clone(),
+            codes: match table {
+                None => c.codes.clone(),
+                Some(table) => c.codes.iter().map(|code| table[*code as usize]).collect()
+            }
+        }).collect()
+    }
+
+    // One column holding the rows of all `chunks`, over a shared dictionary
+    pub fn concat(chunks: &[DictStrColumn]) -> DictStrColumn {
+        let chunks = DictStrColumn::unify(chunks);
+        let dict = chunks.first().map(|c| c.dict.clone()).unwrap_or_default();
+        let mut codes = Buffer::with_capacity(chunks.iter().map(|c| c.len()).sum());
+        chunks.iter().for_each(|c| codes.extend_from_slice(&c.codes));
+        DictStrColumn { dict, codes }
+    }
+
+    // Back to a plain string column
+    pub fn decode(&self) -> Column {
+        Column::InlineStr(self.codes.iter().map(|c| self.dict.value(*c)).collect())
+    }
+
+    pub fn slice(&self, offset: usize, len: usize) -> DictStrColumn {
+        DictStrColumn { dict: self.dict.clone(), codes: self.codes.slice(offset, len) }
+    }
+
+    // Looks `val` up in the dictionary once, then compares codes
+    pub fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        self.filter_planned(val).map(|(mask, _)| mask)
+    }
+
+    // filter, and how it went about it (see kernels::filter_adaptive)
+    pub fn filter_planned(&self, val: Scalar) -> Result<(BoolColumn, FilterPlan), VMError> {
+        if let Scalar::Str(x) = val {
+            // a value that's not in the dictionary matches nothing; no code equals len()
+            let code = self.dict.code_of(&x).unwrap_or(self.dict.len() as u32);
+            let (sel, plan) = kernels::filter_adaptive(&self.codes, |codes| kernels::eq_u32(codes, code), |c| c == code);
+            Ok((BoolColumn::from_selection(sel), plan))
+        } else {
+            Err(VMError::TypeError(format!("Expected a string value, got: {:?}", val)))
+        }
+    }
+
+    // filter, over just the rows in `sel` (see Column::filter_at)
+    pub fn filter_at(&self, val: Scalar, sel: &Selection) -> Result<BoolColumn, VMError> {
+        if let Scalar::Str(x) = val {
+            let code = self.dict.code_of(&x).unwrap_or(self.dict.len() as u32);
+            let mut positions = BitIndex::for_col_len(sel.count_ones());
+            let mut i = 0;
+            sel.for_each(|idx| {
+                if self.codes[idx] == code { positions.set(i); }
+                i += 1;
+            });
+            Ok(BoolColumn::from_mask(positions))
+        } else {
+            Err(VMError::TypeError(format!("Expected a string value, got: {:?}", val)))
+        }
+    }
+
+    // The selected rows, over the same dictionary
+    pub fn select(&self, sel: &Selection) -> DictStrColumn {
+        let mut codes = Buffer::with_capacity(sel.count_ones());
+        sel.for_each(|idx| codes.push(self.codes[idx]));
+        DictStrColumn { dict: self.dict.clone(), codes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{ColumnT, StrColumn};
+
+    fn strs(col: &DictStrColumn) -> Vec<&str> {
+        (0 .. col.len()).map(|i| col.value(i)).collect()
+    }
+
+    const FRUIT: [&str; 8] = ["apple", "kiwi", "apple", "", "fig", "kiwi", "apple", "fig"];
+
+    #[test]
+    fn interns_each_value_once() {
+        let col = DictStrColumn::from_strs(FRUIT.iter().copied());
+        assert_eq!(col.dictionary().values(), &["apple", "kiwi", "", "fig"]);
+        assert_eq!(col.codes(), &[0, 1, 0, 2, 3, 1, 0, 3]);
+        assert_eq!(strs(&col), FRUIT);
+        assert_eq!(col.decode().fingerprint(), Column::from(FRUIT.to_vec()).fingerprint());
+
+        let mut dict = Dictionary::new();
+        assert_eq!((dict.intern("a"), dict.intern("b"), dict.intern("a")), (0, 1, 0));
+        assert_eq!((dict.code_of("b"), dict.code_of("c"), dict.len()), (Some(1), None, 2));
+    }
+
+    #[test]
+    fn encodes_string_columns_only() {
+        let boxed = Column::Str(StrColumn::new(FRUIT.iter().map(|s| s.to_string()).collect()));
+        for plain in [Column::from(FRUIT.to_vec()), boxed] {
+            assert_eq!(strs(&DictStrColumn::encode(&plain).unwrap()), FRUIT);
+        }
+        assert!(DictStrColumn::encode(&Column::from(vec![1.0])).is_none());
+    }
+
+    #[test]
+    fn filters_slices_and_selects_like_the_plain_column() {
+        let plain = Column::from(FRUIT.to_vec());
+        let col = Column::Dict(DictStrColumn::encode(&plain).unwrap());
+        for val in ["apple", "fig", "", "banana"] {
+            let val = Scalar::Str(val.to_string());
+            assert_eq!(col.filter(val.clone()).unwrap().selection().positions(), plain.filter(val).unwrap().selection().positions());
+        }
+        assert!(col.filter(Scalar::Num(1.0)).is_err());
+
+        assert_eq!(col.slice(2, 4).fingerprint(), plain.slice(2, 4).fingerprint());
+        let mask = plain.filter(Scalar::Str("kiwi".to_string())).unwrap();
+        let picked = col.select(&mask);
+        assert!(matches!(&picked, Column::Dict(d) if d.dictionary().len() == 4));
+        assert_eq!(picked.fingerprint(), Column::from(vec!["kiwi", "kiwi"]).fingerprint());
+    }
+
+    #[test]
+    fn chunks_built_on_one_dictionary_share_it() {
+        let mut dict = Dictionary::new();
+        let a = DictStrColumn::encode_into(FRUIT[.. 4].iter().copied(), &mut dict);
+        let b = DictStrColumn::encode_into(FRUIT[4 ..].iter().copied(), &mut dict);
+        let dict = Arc::new(dict);
+        let (a, b) = (DictStrColumn::with_codes(dict.clone(), a), DictStrColumn::with_codes(dict, b));
+        assert!(a.shares_dictionary(&b));
+        let unified = DictStrColumn::unify(&[a.clone(), b.clone()]);
+        assert!(unified[0].shares_dictionary(&a) && unified[1].codes() == b.codes());
+        assert_eq!(strs(&DictStrColumn::concat(&[a, b])), FRUIT);
+    }
+
+    #[test]
+    #[should_panic(expected = "code out of range")]
+    fn codes_must_fit_the_dictionary() {
+        DictStrColumn::with_codes(Arc::new(Dictionary::new()), Buffer::from(vec![0u32]));
+    }
+
+    #[test]
+    fn unifying_remaps_onto_the_first_dictionary() {
+        let a = DictStrColumn::from_strs(vec!["x", "y"]);
+        let b = DictStrColumn::from_strs(vec!["z", "y", "z"]);
+        let c = DictStrColumn::from_strs(vec!["y"]);
+        let unified = DictStrColumn::unify(&[a.clone(), b, c]);
+        assert!(unified.iter().all(|u| u.shares_dictionary(&unified[0])));
+        // the first chunk keeps its codes, and new values go after its own
+        assert_eq!(unified[0].codes(), a.codes());
+        assert_eq!(unified[0].dictionary().values(), &["x", "y", "z"]);
+        assert_eq!((unified[1].codes(), unified[2].codes()), (&[2, 1, 2][..], &[1][..]));
+        assert!(DictStrColumn::unify(&[]).is_empty());
+
+        let all = DictStrColumn::concat(&unified);
+        assert_eq!(strs(&all), vec!["x", "y", "z", "y", "z", "y"]);
+        assert!(DictStrColumn::concat(&[]).is_empty());
+    }
+
+    #[test]
+    fn remapping_needs_every_value() {
+        let shared = Arc::new(DictStrColumn::from_strs(vec!["a", "b", "c"]).dictionary().as_ref().clone());
+        let col = DictStrColumn::from_strs(vec!["c", "a"]);
+        let remapped = col.remap(&shared).unwrap();
+        assert_eq!((remapped.codes(), strs(&remapped)), (&[2, 0][..], vec!["c", "a"]));
+        assert!(DictStrColumn::from_strs(vec!["d"]).remap(&shared).is_none());
+        assert!(remapped.remap(&shared).unwrap().shares_dictionary(&remapped));
+    }
+
+    #[test]
+    fn filters_at_a_selection_like_the_gathered_rows() {
+        let plain = Column::from(FRUIT.to_vec());
+        let col = Column::Dict(DictStrColumn::encode(&plain).unwrap());
+        let sel = Selection::from_positions(vec![1, 2, 4, 6, 7], 8);
+        for val in ["apple", "fig", "kiwi", "banana"] {
+            let val = Scalar::Str(val.to_string());
+            let gathered = plain.select(&BoolColumn::from_selection(sel.clone()));
+            assert_eq!(col.filter_at(val.clone(), &sel).unwrap().selection().positions(), gathered.filter(val).unwrap().selection().positions());
+        }
+        assert!(col.filter_at(Scalar::Num(1.0), &sel).is_err());
+    }
+
+    #[test]
+    fn fused_filter_selects_keep_the_encoding() {
+        let plain = Column::from(FRUIT.to_vec());
+        let col = Column::Dict(DictStrColumn::encode(&plain).unwrap());
+        let picked = Column::from(vec![1.0, 2.0, 1.0, 1.0, 2.0, 2.0, 1.0, 1.0]).filter_select(Scalar::Num(2.0), &col).unwrap();
+        assert!(matches!(picked, Column::Dict(_)));
+        assert_eq!(picked.fingerprint(), Column::from(vec!["kiwi", "fig", "kiwi"]).fingerprint());
+    }
+}
@@ -0,0 +1,105 @@
+use core::fmt::Write;
+
+use crate::core::prelude::*;
+use crate::opcode::Op;
+use crate::schema::Schema;
+
+// Render a program as one line per instruction, labeled by its ip, e.g.
+//
+//   0000  COL        0 2        ; sex: Str
+//   0001  LIT        "f"
+//   0002  FILTER_EQ
+//
+// Column operands are resolved against `schema`, so the output can be read
+// (or asserted on in tests) without cross-referencing positional indices. It describes the
+// first table of the VM's catalog; columns of the others are left as their ids.
+pub fn disassemble(code: &[Op], schema: &Schema) -> String {
+    let mut out = String::new();
+    for (ip, op) in code.iter().enumerate() {
+        // writing to a String can't fail
+        writeln!(out, "{}", disassemble_op(ip, op, schema)).unwrap();
+    }
+    out
+}
+
+pub fn disassemble_op(ip: usize, op: &Op, schema: &Schema) -> String {
+    let operand = match op {
+        Op::Lit(s) => s.to_string(),
+        Op::ColByName(name) => name.clone(),
+        Op::Col(table, idx) => format!("{} {}", table, idx),
+        Op::GroupBy(idx) | Op::Shared(idx) => idx.to_string(),
+        Op::Select(n) | Op::Field(n) | Op::Limit(n) | Op::TopK(n) => n.to_string(),
+        Op::FilterInCidr(prefix) => format!("/{}", prefix),
+        Op::JsonExtract(path, dtype) => format!("{} {}", path, dtype),
+        Op::VectorDistance(metric) => metric.to_string(),
+        Op::Nearest(k, metric) => format!("{} {}", k, metric),
+        Op::ScalarSubquery(code) => format!("({} ops)", code.len()),
+        Op::CallUdf(id, arity) | Op::CallUdaf(id, arity) => format!("{} {}", id, arity),
+        _ => String::new()
+    };
+    let comment = match op {
+        Op::Col(0, idx) | Op::GroupBy(idx) => match schema.field(*idx) {
+            Some(field) => format!("; {}: {}", field.name, field.dtype),
+            None => "; <no such column>".to_string()
+        },
+        Op::ColByName(name) => match schema.index_of(name) {
+            Some(idx) => format!("; 0 {}: {}", idx, schema.fields[idx].dtype),
+            None => "; <no such column>".to_string()
+        },
+        _ => String::new()
+    };
+    let line = format!("{}  {:<10} {:<10} {}", label(ip), op.mnemonic(), operand, comment);
+    line.trim_end().to_string()
+}
+
+pub fn label(ip: usize) -> String {
+    format!("{:04}", ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Scalar;
+    use crate::schema::Datatype;
+
+    fn schema() -> Schema {
+        Schema::from(vec![("name", Datatype::Str), ("sex", Datatype::Str), ("age", Datatype::Num)])
+    }
+
+    #[test]
+    fn names_columns_from_the_schema() {
+        let code = vec![Op::Col(0, 1), Op::Lit(Scalar::Str("f".to_string())), Op::FilterEq, Op::Col(0, 2), Op::Select(1)];
+        let expected = "\
+0000  COL        0 1        ; sex: Str
+0001  LIT        \"f\"
+0002  FILTER_EQ
+0003  COL        0 2        ; age: Num
+0004  SELECT     1
+";
+        assert_eq!(disassemble(&code, &schema()), expected);
+    }
+
+    #[test]
+    fn marks_columns_the_schema_lacks() {
+        assert_eq!(disassemble_op(12, &Op::Col(0, 3), &schema()), "0012  COL        0 3        ; <no such column>");
+        assert_eq!(disassemble_op(0, &Op::Col(0, 0), &Schema::default()), "0000  COL        0 0        ; <no such column>");
+        assert_eq!(disassemble_op(2, &Op::Col(1, 0), &schema()), "0002  COL        1 0");
+        assert_eq!(disassemble_op(1, &Op::ColByName("age".to_string()), &schema()), "0001  COL_NAMED  age        ; 0 2: Num");
+        assert_eq!(disassemble_op(1, &Op::ColByName("height".to_string()), &schema()), "0001  COL_NAMED  height     ; <no such column>");
+    }
+
+    #[test]
+    fn formats_literals() {
+        let lit = |s| disassemble_op(0, &Op::Lit(s), &schema());
+        assert_eq!(lit(Scalar::Num(2.5)), "0000  LIT        2.5");
+        assert_eq!(lit(Scalar::Entity(7)), "0000  LIT        #7");
+        assert_eq!(lit(Scalar::Bool(true)), "0000  LIT        true");
+        assert_eq!(lit(Scalar::Record(vec![Scalar::Num(1.0), Scalar::Str("a".to_string())])), "0000  LIT        (1, \"a\")");
+    }
+
+    #[test]
+    fn renders_nothing_for_no_code() {
+        assert_eq!(disassemble(&[], &schema()), "");
+        assert_eq!(label(10_000), "10000");
+    }
+}
@@ -0,0 +1,259 @@
+// Durations: signed spans of time, counted in nanoseconds (Arrow's Duration(ns)), so they
+// cover about ±292 years exactly. Calendar-aware spans like "one month" aren't durations -
+// their length depends on where they start - and have no type here.
+//
+// A DurationColumn is a PrimitiveColumn, so it gets the generic equality and range filters;
+// this module adds the value type and its text form, which is a sequence of amounts with
+// units, largest first: "1h30m", "-2.5s", "250ms", "1d12h", "0s". Durations add to and
+// subtract from each other and timestamps (see Column::arith), and sum.
+
+use crate::bitindex::BitIndex;
+use crate::column::{Column, Scalar};
+use crate::core::prelude::*;
+use crate::kernels;
+use crate::primitive::{Native, PrimitiveColumn};
+use crate::schema::Datatype;
+use crate::timestamp::Micros;
+
+use core::cmp::Ordering;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Nanos(pub i64);
+
+pub type DurationColumn = PrimitiveColumn<Nanos>;
+
+pub const NANOS_PER_MICRO: i64 = 1_000;
+pub const NANOS_PER_MILLI: i64 = 1_000_000;
+pub const NANOS_PER_SEC: i64 = 1_000_000_000;
+pub const NANOS_PER_MIN: i64 = 60 * NANOS_PER_SEC;
+pub const NANOS_PER_HOUR: i64 = 60 * NANOS_PER_MIN;
+pub const NANOS_PER_DAY: i64 = 24 * NANOS_PER_HOUR;
+
+// Largest first, as written out
+const UNITS: [(&str, i64); 7] = [
+    ("d", NANOS_PER_DAY),
+    ("h", NANOS_PER_HOUR),
+    ("m", NANOS_PER_MIN),
+    ("s", NANOS_PER_SEC),
+    ("ms", NANOS_PER_MILLI),
+    ("us", NANOS_PER_MICRO),
+    ("ns", 1)
+];
+
+impl Nanos {
+    pub fn from_secs(secs: i64) -> Option<Nanos> {
+        secs.checked_mul(NANOS_PER_SEC).map(Nanos)
+    }
+
+    pub fn from_millis(millis: i64) -> Option<Nanos> {
+        millis.checked_mul(NANOS_PER_MILLI).map(Nanos)
+    }
+
+    pub fn checked_add(self, other: Nanos) -> Option<Nanos> {
+        self.0.checked_add(other.0).map(Nanos)
+    }
+
+    pub fn checked_sub(self, other: Nanos) -> Option<Nanos> {
+        self.0.checked_sub(other.0).map(Nanos)
+    }
+
+    pub fn checked_neg(self) -> Option<Nanos> {
+        self.0.checked_neg().map(Nanos)
+    }
+
+    // `ts` moved on by this span; parts of a microsecond are dropped, rounding toward the past
+    pub fn after(self, ts: Micros) -> Option<Micros> {
+        ts.0.checked_add(self.0.div_euclid(NANOS_PER_MICRO)).map(Micros)
+    }
+
+    // The span from `start` to `end`, negative if `end` comes first
+    pub fn between(start: Micros, end: Micros) -> Option<Nanos> {
+        end.0.checked_sub(start.0)?.checked_mul(NANOS_PER_MICRO).map(Nanos)
+    }
+
+    // From text: amounts with units (see above), each amount a whole or decimal number, or a
+    // bare integer count of nanoseconds. None if it's malformed or out of range.
+    pub fn parse(s: &str) -> Option<Nanos> {
+        let s = s.trim();
+        let (negative, mut rest) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s)
+        };
+        if !rest.is_empty() && rest.bytes().all(|b| b.is_ascii_digit()) {
+            return s.parse().ok().map(Nanos);
+        }
+        if rest.is_empty() {
+            return None;
+        }
+        let mut total: i64 = 0;
+        while !rest.is_empty() {
+            let amount_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+            let (amount, tail) = rest.split_at(amount_len);
+            let unit_len = tail.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(tail.len());
+            let (unit, tail) = tail.split_at(unit_len);
+            let scale = UNITS.iter().find(|(u, _)| *u == unit)?.1;
+            total = total.checked_add(scaled(amount, scale)?)?;
+            rest = tail;
+        }
+        Some(Nanos(if negative { -total } else { total }))
+    }
+}
+
+// `amount` (digits, maybe with a decimal point) of a unit `scale` nanoseconds long, exactly;
+// digits past the nanosecond are dropped
+fn scaled(amount: &str, scale: i64) -> Option<i64> {
+    let (whole, frac) = match amount.split_once('.') {
+        Some((whole, frac)) => (whole, frac),
+        None => (amount, "")
+    };
+    if whole.is_empty() && frac.is_empty() || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let whole: i64 = if whole.is_empty() { 0 } else { whole.parse().ok()? };
+    let mut nanos = whole.checked_mul(scale)?;
+    let mut place = scale;
+    for digit in frac.bytes() {
+        place /= 10;
+        nanos = nanos.checked_add(place * (digit - b'0') as i64)?;
+    }
+    Some(nanos)
+}
+
+impl fmt::Display for Nanos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0s");
+        }
+        if self.0 < 0 {
+            write!(f, "-")?;
+        }
+        let mut rest = self.0.unsigned_abs();
+        // whole days, hours and minutes, then what's left in the largest unit that fits it,
+        // with a fraction if need be
+        for (unit, scale) in &UNITS[.. 3] {
+            let n = rest / *scale as u64;
+            if n > 0 {
+                write!(f, "{}{}", n, unit)?;
+                rest %= *scale as u64;
+            }
+        }
+        if rest == 0 {
+            return Ok(());
+        }
+        let (unit, scale) = UNITS[3 ..].iter().find(|(_, scale)| rest >= *scale as u64).expect("ns is 1");
+        let (whole, frac) = (rest / *scale as u64, rest % *scale as u64);
+        if frac == 0 {
+            return write!(f, "{}{}", whole, unit);
+        }
+        let digits = scale.ilog10() as usize;
+        let frac = format!("{:0width$}", frac, width = digits);
+        write!(f, "{}.{}{}", whole, frac.trim_end_matches('0'), unit)
+    }
+}
+
+impl Native for Nanos {
+    const DATATYPE: Datatype = Datatype::Duration;
+    const DESCRIPTION: &'static str = "a duration";
+
+    fn from_scalar(s: &Scalar) -> Option<Nanos> {
+        if let Scalar::Duration(x) = s { Some(*x) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Duration(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<Nanos>) -> Column {
+        Column::Duration(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<Nanos>> {
+        if let Column::Duration(c) = col { Some(c) } else { None }
+    }
+
+    fn eq_mask(data: &[Nanos], val: Nanos) -> BitIndex {
+        kernels::mask_by(data, |x| x == val)
+    }
+
+    fn same(a: Nanos, b: Nanos) -> bool {
+        a == b
+    }
+
+    fn total_cmp(a: Nanos, b: Nanos) -> Ordering {
+        a.cmp(&b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+    use crate::nulls::NullSemantics;
+
+    #[test]
+    fn parses_amounts_with_units() {
+        let cases = [
+            ("1h30m", 90 * NANOS_PER_MIN),
+            ("-2.5s", -2_500 * NANOS_PER_MILLI),
+            ("250ms", 250 * NANOS_PER_MILLI),
+            ("1d12h", 36 * NANOS_PER_HOUR),
+            (" 0s ", 0),
+            (".5us", 500),
+            ("1m1ms1ns", NANOS_PER_MIN + NANOS_PER_MILLI + 1),
+            ("1.0000000019s", NANOS_PER_SEC + 1),     // past the nanosecond is dropped
+            ("42", 42),
+            ("-42", -42)
+        ];
+        for (text, nanos) in cases.iter() {
+            assert_eq!(Nanos::parse(text), Some(Nanos(*nanos)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_and_out_of_range_text() {
+        for text in ["", "-", "s", "1x", "1.5", "1..5s", "1h 30m", "--1s", "1s-", "107000d", "9223372036854775808"].iter() {
+            assert_eq!(Nanos::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn prints_what_parses_back() {
+        let cases = [
+            (0, "0s"),
+            (90 * NANOS_PER_MIN, "1h30m"),
+            (-2_500 * NANOS_PER_MILLI, "-2.5s"),
+            (NANOS_PER_DAY + 1, "1d1ns"),
+            (NANOS_PER_SEC + 1, "1.000000001s"),
+            (1_500, "1.5us")
+        ];
+        for (nanos, text) in cases.iter() {
+            assert_eq!(Nanos(*nanos).to_string(), *text);
+        }
+        for nanos in [i64::MIN + 1, i64::MAX, 123_456_789_012, -7].iter() {
+            assert_eq!(Nanos::parse(&Nanos(*nanos).to_string()), Some(Nanos(*nanos)), "{}", nanos);
+        }
+    }
+
+    #[test]
+    fn arithmetic_is_checked() {
+        assert_eq!(Nanos::from_secs(2).unwrap().checked_sub(Nanos::from_millis(500).unwrap()), Some(Nanos(1_500 * NANOS_PER_MILLI)));
+        assert_eq!(Nanos(i64::MAX).checked_add(Nanos(1)), None);
+        assert_eq!(Nanos::from_secs(i64::MAX / 1000), None);
+    }
+
+    #[test]
+    fn columns_filter_by_value_and_range() {
+        let col = Column::from(vec![Nanos(-5), Nanos(0), Nanos(5), Nanos(10)]);
+        assert_eq!(col.datatype(), Datatype::Duration);
+        let mask = col.filter_range(Scalar::Duration(Nanos(0)), Scalar::Duration(Nanos(10))).unwrap();
+        assert_eq!(mask.count_ones(), 2);
+        let mask = col.filter(Scalar::Duration(Nanos(5))).unwrap();
+        assert_eq!(mask.count_ones(), 1);
+        assert!(col.filter(Scalar::Num(5.0)).is_err());
+        let found = col.filter_in(&Column::from(vec![Nanos(10), Nanos(-5), Nanos(7)]), NullSemantics::default()).unwrap();
+        assert_eq!(found.count_ones(), 2);
+        assert_eq!(col.get(0), Some(Scalar::Duration(Nanos(-5))));
+    }
+}
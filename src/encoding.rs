@@ -0,0 +1,335 @@
+// Picking a physical encoding for a column as it's loaded. A single pass gathers the
+// statistics that decide it - run count, number of distinct strings, the bit width of the
+// value range - and the encoding with the smallest estimated footprint wins, provided it at
+// least halves the plain layout. A schema Field can pin the encoding instead.
+
+use crate::bitpack::{self, PackedColumn};
+use crate::column::Column;
+use crate::core::fract;
+use crate::core::prelude::*;
+use crate::delta::DeltaColumn;
+use crate::dict::DictStrColumn;
+use crate::errors::VMError;
+use crate::frame_of_ref::ForColumn;
+use crate::nulls::NullableColumn;
+use crate::rle::{self, RleColumn};
+use crate::schema::Schema;
+
+use alloc::borrow::Cow;
+use crate::core::HashSet;
+
+// Below this many rows, nothing is worth the bother
+const MIN_ROWS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Encoding {
+    Auto,
+    Plain,
+    Dict,
+    Rle,
+    Packed,
+    Delta,
+    For
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ColumnStats {
+    pub rows: usize,
+    pub runs: Option<usize>,            // Num, Entity and Bool
+    pub distinct: Option<usize>,        // Str; None once it passes half the rows
+    pub packed_width: Option<u32>       // bits per value for bit-packing, if it's possible at all
+}
+
+fn str_values(col: &Column) -> Option<Vec<&[u8]>> {
+    match col {
+        Column::Str(c) => Some(c.data.iter().map(|s| s.as_bytes()).collect()),
+        Column::InlineStr(c) => Some(c.offsets.windows(2).map(|w| &c.data[w[0] .. w[1]]).collect()),
+        _ => None
+    }
+}
+
+fn distinct_up_to(values: &[&[u8]], limit: usize) -> Option<usize> {
+    let mut seen = HashSet::new();
+    for v in values {
+        seen.insert(*v);
+        if seen.len() > limit {
+            return None;
+        }
+    }
+    Some(seen.len())
+}
+
+fn packed_width(col: &Column) -> Option<u32> {
+    let (min, max) = match col {
+        Column::Num(c) => {
+            if c.data.iter().any(|x| fract(*x) != 0.0 || x.abs() > (1u64 << 53) as f64) {
+                return None;
+            }
+            let (min, max) = c.data.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), x| (lo.min(*x), hi.max(*x)));
+            (min as i64 as u64, max as i64 as u64)
+        },
+        Column::Entity(c) => (c.data.iter().copied().min()?, c.data.iter().copied().max()?),
+        _ => return None
+    };
+    Some(bitpack::width_for(max.wrapping_sub(min))).filter(|w| *w <= bitpack::MAX_WIDTH)
+}
+
+pub fn analyze(col: &Column) -> ColumnStats {
+    let rows = col.len();
+    ColumnStats {
+        rows,
+        runs: rle::run_count(col),
+        distinct: str_values(col).and_then(|values| distinct_up_to(&values, rows / 2)),
+        packed_width: packed_width(col)
+    }
+}
+
+fn is_plain(col: &Column) -> bool {
+    matches!(col, Column::Bool(_) | Column::Num(_) | Column::Int(_) | Column::Str(_) | Column::Entity(_) | Column::Duration(_) | Column::Point(_) | Column::Ipv4(_) | Column::Ipv6(_) | Column::InlineStr(_) | Column::Json(_) | Column::Categorical(_))
+}
+
+// The encoding `encode(col, Encoding::Auto)` would use
+pub fn choose(col: &Column) -> Encoding {
+    if col.len() < MIN_ROWS || !is_plain(col) {
+        return Encoding::Plain;
+    }
+    let stats = analyze(col);
+    if stats.distinct.is_some() {
+        return Encoding::Dict;
+    }
+    let (value_bytes, plain_bytes) = match col {
+        Column::Bool(_) => (1, stats.rows / 8),
+        Column::Num(_) | Column::Entity(_) => (8, stats.rows * 8),
+        _ => return Encoding::Plain
+    };
+    let candidates = [
+        (Encoding::Rle, stats.runs.map(|runs| runs * (value_bytes + core::mem::size_of::<usize>()))),
+        (Encoding::Packed, stats.packed_width.map(|w| w as usize * stats.rows / 8))
+    ];
+    candidates.iter()
+        .filter_map(|(enc, bytes)| bytes.map(|b| (*enc, b)))
+        .filter(|(_, bytes)| bytes * 2 <= plain_bytes)
+        .min_by_key(|(_, bytes)| *bytes)
+        .map(|(enc, _)| enc)
+        .unwrap_or(Encoding::Plain)
+}
+
+// Back to the plain layout
+pub fn decode(col: Column) -> Column {
+    match col {
+        Column::Rle(c) => c.decode(),
+        Column::Delta(c) => c.decode(),
+        Column::Packed(c) => c.decode(),
+        Column::For(c) => c.decode(),
+        Column::Dict(c) => c.decode(),
+        Column::Chunked(c) => decode(c.decode()),
+        Column::Nullable(c) => match plain(c.values()) {
+            Cow::Borrowed(_) => Column::Nullable(c),
+            Cow::Owned(values) => Column::Nullable(NullableColumn::new(values, c.validity().clone()).expect("decoding keeps the rows"))
+        },
+        plain => plain
+    }
+}
+
+// The plain layout of `col`, borrowed if it already is plain
+pub fn plain(col: &Column) -> Cow<'_, Column> {
+    match col {
+        Column::Rle(c) => Cow::Owned(c.decode()),
+        Column::Delta(c) => Cow::Owned(c.decode()),
+        Column::Packed(c) => Cow::Owned(c.decode()),
+        Column::For(c) => Cow::Owned(c.decode()),
+        Column::Dict(c) => Cow::Owned(c.decode()),
+        Column::Chunked(c) => Cow::Owned(decode(c.decode())),
+        Column::Nullable(c) => match plain(c.values()) {
+            Cow::Borrowed(_) => Cow::Borrowed(col),
+            Cow::Owned(values) => Cow::Owned(Column::Nullable(NullableColumn::new(values, c.validity().clone()).expect("decoding keeps the rows")))
+        },
+        plain => Cow::Borrowed(plain)
+    }
+}
+
+// Store `col` as `encoding`. Auto leaves encoded columns alone and picks an encoding for
+// plain ones; anything else decodes first if need be, and fails if `col` can't be stored
+// that way (say, Rle for strings, or Packed for a column with a very wide range).
+pub fn encode(col: Column, encoding: Encoding) -> Result<Column, VMError> {
+    let encoding = match encoding {
+        Encoding::Auto if !is_plain(&col) => return Ok(col),
+        Encoding::Auto => choose(&col),
+        other => other
+    };
+    let col = decode(col);
+    let encoded = match encoding {
+        Encoding::Auto | Encoding::Plain => return Ok(col),
+        Encoding::Dict => DictStrColumn::encode(&col).map(Column::Dict),
+        Encoding::Rle => RleColumn::encode(&col).map(Column::Rle),
+        Encoding::Packed => PackedColumn::encode(&col).map(Column::Packed),
+        Encoding::Delta => DeltaColumn::encode(&col).map(Column::Delta),
+        Encoding::For => ForColumn::encode(&col).map(Column::For)
+    };
+    encoded.ok_or_else(|| VMError::TypeError(format!("Can't store this {} column as {:?}", col.datatype(), encoding)))
+}
+
+// encode(col, Encoding::Auto), which can't fail
+pub fn auto(col: Column) -> Column {
+    encode(col, Encoding::Auto).expect("an automatically chosen encoding always applies")
+}
+
+// Cut `col` into chunks of `chunk_len` rows, and encode each one separately - with Auto,
+// each chunk gets whatever suits its own contents.
+pub fn ingest(col: &Column, chunk_len: usize, encoding: Encoding) -> Result<Vec<Column>, VMError> {
+    col.chunks(chunk_len).into_iter().map(|chunk| encode(decode(chunk), encoding)).collect()
+}
+
+// Encode each column as its schema field says
+pub fn apply(schema: &Schema, columns: Vec<Column>) -> Result<Vec<Column>, VMError> {
+    if schema.len() != columns.len() {
+        return Err(VMError::LengthMismatch { expected: schema.len(), found: columns.len() });
+    }
+    schema.fields.iter().zip(columns).map(|(field, col)| encode(col, field.encoding)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{ColumnT, Scalar};
+    use crate::datagen::{self, Rng};
+    use crate::schema::{Datatype, Field};
+
+    fn mask(values: Vec<bool>) -> Column {
+        let nums: Vec<f64> = values.iter().map(|b| *b as u8 as f64).collect();
+        Column::Bool(Column::from(nums).filter(Scalar::Num(1.0)).unwrap())
+    }
+
+    fn wide(i: u64) -> u64 {
+        // too wide a range to bit-pack
+        i * (1 << 40)
+    }
+
+    #[test]
+    fn chooses_by_estimated_size() {
+        let n = 1000u64;
+        let cases = vec![
+            // too short to bother
+            (Column::from(vec![1.0; 63]), Encoding::Plain),
+            // few distinct strings, or too many
+            (datagen::strings(1000, 20, 1), Encoding::Dict),
+            (datagen::strings(1000, 1000, 1), Encoding::Plain),
+            // a constant packs into 0 bits, which beats even a single run
+            (Column::from(vec![5.0; 100]), Encoding::Packed),
+            // small domain, no runs
+            (datagen::uniform_num(1000, 0.0, 8.0, 1).slice(0, 1000), Encoding::Plain),
+            (Column::from((0 .. n).map(|i| (i * 7) % 8).collect::<Vec<_>>()), Encoding::Packed),
+            // wide values: runs of 4 take 16 bytes against 32 - exactly half - and runs of 3 don't
+            (Column::from((0 .. n).map(|i| wide(i / 4)).collect::<Vec<_>>()), Encoding::Rle),
+            (Column::from((0 .. n).map(|i| wide(i / 3)).collect::<Vec<_>>()), Encoding::Plain),
+            // short runs of a small domain: packing beats runs
+            (Column::from((0 .. n).map(|i| (i / 4 % 4) as f64).collect::<Vec<_>>()), Encoding::Packed),
+            // bools: long runs, or not
+            (mask((0 .. 1000).map(|i| i < 500).collect()), Encoding::Rle),
+            (mask((0 .. 1000).map(|i| i % 2 == 0).collect()), Encoding::Plain),
+        ];
+        for (i, (col, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(choose(&col), expected, "case {}", i);
+        }
+        // already encoded
+        assert_eq!(choose(&auto(Column::from(vec![5.0; 100]))), Encoding::Plain);
+    }
+
+    #[test]
+    fn analyzes_in_one_pass() {
+        let stats = analyze(&Column::from(vec![1u64, 1, 2, 9]));
+        assert_eq!(stats, ColumnStats { rows: 4, runs: Some(3), distinct: None, packed_width: Some(4) });
+        let stats = analyze(&Column::from(vec!["a", "b", "a", "a"]));
+        assert_eq!((stats.runs, stats.distinct, stats.packed_width), (None, Some(2), None));
+        assert_eq!(analyze(&Column::from(vec!["a", "b", "c", "a"])).distinct, None);
+        assert_eq!(analyze(&Column::from(vec![0.5, 1.0])).packed_width, None);
+        assert_eq!(analyze(&Column::from(vec![-3.0, 4.0])).packed_width, Some(3));
+    }
+
+    #[test]
+    fn auto_encoding_keeps_the_data() {
+        let mut rng = Rng::new(5);
+        let columns = vec![
+            datagen::strings(500, 10, 2),
+            datagen::zipf_num(500, 5, 1.0, 3),
+            Column::from((0 .. 500u64).map(|i| wide(i / 10)).collect::<Vec<_>>()),
+            Column::from((0 .. 500).map(|_| rng.next_f64()).collect::<Vec<_>>()),
+            mask((0 .. 500).map(|i| i % 100 < 50).collect()),
+        ];
+        for col in columns {
+            let fp = col.fingerprint();
+            let enc = auto(col);
+            assert_eq!(enc.fingerprint(), fp, "{}", enc.kind());
+            assert_eq!(decode(enc).fingerprint(), fp);
+        }
+    }
+
+    #[test]
+    fn explicit_encodings_apply_or_fail() {
+        let sorted = Column::from((0 .. 300u64).map(|i| 1000 + i * 3).collect::<Vec<_>>());
+        for (encoding, kind) in [(Encoding::Delta, "Delta"), (Encoding::For, "For"), (Encoding::Packed, "Packed"),
+                                 (Encoding::Rle, "Rle"), (Encoding::Plain, "Entity")] {
+            let enc = encode(auto(Column::from((0 .. 300u64).map(|i| 1000 + i * 3).collect::<Vec<_>>())), encoding).unwrap();
+            assert_eq!((enc.kind(), enc.fingerprint()), (kind, sorted.fingerprint()));
+        }
+        assert!(matches!(encode(Column::from(vec!["a"]), Encoding::Rle), Err(VMError::TypeError(_))));
+        assert!(matches!(encode(Column::from(vec![1.0]), Encoding::Dict), Err(VMError::TypeError(_))));
+        assert!(encode(Column::from(vec![3u64, 1]), Encoding::Delta).is_err());
+        // Auto doesn't touch columns that are already encoded
+        let rle = encode(Column::from(vec![1.0; 100]), Encoding::Rle).unwrap();
+        assert_eq!(encode(rle, Encoding::Auto).unwrap().kind(), "Rle");
+    }
+
+    #[test]
+    fn ingested_chunks_are_encoded_separately() {
+        let mut values = vec![7.0; 100];
+        values.extend((0 .. 100).map(|i| i as f64 * 0.5));
+        let chunks = ingest(&Column::from(values), 100, Encoding::Auto).unwrap();
+        assert_eq!(chunks.iter().map(|c| c.kind()).collect::<Vec<_>>(), vec!["Packed", "Num"]);
+        assert!(ingest(&Column::from(vec![0.5; 10]), 5, Encoding::Packed).is_err());
+    }
+
+    #[test]
+    fn schemas_pick_each_fields_encoding() {
+        let schema = Schema::new(vec![
+            Field::new("id", Datatype::Entity).with_encoding(Encoding::Plain),
+            Field::new("city", Datatype::Str),
+            Field::new("t", Datatype::Entity).with_encoding(Encoding::Delta),
+        ]);
+        let columns = || vec![
+            Column::from(vec![1u64; 100]),
+            datagen::strings(100, 3, 1),
+            Column::from((0 .. 100u64).collect::<Vec<_>>()),
+        ];
+        let encoded = apply(&schema, columns()).unwrap();
+        assert_eq!(encoded.iter().map(|c| c.kind()).collect::<Vec<_>>(), vec!["Entity", "Dict", "Delta"]);
+        assert!(matches!(apply(&schema, columns().into_iter().take(2).collect()), Err(VMError::LengthMismatch { expected: 3, found: 2 })));
+        let strict = Schema::new(vec![Field::new("x", Datatype::Num).with_encoding(Encoding::Dict)]);
+        assert!(apply(&strict, vec![Column::from(vec![1.0])]).is_err());
+    }
+
+    #[test]
+    fn encoded_cells_read_like_plain_ones() {
+        let keys: Vec<u64> = (0 .. 3000u64).map(|i| 1000 + i / 3 * 7).collect();
+        let plain = Column::from(keys.clone());
+        let nums = Column::from(keys.iter().map(|k| *k as f64).collect::<Vec<_>>());
+        let strs = datagen::strings(3000, 10, 2);
+        let mut encoded = vec![];
+        for encoding in [Encoding::Rle, Encoding::Packed, Encoding::Delta, Encoding::For].iter().copied() {
+            encoded.push((plain.clone(), encode(plain.clone(), encoding).unwrap()));
+            encoded.push((nums.clone(), encode(nums.clone(), encoding).unwrap()));
+        }
+        encoded.push((strs.clone(), encode(strs, Encoding::Dict).unwrap()));
+        for (plain, enc) in encoded {
+            // across block and chunk boundaries, and in a slice that starts mid-block
+            let sliced = (plain.slice(1500, 1000), enc.slice(1500, 1000));
+            for (plain, enc) in &[(plain, enc), sliced] {
+                for idx in [0, 1, 2, 127, 128, 129, 999, plain.len() - 1].iter().copied() {
+                    assert_eq!(enc.get(idx), plain.get(idx), "{} at {}", enc.kind(), idx);
+                }
+                assert_eq!(enc.get(plain.len()), None);
+            }
+        }
+    }
+}
@@ -1,5 +1,24 @@
+#[cfg(feature = "std")]
+use std::io;
+
+use crate::core::prelude::*;
+use crate::memory::Consumer;
+
 #[derive(Debug)]
 pub enum VMError {
     TypeError(String),
-    IllegalOpcode
+    LengthMismatch { expected: usize, found: usize },
+    ColumnIndexOutOfRange { idx: usize, ncols: usize },
+    UnknownColumn(String),  // an Op::ColByName naming no loaded column
+    TableIndexOutOfRange { idx: usize, ntables: usize },
+    RowIndexOutOfRange { idx: usize, nrows: usize },    // a row number past the end, e.g. in TableWriter::delete
+    UnknownFunction(usize),
+    UnknownShared(usize),   // an Op::Shared for a sub-plan that hasn't been computed (see batch.rs)
+    #[cfg(feature = "std")]
+    Io(io::Error),      // e.g. spilling to disk
+    Cancelled,
+    TimedOut,
+    OutOfMemory { consumer: Consumer, requested: usize, available: usize },   // see memory.rs
+    StackUnderflow { ip: usize, pops: usize, depth: usize },  // an op needing more values than the stack would hold (see verify.rs)
+    Overflow    // integer arithmetic past the range of an Int
 }
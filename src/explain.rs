@@ -0,0 +1,157 @@
+use core::fmt::Write;
+use core::time::Duration;
+
+use crate::core::prelude::*;
+use crate::disasm::{disassemble, disassemble_op};
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::schema::Schema;
+use crate::selection::FilterPlan;
+use crate::vm::VM;
+
+// What one instruction did during a profiled run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpProfile {
+    pub ip: usize,
+    pub rows_in: usize,     // rows of the longest column it popped
+    pub rows_out: usize,    // rows of the column it pushed (set bits, for a mask), 0 for a scalar
+    pub elapsed: Duration,
+    pub plan: Option<FilterPlan>   // for a filter, how it found its rows
+}
+
+impl OpProfile {
+    // Fraction of input rows that made it to the output, for ops that consume columns
+    pub fn selectivity(&self) -> Option<f64> {
+        if self.rows_in == 0 { return None; }
+        Some(self.rows_out as f64 / self.rows_in as f64)
+    }
+}
+
+pub fn explain(code: &[Op], schema: &Schema) -> String {
+    disassemble(code, schema)
+}
+
+// Run `code` and return its disassembly with each instruction annotated with
+// the rows it consumed and produced, its selectivity and elapsed time, and for a
+// filter, the plan it settled on.
+pub fn explain_analyze(vm: &mut VM, code: Vec<Op>, schema: &Schema) -> Result<String, VMError> {
+    vm.enable_profiling();
+    let res = vm.run(code.clone());
+    let profile = vm.take_profile().unwrap_or_default();
+    res?;
+    Ok(annotate(&code, schema, &profile))
+}
+
+pub fn annotate(code: &[Op], schema: &Schema, profile: &[OpProfile]) -> String {
+    let lines: Vec<String> = code.iter().enumerate().map(|(ip, op)| disassemble_op(ip, op, schema)).collect();
+    let width = lines.iter().map(|l| l.len()).max().unwrap_or(0);
+
+    let mut out = String::new();
+    for (ip, line) in lines.iter().enumerate() {
+        // writing to a String can't fail
+        match profile.iter().find(|p| p.ip == ip) {
+            Some(p) => {
+                let sel = match p.selectivity() {
+                    Some(s) => format!("{:.1}%", s * 100.0),
+                    None => "-".to_string()
+                };
+                let plan = p.plan.map(|plan| format!(" plan={}", plan)).unwrap_or_default();
+                writeln!(out, "{:<width$}  [in={} out={} sel={} time={:?}{}]",
+                         line, p.rows_in, p.rows_out, sel, p.elapsed, plan, width = width).unwrap();
+            }
+            None => writeln!(out, "{:<width$}  [not executed]", line, width = width).unwrap()
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::Table;
+    use crate::column::{Column, Scalar};
+    use crate::schema::Datatype;
+
+    fn vm() -> VM {
+        VM::new(Table::from_columns(vec![Column::from(vec!["f", "m", "f", "m"]), Column::from(vec![30.0, 41.0, 25.0, 60.0])]).unwrap())
+    }
+
+    fn schema() -> Schema {
+        Schema::from(vec![("sex", Datatype::Str), ("age", Datatype::Num)])
+    }
+
+    fn program() -> Vec<Op> {
+        vec![Op::Col(0, 0), Op::Lit(Scalar::Str("f".to_string())), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]
+    }
+
+    // the bracketed annotation of each line, without the time
+    fn annotations(text: &str) -> Vec<String> {
+        text.lines().map(|l| {
+            let note = &l[l.find('[').unwrap() ..];
+            match note.find(" time=") {
+                Some(i) => format!("{}]", &note[.. i]),
+                None => note.to_string()
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn counts_rows_through_each_instruction() {
+        let mut vm = vm();
+        vm.enable_profiling();
+        vm.run(program()).unwrap();
+        let profile = vm.take_profile().unwrap();
+        let rows: Vec<(usize, usize, usize)> = profile.iter().map(|p| (p.ip, p.rows_in, p.rows_out)).collect();
+        assert_eq!(rows, vec![(0, 0, 4), (1, 0, 0), (2, 4, 2), (3, 0, 4), (4, 4, 2)]);
+        assert_eq!(profile[2].selectivity(), Some(0.5));
+        assert_eq!(profile[0].selectivity(), None);
+        assert!(vm.take_profile().is_none());
+    }
+
+    #[test]
+    fn annotates_the_disassembly() {
+        let text = explain_analyze(&mut vm(), program(), &schema()).unwrap();
+        assert_eq!(annotations(&text), vec![
+            "[in=0 out=4 sel=-]", "[in=0 out=0 sel=-]", "[in=4 out=2 sel=50.0%]", "[in=0 out=4 sel=-]", "[in=4 out=2 sel=50.0%]"
+        ]);
+        assert!(text.lines().next().unwrap().starts_with("0000  COL        0 0        ; sex: Str"));
+        // the annotations line up
+        let column: Vec<usize> = text.lines().map(|l| l.find('[').unwrap()).collect();
+        assert!(column.windows(2).all(|w| w[0] == w[1]), "{}", text);
+    }
+
+    #[test]
+    fn marks_instructions_that_didnt_run() {
+        let profile = vec![OpProfile { ip: 0, rows_in: 0, rows_out: 4, elapsed: Duration::from_micros(3), plan: None }];
+        let text = annotate(&program(), &schema(), &profile);
+        let notes = annotations(&text);
+        assert_eq!(notes[0], "[in=0 out=4 sel=-]");
+        assert!(notes[1 ..].iter().all(|n| n == "[not executed]"));
+        assert!(text.lines().next().unwrap().ends_with("time=3µs]"));
+    }
+
+    #[test]
+    fn reports_the_plan_of_each_filter() {
+        // VM::new encodes the ids, so they're scanned whole rather than sampled
+        let ids = Column::from((0 .. 20_000u64).map(|i| i % 100).collect::<Vec<_>>());
+        let sexes = Column::from((0 .. 20_000).map(|i| if i % 2 == 0 { "f" } else { "m" }).collect::<Vec<_>>());
+        let mut vm = VM::new(Table::from_columns(vec![ids, sexes]).unwrap());
+        let schema = Schema::from(vec![("id", Datatype::Entity), ("sex", Datatype::Str)]);
+        let code = vec![
+            Op::Col(0, 0), Op::Lit(Scalar::Entity(7)), Op::FilterEq,
+            Op::Col(0, 1), Op::Lit(Scalar::Str("f".to_string())), Op::FilterEq
+        ];
+        let text = explain_analyze(&mut vm, code, &schema).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[2].ends_with(" plan=scan to positions (1.0% of 20000 sampled)]"), "{}", lines[2]);
+        assert!(lines[5].ends_with(" plan=scan to bitmap (50.0% of 8192 sampled)]"), "{}", lines[5]);
+        assert!(!lines[0].contains("plan="));
+    }
+
+    #[test]
+    fn fails_with_the_program() {
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterEq];
+        assert!(explain_analyze(&mut vm(), code, &schema()).is_err());
+        assert_eq!(explain(&program(), &schema()), disassemble(&program(), &schema()));
+    }
+}
@@ -0,0 +1,278 @@
+// Frame-of-reference encoding: the column is cut into chunks, and each chunk stores its
+// minimum once plus bit-packed offsets from it. Values that cluster within a chunk (ids
+// loaded in batches, slowly drifting measurements) pack into a few bits each even when the
+// column as a whole spans a wide range. Each chunk's min and max double as a zone map:
+// filters skip chunks that can't match, and accept whole chunks that must, without
+// unpacking anything.
+
+use crate::bitindex::BitIndex;
+use crate::bitpack::{self, BitPacked};
+use crate::column::{BoolColumn, Column, EntityColumn, NumColumn, Scalar};
+use crate::core::prelude::*;
+use crate::delta::{key_num, num_key, num_lower_key};
+use crate::errors::VMError;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+
+const CHUNK: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    min: u64,           // keys, as in delta.rs; `min` is also the frame of reference
+    max: u64,
+    codes: BitPacked
+}
+
+#[derive(Debug, Clone)]
+pub struct ForColumn {
+    dtype: Datatype,
+    chunks: Vec<Chunk>,
+    ends: Vec<usize>    // row after the last of each chunk
+}
+
+impl ForColumn {
+    // None unless the column is Num (integers only) or Entity, and every chunk's range
+    // fits in bitpack::MAX_WIDTH bits
+    pub fn encode(col: &Column) -> Option<ForColumn> {
+        let (dtype, keys): (Datatype, Vec<u64>) = match col {
+            Column::Num(c) => (Datatype::Num, c.data.iter().map(|x| num_key(*x)).collect::<Option<_>>()?),
+            Column::Entity(c) => (Datatype::Entity, c.data.to_vec()),
+            _ => return None
+        };
+        let mut chunks = Vec::with_capacity(keys.len() / CHUNK + 1);
+        let mut ends = Vec::with_capacity(keys.len() / CHUNK + 1);
+        for (i, chunk) in keys.chunks(CHUNK).enumerate() {
+            let (min, max) = (*chunk.iter().min().unwrap(), *chunk.iter().max().unwrap());
+            let width = bitpack::width_for(max - min);
+            if width > bitpack::MAX_WIDTH {
+                return None;
+            }
+            let codes: Vec<u64> = chunk.iter().map(|k| k - min).collect();
+            chunks.push(Chunk { min, max, codes: BitPacked::pack(&codes, width) });
+            ends.push(i * CHUNK + chunk.len());
+        }
+        Some(ForColumn { dtype, chunks, ends })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn datatype(&self) -> Datatype {
+        self.dtype
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.chunks.capacity() * core::mem::size_of::<Chunk>()
+            + self.ends.capacity() * core::mem::size_of::<usize>()
+            + self.chunks.iter().map(|c| c.codes.memory_usage()).sum::<usize>()
+    }
+
+    fn chunk_start(&self, i: usize) -> usize {
+        if i == 0 { 0 } else { self.ends[i - 1] }
+    }
+
+    fn to_column(&self, keys: Vec<u64>) -> Column {
+        match self.dtype {
+            Datatype::Num => Column::Num(NumColumn { data: keys.into_iter().map(key_num).collect() }),
+            _ => Column::Entity(EntityColumn { data: keys.into_iter().collect() })
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Scalar> {
+        if idx >= self.len() {
+            return None;
+        }
+        let chunk = self.ends.partition_point(|end| *end <= idx);
+        let c = &self.chunks[chunk];
+        let key = c.min + c.codes.get(idx - self.chunk_start(chunk));
+        Some(match self.dtype {
+            Datatype::Num => Scalar::Num(key_num(key)),
+            _ => Scalar::Entity(key)
+        })
+    }
+
+    // Back to the plain layout
+    pub fn decode(&self) -> Column {
+        let keys = self.chunks.iter()
+            .flat_map(|c| c.codes.unpack().into_iter().map(move |code| c.min + code))
+            .collect();
+        self.to_column(keys)
+    }
+
+    // The selected rows, as a plain column
+    pub fn gather(&self, sel: &Selection) -> Column {
+        let mut keys = Vec::with_capacity(sel.count_ones());
+        let mut chunk = 0;
+        sel.for_each(|idx| {
+            while self.ends[chunk] <= idx {
+                chunk += 1;
+            }
+            let c = &self.chunks[chunk];
+            keys.push(c.min + c.codes.get(idx - self.chunk_start(chunk)));
+        });
+        self.to_column(keys)
+    }
+
+    // Rows offset .. offset + len, which must be in bounds. Shares the packed codes; the
+    // zone maps of the end chunks are kept as they are, which is conservative but still correct.
+    pub fn slice(&self, offset: usize, len: usize) -> ForColumn {
+        let mut chunks = Vec::new();
+        let mut ends = Vec::new();
+        for (i, c) in self.chunks.iter().enumerate() {
+            let (start, end) = (self.chunk_start(i), self.ends[i]);
+            let (lo, hi) = (start.max(offset), end.min(offset + len));
+            if lo < hi {
+                chunks.push(Chunk { min: c.min, max: c.max, codes: c.codes.slice(lo - start, hi - lo) });
+                ends.push(hi - offset);
+            }
+        }
+        ForColumn { dtype: self.dtype, chunks, ends }
+    }
+
+    // Rows with lo <= key <= hi. Chunks the zone map puts entirely outside the range are
+    // skipped, ones entirely inside it are set wholesale; only the rest get unpacked.
+    fn mask_keys(&self, lo: u64, hi: u64) -> BoolColumn {
+        let mut bits = BitIndex::for_col_len(self.len());
+        if lo > hi {
+            return BoolColumn::from_mask(bits);
+        }
+        for (i, c) in self.chunks.iter().enumerate() {
+            let start = self.chunk_start(i);
+            if c.max < lo || c.min > hi {
+                continue;
+            }
+            if lo <= c.min && c.max <= hi {
+                bits.set_range(start, self.ends[i]);
+                continue;
+            }
+            let (lo, hi) = (lo.saturating_sub(c.min), hi - c.min);
+            bits.or_at(start, &c.codes.mask_by(|code| lo <= code && code <= hi));
+        }
+        BoolColumn::from_mask(bits)
+    }
+
+    pub fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        let key = match (self.dtype, &val) {
+            // -0.0 == 0.0, but only the latter has a key
+            (Datatype::Num, Scalar::Num(x)) => num_key(if *x == 0.0 { 0.0 } else { *x }),
+            (Datatype::Entity, Scalar::Entity(x)) => Some(*x),
+            (Datatype::Num, _) => return Err(VMError::TypeError(format!("Expected a numeric value, got: {:?}", val))),
+            _ => return Err(VMError::TypeError(format!("Expected an entity-id value, got: {:?}", val)))
+        };
+        Ok(match key {
+            Some(key) => self.mask_keys(key, key),
+            None => BoolColumn::from_mask(BitIndex::for_col_len(self.len()))
+        })
+    }
+
+    // lo <= x < hi
+    pub fn filter_range(&self, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        let (lo, hi) = match (self.dtype, &lo, &hi) {
+            (Datatype::Num, Scalar::Num(lo), Scalar::Num(hi)) => {
+                if lo.is_nan() || hi.is_nan() {
+                    return Ok(BoolColumn::from_mask(BitIndex::for_col_len(self.len())));
+                }
+                (num_lower_key(*lo), num_lower_key(*hi))
+            },
+            (Datatype::Entity, Scalar::Entity(lo), Scalar::Entity(hi)) => (*lo, *hi),
+            _ => return Err(VMError::TypeError(format!("Expected {} bounds, got: {:?} and {:?}", self.dtype, lo, hi)))
+        };
+        if hi == 0 {
+            return Ok(BoolColumn::from_mask(BitIndex::for_col_len(self.len())));
+        }
+        Ok(self.mask_keys(lo, hi - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+    use crate::datagen::Rng;
+
+    // batches of ids that sit close together, with the batches far apart
+    fn clustered(n: usize, seed: u64) -> Vec<u64> {
+        let mut rng = Rng::new(seed);
+        (0 .. n).map(|i| (i / CHUNK) as u64 * 1_000_000 + rng.below(500)).collect()
+    }
+
+    fn columns() -> Vec<Column> {
+        let nums: Vec<f64> = clustered(2500, 2).iter().map(|x| *x as f64 - 1_500_000.0).collect();
+        vec![Column::from(clustered(2500, 1)), Column::from(nums)]
+    }
+
+    fn probes(dtype: Datatype) -> Vec<Scalar> {
+        let xs = [0u64, 17, 499, 500, 1_000_000, 1_000_250, 1_999_999, 2_000_100, 5_000_000];
+        match dtype {
+            Datatype::Num => xs.iter().map(|x| Scalar::Num(*x as f64 - 1_500_000.0)).chain(vec![Scalar::Num(0.5), Scalar::Num(f64::NAN)]).collect(),
+            _ => xs.iter().copied().map(Scalar::Entity).collect()
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for col in columns() {
+            let enc = ForColumn::encode(&col).unwrap();
+            assert_eq!((enc.len(), enc.chunk_count(), enc.datatype()), (2500, 3, col.datatype()));
+            assert_eq!(enc.decode().fingerprint(), col.fingerprint());
+            // 9 bits a row instead of 64
+            assert!(enc.memory_usage() * 4 < col.memory_usage());
+        }
+        let empty = ForColumn::encode(&Column::from(Vec::<u64>::new())).unwrap();
+        assert!(empty.is_empty() && empty.decode().is_empty());
+    }
+
+    #[test]
+    fn refuses_what_it_cant_encode() {
+        assert!(ForColumn::encode(&Column::from(vec![0u64, 1 << 33])).is_none());
+        assert!(ForColumn::encode(&Column::from(vec![0.5])).is_none());
+        assert!(ForColumn::encode(&Column::from(vec!["a"])).is_none());
+        // wide overall is fine, as long as each chunk is narrow
+        let mut wide = vec![0u64; CHUNK];
+        wide.extend(vec![1 << 50; CHUNK]);
+        assert!(ForColumn::encode(&Column::from(wide)).is_some());
+    }
+
+    #[test]
+    fn filters_like_the_plain_column() {
+        for col in columns() {
+            let enc = Column::For(ForColumn::encode(&col).unwrap());
+            let ps = probes(col.datatype());
+            for val in &ps {
+                assert_eq!(enc.filter(val.clone()).unwrap().selection().positions(), col.filter(val.clone()).unwrap().selection().positions(), "{:?}", val);
+            }
+            for lo in &ps {
+                for hi in &ps {
+                    let expected = col.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions();
+                    assert_eq!(enc.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions(), expected, "{:?}..{:?}", lo, hi);
+                }
+            }
+            assert!(enc.filter(Scalar::Str("x".to_string())).is_err());
+        }
+    }
+
+    #[test]
+    fn slices_and_gathers_like_the_plain_column() {
+        for col in columns() {
+            let enc = Column::For(ForColumn::encode(&col).unwrap());
+            for (offset, len) in [(0, 2500), (1000, 100), (1020, 10), (5, 0), (2047, 2), (2499, 1)] {
+                let (slice, plain) = (enc.slice(offset, len), col.slice(offset, len));
+                assert_eq!(slice.fingerprint(), plain.fingerprint(), "{}..+{}", offset, len);
+                for val in probes(col.datatype()) {
+                    assert_eq!(slice.filter(val.clone()).unwrap().selection().positions(), plain.filter(val).unwrap().selection().positions());
+                }
+            }
+            let every_seventh = BoolColumn::from_selection(Selection::from_positions((0 .. 2500).step_by(7).collect(), 2500));
+            assert_eq!(enc.select(&every_seventh).fingerprint(), col.select(&every_seventh).fingerprint());
+        }
+    }
+}
@@ -0,0 +1,220 @@
+// Gap filling for time series, as monitoring data wants: rows are put in fixed-width time
+// buckets, every bucket from the first row's to the last row's gets a row of output, and the
+// buckets no row fell in are filled in from their neighbours. The times are a Timestamp
+// column, a Duration column (offsets from some epoch) or an Entity column of ticks, sorted
+// ascending; the interval is in the same units - microseconds, for timestamps. A bucket more than one row fell in takes the last of them.
+//
+// Filling with null makes a Nullable column, of any type, and only numbers can be
+// interpolated; the filled rows come back as a mask too, for filling other columns some
+// other way (see conditional::fill_missing). There's a row per bucket, so a span of more than MAX_BUCKETS
+// intervals is turned away rather than allocated.
+
+use crate::bitindex::BitIndex;
+use crate::column::Column;
+use crate::conditional;
+use crate::core::prelude::*;
+use crate::duration::Nanos;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::nulls::NullableColumn;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+use crate::timestamp::Micros;
+
+use core::convert::TryFrom;
+
+// The most buckets gap_fill will make
+pub const MAX_BUCKETS: usize = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Interpolation {
+    #[default]
+    Null,       // null, making a Nullable column
+    Previous,   // the value of the nearest bucket before that has one
+    Linear      // on the line between the nearest present buckets either side, in Num columns
+}
+
+#[derive(Debug, Clone)]
+pub struct GapFilled {
+    pub time: Column,           // the start of each bucket, of the same type as the times given
+    pub values: Vec<Column>,    // the value columns, a row per bucket
+    pub filled: Selection       // the buckets no row fell in
+}
+
+// The buckets of `interval` ticks from the first of `time` to the last, with `values` (a row
+// per row of `time` each) lined up with them and their gaps filled by `method`
+pub fn gap_fill(time: &Column, values: &[&Column], interval: u64, method: Interpolation) -> Result<GapFilled, VMError> {
+    if interval == 0 {
+        return Err(VMError::TypeError("A gap-filling interval must be positive".to_string()));
+    }
+    if let Some(c) = values.iter().find(|c| c.len() != time.len()) {
+        return Err(VMError::LengthMismatch { expected: time.len(), found: c.len() });
+    }
+    let ticks = ticks(time)?;
+    if let Some(i) = (1 .. ticks.len()).find(|i| ticks[*i] < ticks[i - 1]) {
+        return Err(VMError::TypeError(format!("Times must be sorted to fill gaps, but row {} comes before row {}", i, i - 1)));
+    }
+    let interval = interval as i128;
+    let bucket = |t: i128| t.div_euclid(interval);
+    let (first, last) = match (ticks.first(), ticks.last()) {
+        (Some(first), Some(last)) => (bucket(*first), bucket(*last)),
+        _ => return Ok(GapFilled {
+            time: time.slice(0, 0),
+            values: values.iter().map(|c| c.slice(0, 0)).collect(),
+            filled: Selection::from_positions(vec![], 0)
+        })
+    };
+    let n = usize::try_from(last - first + 1).ok().filter(|n| *n <= MAX_BUCKETS).ok_or_else(|| {
+        VMError::TypeError(format!("Filling gaps would make {} buckets, more than the {} allowed; try a wider interval", last - first + 1, MAX_BUCKETS))
+    })?;
+
+    // the row each bucket takes its value from, if any row fell in it
+    let mut source: Vec<Option<usize>> = vec![None; n];
+    ticks.iter().enumerate().for_each(|(row, t)| source[(bucket(*t) - first) as usize] = Some(row));
+    let starts = (first ..= last).map(|b| b * interval);
+    let time = match time.datatype() {
+        Datatype::Duration => Column::from(starts.map(|t| Nanos(t as i64)).collect::<Vec<Nanos>>()),
+        Datatype::Timestamp => Column::from(starts.map(|t| Micros(t as i64)).collect::<Vec<Micros>>()),
+        _ => Column::from(starts.map(|t| t as u64).collect::<Vec<u64>>())
+    };
+    let filled: Vec<u32> = (0 .. n).filter(|b| source[*b].is_none()).map(|b| b as u32).collect();
+    let values = values.iter().map(|c| fill(c, &source, method)).collect::<Result<_, _>>()?;
+    Ok(GapFilled { time, values, filled: Selection::from_positions(filled, n) })
+}
+
+// Times as plain integers, wide enough for either type
+fn ticks(time: &Column) -> Result<Vec<i128>, VMError> {
+    match encoding::plain(time).as_ref() {
+        Column::Duration(c) => Ok(c.values().iter().map(|t| t.0 as i128).collect()),
+        Column::Timestamp(c) => Ok(c.values().iter().map(|t| t.0 as i128).collect()),
+        Column::Entity(c) => Ok(c.values().iter().map(|t| *t as i128).collect()),
+        other => Err(VMError::TypeError(format!("Expected Timestamp, Duration or Entity times, found a {} column", other.datatype())))
+    }
+}
+
+// One column's values in bucket order. The first and last buckets always have a row, so
+// every gap has a present bucket either side of it.
+fn fill(col: &Column, source: &[Option<usize>], method: Interpolation) -> Result<Column, VMError> {
+    if method == Interpolation::Null {
+        // any row's value will do under a null; the first always has one
+        let rows: Vec<usize> = source.iter().map(|s| s.unwrap_or(0)).collect();
+        let mut present = BitIndex::for_col_len(source.len());
+        source.iter().enumerate().filter(|(_, s)| s.is_some()).for_each(|(b, _)| present.set(b));
+        let col = match conditional::take(col, &rows)? {
+            Column::Nullable(c) => NullableColumn::new(c.values().clone(), c.validity().and(&present))?,
+            col => NullableColumn::new(col, present)?
+        };
+        return Ok(Column::Nullable(col));
+    }
+    if method == Interpolation::Previous {
+        let mut prev = 0;
+        let previous: Vec<usize> = source.iter().map(|s| {
+            if let Some(row) = s { prev = *row; }
+            prev
+        }).collect();
+        return conditional::take(col, &previous);
+    }
+    let plain = encoding::plain(col);
+    let xs = match plain.as_ref() {
+        Column::Num(c) => c.values(),
+        Column::Nullable(_) => return Err(VMError::TypeError("Can't interpolate across nulls; fill a column with them with Previous or Null".to_string())),
+        other => return Err(VMError::TypeError(format!("Can only interpolate a Num column, not a {} column", other.datatype())))
+    };
+    // the next present bucket after each, found from the back
+    let mut next = vec![0; source.len()];
+    let mut after = source.len() - 1;
+    for b in (0 .. source.len()).rev() {
+        if source[b].is_some() { after = b; }
+        next[b] = after;
+    }
+    let mut before = 0;
+    let data: Vec<f64> = (0 .. source.len()).map(|b| match source[b] {
+        Some(row) => { before = b; xs[row] },
+        None => {
+            let (lo, hi) = (xs[source[before].unwrap()], xs[source[next[b]].unwrap()]);
+            lo + (hi - lo) * (b - before) as f64 / (next[b] - before) as f64
+        }
+    }).collect();
+    Ok(Column::from(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Scalar;
+    use crate::timestamp::{MICROS_PER_HOUR, MICROS_PER_MIN};
+
+    // readings at t = 0, 10, 40 and 45 (in the bucket at 40, so it wins), a gap at 20 and 30
+    fn series() -> (Column, Column) {
+        (Column::from(vec![0u64, 10, 40, 45]), Column::from(vec![1.0, 2.0, 5.0, 8.0]))
+    }
+
+    #[test]
+    fn buckets_every_interval_from_first_to_last() {
+        let (time, x) = series();
+        let res = gap_fill(&time, &[&x], 10, Interpolation::Previous).unwrap();
+        assert_eq!(res.time, Column::from(vec![0u64, 10, 20, 30, 40]));
+        assert_eq!(res.values[0], Column::from(vec![1.0, 2.0, 2.0, 2.0, 8.0]));
+        assert_eq!(res.filled.positions(), vec![2, 3]);
+    }
+
+    #[test]
+    fn fills_with_nulls_or_a_line() {
+        let (time, x) = series();
+        let nulls = gap_fill(&time, &[&x], 10, Interpolation::Null).unwrap();
+        let vals: Vec<Scalar> = (0 .. 5).map(|i| nulls.values[0].get(i).unwrap()).collect();
+        assert_eq!(vals, vec![Scalar::Num(1.0), Scalar::Num(2.0), Scalar::Null, Scalar::Null, Scalar::Num(8.0)]);
+        let line = gap_fill(&time, &[&x], 10, Interpolation::Linear).unwrap();
+        assert_eq!(line.values[0], Column::from(vec![1.0, 2.0, 4.0, 6.0, 8.0]));
+    }
+
+    #[test]
+    fn durations_bucket_below_zero_too() {
+        let time = Column::from(vec![Nanos(-15), Nanos(12)]);
+        let names = Column::from(vec!["a", "b"]);
+        let res = gap_fill(&time, &[&names], 10, Interpolation::Previous).unwrap();
+        assert_eq!(res.time, Column::from(vec![Nanos(-20), Nanos(-10), Nanos(0), Nanos(10)]));
+        assert_eq!(res.values[0], Column::from(vec!["a", "a", "a", "b"]));
+        // strings can be null, but can't be interpolated
+        let res = gap_fill(&time, &[&names], 10, Interpolation::Null).unwrap();
+        let vals: Vec<Scalar> = (0 .. 4).map(|i| res.values[0].get(i).unwrap()).collect();
+        assert_eq!(vals, vec![Scalar::Str("a".to_string()), Scalar::Null, Scalar::Null, Scalar::Str("b".to_string())]);
+        assert!(matches!(gap_fill(&time, &[&names], 10, Interpolation::Linear), Err(VMError::TypeError(_))));
+        // nulls in the input stay null
+        let gappy = Column::Nullable(NullableColumn::with_nulls(Column::from(vec![1.0, 2.0]), &Selection::from_positions(vec![0], 2)).unwrap());
+        let res = gap_fill(&time, &[&gappy], 10, Interpolation::Null).unwrap();
+        assert_eq!(res.values[0].get(0), Some(Scalar::Null));
+        assert_eq!(res.values[0].get(3), Some(Scalar::Num(2.0)));
+        assert!(matches!(gap_fill(&time, &[&gappy], 10, Interpolation::Linear), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn timestamps_bucket_by_microseconds() {
+        let at = |h: i64, m: i64| Micros(Micros::parse("2024-03-01").unwrap().0 + h * MICROS_PER_HOUR + m * MICROS_PER_MIN);
+        let time = Column::from(vec![at(9, 5), at(11, 59)]);
+        let res = gap_fill(&time, &[&Column::from(vec![1.0, 3.0])], MICROS_PER_HOUR as u64, Interpolation::Linear).unwrap();
+        assert_eq!(res.time, Column::from(vec![at(9, 0), at(10, 0), at(11, 0)]));
+        assert_eq!(res.values[0], Column::from(vec![1.0, 2.0, 3.0]));
+    }
+
+    #[test]
+    fn times_must_be_sorted_and_line_up() {
+        let (time, x) = series();
+        let unsorted = Column::from(vec![10u64, 0, 40, 45]);
+        assert!(matches!(gap_fill(&unsorted, &[&x], 10, Interpolation::Null), Err(VMError::TypeError(_))));
+        assert!(matches!(gap_fill(&time, &[&x], 0, Interpolation::Null), Err(VMError::TypeError(_))));
+        let short = Column::from(vec![1.0]);
+        assert!(matches!(gap_fill(&time, &[&short], 10, Interpolation::Null), Err(VMError::LengthMismatch { expected: 4, found: 1 })));
+        let empty = gap_fill(&Column::from(Vec::<u64>::new()), &[], 10, Interpolation::Null).unwrap();
+        assert!(empty.time.is_empty() && empty.filled.is_empty());
+    }
+
+    #[test]
+    fn spans_too_long_to_fill_are_turned_away() {
+        let x = Column::from(vec![1.0, 2.0]);
+        let far = Column::from(vec![0u64, MAX_BUCKETS as u64 * 10]);
+        assert!(matches!(gap_fill(&far, &[&x], 10, Interpolation::Null), Err(VMError::TypeError(_))));
+        let ends = Column::from(vec![Nanos(i64::MIN), Nanos(i64::MAX)]);
+        assert!(matches!(gap_fill(&ends, &[&x], 1, Interpolation::Previous), Err(VMError::TypeError(_))));
+    }
+}
@@ -0,0 +1,188 @@
+// Points on a plane: an x/y pair, in whatever coordinates the data uses (longitude/latitude
+// is fine for pre-filtering, though distances are then in degrees). A PointColumn stores the
+// pairs interleaved, as a PrimitiveColumn, which is the layout both kernels here want since
+// they read x and y of a row together. It gets equality filtering, slicing, gathering and
+// the rest from the generic kernels; this module adds the spatial ones.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, NumColumn, Scalar};
+use crate::compare::cmp_f64;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::kernels;
+use crate::primitive::{Native, PrimitiveColumn};
+use crate::schema::Datatype;
+
+use core::cmp::Ordering;
+use core::fmt;
+
+// Ordered by x, then y
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Point {
+    pub x: f64,
+    pub y: f64
+}
+
+pub type PointColumn = PrimitiveColumn<Point>;
+
+impl Point {
+    pub fn new(x: f64, y: f64) -> Self {
+        Point { x, y }
+    }
+
+    pub fn distance(self, other: Point) -> f64 {
+        libm::hypot(self.x - other.x, self.y - other.y)
+    }
+
+    // From text: "x y" or "x,y", optionally in parentheses, as written out by Display
+    pub fn parse(s: &str) -> Option<Point> {
+        let s = s.trim();
+        let s = s.strip_prefix('(').and_then(|s| s.strip_suffix(')')).unwrap_or(s);
+        let mut coords = s.split(|c: char| c == ',' || c.is_whitespace()).filter(|c| !c.is_empty());
+        let x = coords.next()?.parse().ok()?;
+        let y = coords.next()?.parse().ok()?;
+        match coords.next() {
+            None => Some(Point { x, y }),
+            Some(_) => None
+        }
+    }
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+impl Native for Point {
+    const DATATYPE: Datatype = Datatype::Point;
+    const DESCRIPTION: &'static str = "a point";
+
+    fn from_scalar(s: &Scalar) -> Option<Point> {
+        if let Scalar::Point(p) = s { Some(*p) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Point(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<Point>) -> Column {
+        Column::Point(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<Point>> {
+        if let Column::Point(c) = col { Some(c) } else { None }
+    }
+
+    fn same(a: Point, b: Point) -> bool {
+        crate::column::num_eq(a.x, b.x) && crate::column::num_eq(a.y, b.y)
+    }
+
+    fn total_cmp(a: Point, b: Point) -> Ordering {
+        cmp_f64(a.x, b.x).then(cmp_f64(a.y, b.y))
+    }
+}
+
+impl PrimitiveColumn<Point> {
+    // Rows inside the box with corners `min` and `max`, edges included. A box with min above
+    // max on either axis is empty.
+    pub fn within_bbox(&self, min: Point, max: Point) -> BoolColumn {
+        let mask: BitIndex = kernels::mask_by(&self.data, |p| min.x <= p.x && p.x <= max.x && min.y <= p.y && p.y <= max.y);
+        BoolColumn::from_mask(mask)
+    }
+
+    // Euclidean distance from each row to `to`
+    pub fn distance_to(&self, to: Point) -> NumColumn {
+        NumColumn::new(self.data.iter().map(|p| p.distance(to)).collect())
+    }
+}
+
+// A point operand of FilterWithinBBox or DistanceTo
+pub(crate) fn expect_point(s: &Scalar) -> Result<Point, VMError> {
+    Point::from_scalar(s).ok_or_else(|| VMError::TypeError(format!("Expected {}, got: {:?}", Point::DESCRIPTION, s)))
+}
+
+// The point column an op works on
+pub(crate) fn expect_points(col: &Column) -> Result<&PointColumn, VMError> {
+    Point::unwrap(col).ok_or_else(|| VMError::TypeError(format!("Expected a Point column, found a {} column", col.datatype())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+    use crate::nulls::NullSemantics;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    fn points() -> Column {
+        Column::from(vec![Point::new(0.0, 0.0), Point::new(1.0, 2.0), Point::new(3.0, 4.0), Point::new(f64::NAN, 1.0)])
+    }
+
+    #[test]
+    fn parses_what_it_prints() {
+        for text in ["(1, 2)", "1 2", "1,2", " ( -1.5 , 2e3 ) "].iter() {
+            assert!(Point::parse(text).is_some(), "{}", text);
+        }
+        assert_eq!(Point::parse("(1, 2)"), Some(Point::new(1.0, 2.0)));
+        for text in ["", "1", "(1, 2, 3)", "(1, y)", "(1 2"].iter() {
+            assert_eq!(Point::parse(text), None, "{}", text);
+        }
+        let p = Point::new(-0.5, 1e-9);
+        assert_eq!(Point::parse(&p.to_string()), Some(p));
+    }
+
+    #[test]
+    fn bounding_boxes_include_their_edges() {
+        let col = expect_points(&points()).unwrap().clone();
+        let inside = |min, max| (0 .. 4).filter(|&i| col.within_bbox(min, max).selection().contains(i)).collect::<Vec<_>>();
+        assert_eq!(inside(Point::new(0.0, 0.0), Point::new(1.0, 2.0)), vec![0, 1]);
+        assert_eq!(inside(Point::new(-9.0, -9.0), Point::new(9.0, 9.0)), vec![0, 1, 2]);
+        // min above max is an empty box
+        assert_eq!(inside(Point::new(3.0, 4.0), Point::new(0.0, 0.0)), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn distances_are_euclidean() {
+        let dist = expect_points(&points()).unwrap().distance_to(Point::new(0.0, 0.0));
+        assert_eq!(&dist.data[.. 3], &[0.0, 5f64.sqrt(), 5.0]);
+        assert!(dist.data[3].is_nan());
+    }
+
+    #[test]
+    fn vm_ops_take_a_point_column_and_points() {
+        let mut vm = VM::new(Table::from_columns(vec![points(), Column::from(vec![1.0, 2.0, 3.0, 4.0])]).unwrap());
+        vm.run(vec![
+            Op::Col(0, 0), Op::Lit(Scalar::Point(Point::new(0.5, 0.5))), Op::Lit(Scalar::Point(Point::new(5.0, 5.0))), Op::FilterWithinBBox,
+            Op::Col(0, 1), Op::Select(1),
+            Op::Col(0, 0), Op::Lit(Scalar::Point(Point::new(3.0, 0.0))), Op::DistanceTo
+        ]).unwrap();
+        let stack: Vec<Column> = vm.stack().iter().map(|v| vm.column_of(v).unwrap().clone()).collect();
+        crate::assert_columns_eq!(stack[0], Column::from(vec![2.0, 3.0]));
+        assert_eq!(stack[1].get(2), Some(Scalar::Num(4.0)));
+
+        let bad = [
+            vec![Op::Col(0, 1), Op::Lit(Scalar::Point(Point::new(0.0, 0.0))), Op::DistanceTo],
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Num(0.0)), Op::DistanceTo],
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Point(Point::new(0.0, 0.0))), Op::Lit(Scalar::Num(1.0)), Op::FilterWithinBBox]
+        ];
+        for code in bad.iter() {
+            let mut vm = VM::new(Table::from_columns(vec![points(), Column::from(vec![1.0, 2.0, 3.0, 4.0])]).unwrap());
+            assert!(matches!(vm.run(code.clone()), Err(VMError::TypeError(_))), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn generic_kernels_work_on_points() {
+        let col = points();
+        assert_eq!(col.filter(Scalar::Point(Point::new(1.0, 2.0))).unwrap().count_ones(), 1);
+        // filters compare coordinates as Num filters compare values: -0.0 matches 0.0, NaN nothing
+        assert_eq!(col.filter(Scalar::Point(Point::new(-0.0, 0.0))).unwrap().count_ones(), 1);
+        assert_eq!(col.filter(Scalar::Point(Point::new(f64::NAN, 1.0))).unwrap().count_ones(), 0);
+        let both = Column::concat(&[col.clone(), col.slice(1, 2)]).unwrap();
+        assert_eq!(both.get(5), Some(Scalar::Point(Point::new(3.0, 4.0))));
+        assert!(matches!(col.filter_in(&col, NullSemantics::default()), Err(VMError::TypeError(_))));
+    }
+}
@@ -0,0 +1,543 @@
+// Filters and reductions over numeric columns as GPU compute shaders, through wgpu.
+// Experimental, and behind the `gpu` feature.
+//
+// A column is uploaded a chunk at a time - as many rows as one storage buffer binding holds -
+// and each chunk's mask, or its best candidates for a reduction, read back and combined on
+// the CPU. Only plain Num and Entity columns of at least `min_rows` rows go to the GPU; smaller
+// ones, encoded ones and other types run on the CPU kernels as usual, as does everything when
+// there's no GPU (Gpu::new gives None). Results are the same either way: values are compared as
+// bit patterns, and f64s through an order-preserving key, so the shaders need no f64 support
+// except for sum, which is only offloaded when the adapter has it - and then adds in a
+// different order than the CPU does, so may differ in the last bits.
+//
+// Copying a column to the GPU costs more than scanning it once on the CPU until columns get
+// large; benches/gpu.rs measures where the crossover is for a particular machine.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, ColumnT, Scalar};
+use crate::compare;
+use crate::core::prelude::*;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::selection::Selection;
+
+use std::sync::mpsc;
+use wgpu::util::DeviceExt;
+
+// Rows below which a column stays on the CPU unless told otherwise (see with_min_rows)
+pub const DEFAULT_MIN_ROWS: usize = 1 << 20;
+
+// Rows in a chunk: a whole number of reduction workgroups, and no more than fit one binding
+const MAX_CHUNK_ROWS: usize = 1 << 24;
+const ROWS_PER_GROUP: usize = 256 * 64;
+
+// Each invocation masks 32 rows into one word. Values are (lo, hi) word pairs; mode 0 tests
+// for either of two bit patterns, mode 1 for lo <= key < hi with f64 order keys and mode 2 the
+// same with u64s.
+const FILTER_SHADER: &str = r#"
+struct Params { rows: u32, mode: u32, a: vec2<u32>, b: vec2<u32>, pad: vec2<u32> }
+
+@group(0) @binding(0) var<storage, read> data: array<vec2<u32>>;
+@group(0) @binding(1) var<storage, read_write> mask: array<u32>;
+@group(0) @binding(2) var<uniform> params: Params;
+
+fn key(x: vec2<u32>) -> vec2<u32> {
+    if (params.mode == 1u) {
+        if ((x.y & 0x80000000u) != 0u) { return ~x; }
+        return vec2<u32>(x.x, x.y | 0x80000000u);
+    }
+    return x;
+}
+
+fn less(a: vec2<u32>, b: vec2<u32>) -> bool {
+    return a.y < b.y || (a.y == b.y && a.x < b.x);
+}
+
+fn matches(x: vec2<u32>) -> bool {
+    if (params.mode == 0u) { return all(x == params.a) || all(x == params.b); }
+    let k = key(x);
+    return !less(k, params.a) && less(k, params.b);
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let first = id.x * 32u;
+    if (first >= params.rows) { return; }
+    let n = min(32u, params.rows - first);
+    var bits = 0u;
+    for (var i = 0u; i < n; i++) {
+        if (matches(data[first + i])) { bits |= 1u << i; }
+    }
+    mask[id.x] = bits;
+}
+"#;
+
+// Each workgroup finds the smallest (or with `max` set, largest) value of its rows as
+// (key lo, key hi, row, found), ties going to the first row. Mode 1 is f64s, ordered as
+// compare::cmp_f64 does; mode 2 u64s.
+const EXTREME_SHADER: &str = r#"
+struct Params { rows: u32, mode: u32, max: u32, pad: u32 }
+
+@group(0) @binding(0) var<storage, read> data: array<vec2<u32>>;
+@group(0) @binding(1) var<storage, read_write> out: array<vec4<u32>>;
+@group(0) @binding(2) var<uniform> params: Params;
+
+var<workgroup> best: array<vec4<u32>, 256>;
+
+fn order_key(x: vec2<u32>) -> vec2<u32> {
+    if (params.mode == 2u) { return x; }
+    // every NaN is the largest value, and -0.0 the same as 0.0
+    if ((x.y & 0x7ff00000u) == 0x7ff00000u && ((x.y & 0xfffffu) | x.x) != 0u) { return vec2<u32>(0xffffffffu, 0xffffffffu); }
+    if (x.y == 0x80000000u && x.x == 0u) { return vec2<u32>(0u, 0x80000000u); }
+    if ((x.y & 0x80000000u) != 0u) { return ~x; }
+    return vec2<u32>(x.x, x.y | 0x80000000u);
+}
+
+fn better(a: vec4<u32>, b: vec4<u32>) -> bool {
+    if (a.w == 0u) { return false; }
+    if (b.w == 0u) { return true; }
+    if (all(a.xy == b.xy)) { return a.z < b.z; }
+    let lt = a.y < b.y || (a.y == b.y && a.x < b.x);
+    return lt != (params.max == 1u);
+}
+
+@compute @workgroup_size(256)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>,
+        @builtin(workgroup_id) wid: vec3<u32>) {
+    var mine = vec4<u32>(0u, 0u, 0u, 0u);
+    for (var i = 0u; i < 64u; i++) {
+        let row = gid.x * 64u + i;
+        if (row >= params.rows) { break; }
+        let c = vec4<u32>(order_key(data[row]), row, 1u);
+        if (better(c, mine)) { mine = c; }
+    }
+    best[lid.x] = mine;
+    workgroupBarrier();
+    for (var stride = 128u; stride > 0u; stride >>= 1u) {
+        if (lid.x < stride && better(best[lid.x + stride], best[lid.x])) {
+            best[lid.x] = best[lid.x + stride];
+        }
+        workgroupBarrier();
+    }
+    if (lid.x == 0u) { out[wid.x] = best[0]; }
+}
+"#;
+
+// Each workgroup sums its rows
+const SUM_SHADER: &str = r#"
+struct Params { rows: u32, pad0: u32, pad1: u32, pad2: u32 }
+
+@group(0) @binding(0) var<storage, read> data: array<f64>;
+@group(0) @binding(1) var<storage, read_write> out: array<f64>;
+@group(0) @binding(2) var<uniform> params: Params;
+
+var<workgroup> part: array<f64, 256>;
+
+@compute @workgroup_size(256)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>,
+        @builtin(workgroup_id) wid: vec3<u32>) {
+    var total = f64(0.0);
+    for (var i = 0u; i < 64u; i++) {
+        let row = gid.x * 64u + i;
+        if (row >= params.rows) { break; }
+        total += data[row];
+    }
+    part[lid.x] = total;
+    workgroupBarrier();
+    for (var stride = 128u; stride > 0u; stride >>= 1u) {
+        if (lid.x < stride) { part[lid.x] += part[lid.x + stride]; }
+        workgroupBarrier();
+    }
+    if (lid.x == 0u) { out[wid.x] = part[0]; }
+}
+"#;
+
+const EQUALS: u32 = 0;
+const F64: u32 = 1;
+const U64: u32 = 2;
+
+pub struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    name: String,
+    filter: wgpu::ComputePipeline,
+    extreme: wgpu::ComputePipeline,
+    sum: Option<wgpu::ComputePipeline>,     // f64 in shaders is an optional feature
+    chunk_rows: usize,
+    min_rows: usize
+}
+
+// A plain column the shaders can read: its values as 8-byte words, and which kind they are
+enum Words<'a> {
+    F64(&'a [f64]),
+    U64(&'a [u64])
+}
+
+impl Words<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Words::F64(xs) => xs.len(),
+            Words::U64(xs) => xs.len()
+        }
+    }
+
+    fn chunk(&self, offset: usize, len: usize) -> &[u8] {
+        match self {
+            Words::F64(xs) => bytemuck::cast_slice(&xs[offset .. offset + len]),
+            Words::U64(xs) => bytemuck::cast_slice(&xs[offset .. offset + len])
+        }
+    }
+
+    fn mode(&self) -> u32 {
+        match self {
+            Words::F64(_) => F64,
+            Words::U64(_) => U64
+        }
+    }
+}
+
+impl Gpu {
+    // The first GPU wgpu finds, or None if there isn't one
+    pub fn new() -> Option<Gpu> {
+        pollster::block_on(Gpu::request())
+    }
+
+    async fn request() -> Option<Gpu> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        }).await?;
+        let limits = adapter.limits();
+        let f64 = adapter.features().contains(wgpu::Features::SHADER_F64);
+        let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
+            label: Some("collie"),
+            required_features: if f64 { wgpu::Features::SHADER_F64 } else { wgpu::Features::empty() },
+            required_limits: limits.clone(),
+            memory_hints: wgpu::MemoryHints::Performance
+        }, None).await.ok()?;
+
+        let pipeline = |source: &str| {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(source.into())
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: None,
+                layout: None,
+                module: &module,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None
+            })
+        };
+        let binding = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size) as usize;
+        let chunk_rows = (binding / 8).min(MAX_CHUNK_ROWS) / ROWS_PER_GROUP * ROWS_PER_GROUP;
+        if chunk_rows == 0 {
+            return None;
+        }
+        Some(Gpu {
+            name: adapter.get_info().name,
+            filter: pipeline(FILTER_SHADER),
+            extreme: pipeline(EXTREME_SHADER),
+            sum: if f64 { Some(pipeline(SUM_SHADER)) } else { None },
+            device, queue, chunk_rows,
+            min_rows: DEFAULT_MIN_ROWS
+        })
+    }
+
+    // Offload columns of at least `rows` rows (DEFAULT_MIN_ROWS otherwise)
+    pub fn with_min_rows(mut self, rows: usize) -> Self {
+        self.min_rows = rows;
+        self
+    }
+
+    // The adapter's name, e.g. for logging which device queries run on
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Whether sum runs on the GPU, which needs f64 support in shaders
+    pub fn has_f64(&self) -> bool {
+        self.sum.is_some()
+    }
+
+    // Whether filters and reductions on `col` run here rather than on the CPU
+    pub fn offloads(&self, col: &Column) -> bool {
+        col.len() >= self.min_rows && matches!(col, Column::Num(_) | Column::Entity(_))
+    }
+
+    fn words<'a>(&self, col: &'a Column) -> Option<Words<'a>> {
+        if !self.offloads(col) {
+            return None;
+        }
+        match col {
+            Column::Num(c) => Some(Words::F64(c.values())),
+            Column::Entity(c) => Some(Words::U64(c.values())),
+            _ => None
+        }
+    }
+
+    // FilterEq, as Column::filter does it
+    pub fn filter_eq(&self, col: &Column, val: Scalar) -> Result<BoolColumn, VMError> {
+        let params = match (self.words(col), &val) {
+            // NaN equals nothing; -0.0 and 0.0 equal each other
+            (Some(Words::F64(_)), Scalar::Num(x)) if !x.is_nan() => {
+                let (a, b) = if *x == 0.0 { (0.0f64, -0.0f64) } else { (*x, *x) };
+                Some((EQUALS, a.to_bits(), b.to_bits()))
+            },
+            (Some(Words::U64(_)), Scalar::Entity(x)) => Some((EQUALS, *x, *x)),
+            _ => None
+        };
+        self.filter_or(col, params, || col.filter(val.clone()))
+    }
+
+    // lo <= x < hi, as Column::filter_range does it
+    pub fn filter_range(&self, col: &Column, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        let params = match (self.words(col), &lo, &hi) {
+            // NaN bounds hold nothing, and 0.0 as a bound includes -0.0 below and excludes it above
+            (Some(Words::F64(_)), Scalar::Num(a), Scalar::Num(b)) if !a.is_nan() && !b.is_nan() => {
+                Some((F64, order_key(if *a == 0.0 { -0.0 } else { *a }), order_key(if *b == 0.0 { -0.0 } else { *b })))
+            },
+            (Some(Words::U64(_)), Scalar::Entity(a), Scalar::Entity(b)) => Some((U64, *a, *b)),
+            _ => None
+        };
+        self.filter_or(col, params, || col.filter_range(lo.clone(), hi.clone()))
+    }
+
+    // The mask of the filter shader with `params` (mode, a, b), or `cpu`'s if there are none
+    // or the GPU fails
+    fn filter_or<F>(&self, col: &Column, params: Option<(u32, u64, u64)>, cpu: F) -> Result<BoolColumn, VMError>
+        where F: Fn() -> Result<BoolColumn, VMError>
+    {
+        let ((mode, a, b), words) = match (params, self.words(col)) {
+            (Some(params), Some(words)) => (params, words),
+            _ => return cpu()
+        };
+        let len = words.len();
+        let mut mask: Vec<u64> = Vec::with_capacity(len / 64 + 1);
+        for offset in (0 .. len).step_by(self.chunk_rows) {
+            let rows = (len - offset).min(self.chunk_rows);
+            let params = [rows as u32, mode, a as u32, (a >> 32) as u32, b as u32, (b >> 32) as u32, 0, 0];
+            let out_words = rows.div_ceil(32);
+            let bits: Vec<u32> = match self.dispatch(&self.filter, words.chunk(offset, rows), params, out_words as u64 * 4,
+                                                     out_words.div_ceil(64) as u32) {
+                Some(bits) => bits,
+                None => return cpu()
+            };
+            // chunks start on a word boundary, so their masks just follow each other
+            mask.extend(bits.chunks(2).map(|w| w[0] as u64 | (*w.get(1).unwrap_or(&0) as u64) << 32));
+        }
+        mask.resize(len / 64 + 1, 0);
+        Ok(BoolColumn::from_selection(Selection::adaptive(BitIndex::from_words(mask, len))))
+    }
+
+    // The smallest value, as compare::min
+    pub fn min(&self, col: &Column) -> Option<Scalar> {
+        self.extreme(col, false).unwrap_or_else(|| compare::min(col))
+    }
+
+    // The largest value, as compare::max
+    pub fn max(&self, col: &Column) -> Option<Scalar> {
+        self.extreme(col, true).unwrap_or_else(|| compare::max(col))
+    }
+
+    // None to leave it to the CPU
+    fn extreme(&self, col: &Column, max: bool) -> Option<Option<Scalar>> {
+        let words = self.words(col)?;
+        let len = words.len();
+        let mut best: Option<(u64, usize)> = None;
+        for offset in (0 .. len).step_by(self.chunk_rows) {
+            let rows = (len - offset).min(self.chunk_rows);
+            let groups = rows.div_ceil(ROWS_PER_GROUP);
+            let params = [rows as u32, words.mode(), max as u32, 0, 0, 0, 0, 0];
+            let found: Vec<[u32; 4]> = self.dispatch(&self.extreme, words.chunk(offset, rows), params, groups as u64 * 16, groups as u32)?;
+            for [lo, hi, row, ok] in found {
+                let c = ((hi as u64) << 32 | lo as u64, offset + row as usize);
+                // candidates come in row order, so an equal key is never better
+                if ok == 1 && best.is_none_or(|b| if max { c.0 > b.0 } else { c.0 < b.0 }) {
+                    best = Some(c);
+                }
+            }
+        }
+        Some(best.and_then(|(_, row)| col.get(row)))
+    }
+
+    // The sum of a Num column
+    pub fn sum(&self, col: &Column) -> Result<Scalar, VMError> {
+        let words = match (&self.sum, self.words(col)) {
+            (Some(_), Some(words @ Words::F64(_))) => words,
+            _ => return sum_cpu(col)
+        };
+        let pipeline = self.sum.as_ref().unwrap();
+        let len = words.len();
+        let mut total = 0.0;
+        for offset in (0 .. len).step_by(self.chunk_rows) {
+            let rows = (len - offset).min(self.chunk_rows);
+            let groups = rows.div_ceil(ROWS_PER_GROUP);
+            let params = [rows as u32, 0, 0, 0, 0, 0, 0, 0];
+            match self.dispatch::<f64>(pipeline, words.chunk(offset, rows), params, groups as u64 * 8, groups as u32) {
+                Some(parts) => total += parts.iter().sum::<f64>(),
+                None => return sum_cpu(col)
+            }
+        }
+        Ok(Scalar::Num(total))
+    }
+
+    // Run `pipeline` over `data` in `groups` workgroups, and read back the `out_bytes` it writes.
+    // None if the GPU couldn't (a lost device, say).
+    fn dispatch<T: bytemuck::Pod>(&self, pipeline: &wgpu::ComputePipeline, data: &[u8], params: [u32; 8],
+                                  out_bytes: u64, groups: u32) -> Option<Vec<T>> {
+        let input = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None, contents: data, usage: wgpu::BufferUsages::STORAGE
+        });
+        let uniform = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None, contents: bytemuck::cast_slice(&params), usage: wgpu::BufferUsages::UNIFORM
+        });
+        let output = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None, size: out_bytes, usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC, mapped_at_creation: false
+        });
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None, size: out_bytes, usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST, mapped_at_creation: false
+        });
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: input.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: output.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: uniform.as_entire_binding() }
+            ]
+        });
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None, timestamp_writes: None });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(groups, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&output, 0, &staging, 0, out_bytes);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        let (tx, rx) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |res| { let _ = tx.send(res); });
+        self.device.poll(wgpu::Maintain::Wait);
+        rx.recv().ok()?.ok()?;
+        let out = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        staging.unmap();
+        Some(out)
+    }
+}
+
+// The CPU's sum, for columns the GPU doesn't take
+pub fn sum_cpu(col: &Column) -> Result<Scalar, VMError> {
+    match encoding::plain(col).as_ref() {
+        Column::Num(c) => Ok(Scalar::Num(c.values().iter().sum())),
+        other => Err(VMError::TypeError(format!("Expected a Num column, found a {} column", other.datatype())))
+    }
+}
+
+// f64s as u64s in the same order: negative ones below positive ones, and more negative lower
+fn order_key(x: f64) -> u64 {
+    let bits = x.to_bits();
+    if bits >> 63 == 1 { !bits } else { bits | 1 << 63 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoding;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    use core::cmp::Ordering;
+    use std::sync::Arc;
+
+    const NUMS: [f64; 9] = [f64::NEG_INFINITY, -1e300, -2.5, -f64::MIN_POSITIVE, -0.0, 0.0, 1e-300, 3.0, f64::INFINITY];
+
+    // Rows around the chunk and word sizes, with repeats, NaN and both zeros
+    fn data(len: usize) -> (Column, Column) {
+        let nums: Vec<f64> = (0 .. len).map(|i| if i % 97 == 5 { f64::NAN } else { NUMS[i * 7 % NUMS.len()] }).collect();
+        let ids: Vec<u64> = (0 .. len as u64).map(|i| i * 31 % 1000).collect();
+        (Column::from(nums), Column::from(ids))
+    }
+
+    #[test]
+    fn order_keys_sort_as_f64s_do() {
+        for (i, a) in NUMS.iter().enumerate() {
+            for (j, b) in NUMS.iter().enumerate() {
+                // the two zeros differ here, as their bit patterns do
+                let expected = if a == b && a.is_sign_negative() == b.is_sign_negative() { Ordering::Equal } else { i.cmp(&j) };
+                assert_eq!(order_key(*a).cmp(&order_key(*b)), expected, "{} vs {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn sum_cpu_adds_num_columns() {
+        assert_eq!(sum_cpu(&Column::from(vec![1.5, 2.0, -0.5])).unwrap(), Scalar::Num(3.0));
+        let rle = encoding::encode(Column::from(vec![2.0; 10]), Encoding::Rle).unwrap();
+        assert_eq!(sum_cpu(&rle).unwrap(), Scalar::Num(20.0));
+        assert!(matches!(sum_cpu(&Column::from(vec![1u64])), Err(VMError::TypeError(_))));
+    }
+
+    // Runs only where wgpu finds an adapter; without one there's nothing to compare
+    #[test]
+    fn matches_the_cpu_where_there_is_a_gpu() {
+        let gpu = match Gpu::new() {
+            Some(gpu) => gpu.with_min_rows(0),
+            None => return
+        };
+        for &len in &[0, 1, 63, 64, 65, ROWS_PER_GROUP + 3, 100_000] {
+            let (nums, ids) = data(len);
+            assert!(gpu.offloads(&nums) && gpu.offloads(&ids));
+            for &x in &[0.0, -0.0, 3.0, f64::NAN, 4.0] {
+                let val = Scalar::Num(x);
+                assert_eq!(gpu.filter_eq(&nums, val.clone()).unwrap().selection().positions(), nums.filter(val).unwrap().selection().positions(), "{} rows, = {}", len, x);
+            }
+            for &(lo, hi) in &[(-2.5, 3.0), (0.0, 1.0), (-0.0, 0.0), (f64::NEG_INFINITY, f64::NAN)] {
+                let (lo, hi) = (Scalar::Num(lo), Scalar::Num(hi));
+                let cpu = nums.filter_range(lo.clone(), hi.clone()).unwrap();
+                assert_eq!(gpu.filter_range(&nums, lo, hi).unwrap().selection().positions(), cpu.selection().positions(), "{} rows", len);
+            }
+            let cpu = ids.filter_range(Scalar::Entity(10), Scalar::Entity(500)).unwrap();
+            assert_eq!(gpu.filter_range(&ids, Scalar::Entity(10), Scalar::Entity(500)).unwrap().selection().positions(), cpu.selection().positions());
+            assert_eq!(gpu.filter_eq(&ids, Scalar::Entity(31)).unwrap().selection().positions(), ids.filter(Scalar::Entity(31)).unwrap().selection().positions());
+            for col in [&nums, &ids] {
+                assert_eq!(gpu.min(col), compare::min(col), "{} rows", len);
+                assert_eq!(gpu.max(col), compare::max(col), "{} rows", len);
+            }
+            let finite: Vec<f64> = (0 .. len).map(|i| (i % 10) as f64 * 0.5).collect();
+            let (expected, sum) = (sum_cpu(&Column::from(finite.clone())).unwrap(), gpu.sum(&Column::from(finite)).unwrap());
+            match (expected, sum) {
+                (Scalar::Num(a), Scalar::Num(b)) => assert!((a - b).abs() <= 1e-9 * a.abs().max(1.0), "{} vs {}", a, b),
+                other => panic!("{:?}", other)
+            }
+        }
+        // small columns stay on the CPU
+        assert!(!gpu.with_min_rows(10).offloads(&Column::from(vec![1.0])));
+    }
+
+    #[test]
+    fn the_vm_filters_on_the_gpu_once_given_one() {
+        let gpu = match Gpu::new() {
+            Some(gpu) => Arc::new(gpu.with_min_rows(0)),
+            None => return
+        };
+        let (nums, ids) = data(5_000);
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)];
+        let run = |gpu: Option<Arc<Gpu>>| {
+            let mut vm = VM::new(Table::from_columns(vec![nums.clone(), ids.clone()]).unwrap());
+            if let Some(gpu) = gpu {
+                vm.set_gpu(gpu);
+            }
+            vm.run(code.clone()).unwrap();
+            vm.column_of(vm.stack().last().unwrap()).unwrap().clone()
+        };
+        crate::assert_columns_eq!(run(Some(gpu)), run(None));
+    }
+}
+
@@ -0,0 +1,185 @@
+// IP addresses: IPv4 and IPv6 columns, each a PrimitiveColumn of the address type (4 and 16
+// bytes a row, the same as u32 and u128). Text is parsed and printed in the standard forms,
+// "192.168.0.1" and "2001:db8::1". The kernel added here tests membership of a CIDR block;
+// equality and ranges come from the generic primitive kernels, ordering addresses as numbers.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, Scalar};
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::kernels;
+use crate::primitive::{Native, PrimitiveColumn};
+use crate::schema::Datatype;
+
+use core::cmp::Ordering;
+pub use core::net::{Ipv4Addr, Ipv6Addr};
+
+pub type Ipv4Column = PrimitiveColumn<Ipv4Addr>;
+pub type Ipv6Column = PrimitiveColumn<Ipv6Addr>;
+
+impl Native for Ipv4Addr {
+    const DATATYPE: Datatype = Datatype::Ipv4;
+    const DESCRIPTION: &'static str = "an IPv4 address";
+
+    fn from_scalar(s: &Scalar) -> Option<Ipv4Addr> {
+        if let Scalar::Ipv4(x) = s { Some(*x) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Ipv4(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<Ipv4Addr>) -> Column {
+        Column::Ipv4(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<Ipv4Addr>> {
+        if let Column::Ipv4(c) = col { Some(c) } else { None }
+    }
+
+    fn same(a: Ipv4Addr, b: Ipv4Addr) -> bool {
+        a == b
+    }
+
+    fn total_cmp(a: Ipv4Addr, b: Ipv4Addr) -> Ordering {
+        a.cmp(&b)
+    }
+}
+
+impl Native for Ipv6Addr {
+    const DATATYPE: Datatype = Datatype::Ipv6;
+    const DESCRIPTION: &'static str = "an IPv6 address";
+
+    fn from_scalar(s: &Scalar) -> Option<Ipv6Addr> {
+        if let Scalar::Ipv6(x) = s { Some(*x) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Ipv6(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<Ipv6Addr>) -> Column {
+        Column::Ipv6(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<Ipv6Addr>> {
+        if let Column::Ipv6(c) = col { Some(c) } else { None }
+    }
+
+    fn same(a: Ipv6Addr, b: Ipv6Addr) -> bool {
+        a == b
+    }
+
+    fn total_cmp(a: Ipv6Addr, b: Ipv6Addr) -> Ordering {
+        a.cmp(&b)
+    }
+}
+
+// The rows whose top `prefix` bits match `network`'s, with addresses as `bits` maps them:
+// IPv4 ones go in the top 32 bits
+fn cidr_mask<T: Copy, F: Fn(T) -> u128>(data: &[T], network: u128, prefix: u8, bits: F) -> BitIndex {
+    let keep = match prefix {
+        0 => 0,
+        p => u128::MAX << (128 - p as u32)
+    };
+    let network = network & keep;
+    kernels::mask_by(data, |x| bits(x) & keep == network)
+}
+
+// Rows in the CIDR block `network`/`prefix`, e.g. 10.0.0.0/8. The network's bits past the
+// prefix are ignored, so 10.1.2.3/8 is the same block.
+pub fn filter_in_cidr(col: &Column, network: &Scalar, prefix: u8) -> Result<BoolColumn, VMError> {
+    let too_long = |width| VMError::TypeError(format!("A CIDR prefix of {} bits is longer than an {} address", prefix, width));
+    let mask = match (col, network) {
+        (Column::Ipv4(c), Scalar::Ipv4(net)) if prefix <= 32 => {
+            cidr_mask(&c.data, (u32::from(*net) as u128) << 96, prefix, |x| (u32::from(x) as u128) << 96)
+        },
+        (Column::Ipv6(c), Scalar::Ipv6(net)) if prefix <= 128 => {
+            cidr_mask(&c.data, u128::from(*net), prefix, u128::from)
+        },
+        (Column::Ipv4(_), Scalar::Ipv4(_)) => return Err(too_long("IPv4")),
+        (Column::Ipv6(_), Scalar::Ipv6(_)) => return Err(too_long("IPv6")),
+        (Column::Ipv4(_) | Column::Ipv6(_), _) => {
+            return Err(VMError::TypeError(format!("Expected a network address for this {} column, got: {:?}", col.datatype(), network)));
+        },
+        _ => return Err(VMError::TypeError(format!("Expected an IPv4 or IPv6 column, found a {} column", col.datatype())))
+    };
+    Ok(BoolColumn::from_mask(mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+    use crate::nulls::NullSemantics;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    fn v4(text: &str) -> Ipv4Addr {
+        text.parse().unwrap()
+    }
+
+    fn v6(text: &str) -> Ipv6Addr {
+        text.parse().unwrap()
+    }
+
+    fn v4s() -> Column {
+        Column::from(vec![v4("10.0.0.1"), v4("10.255.3.4"), v4("11.0.0.0"), v4("192.168.1.20"), v4("0.0.0.0")])
+    }
+
+    #[test]
+    fn cidr_blocks_match_the_top_bits() {
+        let col = v4s();
+        let block = |net: &str, prefix| filter_in_cidr(&col, &Scalar::Ipv4(v4(net)), prefix).unwrap().selection().positions();
+        assert_eq!(block("10.0.0.0", 8), vec![0, 1]);
+        // bits past the prefix are ignored
+        assert_eq!(block("10.9.9.9", 8), vec![0, 1]);
+        assert_eq!(block("192.168.1.16", 28), vec![3]);
+        assert_eq!(block("192.168.1.20", 32), vec![3]);
+        assert_eq!(block("1.2.3.4", 0), vec![0, 1, 2, 3, 4]);
+
+        let col = Column::from(vec![v6("2001:db8::1"), v6("2001:db8:0:1::"), v6("::1"), v6("fe80::1")]);
+        let block = |net: &str, prefix| filter_in_cidr(&col, &Scalar::Ipv6(v6(net)), prefix).unwrap().selection().positions();
+        assert_eq!(block("2001:db8::", 32), vec![0, 1]);
+        assert_eq!(block("2001:db8::", 64), vec![0]);
+        assert_eq!(block("::1", 128), vec![2]);
+        assert_eq!(block("fe80::", 10), vec![3]);
+        assert_eq!(block("::", 0), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn cidr_needs_a_matching_network_and_prefix() {
+        let v6s = Column::from(vec![v6("::1")]);
+        let bad = [
+            (v4s(), Scalar::Ipv4(v4("10.0.0.0")), 33),
+            (v6s.clone(), Scalar::Ipv6(v6("::")), 129),
+            (v4s(), Scalar::Ipv6(v6("::")), 8),
+            (v6s, Scalar::Ipv4(v4("10.0.0.0")), 8),
+            (Column::from(vec![1u64]), Scalar::Ipv4(v4("10.0.0.0")), 8)
+        ];
+        for (col, net, prefix) in bad.iter() {
+            assert!(matches!(filter_in_cidr(col, net, *prefix), Err(VMError::TypeError(_))), "{:?}/{}", net, prefix);
+        }
+    }
+
+    #[test]
+    fn filters_through_the_vm() {
+        let mut vm = VM::new(Table::from_columns(vec![v4s(), Column::from(vec![1.0, 2.0, 3.0, 4.0, 5.0])]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Ipv4(v4("10.0.0.0"))), Op::FilterInCidr(8), Op::Col(0, 1), Op::Select(1)]).unwrap();
+        crate::assert_columns_eq!(vm.column_of(&vm.stack()[0]).unwrap(), &Column::from(vec![1.0, 2.0]));
+    }
+
+    #[test]
+    fn generic_kernels_order_addresses_as_numbers() {
+        let col = v4s();
+        assert_eq!(col.filter(Scalar::Ipv4(v4("11.0.0.0"))).unwrap().count_ones(), 1);
+        let range = col.filter_range(Scalar::Ipv4(v4("10.0.0.0")), Scalar::Ipv4(v4("11.0.0.0"))).unwrap();
+        assert_eq!(range.selection().positions(), vec![0, 1]);
+        let found = col.filter_in(&Column::from(vec![v4("0.0.0.0"), v4("10.0.0.1"), v4("8.8.8.8")]), NullSemantics::default()).unwrap();
+        assert_eq!(found.selection().positions(), vec![0, 4]);
+        assert!(col.filter_in(&Column::from(vec![v6("::")]), NullSemantics::default()).is_err());
+        assert!(Scalar::Ipv4(v4("9.255.255.255")) < Scalar::Ipv4(v4("10.0.0.0")));
+        assert!(Scalar::Ipv6(v6("::ffff")) < Scalar::Ipv6(v6("1::")));
+    }
+}
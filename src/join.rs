@@ -0,0 +1,496 @@
+// Equi-joins on one key column. hash_join builds a hash table over the right
+// side and probes it with the left. GraceJoin does the same within a memory budget: when the
+// right side is bigger than the budget, both sides are split by key hash into partitions
+// spilled to temporary files, and joined one partition pair at a time, so only one
+// partition's hash table is ever held. A partition still over budget, as skewed keys leave,
+// is split again the same way with another hash, up to MAX_LEVELS deep; one that no hash can
+// split - every row with the same key - is joined whole.
+//
+// With a MemoryManager, GraceJoin also reserves each hash table it builds, spilling when the
+// whole right side doesn't fit in what's left and failing if a partition that can't be split
+// any further doesn't.
+//
+// The output has the left columns, then the right columns other than the key. Rows come in
+// left row order for hash_join; GraceJoin gives the same rows, grouped by partition. Keys
+// match as Scalars compare (see compare.rs), except that null keys match only if the
+// NullSemantics say nulls match.
+//
+// join_positions is the VM's join, for Op::HashJoin: over Entity or Int keys, and giving the matching
+// row numbers of each side rather than the joined rows. GraceJoin needs files to spill to, so
+// it's only there with std.
+
+use crate::bitindex::BitIndex;
+use crate::column::{Column, Scalar};
+use crate::conditional;
+use crate::core::prelude::*;
+use crate::core::HashMap;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::nulls::{self, NullSemantics};
+use crate::result::ResultSet;
+#[cfg(feature = "std")]
+use crate::memory::{Consumer, MemoryManager, Reservation};
+#[cfg(feature = "std")]
+use crate::storage;
+
+use core::hash::Hash;
+#[cfg(feature = "std")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "std")]
+use std::fs::{self, File, OpenOptions};
+#[cfg(feature = "std")]
+use std::hash::Hasher;
+#[cfg(feature = "std")]
+use std::io::{self, BufReader, BufWriter, Write};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "std")]
+use std::sync::Arc;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn key_column<'a>(rs: &'a ResultSet, key: &str) -> Result<&'a Column, VMError> {
+    rs.column(key).ok_or_else(|| VMError::TypeError(format!("No column named '{}' to join on", key)))
+}
+
+// Inner join of `left` and `right` where left_key = right_key
+pub fn hash_join(left: &ResultSet, left_key: &str, right: &ResultSet, right_key: &str, nulls: NullSemantics) -> Result<ResultSet, VMError> {
+    let (lkey, rkey) = (key_column(left, left_key)?, key_column(right, right_key)?);
+    if lkey.datatype() != rkey.datatype() {
+        return Err(VMError::TypeError(format!("Can't join a {} key with a {} key", lkey.datatype(), rkey.datatype())));
+    }
+    // a null key that can't match anything is left out of the table
+    let joinable = |k: &Scalar| nulls.nulls_match() || !nulls::is_null(k);
+    let rkey = encoding::plain(rkey);
+    let mut table: HashMap<Scalar, Vec<usize>> = HashMap::new();
+    for i in 0 .. rkey.len() {
+        let k = rkey.get(i).unwrap();
+        if joinable(&k) {
+            table.entry(k).or_default().push(i);
+        }
+    }
+    let lkey = encoding::plain(lkey);
+    let (mut lrows, mut rrows) = (Vec::new(), Vec::new());
+    for i in 0 .. lkey.len() {
+        if let Some(matches) = table.get(&lkey.get(i).unwrap()) {
+            lrows.extend(core::iter::repeat_n(i, matches.len()));
+            rrows.extend_from_slice(matches);
+        }
+    }
+    let mut res = ResultSet::new();
+    for (name, col) in left.iter() {
+        res.push(name, conditional::take(col, &lrows)?);
+    }
+    for (name, col) in right.iter().filter(|(name, _)| *name != right_key) {
+        res.push(name, conditional::take(col, &rrows)?);
+    }
+    Ok(res)
+}
+
+// The rows of `left` and `right` with equal keys, as a pair of row numbers per match: the
+// left rows in order, each with its matches in right row order
+pub fn join_positions<K: Copy + Eq + Hash>(left: &[K], right: &[K]) -> (Vec<u64>, Vec<u64>) {
+    join_keys(left, right, |_| true)
+}
+
+// join_positions where either side may have nulls: the rows not in its validity. A null key
+// matches only another null, and only if `nulls` says nulls match.
+pub fn join_positions_nullable<K: Copy + Eq + Hash>(left: &[K], left_valid: Option<&BitIndex>, right: &[K], right_valid: Option<&BitIndex>,
+                                                   nulls: NullSemantics) -> (Vec<u64>, Vec<u64>) {
+    if left_valid.is_none() && right_valid.is_none() {
+        return join_positions(left, right);
+    }
+    let keys = |values: &[K], valid: Option<&BitIndex>| -> Vec<Option<K>> {
+        values.iter().enumerate().map(|(i, k)| valid.is_none_or(|v| v.get(i)).then_some(*k)).collect()
+    };
+    join_keys(&keys(left, left_valid), &keys(right, right_valid), |k| k.is_some() || nulls.nulls_match())
+}
+
+// join_positions, of the right rows whose keys `joins`; the left ones that don't find none
+fn join_keys<K: Copy + Eq + Hash, F: Fn(&K) -> bool>(left: &[K], right: &[K], joins: F) -> (Vec<u64>, Vec<u64>) {
+    let mut table: HashMap<K, Vec<u64>> = HashMap::with_capacity(right.len());
+    right.iter().enumerate().filter(|(_, k)| joins(k)).for_each(|(i, k)| table.entry(*k).or_default().push(i as u64));
+    let (mut lrows, mut rrows) = (Vec::new(), Vec::new());
+    for (i, k) in left.iter().enumerate() {
+        if let Some(matches) = table.get(k) {
+            lrows.extend(core::iter::repeat_n(i as u64, matches.len()));
+            rrows.extend_from_slice(matches);
+        }
+    }
+    (lrows, rrows)
+}
+
+#[cfg(feature = "std")]
+fn memory_usage(rs: &ResultSet) -> usize {
+    rs.columns.iter().map(|c| c.memory_usage()).sum()
+}
+
+#[cfg(feature = "std")]
+pub struct GraceJoin {
+    budget: usize,          // bytes the right side may take before the join spills
+    partitions: usize,
+    spill_dir: PathBuf,
+    nulls: NullSemantics,
+    memory: Option<Arc<MemoryManager>>
+}
+
+// Spill file names: unique within the process, as well as across processes by pid
+#[cfg(feature = "std")]
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+// How many times a partition over budget is split again before it's joined as it is
+#[cfg(feature = "std")]
+const MAX_LEVELS: usize = 4;
+
+// Removes the spill files when the join is done with them, however it ends
+#[cfg(feature = "std")]
+struct SpillFiles(Vec<PathBuf>);
+
+#[cfg(feature = "std")]
+impl Drop for SpillFiles {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|p| { let _ = fs::remove_file(p); });
+    }
+}
+
+#[cfg(feature = "std")]
+impl GraceJoin {
+    pub fn new(budget: usize) -> Self {
+        GraceJoin { budget, partitions: 16, spill_dir: std::env::temp_dir(), nulls: NullSemantics::default(), memory: None }
+    }
+
+    pub fn with_partitions(mut self, partitions: usize) -> Self {
+        assert!(partitions > 0, "partitions must be positive");
+        self.partitions = partitions;
+        self
+    }
+
+    pub fn with_spill_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.spill_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn with_null_semantics(mut self, nulls: NullSemantics) -> Self {
+        self.nulls = nulls;
+        self
+    }
+
+    pub fn with_memory_manager(mut self, memory: Arc<MemoryManager>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn join(&self, left: &ResultSet, left_key: &str, right: &ResultSet, right_key: &str) -> Result<ResultSet, VMError> {
+        self.join_level(left, left_key, right, right_key, 0)
+    }
+
+    // The join of `left` and `right`, partitioned `level` times already
+    fn join_level(&self, left: &ResultSet, left_key: &str, right: &ResultSet, right_key: &str, level: usize) -> Result<ResultSet, VMError> {
+        if memory_usage(right) <= self.budget {
+            if let Ok(_table) = self.reserve(right) {
+                return hash_join(left, left_key, right, right_key, self.nulls);
+            }
+        }
+        let (lkey, rkey) = (key_column(left, left_key)?, key_column(right, right_key)?);
+        if lkey.datatype() != rkey.datatype() {
+            return Err(VMError::TypeError(format!("Can't join a {} key with a {} key", lkey.datatype(), rkey.datatype())));
+        }
+        let rrows = self.partition(rkey, level);
+        // all one key, or as deep as splitting goes: joined anyway, unless the memory manager
+        // hasn't room for it
+        if level == MAX_LEVELS || rrows.iter().any(|rows| rows.len() == right.rows()) {
+            let _table = self.reserve(right)?;
+            return hash_join(left, left_key, right, right_key, self.nulls);
+        }
+        let mut files = SpillFiles(Vec::new());
+        let lparts = self.spill(left, self.partition(lkey, level), &mut files)?;
+        let rparts = self.spill(right, rrows, &mut files)?;
+
+        let mut parts = Vec::with_capacity(self.partitions);
+        for (lpath, rpath) in lparts.iter().zip(rparts.iter()) {
+            let (l, r) = (read_part(lpath, left)?, read_part(rpath, right)?);
+            parts.push(self.join_level(&l, left_key, &r, right_key, level + 1)?);
+        }
+        concat(parts)
+    }
+
+    // Room for a hash table over `right`, from the memory manager if there is one
+    fn reserve(&self, right: &ResultSet) -> Result<Option<Reservation>, VMError> {
+        self.memory.as_ref().map(|m| m.try_reserve(Consumer::HashTable, memory_usage(right))).transpose()
+    }
+
+    // The rows of each partition, by the hash of `key`; each level hashes differently, so rows
+    // that shared a partition at one level are spread out at the next
+    fn partition(&self, key: &Column, level: usize) -> Vec<Vec<usize>> {
+        let key = encoding::plain(key);
+        let mut rows: Vec<Vec<usize>> = vec![Vec::new(); self.partitions];
+        for i in 0 .. key.len() {
+            let mut h = DefaultHasher::new();
+            h.write_usize(level);
+            key.get(i).unwrap().hash(&mut h);
+            rows[(h.finish() % self.partitions as u64) as usize].push(i);
+        }
+        rows
+    }
+
+    // Write each partition's `rows` of `rs` to a file; returns the paths
+    fn spill(&self, rs: &ResultSet, rows: Vec<Vec<usize>>, files: &mut SpillFiles) -> Result<Vec<PathBuf>, VMError> {
+        let mut paths = Vec::with_capacity(rows.len());
+        for part in rows {
+            let (path, file) = self.create_spill_file()?;
+            files.0.push(path.clone());
+            let mut w = BufWriter::new(file);
+            for col in &rs.columns {
+                storage::write_column(&mut w, &conditional::take(col, &part)?).map_err(VMError::Io)?;
+            }
+            w.flush().map_err(VMError::Io)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    // A new file in the spill directory, readable only by its owner. A name that's taken - by a
+    // file left over from a crash, or one someone else put there, symlinks included - is
+    // passed over rather than opened.
+    fn create_spill_file(&self) -> Result<(PathBuf, File), VMError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        for _ in 0 .. 100 {
+            let n = SPILLS.fetch_add(1, Ordering::Relaxed);
+            let path = self.spill_dir.join(format!("collie-join-{}-{}.part", std::process::id(), n));
+            match options.open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(VMError::Io(e))
+            }
+        }
+        Err(VMError::Io(io::Error::new(io::ErrorKind::AlreadyExists, "no free spill file name")))
+    }
+}
+
+#[cfg(feature = "std")]
+fn concat(parts: Vec<ResultSet>) -> Result<ResultSet, VMError> {
+    let first = match parts.first() {
+        Some(first) => first,
+        None => return Ok(ResultSet::new())
+    };
+    let mut res = ResultSet::new();
+    for (i, name) in first.names.iter().enumerate() {
+        let cols: Vec<Column> = parts.iter().map(|p| p.columns[i].clone()).collect();
+        res.push(name, Column::concat(&cols)?);
+    }
+    Ok(res)
+}
+
+// A partition of `like` back from its spill file
+#[cfg(feature = "std")]
+fn read_part(path: &Path, like: &ResultSet) -> Result<ResultSet, VMError> {
+    let mut r = BufReader::new(File::open(path).map_err(VMError::Io)?);
+    let mut res = ResultSet::new();
+    for (name, col) in like.iter() {
+        res.push(name, storage::read_column(&mut r, col.datatype()).map_err(VMError::Io)?);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::convert::TryFrom;
+
+    // `rows` orders joined to `customers` customers; a fifth of the orders' customers don't exist
+    fn orders(rows: usize, customers: usize) -> ResultSet {
+        let mut rs = ResultSet::new();
+        rs.push("order", Column::from((0 .. rows as u64).collect::<Vec<_>>()));
+        rs.push("customer", Column::from((0 .. rows).map(|i| (i * 7 % (customers * 5 / 4)) as u64).collect::<Vec<_>>()));
+        rs
+    }
+
+    fn customers(rows: usize) -> ResultSet {
+        let mut rs = ResultSet::new();
+        rs.push("id", Column::from((0 .. rows as u64).collect::<Vec<_>>()));
+        rs.push("name", Column::from((0 .. rows).map(|i| format!("customer {}", i)).collect::<Vec<_>>()));
+        rs
+    }
+
+    #[cfg(feature = "std")]
+    fn sorted_rows(rs: &ResultSet) -> Vec<Vec<Scalar>> {
+        let mut rows: Vec<_> = rs.iter_rows().collect();
+        rows.sort();
+        rows
+    }
+
+    // a spill directory of the test's own, to see what's left in it
+    #[cfg(feature = "std")]
+    fn spill_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("collie-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(feature = "std")]
+    fn files_in(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn spilled_join_matches_the_in_memory_one() {
+        let (left, right) = (orders(50_000, 2_000), customers(2_000));
+        let expected = hash_join(&left, "customer", &right, "id", NullSemantics::default()).unwrap();
+        assert_eq!(expected.rows(), 40_000);
+        let dir = spill_dir("grace-join");
+        let memory = MemoryManager::new(1 << 30);
+        let join = GraceJoin::new(4096).with_spill_dir(&dir).with_memory_manager(memory.clone());
+        let res = join.join(&left, "customer", &right, "id").unwrap();
+        assert_eq!(res.names, expected.names);
+        assert_eq!(sorted_rows(&res), sorted_rows(&expected));
+        assert_eq!(files_in(&dir), 0);
+        assert_eq!(memory.used(), 0);
+        assert!(memory.peak() > 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn joins_in_memory_within_budget() {
+        let (left, right) = (orders(1_000, 100), customers(100));
+        let dir = spill_dir("grace-join-in-memory");
+        let res = GraceJoin::new(1 << 30).with_spill_dir(&dir).join(&left, "customer", &right, "id").unwrap();
+        assert_eq!(res, hash_join(&left, "customer", &right, "id", NullSemantics::default()).unwrap());
+        assert_eq!(files_in(&dir), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn every_partition_count_gives_the_same_rows() {
+        let (left, right) = (orders(2_000, 300), customers(300));
+        let expected = sorted_rows(&hash_join(&left, "customer", &right, "id", NullSemantics::default()).unwrap());
+        for &partitions in &[1, 3, 64] {
+            let res = GraceJoin::new(0).with_partitions(partitions).join(&left, "customer", &right, "id").unwrap();
+            assert_eq!(sorted_rows(&res), expected, "{} partitions", partitions);
+        }
+    }
+
+    #[test]
+    fn keys_must_exist_and_match() {
+        let (left, right) = (orders(10, 4), customers(4));
+        assert!(matches!(hash_join(&left, "nope", &right, "id", NullSemantics::default()), Err(VMError::TypeError(_))));
+        #[cfg(feature = "std")]
+        assert!(matches!(GraceJoin::new(0).join(&left, "customer", &right, "name"), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn reports_spill_failures() {
+        let (left, right) = (orders(1_000, 100), customers(100));
+        let dir = spill_dir("grace-join-fails");
+        let missing = GraceJoin::new(0).with_spill_dir(dir.join("missing")).join(&left, "customer", &right, "id");
+        assert!(matches!(missing, Err(VMError::Io(_))));
+        assert_eq!(files_in(&dir), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn spills_when_the_memory_manager_is_short() {
+        let (left, right) = (orders(2_000, 300), customers(300));
+        let expected = sorted_rows(&hash_join(&left, "customer", &right, "id", NullSemantics::default()).unwrap());
+        // the join's own budget would hold the whole right side, but the manager's won't
+        let dir = spill_dir("grace-join-short");
+        let memory = MemoryManager::new(memory_usage(&right) / 2);
+        let join = GraceJoin::new(1 << 30).with_partitions(8).with_spill_dir(&dir).with_memory_manager(memory.clone());
+        assert_eq!(sorted_rows(&join.join(&left, "customer", &right, "id").unwrap()), expected);
+        assert!(memory.peak() > 0 && memory.peak() <= memory.budget());
+        assert_eq!(memory.used(), 0);
+        assert_eq!(files_in(&dir), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn cleans_up_when_a_partition_doesnt_fit() {
+        let (left, right) = (orders(1_000, 100), customers(100));
+        let dir = spill_dir("grace-join-out-of-memory");
+        let memory = MemoryManager::new(16);
+        let res = GraceJoin::new(0).with_spill_dir(&dir).with_memory_manager(memory.clone()).join(&left, "customer", &right, "id");
+        assert!(matches!(res, Err(VMError::OutOfMemory { consumer: Consumer::HashTable, available: 16, .. })));
+        assert_eq!(files_in(&dir), 0);
+        assert_eq!(memory.used(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn splits_partitions_that_are_still_too_big() {
+        let (left, right) = (orders(4_000, 1_000), customers(1_000));
+        let expected = sorted_rows(&hash_join(&left, "customer", &right, "id", NullSemantics::default()).unwrap());
+        // two partitions a level, each too big until split twice more
+        let dir = spill_dir("grace-join-split");
+        let budget = memory_usage(&right) / 6;
+        let memory = MemoryManager::new(1 << 30);
+        let join = GraceJoin::new(budget).with_partitions(2).with_spill_dir(&dir).with_memory_manager(memory.clone());
+        assert_eq!(sorted_rows(&join.join(&left, "customer", &right, "id").unwrap()), expected);
+        assert!(memory.peak() > 0 && memory.peak() <= budget, "{} > {}", memory.peak(), budget);
+        assert_eq!(files_in(&dir), 0);
+        fs::remove_dir(&dir).unwrap();
+
+        // a key no hash can split is joined whole
+        let mut hot = ResultSet::new();
+        hot.push("id", Column::from(vec![7u64; 500]));
+        hot.push("n", Column::from((0 .. 500u64).collect::<Vec<_>>()));
+        let mut probe = ResultSet::new();
+        probe.push("k", Column::from(vec![7u64, 8, 7]));
+        let res = GraceJoin::new(0).with_partitions(4).join(&probe, "k", &hot, "id").unwrap();
+        assert_eq!(res.rows(), 1_000);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn spill_files_pass_over_names_already_taken() {
+        let dir = spill_dir("grace-join-taken");
+        let next = SPILLS.load(Ordering::Relaxed);
+        let taken: Vec<PathBuf> = (next .. next + 20).map(|n| dir.join(format!("collie-join-{}-{}.part", std::process::id(), n))).collect();
+        taken.iter().for_each(|p| fs::write(p, b"not yours").unwrap());
+        let (path, _file) = GraceJoin::new(0).with_spill_dir(&dir).create_spill_file().unwrap();
+        assert!(!taken.contains(&path));
+        assert!(taken.iter().all(|p| fs::read(p).unwrap() == b"not yours"));
+        fs::remove_file(&path).unwrap();
+        taken.iter().for_each(|p| fs::remove_file(p).unwrap());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn null_keys_join_only_if_nulls_match() {
+        let mut left = ResultSet::new();
+        left.push("k", Column::from(vec![f64::NAN, 1.0, f64::NAN]));
+        left.push("row", Column::from(vec![0u64, 1, 2]));
+        let mut right = ResultSet::new();
+        right.push("k", Column::from(vec![1.0, f64::NAN]));
+        right.push("v", Column::from(vec!["one", "null"]));
+        let joined = |nulls| {
+            let res = hash_join(&left, "k", &right, "k", nulls).unwrap();
+            #[cfg(feature = "std")]
+            {
+                let spilled = GraceJoin::new(0).with_partitions(3).with_null_semantics(nulls).join(&left, "k", &right, "k").unwrap();
+                assert_eq!(sorted_rows(&spilled), sorted_rows(&res), "{:?}", nulls);
+            }
+            Vec::<u64>::try_from(res.column("row").unwrap()).unwrap()
+        };
+        assert_eq!(joined(NullSemantics::Sql), vec![1]);
+        assert_eq!(joined(NullSemantics::NullsDistinct), vec![1]);
+        assert_eq!(joined(NullSemantics::NullEqualsNull), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn positions_pair_up_every_match() {
+        let (lrows, rrows) = join_positions(&[7, 3, 9, 7], &[7, 1, 7, 3]);
+        assert_eq!(lrows, vec![0, 0, 1, 3, 3]);
+        assert_eq!(rrows, vec![0, 2, 3, 0, 2]);
+        assert_eq!(join_positions(&[1, 2], &[]), (vec![], vec![]));
+    }
+}
@@ -0,0 +1,552 @@
+// JSON documents, kept as text: a JsonColumn is an InlineStrColumn whose values are each one
+// valid JSON document, checked when the column is built. Nothing is parsed into a tree;
+// extract() walks each document's text along a path and converts the value it lands on,
+// which is what pulling a few fields out of semi-structured payloads needs.
+//
+// Paths follow the usual JSONPath subset: `$` for the document, `.name` or `["name"]` for
+// a member of an object, `[n]` for an element of an array - "$.user.tags[0]". The leading
+// `$` can be left out.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, ColumnT, InlineStrColumn, Scalar};
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonColumn {
+    pub(crate) docs: InlineStrColumn
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Key(String),
+    Index(usize)
+}
+
+impl JsonColumn {
+    // Fails, naming the row, if any document isn't valid JSON
+    pub fn parse<'a, I: IntoIterator<Item=&'a str>>(docs: I) -> Result<JsonColumn, VMError> {
+        let docs: InlineStrColumn = docs.into_iter().collect();
+        for (i, doc) in docs.iter().enumerate() {
+            validate(doc).map_err(|msg| VMError::TypeError(format!("Row {} isn't valid JSON: {}", i, msg)))?;
+        }
+        Ok(JsonColumn { docs })
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.docs.get(i)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&str> + '_ {
+        self.docs.iter()
+    }
+
+    pub fn slice(&self, offset: usize, len: usize) -> JsonColumn {
+        JsonColumn { docs: self.docs.slice(offset, len) }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.docs.memory_usage()
+    }
+
+    // The value at `path` in each document, as a `dtype` column: numbers for Num, whole
+    // non-negative ones for Entity, whole ones for Int, true/false for Bool, strings for Str,
+    // and any value at all, as a document of its own, for Json. Rows where the path leads
+    // nowhere, or to null or a value of another type, are returned in the selection; their
+    // value in the column is 0, false or "" (null, for Json).
+    pub fn extract(&self, path: &str, dtype: Datatype) -> Result<(Column, Selection), VMError> {
+        let steps = parse_path(path).ok_or_else(|| VMError::TypeError(format!("Invalid JSON path: {}", path)))?;
+        let values: Vec<Option<&str>> = self.iter().map(|doc| lookup(doc, &steps)).collect();
+        let mut missing = BitIndex::for_col_len(values.len());
+        let mut convert = |f: &dyn Fn(&str) -> bool| {
+            values.iter().enumerate().filter(|(_, v)| !v.is_some_and(f)).for_each(|(i, _)| missing.set(i));
+        };
+        let col = match dtype {
+            Datatype::Num => {
+                convert(&|v| number(v).is_some());
+                Column::from(values.iter().map(|v| v.and_then(number).unwrap_or(0.0)).collect::<Vec<f64>>())
+            },
+            Datatype::Entity => {
+                convert(&|v| v.parse::<u64>().is_ok());
+                Column::from(values.iter().map(|v| v.and_then(|v| v.parse().ok()).unwrap_or(0)).collect::<Vec<u64>>())
+            },
+            Datatype::Int => {
+                convert(&|v| v.parse::<i64>().is_ok());
+                Column::from(values.iter().map(|v| v.and_then(|v| v.parse().ok()).unwrap_or(0)).collect::<Vec<i64>>())
+            },
+            Datatype::Bool => {
+                convert(&|v| v == "true" || v == "false");
+                Column::from(values.iter().map(|v| *v == Some("true")).collect::<Vec<bool>>())
+            },
+            Datatype::Str => {
+                convert(&|v| v.starts_with('"'));
+                let strs: Vec<String> = values.iter()
+                    .map(|v| v.filter(|v| v.starts_with('"')).map(unescape).unwrap_or_default())
+                    .collect();
+                Column::from(strs)
+            },
+            Datatype::Json => {
+                convert(&|v| v != "null");
+                Column::Json(JsonColumn { docs: values.iter().map(|v| v.unwrap_or("null")).collect() })
+            },
+            other => return Err(VMError::TypeError(format!("Can't extract {} values from JSON", other)))
+        };
+        Ok((col, Selection::adaptive(missing)))
+    }
+}
+
+impl ColumnT for JsonColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    // Rows whose document is exactly the given text
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        match val {
+            Scalar::Json(doc) => self.docs.filter(Scalar::Str(doc)),
+            _ => Err(VMError::TypeError(format!("Expected a JSON value, got: {:?}", val)))
+        }
+    }
+
+    fn select(&self, mask: &BoolColumn) -> Self {
+        JsonColumn { docs: self.docs.select(mask) }
+    }
+}
+
+// Checks `doc` is one JSON value, with nothing but whitespace around it
+pub fn validate(doc: &str) -> Result<(), String> {
+    let mut p = Parser { s: doc.as_bytes(), pos: 0 };
+    p.value()?;
+    p.ws();
+    if p.pos < p.s.len() {
+        return Err(format!("unexpected text at byte {}", p.pos));
+    }
+    Ok(())
+}
+
+fn parse_path(path: &str) -> Option<Vec<Step>> {
+    let path = path.trim();
+    let dotted;
+    let mut rest = match path.strip_prefix('$') {
+        Some(rest) => rest,
+        // without the `$`, the path can start with a bare member name
+        None if !path.starts_with(['.', '[']) => { dotted = format!(".{}", path); &dotted },
+        None => path
+    };
+    let mut steps = Vec::new();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            if end == 0 {
+                return None;
+            }
+            steps.push(Step::Key(r[.. end].to_string()));
+            rest = &r[end ..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']')?;
+            let inner = r[.. end].trim();
+            if inner.starts_with('"') {
+                validate(inner).ok()?;
+                steps.push(Step::Key(unescape(inner)));
+            } else {
+                steps.push(Step::Index(inner.parse().ok()?));
+            }
+            rest = &r[end + 1 ..];
+        } else {
+            return None;
+        }
+    }
+    Some(steps)
+}
+
+// The text of the value at `steps` in `doc`, which must be valid
+fn lookup<'a>(doc: &'a str, steps: &[Step]) -> Option<&'a str> {
+    let mut p = Parser { s: doc.as_bytes(), pos: 0 };
+    for step in steps {
+        p.ws();
+        match (step, p.peek()) {
+            (Step::Key(key), Some(b'{')) => {
+                p.pos += 1;
+                loop {
+                    p.ws();
+                    if p.peek() != Some(b'"') {
+                        return None;    // `}`: not found
+                    }
+                    let start = p.pos;
+                    p.string().ok()?;
+                    let found = unescape(&doc[start .. p.pos]) == *key;
+                    p.ws();
+                    p.pos += 1;     // ':'
+                    if found {
+                        break;
+                    }
+                    p.value().ok()?;
+                    p.ws();
+                    if p.peek() == Some(b',') { p.pos += 1; }
+                }
+            },
+            (Step::Index(n), Some(b'[')) => {
+                p.pos += 1;
+                for _ in 0 .. *n {
+                    p.ws();
+                    if p.peek() == Some(b']') {
+                        return None;
+                    }
+                    p.value().ok()?;
+                    p.ws();
+                    if p.peek() == Some(b',') { p.pos += 1; }
+                }
+                p.ws();
+                if p.peek() == Some(b']') {
+                    return None;
+                }
+            },
+            _ => return None
+        }
+    }
+    p.ws();
+    let start = p.pos;
+    p.value().ok()?;
+    Some(&doc[start .. p.pos])
+}
+
+fn number(text: &str) -> Option<f64> {
+    match text.as_bytes().first() {
+        Some(b'-' | b'0' ..= b'9') => text.parse().ok(),
+        _ => None
+    }
+}
+
+// The contents of the quoted JSON string `text`, which must be valid, with escapes resolved
+fn unescape(text: &str) -> String {
+    let inner = &text[1 .. text.len() - 1];
+    if !inner.contains('\\') {
+        return inner.to_string();
+    }
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('u') => {
+                let hex = |chars: &mut core::str::Chars| {
+                    let digits: String = chars.take(4).collect();
+                    u32::from_str_radix(&digits, 16).unwrap_or(0xfffd)
+                };
+                let mut code = hex(&mut chars);
+                if (0xd800 .. 0xdc00).contains(&code) && chars.as_str().starts_with("\\u") {
+                    let mut ahead = chars.clone();
+                    ahead.nth(1);
+                    let low = hex(&mut ahead);
+                    if (0xdc00 .. 0xe000).contains(&low) {
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        chars = ahead;
+                    }
+                }
+                out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+            },
+            Some(c) => out.push(c),     // '"', '\\' and '/' stand for themselves
+            None => {}
+        }
+    }
+    out
+}
+
+// A cursor over JSON text. Each method consumes one thing at `pos` or fails saying why.
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        self.ws();
+        match self.peek() {
+            Some(x) if x == c => { self.pos += 1; Ok(()) },
+            _ => Err(format!("expected '{}' at byte {}", c as char, self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<(), String> {
+        self.ws();
+        match self.peek() {
+            Some(b'{') => self.sequence(b'}', |p| {
+                p.ws();
+                p.string()?;
+                p.expect(b':')?;
+                p.value()
+            }),
+            Some(b'[') => self.sequence(b']', Parser::value),
+            Some(b'"') => self.string(),
+            Some(b'-' | b'0' ..= b'9') => self.number(),
+            _ => ["true", "false", "null"].iter()
+                .find(|w| self.s[self.pos ..].starts_with(w.as_bytes()))
+                .map(|w| self.pos += w.len())
+                .ok_or_else(|| format!("expected a value at byte {}", self.pos))
+        }
+    }
+
+    // `{` or `[`, then items separated by commas, then `close`
+    fn sequence<F: Fn(&mut Self) -> Result<(), String>>(&mut self, close: u8, item: F) -> Result<(), String> {
+        self.pos += 1;
+        self.ws();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            item(self)?;
+            self.ws();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(c) if c == close => { self.pos += 1; return Ok(()) },
+                _ => return Err(format!("expected ',' or '{}' at byte {}", close as char, self.pos))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<(), String> {
+        if self.peek() != Some(b'"') {
+            return Err(format!("expected a string at byte {}", self.pos));
+        }
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                Some(b'"') => { self.pos += 1; return Ok(()) },
+                Some(b'\\') => {
+                    match self.s.get(self.pos + 1) {
+                        Some(b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => self.pos += 2,
+                        Some(b'u') if self.s.get(self.pos + 2 .. self.pos + 6).is_some_and(|h| h.iter().all(u8::is_ascii_hexdigit)) => self.pos += 6,
+                        _ => return Err(format!("invalid escape at byte {}", self.pos))
+                    }
+                },
+                Some(c) if c < 0x20 => return Err(format!("control character in a string at byte {}", self.pos)),
+                Some(_) => self.pos += 1,
+                None => return Err("unterminated string".to_string())
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<(), String> {
+        let start = self.pos;
+        let digits = |p: &mut Self| {
+            let from = p.pos;
+            while matches!(p.peek(), Some(b'0' ..= b'9')) { p.pos += 1; }
+            p.pos > from
+        };
+        if self.peek() == Some(b'-') { self.pos += 1; }
+        let leading_zero = self.peek() == Some(b'0');
+        let int_start = self.pos;
+        if !digits(self) || (leading_zero && self.pos - int_start > 1) {
+            return Err(format!("invalid number at byte {}", start));
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !digits(self) { return Err(format!("invalid number at byte {}", start)); }
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) { self.pos += 1; }
+            if !digits(self) { return Err(format!("invalid number at byte {}", start)); }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nulls::NullSemantics;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    // Each row exercises a different way a value can be there, missing or of the wrong type
+    const DOCS: [&str; 5] = [
+        r#"{"n": 1.5, "e": 7, "b": true, "s": "q\"é😀\n", "j": {"k": [1, null]}}"#,
+        r#"{"n": "1", "e": -1, "b": "true", "s": 5, "j": null}"#,
+        r#"{"e": 1.5}"#,
+        r#"[1]"#,
+        r#" { "n" : -2e3 , "e" : 18446744073709551615 , "b" : false , "s" : "" , "j" : [ ] } "#
+    ];
+
+    fn docs() -> JsonColumn {
+        JsonColumn::parse(DOCS.iter().copied()).unwrap()
+    }
+
+    fn missing(sel: &Selection) -> Vec<usize> {
+        (0 .. DOCS.len()).filter(|&i| sel.contains(i)).collect()
+    }
+
+    fn json(docs: &[&str]) -> Column {
+        Column::Json(JsonColumn::parse(docs.iter().copied()).unwrap())
+    }
+
+    #[test]
+    fn paths_parse_as_documented() {
+        let key = |k: &str| Step::Key(k.to_string());
+        assert_eq!(parse_path("$"), Some(vec![]));
+        assert_eq!(parse_path(" $.user.tags[0] "), Some(vec![key("user"), key("tags"), Step::Index(0)]));
+        assert_eq!(parse_path(r#"$["a.b"][ 12 ]["say \"hi\""]"#), Some(vec![key("a.b"), Step::Index(12), key("say \"hi\"")]));
+        // the `$` is optional
+        assert_eq!(parse_path("user.tags"), Some(vec![key("user"), key("tags")]));
+        assert_eq!(parse_path("[1]"), Some(vec![Step::Index(1)]));
+        assert_eq!(parse_path(".a"), Some(vec![key("a")]));
+        for bad in ["$..a", "$.", "$a", "$[x]", "$[-1]", "$[0", r#"$["a]"#, r#"$["a\q"]"#, "$.a[0]b"].iter() {
+            assert_eq!(parse_path(bad), None, "{}", bad);
+            assert!(matches!(docs().extract(bad, Datatype::Num), Err(VMError::TypeError(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn paths_find_nested_values() {
+        let col = JsonColumn::parse(vec![
+            r#"{"a": {"b": [10, 20]}, "a.b": 3, "\u0063": 4}"#,
+            r#"{"a": {"b": []}}"#,
+            r#"{"a": [{"b": [30]}]}"#
+        ]).unwrap();
+        let get = |path| {
+            let (res, sel) = col.extract(path, Datatype::Num).unwrap();
+            (0 .. col.len()).map(|i| if sel.contains(i) { None } else { res.get(i) }).collect::<Vec<_>>()
+        };
+        let n = |x| Some(Scalar::Num(x));
+        assert_eq!(get("$.a.b[0]"), vec![n(10.0), None, None]);
+        assert_eq!(get("$.a.b[1]"), vec![n(20.0), None, None]);
+        assert_eq!(get("$.a.b[2]"), vec![None, None, None]);
+        assert_eq!(get("$.a[0].b[0]"), vec![None, None, n(30.0)]);
+        assert_eq!(get(r#"$["a.b"]"#), vec![n(3.0), None, None]);
+        // keys match after unescaping, on either side
+        assert_eq!(get("$.c"), vec![n(4.0), None, None]);
+        assert_eq!(get(r#"$["\u0063"]"#), vec![n(4.0), None, None]);
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        let bad = [
+            "", " ", "{", "[1,]", "[1 2]", r#"{"a" 1}"#, r#"{a: 1}"#, "{\"a\": 1,}", "1 2", "{} x", "tru", "nul",
+            "01", "-", "1.", ".5", "1e", "+1", "\"abc", r#""\q""#, r#""\u12g4""#, "\"a\u{1}b\"", "'a'", "NaN"
+        ];
+        for doc in bad.iter() {
+            assert!(validate(doc).is_err(), "{:?}", doc);
+            match JsonColumn::parse(vec!["{}", "null", doc]) {
+                Err(VMError::TypeError(msg)) => assert!(msg.starts_with("Row 2 isn't valid JSON"), "{}", msg),
+                res => panic!("{:?} gave {:?}", doc, res)
+            }
+        }
+        assert_eq!(validate("{} x"), Err("unexpected text at byte 3".to_string()));
+        assert_eq!(validate("[01]"), Err("invalid number at byte 1".to_string()));
+        assert_eq!(validate(r#"["\x"]"#), Err("invalid escape at byte 2".to_string()));
+        for good in ["0", "-0.5e+3", "\"\"", " [ ] ", r#"{"a": [true, false, null, "\"\\\/\b\f\n\r\té"]}"#, "\"\u{e9}\""].iter() {
+            assert!(validate(good).is_ok(), "{:?}", good);
+        }
+    }
+
+    #[test]
+    fn extracts_numbers() {
+        let (col, sel) = docs().extract("$.n", Datatype::Num).unwrap();
+        crate::assert_columns_eq!(col, Column::from(vec![1.5, 0.0, 0.0, 0.0, -2000.0]));
+        // a string holding a number isn't one
+        assert_eq!(missing(&sel), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn extracts_entities_only_from_whole_non_negative_numbers() {
+        let (col, sel) = docs().extract("$.e", Datatype::Entity).unwrap();
+        crate::assert_columns_eq!(col, Column::from(vec![7u64, 0, 0, 0, u64::MAX]));
+        assert_eq!(missing(&sel), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn extracts_ints_from_whole_numbers_in_range() {
+        let (col, sel) = docs().extract("$.e", Datatype::Int).unwrap();
+        crate::assert_columns_eq!(col, Column::from(vec![7i64, -1, 0, 0, 0]));
+        assert_eq!(missing(&sel), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn extracts_bools() {
+        let (col, sel) = docs().extract("$.b", Datatype::Bool).unwrap();
+        crate::assert_columns_eq!(col, Column::from(vec![true, false, false, false, false]));
+        assert_eq!(missing(&sel), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn extracts_strings_unescaped() {
+        let (col, sel) = docs().extract("$.s", Datatype::Str).unwrap();
+        crate::assert_columns_eq!(col, Column::from(vec!["q\"\u{e9}\u{1f600}\n", "", "", "", ""]));
+        // an empty string is still a string
+        assert_eq!(missing(&sel), vec![1, 2, 3]);
+        // a lone surrogate can't be a char
+        let col = JsonColumn::parse(vec![r#"["\ud83d", "\ud83dx"]"#]).unwrap();
+        let (res, _) = col.extract("$[0]", Datatype::Str).unwrap();
+        crate::assert_columns_eq!(res, Column::from(vec!["\u{fffd}"]));
+        let (res, _) = col.extract("$[1]", Datatype::Str).unwrap();
+        crate::assert_columns_eq!(res, Column::from(vec!["\u{fffd}x"]));
+    }
+
+    #[test]
+    fn extracts_any_value_as_json() {
+        let (col, sel) = docs().extract("$.j", Datatype::Json).unwrap();
+        crate::assert_columns_eq!(col, json(&[r#"{"k": [1, null]}"#, "null", "null", "null", "[ ]"]));
+        // an explicit null counts as missing
+        assert_eq!(missing(&sel), vec![1, 2, 3]);
+        let (col, sel) = docs().extract("$", Datatype::Json).unwrap();
+        crate::assert_columns_eq!(col, json(&DOCS.map(str::trim)));
+        assert!(missing(&sel).is_empty());
+    }
+
+    #[test]
+    fn other_types_cant_be_extracted() {
+        for dtype in [Datatype::Duration, Datatype::Point, Datatype::Ipv4].iter() {
+            assert!(matches!(docs().extract("$.n", *dtype), Err(VMError::TypeError(_))), "{}", dtype);
+        }
+    }
+
+    #[test]
+    fn filters_by_exact_text() {
+        let col = json(&["{\"a\": 1}", "{\"a\":1}", "{\"a\": 1}"]);
+        let rows = |mask: BoolColumn| (0 .. 3).filter(|&i| mask.selection().contains(i)).collect::<Vec<_>>();
+        assert_eq!(rows(col.filter(Scalar::Json("{\"a\": 1}".to_string())).unwrap()), vec![0, 2]);
+        assert_eq!(rows(col.filter_in(&json(&["{\"a\":1}", "null"]), NullSemantics::default()).unwrap()), vec![1]);
+        assert!(matches!(col.filter(Scalar::Str("{\"a\": 1}".to_string())), Err(VMError::TypeError(_))));
+        assert!(matches!(col.filter_in(&Column::from(vec!["{\"a\": 1}"]), NullSemantics::default()), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn extracts_through_the_vm() {
+        let mut vm = VM::new(Table::from_columns(vec![Column::Json(docs()), Column::from(vec!["{}"; 5])]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::JsonExtract("$.e".to_string(), Datatype::Entity)]).unwrap();
+        crate::assert_columns_eq!(vm.column_of(vm.stack().last().unwrap()).unwrap(), &Column::from(vec![7u64, 0, 0, 0, u64::MAX]));
+        // strings that look like JSON aren't a JSON column
+        assert!(matches!(vm.run(vec![Op::Col(0, 1), Op::JsonExtract("$".to_string(), Datatype::Json)]), Err(VMError::TypeError(_))));
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::JsonExtract("$[".to_string(), Datatype::Num)]), Err(VMError::TypeError(_))));
+    }
+}
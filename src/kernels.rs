@@ -0,0 +1,311 @@
+// Filter kernels that build a mask a whole u64 word at a time, instead of testing and
+// setting one bit per element. On x86_64 with AVX2, equality compares 4 lanes per
+// instruction; everywhere else the portable path processes lanes in groups of 8, which
+// the compiler is generally able to vectorize on its own.
+
+use crate::bitindex::BitIndex;
+use crate::core::prelude::*;
+use crate::selection::{FilterPlan, Selection};
+
+const LANES: usize = 8;
+
+// Portable fallback: evaluate `pred` on 64 elements and pack the results into a word.
+pub fn mask_by<T: Copy, F: Fn(T) -> bool>(data: &[T], pred: F) -> BitIndex {
+    let mut words = Vec::with_capacity(data.len() / 64 + 1);
+    let mut chunks = data.chunks_exact(64);
+    for chunk in &mut chunks {
+        let mut word = 0u64;
+        for (g, group) in chunk.chunks_exact(LANES).enumerate() {
+            let mut bits = 0u64;
+            for (lane, x) in group.iter().enumerate() {
+                bits |= (pred(*x) as u64) << lane;
+            }
+            word |= bits << (g * LANES);
+        }
+        words.push(word);
+    }
+    let mut word = 0u64;
+    for (lane, x) in chunks.remainder().iter().enumerate() {
+        word |= (pred(*x) as u64) << lane;
+    }
+    words.push(word);
+    BitIndex::from_words(words, data.len())
+}
+
+// Rows an adaptive filter tests before deciding how to hold the rest of its result; a whole
+// number of words, so the sample's bitmap lines up with the rest's
+pub const SAMPLE_ROWS: usize = 8192;
+
+// The rows of `data` matching a predicate, given both as `mask`, a kernel building a bitmap,
+// and `pred`, the same test on one value. The first SAMPLE_ROWS are masked; if few enough of
+// them match that positions would be smaller (see Selection::adaptive), the rest are tested
+// one at a time and collected as positions, never building their bitmap. Otherwise the rest
+// are masked too.
+pub fn filter_adaptive<T: Copy, M, P>(data: &[T], mask: M, pred: P) -> (Selection, FilterPlan)
+    where M: Fn(&[T]) -> BitIndex, P: Fn(T) -> bool
+{
+    let sampled = data.len().min(SAMPLE_ROWS);
+    let head = mask(&data[.. sampled]);
+    let hits = head.count_ones();
+    let selectivity = if sampled == 0 { 0.0 } else { hits as f64 / sampled as f64 };
+
+    if hits * 32 < sampled {
+        let mut positions = Vec::with_capacity(hits * (data.len() / sampled));
+        head.for_each(|idx| positions.push(idx as u32));
+        for (i, x) in data[sampled ..].iter().enumerate() {
+            if pred(*x) { positions.push((sampled + i) as u32); }
+        }
+        (Selection::Indices { positions, len: data.len() }, FilterPlan::Scan { sampled, selectivity, positions: true })
+    } else {
+        let mut bits = BitIndex::for_col_len(data.len());
+        bits.or_at(0, &head);
+        bits.or_at(sampled, &mask(&data[sampled ..]));
+        (Selection::Bitmap(bits), FilterPlan::Scan { sampled, selectivity, positions: false })
+    }
+}
+
+// Detecting CPU features at runtime needs std; without it, AVX2 is only used if the build
+// targets it anyway (e.g. -C target-feature=+avx2).
+#[cfg(target_arch = "x86_64")]
+fn has_avx2() -> bool {
+    #[cfg(feature = "std")]
+    { std::is_x86_feature_detected!("avx2") }
+    #[cfg(not(feature = "std"))]
+    { cfg!(target_feature = "avx2") }
+}
+
+pub fn eq_f64(data: &[f64], val: f64) -> BitIndex {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_avx2() {
+            // SAFETY: we just checked the CPU supports AVX2
+            return unsafe { avx2::eq_f64(data, val) };
+        }
+    }
+    mask_by(data, |x| x == val)
+}
+
+pub fn eq_u64(data: &[u64], val: u64) -> BitIndex {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_avx2() {
+            // SAFETY: we just checked the CPU supports AVX2
+            return unsafe { avx2::eq_u64(data, val) };
+        }
+    }
+    mask_by(data, |x| x == val)
+}
+
+// For dictionary codes
+pub fn eq_u32(data: &[u32], val: u32) -> BitIndex {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_avx2() {
+            // SAFETY: we just checked the CPU supports AVX2
+            return unsafe { avx2::eq_u32(data, val) };
+        }
+    }
+    mask_by(data, |x| x == val)
+}
+
+// lo <= x < hi
+pub fn range_f64(data: &[f64], lo: f64, hi: f64) -> BitIndex {
+    mask_by(data, |x| lo <= x && x < hi)
+}
+
+// lo <= x < hi
+pub fn range_u64(data: &[u64], lo: u64, hi: u64) -> BitIndex {
+    mask_by(data, |x| lo <= x && x < hi)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use core::arch::x86_64::*;
+
+    use crate::bitindex::BitIndex;
+    use crate::core::prelude::*;
+
+    // The 64-bit kernels compare 4 lanes at a time, so 16 compares fill a word; eq_u32 does 8.
+    // Loads are unaligned since callers can pass any slice, but column data lives in
+    // 64-byte aligned Buffers, so in practice they never straddle a cache line.
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn eq_f64(data: &[f64], val: f64) -> BitIndex {
+        let needle = _mm256_set1_pd(val);
+        let mut words = Vec::with_capacity(data.len() / 64 + 1);
+        let mut chunks = data.chunks_exact(64);
+        for chunk in &mut chunks {
+            let mut word = 0u64;
+            for i in 0 .. 16 {
+                let xs = _mm256_loadu_pd(chunk.as_ptr().add(i * 4));
+                let eq = _mm256_cmp_pd::<_CMP_EQ_OQ>(xs, needle);
+                word |= (_mm256_movemask_pd(eq) as u64) << (i * 4);
+            }
+            words.push(word);
+        }
+        let mut word = 0u64;
+        for (lane, x) in chunks.remainder().iter().enumerate() {
+            word |= ((*x == val) as u64) << lane;
+        }
+        words.push(word);
+        BitIndex::from_words(words, data.len())
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn eq_u64(data: &[u64], val: u64) -> BitIndex {
+        let needle = _mm256_set1_epi64x(val as i64);
+        let mut words = Vec::with_capacity(data.len() / 64 + 1);
+        let mut chunks = data.chunks_exact(64);
+        for chunk in &mut chunks {
+            let mut word = 0u64;
+            for i in 0 .. 16 {
+                let xs = _mm256_loadu_si256(chunk.as_ptr().add(i * 4) as *const __m256i);
+                let eq = _mm256_cmpeq_epi64(xs, needle);
+                word |= (_mm256_movemask_pd(_mm256_castsi256_pd(eq)) as u64) << (i * 4);
+            }
+            words.push(word);
+        }
+        let mut word = 0u64;
+        for (lane, x) in chunks.remainder().iter().enumerate() {
+            word |= ((*x == val) as u64) << lane;
+        }
+        words.push(word);
+        BitIndex::from_words(words, data.len())
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn eq_u32(data: &[u32], val: u32) -> BitIndex {
+        let needle = _mm256_set1_epi32(val as i32);
+        let mut words = Vec::with_capacity(data.len() / 64 + 1);
+        let mut chunks = data.chunks_exact(64);
+        for chunk in &mut chunks {
+            let mut word = 0u64;
+            for i in 0 .. 8 {
+                let xs = _mm256_loadu_si256(chunk.as_ptr().add(i * 8) as *const __m256i);
+                let eq = _mm256_cmpeq_epi32(xs, needle);
+                word |= (_mm256_movemask_ps(_mm256_castsi256_ps(eq)) as u64) << (i * 8);
+            }
+            words.push(word);
+        }
+        let mut word = 0u64;
+        for (lane, x) in chunks.remainder().iter().enumerate() {
+            word |= ((*x == val) as u64) << lane;
+        }
+        words.push(word);
+        BitIndex::from_words(words, data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(b: &BitIndex) -> Vec<usize> {
+        let mut out = Vec::new();
+        b.for_each(|i| out.push(i));
+        out
+    }
+
+    fn expected<T: Copy>(data: &[T], pred: impl Fn(T) -> bool) -> Vec<usize> {
+        (0 .. data.len()).filter(|i| pred(data[*i])).collect()
+    }
+
+    // lengths around the word and lane sizes
+    const LENS: [usize; 10] = [0, 1, 7, 8, 63, 64, 65, 127, 128, 200];
+
+    fn nums(len: usize) -> Vec<f64> {
+        (0 .. len).map(|i| match i % 5 {
+            0 => 1.5,
+            1 => f64::NAN,
+            2 => -0.0,
+            _ => i as f64
+        }).collect()
+    }
+
+    #[test]
+    fn eq_matches_elementwise_comparison() {
+        for &len in &LENS {
+            let data = nums(len);
+            for &val in &[1.5, 0.0, f64::NAN, 64.0] {
+                let mask = eq_f64(&data, val);
+                assert_eq!(mask.len(), len);
+                assert_eq!(bits(&mask), expected(&data, |x| x == val), "{} rows, = {}", len, val);
+                assert_eq!(bits(&mask_by(&data, |x| x == val)), bits(&mask));
+            }
+            let ids: Vec<u64> = (0 .. len as u64).map(|i| if i % 64 == 63 { u64::MAX } else { i % 3 }).collect();
+            for &val in &[0, 2, 7, u64::MAX] {
+                assert_eq!(bits(&eq_u64(&ids, val)), expected(&ids, |x| x == val), "{} rows, = {}", len, val);
+            }
+            let codes: Vec<u32> = (0 .. len as u32).map(|i| if i % 64 == 63 { u32::MAX } else { i % 3 }).collect();
+            for &val in &[0, 2, 7, u32::MAX] {
+                assert_eq!(bits(&eq_u32(&codes, val)), expected(&codes, |x| x == val), "{} rows, = {}", len, val);
+            }
+        }
+    }
+
+    #[test]
+    fn range_is_half_open() {
+        for &len in &LENS {
+            let data = nums(len);
+            assert_eq!(bits(&range_f64(&data, 0.0, 64.0)), expected(&data, |x| (0.0 .. 64.0).contains(&x)), "{} rows", len);
+            let ids: Vec<u64> = (0 .. len as u64).collect();
+            assert_eq!(bits(&range_u64(&ids, 10, 70)), expected(&ids, |x| (10 .. 70).contains(&x)), "{} rows", len);
+            assert_eq!(bits(&range_u64(&ids, 5, 5)), Vec::<usize>::new());
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "std"))]
+    #[test]
+    fn avx2_agrees_with_the_portable_path() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        for &len in &LENS {
+            let data = nums(len);
+            let ids: Vec<u64> = (0 .. len as u64).map(|i| i % 4).collect();
+            // SAFETY: AVX2 is available, checked above
+            let codes: Vec<u32> = (0 .. len as u32).map(|i| i % 4).collect();
+            // SAFETY: AVX2 is available, checked above
+            let (f, u, c) = unsafe { (avx2::eq_f64(&data, 1.5), avx2::eq_u64(&ids, 3), avx2::eq_u32(&codes, 3)) };
+            assert_eq!(bits(&f), bits(&mask_by(&data, |x| x == 1.5)), "{} rows", len);
+            assert_eq!(bits(&u), bits(&mask_by(&ids, |x| x == 3)), "{} rows", len);
+            assert_eq!(bits(&c), bits(&mask_by(&codes, |x| x == 3)), "{} rows", len);
+        }
+    }
+
+    #[test]
+    fn adaptive_filters_hold_sparse_results_as_positions() {
+        for &len in &[0, 1, 100, SAMPLE_ROWS, SAMPLE_ROWS + 1, 3 * SAMPLE_ROWS + 5] {
+            let data: Vec<u64> = (0 .. len as u64).map(|i| i % 100).collect();
+            for &val in &[3, 200] {
+                let (sel, plan) = filter_adaptive(&data, |xs| eq_u64(xs, val), |x| x == val);
+                assert_eq!(sel.len(), len);
+                assert_eq!(bits(&sel.to_bitmap()), expected(&data, |x| x == val), "{} rows, = {}", len, val);
+                match plan {
+                    FilterPlan::Scan { sampled, positions, .. } => {
+                        assert_eq!(sampled, len.min(SAMPLE_ROWS));
+                        assert_eq!(positions, len > 0, "{} rows, = {}", len, val);
+                    },
+                    other => panic!("{:?}", other)
+                }
+            }
+            // a tenth of the rows is too many for positions
+            let (sel, plan) = filter_adaptive(&data, |xs| mask_by(xs, |x| x < 10), |x| x < 10);
+            assert_eq!(bits(&sel.to_bitmap()), expected(&data, |x| x < 10));
+            assert!(matches!(sel, Selection::Bitmap(_)));
+            if len >= 100 {
+                assert!(matches!(plan, FilterPlan::Scan { selectivity, positions: false, .. } if (selectivity - 0.1).abs() < 0.01), "{:?}", plan);
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_sample_decides() {
+        // nothing matches in the sample, everything after it: the rest still go to positions
+        let data: Vec<u64> = (0 .. 2 * SAMPLE_ROWS as u64).map(|i| (i >= SAMPLE_ROWS as u64) as u64).collect();
+        let (sel, plan) = filter_adaptive(&data, |xs| eq_u64(xs, 1), |x| x == 1);
+        assert_eq!(plan, FilterPlan::Scan { sampled: SAMPLE_ROWS, selectivity: 0.0, positions: true });
+        assert_eq!(sel.count_ones(), SAMPLE_ROWS);
+    }
+}
+
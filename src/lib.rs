@@ -0,0 +1,97 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod aggregate;
+#[cfg(feature = "std")]
+pub mod audit;
+pub mod column;
+pub mod compare;
+pub mod conditional;
+pub mod core;
+#[cfg(feature = "std")]
+pub mod csv;
+pub mod cursor;
+pub mod datagen;
+#[cfg(feature = "std")]
+pub mod db;
+pub mod delta;
+pub mod dict;
+pub mod duration;
+pub mod encoding;
+pub mod batch;
+pub mod bitindex;
+pub mod bitpack;
+pub mod buffer;
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod bytecode;
+#[cfg(feature = "std")]
+pub mod cache;
+pub mod cancel;
+pub mod catalog;
+pub mod categorical;
+pub mod chunked;
+pub mod collation;
+#[cfg(feature = "tui")]
+pub mod browse;
+pub mod errors;
+pub mod frame_of_ref;
+pub mod gapfill;
+pub mod geo;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod ip;
+pub mod json;
+pub mod join;
+pub mod kernels;
+pub mod list;
+pub mod memory;
+pub mod normalize;
+pub mod nulls;
+pub mod opcode;
+pub mod optimizer;
+#[cfg(feature = "std")]
+pub mod policy;
+pub mod primitive;
+pub mod result;
+pub mod record_column;
+pub mod rle;
+#[cfg(feature = "std")]
+pub mod sample;
+pub mod schema;
+pub mod selection;
+#[cfg(feature = "std")]
+pub mod setops;
+#[cfg(feature = "std")]
+pub mod shared;
+pub mod sketch;
+#[cfg(feature = "std")]
+pub mod storage;
+pub mod table;
+pub mod disasm;
+pub mod explain;
+#[cfg(feature = "std")]
+pub mod metrics;
+pub mod snapshot;
+pub mod stats;
+pub mod timestamp;
+pub mod trace;
+pub mod tracer;
+pub mod udf;
+#[cfg(feature = "std")]
+pub mod view;
+pub mod vector;
+pub mod verify;
+pub mod vm;
+#[cfg(feature = "std")]
+pub mod workload;
+
+pub use crate::catalog::Catalog;
+pub use crate::column::*;
+pub use crate::opcode::Op;
+pub use crate::errors::VMError;
+pub use crate::result::ResultSet;
+pub use crate::schema::{Datatype, Field, Schema};
+pub use crate::table::Table;
+pub use crate::vm::{ColumnMode, ColumnSlot, Value, VM};
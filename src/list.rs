@@ -0,0 +1,231 @@
+// List-valued columns, laid out as Arrow lays them out: the items of every list back to back
+// in one child column, and an offset per row into it - row i is items offsets[i] ..
+// offsets[i + 1], so there's one more offset than there are rows. Slices share both.
+//
+// GroupBy makes them: the rows of a value column partitioned by a key column, one list per
+// distinct key, which the aggregate ops then reduce a list at a time (see aggregate.rs). Rows
+// filter by the whole list (FilterEq with a Scalar::List), by an item in it (FilterContains),
+// or by its length (ListLen, then the usual comparisons).
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Column, ColumnT, Scalar};
+use crate::conditional;
+use crate::core::prelude::*;
+use crate::core::HashMap;
+use crate::errors::VMError;
+use crate::schema::Datatype;
+
+use alloc::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct ListColumn {
+    offsets: Buffer<usize>,
+    items: Arc<Column>
+}
+
+impl ListColumn {
+    // Lists of `items`, split at `offsets`: those must start at 0, never decrease, and end at
+    // the number of items
+    pub fn new(offsets: Vec<usize>, items: Column) -> Result<ListColumn, VMError> {
+        let (first, last) = (offsets.first().copied(), offsets.last().copied());
+        if first != Some(0) || (1 .. offsets.len()).any(|i| offsets[i] < offsets[i - 1]) {
+            return Err(VMError::TypeError(format!("List offsets must start at 0 and never decrease, got: {:?}", offsets)));
+        }
+        if last != Some(items.len()) {
+            return Err(VMError::LengthMismatch { expected: items.len(), found: last.unwrap_or(0) });
+        }
+        Ok(ListColumn { offsets: Buffer::from(offsets), items: Arc::new(items) })
+    }
+
+    // A list per group of rows of `items`, each group given by its row numbers
+    pub fn from_groups(items: &Column, groups: &[Vec<usize>]) -> Result<ListColumn, VMError> {
+        let mut offsets = Vec::with_capacity(groups.len() + 1);
+        offsets.push(0);
+        groups.iter().for_each(|g| offsets.push(offsets[offsets.len() - 1] + g.len()));
+        let rows: Vec<usize> = groups.iter().flatten().copied().collect();
+        ListColumn::new(offsets, conditional::take(items, &rows)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The type of the items
+    pub fn item_type(&self) -> Datatype {
+        self.items.datatype()
+    }
+
+    // The items of every row, including any outside a slice's rows
+    pub fn items(&self) -> &Column {
+        &self.items
+    }
+
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    // Row i's items, which must be in bounds; shares the child column
+    pub fn list(&self, i: usize) -> Column {
+        let (start, end) = (self.offsets[i], self.offsets[i + 1]);
+        self.items.slice(start, end - start)
+    }
+
+    pub fn list_len(&self, i: usize) -> usize {
+        self.offsets[i + 1] - self.offsets[i]
+    }
+
+    // The number of items in each row's list
+    pub fn lengths(&self) -> Column {
+        Column::from((0 .. self.len()).map(|i| self.list_len(i) as u64).collect::<Vec<u64>>())
+    }
+
+    // Rows whose list has an item equal to `val`, as the items' own filter has it, so a null
+    // never matches and a value of another type is a type error
+    pub fn filter_contains(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        let (start, end) = (self.offsets[0], self.offsets[self.len()]);
+        let found = self.items.slice(start, end - start).filter(val)?;
+        let found = found.selection();
+        let mut mask = BitIndex::for_col_len(self.len());
+        (0 .. self.len())
+            .filter(|i| (self.offsets[*i] .. self.offsets[i + 1]).any(|j| found.contains(j - start)))
+            .for_each(|i| mask.set(i));
+        Ok(BoolColumn::from_mask(mask))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=Column> + '_ {
+        (0 .. self.len()).map(move |i| self.list(i))
+    }
+
+    pub fn get(&self, i: usize) -> Option<Scalar> {
+        if i >= self.len() {
+            return None;
+        }
+        let list = self.list(i);
+        Some(Scalar::List((0 .. list.len()).filter_map(|j| list.get(j)).collect()))
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.offsets.memory_usage() + self.items.memory_usage()
+    }
+
+    // Rows offset .. offset + len, which must be in bounds
+    pub fn slice(&self, offset: usize, len: usize) -> ListColumn {
+        ListColumn { offsets: self.offsets.slice(offset, len + 1), items: self.items.clone() }
+    }
+
+    // The lists at `rows`, in that order, with their items copied out
+    pub fn take(&self, rows: &[usize]) -> Result<ListColumn, VMError> {
+        let groups: Vec<Vec<usize>> = rows.iter().map(|r| (self.offsets[*r] .. self.offsets[r + 1]).collect()).collect();
+        ListColumn::from_groups(&self.items, &groups)
+    }
+}
+
+impl ColumnT for ListColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    // Rows whose list is the given one, item by item
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        if !matches!(val, Scalar::List(_)) {
+            return Err(VMError::TypeError(format!("Expected a list value, got: {:?}", val)));
+        }
+        let mut mask = BitIndex::for_col_len(self.len());
+        (0 .. self.len()).filter(|i| self.get(*i).as_ref() == Some(&val)).for_each(|i| mask.set(i));
+        Ok(BoolColumn::from_mask(mask))
+    }
+
+    fn select(&self, mask: &BoolColumn) -> Self {
+        let mut rows = Vec::with_capacity(mask.selection().count_ones());
+        mask.selection().for_each(|i| rows.push(i));
+        self.take(&rows).expect("the items can be taken, having been put in a column")
+    }
+}
+
+pub(crate) fn expect_lists(col: &Column) -> Result<&ListColumn, VMError> {
+    match col {
+        Column::List(c) => Ok(c),
+        _ => Err(VMError::TypeError(format!("Expected a List column, found a {} column", col.datatype())))
+    }
+}
+
+// Same items in the same lists
+impl PartialEq for ListColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self.item_type() == other.item_type()
+            && (0 .. self.len()).all(|i| self.list_len(i) == other.list_len(i) && self.list(i) == other.list(i))
+    }
+}
+
+// The rows of `values` partitioned by `keys`: the distinct keys, in order of first appearance,
+// and a list of the values of each one's rows, in row order. Keys match as Scalars compare
+// (see compare.rs), so nulls (NaN) make a group of their own, as in SQL.
+pub fn group_by(keys: &Column, values: &Column) -> Result<(Column, ListColumn), VMError> {
+    if keys.len() != values.len() {
+        return Err(VMError::LengthMismatch { expected: keys.len(), found: values.len() });
+    }
+    let mut ids: HashMap<Scalar, usize> = HashMap::new();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for row in 0 .. keys.len() {
+        let id = *ids.entry(keys.get(row).expect("row in bounds")).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[id].push(row);
+    }
+    let firsts: Vec<usize> = groups.iter().map(|g| g[0]).collect();
+    Ok((conditional::take(keys, &firsts)?, ListColumn::from_groups(values, &groups)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_split_the_items() {
+        let lists = ListColumn::new(vec![0, 2, 2, 5], Column::from(vec![1.0, 2.0, 3.0, 4.0, 5.0])).unwrap();
+        assert_eq!(lists.len(), 3);
+        assert_eq!(lists.list(1), Column::from(Vec::<f64>::new()));
+        assert_eq!(lists.get(2), Some(Scalar::List(vec![Scalar::Num(3.0), Scalar::Num(4.0), Scalar::Num(5.0)])));
+        let tail = lists.slice(1, 2);
+        assert_eq!((tail.len(), tail.list_len(0), tail.list(1)), (2, 0, Column::from(vec![3.0, 4.0, 5.0])));
+        assert_eq!(lists.take(&[2, 0]).unwrap(), ListColumn::new(vec![0, 3, 5], Column::from(vec![3.0, 4.0, 5.0, 1.0, 2.0])).unwrap());
+        assert!(matches!(ListColumn::new(vec![0, 3, 2], Column::from(vec![1.0, 2.0])), Err(VMError::TypeError(_))));
+        assert!(matches!(ListColumn::new(vec![0, 1], Column::from(vec![1.0, 2.0])), Err(VMError::LengthMismatch { expected: 2, found: 1 })));
+    }
+
+    #[test]
+    fn groups_in_order_of_first_appearance() {
+        let keys = Column::from(vec!["b", "a", "b", "c", "a"]);
+        let values = Column::from(vec![1u64, 2, 3, 4, 5]);
+        let (distinct, lists) = group_by(&keys, &values).unwrap();
+        assert_eq!(distinct, Column::from(vec!["b", "a", "c"]));
+        assert_eq!(lists, ListColumn::new(vec![0, 2, 4, 5], Column::from(vec![1u64, 3, 2, 5, 4])).unwrap());
+        // nulls group together
+        let (distinct, lists) = group_by(&Column::from(vec![f64::NAN, 1.0, f64::NAN]), &values.slice(0, 3)).unwrap();
+        assert_eq!((distinct.len(), lists.list(0)), (2, Column::from(vec![1u64, 3])));
+        assert!(matches!(group_by(&keys, &values.slice(0, 2)), Err(VMError::LengthMismatch { expected: 5, found: 2 })));
+    }
+
+    #[test]
+    fn filters_by_item_and_length() {
+        let lists = ListColumn::new(vec![0, 2, 2, 5, 6], Column::from(vec![1.0, 2.0, 2.0, f64::NAN, 4.0, 1.0])).unwrap();
+        let rows = |mask: BoolColumn| (0 .. lists.len()).filter(|i| mask.selection().contains(*i)).collect::<Vec<_>>();
+        assert_eq!(lists.lengths(), Column::from(vec![2u64, 0, 3, 1]));
+        assert_eq!(rows(lists.filter_contains(Scalar::Num(1.0)).unwrap()), vec![0, 3]);
+        assert_eq!(rows(lists.filter_contains(Scalar::Num(2.0)).unwrap()), vec![0, 2]);
+        assert_eq!(rows(lists.filter_contains(Scalar::Num(f64::NAN)).unwrap()), Vec::<usize>::new());
+        assert!(matches!(lists.filter_contains(Scalar::Str("a".to_string())), Err(VMError::TypeError(_))));
+        // a slice's rows, not the items around them
+        let tail = lists.slice(2, 2);
+        assert_eq!(tail.filter_contains(Scalar::Num(1.0)).unwrap().selection().count_ones(), 1);
+        assert_eq!(tail.lengths(), Column::from(vec![3u64, 1]));
+        assert_eq!(rows(lists.filter(Scalar::List(vec![Scalar::Num(1.0), Scalar::Num(2.0)])).unwrap()), vec![0]);
+    }
+}
@@ -1,149 +1,83 @@
-use std::rc::Rc;
-
-mod column;
-mod bitindex;
-mod errors;
-mod opcode;
-use crate::column::*;
-use crate::opcode::Op;
-use crate::errors::VMError;
-
-// TODO
-// - wrap Scalar::Str in rc
-// - profile, try to figure out how bad rc overhead is
-// - consider alternatives to rc, most likely unsafe moving of ptrs, or implementing your own Heap
-// - ... all the language features ...
-// - figure out what to do about non-primitive type columns:
-//  - list-valued columns (necessary for current impl of group by/agg)
-//  - struct/record-type columns, unless you're *very* religious about normalization.
-
-#[derive(Debug)]
-pub enum Value {
-    // A value on the Stack.
-    Scalar(Scalar),
-    ColumnRef(Rc<Column>)
-}
-
-pub struct VM {
-    code: Vec<Op>,
-    ip: usize,
-    stack: Vec<Value>,
-    columns: Vec<Rc<Column>>
-}
-
-// so what SHOULD be done with the col reference when pushing on stack
-// if we wanted to avoid the overhead of RC?
-// Op::Col can "move" ownership of the ref from `self.columns` to `self.stack` theoretically,
-// but unless we std::mem::take the val out of the vec (or remove it, and shift the rest of the elems)
-// a ref will also remain in the vec too which Rust considers invalid
-// we *think* that only one of these will be used at a time -- because of the serial nature of
-// push/pop off the stack -- and because 1) only Op::Col will refer into `self.columns`, other opcodes
-// (or their helpers) never work with column indices directly, they just pop them off the stack and
-// 2) a correct compiler will never generate two Op::Col(i) for the same i, without some other
-// opcode in between that pops that ColumnRef off the stack. But the compiler doesn't know that.
-// so I think we have to use Rc here, or unsafe.
-// profile and see how big the overhead of refcounting is -- likely not that bad, if it's amortized
-//  over columns.
-
-
-impl VM {
-    pub fn new(columns: Vec<Column>) -> Self {
-        // take ownership of columns and wrap them in rc's
-        let rcs = columns.into_iter().map(|col| Rc::new(col)).collect();
-        VM { code: Vec::new(), ip: 0, stack: Vec::new(), columns: rcs }
-    }
-
-    // Associated functions so they can borrow part of self, rather than borrowing all of self as mut
-    fn pop_scalar(stack: &mut Vec<Value>) -> Result<Scalar, VMError> {
-        if let Some(Value::Scalar(s)) = stack.pop() { return Ok(s); }
-        return Err(VMError::TypeError(format!("expected a scalar value")))
-    }
-
-    fn pop_column(stack: &mut Vec<Value>) -> Result<Rc<Column>, VMError> {
-        if let Some(Value::ColumnRef(c)) = stack.pop() { return Ok(c); }
-        return Err(VMError::TypeError(format!("expected a column value")))
-    }
-
-    fn expect_col_bool(v: Rc<Column>) -> Result<BoolColumn, VMError> {
-        // is there a better way to do this?
-        let res = Rc::try_unwrap(v).unwrap();
-        if let Column::Bool(inner) = res {
-            return Ok(inner);
-        }
-        Err(VMError::TypeError(format!("Type error: expected a boolean column, found: {:?}", res)))
-    }
-
-    pub fn run(&mut self, code: Vec<Op>) -> Result<(), VMError>  {
-        self.code = code;
-
-        while self.ip < self.code.len() {
-            let op = &self.code[self.ip];
-            self.ip += 1;
-
-            println!("Stack: {:?}", self.stack);
-            println!("Op: {:?}", op);
-
-            match op {
-
-                Op::Lit(s) => self.stack.push(Value::Scalar(s.clone())),
-
-                // panics(?) if idx is not a valid column idx
-                Op::Col(idx) => self.stack.push(
-                    Value::ColumnRef(self.columns[*idx].clone())    // Clone the RC = inc reference
-                ),
-
-                Op::FilterEq => {
-                    // TOS is a scalar. TOS-1 is a column.
-                    // Push a new column of positions
-                    let s = VM::pop_scalar(&mut self.stack)?;
-                    let col = VM::pop_column(&mut self.stack)?;
-                    let new_col = Column::Bool(col.filter(s)?);
-                    self.stack.push(Value::ColumnRef(Rc::new(new_col)));
-                },
-
-                Op::Select(_) => {
-                    // todo: select multiple
-                    let data = VM::pop_column(&mut self.stack)?;
-                    let selector = VM::pop_column(&mut self.stack)?;
-                    let selector = VM::expect_col_bool(selector)?;
-                    let new_col = Column::from(data.select(&selector));
-                    self.stack.push(Value::ColumnRef(Rc::new(new_col)));
-                }
-
-                _ => { return Err(VMError::IllegalOpcode); }
-
-            }
-        }
-
-
-        Ok(())
-    }
-
-}
-
+use collie::*;
+use collie::disasm::disassemble;
 
 fn test_vm() {
     let persons: Vec<Column> = vec![
-        Column::from(vec!["alice", "bob", "carol", "dave"]),  // col 0 - name
-        Column::from(vec![18.0, 42.0, 34.0, 20.0]),      // col 1 - age
-        Column::InlineStr(InlineStrColumn::from_strs(vec!["f", "m", "f", "m"]))
+        col!["alice", "bob", "carol", "dave"],  // col 0 - name
+        col![18.0, 42.0, 34.0, 20.0],           // col 1 - age
+        col!["f", "m", "f", "m"]
     ];
+    let schema = Schema::from(vec![
+        ("name", Datatype::Str),
+        ("age", Datatype::Num),
+        ("sex", Datatype::Str)
+    ]);
 
     let code = vec![
-        Op::Col(2),                 // load column 2 (sex)
+        Op::Col(0, 2),                 // load column 2 (sex)
         Op::Lit(Scalar::Str("f".to_string())),  // load scalar
         Op::FilterEq,               // pop both and push a bit mask of equal positions
-        Op::Col(0),                 // load col 0 (name)
+        Op::Col(0, 0),                 // load col 0 (name)
         Op::Select(1)               // pop the column and bit mask, push a new column
 
     ];
+    print!("{}", disassemble(&code, &schema));
 
-    let mut vm = VM::new(persons);
-    vm.run(code);
-    println!("{:?}", vm.stack);
+    let mut vm = VM::new(Table::with_schema(schema, persons).expect("the columns of one table"));
+    vm.set_verbose(true);
+    if let Err(e) = vm.run(code) {
+        println!("Error: {:?}\n{}", e, vm.snapshot());
+        return;
+    }
+    let mut result = ResultSet::new();
+    for (i, col) in vm.into_columns().expect("a column result").into_iter().enumerate() {
+        result.push(&format!("#{}", i), col);
+    }
+    println!("{}", result);
 }
 
 
+// Sample tables to look at: the people table from datagen, encoded as it would be on load
+#[cfg(feature = "tui")]
+fn browse() {
+    let (schema, columns) = collie::datagen::people(10_000, 42);
+    let people: Vec<(&str, Column)> = schema.fields.iter()
+        .map(|f| f.name.as_str())
+        .zip(columns.into_iter().map(|c| c.auto_encode()))
+        .collect();
+    let tables = vec![("people".to_string(), ResultSet::from(people))];
+    if let Err(e) = collie::browse::browse(tables) {
+        eprintln!("Error: {}", e);
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn browse() {
+    eprintln!("collie was built without the `tui` feature; rebuild with --features tui");
+    std::process::exit(1);
+}
+
+// Check the engine's answers to the workload queries: `collie workload [customers]`
+fn workload(customers: usize) {
+    let w = collie::workload::Workload::generate(customers, 42);
+    println!("{} customers, {} orders, {} line items", w.customers.len(), w.orders.len(), w.lineitems.len());
+    let outcomes = collie::workload::check(&w);
+    for o in &outcomes {
+        match &o.result {
+            Ok(()) => println!("ok    {:<20} {:?}", o.query, o.elapsed),
+            Err(e) => println!("FAIL  {:<20} {}", o.query, e)
+        }
+    }
+    if outcomes.iter().any(|o| o.result.is_err()) {
+        std::process::exit(1);
+    }
+}
+
 fn main() {
-    test_vm()
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("browse") => browse(),
+        Some("workload") => workload(args.next().and_then(|n| n.parse().ok()).unwrap_or(1000)),
+        _ => test_vm()
+    }
 }
@@ -0,0 +1,208 @@
+// A memory budget shared by the operators of any number of queries. An operator reserves
+// what it's about to hold - a join's hash table, the intermediate columns on a VM's stack -
+// before holding it, and gets a Reservation back that returns the bytes when dropped. When a
+// reservation doesn't fit, an operator that can spill (GraceJoin) does; anything else fails
+// with VMError::OutOfMemory, naming what asked for how much, instead of the process being
+// killed for running out.
+//
+// Sizes are the columns' memory_usage, an estimate of what they hold rather than a count of
+// allocations, and reservations are taken just ahead of use - so the budget is approximate,
+// and should be set with some headroom below the memory actually available.
+
+use crate::errors::VMError;
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// What memory is reserved for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Consumer {
+    HashTable,      // the build side of a join, or the groups of a group-by
+    SortRun,        // what's held sorting one run
+    Intermediate    // columns made by ops partway through a program
+}
+
+const CONSUMERS: [Consumer; 3] = [Consumer::HashTable, Consumer::SortRun, Consumer::Intermediate];
+
+impl fmt::Display for Consumer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Consumer::HashTable => write!(f, "hash table"),
+            Consumer::SortRun => write!(f, "sort run"),
+            Consumer::Intermediate => write!(f, "intermediate columns")
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryManager {
+    budget: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    by_consumer: [AtomicUsize; 3]   // indexed as CONSUMERS
+}
+
+impl MemoryManager {
+    pub fn new(budget: usize) -> Arc<Self> {
+        Arc::new(MemoryManager {
+            budget, used: AtomicUsize::new(0), peak: AtomicUsize::new(0),
+            by_consumer: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]
+        })
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    // Bytes reserved right now
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    // The most ever reserved at once
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn used_by(&self, consumer: Consumer) -> usize {
+        self.by_consumer[index(consumer)].load(Ordering::Relaxed)
+    }
+
+    // `bytes` for `consumer`, if they fit in what's left of the budget
+    pub fn try_reserve(self: &Arc<Self>, consumer: Consumer, bytes: usize) -> Result<Reservation, VMError> {
+        self.acquire(consumer, bytes)?;
+        Ok(Reservation { manager: self.clone(), consumer, bytes })
+    }
+
+    fn acquire(&self, consumer: Consumer, bytes: usize) -> Result<(), VMError> {
+        let fits = |used: usize| used.checked_add(bytes).filter(|total| *total <= self.budget);
+        match self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, fits) {
+            Ok(before) => {
+                self.peak.fetch_max(before + bytes, Ordering::Relaxed);
+                self.by_consumer[index(consumer)].fetch_add(bytes, Ordering::Relaxed);
+                Ok(())
+            },
+            Err(used) => Err(VMError::OutOfMemory { consumer, requested: bytes, available: self.budget.saturating_sub(used) })
+        }
+    }
+
+    fn release(&self, consumer: Consumer, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+        self.by_consumer[index(consumer)].fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+fn index(consumer: Consumer) -> usize {
+    CONSUMERS.iter().position(|c| *c == consumer).unwrap()
+}
+
+// Bytes held against a MemoryManager's budget, until dropped
+#[derive(Debug)]
+pub struct Reservation {
+    manager: Arc<MemoryManager>,
+    consumer: Consumer,
+    bytes: usize
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Grow or shrink to `bytes`. Growing fails if the difference doesn't fit, leaving the
+    // reservation as it was.
+    pub fn resize(&mut self, bytes: usize) -> Result<(), VMError> {
+        if bytes > self.bytes {
+            self.manager.acquire(self.consumer, bytes - self.bytes)?;
+        } else {
+            self.manager.release(self.consumer, self.bytes - bytes);
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.manager.release(self.consumer, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{Column, Scalar};
+    use crate::core::prelude::*;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    #[test]
+    fn reservations_hold_bytes_until_dropped() {
+        let memory = MemoryManager::new(100);
+        let a = memory.try_reserve(Consumer::HashTable, 60).unwrap();
+        let b = memory.try_reserve(Consumer::SortRun, 30).unwrap();
+        assert_eq!((memory.used(), memory.used_by(Consumer::HashTable), memory.used_by(Consumer::SortRun)), (90, 60, 30));
+        assert_eq!(memory.used_by(Consumer::Intermediate), 0);
+        assert_eq!(a.bytes(), 60);
+        drop(a);
+        assert_eq!((memory.used(), memory.used_by(Consumer::HashTable)), (30, 0));
+        drop(b);
+        assert_eq!((memory.used(), memory.peak()), (0, 90));
+    }
+
+    #[test]
+    fn refuses_what_doesnt_fit() {
+        let memory = MemoryManager::new(100);
+        let _held = memory.try_reserve(Consumer::HashTable, 70).unwrap();
+        match memory.try_reserve(Consumer::SortRun, 31) {
+            Err(VMError::OutOfMemory { consumer, requested, available }) => {
+                assert_eq!((consumer, requested, available), (Consumer::SortRun, 31, 30));
+            },
+            other => panic!("{:?}", other)
+        }
+        assert!(memory.try_reserve(Consumer::SortRun, usize::MAX).is_err());
+        // a failed reservation takes nothing
+        assert_eq!((memory.used(), memory.used_by(Consumer::SortRun)), (70, 0));
+        assert!(memory.try_reserve(Consumer::SortRun, 30).is_ok());
+        assert_eq!(Consumer::Intermediate.to_string(), "intermediate columns");
+    }
+
+    #[test]
+    fn reservations_resize() {
+        let memory = MemoryManager::new(100);
+        let mut r = memory.try_reserve(Consumer::Intermediate, 10).unwrap();
+        r.resize(80).unwrap();
+        assert_eq!(memory.used(), 80);
+        assert!(r.resize(101).is_err());
+        assert_eq!((r.bytes(), memory.used()), (80, 80));
+        r.resize(5).unwrap();
+        assert_eq!((memory.used(), memory.used_by(Consumer::Intermediate), memory.peak()), (5, 5, 80));
+        drop(r);
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[test]
+    fn the_vm_holds_only_the_columns_it_makes() {
+        let big = Column::from((0 .. 10_000u64).collect::<Vec<_>>());
+        let size = big.memory_usage();
+        // loading a column costs nothing; a filter's mask and selected copy do
+        let code = vec![Op::Col(0, 0), Op::Col(0, 0), Op::Lit(Scalar::Entity(5)), Op::FilterEq, Op::Col(0, 0), Op::Select(1)];
+        let run = |budget| {
+            let memory = MemoryManager::new(budget);
+            let mut vm = VM::new(Table::from_columns(vec![big.clone()]).unwrap());
+            vm.set_memory_manager(memory.clone());
+            let res = vm.run(code.clone());
+            (res, memory)
+        };
+        let (res, memory) = run(size / 2);
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(memory.peak() > 0 && memory.peak() < size / 2);
+        assert_eq!(memory.used(), 0);
+
+        let (res, memory) = run(64);
+        assert!(matches!(res, Err(VMError::OutOfMemory { consumer: Consumer::Intermediate, .. })), "{:?}", res);
+        assert_eq!(memory.used(), 0);
+    }
+}
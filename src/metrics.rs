@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Counters shared between any number of VMs and a metrics endpoint, exported in the
+// Prometheus text format. VMs only touch these if one is attached with VM::set_metrics.
+// The memory gauge is the sum over the attached VMs: each adds what its columns hold, and
+// takes it back off when it's dropped or attached elsewhere.
+
+#[derive(Debug, Default, Clone, Copy)]
+struct OpStats {
+    count: u64,
+    nanos: u64
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    queries: AtomicU64,
+    query_errors: AtomicU64,
+    query_nanos: AtomicU64,
+    rows_scanned: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    memory_bytes: AtomicU64,
+    // keyed by opcode mnemonic; BTreeMap so the exported lines come out in a stable order
+    ops: Mutex<BTreeMap<&'static str, OpStats>>
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn record_query(&self, elapsed: Duration, ok: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.query_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if !ok {
+            self.query_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_op(&self, mnemonic: &'static str, elapsed: Duration) {
+        let mut ops = self.ops.lock().unwrap();
+        let stats = ops.entry(mnemonic).or_default();
+        stats.count += 1;
+        stats.nanos += elapsed.as_nanos() as u64;
+    }
+
+    pub fn add_rows_scanned(&self, rows: usize) {
+        self.rows_scanned.fetch_add(rows as u64, Ordering::Relaxed);
+    }
+
+    // A lookup in a result cache, and whether it found the result
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit { &self.cache_hits } else { &self.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_memory_bytes(&self, bytes: usize) {
+        self.memory_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sub_memory_bytes(&self, bytes: usize) {
+        self.memory_bytes.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let secs = |nanos: u64| nanos as f64 / 1e9;
+
+        // writing to a String can't fail
+        writeln!(out, "# HELP collie_queries_total Programs executed.").unwrap();
+        writeln!(out, "# TYPE collie_queries_total counter").unwrap();
+        writeln!(out, "collie_queries_total {}", self.queries.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP collie_query_errors_total Programs that returned an error.").unwrap();
+        writeln!(out, "# TYPE collie_query_errors_total counter").unwrap();
+        writeln!(out, "collie_query_errors_total {}", self.query_errors.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP collie_query_duration_seconds Wall time spent executing programs.").unwrap();
+        writeln!(out, "# TYPE collie_query_duration_seconds summary").unwrap();
+        writeln!(out, "collie_query_duration_seconds_sum {}", secs(self.query_nanos.load(Ordering::Relaxed))).unwrap();
+        writeln!(out, "collie_query_duration_seconds_count {}", self.queries.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP collie_rows_scanned_total Rows in columns loaded by programs.").unwrap();
+        writeln!(out, "# TYPE collie_rows_scanned_total counter").unwrap();
+        writeln!(out, "collie_rows_scanned_total {}", self.rows_scanned.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP collie_cache_hits_total Result cache lookups that found the result.").unwrap();
+        writeln!(out, "# TYPE collie_cache_hits_total counter").unwrap();
+        writeln!(out, "collie_cache_hits_total {}", self.cache_hits.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP collie_cache_misses_total Result cache lookups that had to run the program.").unwrap();
+        writeln!(out, "# TYPE collie_cache_misses_total counter").unwrap();
+        writeln!(out, "collie_cache_misses_total {}", self.cache_misses.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP collie_memory_bytes Bytes held by the loaded columns of every VM reporting here.").unwrap();
+        writeln!(out, "# TYPE collie_memory_bytes gauge").unwrap();
+        writeln!(out, "collie_memory_bytes {}", self.memory_bytes.load(Ordering::Relaxed)).unwrap();
+
+        let ops = self.ops.lock().unwrap();
+        writeln!(out, "# HELP collie_op_duration_seconds Wall time spent per opcode.").unwrap();
+        writeln!(out, "# TYPE collie_op_duration_seconds summary").unwrap();
+        for (op, stats) in ops.iter() {
+            writeln!(out, "collie_op_duration_seconds_sum{{op=\"{}\"}} {}", op, secs(stats.nanos)).unwrap();
+            writeln!(out, "collie_op_duration_seconds_count{{op=\"{}\"}} {}", op, stats.count).unwrap();
+        }
+        out
+    }
+}
+
+// A VM's attachment to a Metrics: derefs to it, and remembers the bytes the VM last added
+// to the memory gauge, to take them back off when it's dropped
+#[derive(Debug)]
+pub(crate) struct Attached {
+    metrics: Arc<Metrics>,
+    bytes: usize
+}
+
+impl Attached {
+    pub(crate) fn new(metrics: Arc<Metrics>, bytes: usize) -> Self {
+        metrics.add_memory_bytes(bytes);
+        Attached { metrics, bytes }
+    }
+
+    pub(crate) fn set_memory_bytes(&mut self, bytes: usize) {
+        self.metrics.sub_memory_bytes(self.bytes);
+        self.metrics.add_memory_bytes(bytes);
+        self.bytes = bytes;
+    }
+}
+
+impl Deref for Attached {
+    type Target = Metrics;
+
+    fn deref(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        self.metrics.sub_memory_bytes(self.bytes);
+    }
+}
+
+// Longest request line read before answering 400
+const MAX_REQUEST_LINE: usize = 8192;
+
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub addr: String,       // e.g. "0.0.0.0:9184"
+    pub path: String,       // e.g. "/metrics"
+    pub timeout: Duration   // for reading a request and writing the response, per connection
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { addr: "127.0.0.1:9184".to_string(), path: "/metrics".to_string(), timeout: Duration::from_secs(5) }
+    }
+}
+
+// Serve `metrics` over HTTP on a background thread. Deliberately minimal: one GET endpoint,
+// one connection at a time, which is all a Prometheus scraper needs.
+pub fn serve(metrics: Arc<Metrics>, config: MetricsConfig) -> io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(&config.addr)?;
+    Ok(serve_on(listener, metrics, config))
+}
+
+// As serve, on a listener that's already bound (config.addr is ignored)
+pub fn serve_on(listener: TcpListener, metrics: Arc<Metrics>, config: MetricsConfig) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // a misbehaving client shouldn't take the endpoint down: one that never sends a
+            // request, or never reads the response, is dropped after the timeout
+            let _ = respond(stream, &metrics, &config);
+        }
+    })
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics, config: &MetricsConfig) -> io::Result<()> {
+    stream.set_read_timeout(Some(config.timeout))?;
+    stream.set_write_timeout(Some(config.timeout))?;
+    // the read timeout only bounds each read, so cap the line too, or a client that never
+    // sends a newline would grow it without end
+    let mut request_line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST_LINE as u64)).read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next(), parts.next());
+
+    let (status, body) = if !request_line.ends_with('\n') && request_line.len() >= MAX_REQUEST_LINE {
+        ("400 Bad Request", String::new())
+    } else if method == Some("GET") && target == Some(config.path.as_str()) {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{Column, Scalar};
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    use std::time::Instant;
+
+    // the value on the line for `series`
+    fn value(text: &str, series: &str) -> f64 {
+        let line = text.lines().find(|l| l.starts_with(series) && l[series.len() ..].starts_with(' '))
+            .unwrap_or_else(|| panic!("no {} in:\n{}", series, text));
+        line[series.len() + 1 ..].parse().unwrap()
+    }
+
+    fn vm() -> VM {
+        VM::new(Table::from_columns(vec![Column::from(vec![1.0, 2.0, 2.0]), Column::from(vec![5.0, 6.0, 7.0])]).unwrap())
+    }
+
+    fn program() -> Vec<Op> {
+        vec![Op::Col(0, 0), Op::Lit(Scalar::Num(2.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]
+    }
+
+    #[test]
+    fn renders_what_was_recorded() {
+        let metrics = Metrics::new();
+        metrics.record_query(Duration::from_millis(1500), true);
+        metrics.record_query(Duration::from_millis(500), false);
+        metrics.record_op("COL", Duration::from_millis(250));
+        metrics.record_op("COL", Duration::from_millis(250));
+        metrics.add_rows_scanned(40);
+        metrics.record_cache_lookup(true);
+        metrics.record_cache_lookup(false);
+        metrics.record_cache_lookup(true);
+        let text = metrics.render();
+        assert_eq!(value(&text, "collie_queries_total"), 2.0);
+        assert_eq!(value(&text, "collie_query_errors_total"), 1.0);
+        assert_eq!(value(&text, "collie_query_duration_seconds_sum"), 2.0);
+        assert_eq!(value(&text, "collie_rows_scanned_total"), 40.0);
+        assert_eq!(value(&text, "collie_cache_hits_total"), 2.0);
+        assert_eq!(value(&text, "collie_cache_misses_total"), 1.0);
+        assert_eq!(value(&text, "collie_op_duration_seconds_sum{op=\"COL\"}"), 0.5);
+        assert_eq!(value(&text, "collie_op_duration_seconds_count{op=\"COL\"}"), 2.0);
+        // every series is declared
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split([' ', '{']).next().unwrap();
+            let family = name.trim_end_matches("_sum").trim_end_matches("_count");
+            assert!(text.contains(&format!("# TYPE {} ", family)), "{} isn't declared", name);
+        }
+    }
+
+    #[test]
+    fn vms_report_their_runs() {
+        let metrics = Arc::new(Metrics::new());
+        let mut vm = vm();
+        vm.set_metrics(metrics.clone());
+        vm.run(program()).unwrap();
+        let text = metrics.render();
+        assert_eq!(value(&text, "collie_queries_total"), 1.0);
+        assert_eq!(value(&text, "collie_rows_scanned_total"), 6.0);
+        assert_eq!(value(&text, "collie_op_duration_seconds_count{op=\"COL\"}"), 2.0);
+        assert_eq!(value(&text, "collie_op_duration_seconds_count{op=\"SELECT\"}"), 1.0);
+    }
+
+    #[test]
+    fn memory_is_summed_over_vms() {
+        let metrics = Arc::new(Metrics::new());
+        let (mut a, mut b) = (vm(), VM::new(Table::from_columns(vec![Column::from(vec![1.0; 1000])]).unwrap()));
+        let (a_bytes, b_bytes) = (a.memory_usage(), b.memory_usage());
+        a.set_metrics(metrics.clone());
+        b.set_metrics(metrics.clone());
+        a.run(program()).unwrap();
+        b.run(vec![Op::Col(0, 0)]).unwrap();
+        a.run(program()).unwrap();
+        assert_eq!(value(&metrics.render(), "collie_memory_bytes"), (a_bytes + b_bytes) as f64);
+        drop(b);
+        assert_eq!(value(&metrics.render(), "collie_memory_bytes"), a_bytes as f64);
+        a.set_metrics(Arc::new(Metrics::new()));
+        assert_eq!(value(&metrics.render(), "collie_memory_bytes"), 0.0);
+    }
+
+    fn get(addr: std::net::SocketAddr, target: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_the_metrics_page() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.record_query(Duration::from_millis(3), true);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = MetricsConfig { timeout: Duration::from_millis(200), ..MetricsConfig::default() };
+        serve_on(listener, metrics.clone(), config);
+
+        let page = get(addr, "/metrics");
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"), "{}", page);
+        let body = &page[page.find("\r\n\r\n").unwrap() + 4 ..];
+        assert_eq!(body, metrics.render());
+        assert!(page.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(get(addr, "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn a_silent_client_doesnt_stall_the_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = MetricsConfig { timeout: Duration::from_millis(200), ..MetricsConfig::default() };
+        serve_on(listener, Arc::new(Metrics::new()), config);
+
+        let _silent = TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < Duration::from_secs(2), "took {:?}", start.elapsed());
+    }
+    #[test]
+    fn overlong_request_lines_are_turned_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = MetricsConfig { timeout: Duration::from_millis(200), ..MetricsConfig::default() };
+        serve_on(listener, Arc::new(Metrics::new()), config);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(format!("GET /{}", "x".repeat(MAX_REQUEST_LINE - 5)).as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
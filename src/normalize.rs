@@ -0,0 +1,159 @@
+// Unicode normalization of strings, to NFC. The same text can be encoded more than one way -
+// "é" as a single code point, or as "e" followed by a combining accent - and comparing bytes
+// tells the two apart. NFC settles on one form, the composed one, so strings that are
+// logically identical become byte-identical.
+//
+// Strings can be normalized as they're loaded (see Field::with_nfc), after which FilterEq,
+// grouping and joins on them need nothing further; or at comparison time, by a VM with
+// set_nfc, which compares the NFC forms of both sides in its equality filters.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, ColumnT, Scalar};
+use crate::core::prelude::*;
+use crate::dict::{DictStrColumn, Dictionary};
+use crate::errors::VMError;
+use crate::kernels;
+
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+// `s` in NFC, borrowed if it already is (as most text is)
+pub fn nfc(s: &str) -> Cow<'_, str> {
+    match is_nfc_quick(s.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(s),
+        _ => Cow::Owned(s.nfc().collect())
+    }
+}
+
+// `col` with its strings in NFC, as an InlineStr column (Dict stays Dict, with values that
+// normalize alike merged into one entry); columns of other types are returned as they are
+pub fn nfc_column(col: &Column) -> Column {
+    match col {
+        Column::Str(c) => inline(c.data.iter().map(|s| nfc(s)).collect()),
+        Column::InlineStr(c) => inline(c.iter().map(nfc).collect()),
+        Column::Dict(c) => {
+            let mut dict = Dictionary::new();
+            let table: Vec<u32> = c.dictionary().values().iter().map(|v| dict.intern(&nfc(v))).collect();
+            let codes = c.codes().iter().map(|code| table[*code as usize]).collect();
+            Column::Dict(DictStrColumn::with_codes(Arc::new(dict), codes))
+        },
+        other => other.clone()
+    }
+}
+
+// FilterEq comparing NFC forms: rows whose string normalizes to the same as `val`. Anything
+// but a Str value goes to Column::filter.
+pub fn filter_eq(col: &Column, val: Scalar) -> Result<BoolColumn, VMError> {
+    let x = match &val {
+        Scalar::Str(x) => nfc(x),
+        _ => return col.filter(val)
+    };
+    let mask = match col {
+        Column::Str(c) => matches(c.data.iter().map(|s| s.as_str()), c.data.len(), &x),
+        Column::InlineStr(c) => matches(c.iter(), c.len(), &x),
+        // each dictionary value once
+        Column::Dict(c) => {
+            let hits: Vec<bool> = c.dictionary().values().iter().map(|v| nfc(v) == x).collect();
+            kernels::mask_by(c.codes(), |code| hits[code as usize])
+        },
+        _ => return col.filter(val)
+    };
+    Ok(BoolColumn::from_mask(mask))
+}
+
+fn inline(strs: Vec<Cow<str>>) -> Column {
+    Column::InlineStr(strs.iter().map(|s| s.as_ref()).collect())
+}
+
+fn matches<'a, I: Iterator<Item=&'a str>>(values: I, len: usize, x: &str) -> BitIndex {
+    let mut mask = BitIndex::for_col_len(len);
+    values.enumerate().filter(|(_, v)| nfc(v) == x).for_each(|(i, _)| mask.set(i));
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{self, Encoding};
+    use crate::opcode::Op;
+    use crate::vm::{Value, VM};
+    use crate::table::Table;
+
+    use core::convert::TryFrom;
+
+    const COMPOSED: &str = "caf\u{e9}";
+    const DECOMPOSED: &str = "cafe\u{301}";
+
+    fn words() -> Column {
+        Column::from(vec![COMPOSED, "cafe", DECOMPOSED, "tea"])
+    }
+
+    #[test]
+    fn nfc_composes_and_borrows_what_already_is() {
+        assert!(matches!(nfc(COMPOSED), Cow::Borrowed(COMPOSED)));
+        assert!(matches!(nfc("plain ascii"), Cow::Borrowed(_)));
+        assert_eq!(nfc(DECOMPOSED), COMPOSED);
+        // canonical reordering of combining marks: dot below before dot above
+        assert_eq!(nfc("q\u{307}\u{323}"), "q\u{323}\u{307}");
+        assert_eq!(nfc("\u{212b}"), "\u{c5}");
+    }
+
+    #[test]
+    fn columns_normalize_to_one_form() {
+        let expected = Column::from(vec![COMPOSED, "cafe", COMPOSED, "tea"]);
+        crate::assert_columns_eq!(&nfc_column(&words()), &expected);
+        let boxed = Column::from(vec![COMPOSED.to_string(), "cafe".to_string(), DECOMPOSED.to_string(), "tea".to_string()]);
+        crate::assert_columns_eq!(&nfc_column(&boxed), &expected);
+
+        let dict = encoding::encode(words(), Encoding::Dict).unwrap();
+        match nfc_column(&dict) {
+            // the two forms of "café" become one entry
+            Column::Dict(c) => assert_eq!(c.dictionary().len(), 3),
+            other => panic!("{:?}", other)
+        }
+        crate::assert_columns_eq!(&nfc_column(&dict), &expected);
+        let nums = Column::from(vec![1.0]);
+        crate::assert_columns_eq!(&nfc_column(&nums), &nums);
+    }
+
+    #[test]
+    fn filter_eq_compares_nfc_forms() {
+        let dict = encoding::encode(words(), Encoding::Dict).unwrap();
+        for col in [words(), dict] {
+            for val in [COMPOSED, DECOMPOSED] {
+                assert_eq!(filter_eq(&col, Scalar::Str(val.to_string())).unwrap().selection().positions(), vec![0, 2]);
+            }
+            assert_eq!(filter_eq(&col, Scalar::Str("cafe".to_string())).unwrap().selection().positions(), vec![1]);
+            assert!(matches!(filter_eq(&col, Scalar::Num(1.0)), Err(VMError::TypeError(_))));
+        }
+        // other columns filter as usual
+        assert_eq!(filter_eq(&Column::from(vec![1.0, 2.0]), Scalar::Num(2.0)).unwrap().selection().positions(), vec![1]);
+    }
+
+    #[test]
+    fn the_vm_compares_nfc_forms_once_asked() {
+        let run = |nfc, code: Vec<Op>| {
+            let mut vm = VM::new(Table::from_columns(vec![words(), Column::from(vec![0u64, 1, 2, 3]), Column::from(vec![DECOMPOSED; 4])]).unwrap());
+            vm.set_nfc(nfc);
+            vm.run(code).unwrap();
+            Vec::<u64>::try_from(vm.column_of(vm.stack().last().unwrap()).unwrap()).unwrap()
+        };
+        let lit = Op::Lit(Scalar::Str(DECOMPOSED.to_string()));
+        let programs = [
+            vec![Op::Col(0, 0), lit.clone(), Op::FilterEq, Op::Col(0, 1), Op::Select(1)],
+            vec![Op::Col(0, 0), lit.clone(), Op::Col(0, 1), Op::FilterSelect],
+            // rows of column 0 found in column 2
+            vec![Op::Col(0, 0), Op::Col(0, 2), Op::FilterIn, Op::Col(0, 1), Op::Select(1)]
+        ];
+        for code in programs.iter() {
+            assert_eq!(run(true, code.clone()), vec![0, 2], "{:?}", code);
+            assert_eq!(run(false, code.clone()), vec![2], "{:?}", code);
+        }
+
+        let mut vm = VM::new(Table::from_columns(vec![]).unwrap());
+        vm.set_nfc(true);
+        vm.run(vec![lit, Op::Lit(Scalar::Str(COMPOSED.to_string())), Op::FilterEq]).unwrap();
+        assert!(matches!(vm.stack(), [Value::Scalar(Scalar::Bool(true))]));
+    }
+}
@@ -0,0 +1,409 @@
+// How nulls compare for equality, as a setting rather than one fixed behavior: SQL's
+// three-valued logic suits filters, while deduplication and joins on nullable keys often want
+// null to equal null. The setting is per query: held by the VM for its filters, and passed
+// to joins, set operations and grouping.
+//
+// A null is Scalar::Null, or a row of a Nullable column whose validity bit is unset - any
+// column can be given nulls of its own that way. NaN in a Num column is null too, as it was
+// before columns had nulls, and a record is null-ish if any of its fields is.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Cmp, Column, ColumnT, Scalar};
+use crate::compare;
+use crate::conditional;
+use crate::core::prelude::*;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::kernels;
+use crate::selection::Selection;
+
+use alloc::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum NullSemantics {
+    // As in SQL: `x = null` is unknown, so equality filters and joins never match a null, but
+    // GROUP BY and DISTINCT treat nulls as not distinct from each other - one group
+    #[default]
+    Sql,
+    // null is a value like any other: it matches null in filters and joins, and groups with it
+    NullEqualsNull,
+    // null equals nothing, anywhere: each null row is a group of its own, and DISTINCT keeps
+    // every one (Postgres's NULLS DISTINCT)
+    NullsDistinct
+}
+
+impl NullSemantics {
+    // Whether a null matches a null in an equality filter or a join
+    pub fn nulls_match(self) -> bool {
+        self == NullSemantics::NullEqualsNull
+    }
+
+    // Whether nulls all fall in one group, for grouping and DISTINCT
+    pub fn nulls_group(self) -> bool {
+        self != NullSemantics::NullsDistinct
+    }
+}
+
+pub fn is_null(s: &Scalar) -> bool {
+    match s {
+        Scalar::Null => true,
+        Scalar::Num(x) => x.is_nan(),
+        Scalar::Record(xs) => xs.iter().any(is_null),
+        _ => false
+    }
+}
+
+// `a = b` in a filter: false if either is null, unless nulls match
+pub fn scalars_equal(a: &Scalar, b: &Scalar, nulls: NullSemantics) -> bool {
+    if !nulls.nulls_match() && (is_null(a) || is_null(b)) {
+        return false;
+    }
+    a == b
+}
+
+// FilterEq: Column::filter, except that a null `val` picks out the null rows when nulls match
+pub fn filter_eq(col: &Column, val: Scalar, nulls: NullSemantics) -> Result<BoolColumn, VMError> {
+    if !(nulls.nulls_match() && is_null(&val)) {
+        return col.filter(val);
+    }
+    match encoding::plain(col).as_ref() {
+        Column::Num(c) => Ok(BoolColumn::from_mask(kernels::mask_by(c.values(), |x| x.is_nan()))),
+        Column::Nullable(c) => {
+            let missing = c.validity().inverted();
+            match c.values() {
+                Column::Num(_) => Ok(BoolColumn::from_mask(missing.or(&filter_eq(c.values(), val, nulls)?.selection().to_bitmap()))),
+                _ => Ok(BoolColumn::from_mask(missing))
+            }
+        },
+        _ => col.filter(val)
+    }
+}
+
+// A column with nulls of its own: the values of any other column, and a validity bitmap with
+// a bit set for each row that has one. The values at null rows are placeholders, never read.
+#[derive(Debug, Clone)]
+pub struct NullableColumn {
+    values: Arc<Column>,
+    validity: BitIndex
+}
+
+impl NullableColumn {
+    // `values`, null where `validity` is unset. Nulls over a column that already has some add
+    // to them.
+    pub fn new(values: Column, validity: BitIndex) -> Result<NullableColumn, VMError> {
+        if validity.len() != values.len() {
+            return Err(VMError::LengthMismatch { expected: values.len(), found: validity.len() });
+        }
+        Ok(match values {
+            Column::Nullable(c) => NullableColumn { validity: c.validity.and(&validity), values: c.values },
+            values => NullableColumn { values: Arc::new(values), validity }
+        })
+    }
+
+    // `values` with the rows in `nulls` made null
+    pub fn with_nulls(values: Column, nulls: &Selection) -> Result<NullableColumn, VMError> {
+        NullableColumn::new(values, nulls.to_bitmap().inverted())
+    }
+
+    pub fn len(&self) -> usize {
+        self.validity.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn values(&self) -> &Column {
+        &self.values
+    }
+
+    pub fn validity(&self) -> &BitIndex {
+        &self.validity
+    }
+
+    pub fn is_valid(&self, i: usize) -> bool {
+        self.validity.get(i)
+    }
+
+    pub fn null_count(&self) -> usize {
+        self.len() - self.validity.count_ones()
+    }
+
+    pub fn get(&self, i: usize) -> Option<Scalar> {
+        match i < self.len() {
+            true if self.is_valid(i) => self.values.get(i),
+            true => Some(Scalar::Null),
+            false => None
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.values.memory_usage() + self.validity.memory_usage()
+    }
+
+    // Rows offset .. offset + len, which must be in bounds
+    pub fn slice(&self, offset: usize, len: usize) -> NullableColumn {
+        NullableColumn { values: Arc::new(self.values.slice(offset, len)), validity: bits_where(len, |i| self.is_valid(offset + i)) }
+    }
+
+    // The rows in `sel`, in order
+    pub fn gather(&self, sel: &Selection) -> NullableColumn {
+        let mut rows = Vec::with_capacity(sel.count_ones());
+        sel.for_each(|i| rows.push(i));
+        NullableColumn {
+            values: Arc::new(self.values.select(&BoolColumn::from_selection(sel.clone()))),
+            validity: bits_where(rows.len(), |i| self.is_valid(rows[i]))
+        }
+    }
+
+    // The rows at `rows`, which may repeat and must be in bounds
+    pub fn take(&self, rows: &[usize]) -> Result<NullableColumn, VMError> {
+        Ok(NullableColumn { values: Arc::new(conditional::take(&self.values, rows)?), validity: bits_where(rows.len(), |i| self.is_valid(rows[i])) })
+    }
+
+    // The values of the rows that have one
+    pub fn present(&self) -> Column {
+        self.values.select(&BoolColumn::from_mask(self.validity.clone()))
+    }
+
+    // `mask` without the null rows: a filter never matches one
+    fn valid_only(&self, mask: BoolColumn) -> BoolColumn {
+        BoolColumn::from_mask(mask.selection().to_bitmap().and(&self.validity))
+    }
+
+    pub fn filter_range(&self, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        Ok(self.valid_only(self.values.filter_range(lo, hi)?))
+    }
+
+    pub fn filter_in(&self, set: &Column, nulls: NullSemantics) -> Result<BoolColumn, VMError> {
+        let found = self.valid_only(self.values.filter_in(set, nulls)?);
+        let set_has_null = (0 .. set.len()).any(|i| set.get(i).as_ref().is_some_and(is_null));
+        match nulls.nulls_match() && set_has_null {
+            true => Ok(BoolColumn::from_mask(found.selection().to_bitmap().or(&self.validity.inverted()))),
+            false => Ok(found)
+        }
+    }
+}
+
+fn bits_where<F: Fn(usize) -> bool>(len: usize, pred: F) -> BitIndex {
+    let mut bits = BitIndex::for_col_len(len);
+    (0 .. len).filter(|i| pred(*i)).for_each(|i| bits.set(i));
+    bits
+}
+
+impl ColumnT for NullableColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        match val {
+            Scalar::Null => Ok(BoolColumn::from_mask(BitIndex::for_col_len(self.len()))),
+            val => Ok(self.valid_only(self.values.filter(val)?))
+        }
+    }
+
+    fn select(&self, mask: &BoolColumn) -> Self {
+        self.gather(mask.selection())
+    }
+
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        match val {
+            Scalar::Null => Ok(BoolColumn::from_mask(BitIndex::for_col_len(self.len()))),
+            val => Ok(self.valid_only(self.values.filter_cmp(cmp, val)?))
+        }
+    }
+}
+
+// Same rows null, and the same values in the rest
+impl PartialEq for NullableColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && (0 .. self.len()).all(|i| match (self.get(i), other.get(i)) {
+            (Some(a), Some(b)) => compare::cmp_scalar(&a, &b).is_eq(),
+            _ => false
+        })
+    }
+}
+
+// Where each of `cols` has a value, or None if none of them has nulls
+pub fn validity_of(cols: &[&Column]) -> Option<BitIndex> {
+    cols.iter().filter_map(|c| match c {
+        Column::Nullable(c) => Some(c.validity().clone()),
+        _ => None
+    }).reduce(|a, b| a.and(&b))
+}
+
+// A column's values without its nulls' validity: a Nullable column's placeholders included
+pub fn values_of(col: &Column) -> &Column {
+    match col {
+        Column::Nullable(c) => c.values(),
+        col => col
+    }
+}
+
+// `mask`, from a kernel run on values_of(col), without `col`'s null rows
+pub fn valid_only(col: &Column, mask: BoolColumn) -> BoolColumn {
+    match col {
+        Column::Nullable(c) => c.valid_only(mask),
+        _ => mask
+    }
+}
+
+// `res`, from a kernel run on values_of(col), null in `col`'s null rows
+pub fn null_where(col: &Column, res: Column) -> Result<Column, VMError> {
+    match col {
+        Column::Nullable(c) => Ok(Column::Nullable(NullableColumn::new(res, c.validity().clone())?)),
+        _ => Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Arith;
+    use crate::encoding::Encoding;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    use core::convert::TryFrom;
+
+    const ALL: [NullSemantics; 3] = [NullSemantics::Sql, NullSemantics::NullEqualsNull, NullSemantics::NullsDistinct];
+
+    fn nums() -> Column {
+        Column::from(vec![1.0, f64::NAN, 2.0, f64::NAN])
+    }
+
+    #[test]
+    fn settings_say_how_nulls_match_and_group() {
+        let found: Vec<(bool, bool)> = ALL.iter().map(|n| (n.nulls_match(), n.nulls_group())).collect();
+        assert_eq!(found, vec![(false, true), (true, true), (false, false)]);
+        assert_eq!(NullSemantics::default(), NullSemantics::Sql);
+    }
+
+    #[test]
+    fn nan_and_records_holding_it_are_null() {
+        assert!(is_null(&Scalar::Num(f64::NAN)));
+        assert!(is_null(&Scalar::Record(vec![Scalar::Num(1.0), Scalar::Record(vec![Scalar::Num(f64::NAN)])])));
+        assert!(!is_null(&Scalar::Num(f64::INFINITY)));
+        assert!(!is_null(&Scalar::Str("".to_string())));
+        assert!(!is_null(&Scalar::Record(vec![])));
+    }
+
+    #[test]
+    fn scalars_equal_only_if_nulls_match() {
+        let (nan, one) = (Scalar::Num(f64::NAN), Scalar::Num(1.0));
+        for &nulls in ALL.iter() {
+            assert_eq!(scalars_equal(&nan, &nan, nulls), nulls.nulls_match(), "{:?}", nulls);
+            assert!(!scalars_equal(&nan, &one, nulls));
+            assert!(scalars_equal(&one, &Scalar::Num(1.0), nulls));
+            assert!(scalars_equal(&Scalar::Num(-0.0), &Scalar::Num(0.0), nulls));
+        }
+    }
+
+    #[test]
+    fn filter_eq_finds_nulls_only_if_they_match() {
+        let rle = encoding::encode(Column::from(vec![f64::NAN, f64::NAN, 1.0]), Encoding::Rle).unwrap();
+        for &nulls in ALL.iter() {
+            let expected = if nulls.nulls_match() { vec![1, 3] } else { vec![] };
+            assert_eq!(filter_eq(&nums(), Scalar::Num(f64::NAN), nulls).unwrap().selection().positions(), expected, "{:?}", nulls);
+            let expected = if nulls.nulls_match() { vec![0, 1] } else { vec![] };
+            assert_eq!(filter_eq(&rle, Scalar::Num(f64::NAN), nulls).unwrap().selection().positions(), expected, "{:?}", nulls);
+            // other values are unaffected
+            assert_eq!(filter_eq(&nums(), Scalar::Num(2.0), nulls).unwrap().selection().positions(), vec![2]);
+        }
+        assert!(matches!(filter_eq(&nums(), Scalar::Str("a".to_string()), NullSemantics::NullEqualsNull), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn filter_in_finds_nulls_only_if_they_match() {
+        let set = Column::from(vec![f64::NAN, 2.0]);
+        for &nulls in ALL.iter() {
+            let expected = if nulls.nulls_match() { vec![1, 2, 3] } else { vec![2] };
+            assert_eq!(nums().filter_in(&set, nulls).unwrap().selection().positions(), expected, "{:?}", nulls);
+            // without a null in the set, a null row never matches
+            assert_eq!(nums().filter_in(&Column::from(vec![1.0]), nulls).unwrap().selection().positions(), vec![0]);
+        }
+    }
+
+    #[test]
+    fn the_vm_filters_by_its_setting() {
+        let code = |nulls| {
+            let flags = Column::from(vec![true, false, true, false]);
+            let mut vm = VM::new(Table::from_columns(vec![nums(), Column::from(vec![10u64, 11, 12, 13]), flags]).unwrap());
+            vm.set_null_semantics(nulls);
+            let nan = Op::Lit(Scalar::Num(f64::NAN));
+            let found = |vm: &mut VM, code| {
+                vm.run(code).unwrap();
+                Vec::<u64>::try_from(vm.column_of(vm.stack().last().unwrap()).unwrap()).unwrap()
+            };
+            (
+                found(&mut vm, vec![Op::Col(0, 0), nan.clone(), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]),
+                found(&mut vm, vec![Op::Col(0, 0), nan.clone(), Op::Col(0, 1), Op::FilterSelect]),
+                // subqueries run with the same setting: this one is NaN = NaN
+                found(&mut vm, vec![Op::Col(0, 2), Op::ScalarSubquery(vec![nan.clone(), nan, Op::FilterEq]), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]),
+                found(&mut vm, vec![Op::Col(0, 0), Op::Col(0, 0), Op::FilterIn, Op::Col(0, 1), Op::Select(1)])
+            )
+        };
+        assert_eq!(code(NullSemantics::Sql), (vec![], vec![], vec![11, 13], vec![10, 12]));
+        assert_eq!(code(NullSemantics::NullEqualsNull), (vec![11, 13], vec![11, 13], vec![10, 12], vec![10, 11, 12, 13]));
+        assert_eq!(code(NullSemantics::NullsDistinct), code(NullSemantics::Sql));
+    }
+    // 1, null, 3, null
+    fn ints() -> Column {
+        Column::Nullable(NullableColumn::with_nulls(Column::from(vec![1i64, 2, 3, 4]), &Selection::from_positions(vec![1, 3], 4)).unwrap())
+    }
+
+    fn scalars(col: &Column) -> Vec<Scalar> {
+        (0 .. col.len()).map(|i| col.get(i).unwrap()).collect()
+    }
+
+    #[test]
+    fn filters_never_match_a_null_row() {
+        let col = ints();
+        assert_eq!(col.filter(Scalar::Int(2)).unwrap().selection().positions(), Vec::<usize>::new());
+        assert_eq!(col.filter_cmp(Cmp::Gt, Scalar::Int(0)).unwrap().selection().positions(), vec![0, 2]);
+        assert_eq!(col.filter_range(Scalar::Int(0), Scalar::Int(10)).unwrap().selection().positions(), vec![0, 2]);
+        assert_eq!(col.filter(Scalar::Null).unwrap().selection().positions(), vec![]);
+        // ... except a null filter, when nulls match
+        for &nulls in ALL.iter() {
+            let expected = if nulls.nulls_match() { vec![1, 3] } else { vec![] };
+            assert_eq!(filter_eq(&col, Scalar::Null, nulls).unwrap().selection().positions(), expected, "{:?}", nulls);
+        }
+    }
+
+    #[test]
+    fn selecting_rows_keeps_their_nulls() {
+        let col = ints();
+        assert_eq!(scalars(&col.slice(1, 2)), vec![Scalar::Null, Scalar::Int(3)]);
+        let picked = col.select(&BoolColumn::from_mask(Selection::from_positions(vec![0, 1], 4).to_bitmap()));
+        assert_eq!(scalars(&picked), vec![Scalar::Int(1), Scalar::Null]);
+        let parts = Column::concat(&[col.clone(), Column::from(vec![5i64])]).unwrap();
+        assert_eq!(scalars(&parts), vec![Scalar::Int(1), Scalar::Null, Scalar::Int(3), Scalar::Null, Scalar::Int(5)]);
+        assert_eq!(col.datatype(), crate::schema::Datatype::Int);
+    }
+
+    #[test]
+    fn arithmetic_propagates_nulls() {
+        let col = ints();
+        assert_eq!(scalars(&col.arith_scalar(Arith::Add, &Scalar::Int(10)).unwrap()), vec![Scalar::Int(11), Scalar::Null, Scalar::Int(13), Scalar::Null]);
+        let other = Column::Nullable(NullableColumn::with_nulls(Column::from(vec![1i64, 1, 1, 1]), &Selection::from_positions(vec![0], 4)).unwrap());
+        assert_eq!(scalars(&col.arith(Arith::Mul, &other).unwrap()), vec![Scalar::Null, Scalar::Null, Scalar::Int(3), Scalar::Null]);
+        assert!(scalars(&Column::from(vec![1.0, 2.0]).arith_scalar(Arith::Add, &Scalar::Null).unwrap()).iter().all(|s| *s == Scalar::Null));
+        let cond = Selection::from_positions(vec![0, 1], 4);
+        let chosen = conditional::if_else(&cond, conditional::Branch::Scalar(&Scalar::Null), conditional::Branch::Column(&col)).unwrap();
+        assert_eq!(scalars(&chosen), vec![Scalar::Null, Scalar::Null, Scalar::Int(3), Scalar::Null]);
+    }
+
+    #[test]
+    fn the_vm_sorts_nulls_last_and_sums_the_rest() {
+        let mut vm = VM::new(Table::from_columns(vec![ints(), Column::from(vec![10u64, 11, 12, 13])]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::ArgSort, Op::Col(0, 1), Op::SortBy]).unwrap();
+        assert_eq!(Vec::<u64>::try_from(vm.column_of(vm.stack().last().unwrap()).unwrap()).unwrap(), vec![10, 12, 11, 13]);
+        let nums = NullableColumn::with_nulls(Column::from(vec![1.0, 2.0, 3.0, 4.0]), &Selection::from_positions(vec![1, 3], 4)).unwrap();
+        let mut vm = VM::new(Table::from_columns(vec![Column::Nullable(nums)]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Sum]).unwrap();
+        assert!(matches!(vm.stack().last(), Some(crate::vm::Value::Scalar(Scalar::Num(x))) if *x == 4.0));
+    }
+}
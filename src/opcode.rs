@@ -1,11 +1,161 @@
 use crate::Scalar;
+use crate::core::prelude::*;
+use crate::schema::Datatype;
+use crate::vector::Metric;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Op {
     Lit(Scalar),
-    Col(usize),
+    Col(usize, usize),  // (table id, column id): pushes that column of that table in the VM's catalog (see catalog.rs)
+    ColByName(String),  // Col, for the column of that name: "table.column", or a column name only one table has
     Select(usize),
-    FilterEq,
-    AddVs,
+    FilterEq,       // pops a scalar, then a column (pushing the mask of rows equal to it) or another scalar (pushing whether they're equal)
+    FilterLt,       // pops a scalar, then a column; pushes the mask of rows less than it
+    FilterLe,
+    FilterGt,
+    FilterGe,
+    FilterNe,       // ... of rows not equal to it, and not null
+    And,            // pops two masks over the same rows; pushes the rows both select
+    Or,             // ... the rows either selects
+    Not,            // pops a mask; pushes the rows it doesn't select
+    FilterSelect,   // FilterEq then Select, in one pass: pops target column, scalar, filter column
+    FilterIn,       // pops a column of values, then a column; pushes the mask of rows found among the values
+    ScalarSubquery(Vec<Op>),    // runs the program over the whole table and pushes its single value
+    CallUdf(usize, usize),      // (function id, arity): pops the arguments, pushes the function's result
+    CallUdaf(usize, usize),     // (function id, arity): pops the arguments, pushes the aggregate of all their rows
+    Field(usize),   // pops a record, pushes its field at that index; of a Record column, pushes the column of that field
+    FilterWithinBBox,   // pops the max corner, the min corner, then a point column; pushes the mask of rows in that box, edges included
+    DistanceTo,     // pops a point, then a point column; pushes a Num column of each row's distance to it
+    JsonExtract(String, Datatype),  // (path, type): pops a JSON column, pushes the value at that path in each document (see JsonColumn::extract)
+    FilterInCidr(u8),   // (prefix length): pops a network address, then an IP column; pushes the mask of rows in that block
+    IfElse,         // pops else, then (each a column or a scalar) and a mask; pushes then where the mask is set, else elsewhere
+    VectorDistance(Metric),     // pops a vector, then a Vector column; pushes a Num column of each row's distance to it
+    Nearest(usize, Metric),     // (k, metric): pops a vector, then a Vector column; pushes the mask of the k rows nearest it
+    ListLen,        // pops a List column; pushes the number of items in each row's list, as an Entity column
+    FilterContains, // pops a scalar, then a List column; pushes the mask of rows whose list has an item equal to it
+    Sum,            // pops a column; pushes the aggregate of its rows as a scalar (see aggregate.rs)
+    Count,
+    Min,
+    Max,
+    Mean,
+    GroupBy(usize), // (key column, of the first table): pops a column of values; pushes the distinct keys, then a List column of each one's values (see list.rs)
+    HashJoin,       // pops two Entity (or two Int) key columns, right then left; pushes the left and then the right row numbers of each pair of equal keys
+    ArgSort,        // pops a column; pushes the row numbers that put it in ascending order, as an Entity column (see compare::argsort)
+    SortBy,         // pops a column, then row numbers from ArgSort; pushes the column's rows in that order
+    Take,           // pops a column, then an Entity column of row numbers (e.g. from HashJoin); pushes the column's rows at those numbers
+    Limit(usize),   // pops a column; pushes its first n rows, or all of them if it has fewer
+    TopK(usize),    // pops a Num or Int column; pushes the row numbers of its n largest values, largest first, as an Entity column (see compare::top_k)
+    Shared(usize),  // pushes the value of a Batch's shared sub-plan, computed once for all its queries (see batch.rs)
+    AddVs,          // pops a number, then a Num column; pushes each row plus the number. Adds and subtracts times too (see Column::arith)
+    SubVs,          // ... each row minus the number
+    MulVs,
     DivVs,
-}
\ No newline at end of file
+    AddVv,          // pops two Num columns of the same length; pushes their sum, row by row. Adds and subtracts times too
+    SubVv,          // ... the first popped subtracted from the other
+    MulVv,
+    DivVv,
+}
+
+impl Op {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Op::Lit(_) => "LIT",
+            Op::Col(..) => "COL",
+            Op::ColByName(_) => "COL_NAMED",
+            Op::Select(_) => "SELECT",
+            Op::FilterEq => "FILTER_EQ",
+            Op::FilterLt => "FILTER_LT",
+            Op::FilterLe => "FILTER_LE",
+            Op::FilterGt => "FILTER_GT",
+            Op::FilterGe => "FILTER_GE",
+            Op::FilterNe => "FILTER_NE",
+            Op::And => "AND",
+            Op::Or => "OR",
+            Op::Not => "NOT",
+            Op::FilterSelect => "FILTER_SELECT",
+            Op::FilterIn => "FILTER_IN",
+            Op::ScalarSubquery(_) => "SUBQUERY",
+            Op::Field(_) => "FIELD",
+            Op::FilterWithinBBox => "FILTER_BBOX",
+            Op::DistanceTo => "DISTANCE_TO",
+            Op::FilterInCidr(_) => "FILTER_CIDR",
+            Op::JsonExtract(..) => "JSON_EXTRACT",
+            Op::IfElse => "IF_ELSE",
+            Op::VectorDistance(_) => "VECTOR_DISTANCE",
+            Op::Nearest(..) => "NEAREST",
+            Op::ListLen => "LIST_LEN",
+            Op::FilterContains => "FILTER_CONTAINS",
+            Op::Sum => "SUM",
+            Op::Count => "COUNT",
+            Op::Min => "MIN",
+            Op::Max => "MAX",
+            Op::Mean => "MEAN",
+            Op::GroupBy(_) => "GROUP_BY",
+            Op::HashJoin => "HASH_JOIN",
+            Op::ArgSort => "ARG_SORT",
+            Op::SortBy => "SORT_BY",
+            Op::Take => "TAKE",
+            Op::Limit(_) => "LIMIT",
+            Op::TopK(_) => "TOP_K",
+            Op::Shared(_) => "SHARED",
+            Op::CallUdf(..) => "CALL_UDF",
+            Op::CallUdaf(..) => "CALL_UDAF",
+            Op::AddVs => "ADD_VS",
+            Op::SubVs => "SUB_VS",
+            Op::MulVs => "MUL_VS",
+            Op::DivVs => "DIV_VS",
+            Op::AddVv => "ADD_VV",
+            Op::SubVv => "SUB_VV",
+            Op::MulVv => "MUL_VV",
+            Op::DivVv => "DIV_VV",
+        }
+    }
+
+    // Whether the op works on each row independently of the others. A program made of only
+    // these gives the same rows run over a whole table as over its row ranges one at a
+    // time, which is what lets a Cursor page through results. A subquery always sees the
+    // whole table, so its value is the same for every row range.
+    pub fn is_row_local(&self) -> bool {
+        match self {
+            Op::Lit(_) | Op::Col(..) | Op::ColByName(_) | Op::Select(_) | Op::FilterEq | Op::FilterSelect => true,
+            Op::FilterLt | Op::FilterLe | Op::FilterGt | Op::FilterGe | Op::FilterNe => true,
+            Op::And | Op::Or | Op::Not => true,
+            Op::AddVs | Op::SubVs | Op::MulVs | Op::DivVs => true,
+            Op::AddVv | Op::SubVv | Op::MulVv | Op::DivVv => true,
+            Op::ScalarSubquery(_) | Op::Field(_) | Op::IfElse | Op::CallUdf(..) => true,
+            Op::FilterWithinBBox | Op::DistanceTo | Op::FilterInCidr(_) | Op::JsonExtract(..) | Op::VectorDistance(_) => true,
+            Op::ListLen | Op::FilterContains => true,
+            // the k nearest of a row range aren't the k nearest of the table, and a shared
+            // sub-plan's columns have a row per row of the table, not of the range
+            Op::FilterIn | Op::CallUdaf(..) | Op::Nearest(..) | Op::Shared(_) => false,
+            Op::Sum | Op::Count | Op::Min | Op::Max | Op::Mean | Op::GroupBy(_) | Op::HashJoin => false,
+            // a row range's order is its own, and its row numbers count from its first row
+            Op::ArgSort | Op::SortBy | Op::Take => false,
+            // the first n rows, or the largest n, of each row range aren't those of the table
+            Op::Limit(_) | Op::TopK(_) => false
+        }
+    }
+
+    // (values popped, values pushed)
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Op::Lit(_) | Op::Col(..) | Op::ColByName(_) | Op::ScalarSubquery(_) | Op::Shared(_) => (0, 1),
+            Op::Select(_) => (2, 1),
+            Op::Field(_) | Op::JsonExtract(..) | Op::Not | Op::ListLen => (1, 1),
+            Op::Sum | Op::Count | Op::Min | Op::Max | Op::Mean => (1, 1),
+            Op::GroupBy(_) => (1, 2),
+            Op::ArgSort | Op::Limit(_) | Op::TopK(_) => (1, 1),
+            Op::SortBy | Op::Take => (2, 1),
+            Op::HashJoin => (2, 2),
+            Op::FilterEq | Op::FilterIn | Op::DistanceTo | Op::FilterInCidr(_) | Op::FilterContains => (2, 1),
+            Op::FilterLt | Op::FilterLe | Op::FilterGt | Op::FilterGe | Op::FilterNe => (2, 1),
+            Op::And | Op::Or => (2, 1),
+            Op::AddVs | Op::SubVs | Op::MulVs | Op::DivVs => (2, 1),
+            Op::AddVv | Op::SubVv | Op::MulVv | Op::DivVv => (2, 1),
+            Op::VectorDistance(_) | Op::Nearest(..) => (2, 1),
+            Op::FilterSelect | Op::FilterWithinBBox | Op::IfElse => (3, 1),
+            Op::CallUdf(_, arity) | Op::CallUdaf(_, arity) => (*arity, 1),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::core::prelude::*;
+use crate::opcode::Op;
+
+// Peephole rewrites over compiled programs. Every rewrite leaves the program's result
+// unchanged; they only cut down on intermediate columns and passes over the data.
+
+pub fn optimize(code: Vec<Op>) -> Vec<Op> {
+    let code = code.into_iter().map(|op| match op {
+        Op::ScalarSubquery(sub) => Op::ScalarSubquery(optimize(sub)),
+        op => op
+    }).collect();
+    fuse_filter_select(code)
+}
+
+// FilterEq, Col(b), Select  =>  Col(b), FilterSelect (and likewise for ColByName)
+// The stack going into FilterSelect is (column, scalar, target) either way, and it
+// produces the selected target rows without materializing the mask in between.
+fn fuse_filter_select(code: Vec<Op>) -> Vec<Op> {
+    let mut out = Vec::with_capacity(code.len());
+    let mut i = 0;
+    while i < code.len() {
+        match (&code[i], code.get(i + 1), code.get(i + 2)) {
+            (Op::FilterEq, Some(load @ (Op::Col(..) | Op::ColByName(_))), Some(Op::Select(_))) => {
+                out.push(load.clone());
+                out.push(Op::FilterSelect);
+                i += 3;
+            },
+            (op, _, _) => {
+                out.push(op.clone());
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{Column, InlineStrColumn, Scalar};
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::from(vec![1.0, 2.0, 1.0, 3.0]),
+            Column::from(vec![10u64, 11, 12, 13]),
+            Column::from(vec!["a", "b", "a", "c"]),
+            Column::InlineStr(InlineStrColumn::from_strs(vec!["w", "xx", "yyyyyy", "z"])),
+        ]
+    }
+
+    fn results(code: Vec<Op>) -> Result<Vec<u64>, String> {
+        let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+        vm.run(code).map_err(|e| format!("{:?}", e))?;
+        Ok(vm.stack().iter().map(|v| vm.column_of(v).unwrap().fingerprint()).collect())
+    }
+
+    fn filter_select(col: usize, val: Scalar, target: usize) -> Vec<Op> {
+        vec![Op::Col(0, col), Op::Lit(val), Op::FilterEq, Op::Col(0, target), Op::Select(1)]
+    }
+
+    #[test]
+    fn fuses_filter_then_select() {
+        assert_eq!(
+            optimize(filter_select(0, Scalar::Num(1.0), 1)),
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::Col(0, 1), Op::FilterSelect]
+        );
+        // twice in a row, and with something in between
+        let mut code = filter_select(0, Scalar::Num(1.0), 1);
+        code.extend(filter_select(2, Scalar::Str("a".to_string()), 3));
+        assert_eq!(optimize(code).iter().filter(|op| **op == Op::FilterSelect).count(), 2);
+        // a target loaded by name
+        let by_name = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterEq, Op::ColByName("c1".to_string()), Op::Select(1)];
+        assert_eq!(optimize(by_name)[2..], [Op::ColByName("c1".to_string()), Op::FilterSelect]);
+    }
+
+    #[test]
+    fn leaves_other_programs_alone() {
+        let cases = [
+            // mask used on its own, select from a computed column, program ends mid-pattern
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterEq],
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterEq, Op::Col(0, 1), Op::Col(0, 2)],
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterEq, Op::Col(0, 1)],
+            vec![],
+        ];
+        for code in cases {
+            assert_eq!(optimize(code.clone()), code);
+        }
+    }
+
+    #[test]
+    fn fused_programs_give_the_same_results() {
+        let cases = [
+            filter_select(0, Scalar::Num(1.0), 1),
+            filter_select(0, Scalar::Num(1.0), 2),
+            filter_select(0, Scalar::Num(9.0), 3),
+            filter_select(1, Scalar::Entity(12), 0),
+            filter_select(2, Scalar::Str("a".to_string()), 3),
+            filter_select(3, Scalar::Str("yyyyyy".to_string()), 2),
+            // a mask as the filter column falls back to filter-then-select
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterEq, Op::Lit(Scalar::Bool(false)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)],
+        ];
+        for code in cases {
+            let fused = optimize(code.clone());
+            assert!(fused.contains(&Op::FilterSelect));
+            assert_eq!(results(fused).unwrap(), results(code).unwrap());
+        }
+    }
+
+    #[test]
+    fn fused_programs_check_their_operands() {
+        // filter column and target of different lengths: the target is the ids over 11
+        let short = vec![Op::Col(0, 1), Op::Lit(Scalar::Entity(11)), Op::FilterGt, Op::Col(0, 1), Op::Select(1)];
+        let err = results([vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0))], short, vec![Op::FilterSelect]].concat()).unwrap_err();
+        assert!(err.contains("LengthMismatch"), "{}", err);
+        // filtering a numeric column by a string
+        let err = results(optimize(filter_select(0, Scalar::Str("a".to_string()), 1))).unwrap_err();
+        assert!(err.contains("TypeError"), "{}", err);
+    }
+
+    #[test]
+    fn rewrites_the_programs_in_subqueries() {
+        let sub = Op::ScalarSubquery(filter_select(0, Scalar::Num(3.0), 1));
+        let code = vec![Op::Col(0, 1), sub];
+        assert_eq!(optimize(code.clone()), vec![
+            Op::Col(0, 1),
+            Op::ScalarSubquery(vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::Col(0, 1), Op::FilterSelect])
+        ]);
+        let mut code = code;
+        code.push(Op::FilterEq);
+        assert_eq!(results(optimize(code.clone())).unwrap(), results(code).unwrap());
+    }
+}
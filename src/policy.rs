@@ -0,0 +1,243 @@
+// Row-level security. A table's policy is a program over its columns that leaves the mask of
+// the rows a session may see; Db::query_in runs it first and then runs the caller's program
+// over those rows alone, so every Op::Col - in subqueries too - loads only what's visible,
+// and embedders serving many tenants don't have to rewrite their users' queries.
+//
+// Policies are checked once, when made. What varies by session is filled in as the policy
+// runs: a parameter is an Op::Lit whose value is taken from the session's QueryContext. A
+// session lacking a value its policy needs is refused, rather than shown everything.
+//
+// Only Db queries are filtered; Db::table hands out the whole table, for the embedder's own use.
+//
+// A policy is saved with its table in a snapshot: its program in bytecode.rs layout, then the
+// parameter count (u32) and per parameter its op index (u32), context key and datatype tag.
+
+use crate::audit::QueryContext;
+use crate::bytecode;
+use crate::column::{Column, ColumnT, Scalar};
+use crate::encoding;
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::schema::Datatype;
+use crate::shared::Version;
+use crate::storage::{self, invalid};
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct RowPolicy {
+    code: Vec<Op>,
+    params: Vec<Param>
+}
+
+#[derive(Debug, Clone)]
+struct Param {
+    at: usize,          // of an Op::Lit in the code
+    key: String,        // in QueryContext::values
+    dtype: Datatype
+}
+
+impl RowPolicy {
+    // `code` must leave exactly one value, the mask of visible rows
+    pub fn new(code: Vec<Op>) -> Result<Self, VMError> {
+        let depth = code.iter().try_fold(0usize, |depth, op| {
+            let (pops, pushes) = op.stack_effect();
+            depth.checked_sub(pops).map(|d| d + pushes)
+        });
+        match depth {
+            Some(1) => Ok(RowPolicy { code, params: Vec::new() }),
+            Some(n) => Err(VMError::TypeError(format!("A row policy must leave one mask, but leaves {} values", n))),
+            None => Err(VMError::TypeError("A row policy pops more values than it pushes".to_string()))
+        }
+    }
+
+    // The Op::Lit at `at` takes the context's value for `key`, parsed as a `dtype` (Str, Num,
+    // Entity or Bool)
+    pub fn with_param(mut self, at: usize, key: &str, dtype: Datatype) -> Result<Self, VMError> {
+        if !matches!(self.code.get(at), Some(Op::Lit(_))) {
+            return Err(VMError::TypeError(format!("A row policy parameter must be an Op::Lit, found {:?}", self.code.get(at))));
+        }
+        if !matches!(dtype, Datatype::Str | Datatype::Num | Datatype::Entity | Datatype::Bool) {
+            return Err(VMError::TypeError(format!("Row policy parameters can't be {}", dtype)));
+        }
+        self.params.push(Param { at, key: key.to_string(), dtype });
+        Ok(self)
+    }
+
+    // The policy's program, with the parameters filled in from `context`
+    pub fn bind(&self, context: &QueryContext) -> Result<Vec<Op>, VMError> {
+        let mut code = self.code.clone();
+        for param in &self.params {
+            let value = context.values.get(&param.key)
+                .ok_or_else(|| VMError::TypeError(format!("The query context has no '{}', which the row policy needs", param.key)))?;
+            code[param.at] = Op::Lit(parse(value, param.dtype)?);
+        }
+        Ok(code)
+    }
+
+    pub(crate) fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        bytecode::write_program(w, &self.code)?;
+        storage::write_len(w, self.params.len())?;
+        self.params.iter().try_for_each(|param| {
+            storage::write_len(w, param.at)?;
+            storage::write_str(w, &param.key)?;
+            w.write_all(&[storage::datatype_tag(param.dtype)])
+        })
+    }
+
+    // A policy as `write` saved it, checked as if made afresh
+    pub(crate) fn read<R: Read>(r: &mut R) -> io::Result<RowPolicy> {
+        let rejected = |e: VMError| invalid(format!("{:?}", e));
+        let mut policy = RowPolicy::new(bytecode::read_program(r)?).map_err(rejected)?;
+        for _ in 0 .. storage::read_u32(r)? {
+            let at = storage::read_u32(r)? as usize;
+            let key = storage::read_str(r)?;
+            let mut tag = [0; 1];
+            r.read_exact(&mut tag)?;
+            policy = policy.with_param(at, &key, storage::datatype_of_tag(tag[0])?).map_err(rejected)?;
+        }
+        Ok(policy)
+    }
+
+    // `version` cut down to the rows visible to `context`
+    pub fn apply(&self, version: &Version, context: &QueryContext) -> Result<Version, VMError> {
+        let mut vm = version.vm()?;
+        let res = vm.run(self.bind(context)?);
+        let stack = vm.take_stack();
+        res?;
+        let mask = match stack.first().and_then(|v| vm.column_of(v)).map(encoding::plain).as_deref() {
+            Some(Column::Bool(mask)) if mask.selection().len() == version.rows() => mask.clone(),
+            other => return Err(VMError::TypeError(format!("A row policy must leave a mask of the table's rows, found: {:?}", other)))
+        };
+        Ok(Version {
+            number: version.number,
+            rewritten: version.rewritten,
+            names: version.names.clone(),
+            columns: version.columns.iter().map(|c| Arc::new(c.select(&mask))).collect()
+        })
+    }
+}
+
+fn parse(value: &str, dtype: Datatype) -> Result<Scalar, VMError> {
+    let bad = || VMError::TypeError(format!("Can't parse '{}' as {}", value, dtype));
+    Ok(match dtype {
+        Datatype::Num => Scalar::Num(value.parse().map_err(|_| bad())?),
+        Datatype::Entity => Scalar::Entity(value.parse().map_err(|_| bad())?),
+        Datatype::Bool => Scalar::Bool(value.parse().map_err(|_| bad())?),
+        _ => Scalar::Str(value.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+    use crate::result::ResultSet;
+    use crate::shared::SharedTable;
+
+    use std::convert::TryFrom;
+
+    fn data() -> ResultSet {
+        let mut data = ResultSet::new();
+        data.push("tenant", Column::from(vec!["a", "b", "a", "c"]));
+        data.push("x", Column::from(vec![10u64, 11, 12, 13]));
+        data.push("flag", Column::from(vec![true, false, false, true]));
+        data
+    }
+
+    // rows whose tenant is the session's
+    fn by_tenant() -> RowPolicy {
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Str(String::new())), Op::FilterEq];
+        RowPolicy::new(code).unwrap().with_param(1, "tenant", Datatype::Str).unwrap()
+    }
+
+    fn tenant(name: &str) -> QueryContext {
+        QueryContext::new().with_value("tenant", name)
+    }
+
+    fn xs(version: &Version) -> Vec<u64> {
+        Vec::<u64>::try_from(version.columns[1].as_ref()).unwrap()
+    }
+
+    #[test]
+    fn a_policy_leaves_one_value() {
+        assert!(RowPolicy::new(vec![Op::Col(0, 2)]).is_ok());
+        for code in [vec![], vec![Op::Col(0, 0), Op::Col(0, 1)]] {
+            assert!(matches!(RowPolicy::new(code), Err(VMError::TypeError(ref msg)) if msg.contains("must leave one mask")));
+        }
+        assert!(matches!(RowPolicy::new(vec![Op::FilterEq]), Err(VMError::TypeError(ref msg)) if msg.contains("pops more")));
+    }
+
+    #[test]
+    fn parameters_are_literals_of_simple_types() {
+        assert!(RowPolicy::new(vec![Op::Col(0, 2)]).unwrap().with_param(0, "k", Datatype::Bool).is_err());
+        assert!(RowPolicy::new(vec![Op::Lit(Scalar::Bool(true))]).unwrap().with_param(1, "k", Datatype::Bool).is_err());
+        assert!(RowPolicy::new(vec![Op::Lit(Scalar::Bool(true))]).unwrap().with_param(0, "k", Datatype::Point).is_err());
+    }
+
+    #[test]
+    fn binds_parameters_from_the_context() {
+        let code = vec![Op::Lit(Scalar::Num(0.0)), Op::Lit(Scalar::Num(0.0)), Op::Lit(Scalar::Num(0.0)), Op::IfElse, Op::Lit(Scalar::Num(0.0)), Op::FilterEq];
+        let policy = RowPolicy::new(code).unwrap()
+            .with_param(0, "flag", Datatype::Bool).unwrap()
+            .with_param(1, "n", Datatype::Num).unwrap()
+            .with_param(2, "id", Datatype::Entity).unwrap()
+            .with_param(4, "who", Datatype::Str).unwrap();
+        let context = QueryContext::new().with_value("flag", "true").with_value("n", "-1.5").with_value("id", "7").with_value("who", "ann");
+        let bound = policy.bind(&context).unwrap();
+        assert_eq!(bound, vec![
+            Op::Lit(Scalar::Bool(true)), Op::Lit(Scalar::Num(-1.5)), Op::Lit(Scalar::Entity(7)), Op::IfElse,
+            Op::Lit(Scalar::Str("ann".to_string())), Op::FilterEq
+        ]);
+        let context = context.with_value("id", "-7");
+        assert!(matches!(policy.bind(&context), Err(VMError::TypeError(ref msg)) if msg == "Can't parse '-7' as Entity"));
+    }
+
+    #[test]
+    fn refuses_a_context_missing_a_parameter() {
+        let version = SharedTable::new(data()).unwrap().snapshot();
+        let res = by_tenant().apply(&version, &QueryContext::new().with_value("user", "ann"));
+        assert!(matches!(res, Err(VMError::TypeError(ref msg)) if msg.contains("has no 'tenant'")));
+        assert_eq!(xs(&by_tenant().apply(&version, &tenant("a")).unwrap()), vec![10, 12]);
+        assert_eq!(xs(&by_tenant().apply(&version, &tenant("z")).unwrap()), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn rejects_anything_but_a_mask_of_the_tables_rows() {
+        let version = SharedTable::new(data()).unwrap().snapshot();
+        let none = QueryContext::new();
+        // the flags of rows with x = 12: a mask, but of one row
+        let short = vec![Op::Col(0, 1), Op::Lit(Scalar::Entity(12)), Op::FilterEq, Op::Col(0, 2), Op::Select(1)];
+        for code in [short, vec![Op::Col(0, 1)], vec![Op::Lit(Scalar::Bool(true))]] {
+            let res = RowPolicy::new(code.clone()).unwrap().apply(&version, &none);
+            assert!(matches!(res, Err(VMError::TypeError(ref msg)) if msg.starts_with("A row policy must leave a mask")), "{:?}", code);
+        }
+        // errors from running it come through
+        assert!(RowPolicy::new(vec![Op::Col(0, 9)]).unwrap().apply(&version, &none).is_err());
+        let visible = RowPolicy::new(vec![Op::Col(0, 2)]).unwrap().apply(&version, &none).unwrap();
+        assert_eq!(xs(&visible), vec![10, 13]);
+        assert_eq!(visible.number, version.number);
+    }
+
+    #[test]
+    fn db_queries_see_only_visible_rows() {
+        let mut db = Db::new();
+        db.create_table("t", data()).unwrap();
+        db.enable_result_cache(4);
+        db.set_row_policy("t", by_tenant()).unwrap();
+        fn run(db: &Db, code: &[Op], context: &QueryContext) -> Vec<u64> {
+            Vec::<u64>::try_from(db.query_in("t", code, &["x"], context).unwrap().column("x").unwrap()).unwrap()
+        }
+        assert_eq!(run(&db, &[Op::Col(0, 1)], &tenant("a")), vec![10, 12]);
+        assert_eq!(run(&db, &[Op::Col(0, 1)], &tenant("b")), vec![11]);
+        // subqueries load only visible rows too: no 'c' row for tenant a
+        let code = [Op::Col(0, 0), Op::ScalarSubquery(vec![Op::Lit(Scalar::Str("c".to_string()))]), Op::FilterEq, Op::Col(0, 1), Op::Select(1)];
+        assert_eq!(run(&db, &code, &tenant("a")), Vec::<u64>::new());
+        assert!(db.query("t", &[Op::Col(0, 1)], &["x"]).is_err());
+        assert!(db.set_row_policy("nobody", by_tenant()).is_err());
+
+        db.clear_row_policy("t").unwrap();
+        assert_eq!(run(&db, &[Op::Col(0, 1)], &QueryContext::new()), vec![10, 11, 12, 13]);
+    }
+}
@@ -0,0 +1,343 @@
+// Columns of fixed-width values - f64 for Num, u64 for Entity, and so on - share one generic
+// implementation. A kernel for them is written once against PrimitiveColumn<T>, using
+// whatever it needs from Native, and the match_primitive! macro instantiates it for each
+// Column variant that holds one.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Cmp, Column, ColumnT, Scalar};
+use crate::compare;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::kernels;
+use crate::schema::Datatype;
+use crate::selection::{FilterPlan, Selection};
+
+use core::cmp::Ordering;
+use core::fmt;
+
+// A value type that can back a PrimitiveColumn
+pub trait Native: Copy + PartialEq + PartialOrd + fmt::Debug + 'static {
+    const DATATYPE: Datatype;
+    const DESCRIPTION: &'static str;   // for type errors: "Expected {DESCRIPTION}, got ..."
+
+    fn from_scalar(s: &Scalar) -> Option<Self>;
+    fn into_scalar(self) -> Scalar;
+    // The Column variant holding a PrimitiveColumn<Self>, and back
+    fn wrap(col: PrimitiveColumn<Self>) -> Column;
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<Self>>;
+
+    // Bitmap of positions equal to `val`; the place for a SIMD version
+    fn eq_mask(data: &[Self], val: Self) -> BitIndex {
+        kernels::mask_by(data, |x| x == val)
+    }
+
+    // Equality for comparing columns, and a total order (see compare.rs)
+    fn same(a: Self, b: Self) -> bool;
+    fn total_cmp(a: Self, b: Self) -> Ordering;
+}
+
+impl Native for f64 {
+    const DATATYPE: Datatype = Datatype::Num;
+    const DESCRIPTION: &'static str = "a numeric value";
+
+    fn from_scalar(s: &Scalar) -> Option<f64> {
+        if let Scalar::Num(x) = s { Some(*x) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Num(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<f64>) -> Column {
+        Column::Num(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<f64>> {
+        if let Column::Num(c) = col { Some(c) } else { None }
+    }
+
+    fn eq_mask(data: &[f64], val: f64) -> BitIndex {
+        kernels::eq_f64(data, val)
+    }
+
+    fn same(a: f64, b: f64) -> bool {
+        crate::column::num_eq(a, b)
+    }
+
+    fn total_cmp(a: f64, b: f64) -> Ordering {
+        compare::cmp_f64(a, b)
+    }
+}
+
+impl Native for u64 {
+    const DATATYPE: Datatype = Datatype::Entity;
+    const DESCRIPTION: &'static str = "an entity-id value";
+
+    fn from_scalar(s: &Scalar) -> Option<u64> {
+        if let Scalar::Entity(x) = s { Some(*x) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Entity(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<u64>) -> Column {
+        Column::Entity(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<u64>> {
+        if let Column::Entity(c) = col { Some(c) } else { None }
+    }
+
+    fn eq_mask(data: &[u64], val: u64) -> BitIndex {
+        kernels::eq_u64(data, val)
+    }
+
+    fn same(a: u64, b: u64) -> bool {
+        a == b
+    }
+
+    fn total_cmp(a: u64, b: u64) -> Ordering {
+        a.cmp(&b)
+    }
+}
+
+impl Native for i64 {
+    const DATATYPE: Datatype = Datatype::Int;
+    const DESCRIPTION: &'static str = "an integer value";
+
+    fn from_scalar(s: &Scalar) -> Option<i64> {
+        if let Scalar::Int(x) = s { Some(*x) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Int(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<i64>) -> Column {
+        Column::Int(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<i64>> {
+        if let Column::Int(c) = col { Some(c) } else { None }
+    }
+
+    fn same(a: i64, b: i64) -> bool {
+        a == b
+    }
+
+    fn total_cmp(a: i64, b: i64) -> Ordering {
+        a.cmp(&b)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrimitiveColumn<T: Native> {
+    pub(crate) data: Buffer<T>
+}
+
+impl<T: Native> PrimitiveColumn<T> {
+    pub fn new(data: Buffer<T>) -> Self {
+        PrimitiveColumn { data }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn values(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn datatype(&self) -> Datatype {
+        T::DATATYPE
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.data.memory_usage()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Scalar> {
+        self.data.get(idx).map(|x| x.into_scalar())
+    }
+
+    // Rows i and j, in the total order of compare.rs
+    pub fn cmp_rows(&self, i: usize, j: usize) -> Ordering {
+        T::total_cmp(self.data[i], self.data[j])
+    }
+
+    // `val` as this column's value type
+    pub(crate) fn native(&self, val: &Scalar) -> Result<T, VMError> {
+        T::from_scalar(val).ok_or_else(|| VMError::TypeError(format!("Expected {}, got: {:?}", T::DESCRIPTION, val)))
+    }
+
+    // Rows offset .. offset + len, which must be in bounds; shares the buffer
+    pub fn slice(&self, offset: usize, len: usize) -> Self {
+        PrimitiveColumn { data: self.data.slice(offset, len) }
+    }
+
+    pub fn gather(&self, sel: &Selection) -> Self {
+        let mut data = Buffer::with_capacity(sel.count_ones());
+        sel.for_each(|idx| data.push(self.data[idx]));
+        PrimitiveColumn { data }
+    }
+
+    pub fn gather_where<F: Fn(usize) -> bool>(&self, pred: F) -> Self {
+        PrimitiveColumn { data: (0 .. self.len()).filter(|i| pred(*i)).map(|i| self.data[i]).collect() }
+    }
+
+    // FilterEq, and how it went about it (see kernels::filter_adaptive)
+    pub fn filter_planned(&self, val: &Scalar) -> Result<(BoolColumn, FilterPlan), VMError> {
+        let x = self.native(val)?;
+        let (sel, plan) = kernels::filter_adaptive(&self.data, |xs| T::eq_mask(xs, x), |y| y == x);
+        Ok((BoolColumn::from_selection(sel), plan))
+    }
+
+    // FilterEq over the rows in `sel` (see Column::filter_at)
+    pub fn filter_at(&self, val: &Scalar, sel: &Selection) -> Result<BoolColumn, VMError> {
+        let x = self.native(val)?;
+        let mut positions = BitIndex::for_col_len(sel.count_ones());
+        let mut i = 0;
+        sel.for_each(|idx| {
+            if self.data[idx] == x { positions.set(i); }
+            i += 1;
+        });
+        Ok(BoolColumn::from_mask(positions))
+    }
+
+    // lo <= x < hi
+    pub fn filter_range(&self, lo: &Scalar, hi: &Scalar) -> Result<BoolColumn, VMError> {
+        let (lo, hi) = (self.native(lo)?, self.native(hi)?);
+        Ok(BoolColumn::from_mask(kernels::mask_by(&self.data, |x| lo <= x && x < hi)))
+    }
+}
+
+impl<T: Native> ColumnT for PrimitiveColumn<T> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        self.filter_planned(&val).map(|(mask, _)| mask)
+    }
+
+    fn select(&self, mask: &BoolColumn) -> Self {
+        self.gather(mask.selection())
+    }
+
+    // by partial_cmp, so NaN compares with nothing
+    fn filter_cmp(&self, cmp: Cmp, val: Scalar) -> Result<BoolColumn, VMError> {
+        let y = self.native(&val)?;
+        Ok(BoolColumn::from_mask(kernels::mask_by(&self.data, |x| x.partial_cmp(&y).is_some_and(|o| cmp.holds(o)))))
+    }
+}
+
+impl<T: Native> PartialEq for PrimitiveColumn<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.data.iter().zip(other.data.iter()).all(|(a, b)| T::same(*a, *b))
+    }
+}
+
+impl<T: Native> From<PrimitiveColumn<T>> for Column {
+    fn from(col: PrimitiveColumn<T>) -> Column {
+        T::wrap(col)
+    }
+}
+
+// `match $col { .. }` with an arm per primitive Column variant, each running `$body` with `$c`
+// bound to the PrimitiveColumn it holds, followed by the arms given in `$rest` for the other
+// variants. The body is type-checked separately for each value type.
+//     match_primitive!(self, c => c.len(), Column::Bool(c) => c.data.len(), ...)
+macro_rules! match_primitive {
+    ($col:expr, $c:ident => $body:expr, $($rest:tt)*) => {
+        match $col {
+            $crate::column::Column::Num($c) => $body,
+            $crate::column::Column::Int($c) => $body,
+            $crate::column::Column::Entity($c) => $body,
+            $crate::column::Column::Duration($c) => $body,
+            $crate::column::Column::Timestamp($c) => $body,
+            $crate::column::Column::Point($c) => $body,
+            $crate::column::Column::Ipv4($c) => $body,
+            $crate::column::Column::Ipv6($c) => $body,
+            $($rest)*
+        }
+    };
+}
+
+pub(crate) use match_primitive;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn col<T: Native>(values: &[T]) -> PrimitiveColumn<T> {
+        PrimitiveColumn::new(values.iter().copied().collect())
+    }
+
+    // The kernels, checked against the values one at a time
+    fn agrees_with_the_values<T: Native>(values: &[T], probe: T, lo: T, hi: T) {
+        let c = col(values);
+        let eq: Vec<usize> = (0 .. values.len()).filter(|i| values[*i] == probe).collect();
+        assert_eq!(c.filter(probe.into_scalar()).unwrap().selection().positions(), eq);
+        let range: Vec<usize> = (0 .. values.len()).filter(|i| lo <= values[*i] && values[*i] < hi).collect();
+        assert_eq!(c.filter_range(&lo.into_scalar(), &hi.into_scalar()).unwrap().selection().positions(), range);
+
+        let odd = Selection::from_positions((1 .. values.len() as u32).step_by(2).collect(), values.len());
+        let gathered = c.gather(&odd);
+        assert_eq!(gathered.values(), values.iter().skip(1).step_by(2).copied().collect::<Vec<_>>().as_slice());
+        assert_eq!(c.filter_at(&probe.into_scalar(), &odd).unwrap().selection().positions(),
+                   gathered.filter(probe.into_scalar()).unwrap().selection().positions());
+        assert_eq!(c.gather_where(|i| i % 2 == 1), gathered);
+
+        assert_eq!(c.slice(1, 2).values(), &values[1 .. 3]);
+        assert_eq!(c.get(1), Some(values[1].into_scalar()));
+        assert_eq!(c.get(values.len()), None);
+        assert_eq!(c.datatype(), T::DATATYPE);
+        assert_eq!(Column::from(c).datatype(), T::DATATYPE);
+    }
+
+    #[test]
+    fn kernels_work_for_each_value_type() {
+        agrees_with_the_values(&[3.0, 1.0, 3.0, -2.5, 7.0, 3.0], 3.0, 0.0, 3.5);
+        agrees_with_the_values(&[3u64, 1, 3, 9, 7, 3], 3, 1, 8);
+        agrees_with_the_values(&[3i64, -1, i64::MAX, -9, 7, 3], 3, -1, 8);
+    }
+
+    #[test]
+    fn values_of_another_type_are_type_errors() {
+        let nums = col(&[1.0]);
+        let err = nums.filter(Scalar::Entity(1)).unwrap_err();
+        assert!(matches!(&err, VMError::TypeError(msg) if msg.starts_with("Expected a numeric value")), "{:?}", err);
+        let ids = col(&[1u64]);
+        let err = ids.filter_range(&Scalar::Entity(0), &Scalar::Num(2.0)).unwrap_err();
+        assert!(matches!(&err, VMError::TypeError(msg) if msg.starts_with("Expected an entity-id value")), "{:?}", err);
+        let all = Selection::from_positions(vec![0], 1);
+        assert!(matches!(ids.filter_at(&Scalar::Str("1".to_string()), &all), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn equality_and_order_follow_compare() {
+        assert_eq!(col(&[f64::NAN, -0.0]), col(&[f64::NAN, 0.0]));
+        assert_ne!(col(&[1.0]), col(&[1.0, 1.0]));
+        let c = col(&[f64::NAN, 1.0]);
+        assert_eq!(c.cmp_rows(0, 1), Ordering::Greater);
+        assert_eq!(col(&[2u64, 5]).cmp_rows(0, 1), Ordering::Less);
+    }
+
+    #[test]
+    fn match_primitive_dispatches_by_variant() {
+        fn describe(col: &Column) -> String {
+            match_primitive!(col, c => format!("{:?} x{}", c.datatype(), c.len()),
+                _ => "other".to_string()
+            )
+        }
+        assert_eq!(describe(&Column::from(vec![1.0, 2.0])), "Num x2");
+        assert_eq!(describe(&Column::from(vec![1u64])), "Entity x1");
+        assert_eq!(describe(&Column::from(vec!["a"])), "other");
+    }
+}
@@ -1,160 +1,311 @@
-// An experimental demonstration of how we might store packed struct/record type columns.
-// Compare/contrast with the Arrow spec on this
-// requires nightly
+// Record columns: a fixed list of typed fields per row, packed row by row - each record's
+// fields back to back in one byte buffer, in field order. Arrow instead stores a struct as a
+// column per field; packing suits records whose fields are almost always read together, like
+// `record Point(x: Num, y: Num)`.
+//
+// Fields are Bool (a byte), Num, Int, Entity, Duration or Timestamp (eight bytes each,
+// little-endian), or Str. Strings are kept out of line, each distinct one once, and a record
+// holds the four-byte index of its string, as a dictionary-encoded column would. Op::Field
+// projects one field out as a column of its own.
 
-// n.b. Arrow stores structs by decomposing into one columnar array per field;
-// but with a validity bitmap for the whole thing
-// which is (sorta) equivalent to an EntityColumn + multiple other columns for entities of that type
-// the whole point here is mainly for things like `record Point(x: Num, y: Num)`
-// where you will almost always want to access all values of a struct or "row" at the same time
+use crate::buffer::Buffer;
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, ColumnT, Scalar};
+use crate::core::prelude::*;
+use crate::core::HashMap;
+use crate::duration::Nanos;
+use crate::errors::VMError;
+use crate::nulls;
+use crate::schema::Datatype;
+use crate::timestamp::Micros;
 
-// possible improvements:
-// - store strings inline by converting them to bytes (now your records are variable width)
-// - if you don't want to store them inline, instead of pointers, try something like Arrow's
-//      Dictionary encoding - put the string data out-of-band and store an index?
+use alloc::sync::Arc;
+use core::convert::TryInto;
 
-#![feature(vec_into_raw_parts)]
-
-use std::convert::TryInto;
-
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum Datatype {
-    Bool,
-    Num,
-    Str,
-    Entity,
-    Record
-}
-
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RecordColumn {
-    // For now, we only store records of scalars, no nesting
-    data: Vec<u8>,
-    pub len: usize,         // how many records are stored - not how many bytes
-    width: usize,           // how many bytes does each record occupy?
-    offsets: Vec<usize>,    // where does each record start?
-    fields: Vec<Datatype>
+    fields: Arc<Vec<Datatype>>,
+    width: usize,               // bytes per record
+    len: usize,
+    data: Buffer<u8>,
+    strings: Arc<Vec<String>>   // the Str fields' values, indexed from the records
 }
 
-fn width_of(dt: &Datatype) -> usize {
-    // How many bytes to store each datatype?
-    match *dt {
-        Datatype::Bool => 1,
-        Datatype::Num  => 8,
-        Datatype::Str  => 24,   // 3x8 - ptr, len, capacity
-        Datatype::Entity => 8,
-        Datatype::Record => 24, // ptr, len, capacity, like string?
+// How many bytes a field of a type takes up, for the types a record can hold
+fn width_of(dtype: Datatype) -> Option<usize> {
+    match dtype {
+        Datatype::Bool => Some(1),
+        Datatype::Num | Datatype::Int | Datatype::Entity | Datatype::Duration | Datatype::Timestamp => Some(8),
+        Datatype::Str => Some(4),
+        _ => None
     }
 }
 
-fn read_f64(input: &[u8]) -> f64 {
-    let (bytes, _rest) = input.split_at(8);
-    f64::from_le_bytes(bytes.try_into().unwrap())
+fn word(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[.. 8].try_into().expect("eight bytes"))
 }
 
-fn read_u64(input: &[u8]) -> u64 {
-    let (bytes, _rest) = input.split_at(8);
-    u64::from_le_bytes(bytes.try_into().unwrap())
+fn index(bytes: &[u8]) -> usize {
+    u32::from_le_bytes(bytes[.. 4].try_into().expect("four bytes")) as usize
 }
 
-unsafe fn read_string(input: &[u8]) -> String {
-    // SAFETY: MUST have been previously written with RecordColumn::insert
-    let (ptr_bytes, rest) = input.split_at(std::mem::size_of::<&u8>());
-    let (len_bytes, rest) = rest.split_at(8);
-    let (cap_bytes, _)    = rest.split_at(8);
-    let ptr = (usize::from_le_bytes(ptr_bytes.try_into().unwrap())) as *mut u8;
-    let len = usize::from_le_bytes(len_bytes.try_into().unwrap());
-    let cap = usize::from_le_bytes(cap_bytes.try_into().unwrap());
-    String::from_raw_parts(ptr, len, cap)
+// Records, packed one at a time
+struct Packer {
+    fields: Arc<Vec<Datatype>>,
+    data: Vec<u8>,
+    strings: Vec<String>,
+    ids: HashMap<String, u32>,
+    len: usize
 }
 
-impl RecordColumn {
-    pub fn for_fields(fields: Vec<Datatype>) -> Self {
-        // calculate offsets for fields -- cumulative sum
-        let width = fields
-            .iter()
-            .map(|f| width_of(f))
-            .sum();
-        RecordColumn { data: Vec::new(), offsets: Vec::new(), fields, len: 0, width }
-    }
-
-    pub fn insert(&mut self, data: Vec<Scalar>) -> () {
-        // data MUST be a vector of scalars of the same types, in the same order, as self.fields
-        // this is not checked - assume our hypothetical compiler generates correct code
-        for d in data {
-            match d {
-                Scalar::Bool(x) => self.data.push(x as u8),
-                Scalar::Num(x)  => self.data.extend(x.to_le_bytes()),
-                Scalar::Str(x)  => {
-                    // requires nightly
-                    let (ptr, len, capacity) = x.into_raw_parts();
-                    self.data.extend((ptr as u64).to_le_bytes());
-                    self.data.extend((len as u64).to_le_bytes());
-                    self.data.extend((capacity as u64).to_le_bytes());
+impl Packer {
+    fn new(fields: Vec<Datatype>) -> Result<Packer, VMError> {
+        if let Some(dtype) = fields.iter().find(|f| width_of(**f).is_none()) {
+            return Err(VMError::TypeError(format!("A record can't have a {} field", dtype)));
+        }
+        Ok(Packer { fields: Arc::new(fields), data: Vec::new(), strings: Vec::new(), ids: HashMap::new(), len: 0 })
+    }
+
+    fn push(&mut self, record: &[Scalar]) -> Result<(), VMError> {
+        if record.len() != self.fields.len() {
+            return Err(VMError::TypeError(format!("Expected a record of {} fields, got: {:?}", self.fields.len(), record)));
+        }
+        for (dtype, val) in self.fields.iter().zip(record) {
+            match (dtype, val) {
+                (Datatype::Bool, Scalar::Bool(x)) => self.data.push(*x as u8),
+                (Datatype::Num, Scalar::Num(x)) => self.data.extend_from_slice(&x.to_bits().to_le_bytes()),
+                (Datatype::Int, Scalar::Int(x)) => self.data.extend_from_slice(&x.to_le_bytes()),
+                (Datatype::Entity, Scalar::Entity(x)) => self.data.extend_from_slice(&x.to_le_bytes()),
+                (Datatype::Duration, Scalar::Duration(x)) => self.data.extend_from_slice(&x.0.to_le_bytes()),
+                (Datatype::Timestamp, Scalar::Timestamp(x)) => self.data.extend_from_slice(&x.0.to_le_bytes()),
+                (Datatype::Str, Scalar::Str(s)) => {
+                    let id = match self.ids.get(s) {
+                        Some(id) => *id,
+                        None => {
+                            let id = self.strings.len().try_into()
+                                .map_err(|_| VMError::TypeError("Too many distinct strings for a record column".to_string()))?;
+                            self.ids.insert(s.clone(), id);
+                            self.strings.push(s.clone());
+                            id
+                        }
+                    };
+                    self.data.extend_from_slice(&id.to_le_bytes());
                 },
-                Scalar::Entity(x) => self.data.extend(x.to_le_bytes())
+                (dtype, val) => return Err(VMError::TypeError(format!("Expected {} for a record field, got: {:?}", dtype, val)))
             }
         }
         self.len += 1;
+        Ok(())
     }
 
-    pub fn get(&self, idx: usize) -> Vec<Scalar> {
-        let mut offset = self.width * idx;
-        let mut res = Vec::new();
-        for dtype in &self.fields {
-            let val = match dtype {
-                Datatype::Bool => {
-                    let v = Scalar::Bool(self.data[offset] != 0);
-                    offset += 1;
-                    v
-                },
-                Datatype::Num => {
-                    let num = read_f64(&self.data[offset..offset+8]);
-                    offset += 8;
-                    Scalar::Num(num)
-                },
-                Datatype::Entity => {
-                    let num = read_u64(&self.data[offset..offset+8]);
-                    offset += 8;
-                    Scalar::Entity(num)
-                },
-                Datatype::Str => {
-                    let slice = &self.data[offset..offset+24];
-                    offset += 24;
-                    unsafe {
-                        // SAFETY: bytes representing string will have been written with `self.insert`
-                        Scalar::Str(read_string(slice))
-                    }
-                }
-            };
-            res.push(val);
+    fn finish(self) -> RecordColumn {
+        let width = self.fields.iter().map(|f| width_of(*f).expect("checked in new")).sum();
+        RecordColumn { fields: self.fields, width, len: self.len, data: Buffer::from(self.data), strings: Arc::new(self.strings) }
+    }
+}
+
+impl RecordColumn {
+    // Records with fields of the given types, each record a field per type, in that order
+    pub fn from_records(fields: Vec<Datatype>, records: &[Vec<Scalar>]) -> Result<RecordColumn, VMError> {
+        let mut packer = Packer::new(fields)?;
+        records.iter().try_for_each(|r| packer.push(r))?;
+        Ok(packer.finish())
+    }
+
+    // Records of a field per column, all of the same length: row i is every column's row i
+    pub fn from_fields(columns: &[Column]) -> Result<RecordColumn, VMError> {
+        let len = columns.first().map(|c| c.len()).unwrap_or(0);
+        if let Some(c) = columns.iter().find(|c| c.len() != len) {
+            return Err(VMError::LengthMismatch { expected: len, found: c.len() });
         }
-        res
+        let mut packer = Packer::new(columns.iter().map(|c| c.datatype()).collect())?;
+        for row in 0 .. len {
+            packer.push(&columns.iter().map(|c| c.get(row).expect("row in bounds")).collect::<Vec<_>>())?;
+        }
+        Ok(packer.finish())
+    }
+
+    // The records of several columns with the same fields, one after another
+    pub fn concat(parts: &[&RecordColumn]) -> Result<RecordColumn, VMError> {
+        let fields = parts.first().map(|c| c.fields.to_vec()).unwrap_or_default();
+        if let Some(c) = parts.iter().find(|c| *c.fields != fields) {
+            return Err(VMError::TypeError(format!("Can't put records of {:?} after records of {:?}", c.fields, fields)));
+        }
+        let mut packer = Packer::new(fields)?;
+        for c in parts {
+            (0 .. c.len()).try_for_each(|i| packer.push(&c.record(i)))?;
+        }
+        Ok(packer.finish())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The types of the fields
+    pub fn fields(&self) -> &[Datatype] {
+        &self.fields
+    }
+
+    // Where field j starts within a record
+    fn offset_of(&self, j: usize) -> usize {
+        self.fields[.. j].iter().map(|f| width_of(*f).expect("checked when packed")).sum()
+    }
+
+    fn decode(&self, dtype: Datatype, bytes: &[u8]) -> Scalar {
+        match dtype {
+            Datatype::Bool => Scalar::Bool(bytes[0] != 0),
+            Datatype::Num => Scalar::Num(f64::from_bits(word(bytes))),
+            Datatype::Int => Scalar::Int(word(bytes) as i64),
+            Datatype::Entity => Scalar::Entity(word(bytes)),
+            Datatype::Duration => Scalar::Duration(Nanos(word(bytes) as i64)),
+            Datatype::Timestamp => Scalar::Timestamp(Micros(word(bytes) as i64)),
+            Datatype::Str => Scalar::Str(self.strings[index(bytes)].clone()),
+            _ => unreachable!("only fixed-width fields are packed")
+        }
+    }
+
+    // Record i's fields, which must be in bounds
+    fn record(&self, i: usize) -> Vec<Scalar> {
+        let bytes = &self.data[i * self.width .. (i + 1) * self.width];
+        let mut at = 0;
+        self.fields.iter().map(|f| {
+            let val = self.decode(*f, &bytes[at ..]);
+            at += width_of(*f).expect("checked when packed");
+            val
+        }).collect()
+    }
+
+    pub fn get(&self, i: usize) -> Option<Scalar> {
+        match i < self.len {
+            true => Some(Scalar::Record(self.record(i))),
+            false => None
+        }
+    }
+
+    // Field j of every record, as a column of the field's type
+    pub fn field(&self, j: usize) -> Result<Column, VMError> {
+        let dtype = match self.fields.get(j) {
+            Some(dtype) => *dtype,
+            None => return Err(VMError::TypeError(format!("Record has {} fields, so there's no field {}", self.fields.len(), j)))
+        };
+        let at = self.offset_of(j);
+        let rows = (0 .. self.len).map(|i| &self.data[i * self.width + at ..]);
+        Ok(match dtype {
+            Datatype::Bool => Column::from(rows.map(|b| b[0] != 0).collect::<Vec<bool>>()),
+            Datatype::Num => Column::from(rows.map(|b| f64::from_bits(word(b))).collect::<Vec<f64>>()),
+            Datatype::Int => Column::from(rows.map(|b| word(b) as i64).collect::<Vec<i64>>()),
+            Datatype::Entity => Column::from(rows.map(word).collect::<Vec<u64>>()),
+            Datatype::Duration => Column::from(rows.map(|b| Nanos(word(b) as i64)).collect::<Vec<Nanos>>()),
+            Datatype::Timestamp => Column::from(rows.map(|b| Micros(word(b) as i64)).collect::<Vec<Micros>>()),
+            Datatype::Str => Column::from(rows.map(|b| self.strings[index(b)].as_str()).collect::<Vec<&str>>()),
+            _ => unreachable!("only fixed-width fields are packed")
+        })
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.data.memory_usage() + self.strings.iter().map(|s| s.len()).sum::<usize>()
+    }
+
+    // Records offset .. offset + len, which must be in bounds; shares the strings
+    pub fn slice(&self, offset: usize, len: usize) -> RecordColumn {
+        RecordColumn { data: self.data.slice(offset * self.width, len * self.width), len, ..self.clone() }
+    }
+
+    // The records at `rows`, in that order, which must be in bounds; shares the strings
+    pub fn take(&self, rows: &[usize]) -> RecordColumn {
+        let mut data = Buffer::with_capacity(rows.len() * self.width);
+        rows.iter().for_each(|r| data.extend_from_slice(&self.data[r * self.width .. (r + 1) * self.width]));
+        RecordColumn { data, len: rows.len(), ..self.clone() }
     }
 }
 
+impl ColumnT for RecordColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    // Records equal to the given one, field by field. A record with a null field is null, so
+    // it matches nothing.
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        let fields = match &val {
+            Scalar::Record(fields) => fields,
+            _ => return Err(VMError::TypeError(format!("Expected a record value, got: {:?}", val)))
+        };
+        let mut mask = BitIndex::for_col_len(self.len);
+        if fields.len() == self.fields.len() && !nulls::is_null(&val) {
+            (0 .. self.len).filter(|i| self.record(*i) == *fields).for_each(|i| mask.set(i));
+        }
+        Ok(BoolColumn::from_mask(mask))
+    }
+
+    fn select(&self, mask: &BoolColumn) -> Self {
+        let mut rows = Vec::with_capacity(mask.selection().count_ones());
+        mask.selection().for_each(|i| rows.push(i));
+        self.take(&rows)
+    }
+}
+
+// Same fields, and the same values in them
+impl PartialEq for RecordColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.fields == other.fields && (0 .. self.len).all(|i| self.record(i) == other.record(i))
+    }
+}
 
-fn main() {
-    let mut rc = RecordColumn::for_fields(vec![
-        Datatype::Bool,
-        Datatype::Num,
-        Datatype::Str
-    ]);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let a_record = vec![
-        Scalar::Bool(true),
-        Scalar::Num(12.3),
-        Scalar::Str(String::from("hello"))
-    ];
-    let another_record = a_record.clone();
+    fn points() -> RecordColumn {
+        let rec = |x, y, name: &str| vec![Scalar::Num(x), Scalar::Num(y), Scalar::Str(name.to_string())];
+        let records = [rec(0.0, 1.0, "a"), rec(2.0, 3.0, "b"), rec(0.0, 1.0, "a"), rec(4.0, f64::NAN, "b")];
+        RecordColumn::from_records(vec![Datatype::Num, Datatype::Num, Datatype::Str], &records).unwrap()
+    }
 
-    rc.insert(a_record);
-    rc.insert(another_record);
+    #[test]
+    fn packs_records_and_reads_them_back() {
+        let col = points();
+        assert_eq!((col.len(), col.width), (4, 20));
+        assert_eq!(col.get(1), Some(Scalar::Record(vec![Scalar::Num(2.0), Scalar::Num(3.0), Scalar::Str("b".to_string())])));
+        assert_eq!(col.get(4), None);
+        // each distinct string once
+        assert_eq!(*col.strings, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(col.field(2).unwrap(), Column::from(vec!["a", "b", "a", "b"]));
+        assert_eq!(col.field(0).unwrap(), Column::from(vec![0.0, 2.0, 0.0, 4.0]));
+        assert!(matches!(col.field(3), Err(VMError::TypeError(_))));
+        let by_field = RecordColumn::from_fields(&[col.field(0).unwrap(), col.field(1).unwrap(), col.field(2).unwrap()]).unwrap();
+        assert_eq!(by_field, col);
+    }
 
-    println!("{:?}", rc);
+    #[test]
+    fn records_must_fit_the_fields() {
+        let fields = vec![Datatype::Int, Datatype::Bool];
+        let bad = [vec![vec![Scalar::Int(1)]], vec![vec![Scalar::Int(1), Scalar::Num(1.0)]]];
+        for records in bad.iter() {
+            assert!(matches!(RecordColumn::from_records(fields.clone(), records), Err(VMError::TypeError(_))));
+        }
+        assert!(matches!(RecordColumn::from_records(vec![Datatype::Json], &[]), Err(VMError::TypeError(_))));
+        let short = Column::from(vec![1.0]);
+        assert!(matches!(RecordColumn::from_fields(&[points().field(0).unwrap(), short]), Err(VMError::LengthMismatch { expected: 4, found: 1 })));
+    }
 
-    let res = rc.get(1);
-    println!("{:?}", res);
-}
\ No newline at end of file
+    #[test]
+    fn filters_and_selects_whole_records() {
+        let col = points();
+        let a = col.get(0).unwrap();
+        assert_eq!(col.filter(a.clone()).unwrap().selection().positions(), vec![0, 2]);
+        // a record with a null in it equals nothing, itself included
+        assert_eq!(col.filter(col.get(3).unwrap()).unwrap().selection().positions(), Vec::<usize>::new());
+        assert_eq!(col.filter(Scalar::Record(vec![Scalar::Num(0.0)])).unwrap().selection().positions(), Vec::<usize>::new());
+        assert!(matches!(col.filter(Scalar::Num(0.0)), Err(VMError::TypeError(_))));
+        let picked = col.select(&BoolColumn::from_mask(col.filter(a.clone()).unwrap().selection().to_bitmap().inverted()));
+        assert_eq!(picked.field(2).unwrap(), Column::from(vec!["b", "b"]));
+        assert_eq!(col.slice(1, 2).get(1), Some(a));
+        assert_eq!(RecordColumn::concat(&[&col.slice(2, 2), &col.slice(0, 2)]).unwrap(), col.take(&[2, 3, 0, 1]));
+    }
+}
@@ -0,0 +1,350 @@
+use crate::column::{Column, Scalar};
+use crate::core::prelude::*;
+use crate::schema::Datatype;
+
+use core::fmt;
+
+// Named output columns of a query, in order
+#[derive(Clone, PartialEq, Default)]
+pub struct ResultSet {
+    pub names: Vec<String>,
+    pub columns: Vec<Column>
+}
+
+impl ResultSet {
+    pub fn new() -> Self {
+        ResultSet::default()
+    }
+
+    pub fn push(&mut self, name: &str, col: Column) {
+        self.names.push(name.to_string());
+        self.columns.push(col);
+    }
+
+    // Number of columns
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    // Number of rows, i.e. the length of the longest column
+    pub fn rows(&self) -> usize {
+        self.columns.iter().map(|c| c.len()).max().unwrap_or(0)
+    }
+
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.names.iter().position(|n| n == name).map(|i| &self.columns[i])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &Column)> {
+        self.names.iter().map(|n| n.as_str()).zip(self.columns.iter())
+    }
+
+    // The values of row `idx`, one per column; None if some column has no such row
+    pub fn row(&self, idx: usize) -> Option<Vec<Scalar>> {
+        self.columns.iter().map(|c| c.get(idx)).collect()
+    }
+
+    // Rows up to the length of the shortest column
+    pub fn iter_rows(&self) -> impl Iterator<Item=Vec<Scalar>> + '_ {
+        let rows = self.columns.iter().map(|c| c.len()).min().unwrap_or(0);
+        (0 .. rows).filter_map(move |i| self.row(i))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TableFormat {
+    pub max_rows: usize,        // rows past this are left out, and the footer says so
+    pub max_width: usize        // longer cells are cut short, ending in "…"
+}
+
+impl Default for TableFormat {
+    fn default() -> Self {
+        TableFormat { max_rows: 20, max_width: 32 }
+    }
+}
+
+// Strings are shown bare in tables; everything else as Scalar displays it
+fn cell(val: Scalar) -> String {
+    match val {
+        Scalar::Str(s) => s,
+        other => other.to_string()
+    }
+}
+
+// A value as it's shown in a table cell, at most `width` characters
+pub(crate) fn cell_text(val: Scalar, width: usize) -> String {
+    truncate(cell(val), width)
+}
+
+fn truncate(s: String, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s;
+    }
+    let mut cut: String = s.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+impl ResultSet {
+    // An aligned ASCII table: header, one line per row (numbers right-aligned), and a footer
+    // with the row count
+    pub fn fmt_table(&self, format: &TableFormat) -> String {
+        if self.columns.is_empty() {
+            return "(no columns)".to_string();
+        }
+        let rows = self.rows();
+        let shown = rows.min(format.max_rows);
+        let columns: Vec<Vec<String>> = self.columns.iter()
+            .map(|c| (0 .. shown).filter_map(|i| c.get(i)).map(|v| cell_text(v, format.max_width)).collect())
+            .collect();
+        let names: Vec<String> = self.names.iter().map(|n| truncate(n.clone(), format.max_width)).collect();
+        let widths: Vec<usize> = names.iter().zip(columns.iter())
+            .map(|(n, cells)| cells.iter().chain(core::iter::once(n)).map(|s| s.chars().count()).max().unwrap_or(0))
+            .collect();
+
+        let rule: String = widths.iter().map(|w| format!("+{}", "-".repeat(w + 2))).collect::<String>() + "+\n";
+        let mut out = rule.clone();
+        for (n, w) in names.iter().zip(widths.iter()) {
+            out += &format!("| {:<w$} ", n, w = w);
+        }
+        out += "|\n";
+        out += &rule;
+        for row in 0 .. shown {
+            for ((cells, col), w) in columns.iter().zip(self.columns.iter()).zip(widths.iter()) {
+                let cell = cells.get(row).map(|s| s.as_str()).unwrap_or("");
+                match col.datatype() {
+                    Datatype::Num | Datatype::Int | Datatype::Duration => out += &format!("| {:>w$} ", cell, w = w),
+                    _ => out += &format!("| {:<w$} ", cell, w = w)
+                }
+            }
+            out += "|\n";
+        }
+        out += &rule;
+        out += &match (rows, shown) {
+            (1, _) => "1 row".to_string(),
+            (n, m) if n == m => format!("{} rows", n),
+            (n, m) => format!("{} rows ({} shown)", n, m)
+        };
+        out
+    }
+}
+
+impl fmt::Display for ResultSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.fmt_table(&TableFormat::default()))
+    }
+}
+
+// Tables are far easier to read than the column structs, e.g. in assert_columns_eq! failures
+impl fmt::Debug for ResultSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\n{}", self)
+    }
+}
+
+impl From<Vec<(&str, Column)>> for ResultSet {
+    fn from(v: Vec<(&str, Column)>) -> Self {
+        let mut res = ResultSet::new();
+        v.into_iter().for_each(|(name, col)| res.push(name, col));
+        res
+    }
+}
+
+// A ResultSet from names and lists of values, each list made into a column by col!
+//     table!{"name" => ["alice", "bob"], "age" => [18.0, 42.0]}
+#[macro_export]
+macro_rules! table {
+    ($($name:expr => [$($x:tt)*]),* $(,)?) => {
+        $crate::ResultSet::from($crate::core::__vec![$(($name, $crate::col![$($x)*])),*])
+    };
+}
+
+// Like assert_eq!, for Columns and ResultSets: compares logically (see `impl PartialEq for
+// Column`), so the expected value can be written as plain columns whatever encoding the
+// actual result came back in.
+#[macro_export]
+macro_rules! assert_columns_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => if !(*left == *right) {
+                panic!("assertion failed: `(left == right)`\n  left: {:?}\n right: {:?}", left, right)
+            }
+        }
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => if !(*left == *right) {
+                panic!("assertion failed: `(left == right)`: {}\n  left: {:?}\n right: {:?}", format_args!($($arg)+), left, right)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{BoolColumn, ColumnT, Scalar, StrColumn};
+    use crate::encoding::{self, Encoding};
+    use core::convert::TryFrom;
+
+    fn strs(v: &[&str]) -> Column {
+        Column::Str(StrColumn::new(v.iter().map(|s| s.to_string()).collect()))
+    }
+
+    // sorted whole numbers in runs, which every numeric encoding takes
+    fn nums() -> Column {
+        Column::from((0 .. 200).map(|i| (1_000 + i / 10) as f64).collect::<Vec<_>>())
+    }
+
+    fn ids() -> Column {
+        Column::from((0 .. 200).map(|i| 1_000 + i / 10).collect::<Vec<u64>>())
+    }
+
+    #[test]
+    fn floats_compare_by_num_eq() {
+        assert_columns_eq!(Column::from(vec![f64::NAN, 1.0]), Column::from(vec![f64::NAN, 1.0]));
+        assert_columns_eq!(Column::from(vec![-0.0]), Column::from(vec![0.0]));
+        assert_ne!(Column::from(vec![1.0]), Column::from(vec![1.0 + f64::EPSILON]));
+        assert_ne!(Column::from(vec![f64::NAN]), Column::from(vec![0.0]));
+        assert_ne!(Column::from(vec![f64::INFINITY]), Column::from(vec![f64::NEG_INFINITY]));
+        // same values, different types
+        assert_ne!(Column::from(vec![1.0]), Column::from(vec![1u64]));
+    }
+
+    #[test]
+    fn strings_compare_whatever_their_layout() {
+        let inline = Column::from(vec!["a", "bb", ""]);
+        assert_columns_eq!(strs(&["a", "bb", ""]), inline);
+        assert_columns_eq!(inline, strs(&["a", "bb", ""]));
+        assert_ne!(strs(&["a", "bb", "c"]), inline);
+        assert_ne!(strs(&["a", "bb"]), inline);
+
+        let repeated: Vec<&str> = (0 .. 200).map(|i| ["x", "y"][i % 2]).collect();
+        let dict = encoding::encode(strs(&repeated), Encoding::Dict).unwrap();
+        assert!(matches!(dict, Column::Dict(_)));
+        assert_columns_eq!(dict, strs(&repeated));
+        assert_columns_eq!(Column::from(repeated.clone()), dict);
+    }
+
+    #[test]
+    fn encoded_columns_equal_their_plain_form() {
+        let other = Column::from((0 .. 200).map(|i| (1_000 + i / 10 + (i == 150) as u64) as f64).collect::<Vec<_>>());
+        for encoding in &[Encoding::Rle, Encoding::Delta, Encoding::For, Encoding::Packed] {
+            let encoded = encoding::encode(nums(), *encoding).unwrap();
+            assert!(!matches!(encoded, Column::Num(_)), "{:?} left the column plain", encoding);
+            assert_columns_eq!(encoded, nums(), "{:?}", encoding);
+            assert_columns_eq!(nums(), encoded, "{:?}", encoding);
+            assert_columns_eq!(encoded, encoding::encode(nums(), Encoding::Rle).unwrap(), "{:?} against Rle", encoding);
+            assert_ne!(encoded, other, "{:?}", encoding);
+        }
+        assert_ne!(encoding::encode(nums(), Encoding::Packed).unwrap(), ids());
+    }
+
+    #[test]
+    fn masks_compare_by_the_rows_they_select() {
+        let values: Vec<f64> = (0 .. 300).map(|i| (i % 50 == 0) as u8 as f64).collect();
+        let sparse = Column::from(values).filter(Scalar::Num(1.0)).unwrap();
+        assert!(matches!(sparse.selection(), crate::selection::Selection::Indices { .. }));
+        let bitmap = BoolColumn::from_selection(crate::selection::Selection::Bitmap(sparse.selection().to_bitmap()));
+        assert_columns_eq!(Column::Bool(sparse.clone()), Column::Bool(bitmap));
+        assert_ne!(Column::Bool(sparse.clone()), Column::Bool(BoolColumn::from_selection(sparse.selection().inverted())));
+    }
+
+    #[test]
+    fn result_sets_compare_names_and_columns() {
+        let a = ResultSet::from(vec![("x", ids()), ("s", Column::from(vec!["a"; 200]))]);
+        let encoded = ResultSet::from(vec![("x", ids().auto_encode()), ("s", strs(&["a"; 200]).auto_encode())]);
+        assert_columns_eq!(a, encoded);
+        assert_ne!(a, ResultSet::from(vec![("y", ids()), ("s", Column::from(vec!["a"; 200]))]));
+        assert_ne!(a, ResultSet::from(vec![("x", ids())]));
+
+        assert_eq!((a.len(), a.rows(), a.is_empty()), (2, 200, false));
+        assert_eq!(a.column("s"), Some(&Column::from(vec!["a"; 200])));
+        assert_eq!(a.column("z"), None);
+        assert_eq!(a.iter().map(|(name, _)| name).collect::<Vec<_>>(), vec!["x", "s"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "left: Num(PrimitiveColumn { data: [1.0, 2.0] })")]
+    fn assert_columns_eq_shows_both_sides() {
+        assert_columns_eq!(Column::from(vec![1.0, 2.0]), Column::from(vec![1.0, 3.0]));
+    }
+
+    #[test]
+    fn renders_aligned_tables() {
+        let res = ResultSet::from(vec![
+            ("id", Column::from(vec![7u64, 12])),
+            ("name", Column::from(vec!["ann", "bartholomew"])),
+            ("score", Column::from(vec![1.5, 10.0])),
+        ]);
+        assert_eq!(res.to_string(), "\
++-----+-------------+-------+
+| id  | name        | score |
++-----+-------------+-------+
+| #7  | ann         |   1.5 |
+| #12 | bartholomew |    10 |
++-----+-------------+-------+
+2 rows");
+        assert_eq!(ResultSet::from(vec![("x", Column::from(vec![1.0]))]).to_string().lines().last(), Some("1 row"));
+        assert_eq!(ResultSet::new().to_string(), "(no columns)");
+    }
+
+    #[test]
+    fn truncates_long_tables_and_cells() {
+        let res = ResultSet::from(vec![
+            ("n", Column::from((0 .. 100).map(f64::from).collect::<Vec<_>>()).auto_encode()),
+            ("a_long_column_name", Column::from(vec!["abcdefghij"; 100])),
+        ]);
+        let text = res.fmt_table(&TableFormat { max_rows: 3, max_width: 5 });
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "| n | a_lo… |");
+        assert_eq!(lines[3], "| 0 | abcd… |");
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[7], "100 rows (3 shown)");
+    }
+
+    #[test]
+    fn renders_masks_and_encoded_columns_as_values() {
+        let mask = Column::from(vec![1.0, 0.0]).filter(Scalar::Num(1.0)).unwrap();
+        let dict = encoding::encode(Column::from(vec!["x"; 100]), Encoding::Dict).unwrap();
+        let res = ResultSet::from(vec![("m", Column::Bool(mask)), ("d", dict)]);
+        let lines: Vec<String> = res.to_string().lines().map(|l| l.to_string()).collect();
+        assert_eq!(&lines[3 .. 5], &["| true  | x |", "| false | x |"]);
+        // Debug is the table too, on a line of its own
+        assert!(format!("{:?}", res).starts_with("\n+-------+---+"));
+    }
+
+    #[test]
+    fn rows_read_across_columns() {
+        let res = ResultSet::from(vec![
+            ("id", Column::from(vec![7u64, 12, 13])),
+            ("name", encoding::encode(Column::from(vec!["ann", "bo"]), Encoding::Dict).unwrap()),
+        ]);
+        assert_eq!(res.row(1), Some(vec![Scalar::Entity(12), Scalar::Str("bo".to_string())]));
+        // the shorter column runs out first
+        assert_eq!(res.row(2), None);
+        assert_eq!(res.iter_rows().count(), 2);
+        assert_eq!(ResultSet::new().iter_rows().count(), 0);
+    }
+
+    #[test]
+    fn macros_type_columns_by_their_values() {
+        assert_eq!(crate::col![1.0, 2.0], Column::from(vec![1.0, 2.0]));
+        assert_eq!(crate::col!["a", "b",], Column::from(vec!["a", "b"]));
+        assert_eq!(crate::col![3u64, 4].kind(), "Entity");
+        assert_eq!(Vec::<bool>::try_from(&crate::col![true, false]).unwrap(), vec![true, false]);
+        assert_eq!(crate::col![false; 3].len(), 3);
+
+        let res = crate::table!{"name" => ["ann", "bo"], "age" => [18.0, 42.0], "ok" => [true; 2]};
+        assert_eq!(res, ResultSet::from(vec![
+            ("name", Column::from(vec!["ann", "bo"])),
+            ("age", Column::from(vec![18.0, 42.0])),
+            ("ok", Column::from(vec![true, true])),
+        ]));
+        assert_eq!(crate::table!{}, ResultSet::new());
+    }
+}
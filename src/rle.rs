@@ -0,0 +1,428 @@
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Column, ColumnT, EntityColumn, NumColumn, Scalar};
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::schema::Datatype;
+use crate::selection::Selection;
+
+// One value per run
+#[derive(Debug, Clone)]
+pub enum Runs {
+    Bool(Vec<bool>),
+    Num(Buffer<f64>),
+    Entity(Buffer<u64>)
+}
+
+// Run-length encoded column: run i holds values[i] for rows ends[i-1] .. ends[i].
+// Storing cumulative ends rather than run lengths makes finding a row's run a binary search.
+#[derive(Debug, Clone)]
+pub struct RleColumn {
+    pub(crate) values: Runs,
+    pub(crate) ends: Buffer<usize>
+}
+
+fn encode_runs<T: Copy, I, F>(data: I, same: F) -> (Vec<T>, Buffer<usize>)
+    where I: IntoIterator<Item=T>, F: Fn(T, T) -> bool {
+
+    let mut values: Vec<T> = Vec::new();
+    let mut ends = Buffer::new();
+    for (i, x) in data.into_iter().enumerate() {
+        match values.last() {
+            Some(prev) if same(*prev, x) => *ends.last_mut().unwrap() = i + 1,
+            _ => {
+                values.push(x);
+                ends.push(i + 1);
+            }
+        }
+    }
+    (values, ends)
+}
+
+fn count_runs<T: Copy, F: Fn(T, T) -> bool>(data: &[T], same: F) -> usize {
+    if data.is_empty() {
+        return 0;
+    }
+    1 + data.windows(2).filter(|w| !same(w[0], w[1])).count()
+}
+
+// floats are compared bitwise, so -0.0 and 0.0 stay distinct and NaNs still form runs
+fn same_f64(a: f64, b: f64) -> bool {
+    a.to_bits() == b.to_bits()
+}
+
+fn bool_values(sel: &Selection) -> impl Iterator<Item=bool> + '_ {
+    (0 .. sel.len()).map(move |i| sel.contains(i))
+}
+
+// Number of runs in a plain Num, Entity or Bool column; None for other columns
+pub fn run_count(col: &Column) -> Option<usize> {
+    match col {
+        Column::Num(c) => Some(count_runs(&c.data, same_f64)),
+        Column::Entity(c) => Some(count_runs(&c.data, |a, b| a == b)),
+        Column::Bool(c) => {
+            let values: Vec<bool> = bool_values(&c.data).collect();
+            Some(count_runs(&values, |a, b| a == b))
+        },
+        _ => None
+    }
+}
+
+impl RleColumn {
+    pub fn encode(col: &Column) -> Option<RleColumn> {
+        let (values, ends) = match col {
+            Column::Num(c) => {
+                let (values, ends) = encode_runs(c.data.iter().copied(), same_f64);
+                (Runs::Num(Buffer::from(values)), ends)
+            },
+            Column::Entity(c) => {
+                let (values, ends) = encode_runs(c.data.iter().copied(), |a, b| a == b);
+                (Runs::Entity(Buffer::from(values)), ends)
+            },
+            Column::Bool(c) => {
+                let (values, ends) = encode_runs(bool_values(&c.data), |a, b| a == b);
+                (Runs::Bool(values), ends)
+            },
+            _ => return None
+        };
+        Some(RleColumn { values, ends })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn run_count(&self) -> usize {
+        self.ends.len()
+    }
+
+    fn run_start(&self, run: usize) -> usize {
+        if run == 0 { 0 } else { self.ends[run - 1] }
+    }
+
+    // The run holding `row`
+    fn run_at(&self, row: usize) -> usize {
+        self.ends.partition_point(|end| *end <= row)
+    }
+
+    // The value at `row`, found by binary search over the run ends
+    pub fn get(&self, row: usize) -> Option<Scalar> {
+        if row >= self.len() {
+            return None;
+        }
+        let run = self.run_at(row);
+        Some(match &self.values {
+            Runs::Bool(v) => Scalar::Bool(v[run]),
+            Runs::Num(v) => Scalar::Num(v[run]),
+            Runs::Entity(v) => Scalar::Entity(v[run])
+        })
+    }
+
+    pub fn datatype(&self) -> Datatype {
+        match self.values {
+            Runs::Bool(_) => Datatype::Bool,
+            Runs::Num(_) => Datatype::Num,
+            Runs::Entity(_) => Datatype::Entity
+        }
+    }
+
+    // Heap bytes for the run values and ends
+    pub fn memory_usage(&self) -> usize {
+        let values = match &self.values {
+            Runs::Bool(v) => v.capacity(),
+            Runs::Num(v) => v.memory_usage(),
+            Runs::Entity(v) => v.memory_usage()
+        };
+        values + self.ends.memory_usage()
+    }
+
+    fn expand<T: Copy>(&self, values: &[T]) -> Buffer<T> {
+        let mut res = Buffer::with_capacity(self.len());
+        for (run, x) in values.iter().enumerate() {
+            for _ in self.run_start(run) .. self.ends[run] {
+                res.push(*x);
+            }
+        }
+        res
+    }
+
+    // The rows set in a Bool column, None for other types
+    pub fn to_selection(&self) -> Option<Selection> {
+        if let Runs::Bool(values) = &self.values {
+            let mut bits = BitIndex::for_col_len(self.len());
+            values.iter().enumerate()
+                .filter(|(_, x)| **x)
+                .for_each(|(run, _)| bits.set_range(self.run_start(run), self.ends[run]));
+            Some(Selection::adaptive(bits))
+        } else {
+            None
+        }
+    }
+
+    // Back to the plain layout
+    pub fn decode(&self) -> Column {
+        match &self.values {
+            Runs::Bool(_) => Column::Bool(BoolColumn::from_selection(self.to_selection().unwrap())),
+            Runs::Num(v) => Column::Num(NumColumn { data: self.expand(v) }),
+            Runs::Entity(v) => Column::Entity(EntityColumn { data: self.expand(v) })
+        }
+    }
+
+    // Rows offset .. offset + len, which must be in bounds. Shares the run buffers.
+    pub fn slice(&self, offset: usize, len: usize) -> RleColumn {
+        let (first, last) = if len == 0 {
+            (0, 0)
+        } else {
+            (self.run_at(offset), self.run_at(offset + len - 1) + 1)
+        };
+        let values = match &self.values {
+            Runs::Bool(v) => Runs::Bool(v[first .. last].to_vec()),
+            Runs::Num(v) => Runs::Num(v.slice(first, last - first)),
+            Runs::Entity(v) => Runs::Entity(v.slice(first, last - first))
+        };
+        let ends = self.ends[first .. last].iter().map(|end| end.min(&(offset + len)) - offset).collect();
+        RleColumn { values, ends }
+    }
+
+    // lo <= x < hi
+    pub fn filter_range(&self, lo: Scalar, hi: Scalar) -> Result<BoolColumn, VMError> {
+        match (&self.values, &lo, &hi) {
+            (Runs::Num(v), Scalar::Num(lo), Scalar::Num(hi)) => Ok(self.mask_runs(|run| *lo <= v[run] && v[run] < *hi)),
+            (Runs::Entity(v), Scalar::Entity(lo), Scalar::Entity(hi)) => Ok(self.mask_runs(|run| *lo <= v[run] && v[run] < *hi)),
+            _ => Err(VMError::TypeError(format!("Expected {} bounds, got: {:?} and {:?}", self.datatype(), lo, hi)))
+        }
+    }
+
+    // filter, over just the rows in `sel` (see Column::filter_at). Each run touched by the
+    // selection is compared once, however many of its rows are selected.
+    pub fn filter_at(&self, val: Scalar, sel: &Selection) -> Result<BoolColumn, VMError> {
+        let matches: Box<dyn Fn(usize) -> bool> = match (&self.values, val) {
+            (Runs::Bool(v), Scalar::Bool(x)) => Box::new(move |run| v[run] == x),
+            (Runs::Num(v), Scalar::Num(x)) => Box::new(move |run| v[run] == x),
+            (Runs::Entity(v), Scalar::Entity(x)) => Box::new(move |run| v[run] == x),
+            (_, val) => return self.filter(val)
+        };
+        let mut positions = BitIndex::for_col_len(sel.count_ones());
+        let (mut run, mut i) = (0, 0);
+        let mut hit = self.run_count() > 0 && matches(0);
+        sel.for_each(|idx| {
+            if self.ends[run] <= idx {
+                while self.ends[run] <= idx {
+                    run += 1;
+                }
+                hit = matches(run);
+            }
+            if hit { positions.set(i); }
+            i += 1;
+        });
+        Ok(BoolColumn::from_mask(positions))
+    }
+
+    fn mask_runs<F: Fn(usize) -> bool>(&self, matches: F) -> BoolColumn {
+        let mut bits = BitIndex::for_col_len(self.len());
+        (0 .. self.run_count())
+            .filter(|run| matches(*run))
+            .for_each(|run| bits.set_range(self.run_start(run), self.ends[run]));
+        BoolColumn::from_mask(bits)
+    }
+}
+
+impl ColumnT for RleColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    // One comparison per run, then whole runs are set in the mask at once
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        match (&self.values, &val) {
+            (Runs::Bool(v), Scalar::Bool(x)) => Ok(self.mask_runs(|run| v[run] == *x)),
+            (Runs::Num(v), Scalar::Num(x)) => Ok(self.mask_runs(|run| v[run] == *x)),
+            (Runs::Entity(v), Scalar::Entity(x)) => Ok(self.mask_runs(|run| v[run] == *x)),
+            (Runs::Bool(_), _) => Err(VMError::TypeError(format!("Expected a boolean value, got: {:?}", val))),
+            (Runs::Num(_), _) => Err(VMError::TypeError(format!("Expected a numeric value, got: {:?}", val))),
+            (Runs::Entity(_), _) => Err(VMError::TypeError(format!("Expected an entity-id value, got: {:?}", val)))
+        }
+    }
+
+    // Selected rows that came from the same run stay in one run, so the result is still encoded
+    fn select(&self, mask: &BoolColumn) -> Self {
+        let mut picked: Vec<usize> = Vec::new();
+        let mut ends = Buffer::new();
+        let (mut run, mut n) = (0, 0);
+        mask.selection().for_each(|idx| {
+            while self.ends[run] <= idx {
+                run += 1;
+            }
+            n += 1;
+            if picked.last() == Some(&run) {
+                *ends.last_mut().unwrap() = n;
+            } else {
+                picked.push(run);
+                ends.push(n);
+            }
+        });
+        let values = match &self.values {
+            Runs::Bool(v) => Runs::Bool(picked.iter().map(|run| v[*run]).collect()),
+            Runs::Num(v) => Runs::Num(picked.iter().map(|run| v[*run]).collect()),
+            Runs::Entity(v) => Runs::Entity(picked.iter().map(|run| v[*run]).collect())
+        };
+        RleColumn { values, ends }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::Table;
+    use crate::datagen::Rng;
+
+    fn mask(col: &Column, val: Scalar) -> BoolColumn {
+        col.filter(val).unwrap()
+    }
+
+    // runs of random lengths 1..8 over a handful of values
+    fn runs_of(n: usize, seed: u64) -> Vec<u64> {
+        let mut rng = Rng::new(seed);
+        let mut out = Vec::new();
+        while out.len() < n {
+            let (x, len) = (rng.below(4), 1 + rng.below(8) as usize);
+            out.extend(core::iter::repeat_n(x, len.min(n - out.len())));
+        }
+        out
+    }
+
+    fn plain_columns() -> Vec<Column> {
+        let ids = runs_of(300, 1);
+        let nums: Vec<f64> = runs_of(300, 2).iter().map(|x| match x { 0 => -0.0, 1 => 0.0, 2 => f64::NAN, _ => 1.5 }).collect();
+        let bools = Column::from(runs_of(300, 3).iter().map(|x| (x % 2) as f64).collect::<Vec<_>>()).filter(Scalar::Num(1.0)).unwrap();
+        vec![Column::from(ids), Column::from(nums), Column::Bool(bools)]
+    }
+
+    fn values(col: &Column) -> Vec<Scalar> {
+        match col.datatype() {
+            Datatype::Bool => vec![Scalar::Bool(true), Scalar::Bool(false)],
+            Datatype::Num => vec![Scalar::Num(0.0), Scalar::Num(1.5), Scalar::Num(7.0)],
+            _ => (0 .. 5).map(Scalar::Entity).collect()
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for plain in plain_columns() {
+            let rle = RleColumn::encode(&plain).unwrap();
+            assert_eq!(rle.len(), plain.len());
+            assert!(rle.run_count() < plain.len());
+            assert_eq!(rle.datatype(), plain.datatype());
+            assert_eq!(rle.decode().fingerprint(), plain.fingerprint());
+            assert_eq!(Column::Rle(rle).fingerprint(), plain.fingerprint());
+        }
+        let empty = RleColumn::encode(&Column::from(Vec::<f64>::new())).unwrap();
+        assert!(empty.is_empty() && empty.decode().is_empty());
+        assert!(RleColumn::encode(&Column::from(vec!["a", "a"])).is_none());
+    }
+
+    #[test]
+    fn filters_like_the_plain_column() {
+        for plain in plain_columns() {
+            let rle = Column::Rle(RleColumn::encode(&plain).unwrap());
+            for val in values(&plain) {
+                assert_eq!(mask(&rle, val.clone()).selection().positions(), mask(&plain, val.clone()).selection().positions(), "{:?}", val);
+            }
+            assert!(rle.filter(Scalar::Str("x".to_string())).is_err());
+        }
+    }
+
+    #[test]
+    fn selects_like_the_plain_column() {
+        let selector = Column::from(runs_of(300, 4).iter().map(|x| (*x > 0) as u8 as f64).collect::<Vec<_>>());
+        let selector = mask(&selector, Scalar::Num(1.0));
+        for plain in plain_columns() {
+            let rle = Column::Rle(RleColumn::encode(&plain).unwrap());
+            let picked = rle.select(&selector);
+            assert!(matches!(picked, Column::Rle(_)));
+            if let Column::Bool(b) = &plain {
+                let mut expected = Vec::new();
+                let mut i = 0;
+                selector.selection().for_each(|idx| { if b.selection().contains(idx) { expected.push(i) } i += 1; });
+                assert_eq!(mask(&picked, Scalar::Bool(true)).selection().positions(), expected);
+            } else {
+                assert_eq!(picked.fingerprint(), plain.select(&selector).fingerprint());
+            }
+        }
+    }
+
+    #[test]
+    fn slices_like_the_plain_column() {
+        for plain in plain_columns() {
+            let rle = Column::Rle(RleColumn::encode(&plain).unwrap());
+            for (offset, len) in [(0, 300), (0, 1), (3, 0), (17, 50), (299, 1), (100, 200)] {
+                let slice = rle.slice(offset, len);
+                assert_eq!(slice.len(), len);
+                assert_eq!(slice.fingerprint(), plain.slice(offset, len).fingerprint(), "{}..+{}", offset, len);
+            }
+        }
+    }
+
+    #[test]
+    fn counts_runs() {
+        assert_eq!(run_count(&Column::from(vec![3.0; 100])), Some(1));
+        assert_eq!(run_count(&Column::from(vec![0.0, -0.0, 0.0, f64::NAN, f64::NAN])), Some(4));
+        assert_eq!(run_count(&Column::from(vec![1u64, 1, 2, 1])), Some(3));
+        assert_eq!(run_count(&Column::from(Vec::<u64>::new())), Some(0));
+        assert_eq!(run_count(&plain_columns()[2]), Some(RleColumn::encode(&plain_columns()[2]).unwrap().run_count()));
+        assert_eq!(run_count(&Column::from(vec!["a"; 100])), None);
+    }
+
+    #[test]
+    fn vms_run_over_encoded_columns() {
+        use crate::opcode::Op;
+        use crate::vm::VM;
+        let sorted: Vec<u64> = (0 .. 100).map(|i| i / 10).collect();
+        let values: Vec<f64> = (0 .. 100).map(|i| i as f64).collect();
+        let mut vm = VM::new(Table::from_columns(vec![Column::from(sorted), Column::from(values)]).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Entity(4)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]).unwrap();
+        let res = vm.column_of(&vm.stack()[0]).unwrap();
+        assert_eq!(res.fingerprint(), Column::from((40 .. 50).map(|i| i as f64).collect::<Vec<_>>()).fingerprint());
+    }
+
+    #[test]
+    fn range_filters_like_the_plain_column() {
+        for plain in plain_columns().into_iter().take(2) {
+            let rle = Column::Rle(RleColumn::encode(&plain).unwrap());
+            let bounds = match plain.datatype() {
+                Datatype::Num => vec![(Scalar::Num(-1.0), Scalar::Num(1.0)), (Scalar::Num(0.0), Scalar::Num(1.5)), (Scalar::Num(1.5), Scalar::Num(9.0))],
+                _ => vec![(Scalar::Entity(0), Scalar::Entity(2)), (Scalar::Entity(1), Scalar::Entity(1)), (Scalar::Entity(3), Scalar::Entity(9))]
+            };
+            for (lo, hi) in bounds {
+                let expected = plain.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions();
+                assert_eq!(rle.filter_range(lo.clone(), hi.clone()).unwrap().selection().positions(), expected, "{:?}..{:?}", lo, hi);
+            }
+        }
+        let bools = Column::Rle(RleColumn::encode(&plain_columns()[2]).unwrap());
+        assert!(bools.filter_range(Scalar::Bool(false), Scalar::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn filters_at_a_selection_like_the_gathered_rows() {
+        let sels = [
+            Selection::from_positions(vec![0, 1, 2, 50, 51, 200, 299], 300),
+            Selection::Bitmap(mask(&Column::from((0 .. 300).map(|i| (i % 3 == 0) as u8 as f64).collect::<Vec<_>>()), Scalar::Num(1.0)).selection().to_bitmap()),
+            Selection::from_positions(vec![], 300),
+        ];
+        for plain in plain_columns() {
+            let rle = Column::Rle(RleColumn::encode(&plain).unwrap());
+            for sel in &sels {
+                for val in values(&plain) {
+                    let at = rle.filter_at(val.clone(), sel).unwrap();
+                    let gathered = Column::Rle(RleColumn::encode(&plain).unwrap()).select(&BoolColumn::from_selection(sel.clone()));
+                    assert_eq!(at.selection().positions(), gathered.filter(val.clone()).unwrap().selection().positions(), "{:?}", val);
+                }
+            }
+            assert!(rle.filter_at(Scalar::Str("x".to_string()), &sels[0]).is_err());
+        }
+    }
+}
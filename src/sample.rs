@@ -0,0 +1,168 @@
+// Row sampling, e.g. for building training sets from a table. Each sampler returns the row
+// numbers it picked as an Entity column, in ascending order, ready to gather the rows of
+// every column of the table with. Sampling is without replacement, and driven by an explicit
+// seed like datagen, so the same call always picks the same rows.
+
+use crate::column::Column;
+use crate::compare::{self, Comparator};
+use crate::datagen::Rng;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::nulls::{self, NullSemantics};
+
+use std::cmp::Ordering;
+
+fn row_ids(mut rows: Vec<usize>) -> Column {
+    rows.sort_unstable();
+    Column::from(rows.into_iter().map(|r| r as u64).collect::<Vec<u64>>())
+}
+
+// k of `rows` (all of them if there are no more than k), each equally likely; a partial
+// Fisher-Yates shuffle
+fn pick(mut rows: Vec<usize>, k: usize, rng: &mut Rng) -> Vec<usize> {
+    let k = k.min(rows.len());
+    for i in 0 .. k {
+        let j = i + rng.below((rows.len() - i) as u64) as usize;
+        rows.swap(i, j);
+    }
+    rows.truncate(k);
+    rows
+}
+
+// k rows out of `rows`, uniformly
+pub fn uniform(rows: usize, k: usize, seed: u64) -> Column {
+    row_ids(pick((0 .. rows).collect(), k, &mut Rng::new(seed)))
+}
+
+// k rows from each group of rows with equal values in `key` (every row of smaller groups).
+// Groups are as for sorting, so NaN (null) keys form one group - unless `nulls` makes nulls
+// distinct, when each is a group of its own.
+pub fn stratified(key: &Column, k: usize, seed: u64, nulls: NullSemantics) -> Column {
+    let mut rng = Rng::new(seed);
+    let cmp = Comparator::new(key);
+    let order = compare::argsort(key);
+    let mut picked = Vec::new();
+    let mut start = 0;
+    while start < order.len() {
+        let end = start + order[start ..].iter().take_while(|r| cmp.cmp(**r, order[start]) == Ordering::Equal).count();
+        if !nulls.nulls_group() && key.get(order[start]).is_some_and(|x| nulls::is_null(&x)) {
+            order[start .. end].iter().for_each(|r| picked.extend(pick(vec![*r], k, &mut rng)));
+        } else {
+            picked.extend(pick(order[start .. end].to_vec(), k, &mut rng));
+        }
+        start = end;
+    }
+    row_ids(picked)
+}
+
+// k rows, each row's chance of being picked proportional to its value in the Num column
+// `weights` (Efraimidis-Spirakis: every row draws u^(1/w) for a uniform u, and the k highest
+// draws win). Rows with zero weight are never picked, so fewer than k rows come back if
+// fewer than k have positive weight. Weights can't be negative or NaN.
+pub fn weighted(weights: &Column, k: usize, seed: u64) -> Result<Column, VMError> {
+    let weights = encoding::plain(weights);
+    let weights = match weights.as_ref() {
+        Column::Num(c) => c.values(),
+        other => return Err(VMError::TypeError(format!("Sample weights must be numbers, got a {} column", other.datatype())))
+    };
+    if let Some(w) = weights.iter().find(|w| w.is_nan() || **w < 0.0) {
+        return Err(VMError::TypeError(format!("Sample weights can't be negative or NaN, got: {}", w)));
+    }
+    let mut rng = Rng::new(seed);
+    // ln(u) / w ranks rows the same as u^(1/w), without underflowing for small weights
+    let mut draws: Vec<(f64, usize)> = weights.iter().enumerate()
+        .map(|(i, w)| (rng.next_f64().ln() / w, i))
+        .filter(|(_, i)| weights[*i] > 0.0)
+        .collect();
+    draws.sort_unstable_by(|a, b| compare::cmp_f64(b.0, a.0));
+    draws.truncate(k);
+    Ok(row_ids(draws.into_iter().map(|(_, i)| i).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scalar;
+
+    const SEEDS: u64 = 10_000;
+
+    fn rows(picked: &Column) -> Vec<usize> {
+        (0 .. picked.len()).map(|i| match picked.get(i) {
+            Some(Scalar::Entity(r)) => r as usize,
+            other => panic!("row {} of the sample is {:?}", i, other)
+        }).collect()
+    }
+
+    // how often each of `n` rows is picked, over SEEDS seeds
+    fn rates(n: usize, sample: impl Fn(u64) -> Column) -> Vec<f64> {
+        let mut counts = vec![0; n];
+        for seed in 0 .. SEEDS {
+            let picked = rows(&sample(seed));
+            assert!(picked.windows(2).all(|w| w[0] < w[1]), "seed {} picked {:?}", seed, picked);
+            picked.into_iter().for_each(|r| counts[r] += 1);
+        }
+        counts.into_iter().map(|c| c as f64 / SEEDS as f64).collect()
+    }
+
+    fn assert_rates(rates: &[f64], expected: &[f64]) {
+        for (row, (rate, expected)) in rates.iter().zip(expected).enumerate() {
+            assert!((rate - expected).abs() < 0.02, "row {} picked at {}, expected {}", row, rate, expected);
+        }
+    }
+
+    #[test]
+    fn uniform_picks_rows_evenly() {
+        assert_rates(&rates(10, |seed| uniform(10, 3, seed)), &[0.3; 10]);
+        assert_eq!(rows(&uniform(10, 3, 5)), rows(&uniform(10, 3, 5)));
+        assert_eq!(rows(&uniform(4, 10, 5)), vec![0, 1, 2, 3]);
+        assert_eq!(uniform(0, 3, 5).len(), 0);
+    }
+
+    #[test]
+    fn stratified_picks_from_every_group() {
+        let key = Column::from(vec!["b", "a", "c", "a", "b", "a"]);
+        for seed in 0 .. 100 {
+            let picked = rows(&stratified(&key, 2, seed, NullSemantics::default()));
+            let in_group = |g: &str| picked.iter().filter(|r| key.get(**r) == Some(Scalar::Str(g.to_string()))).count();
+            assert_eq!((in_group("a"), in_group("b"), in_group("c")), (2, 2, 1), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn stratified_groups_nans_together_unless_nulls_are_distinct() {
+        // NaN equals NaN in the compare.rs order, so the NaNs are one group
+        let key = Column::from(vec![1.0, f64::NAN, f64::NAN, 1.0, f64::NAN]);
+        assert_eq!(stratified(&key, 1, 3, NullSemantics::default()).len(), 2);
+        assert_eq!(stratified(&key, 5, 3, NullSemantics::default()).len(), 5);
+        assert_eq!(stratified(&key, 1, 3, NullSemantics::NullEqualsNull).len(), 2);
+        // each NaN row is a group of its own, and so always picked
+        let picked = rows(&stratified(&key, 1, 3, NullSemantics::NullsDistinct));
+        assert_eq!(picked.len(), 4);
+        assert!([1, 2, 4].iter().all(|r| picked.contains(r)), "{:?}", picked);
+    }
+
+    #[test]
+    fn weighted_picks_rows_by_their_share_of_the_weight() {
+        let weights = [1.0, 2.0, 3.0, 4.0, 0.0, 10.0];
+        let col = Column::from(weights.to_vec());
+        let expected: Vec<f64> = weights.iter().map(|w| w / 20.0).collect();
+        assert_rates(&rates(6, |seed| weighted(&col, 1, seed).unwrap()), &expected);
+    }
+
+    #[test]
+    fn weighted_never_picks_zero_weights() {
+        let col = Column::from(vec![0.0, 5.0, 0.0, 1.0]);
+        for seed in 0 .. 100 {
+            assert_eq!(rows(&weighted(&col, 3, seed).unwrap()), vec![1, 3]);
+        }
+    }
+
+    #[test]
+    fn weighted_rejects_bad_weights() {
+        for bad in &[-1.0, f64::NAN] {
+            let col = Column::from(vec![1.0, *bad]);
+            assert!(matches!(weighted(&col, 1, 1), Err(VMError::TypeError(_))), "weight {}", bad);
+        }
+        assert!(matches!(weighted(&Column::from(vec!["a"]), 1, 1), Err(VMError::TypeError(_))));
+    }
+}
@@ -0,0 +1,152 @@
+use crate::categorical::Categories;
+use crate::core::prelude::*;
+use crate::encoding::Encoding;
+use crate::errors::VMError;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt;
+
+// The logical type of a column, independent of how it's laid out in memory
+// (e.g. both StrColumn and InlineStrColumn are `Str`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Datatype {
+    Bool,
+    Num,
+    Int,            // 64-bit signed integers, exact where Num's f64 would round large ids and counts
+    Str,
+    Entity,
+    Duration,
+    Timestamp,      // instants, as microseconds since the Unix epoch in UTC
+    Point,
+    Ipv4,
+    Ipv6,
+    Json,
+    Categorical,    // strings from a fixed, ordered list; see Field::categorical
+    Vector,         // fixed-size f32 vectors, e.g. embeddings; the size is the column's
+    List,           // lists of values of one type, e.g. the groups of a GroupBy; the type is the column's
+    Record          // records of typed fields, packed row by row; the fields are the column's
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub dtype: Datatype,
+    pub encoding: Encoding,    // how the column is stored when loaded; see encoding::apply
+    pub categories: Option<Arc<Categories>>,    // for a Categorical field
+    pub dim: Option<usize>,     // values per row, for a Vector field
+    pub nfc: bool,              // strings are normalized to NFC as they're loaded; see normalize.rs
+    pub metadata: Metadata
+}
+
+// Key/value tags on a column or table - units, source, PII flags - that collie keeps with it
+// but doesn't interpret
+pub type Metadata = BTreeMap<String, String>;
+
+impl Field {
+    pub fn new(name: &str, dtype: Datatype) -> Self {
+        Field { name: name.to_string(), dtype, encoding: Encoding::Auto, categories: None, dim: None, nfc: false, metadata: Metadata::new() }
+    }
+
+    // A Categorical field whose values are `categories`, ordered as they're listed
+    pub fn categorical(name: &str, categories: Categories) -> Self {
+        Field { categories: Some(Arc::new(categories)), ..Field::new(name, Datatype::Categorical) }
+    }
+
+    // A Vector field of `dim` values per row
+    pub fn vector(name: &str, dim: usize) -> Self {
+        Field { dim: Some(dim), ..Field::new(name, Datatype::Vector) }
+    }
+
+    // Store this column in a fixed encoding, instead of letting the loader pick one
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_nfc(mut self, nfc: bool) -> Self {
+        self.nfc = nfc;
+        self
+    }
+
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(|v| v.as_str())
+    }
+}
+
+// Names and types of the columns a program runs against,
+// in the same order that Op::Col addresses them.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Schema {
+    pub fields: Vec<Field>
+}
+
+impl Schema {
+    pub fn new(fields: Vec<Field>) -> Self {
+        Schema { fields }
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn field(&self, idx: usize) -> Option<&Field> {
+        self.fields.get(idx)
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.name == name)
+    }
+
+    // The fields named by `names`, in that order, metadata and all
+    pub fn project(&self, names: &[&str]) -> Result<Schema, VMError> {
+        let fields = names.iter()
+            .map(|name| self.fields.iter().find(|f| f.name == *name).cloned()
+                .ok_or_else(|| VMError::TypeError(format!("No column named '{}'", name))))
+            .collect::<Result<_, _>>()?;
+        Ok(Schema { fields })
+    }
+
+    // The fields tagged `key` = `value`
+    pub fn tagged<'a>(&'a self, key: &'a str, value: &'a str) -> impl Iterator<Item=&'a Field> + 'a {
+        self.fields.iter().filter(move |f| f.metadata(key) == Some(value))
+    }
+}
+
+impl From<Vec<(&str, Datatype)>> for Schema {
+    fn from(v: Vec<(&str, Datatype)>) -> Self {
+        Schema { fields: v.into_iter().map(|(name, dtype)| Field::new(name, dtype)).collect() }
+    }
+}
+
+impl fmt::Display for Datatype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Datatype::Bool => write!(f, "Bool"),
+            Datatype::Num => write!(f, "Num"),
+            Datatype::Int => write!(f, "Int"),
+            Datatype::Str => write!(f, "Str"),
+            Datatype::Entity => write!(f, "Entity"),
+            Datatype::Duration => write!(f, "Duration"),
+            Datatype::Timestamp => write!(f, "Timestamp"),
+            Datatype::Point => write!(f, "Point"),
+            Datatype::Ipv4 => write!(f, "Ipv4"),
+            Datatype::Ipv6 => write!(f, "Ipv6"),
+            Datatype::Json => write!(f, "Json"),
+            Datatype::Categorical => write!(f, "Categorical"),
+            Datatype::Vector => write!(f, "Vector"),
+            Datatype::List => write!(f, "List"),
+            Datatype::Record => write!(f, "Record")
+        }
+    }
+}
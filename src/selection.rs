@@ -0,0 +1,374 @@
+use core::fmt;
+use core::hash::{Hash, Hasher};
+
+use crate::bitindex::BitIndex;
+use crate::core::prelude::*;
+
+// A set of selected row positions out of `len` rows.
+// Dense selections are cheapest as a bitmap (len / 8 bytes); very selective ones
+// as a sorted list of positions (4 bytes each). `Selection::adaptive` picks whichever
+// is smaller, and every consumer works with both.
+#[derive(Debug, Clone)]
+pub enum Selection {
+    Bitmap(BitIndex),
+    // Sorted, no duplicates. u32 positions cap a column at 4B rows, which is fine for now.
+    Indices { positions: Vec<u32>, len: usize }
+}
+
+impl Selection {
+    pub fn adaptive(bitmap: BitIndex) -> Self {
+        // 32 bits per index vs 1 bit per row
+        if bitmap.count_ones() * 32 < bitmap.len() {
+            Selection::from_bitmap_sparse(&bitmap)
+        } else {
+            Selection::Bitmap(bitmap)
+        }
+    }
+
+    fn from_bitmap_sparse(bitmap: &BitIndex) -> Self {
+        let mut positions = Vec::with_capacity(bitmap.count_ones());
+        bitmap.for_each(|idx| positions.push(idx as u32));
+        Selection::Indices { positions, len: bitmap.len() }
+    }
+
+    pub fn from_positions(mut positions: Vec<u32>, len: usize) -> Self {
+        positions.sort_unstable();
+        positions.dedup();
+        Selection::Indices { positions, len }
+    }
+
+    // Apply `inner`, a selection over the rows this one selects, giving a selection over
+    // the same rows as `self`. I.e. select(select(col, self), inner) == select(col, compose).
+    pub fn compose(&self, inner: &Selection) -> Selection {
+        let mut outer = Vec::with_capacity(self.count_ones());
+        self.for_each(|idx| outer.push(idx as u32));
+        let mut positions = Vec::with_capacity(inner.count_ones());
+        inner.for_each(|idx| positions.push(outer[idx]));
+
+        // still sorted, since both selections are
+        let len = self.len();
+        if positions.len() * 32 < len {
+            Selection::Indices { positions, len }
+        } else {
+            let mut b = BitIndex::for_col_len(len);
+            positions.iter().for_each(|p| b.set(*p as usize));
+            Selection::Bitmap(b)
+        }
+    }
+
+    // Number of rows selected from
+    pub fn len(&self) -> usize {
+        match self {
+            Selection::Bitmap(b) => b.len(),
+            Selection::Indices { len, .. } => *len
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Number of rows selected
+    pub fn count_ones(&self) -> usize {
+        match self {
+            Selection::Bitmap(b) => b.count_ones(),
+            Selection::Indices { positions, .. } => positions.len()
+        }
+    }
+
+    pub fn contains(&self, idx: usize) -> bool {
+        match self {
+            Selection::Bitmap(b) => b.get(idx),
+            Selection::Indices { positions, .. } => positions.binary_search(&(idx as u32)).is_ok()
+        }
+    }
+
+    pub fn for_each<F>(&self, mut callback: F)
+        where F: FnMut(usize) {
+        match self {
+            Selection::Bitmap(b) => b.for_each(callback),
+            Selection::Indices { positions, .. } => positions.iter().for_each(|p| callback(*p as usize))
+        }
+    }
+
+    // The rows selected, in order
+    pub fn positions(&self) -> Vec<usize> {
+        let mut out = Vec::with_capacity(self.count_ones());
+        self.for_each(|i| out.push(i));
+        out
+    }
+
+    // Gather the selected elements of `col`, in order
+    pub fn select<T: Clone>(&self, col: &[T]) -> Vec<T> {
+        match self {
+            Selection::Bitmap(b) => b.select(col),
+            Selection::Indices { positions, .. } => positions.iter().map(|p| col[*p as usize].clone()).collect()
+        }
+    }
+
+    // The selection restricted to rows offset .. offset + len, renumbered from 0
+    pub fn slice(&self, offset: usize, len: usize) -> Selection {
+        match self {
+            Selection::Bitmap(b) => {
+                let mut out = BitIndex::for_col_len(len);
+                b.for_each(|idx| if idx >= offset && idx < offset + len { out.set(idx - offset) });
+                Selection::Bitmap(out)
+            },
+            Selection::Indices { positions, .. } => {
+                let (lo, hi) = (offset as u32, (offset + len) as u32);
+                let positions = positions.iter().filter(|p| **p >= lo && **p < hi).map(|p| p - lo).collect();
+                Selection::Indices { positions, len }
+            }
+        }
+    }
+
+    pub fn to_bitmap(&self) -> BitIndex {
+        match self {
+            Selection::Bitmap(b) => b.clone(),
+            Selection::Indices { positions, len } => {
+                let mut b = BitIndex::for_col_len(*len);
+                positions.iter().for_each(|p| b.set(*p as usize));
+                b
+            }
+        }
+    }
+
+    pub fn inverted(&self) -> Selection {
+        Selection::adaptive(self.to_bitmap().inverted())
+    }
+
+    // The rows both select. Position lists are intersected without building a bitmap, so
+    // a sparse selection stays sparse. Both must select from the same number of rows.
+    pub fn and(&self, other: &Selection) -> Selection {
+        assert_eq!(self.len(), other.len(), "selections cover different numbers of rows");
+        match (self, other) {
+            (Selection::Indices { positions, len }, other) | (other, Selection::Indices { positions, len }) => {
+                let positions = positions.iter().copied().filter(|p| other.contains(*p as usize)).collect();
+                Selection::Indices { positions, len: *len }
+            },
+            (Selection::Bitmap(a), Selection::Bitmap(b)) => Selection::adaptive(a.and(b))
+        }
+    }
+
+    // The rows either selects
+    pub fn or(&self, other: &Selection) -> Selection {
+        assert_eq!(self.len(), other.len(), "selections cover different numbers of rows");
+        match (self, other) {
+            (Selection::Indices { positions: a, len }, Selection::Indices { positions: b, .. }) => {
+                let mut positions = Vec::with_capacity(a.len() + b.len());
+                let (mut i, mut j) = (0, 0);
+                while i < a.len() || j < b.len() {
+                    let next = match (a.get(i), b.get(j)) {
+                        (Some(x), Some(y)) if x <= y => { i += 1; if x == y { j += 1; } *x },
+                        (Some(x), None) => { i += 1; *x },
+                        (_, Some(y)) => { j += 1; *y },
+                        (None, None) => unreachable!()
+                    };
+                    positions.push(next);
+                }
+                if positions.len() * 32 < *len {
+                    Selection::Indices { positions, len: *len }
+                } else {
+                    let mut b = BitIndex::for_col_len(*len);
+                    positions.iter().for_each(|p| b.set(*p as usize));
+                    Selection::Bitmap(b)
+                }
+            },
+            (a, b) => Selection::adaptive(a.to_bitmap().or(&b.to_bitmap()))
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            Selection::Bitmap(b) => b.memory_usage(),
+            Selection::Indices { positions, .. } => positions.capacity() * core::mem::size_of::<u32>()
+        }
+    }
+}
+
+// Hash the selected positions rather than the representation, so equal selections
+// hash equally whichever form they're in.
+impl Hash for Selection {
+    fn hash<H: Hasher>(&self, h: &mut H) {
+        self.len().hash(h);
+        self.for_each(|idx| idx.hash(h));
+    }
+}
+
+// Likewise, equal if they select the same rows out of the same number
+impl PartialEq for Selection {
+    fn eq(&self, other: &Self) -> bool {
+        if self.len() != other.len() || self.count_ones() != other.count_ones() {
+            return false;
+        }
+        let (mut a, mut b) = (Vec::with_capacity(self.count_ones()), Vec::with_capacity(other.count_ones()));
+        self.for_each(|idx| a.push(idx));
+        other.for_each(|idx| b.push(idx));
+        a == b
+    }
+}
+
+// How a filter went about finding its rows, for EXPLAIN ANALYZE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterPlan {
+    // Tested every row. The first `sampled` went into a bitmap, and the fraction of them that
+    // matched decided whether the rest were collected as positions instead.
+    Scan { sampled: usize, selectivity: f64, positions: bool },
+    // Found the matching rows without testing each one - a binary search of a sorted column
+    IndexLookup
+}
+
+impl FilterPlan {
+    // The plan of a filter that tested every row and left the result in `sel`
+    pub fn scanned(sel: &Selection) -> Self {
+        let selectivity = if sel.is_empty() { 0.0 } else { sel.count_ones() as f64 / sel.len() as f64 };
+        FilterPlan::Scan { sampled: sel.len(), selectivity, positions: matches!(sel, Selection::Indices { .. }) }
+    }
+}
+
+impl fmt::Display for FilterPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterPlan::Scan { sampled, selectivity, positions } => {
+                write!(f, "scan to {} ({:.1}% of {} sampled)", if *positions { "positions" } else { "bitmap" },
+                       selectivity * 100.0, sampled)
+            },
+            FilterPlan::IndexLookup => write!(f, "index lookup")
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selection::Bitmap(b) => write!(f, "{}", b),
+            Selection::Indices { positions, len } => write!(f, "Indices[{:?} of {}]", positions, len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::StableHasher;
+
+    fn bitmap(len: usize, set: &[usize]) -> BitIndex {
+        let mut b = BitIndex::for_col_len(len);
+        set.iter().for_each(|i| b.set(*i));
+        b
+    }
+
+    fn hash(s: &Selection) -> u64 {
+        let mut h = StableHasher::default();
+        s.hash(&mut h);
+        h.finish()
+    }
+
+    #[test]
+    fn and_or_agree_across_forms() {
+        let (a, b) = (&[1, 5, 64, 99][..], &[0, 5, 64, 70][..]);
+        let forms = |set: &[usize]| vec![Selection::Bitmap(bitmap(100, set)), Selection::from_positions(set.iter().map(|i| *i as u32).collect(), 100)];
+        for x in forms(a) {
+            for y in forms(b) {
+                assert_eq!(x.and(&y).positions(), vec![5, 64]);
+                assert_eq!(x.or(&y).positions(), vec![0, 1, 5, 64, 70, 99]);
+            }
+        }
+        // a sparse side keeps the result sparse
+        let sparse = Selection::from_positions(vec![3], 100);
+        assert!(matches!(Selection::Bitmap(bitmap(100, &(0 .. 100).collect::<Vec<_>>())).and(&sparse), Selection::Indices { .. }));
+    }
+
+    #[test]
+    fn picks_the_smaller_form() {
+        // 3 of 1000 rows: 96 bits of positions against 1000 of bitmap
+        assert!(matches!(Selection::adaptive(bitmap(1000, &[1, 500, 999])), Selection::Indices { len: 1000, .. }));
+        // 32 of 1000: 1024 bits of positions
+        let dense: Vec<usize> = (0 .. 32).collect();
+        assert!(matches!(Selection::adaptive(bitmap(1000, &dense)), Selection::Bitmap(_)));
+        assert!(matches!(Selection::adaptive(bitmap(0, &[])), Selection::Bitmap(_)));
+    }
+
+    #[test]
+    fn both_forms_select_alike() {
+        let set = [0, 5, 63, 64, 129];
+        let col: Vec<usize> = (0 .. 130).map(|i| i * 10).collect();
+        let forms = [Selection::Bitmap(bitmap(130, &set)), Selection::from_positions(vec![129, 5, 0, 64, 63, 5], 130)];
+        for s in &forms {
+            assert_eq!(s.positions(), set);
+            assert_eq!((s.len(), s.count_ones()), (130, 5));
+            assert_eq!(s.select(&col), vec![0, 50, 630, 640, 1290]);
+            assert_eq!(Selection::Bitmap(s.to_bitmap()).positions(), set);
+            let inverted = s.inverted().positions();
+            assert_eq!(inverted.len(), 125);
+            assert!(set.iter().all(|i| !inverted.contains(i)) && inverted.iter().all(|i| *i < 130));
+        }
+        assert_eq!(hash(&forms[0]), hash(&forms[1]));
+        assert_ne!(hash(&forms[0]), hash(&Selection::from_positions(vec![0, 5], 130)));
+    }
+
+    #[test]
+    fn filters_fill_bool_columns_adaptively() {
+        use crate::column::{Column, ColumnT, Scalar};
+        let rare: Vec<f64> = (0 .. 1000).map(|i| if i % 100 == 0 { 1.0 } else { 0.0 }).collect();
+        let mask = Column::from(rare).filter(Scalar::Num(1.0)).unwrap();
+        assert!(matches!(mask.selection(), Selection::Indices { .. }));
+        assert_eq!(mask.selection().count_ones(), 10);
+        let common = Column::from(vec![1.0; 1000]).filter(Scalar::Num(1.0)).unwrap();
+        assert!(matches!(common.selection(), Selection::Bitmap(_)));
+    }
+
+    #[test]
+    fn composing_selects_the_selected_rows() {
+        let col: Vec<usize> = (0 .. 200).collect();
+        let outer = Selection::from_positions(vec![3, 50, 64, 100, 199], 200);
+        let inner = Selection::from_positions(vec![1, 4], 5);
+        let composed = outer.compose(&inner);
+        assert_eq!(composed.len(), 200);
+        assert_eq!(composed.select(&col), inner.select(&outer.select(&col)));
+        assert_eq!(composed.select(&col), vec![50, 199]);
+
+        // a dense result comes back as a bitmap
+        let all = Selection::Bitmap(bitmap(10, &(0 .. 10).collect::<Vec<_>>()));
+        assert!(matches!(all.compose(&all), Selection::Bitmap(_)));
+    }
+
+    #[test]
+    fn filtering_at_a_selection_matches_filtering_the_gathered_rows() {
+        use crate::column::{BoolColumn, Column, ColumnT, InlineStrColumn, Scalar};
+        let sel = Selection::from_positions(vec![0, 2, 3], 5);
+        let cols = [
+            (Column::from(vec![1.0, 2.0, 1.0, 1.0, 2.0]), Scalar::Num(1.0)),
+            (Column::from(vec!["a", "b", "a", "c", "a"]), Scalar::Str("a".to_string())),
+            (Column::InlineStr(InlineStrColumn::from_strs(vec!["a", "b", "a", "c", "a"])), Scalar::Str("a".to_string())),
+            (Column::from(vec![4u64, 4, 5, 4, 4]), Scalar::Entity(4)),
+        ];
+        for (col, val) in cols {
+            let at = col.filter_at(val.clone(), &sel).unwrap();
+            let gathered = col.select(&BoolColumn::from_selection(sel.clone())).filter(val).unwrap();
+            assert_eq!(at.selection().positions(), gathered.selection().positions());
+        }
+        assert!(Column::from(vec![1.0; 5]).filter_at(Scalar::Bool(true), &sel).is_err());
+    }
+
+    #[test]
+    fn slices_renumber_from_the_offset() {
+        let forms = [Selection::Bitmap(bitmap(200, &[5, 70, 71, 150])), Selection::from_positions(vec![5, 70, 71, 150], 200)];
+        for s in &forms {
+            let part = s.slice(70, 81);
+            assert_eq!((part.len(), part.positions()), (81, vec![0, 1, 80]));
+            assert_eq!(s.slice(0, 5).positions(), Vec::<usize>::new());
+        }
+    }
+
+    #[test]
+    fn scanned_plans_describe_the_selection() {
+        let sparse = Selection::adaptive(bitmap(1000, &[1, 500]));
+        assert_eq!(FilterPlan::scanned(&sparse), FilterPlan::Scan { sampled: 1000, selectivity: 0.002, positions: true });
+        let dense = Selection::adaptive(bitmap(4, &[0, 1, 3]));
+        assert_eq!(FilterPlan::scanned(&dense).to_string(), "scan to bitmap (75.0% of 4 sampled)");
+        assert_eq!(FilterPlan::scanned(&Selection::adaptive(bitmap(0, &[]))).to_string(), "scan to bitmap (0.0% of 0 sampled)");
+        assert_eq!(FilterPlan::IndexLookup.to_string(), "index lookup");
+    }
+}
+
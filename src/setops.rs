@@ -0,0 +1,169 @@
+// Set operations over result sets: UNION ALL, UNION, INTERSECT and EXCEPT. Both sides must
+// have the same number of columns with the same datatypes, pairwise; the result takes its
+// column names from the left side. Rows compare as Scalars do (see compare.rs), so -0.0
+// matches 0.0, and NaN (null) matches NaN unless the NullSemantics make nulls distinct - then
+// a row with a null in it matches nothing, not even itself.
+//
+// As in SQL, everything but UNION ALL returns distinct rows, in order of first appearance.
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, ColumnT, Scalar};
+use crate::errors::VMError;
+use crate::nulls::{self, NullSemantics};
+use crate::result::ResultSet;
+
+use std::collections::HashSet;
+
+impl ResultSet {
+    // The rows of `self` followed by the rows of `other`
+    pub fn union_all(&self, other: &ResultSet) -> Result<ResultSet, VMError> {
+        self.check_compatible(other)?;
+        self.joined(&self.columns, &other.columns)
+    }
+
+    // The distinct rows of either side
+    pub fn union(&self, other: &ResultSet, nulls: NullSemantics) -> Result<ResultSet, VMError> {
+        self.check_compatible(other)?;
+        let mut seen = HashSet::new();
+        let left = self.keep_rows(|row| distinct(&row, nulls) || seen.insert(row));
+        let right = other.keep_rows(|row| distinct(&row, nulls) || seen.insert(row));
+        self.joined(&left, &right)
+    }
+
+    // The distinct rows of `self` that `other` also has
+    pub fn intersect(&self, other: &ResultSet, nulls: NullSemantics) -> Result<ResultSet, VMError> {
+        self.semi_join(other, true, nulls)
+    }
+
+    // The distinct rows of `self` that `other` doesn't have
+    pub fn except(&self, other: &ResultSet, nulls: NullSemantics) -> Result<ResultSet, VMError> {
+        self.semi_join(other, false, nulls)
+    }
+
+    fn semi_join(&self, other: &ResultSet, keep_matches: bool, nulls: NullSemantics) -> Result<ResultSet, VMError> {
+        self.check_compatible(other)?;
+        let probe: HashSet<Vec<Scalar>> = other.iter_rows().collect();
+        let mut seen = HashSet::new();
+        let columns = self.keep_rows(|row| if distinct(&row, nulls) {
+            !keep_matches
+        } else {
+            probe.contains(&row) == keep_matches && seen.insert(row)
+        });
+        Ok(self.named(columns))
+    }
+
+    fn check_compatible(&self, other: &ResultSet) -> Result<(), VMError> {
+        if self.len() != other.len() {
+            return Err(VMError::LengthMismatch { expected: self.len(), found: other.len() });
+        }
+        let types = self.columns.iter().zip(other.columns.iter()).map(|(a, b)| (a.datatype(), b.datatype()));
+        for (i, (left, right)) in types.enumerate() {
+            if left != right {
+                return Err(VMError::TypeError(format!("Column {} is {} on the left but {} on the right", i, left, right)));
+            }
+        }
+        Ok(())
+    }
+
+    // The columns cut down to the rows `keep` says yes to, asked in row order. Rows past the
+    // end of the shortest column are dropped, as iter_rows does.
+    fn keep_rows<F: FnMut(Vec<Scalar>) -> bool>(&self, mut keep: F) -> Vec<Column> {
+        let rows = self.columns.iter().map(|c| c.len()).min().unwrap_or(0);
+        let mut mask = BitIndex::for_col_len(rows);
+        for (i, row) in self.iter_rows().enumerate() {
+            if keep(row) { mask.set(i); }
+        }
+        let mask = BoolColumn::from_mask(mask);
+        self.columns.iter().map(|c| c.select(&mask)).collect()
+    }
+
+    fn joined(&self, left: &[Column], right: &[Column]) -> Result<ResultSet, VMError> {
+        let columns = left.iter().zip(right.iter())
+            .map(|(a, b)| Column::concat(&[a.clone(), b.clone()]))
+            .collect::<Result<_, _>>()?;
+        Ok(self.named(columns))
+    }
+
+    fn named(&self, columns: Vec<Column>) -> ResultSet {
+        ResultSet { names: self.names.clone(), columns }
+    }
+}
+
+// Whether `row` has a null that makes it distinct from every other row, itself included
+fn distinct(row: &[Scalar], nulls: NullSemantics) -> bool {
+    !nulls.nulls_group() && row.iter().any(nulls::is_null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{self, Encoding};
+
+    fn people(ids: Vec<u64>, names: Vec<&str>) -> ResultSet {
+        ResultSet::from(vec![("id", Column::from(ids)), ("name", Column::from(names))])
+    }
+
+    #[test]
+    fn union_all_keeps_every_row() {
+        let a = people(vec![1, 2, 2], vec!["ann", "bo", "bo"]);
+        let b = ResultSet::from(vec![("key", Column::from(vec![2u64])), ("who", Column::from(vec!["bo"]))]);
+        assert_eq!(a.union_all(&b).unwrap(), people(vec![1, 2, 2, 2], vec!["ann", "bo", "bo", "bo"]));
+    }
+
+    #[test]
+    fn union_keeps_distinct_rows_in_order_of_first_appearance() {
+        let a = people(vec![2, 1, 2], vec!["bo", "ann", "bo"]);
+        let b = people(vec![3, 1, 2], vec!["cy", "ann", "x"]);
+        assert_eq!(a.union(&b, NullSemantics::default()).unwrap(), people(vec![2, 1, 3, 2], vec!["bo", "ann", "cy", "x"]));
+    }
+
+    #[test]
+    fn intersect_and_except_compare_whole_rows() {
+        let a = people(vec![1, 2, 2, 3, 1], vec!["ann", "bo", "bo", "cy", "ann"]);
+        let b = people(vec![2, 3, 9], vec!["bo", "zed", "ann"]);
+        assert_eq!(a.intersect(&b, NullSemantics::default()).unwrap(), people(vec![2], vec!["bo"]));
+        assert_eq!(a.except(&b, NullSemantics::default()).unwrap(), people(vec![1, 3], vec!["ann", "cy"]));
+        assert_eq!(a.except(&a, NullSemantics::default()).unwrap().rows(), 0);
+    }
+
+    #[test]
+    fn rows_compare_as_scalars() {
+        let nums = |xs: Vec<f64>| ResultSet::from(vec![("x", Column::from(xs))]);
+        let a = nums(vec![f64::NAN, -0.0, 1.0]);
+        let b = nums(vec![0.0, f64::NAN]);
+        assert_eq!(a.intersect(&b, NullSemantics::default()).unwrap(), nums(vec![f64::NAN, -0.0]));
+        assert_eq!(a.union(&b, NullSemantics::default()).unwrap().rows(), 3);
+        // and encoded columns as their values
+        let rle = ResultSet::from(vec![("x", encoding::encode(Column::from(vec![1.0; 100]), Encoding::Rle).unwrap())]);
+        assert_eq!(rle.union(&a, NullSemantics::default()).unwrap(), nums(vec![1.0, f64::NAN, 0.0]));
+    }
+
+    #[test]
+    fn nulls_are_distinct_only_if_asked() {
+        let nums = |xs: Vec<f64>| ResultSet::from(vec![("x", Column::from(xs))]);
+        let (a, b) = (nums(vec![f64::NAN, 1.0, f64::NAN]), nums(vec![f64::NAN, 2.0]));
+        for &nulls in &[NullSemantics::Sql, NullSemantics::NullEqualsNull] {
+            assert_eq!(a.union(&b, nulls).unwrap(), nums(vec![f64::NAN, 1.0, 2.0]));
+            assert_eq!(a.intersect(&b, nulls).unwrap(), nums(vec![f64::NAN]));
+            assert_eq!(a.except(&b, nulls).unwrap(), nums(vec![1.0]));
+        }
+        // a row with a null in it matches no other row, so it's always kept by UNION and EXCEPT
+        let nulls = NullSemantics::NullsDistinct;
+        assert_eq!(a.union(&b, nulls).unwrap(), nums(vec![f64::NAN, 1.0, f64::NAN, f64::NAN, 2.0]));
+        assert_eq!(a.intersect(&b, nulls).unwrap().rows(), 0);
+        assert_eq!(a.except(&b, nulls).unwrap(), a);
+        let pairs = ResultSet::from(vec![("x", Column::from(vec![1.0, 1.0])), ("y", Column::from(vec![f64::NAN, f64::NAN]))]);
+        assert_eq!(pairs.union(&pairs, nulls).unwrap().rows(), 4);
+    }
+
+    #[test]
+    fn sides_must_match() {
+        let a = people(vec![1], vec!["ann"]);
+        let fewer = ResultSet::from(vec![("id", Column::from(vec![1u64]))]);
+        assert!(matches!(a.union(&fewer, NullSemantics::default()), Err(VMError::LengthMismatch { expected: 2, found: 1 })));
+        let swapped = ResultSet::from(vec![("name", Column::from(vec!["ann"])), ("id", Column::from(vec![1u64]))]);
+        for res in [a.union_all(&swapped), a.union(&swapped, NullSemantics::default()), a.intersect(&swapped, NullSemantics::default()), a.except(&swapped, NullSemantics::default())] {
+            assert!(matches!(res, Err(VMError::TypeError(_))));
+        }
+    }
+}
@@ -0,0 +1,491 @@
+// A table shared by many readers and appended to by writers. Every committed state of the
+// table is an immutable Version; readers take the current one (lock-free - no reader ever
+// waits for a writer, or for other readers) and query it for as long as they like, while
+// writers take turns through a table-level lock, stage their appends against the latest
+// version, and publish a new version in one atomic swap on commit. So a reader sees a table
+// either before an append or after it, never part-way through.
+//
+// Writes can also delete and update rows. Subscribers are told of every committed change,
+// in commit order, with the row positions it touched (see ChangeEvent).
+
+use crate::bitindex::BitIndex;
+use crate::column::{BoolColumn, Column, ColumnT};
+use crate::conditional;
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::result::ResultSet;
+use crate::table::check_shape;
+use crate::vm::{ColumnMode, VM};
+
+use arc_swap::ArcSwap;
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// One committed state of a SharedTable
+#[derive(Debug)]
+pub struct Version {
+    pub number: u64,        // 0 for the table as created, then one more per commit
+    pub rewritten: u64,     // the last version that deleted or updated rows; later ones only appended
+    pub names: Vec<String>,
+    pub columns: Vec<Arc<Column>>
+}
+
+impl Version {
+    pub fn rows(&self) -> usize {
+        self.columns.iter().map(|c| c.len()).max().unwrap_or(0)
+    }
+
+    // A VM for querying this version; Op::Col(0, i) loads column i
+    pub fn vm(&self) -> Result<VM, VMError> {
+        VM::with_shared_columns(self.columns.clone(), ColumnMode::Rc)
+    }
+
+    // Run `code` over rows offset .. offset + len (all of them for None), and name the
+    // resulting columns: names[i], or "#i" past the end of `names`
+    pub fn query(&self, code: &[Op], window: Option<(usize, usize)>, names: &[String]) -> Result<ResultSet, VMError> {
+        let mut vm = self.vm()?;
+        vm.set_window(window);
+        vm.run(code.to_vec())?;
+        let mut out = ResultSet::new();
+        for (i, col) in vm.into_columns()?.into_iter().enumerate() {
+            match names.get(i) {
+                Some(name) => out.push(name, col),
+                None => out.push(&format!("#{}", i), col)
+            }
+        }
+        Ok(out)
+    }
+}
+
+// What a commit did, in the order it was done. Row positions are those in the table just
+// before the change.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Append { first_row: usize, rows: ResultSet },
+    Delete { rows: Vec<usize> },
+    Update { column: String, rows: Vec<usize>, values: Column }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+    pub version: u64,       // the version the commit made
+    pub changes: Vec<Change>
+}
+
+pub type ChangeCallback = Arc<dyn Fn(&ChangeEvent) + Send + Sync>;
+
+enum Subscriber {
+    Callback(ChangeCallback),
+    Channel(Sender<ChangeEvent>)
+}
+
+pub struct SharedTable {
+    current: ArcSwap<Version>,
+    write_lock: Mutex<()>,
+    subscribers: Mutex<Vec<(usize, Subscriber)>>,
+    next_subscriber: Mutex<usize>
+}
+
+impl SharedTable {
+    // A table of `data`, whose columns must have distinct names and the same number of rows
+    pub fn new(data: ResultSet) -> Result<Self, VMError> {
+        check_columns(&data)?;
+        let version = Version {
+            number: 0,
+            rewritten: 0,
+            names: data.names,
+            columns: data.columns.into_iter().map(|c| Arc::new(c.auto_encode())).collect()
+        };
+        Ok(SharedTable {
+            current: ArcSwap::from_pointee(version), write_lock: Mutex::new(()), subscribers: Mutex::new(Vec::new()),
+            next_subscriber: Mutex::new(0)
+        })
+    }
+
+    // The latest committed version
+    pub fn snapshot(&self) -> Arc<Version> {
+        self.current.load_full()
+    }
+
+    // Wait for any other writer to finish, then start writing. Nothing written is visible to
+    // readers until TableWriter::commit.
+    pub fn write(&self) -> TableWriter<'_> {
+        // a writer that panicked never committed, so the table is still consistent
+        let guard = lock(&self.write_lock);
+        let base = self.snapshot();
+        TableWriter { table: self, _guard: guard, base, columns: None, changes: Vec::new() }
+    }
+
+    // Call `f` with every commit from now on, until unsubscribed. Callbacks run on the
+    // committing thread, holding the write lock, so they mustn't write to the table.
+    pub fn subscribe<F>(&self, f: F) -> usize
+        where F: Fn(&ChangeEvent) + Send + Sync + 'static {
+        self.add_subscriber(Subscriber::Callback(Arc::new(f)))
+    }
+
+    // Every commit from now on, as a message on the channel returned. Dropping the receiver
+    // unsubscribes.
+    pub fn subscribe_channel(&self) -> Receiver<ChangeEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.add_subscriber(Subscriber::Channel(tx));
+        rx
+    }
+
+    pub fn unsubscribe(&self, id: usize) {
+        lock(&self.subscribers).retain(|(i, _)| *i != id);
+    }
+
+    fn add_subscriber(&self, sub: Subscriber) -> usize {
+        let mut next = lock(&self.next_subscriber);
+        let id = *next;
+        *next += 1;
+        lock(&self.subscribers).push((id, sub));
+        id
+    }
+
+    fn publish(&self, event: ChangeEvent) {
+        lock(&self.subscribers).retain(|(_, sub)| match sub {
+            Subscriber::Callback(f) => { f(&event); true },
+            Subscriber::Channel(tx) => tx.send(event.clone()).is_ok()
+        });
+    }
+}
+
+// Metadata and updates find columns by name, and a ragged table fails every query after it
+fn check_columns(data: &ResultSet) -> Result<(), VMError> {
+    if data.names.len() != data.columns.len() {
+        return Err(VMError::LengthMismatch { expected: data.names.len(), found: data.columns.len() });
+    }
+    if let Some(name) = data.names.iter().enumerate().find(|(i, n)| data.names[.. *i].contains(n)).map(|(_, n)| n) {
+        return Err(VMError::TypeError(format!("There's more than one column named '{}'", name)));
+    }
+    check_shape(data.columns.iter())
+}
+
+// Poisoning only means some other thread panicked while holding the lock; the data guarded
+// here is never left half-changed
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Exclusive write access to a SharedTable. Changes apply in the order they're made, each to
+// the table as the ones before left it. Dropping the writer without committing discards them.
+pub struct TableWriter<'a> {
+    table: &'a SharedTable,
+    _guard: MutexGuard<'a, ()>,
+    base: Arc<Version>,
+    columns: Option<Vec<Column>>,   // the table with the changes so far; None while there are none
+    changes: Vec<Change>
+}
+
+impl TableWriter<'_> {
+    fn columns(&mut self) -> &mut Vec<Column> {
+        let base = &self.base;
+        self.columns.get_or_insert_with(|| base.columns.iter().map(|c| c.as_ref().clone()).collect())
+    }
+
+    // Rows in the table as changed so far
+    pub fn rows(&self) -> usize {
+        match &self.columns {
+            Some(columns) => columns.iter().map(|c| c.len()).max().unwrap_or(0),
+            None => self.base.rows()
+        }
+    }
+
+    // Append `rows`: the same columns as the table, in the same order, with the same datatypes
+    pub fn append(&mut self, rows: ResultSet) -> Result<(), VMError> {
+        if rows.names != self.base.names {
+            return Err(VMError::TypeError(format!("Expected columns {:?}, got {:?}", self.base.names, rows.names)));
+        }
+        for (name, (old, new)) in rows.names.iter().zip(self.base.columns.iter().zip(rows.columns.iter())) {
+            if old.datatype() != new.datatype() {
+                return Err(VMError::TypeError(format!("Column {} is {}, can't append {} values", name, old.datatype(), new.datatype())));
+            }
+        }
+        check_shape(rows.columns.iter())?;
+        let first_row = self.rows();
+        let columns = self.columns();
+        for (col, new) in columns.iter_mut().zip(rows.columns.iter()) {
+            *col = Column::concat(&[col.clone(), new.clone()])?;
+        }
+        self.changes.push(Change::Append { first_row, rows });
+        Ok(())
+    }
+
+    // Delete the rows at positions `rows`
+    pub fn delete(&mut self, rows: &[usize]) -> Result<(), VMError> {
+        let n = self.rows();
+        let mut keep = BitIndex::for_col_len(n);
+        keep.set_range(0, n);
+        let mut gone = BitIndex::for_col_len(n);
+        for &row in rows {
+            if row >= n {
+                return Err(VMError::RowIndexOutOfRange { idx: row, nrows: n });
+            }
+            gone.set(row);
+        }
+        let keep = BoolColumn::from_mask(keep.and(&gone.inverted()));
+        let columns = self.columns();
+        for col in columns.iter_mut() {
+            *col = col.select(&keep);
+        }
+        let mut rows = rows.to_vec();
+        rows.sort_unstable();
+        rows.dedup();
+        self.changes.push(Change::Delete { rows });
+        Ok(())
+    }
+
+    // Set `column` at positions rows[i] to values[i], for each i. A row given twice gets its last value.
+    pub fn update(&mut self, column: &str, rows: &[usize], values: Column) -> Result<(), VMError> {
+        let idx = self.base.names.iter().position(|n| n == column)
+            .ok_or_else(|| VMError::TypeError(format!("No column named '{}'", column)))?;
+        if values.len() != rows.len() {
+            return Err(VMError::LengthMismatch { expected: rows.len(), found: values.len() });
+        }
+        let n = self.rows();
+        let col = &mut self.columns()[idx];
+        // pick each row from the old values followed by the new ones
+        let mut picks: Vec<usize> = (0 .. col.len()).collect();
+        for (i, &row) in rows.iter().enumerate() {
+            if row >= n {
+                return Err(VMError::RowIndexOutOfRange { idx: row, nrows: n });
+            }
+            picks[row] = col.len() + i;
+        }
+        let both = Column::concat(&[col.clone(), values.clone()])?;
+        *col = conditional::take(&both, &picks)?;
+        self.changes.push(Change::Update { column: column.to_string(), rows: rows.to_vec(), values });
+        Ok(())
+    }
+
+    // Publish the changes as a new version, tell subscribers, and return the version's number
+    pub fn commit(mut self) -> Result<u64, VMError> {
+        let columns = match self.columns.take() {
+            Some(columns) if !self.changes.is_empty() => columns,
+            _ => return Ok(self.base.number)
+        };
+        let number = self.base.number + 1;
+        let rewrites = self.changes.iter().any(|c| !matches!(c, Change::Append { .. }));
+        let version = Version {
+            number,
+            rewritten: if rewrites { number } else { self.base.rewritten },
+            names: self.base.names.clone(),
+            columns: columns.into_iter().map(|c| Arc::new(c.auto_encode())).collect()
+        };
+        self.table.current.store(Arc::new(version));
+        self.table.publish(ChangeEvent { version: number, changes: std::mem::take(&mut self.changes) });
+        Ok(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Scalar;
+
+    use crate::opcode::Op;
+
+    use std::convert::TryFrom;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    const WRITERS: usize = 4;
+    const READERS: usize = 4;
+    const COMMITS: usize = 50;     // per writer
+    const BATCH: usize = 5;         // rows per append
+    const START: usize = 10;
+
+    // `rows` rows of (id, x = 1.0), ids from `first`
+    fn rows(first: usize, rows: usize) -> ResultSet {
+        let mut rs = ResultSet::new();
+        rs.push("id", Column::from((first as u64 .. (first + rows) as u64).collect::<Vec<_>>()));
+        rs.push("x", Column::from(vec![1.0; rows]));
+        rs
+    }
+
+    fn sum_x(version: &Version) -> Scalar {
+        Scalar::Num(Vec::<f64>::try_from(version.columns[1].as_ref()).unwrap().iter().sum())
+    }
+
+    #[test]
+    fn readers_never_see_a_table_mid_append() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            let writers: Vec<_> = (0 .. WRITERS).map(|w| {
+                let table = &table;
+                s.spawn(move || for i in 0 .. COMMITS {
+                    let mut writer = table.write();
+                    writer.append(rows(1_000_000 * (w + 1) + i * BATCH, BATCH)).unwrap();
+                    writer.commit().unwrap();
+                })
+            }).collect();
+            for _ in 0 .. READERS {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let v = table.snapshot();
+                        assert!(v.number >= last, "version {} after {}", v.number, last);
+                        last = v.number;
+                        let expected = START + BATCH * v.number as usize;
+                        assert!(v.columns.iter().all(|c| c.len() == expected), "version {} has {:?} rows", v.number,
+                            v.columns.iter().map(|c| c.len()).collect::<Vec<_>>());
+                        assert_eq!(sum_x(&v), Scalar::Num(expected as f64), "version {}", v.number);
+                    }
+                });
+            }
+            writers.into_iter().for_each(|w| w.join().unwrap());
+            done.store(true, Ordering::Relaxed);
+        });
+        let last = table.snapshot();
+        assert_eq!(last.number, (WRITERS * COMMITS) as u64);
+        assert_eq!(last.rows(), START + WRITERS * COMMITS * BATCH);
+        let mut ids: Vec<Scalar> = (0 .. last.rows()).map(|i| last.columns[0].get(i).unwrap()).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), last.rows());
+    }
+
+    #[test]
+    fn versions_are_queryable() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let mut writer = table.write();
+        writer.append(rows(START, BATCH)).unwrap();
+        assert_eq!(writer.commit().unwrap(), 1);
+        let v = table.snapshot();
+        let mut vm = v.vm().unwrap();
+        vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Entity(12)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]).unwrap();
+        crate::assert_columns_eq!(vm.column_of(&vm.stack()[0]).unwrap(), &Column::from(vec![1.0]));
+    }
+
+    #[test]
+    fn uncommitted_writes_are_discarded() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        {
+            let mut writer = table.write();
+            writer.append(rows(START, BATCH)).unwrap();
+            assert_eq!(writer.rows(), START + BATCH);
+        }
+        assert_eq!(table.snapshot().rows(), START);
+        assert_eq!(table.write().commit().unwrap(), 0);
+    }
+
+    #[test]
+    fn appends_must_match_the_table() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let mut writer = table.write();
+        let mut renamed = ResultSet::new();
+        renamed.push("id", Column::from(vec![1u64]));
+        renamed.push("y", Column::from(vec![1.0]));
+        assert!(writer.append(renamed).is_err());
+        let mut retyped = ResultSet::new();
+        retyped.push("id", Column::from(vec![1.0]));
+        retyped.push("x", Column::from(vec![1.0]));
+        assert!(writer.append(retyped).is_err());
+        assert_eq!(writer.rows(), START);
+        drop(writer);
+        assert_eq!(table.snapshot().number, 0);
+    }
+
+    #[test]
+    fn subscribers_see_commits_in_order() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let events = table.subscribe_channel();
+        let mut writer = table.write();
+        writer.append(rows(START, BATCH)).unwrap();
+        writer.commit().unwrap();
+        let mut writer = table.write();
+        writer.delete(&[3, 1, 3]).unwrap();
+        writer.update("x", &[0], Column::from(vec![5.0])).unwrap();
+        assert_eq!(writer.commit().unwrap(), 2);
+
+        let first = events.recv().unwrap();
+        assert_eq!(first.version, 1);
+        assert!(matches!(&first.changes[..], [Change::Append { first_row: START, .. }]));
+        let second = events.recv().unwrap();
+        assert_eq!(second.version, 2);
+        assert!(matches!(&second.changes[..], [Change::Delete { rows }, Change::Update { .. }] if rows == &[1, 3]));
+
+        let v = table.snapshot();
+        assert_eq!((v.rows(), v.rewritten), (START + BATCH - 2, 2));
+        assert_eq!(sum_x(&v), Scalar::Num((START + BATCH - 3 + 5) as f64));
+    }
+
+    #[test]
+    fn rows_past_the_end_are_errors() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let mut writer = table.write();
+        assert!(matches!(writer.delete(&[0, START]), Err(VMError::RowIndexOutOfRange { idx: START, nrows: START })));
+        assert!(matches!(writer.update("x", &[START + 1], Column::from(vec![2.0])),
+            Err(VMError::RowIndexOutOfRange { idx, nrows: START }) if idx == START + 1));
+        // positions count rows as the changes before left them
+        writer.delete(&[0]).unwrap();
+        assert!(matches!(writer.update("x", &[START - 1], Column::from(vec![2.0])),
+            Err(VMError::RowIndexOutOfRange { idx, nrows }) if idx == START - 1 && nrows == START - 1));
+        assert!(matches!(writer.update("y", &[0], Column::from(vec![2.0])), Err(VMError::TypeError(_))));
+        assert!(matches!(writer.update("x", &[0, 1], Column::from(vec![2.0])), Err(VMError::LengthMismatch { expected: 2, found: 1 })));
+        // only the delete went through
+        assert_eq!(writer.commit().unwrap(), 1);
+        assert_eq!(table.snapshot().rows(), START - 1);
+        assert_eq!(sum_x(&table.snapshot()), Scalar::Num((START - 1) as f64));
+    }
+
+    #[test]
+    fn appends_must_be_rectangular() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let mut writer = table.write();
+        let mut ragged = ResultSet::new();
+        ragged.push("id", Column::from(vec![1u64, 2, 3]));
+        ragged.push("x", Column::from(vec![1.0]));
+        assert!(matches!(writer.append(ragged), Err(VMError::LengthMismatch { expected: 3, found: 1 })));
+        assert_eq!(writer.commit().unwrap(), 0);
+        assert_eq!(table.snapshot().rows(), START);
+
+        let mut twice = ResultSet::new();
+        twice.push("x", Column::from(vec![1.0]));
+        twice.push("x", Column::from(vec![2.0]));
+        assert!(matches!(SharedTable::new(twice), Err(VMError::TypeError(_))));
+        let mut ragged = rows(0, START);
+        ragged.columns[1] = Column::from(vec![1.0]);
+        assert!(matches!(SharedTable::new(ragged), Err(VMError::LengthMismatch { expected: START, found: 1 })));
+    }
+
+    #[test]
+    fn the_last_update_to_a_row_wins() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let mut writer = table.write();
+        writer.update("x", &[2, 4, 2], Column::from(vec![3.0, 5.0, 7.0])).unwrap();
+        writer.commit().unwrap();
+        let x = Vec::<f64>::try_from(table.snapshot().columns[1].as_ref()).unwrap();
+        assert_eq!((x[2], x[4]), (7.0, 5.0));
+        assert_eq!(sum_x(&table.snapshot()), Scalar::Num((START - 2) as f64 + 12.0));
+    }
+
+    #[test]
+    fn dropping_a_receiver_unsubscribes() {
+        let table = SharedTable::new(rows(0, START)).unwrap();
+        let kept = table.subscribe_channel();
+        drop(table.subscribe_channel());
+        let calls = Arc::new(Mutex::new(0));
+        let seen = Arc::clone(&calls);
+        let id = table.subscribe(move |_| *lock(&seen) += 1);
+        assert_eq!(lock(&table.subscribers).len(), 3);
+
+        let mut writer = table.write();
+        writer.append(rows(START, 1)).unwrap();
+        writer.commit().unwrap();
+        // the dropped receiver went on the first send
+        assert_eq!(lock(&table.subscribers).len(), 2);
+        table.unsubscribe(id);
+        let mut writer = table.write();
+        writer.delete(&[0]).unwrap();
+        writer.commit().unwrap();
+
+        assert_eq!(lock(&table.subscribers).len(), 1);
+        assert_eq!(*lock(&calls), 1);
+        assert_eq!(kept.try_iter().map(|e| e.version).collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
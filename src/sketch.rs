@@ -0,0 +1,432 @@
+// Approximate aggregates for data too big to aggregate exactly: t-digests for quantiles and
+// count-min sketches for heavy hitters. Each takes a bounded amount of memory whatever the
+// number of rows, and two built from different rows - other chunks of a stream, other
+// threads' partitions - merge into one as if it had seen both.
+//
+// Both are Accumulators, so they can be registered as aggregate functions:
+//
+//     vm.register_udaf("p99", sketch::quantile(0.99));
+//     vm.register_udaf("top10", sketch::heavy_hitters(10));
+//
+// and run by Op::CallUdaf, which merges partial states as it goes. For data streamed in
+// chunks, keep a TDigest or CountMin and add_column each chunk instead, merging as needed.
+
+use crate::column::{Column, Scalar};
+use crate::compare::cmp_f64;
+use crate::core::prelude::*;
+use crate::core::StableHasher;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::nulls;
+use crate::udf::Accumulator;
+
+use core::f64::consts::PI;
+use core::hash::{Hash, Hasher};
+
+// A cluster of values, by their mean and how many there are
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Centroid {
+    mean: f64,
+    weight: f64
+}
+
+// Values buffered, per unit of compression, before they're merged into the centroids
+const BUFFER_FACTOR: usize = 5;
+
+pub const DEFAULT_COMPRESSION: f64 = 100.0;
+
+// A t-digest (Dunning's merging variant): values clustered into centroids that are small near
+// the extremes and larger in the middle, so tail quantiles - p99, p999 - stay accurate. It
+// keeps at most about `compression` centroids; more means more accurate, and bigger.
+#[derive(Debug, Clone)]
+pub struct TDigest {
+    compression: f64,
+    centroids: Vec<Centroid>,   // merged, in order of mean
+    buffer: Vec<Centroid>,      // added since, in no order
+    min: f64,
+    max: f64
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        TDigest::new(DEFAULT_COMPRESSION)
+    }
+}
+
+impl TDigest {
+    pub fn new(compression: f64) -> TDigest {
+        TDigest { compression: compression.max(1.0), centroids: Vec::new(), buffer: Vec::new(), min: f64::INFINITY, max: f64::NEG_INFINITY }
+    }
+
+    // Values added, or merged in from other digests
+    pub fn count(&self) -> f64 {
+        self.centroids.iter().chain(&self.buffer).map(|c| c.weight).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.centroids.is_empty() && self.buffer.is_empty()
+    }
+
+    // NaNs - nulls - are skipped
+    pub fn add(&mut self, x: f64) {
+        self.add_weighted(x, 1.0);
+    }
+
+    fn add_weighted(&mut self, x: f64, weight: f64) {
+        if x.is_nan() || weight <= 0.0 {
+            return;
+        }
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        self.buffer.push(Centroid { mean: x, weight });
+        if self.buffer.len() >= BUFFER_FACTOR * self.compression as usize {
+            self.compress();
+        }
+    }
+
+    // Every value of a Num column
+    pub fn add_column(&mut self, col: &Column) -> Result<(), VMError> {
+        match encoding::plain(col).as_ref() {
+            Column::Num(c) => c.values().iter().for_each(|x| self.add(*x)),
+            other => return Err(VMError::TypeError(format!("Expected a Num column, found a {} column", other.datatype())))
+        }
+        Ok(())
+    }
+
+    // Fold in a digest of other values
+    pub fn merge(&mut self, other: &TDigest) {
+        other.centroids.iter().chain(&other.buffer).for_each(|c| self.add_weighted(c.mean, c.weight));
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    // The value below which a fraction `q` of the values fall, interpolating between centroids;
+    // None if there are no values
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let centroids = self.merged();
+        let total: f64 = centroids.iter().map(|c| c.weight).sum();
+        let (first, last) = (centroids.first()?, centroids.last()?);
+        if q.is_nan() {
+            return None;
+        }
+        let target = q.clamp(0.0, 1.0) * total;
+        // below the first centroid's middle, towards the smallest value; above the last's,
+        // towards the largest
+        if target < first.weight / 2.0 {
+            return Some(self.min + (first.mean - self.min) * target / (first.weight / 2.0));
+        }
+        if target >= total - last.weight / 2.0 {
+            let above = (target - (total - last.weight / 2.0)) / (last.weight / 2.0);
+            return Some(last.mean + (self.max - last.mean) * above.min(1.0));
+        }
+        let mut before = 0.0;
+        for pair in centroids.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let (from, to) = (before + a.weight / 2.0, before + a.weight + b.weight / 2.0);
+            if target < to {
+                return Some(a.mean + (b.mean - a.mean) * (target - from) / (to - from));
+            }
+            before += a.weight;
+        }
+        Some(last.mean)
+    }
+
+    // Merge the buffer into the centroids
+    fn compress(&mut self) {
+        if !self.buffer.is_empty() {
+            self.centroids = self.merged();
+            self.buffer.clear();
+        }
+    }
+
+    // The centroids with the buffer merged in. Neighbours are combined while the combination
+    // spans no more than one unit of the scale function k, which is what keeps centroids near
+    // either end small.
+    fn merged(&self) -> Vec<Centroid> {
+        if self.buffer.is_empty() {
+            return self.centroids.clone();
+        }
+        let mut all: Vec<Centroid> = self.centroids.iter().chain(&self.buffer).copied().collect();
+        all.sort_by(|a, b| cmp_f64(a.mean, b.mean));
+        let total: f64 = all.iter().map(|c| c.weight).sum();
+        let k = |q: f64| self.compression / (2.0 * PI) * libm::asin(2.0 * q - 1.0);
+        let k_inv = |k: f64| (libm::sin(k * 2.0 * PI / self.compression) + 1.0) / 2.0;
+
+        let mut out = Vec::with_capacity(self.compression as usize);
+        let mut cur = all[0];
+        let mut before = 0.0;
+        let mut limit = k_inv(k(0.0) + 1.0);
+        for c in &all[1 ..] {
+            if (before + cur.weight + c.weight) / total <= limit {
+                let weight = cur.weight + c.weight;
+                cur.mean += (c.mean - cur.mean) * c.weight / weight;
+                cur.weight = weight;
+            } else {
+                before += cur.weight;
+                out.push(cur);
+                limit = k_inv(k(before / total) + 1.0);
+                cur = *c;
+            }
+        }
+        out.push(cur);
+        out
+    }
+
+    // As values an Accumulator's state can hold: compression, min, max, then each centroid's
+    // mean and weight
+    fn to_state(&self) -> Vec<Scalar> {
+        let centroids = self.merged();
+        let mut state = Vec::with_capacity(3 + 2 * centroids.len());
+        state.extend([self.compression, self.min, self.max].map(Scalar::Num));
+        centroids.iter().for_each(|c| state.extend([Scalar::Num(c.mean), Scalar::Num(c.weight)]));
+        state
+    }
+
+    fn merge_state(&mut self, state: &[Scalar]) {
+        let nums: Vec<f64> = state.iter().map(|s| if let Scalar::Num(x) = s { *x } else { f64::NAN }).collect();
+        if nums.len() < 3 || nums.len().is_multiple_of(2) {
+            return;
+        }
+        for c in nums[3 ..].chunks_exact(2) {
+            self.add_weighted(c[0], c[1]);
+        }
+        // the extremes, which the centroids' means needn't reach
+        self.min = self.min.min(nums[1]);
+        self.max = self.max.max(nums[2]);
+    }
+}
+
+pub const DEFAULT_WIDTH: usize = 2048;
+pub const DEFAULT_DEPTH: usize = 5;
+
+// A count-min sketch: `depth` rows of `width` counters, each value adding one to a counter in
+// every row, picked by a hash. A value's count is estimated as the smallest of its counters:
+// never too low, and too high by more than e/width of all the values only with probability
+// e^-depth. It also keeps the `k` values with the largest estimates seen so far - the heavy
+// hitters.
+#[derive(Debug, Clone)]
+pub struct CountMin {
+    width: usize,
+    depth: usize,
+    counts: Vec<u64>,           // row r is counts[r * width .. (r + 1) * width]
+    total: u64,
+    k: usize,
+    heavy: Vec<(Scalar, u64)>   // up to k values and their estimates
+}
+
+impl Default for CountMin {
+    fn default() -> Self {
+        CountMin::new(DEFAULT_WIDTH, DEFAULT_DEPTH).expect("the default size fits")
+    }
+}
+
+impl CountMin {
+    // Fails if there'd be more counters than memory has room for
+    pub fn new(width: usize, depth: usize) -> Result<CountMin, VMError> {
+        let (width, depth) = (width.max(1), depth.max(1));
+        let cells = width.checked_mul(depth)
+            .filter(|n| n.checked_mul(core::mem::size_of::<u64>()).is_some_and(|bytes| bytes <= isize::MAX as usize))
+            .ok_or_else(|| VMError::TypeError(format!("A {}x{} count-min sketch has too many counters", width, depth)))?;
+        Ok(CountMin { width, depth, counts: vec![0; cells], total: 0, k: 0, heavy: Vec::new() })
+    }
+
+    // Counts off by at most `epsilon` of all the values, with probability 1 - `delta`; both
+    // must be strictly between 0 and 1
+    pub fn with_error(epsilon: f64, delta: f64) -> Result<CountMin, VMError> {
+        for (name, x) in [("epsilon", epsilon), ("delta", delta)] {
+            if !(x > 0.0 && x < 1.0) {
+                return Err(VMError::TypeError(format!("A count-min sketch's {} must be between 0 and 1, not {}", name, x)));
+            }
+        }
+        let width = libm::ceil(core::f64::consts::E / epsilon);
+        let depth = libm::ceil(libm::log(1.0 / delta));
+        CountMin::new(width as usize, depth as usize)
+    }
+
+    // Keep the `k` values with the largest counts
+    pub fn with_heavy_hitters(mut self, k: usize) -> Self {
+        self.k = k;
+        self
+    }
+
+    // Values added, or merged in from other sketches
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // Nulls are skipped
+    pub fn add(&mut self, x: &Scalar) {
+        if nulls::is_null(x) {
+            return;
+        }
+        let (h1, h2) = hashes(x);
+        let mut estimate = u64::MAX;
+        for r in 0 .. self.depth {
+            let i = r * self.width + self.slot(h1, h2, r);
+            self.counts[i] += 1;
+            estimate = estimate.min(self.counts[i]);
+        }
+        self.total += 1;
+        if self.k > 0 {
+            self.offer(x, estimate);
+        }
+    }
+
+    // Every value of a column
+    pub fn add_column(&mut self, col: &Column) {
+        let col = encoding::plain(col);
+        (0 .. col.len()).filter_map(|i| col.get(i)).for_each(|x| self.add(&x));
+    }
+
+    // How many times `x` was added, or more
+    pub fn estimate(&self, x: &Scalar) -> u64 {
+        let (h1, h2) = hashes(x);
+        (0 .. self.depth).map(|r| self.counts[r * self.width + self.slot(h1, h2, r)]).min().unwrap_or(0)
+    }
+
+    // Up to k values with the largest counts, largest first
+    pub fn heavy_hitters(&self) -> Vec<(Scalar, u64)> {
+        let mut heavy = self.heavy.clone();
+        heavy.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        heavy
+    }
+
+    // Fold in a sketch of other values, which must have the same width and depth
+    pub fn merge(&mut self, other: &CountMin) -> Result<(), VMError> {
+        if (self.width, self.depth) != (other.width, other.depth) {
+            return Err(VMError::TypeError(format!("Can't merge a {}x{} count-min sketch into a {}x{} one",
+                other.depth, other.width, self.depth, self.width)));
+        }
+        self.counts.iter_mut().zip(&other.counts).for_each(|(a, b)| *a += b);
+        self.total += other.total;
+        // either side's candidates, counted again with the merged counters
+        let candidates: Vec<Scalar> = self.heavy.drain(..).chain(other.heavy.iter().cloned()).map(|(x, _)| x).collect();
+        for x in candidates {
+            let estimate = self.estimate(&x);
+            self.offer(&x, estimate);
+        }
+        Ok(())
+    }
+
+    fn slot(&self, h1: u64, h2: u64, row: usize) -> usize {
+        (h1.wrapping_add((row as u64).wrapping_mul(h2)) % self.width as u64) as usize
+    }
+
+    // Track `x` as a heavy hitter if its estimate is among the k largest
+    fn offer(&mut self, x: &Scalar, estimate: u64) {
+        if let Some(entry) = self.heavy.iter_mut().find(|(y, _)| y == x) {
+            entry.1 = estimate;
+        } else if self.heavy.len() < self.k {
+            self.heavy.push((x.clone(), estimate));
+        } else if let Some(least) = self.heavy.iter_mut().min_by_key(|(_, n)| *n).filter(|(_, n)| *n < estimate) {
+            *least = (x.clone(), estimate);
+        }
+    }
+
+    // As values an Accumulator's state can hold: width, depth and total, the counters, then
+    // the heavy hitters
+    fn to_state(&self) -> Vec<Scalar> {
+        let mut state = Vec::with_capacity(3 + self.counts.len() + self.heavy.len());
+        state.extend([self.width as u64, self.depth as u64, self.total].map(Scalar::Entity));
+        state.extend(self.counts.iter().map(|n| Scalar::Entity(*n)));
+        state.extend(self.heavy.iter().map(|(x, _)| x.clone()));
+        state
+    }
+
+    fn merge_state(&mut self, state: &[Scalar]) {
+        let (width, depth, total) = match state {
+            [Scalar::Entity(w), Scalar::Entity(d), Scalar::Entity(t), ..] => (*w as usize, *d as usize, *t),
+            _ => return
+        };
+        let cells = width.saturating_mul(depth);
+        if state.len() < 3 + cells {
+            return;
+        }
+        let counts = state[3 .. 3 + cells].iter().map(|s| if let Scalar::Entity(n) = s { *n } else { 0 }).collect();
+        let heavy = state[3 + cells ..].iter().map(|x| (x.clone(), 0)).collect();
+        let other = CountMin { width, depth, counts, total, k: self.k, heavy };
+        // a state from a differently sized sketch can't be merged; Accumulator::merge has no way
+        // to say so, but every accumulator a function makes is sized alike
+        let _ = self.merge(&other);
+    }
+}
+
+// Two independent hashes of `x`, from which each row's slot is derived (Kirsch-Mitzenmacher).
+// Stable across runs, so sketches built by different processes can be merged too.
+fn hashes(x: &Scalar) -> (u64, u64) {
+    let mut h = StableHasher::default();
+    x.hash(&mut h);
+    let h1 = mix(h.finish());
+    (h1, mix(h1) | 1)
+}
+
+// splitmix64's finalizer, to spread FNV's output over every bit
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// The `q` quantile of the Num argument, as a t-digest estimates it
+struct Quantile {
+    q: f64,
+    digest: TDigest
+}
+
+impl Accumulator for Quantile {
+    fn update(&mut self, args: &[Scalar]) {
+        if let Some(Scalar::Num(x)) = args.first() {
+            self.digest.add(*x);
+        }
+    }
+
+    fn state(&self) -> Vec<Scalar> {
+        self.digest.to_state()
+    }
+
+    fn merge(&mut self, state: &[Scalar]) {
+        self.digest.merge_state(state);
+    }
+
+    fn finish(&self) -> Scalar {
+        Scalar::Num(self.digest.quantile(self.q).unwrap_or(f64::NAN))
+    }
+}
+
+// The most frequent values of the argument, as a record of [value, count] records, most
+// frequent first
+struct HeavyHitters(CountMin);
+
+impl Accumulator for HeavyHitters {
+    fn update(&mut self, args: &[Scalar]) {
+        if let Some(x) = args.first() {
+            self.0.add(x);
+        }
+    }
+
+    fn state(&self) -> Vec<Scalar> {
+        self.0.to_state()
+    }
+
+    fn merge(&mut self, state: &[Scalar]) {
+        self.0.merge_state(state);
+    }
+
+    fn finish(&self) -> Scalar {
+        Scalar::Record(self.0.heavy_hitters().into_iter()
+            .map(|(x, n)| Scalar::Record(vec![x, Scalar::Entity(n)]))
+            .collect())
+    }
+}
+
+// An aggregate function, for VM::register_udaf, estimating the `q` quantile (0.5 the median)
+// of its argument
+pub fn quantile(q: f64) -> impl Fn() -> Box<dyn Accumulator> + Send + Sync {
+    move || Box::new(Quantile { q, digest: TDigest::default() })
+}
+
+// An aggregate function, for VM::register_udaf, giving the `k` most frequent values of its
+// argument with their estimated counts
+pub fn heavy_hitters(k: usize) -> impl Fn() -> Box<dyn Accumulator> + Send + Sync {
+    move || Box::new(HeavyHitters(CountMin::default().with_heavy_hitters(k)))
+}
@@ -0,0 +1,189 @@
+use core::fmt;
+
+use crate::column::{Column, Scalar};
+use crate::core::prelude::*;
+use crate::opcode::Op;
+use crate::schema::Datatype;
+
+// A description of VM state that can outlive the VM: enough to report where and on what
+// a program failed, without copying any column data.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnMeta {
+    pub kind: &'static str,
+    pub dtype: Datatype,
+    pub len: usize,
+    pub bytes: usize
+}
+
+impl ColumnMeta {
+    pub fn of(col: &Column) -> Self {
+        ColumnMeta { kind: col.kind(), dtype: col.datatype(), len: col.len(), bytes: col.memory_usage() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueMeta {
+    Scalar(Scalar),
+    Column(ColumnMeta)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VMSnapshot {
+    pub ip: usize,                  // next instruction to execute
+    pub last_op: Option<Op>,        // the instruction at ip - 1, i.e. the one that failed after an error
+    pub code_len: usize,
+    pub stack: Vec<ValueMeta>,      // bottom first
+    pub columns: Vec<ColumnMeta>
+}
+
+impl VMSnapshot {
+    // Render as a JSON object, so it can be shipped to whatever collects crash reports.
+    pub fn to_json(&self) -> String {
+        let last_op = match &self.last_op {
+            Some(op) => json_str(&format!("{:?}", op)),
+            None => "null".to_string()
+        };
+        let stack: Vec<String> = self.stack.iter().map(|v| match v {
+            ValueMeta::Scalar(s) => format!("{{\"scalar\":{}}}", scalar_json(s)),
+            ValueMeta::Column(c) => format!("{{\"column\":{}}}", column_json(c))
+        }).collect();
+        let columns: Vec<String> = self.columns.iter().map(column_json).collect();
+        format!(
+            "{{\"ip\":{},\"last_op\":{},\"code_len\":{},\"stack\":[{}],\"columns\":[{}]}}",
+            self.ip, last_op, self.code_len, stack.join(","), columns.join(",")
+        )
+    }
+}
+
+fn column_json(c: &ColumnMeta) -> String {
+    format!(
+        "{{\"kind\":{},\"dtype\":{},\"len\":{},\"bytes\":{}}}",
+        json_str(c.kind), json_str(&c.dtype.to_string()), c.len, c.bytes
+    )
+}
+
+fn scalar_json(s: &Scalar) -> String {
+    match s {
+        Scalar::Bool(x) => x.to_string(),
+        Scalar::Num(x) if x.is_finite() => x.to_string(),
+        Scalar::Num(_) | Scalar::Null => "null".to_string(),
+        Scalar::Str(x) => json_str(x),
+        Scalar::Entity(x) => x.to_string(),
+        Scalar::Int(x) => x.to_string(),
+        Scalar::Duration(x) => json_str(&x.to_string()),
+        Scalar::Timestamp(x) => json_str(&x.to_string()),
+        Scalar::Ipv4(x) => json_str(&x.to_string()),
+        Scalar::Ipv6(x) => json_str(&x.to_string()),
+        Scalar::Json(x) => x.clone(),
+        Scalar::Point(p) => format!("[{},{}]", scalar_json(&Scalar::Num(p.x)), scalar_json(&Scalar::Num(p.y))),
+        Scalar::Vector(xs) => {
+            let xs: Vec<String> = xs.iter().map(|x| scalar_json(&Scalar::Num(*x as f64))).collect();
+            format!("[{}]", xs.join(","))
+        },
+        Scalar::Record(xs) | Scalar::List(xs) => {
+            let xs: Vec<String> = xs.iter().map(scalar_json).collect();
+            format!("[{}]", xs.join(","))
+        }
+    }
+}
+
+pub(crate) fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for VMSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "ip: {}/{}", self.ip, self.code_len)?;
+        if let Some(op) = &self.last_op {
+            writeln!(f, "last op: {:?}", op)?;
+        }
+        writeln!(f, "stack (top last):")?;
+        for v in &self.stack {
+            match v {
+                ValueMeta::Scalar(s) => writeln!(f, "  {}", s)?,
+                ValueMeta::Column(c) => writeln!(f, "  {}[{} rows]", c.kind, c.len)?
+            }
+        }
+        writeln!(f, "columns:")?;
+        for (i, c) in self.columns.iter().enumerate() {
+            writeln!(f, "  {}: {}[{} rows, {} bytes]", i, c.kind, c.len, c.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    fn columns() -> Vec<Column> {
+        vec![Column::from(vec![1.0, 2.0, 3.0]), Column::from(vec!["a", "b", "c"])]
+    }
+
+    fn failed_vm() -> VM {
+        let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+        // FilterEq pops the 2 and the strings, then fails comparing them
+        let code = vec![Op::Col(0, 0), Op::Col(0, 1), Op::Lit(Scalar::Num(2.0)), Op::FilterEq];
+        assert!(vm.run(code).is_err());
+        vm
+    }
+
+    #[test]
+    fn describes_where_a_run_failed() {
+        let snap = failed_vm().snapshot();
+        assert_eq!(snap.ip, 4);
+        assert_eq!(snap.code_len, 4);
+        assert_eq!(snap.last_op, Some(Op::FilterEq));
+        let bytes: Vec<usize> = columns().iter().map(|c| c.memory_usage()).collect();
+        let num = ColumnMeta { kind: "Num", dtype: Datatype::Num, len: 3, bytes: bytes[0] };
+        assert_eq!(snap.stack, vec![ValueMeta::Column(num.clone())]);
+        assert_eq!(snap.columns, vec![num, ColumnMeta { kind: "InlineStr", dtype: Datatype::Str, len: 3, bytes: bytes[1] }]);
+    }
+
+    #[test]
+    fn has_no_last_op_before_a_run() {
+        let snap = VM::new(Table::from_columns(vec![]).unwrap()).snapshot();
+        assert_eq!((snap.ip, snap.last_op, snap.code_len), (0, None, 0));
+        assert!(snap.stack.is_empty() && snap.columns.is_empty());
+    }
+
+    #[test]
+    fn renders_json() {
+        let json = failed_vm().snapshot().to_json();
+        let bytes: Vec<usize> = columns().iter().map(|c| c.memory_usage()).collect();
+        let num = format!("{{\"kind\":\"Num\",\"dtype\":\"Num\",\"len\":3,\"bytes\":{}}}", bytes[0]);
+        let strs = format!("{{\"kind\":\"InlineStr\",\"dtype\":\"Str\",\"len\":3,\"bytes\":{}}}", bytes[1]);
+        assert_eq!(json, format!("{{\"ip\":4,\"last_op\":\"FilterEq\",\"code_len\":4,\"stack\":[{{\"column\":{}}}],\"columns\":[{},{}]}}", num, num, strs));
+    }
+
+    #[test]
+    fn escapes_json_strings_and_nulls_non_finite_numbers() {
+        assert_eq!(json_str("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+        assert_eq!(scalar_json(&Scalar::Num(f64::NAN)), "null");
+        assert_eq!(scalar_json(&Scalar::Record(vec![Scalar::Entity(3), Scalar::Str("x".to_string())])), "[3,\"x\"]");
+    }
+
+    #[test]
+    fn renders_text() {
+        let text = failed_vm().snapshot().to_string();
+        let bytes: Vec<usize> = columns().iter().map(|c| c.memory_usage()).collect();
+        assert_eq!(text, format!("ip: 4/4\nlast op: FilterEq\nstack (top last):\n  Num[3 rows]\ncolumns:\n  0: Num[3 rows, {} bytes]\n  1: InlineStr[3 rows, {} bytes]\n", bytes[0], bytes[1]));
+    }
+}
@@ -0,0 +1,76 @@
+// Counts and timings gathered over a VM's last run, op kind by op kind (see VM::stats), for
+// finding which instructions a query spends its time in without reaching for a profiler.
+// explain_analyze gives the same per instruction of one program; these add up by kind, and
+// are there after every run without asking.
+
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+use crate::core::prelude::*;
+
+// The runs of one kind of op
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub count: usize,
+    pub rows_out: usize,    // rows of the columns they pushed (set bits, for masks), 0 for scalars
+    pub elapsed: Duration   // zero without std, which has no clock to read
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecStats {
+    pub ops: BTreeMap<&'static str, OpStats>,   // by mnemonic
+    pub rows_scanned: usize,    // rows of loaded columns pushed by Op::Col and Op::ColByName
+    pub rows_produced: usize    // rows of the longest column in the result
+}
+
+impl ExecStats {
+    pub(crate) fn record(&mut self, mnemonic: &'static str, rows_out: usize, elapsed: Duration) {
+        let op = self.ops.entry(mnemonic).or_default();
+        op.count += 1;
+        op.rows_out += rows_out;
+        op.elapsed += elapsed;
+    }
+
+    // Instructions run
+    pub fn instructions(&self) -> usize {
+        self.ops.values().map(|op| op.count).sum()
+    }
+
+    // Time spent in instructions, subqueries' included in theirs
+    pub fn elapsed(&self) -> Duration {
+        self.ops.values().map(|op| op.elapsed).sum()
+    }
+
+    // The kinds of op run, the one that took longest first
+    pub fn by_time(&self) -> Vec<(&'static str, OpStats)> {
+        let mut ops: Vec<_> = self.ops.iter().map(|(name, op)| (*name, *op)).collect();
+        ops.sort_by(|a, b| b.1.elapsed.cmp(&a.1.elapsed).then(a.0.cmp(b.0)));
+        ops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::{Column, Scalar};
+    use crate::opcode::Op;
+    use crate::table::Table;
+    use crate::vm::VM;
+
+    #[test]
+    fn runs_are_counted_by_op_kind() {
+        let mut vm = VM::new(Table::from_columns(vec![Column::from(vec![1.0, 2.0, 2.0, 5.0]), Column::from(vec![10u64, 11, 12, 13])]).unwrap());
+        let filter = |x| vec![Op::Col(0, 0), Op::Lit(Scalar::Num(x)), Op::FilterEq];
+        vm.run([filter(2.0), filter(5.0), vec![Op::Or, Op::Col(0, 1), Op::Select(1)]].concat()).unwrap();
+        let stats = vm.stats();
+        assert_eq!(stats.instructions(), 9);
+        assert_eq!((stats.ops["FILTER_EQ"].count, stats.ops["FILTER_EQ"].rows_out), (2, 3));
+        assert_eq!((stats.ops["COL"].count, stats.ops["OR"].rows_out, stats.ops["SELECT"].rows_out), (3, 3, 3));
+        assert_eq!((stats.rows_scanned, stats.rows_produced), (12, 3));
+        assert_eq!(stats.by_time().len(), 5);
+        // each run starts over
+        vm.take_stack();
+        vm.run(vec![Op::Col(0, 0), Op::Sum]).unwrap();
+        assert_eq!((vm.stats().instructions(), vm.stats().rows_scanned, vm.stats().rows_produced), (2, 4, 0));
+    }
+}
@@ -0,0 +1,356 @@
+// The on-disk layout of column data, shared by join spill files and database snapshots.
+// A column is its row count then its values: numbers as little-endian 8-byte words, bools as
+// a byte each, strings (and JSON documents) as a 4-byte length and their UTF-8 bytes, IP
+// addresses as their 4 or 16 bytes in network order. A categorical column lists its
+// categories (a count, then each as a string) ahead of a 4-byte rank per row. A list column
+// is its items' datatype tag, a 4-byte length per row, then the items as a column. A column
+// with nulls sets the top bit of its row count, and follows it with a byte per row, 1 where
+// the row has a value, then its values as a column. Columns are written plain, whatever their
+// encoding; the datatype to read one back as is stored elsewhere.
+
+use crate::buffer::Buffer;
+use crate::categorical::{Categories, CategoricalColumn};
+use crate::column::Column;
+use crate::duration::Nanos;
+use crate::geo::Point;
+use crate::ip::{Ipv4Addr, Ipv6Addr};
+use crate::json::JsonColumn;
+use crate::list::ListColumn;
+use crate::nulls::NullableColumn;
+use crate::record_column::RecordColumn;
+use crate::bitindex::BitIndex;
+use crate::encoding;
+use crate::schema::Datatype;
+use crate::timestamp::Micros;
+use crate::vector::VectorColumn;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+pub fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn write_u32<W: Write>(w: &mut W, x: u32) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+pub fn write_u64<W: Write>(w: &mut W, x: u64) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+// A length or count, as 4 bytes; one that doesn't fit is an error rather than cut short
+pub fn write_len<W: Write>(w: &mut W, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid(format!("{} is too long to write: the most is {}", len, u32::MAX)))?;
+    write_u32(w, len)
+}
+
+pub fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_len(w, s.len())?;
+    w.write_all(s.as_bytes())
+}
+
+pub fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = read_u32(r)? as usize;
+    let mut buf = Vec::new();
+    // take() rather than a buffer of `len`: a corrupt length mustn't allocate gigabytes
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    String::from_utf8(buf).map_err(|e| invalid(e.to_string()))
+}
+
+pub fn datatype_tag(dtype: Datatype) -> u8 {
+    match dtype {
+        Datatype::Bool => 0,
+        Datatype::Num => 1,
+        Datatype::Str => 2,
+        Datatype::Entity => 3,
+        Datatype::Duration => 4,
+        Datatype::Point => 5,
+        Datatype::Ipv4 => 6,
+        Datatype::Ipv6 => 7,
+        Datatype::Json => 8,
+        Datatype::Categorical => 9,
+        Datatype::Vector => 10,
+        Datatype::List => 11,
+        Datatype::Int => 12,
+        Datatype::Timestamp => 13,
+        Datatype::Record => 14
+    }
+}
+
+pub fn datatype_of_tag(tag: u8) -> io::Result<Datatype> {
+    match tag {
+        0 => Ok(Datatype::Bool),
+        1 => Ok(Datatype::Num),
+        2 => Ok(Datatype::Str),
+        3 => Ok(Datatype::Entity),
+        4 => Ok(Datatype::Duration),
+        5 => Ok(Datatype::Point),
+        6 => Ok(Datatype::Ipv4),
+        7 => Ok(Datatype::Ipv6),
+        8 => Ok(Datatype::Json),
+        9 => Ok(Datatype::Categorical),
+        10 => Ok(Datatype::Vector),
+        11 => Ok(Datatype::List),
+        12 => Ok(Datatype::Int),
+        13 => Ok(Datatype::Timestamp),
+        14 => Ok(Datatype::Record),
+        _ => Err(invalid(format!("unknown datatype tag {}", tag)))
+    }
+}
+
+// Set in a column's row count if it has nulls
+const HAS_NULLS: u64 = 1 << 63;
+
+pub fn write_column<W: Write>(w: &mut W, col: &Column) -> io::Result<()> {
+    // stored as one column; it's read back plain
+    if let Column::Chunked(c) = col {
+        return write_column(w, &c.decode());
+    }
+    if let Column::Nullable(c) = col {
+        write_u64(w, c.len() as u64 | HAS_NULLS)?;
+        (0 .. c.len()).try_for_each(|i| w.write_all(&[c.is_valid(i) as u8]))?;
+        return write_column(w, c.values());
+    }
+    write_u64(w, col.len() as u64)?;
+    let col = encoding::plain(col);
+    match col.as_ref() {
+        Column::Num(c) => c.values().iter().try_for_each(|x| w.write_all(&x.to_le_bytes())),
+        Column::Entity(c) => c.values().iter().try_for_each(|x| write_u64(w, *x)),
+        Column::Int(c) => c.values().iter().try_for_each(|x| write_u64(w, *x as u64)),
+        Column::Duration(c) => c.values().iter().try_for_each(|x| write_u64(w, x.0 as u64)),
+        Column::Timestamp(c) => c.values().iter().try_for_each(|x| write_u64(w, x.0 as u64)),
+        Column::Point(c) => c.values().iter().try_for_each(|p| write_u64(w, p.x.to_bits()).and_then(|_| write_u64(w, p.y.to_bits()))),
+        Column::Ipv4(c) => c.values().iter().try_for_each(|x| w.write_all(&x.octets())),
+        Column::Ipv6(c) => c.values().iter().try_for_each(|x| w.write_all(&x.octets())),
+        Column::Bool(c) => (0 .. c.selection().len()).try_for_each(|i| w.write_all(&[c.selection().contains(i) as u8])),
+        Column::Str(c) => c.data.iter().try_for_each(|s| write_str(w, s)),
+        Column::InlineStr(c) => c.iter().try_for_each(|s| write_str(w, s)),
+        Column::Json(c) => c.iter().try_for_each(|s| write_str(w, s)),
+        Column::Vector(c) => {
+            write_len(w, c.dim())?;
+            c.data.iter().try_for_each(|x| w.write_all(&x.to_le_bytes()))
+        },
+        Column::Categorical(c) => {
+            write_len(w, c.categories().len())?;
+            c.categories().names().iter().try_for_each(|s| write_str(w, s))?;
+            c.codes().iter().try_for_each(|x| write_u32(w, *x))
+        },
+        Column::List(c) => {
+            w.write_all(&[datatype_tag(c.item_type())])?;
+            (0 .. c.len()).try_for_each(|i| write_len(w, c.list_len(i)))?;
+            let (start, end) = (c.offsets()[0], c.offsets()[c.len()]);
+            write_column(w, &c.items().slice(start, end - start))
+        },
+        // a column per field
+        Column::Record(c) => {
+            write_len(w, c.fields().len())?;
+            c.fields().iter().try_for_each(|f| w.write_all(&[datatype_tag(*f)]))?;
+            (0 .. c.fields().len()).try_for_each(|j| write_column(w, &c.field(j).expect("field in range")))
+        },
+        _ => unreachable!("plain() returns plain columns")
+    }
+}
+
+// `len` values from `read`, grown as they arrive rather than reserved up front, for the same
+// reason as in read_str
+fn read_values<T, F: FnMut() -> io::Result<T>>(len: usize, mut read: F) -> io::Result<Vec<T>> {
+    let mut values = Vec::new();
+    for _ in 0 .. len {
+        values.push(read()?);
+    }
+    Ok(values)
+}
+
+pub fn read_column<R: Read>(r: &mut R, dtype: Datatype) -> io::Result<Column> {
+    let len = read_u64(r)?;
+    if len & HAS_NULLS != 0 {
+        let valid = read_values((len & !HAS_NULLS) as usize, || {
+            let mut b = [0; 1];
+            r.read_exact(&mut b).map(|_| b[0] != 0)
+        })?;
+        let mut validity = BitIndex::for_col_len(valid.len());
+        (0 .. valid.len()).filter(|i| valid[*i]).for_each(|i| validity.set(i));
+        let values = read_column(r, dtype)?;
+        return NullableColumn::new(values, validity).map(Column::Nullable).map_err(|e| invalid(format!("{:?}", e)));
+    }
+    let len = len as usize;
+    Ok(match dtype {
+        Datatype::Num => Column::from(read_values(len, || read_u64(r).map(f64::from_bits))?),
+        Datatype::Entity => Column::from(read_values(len, || read_u64(r))?),
+        Datatype::Int => Column::from(read_values(len, || read_u64(r).map(|x| x as i64))?),
+        Datatype::Duration => Column::from(read_values(len, || read_u64(r).map(|x| Nanos(x as i64)))?),
+        Datatype::Timestamp => Column::from(read_values(len, || read_u64(r).map(|x| Micros(x as i64)))?),
+        Datatype::Point => Column::from(read_values(len, || {
+            Ok(Point::new(f64::from_bits(read_u64(r)?), f64::from_bits(read_u64(r)?)))
+        })?),
+        Datatype::Ipv4 => Column::from(read_values(len, || {
+            let mut b = [0; 4];
+            r.read_exact(&mut b).map(|_| Ipv4Addr::from(b))
+        })?),
+        Datatype::Ipv6 => Column::from(read_values(len, || {
+            let mut b = [0; 16];
+            r.read_exact(&mut b).map(|_| Ipv6Addr::from(b))
+        })?),
+        Datatype::Bool => Column::from(read_values(len, || {
+            let mut b = [0; 1];
+            r.read_exact(&mut b).map(|_| b[0] != 0)
+        })?),
+        Datatype::Str => Column::from(read_values(len, || read_str(r))?),
+        Datatype::Json => {
+            let docs = read_values(len, || read_str(r))?;
+            Column::Json(JsonColumn::parse(docs.iter().map(|d| d.as_str())).map_err(|e| invalid(format!("{:?}", e)))?)
+        },
+        Datatype::Vector => {
+            let dim = read_u32(r)? as usize;
+            let data = read_values(len.saturating_mul(dim), || read_u32(r).map(f32::from_bits))?;
+            Column::Vector(VectorColumn::new(dim, data).map_err(|e| invalid(format!("{:?}", e)))?)
+        },
+        Datatype::Categorical => {
+            let count = read_u32(r)? as usize;
+            let names = read_values(count, || read_str(r))?;
+            let categories = Categories::new(&names.iter().map(|s| s.as_str()).collect::<Vec<_>>())
+                .map_err(|e| invalid(format!("{:?}", e)))?;
+            let codes = read_values(len, || read_u32(r))?;
+            if let Some(code) = codes.iter().find(|x| **x as usize >= count) {
+                return Err(invalid(format!("category {} of {}", code, count)));
+            }
+            Column::Categorical(CategoricalColumn::with_codes(Arc::new(categories), Buffer::from(codes)))
+        },
+        Datatype::List => {
+            let mut tag = [0; 1];
+            r.read_exact(&mut tag)?;
+            let lens = read_values(len, || read_u32(r))?;
+            let mut offsets = vec![0];
+            lens.iter().for_each(|n| offsets.push(offsets[offsets.len() - 1] + *n as usize));
+            let items = read_column(r, datatype_of_tag(tag[0])?)?;
+            Column::List(ListColumn::new(offsets, items).map_err(|e| invalid(format!("{:?}", e)))?)
+        },
+        Datatype::Record => {
+            let count = read_u32(r)? as usize;
+            let tags = read_values(count, || {
+                let mut tag = [0; 1];
+                r.read_exact(&mut tag).map(|_| tag[0])
+            })?;
+            let fields = tags.iter().map(|t| read_column(r, datatype_of_tag(*t)?)).collect::<io::Result<Vec<_>>>()?;
+            if let Some(c) = fields.iter().find(|c| c.len() != len) {
+                return Err(invalid(format!("record field of {} rows in a column of {}", c.len(), len)));
+            }
+            let records = match fields.is_empty() {
+                true => RecordColumn::from_records(Vec::new(), &vec![Vec::new(); len]),
+                false => RecordColumn::from_fields(&fields)
+            };
+            Column::Record(records.map_err(|e| invalid(format!("{:?}", e)))?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunked::ChunkedColumn;
+    use crate::column::Scalar;
+    use crate::selection::Selection;
+
+    fn round_trip(col: &Column) -> Column {
+        let mut buf = Vec::new();
+        write_column(&mut buf, col).unwrap();
+        let mut r = buf.as_slice();
+        let res = read_column(&mut r, col.datatype()).unwrap();
+        assert!(r.is_empty(), "{} bytes left over", r.len());
+        res
+    }
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::from(vec![1.5, -0.0, f64::INFINITY]),
+            Column::from(vec![1u64, 2, u64::MAX]),
+            Column::from(vec![Nanos(-5), Nanos(0), Nanos(i64::MAX)]),
+            Column::from(vec![Micros(-1), Micros(1_709_296_200_000_000)]),
+            Column::Record(RecordColumn::from_records(vec![Datatype::Bool, Datatype::Str], &[vec![Scalar::Bool(true), Scalar::Str("x".to_string())]]).unwrap()),
+            Column::from(vec![i64::MIN, -1, 9_007_199_254_740_993]),
+            Column::Nullable(NullableColumn::with_nulls(Column::from(vec!["a", "b", "c"]), &Selection::from_positions(vec![1], 3)).unwrap()),
+            Column::from(vec![Point::new(1.0, 2.0), Point::new(-0.0, f64::NAN)]),
+            Column::from(vec![Ipv4Addr::from([10, 0, 0, 1]), Ipv4Addr::BROADCAST]),
+            Column::from(vec![Ipv6Addr::from([0xfe; 16]), Ipv6Addr::UNSPECIFIED]),
+            Column::from(vec![true, false, true]),
+            Column::from(vec!["".to_string(), "h\u{e9}llo".to_string(), "a\u{0}b".to_string()]),
+            Column::Json(JsonColumn::parse(vec![r#"{"a": [1, "\u00e9"]}"#, "null"]).unwrap()),
+            Column::Categorical(CategoricalColumn::from_strs(Arc::new(Categories::new(&["lo", "mid", "hi"]).unwrap()), vec!["hi", "lo", "hi"]).unwrap()),
+            Column::Vector(VectorColumn::new(2, vec![1.5, -0.0, f32::NAN, f32::INFINITY]).unwrap()),
+            // a slice, so its lists don't start at the first item
+            Column::List(ListColumn::new(vec![0, 1, 1, 3], Column::from(vec!["a", "b", "c"])).unwrap()).slice(1, 2),
+            Column::from(Vec::<f64>::new())
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_datatype() {
+        for col in columns() {
+            crate::assert_columns_eq!(round_trip(&col), col);
+        }
+    }
+
+    #[test]
+    fn round_trips_encoded_columns_as_plain() {
+        let col = Column::from((0 .. 1000).map(|i| i / 100).collect::<Vec<u64>>()).auto_encode();
+        crate::assert_columns_eq!(round_trip(&col), col);
+        let chunked = Column::Chunked(ChunkedColumn::new(col.clone(), 300).unwrap());
+        crate::assert_columns_eq!(round_trip(&chunked), col);
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        for col in columns().iter().filter(|c| !c.is_empty()) {
+            let mut buf = Vec::new();
+            write_column(&mut buf, col).unwrap();
+            for len in 0 .. buf.len() {
+                assert!(read_column(&mut &buf[.. len], col.datatype()).is_err(), "{:?} cut to {} bytes", col.datatype(), len);
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_lengths_past_u32() {
+        assert!(write_len(&mut Vec::new(), u32::MAX as usize).is_ok());
+        #[cfg(target_pointer_width = "64")]
+        assert!(write_len(&mut Vec::new(), u32::MAX as usize + 1).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_strings_and_tags() {
+        let mut buf = Vec::new();
+        write_u64(&mut buf, 1).unwrap();
+        write_u32(&mut buf, 2).unwrap();
+        buf.extend_from_slice(&[0xff, 0xfe]);
+        assert!(read_column(&mut buf.as_slice(), Datatype::Str).is_err());
+        assert!(datatype_of_tag(200).is_err());
+        // JSON columns are checked as they're read
+        let mut buf = Vec::new();
+        write_column(&mut buf, &Column::from(vec!["{}", "{"])).unwrap();
+        assert!(read_column(&mut buf.as_slice(), Datatype::Json).is_err());
+        // and categorical ones for ranks past their categories
+        let mut buf = Vec::new();
+        write_u64(&mut buf, 1).unwrap();
+        write_u32(&mut buf, 1).unwrap();
+        write_str(&mut buf, "a").unwrap();
+        write_u32(&mut buf, 1).unwrap();
+        assert!(read_column(&mut buf.as_slice(), Datatype::Categorical).is_err());
+    }
+}
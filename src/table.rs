@@ -0,0 +1,177 @@
+// A table: named columns, all with the same number of rows, and the schema describing them.
+// It's what a VM is loaded with (see VM::new), so a ragged set of columns is turned away when
+// the table is made rather than surfacing as a mask of the wrong length mid-query.
+
+use crate::column::Column;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::result::{ResultSet, TableFormat};
+use crate::schema::{Field, Schema};
+
+use core::convert::TryFrom;
+use core::fmt;
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    schema: Schema,
+    columns: Vec<Column>
+}
+
+impl Table {
+    // Columns under the names given, which must be distinct. The schema is each column's type.
+    pub fn new(columns: Vec<(String, Column)>) -> Result<Table, VMError> {
+        let fields = columns.iter().map(|(name, col)| field_of(name, col)).collect();
+        Table::with_schema(Schema::new(fields), columns.into_iter().map(|(_, col)| col).collect())
+    }
+
+    // Columns named c0, c1 and so on, for when only their positions matter
+    pub fn from_columns(columns: Vec<Column>) -> Result<Table, VMError> {
+        Table::with_schema(default_schema(columns.iter()), columns)
+    }
+
+    // Columns described by `schema`: one per field, of the field's type
+    pub fn with_schema(schema: Schema, columns: Vec<Column>) -> Result<Table, VMError> {
+        if schema.len() != columns.len() {
+            return Err(VMError::LengthMismatch { expected: schema.len(), found: columns.len() });
+        }
+        for (i, field) in schema.fields.iter().enumerate() {
+            if schema.fields[.. i].iter().any(|f| f.name == field.name) {
+                return Err(VMError::TypeError(format!("Two columns are named '{}'", field.name)));
+            }
+            if columns[i].datatype() != field.dtype {
+                return Err(VMError::TypeError(format!("Column '{}' is {}, but holds {} values", field.name, field.dtype, columns[i].datatype())));
+            }
+        }
+        check_shape(columns.iter())?;
+        Ok(Table { schema, columns })
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn into_columns(self) -> Vec<Column> {
+        self.columns
+    }
+
+    pub fn into_parts(self) -> (Schema, Vec<Column>) {
+        (self.schema, self.columns)
+    }
+
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.schema.index_of(name).map(|i| &self.columns[i])
+    }
+
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.schema.fields.iter().map(|f| f.name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &Column)> {
+        self.names().zip(self.columns.iter())
+    }
+
+    // Number of columns
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn rows(&self) -> usize {
+        self.columns.first().map(|c| c.len()).unwrap_or(0)
+    }
+
+    // See ResultSet::fmt_table
+    pub fn fmt_table(&self, format: &TableFormat) -> String {
+        ResultSet::from(self.clone()).fmt_table(format)
+    }
+}
+
+// A table's columns all have its number of rows; a ragged one is a loading bug that
+// would otherwise surface as a mask of the wrong length several ops later
+pub(crate) fn check_shape<'a, I: Iterator<Item=&'a Column>>(mut columns: I) -> Result<(), VMError> {
+    let rows = match columns.next() {
+        Some(first) => first.len(),
+        None => return Ok(())
+    };
+    match columns.find(|c| c.len() != rows) {
+        Some(c) => Err(VMError::LengthMismatch { expected: rows, found: c.len() }),
+        None => Ok(())
+    }
+}
+
+// The schema of columns named by position, as Table::from_columns names them
+pub(crate) fn default_schema<'a, I: Iterator<Item=&'a Column>>(columns: I) -> Schema {
+    Schema::new(columns.enumerate().map(|(i, col)| field_of(&format!("c{}", i), col)).collect())
+}
+
+// A field for `col`, with its categories or size if it has them
+fn field_of(name: &str, col: &Column) -> Field {
+    match col {
+        Column::Categorical(c) => Field { categories: Some(c.categories().clone()), ..Field::new(name, col.datatype()) },
+        Column::Vector(c) => Field::vector(name, c.dim()),
+        col => Field::new(name, col.datatype())
+    }
+}
+
+impl TryFrom<ResultSet> for Table {
+    type Error = VMError;
+
+    fn try_from(res: ResultSet) -> Result<Table, VMError> {
+        Table::new(res.names.into_iter().zip(res.columns).collect())
+    }
+}
+
+impl From<Table> for ResultSet {
+    fn from(table: Table) -> ResultSet {
+        ResultSet { names: table.schema.fields.into_iter().map(|f| f.name).collect(), columns: table.columns }
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.fmt_table(&TableFormat::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Datatype;
+
+    fn people() -> Table {
+        Table::new(vec![
+            ("name".to_string(), Column::from(vec!["ann", "bo"])),
+            ("age".to_string(), Column::from(vec![31.0, 27.0]))
+        ]).unwrap()
+    }
+
+    #[test]
+    fn columns_are_found_by_name() {
+        let t = people();
+        assert_eq!((t.len(), t.rows()), (2, 2));
+        assert_eq!(t.column("age"), Some(&Column::from(vec![31.0, 27.0])));
+        assert_eq!(t.column("height"), None);
+        assert_eq!(t.schema().field(0).map(|f| f.dtype), Some(Datatype::Str));
+        assert_eq!(t.names().collect::<Vec<_>>(), vec!["name", "age"]);
+        assert_eq!(Table::from_columns(vec![Column::from(vec![1.0])]).unwrap().names().collect::<Vec<_>>(), vec!["c0"]);
+        assert!(t.to_string().ends_with("2 rows"));
+    }
+
+    #[test]
+    fn malformed_tables_are_errors() {
+        let ragged = Table::from_columns(vec![Column::from(vec![1.0, 2.0]), Column::from(vec![1.0])]);
+        assert!(matches!(ragged, Err(VMError::LengthMismatch { expected: 2, found: 1 })));
+        let twice = Table::new(vec![("x".to_string(), Column::from(vec![1.0])), ("x".to_string(), Column::from(vec![2.0]))]);
+        assert!(matches!(twice, Err(VMError::TypeError(_))));
+        let schema = Schema::from(vec![("x", Datatype::Int)]);
+        assert!(matches!(Table::with_schema(schema.clone(), vec![Column::from(vec![1.0])]), Err(VMError::TypeError(_))));
+        assert!(matches!(Table::with_schema(schema, vec![]), Err(VMError::LengthMismatch { expected: 1, found: 0 })));
+    }
+}
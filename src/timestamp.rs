@@ -0,0 +1,315 @@
+// Timestamps: instants in time, counted in microseconds since the Unix epoch (Arrow's
+// Timestamp(us, "UTC")), so they cover about ±292,000 years. No time zone is stored - every
+// timestamp is in UTC, and an offset given in text is applied as it's parsed.
+//
+// A TimestampColumn is a PrimitiveColumn, so it gets the generic equality and range filters
+// (FilterEq, FilterLt and the rest, given a timestamp to compare with); this module adds the
+// value type and its text form, RFC 3339 for years 0000 to 9999: "2024-03-01T12:30:00Z",
+// "2024-03-01 12:30:00.25+01:00", or a bare date, "2024-03-01", for its midnight.
+
+use crate::bitindex::BitIndex;
+use crate::column::{Column, Scalar};
+use crate::core::prelude::*;
+use crate::kernels;
+use crate::primitive::{Native, PrimitiveColumn};
+use crate::schema::Datatype;
+
+use core::cmp::Ordering;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Micros(pub i64);
+
+pub type TimestampColumn = PrimitiveColumn<Micros>;
+
+pub const MICROS_PER_MILLI: i64 = 1_000;
+pub const MICROS_PER_SEC: i64 = 1_000_000;
+pub const MICROS_PER_MIN: i64 = 60 * MICROS_PER_SEC;
+pub const MICROS_PER_HOUR: i64 = 60 * MICROS_PER_MIN;
+pub const MICROS_PER_DAY: i64 = 24 * MICROS_PER_HOUR;
+
+impl Micros {
+    pub fn from_secs(secs: i64) -> Option<Micros> {
+        secs.checked_mul(MICROS_PER_SEC).map(Micros)
+    }
+
+    pub fn from_millis(millis: i64) -> Option<Micros> {
+        millis.checked_mul(MICROS_PER_MILLI).map(Micros)
+    }
+
+    // Midnight at the start of a date, in the proleptic Gregorian calendar. None if there's
+    // no such date.
+    pub fn from_date(year: i64, month: u32, day: u32) -> Option<Micros> {
+        if !(1 ..= 12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+            return None;
+        }
+        days_from_civil(year, month, day).checked_mul(MICROS_PER_DAY).map(Micros)
+    }
+
+    // The (year, month, day) this instant falls on
+    pub fn date(self) -> (i64, u32, u32) {
+        civil_from_days(self.0.div_euclid(MICROS_PER_DAY))
+    }
+
+    // From text: RFC 3339 (see above), with 'T' or a space between date and time, and 'Z', an
+    // offset or nothing (UTC) after it; digits past the microsecond are dropped. A bare integer
+    // is a count of microseconds. None if it's malformed or out of range.
+    pub fn parse(s: &str) -> Option<Micros> {
+        let s = s.trim();
+        let digits = s.strip_prefix('-').unwrap_or(s);
+        if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+            return s.parse().ok().map(Micros);
+        }
+        let (date, time) = match s.split_once(['T', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None)
+        };
+        let mut parts = date.split('-');
+        let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        let midnight = Micros::from_date(number(year, 4)?, number(month, 2)? as u32, number(day, 2)? as u32)?;
+        let time = match time {
+            Some(time) => time,
+            None => return Some(midnight)
+        };
+        let (time, offset) = offset(time)?;
+        let (hms, frac) = match time.split_once('.') {
+            Some((hms, frac)) if !frac.is_empty() && frac.bytes().all(|b| b.is_ascii_digit()) => (hms, frac),
+            Some(_) => return None,
+            None => (time, "")
+        };
+        let mut parts = hms.split(':');
+        let (h, m, sec) = (number(parts.next()?, 2)?, number(parts.next()?, 2)?, number(parts.next()?, 2)?);
+        if parts.next().is_some() || h > 23 || m > 59 || sec > 59 {
+            return None;
+        }
+        let frac: i64 = format!("{:0<6}", &frac[.. frac.len().min(6)]).parse().ok()?;
+        let since_midnight = h * MICROS_PER_HOUR + m * MICROS_PER_MIN + sec * MICROS_PER_SEC + frac;
+        midnight.0.checked_add(since_midnight)?.checked_sub(offset).map(Micros)
+    }
+}
+
+// Exactly `width` digits, as a number
+fn number(s: &str, width: usize) -> Option<i64> {
+    match s.len() == width && s.bytes().all(|b| b.is_ascii_digit()) {
+        true => s.parse().ok(),
+        false => None
+    }
+}
+
+// The time of day, and the offset from UTC after it, in microseconds: 'Z', "+hh:mm" or
+// "-hh:mm", or nothing for UTC
+fn offset(time: &str) -> Option<(&str, i64)> {
+    if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        return Some((time, 0));
+    }
+    let split = time.len().checked_sub(6).filter(|i| time.is_char_boundary(*i));
+    let sign = match split.map(|i| time.as_bytes()[i]) {
+        Some(b'+') => 1,
+        Some(b'-') => -1,
+        _ => return Some((time, 0))
+    };
+    let (time, zone) = time.split_at(split?);
+    let (h, m) = zone[1 ..].split_once(':')?;
+    let (h, m) = (number(h, 2)?, number(m, 2)?);
+    if h > 23 || m > 59 {
+        return None;
+    }
+    Some((time, sign * (h * MICROS_PER_HOUR + m * MICROS_PER_MIN)))
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+// Days since 1970-01-01 of a date, and back, counting in 400-year eras of 146,097 days with
+// years starting in March, so the leap day comes last (Howard Hinnant's algorithms)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+impl TimestampColumn {
+    // From counts of microseconds since the epoch; Column::from(Vec<i64>) makes an Int column
+    pub fn from_micros(micros: Vec<i64>) -> TimestampColumn {
+        PrimitiveColumn::new(micros.into_iter().map(Micros).collect::<Vec<_>>().into())
+    }
+}
+
+impl fmt::Display for Micros {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = self.date();
+        let time = self.0.rem_euclid(MICROS_PER_DAY);
+        let (secs, frac) = (time / MICROS_PER_SEC, time % MICROS_PER_SEC);
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)?;
+        if frac != 0 {
+            write!(f, ".{}", format!("{:06}", frac).trim_end_matches('0'))?;
+        }
+        write!(f, "Z")
+    }
+}
+
+impl Native for Micros {
+    const DATATYPE: Datatype = Datatype::Timestamp;
+    const DESCRIPTION: &'static str = "a timestamp";
+
+    fn from_scalar(s: &Scalar) -> Option<Micros> {
+        if let Scalar::Timestamp(x) = s { Some(*x) } else { None }
+    }
+
+    fn into_scalar(self) -> Scalar {
+        Scalar::Timestamp(self)
+    }
+
+    fn wrap(col: PrimitiveColumn<Micros>) -> Column {
+        Column::Timestamp(col)
+    }
+
+    fn unwrap(col: &Column) -> Option<&PrimitiveColumn<Micros>> {
+        if let Column::Timestamp(c) = col { Some(c) } else { None }
+    }
+
+    fn eq_mask(data: &[Micros], val: Micros) -> BitIndex {
+        kernels::mask_by(data, |x| x == val)
+    }
+
+    fn same(a: Micros, b: Micros) -> bool {
+        a == b
+    }
+
+    fn total_cmp(a: Micros, b: Micros) -> Ordering {
+        a.cmp(&b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::ColumnT;
+    use crate::nulls::NullSemantics;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    use core::convert::TryFrom;
+
+    #[test]
+    fn parses_dates_and_times() {
+        let cases = [
+            ("1970-01-01", 0),
+            ("1970-01-01T00:00:01Z", MICROS_PER_SEC),
+            ("2024-03-01T12:30:00Z", 1_709_296_200 * MICROS_PER_SEC),
+            ("2024-03-01 12:30:00", 1_709_296_200 * MICROS_PER_SEC),
+            ("2024-03-01T13:30:00+01:00", 1_709_296_200 * MICROS_PER_SEC),
+            ("2024-03-01T12:30:00.25z", 1_709_296_200 * MICROS_PER_SEC + 250_000),
+            ("2024-03-01T12:30:00.0000019Z", 1_709_296_200 * MICROS_PER_SEC + 1),   // past the microsecond is dropped
+            ("1969-12-31T23:59:59.999999Z", -1),
+            ("2000-02-29", 11_016 * MICROS_PER_DAY),
+            ("42", 42),
+            ("-42", -42)
+        ];
+        for (text, micros) in cases.iter() {
+            assert_eq!(Micros::parse(text), Some(Micros(*micros)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_text_and_impossible_dates() {
+        let cases = ["", "-", "2024-03", "24-03-01", "2024-3-1", "2024-13-01", "2023-02-29", "1900-02-29",
+            "2024-03-01T", "2024-03-01T24:00:00Z", "2024-03-01T12:30Z", "2024-03-01T12:30:00.Z", "2024-03-01T12:30:00+1:00",
+            "2024-03-01T12:30:00+01:60", "2024-03-01T12:30:00 Z", "2024-03-01-01"];
+        for text in cases.iter() {
+            assert_eq!(Micros::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn prints_what_parses_back() {
+        let cases = [
+            (0, "1970-01-01T00:00:00Z"),
+            (1_709_296_200 * MICROS_PER_SEC + 250_000, "2024-03-01T12:30:00.25Z"),
+            (-1, "1969-12-31T23:59:59.999999Z"),
+            (11_016 * MICROS_PER_DAY, "2000-02-29T00:00:00Z")
+        ];
+        for (micros, text) in cases.iter() {
+            assert_eq!(Micros(*micros).to_string(), *text);
+        }
+        let last = Micros::from_date(9999, 12, 31).unwrap().0 + MICROS_PER_DAY - 1;
+        for micros in [Micros::from_date(0, 1, 1).unwrap().0, last, 123_456_789_012_345, -7].iter() {
+            assert_eq!(Micros::parse(&Micros(*micros).to_string()), Some(Micros(*micros)), "{}", micros);
+        }
+    }
+
+    #[test]
+    fn dates_round_trip_through_days() {
+        for days in (-800_000 .. 800_000).step_by(97) {
+            let (y, m, d) = civil_from_days(days);
+            assert!(d >= 1 && d <= days_in_month(y, m), "{}", days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        assert_eq!(Micros::from_secs(1_709_296_200).unwrap().date(), (2024, 3, 1));
+        assert_eq!(Micros(-1).date(), (1969, 12, 31));
+    }
+
+    #[test]
+    fn columns_filter_by_value_and_range() {
+        let day = |d| Micros::from_date(2024, 3, d).unwrap();
+        let col = Column::from(vec![day(1), day(2), day(3), day(4)]);
+        assert_eq!(col.datatype(), Datatype::Timestamp);
+        let mask = col.filter_range(Scalar::Timestamp(day(2)), Scalar::Timestamp(day(4))).unwrap();
+        assert_eq!(mask.count_ones(), 2);
+        let mask = col.filter_cmp(crate::column::Cmp::Lt, Scalar::Timestamp(day(3))).unwrap();
+        assert_eq!(mask.count_ones(), 2);
+        assert_eq!(col.filter(Scalar::Timestamp(day(3))).unwrap().count_ones(), 1);
+        assert!(col.filter(Scalar::Int(day(3).0)).is_err());
+        let found = col.filter_in(&Column::from(vec![day(4), day(1), day(9)]), NullSemantics::default()).unwrap();
+        assert_eq!(found.count_ones(), 2);
+        assert_eq!(col.get(0), Some(Scalar::Timestamp(day(1))));
+        assert_eq!(TimestampColumn::from_micros(vec![day(1).0]).values(), &[day(1)]);
+    }
+
+    #[test]
+    fn the_vm_filters_a_time_window() {
+        let at = |s: &str| Scalar::Timestamp(Micros::parse(s).unwrap());
+        let times: Vec<Micros> = ["2024-02-29T23:59:59Z", "2024-03-01T00:00:00Z", "2024-03-01T18:00:00+02:00", "2024-03-02T00:00:00Z"]
+            .iter().map(|s| Micros::parse(s).unwrap()).collect();
+        let mut vm = VM::new(Table::from_columns(vec![Column::from(times), Column::from(vec![10u64, 11, 12, 13])]).unwrap());
+        let window = vec![
+            Op::Col(0, 0), Op::Lit(at("2024-03-01")), Op::FilterGe,
+            Op::Col(0, 0), Op::Lit(at("2024-03-02")), Op::FilterLt,
+            Op::And, Op::Col(0, 1), Op::Select(1)
+        ];
+        vm.run(window).unwrap();
+        assert_eq!(Vec::<u64>::try_from(vm.column_of(vm.stack().last().unwrap()).unwrap()).unwrap(), vec![11, 12]);
+    }
+}
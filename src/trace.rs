@@ -0,0 +1,245 @@
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::vm::{Value, VM};
+
+// Deterministic execution traces: one entry per executed instruction, holding fingerprints
+// of the values it consumed and produced. Record a trace from a run that behaves oddly, then
+// replay the same program against the same data and diff, to find the first instruction
+// whose output differs between runs.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub ip: usize,
+    pub op: String,
+    pub operands: Vec<u64>,     // fingerprints of popped values, bottom first
+    pub results: Vec<u64>,      // fingerprints of pushed values, bottom first
+    pub rows: Option<usize>     // row count of the pushed column, if any
+}
+
+#[derive(Debug, Default)]
+pub struct TraceRecorder {
+    entries: Vec<TraceEntry>
+}
+
+impl TraceRecorder {
+    pub fn new() -> Self {
+        TraceRecorder { entries: Vec::new() }
+    }
+
+    pub fn record(&mut self, ip: usize, op: &Op, operands: Vec<u64>, results: Vec<u64>, rows: Option<usize>) {
+        self.entries.push(TraceEntry { ip, op: format!("{:?}", op), operands, results, rows });
+    }
+
+    pub fn entries(&self) -> &[TraceEntry] {
+        &self.entries
+    }
+
+    pub fn into_entries(self) -> Vec<TraceEntry> {
+        self.entries
+    }
+
+    #[cfg(feature = "std")]
+    // One line per entry: ip, op, operand hashes, result hashes, rows - tab separated.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for e in &self.entries {
+            let rows = match e.rows {
+                Some(n) => n.to_string(),
+                None => "-".to_string()
+            };
+            writeln!(w, "{}\t{}\t{}\t{}\t{}", e.ip, e.op, hashes_to_str(&e.operands), hashes_to_str(&e.results), rows)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+}
+
+#[cfg(feature = "std")]
+fn hashes_to_str(hs: &[u64]) -> String {
+    let hs: Vec<String> = hs.iter().map(|h| format!("{:016x}", h)).collect();
+    hs.join(",")
+}
+
+#[cfg(feature = "std")]
+fn invalid(line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("trace line {}: {}", line + 1, msg))
+}
+
+#[cfg(feature = "std")]
+fn parse_hashes(s: &str, line: usize) -> io::Result<Vec<u64>> {
+    if s.is_empty() { return Ok(Vec::new()); }
+    s.split(',')
+        .map(|h| u64::from_str_radix(h, 16).map_err(|_| invalid(line, "bad hash")))
+        .collect()
+}
+
+#[cfg(feature = "std")]
+pub fn read_from<R: BufRead>(r: R) -> io::Result<Vec<TraceEntry>> {
+    let mut entries = Vec::new();
+    for (n, line) in r.lines().enumerate() {
+        let line = line?;
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() != 5 {
+            return Err(invalid(n, "expected 5 tab-separated fields"));
+        }
+        let ip = parts[0].parse().map_err(|_| invalid(n, "bad ip"))?;
+        let rows = match parts[4] {
+            "-" => None,
+            s => Some(s.parse().map_err(|_| invalid(n, "bad row count"))?)
+        };
+        entries.push(TraceEntry {
+            ip,
+            op: parts[1].to_string(),
+            operands: parse_hashes(parts[2], n)?,
+            results: parse_hashes(parts[3], n)?,
+            rows
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(feature = "std")]
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<TraceEntry>> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub index: usize,                   // position in the trace
+    pub expected: Option<TraceEntry>,   // None: the replay ran further than the recording
+    pub actual: Option<TraceEntry>      // None: the replay stopped early (e.g. on an error)
+}
+
+#[derive(Debug)]
+pub struct Replay {
+    pub divergences: Vec<Divergence>,
+    pub result: Result<Vec<Value>, VMError>   // as VM::run returned it
+}
+
+// Re-run `code` on `vm` (which must hold the same columns as the recorded run)
+// and report every entry that doesn't match the recorded trace.
+pub fn replay(mut vm: VM, code: Vec<Op>, expected: &[TraceEntry]) -> Replay {
+    vm.record_trace();
+    let result = vm.run(code);
+    let actual = vm.take_trace().map(|t| t.into_entries()).unwrap_or_default();
+
+    let mut divergences = Vec::new();
+    for i in 0 .. expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e != a {
+            divergences.push(Divergence { index: i, expected: e.cloned(), actual: a.cloned() });
+        }
+    }
+    Replay { divergences, result }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::Table;
+    use crate::column::{Column, InlineStrColumn, Scalar};
+
+    fn vm(sexes: Vec<&str>) -> VM {
+        VM::new(Table::from_columns(vec![Column::from(sexes), Column::from(vec![30.0, 41.0, 25.0])]).unwrap())
+    }
+
+    // ages of the women
+    fn program() -> Vec<Op> {
+        vec![Op::Col(0, 0), Op::Lit(Scalar::Str("f".to_string())), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]
+    }
+
+    fn recorded(sexes: Vec<&str>) -> Vec<TraceEntry> {
+        let mut vm = vm(sexes);
+        vm.record_trace();
+        vm.run(program()).unwrap();
+        vm.take_trace().unwrap().into_entries()
+    }
+
+    #[test]
+    fn records_every_instruction() {
+        let trace = recorded(vec!["f", "m", "f"]);
+        let ops: Vec<&str> = trace.iter().map(|e| e.op.as_str()).collect();
+        assert_eq!(ops, vec!["Col(0, 0)", "Lit(Str(\"f\"))", "FilterEq", "Col(0, 1)", "Select(1)"]);
+        assert_eq!(trace.iter().map(|e| e.ip).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(trace.iter().map(|e| (e.operands.len(), e.results.len())).collect::<Vec<_>>(), vec![(0, 1), (0, 1), (2, 1), (0, 1), (2, 1)]);
+        assert_eq!(trace.iter().map(|e| e.rows).collect::<Vec<_>>(), vec![Some(3), None, Some(3), Some(3), Some(2)]);
+        // FilterEq consumed what Col and Lit pushed
+        assert_eq!(trace[2].operands, vec![trace[0].results[0], trace[1].results[0]]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn round_trips_through_text() {
+        let mut vm = vm(vec!["f", "m", "f"]);
+        vm.record_trace();
+        vm.run(program()).unwrap();
+        let recorder = vm.take_trace().unwrap();
+        let mut text = Vec::new();
+        recorder.write_to(&mut text).unwrap();
+        assert_eq!(read_from(&text[..]).unwrap(), recorder.entries());
+
+        let path = std::env::temp_dir().join(format!("collie-trace-{}.tsv", std::process::id()));
+        recorder.save(&path).unwrap();
+        assert_eq!(load(&path).unwrap(), recorder.entries());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn rejects_malformed_lines() {
+        for bad in &["0\tCol(0)\t\t", "x\tCol(0)\t\t\t-", "0\tCol(0)\tzz\t\t-", "0\tCol(0)\t\t\tmany"] {
+            let err = read_from(bad.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", bad);
+            assert!(err.to_string().starts_with("trace line 1:"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn replaying_the_same_data_matches() {
+        let replayed = replay(vm(vec!["f", "m", "f"]), program(), &recorded(vec!["f", "m", "f"]));
+        assert!(replayed.result.is_ok());
+        assert_eq!(replayed.divergences, vec![]);
+    }
+
+    #[test]
+    fn replaying_other_data_finds_the_first_difference() {
+        let replayed = replay(vm(vec!["f", "m", "m"]), program(), &recorded(vec!["f", "m", "f"]));
+        let first = &replayed.divergences[0];
+        assert_eq!(first.index, 0);
+        assert_eq!(first.expected.as_ref().unwrap().op, "Col(0, 0)");
+        // the literal is the same either way
+        assert!(replayed.divergences.iter().all(|d| d.index != 1));
+    }
+
+    #[test]
+    fn replaying_a_failing_run_stops_short() {
+        let mut code = program();
+        code.insert(1, Op::Lit(Scalar::Num(0.0)));
+        code.insert(2, Op::Select(1));
+        let replayed = replay(vm(vec!["f", "m", "f"]), code, &recorded(vec!["f", "m", "f"]));
+        assert!(replayed.result.is_err());
+        assert!(replayed.divergences.iter().any(|d| d.actual.is_none()));
+    }
+
+    #[test]
+    fn string_layouts_fingerprint_alike() {
+        let strs = Column::from(vec!["a", "bc", ""]);
+        let inline = Column::InlineStr(InlineStrColumn::from_strs(vec!["a", "bc", ""]));
+        assert_eq!(strs.fingerprint(), inline.fingerprint());
+        assert_ne!(strs.fingerprint(), Column::from(vec!["a", "b", "c"]).fingerprint());
+        assert_ne!(Scalar::Num(1.0).fingerprint(), Scalar::Entity(1).fingerprint());
+    }
+}
@@ -0,0 +1,81 @@
+// Watching a VM run, one instruction at a time (see VM::set_tracer). A VM runs silently
+// unless it has a tracer: PrintTracer prints each instruction and the stack it finds, for
+// debugging by eye, and tests can capture structured events with a Tracer of their own.
+
+use crate::opcode::Op;
+use crate::vm::Value;
+
+// Callbacks from a running VM. Each does nothing unless implemented.
+pub trait Tracer {
+    // Before the instruction at `ip` runs, with the stack as it finds it
+    fn on_op(&mut self, _ip: usize, _op: &Op, _stack: &[Value]) {}
+
+    // Each value the instruction takes off the stack, bottom first
+    fn on_pop(&mut self, _value: &Value) {}
+
+    // Each value it leaves on the stack, bottom first, once it's done
+    fn on_push(&mut self, _value: &Value) {}
+}
+
+// Print the stack and each op as it executes
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct PrintTracer;
+
+#[cfg(feature = "std")]
+impl Tracer for PrintTracer {
+    fn on_op(&mut self, _ip: usize, op: &Op, stack: &[Value]) {
+        println!("Stack: {:?}", stack);
+        println!("Op: {:?}", op);
+    }
+}
+
+// Captured through a Mutex, which needs std
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::column::{Column, Scalar};
+    use crate::table::Table;
+    use crate::vm::VM;
+
+    use std::sync::{Arc, Mutex};
+
+    // Each callback as a line, e.g. "op 2 FILTER_EQ", "pop column", "push scalar"
+    struct Events(Arc<Mutex<Vec<String>>>);
+
+    fn kind(v: &Value) -> &'static str {
+        match v {
+            Value::Scalar(_) => "scalar",
+            _ => "column"
+        }
+    }
+
+    impl Tracer for Events {
+        fn on_op(&mut self, ip: usize, op: &Op, _stack: &[Value]) {
+            self.0.lock().unwrap().push(format!("op {} {}", ip, op.mnemonic()));
+        }
+
+        fn on_pop(&mut self, value: &Value) {
+            self.0.lock().unwrap().push(format!("pop {}", kind(value)));
+        }
+
+        fn on_push(&mut self, value: &Value) {
+            self.0.lock().unwrap().push(format!("push {}", kind(value)));
+        }
+    }
+
+    #[test]
+    fn tracers_see_every_pop_and_push() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = VM::new(Table::from_columns(vec![Column::from(vec![1.0, 2.0, 2.0])]).unwrap());
+        vm.set_tracer(Some(Box::new(Events(events.clone()))));
+        vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Num(2.0)), Op::FilterEq, Op::Col(0, 0), Op::Select(1), Op::Sum]).unwrap();
+        assert_eq!(events.lock().unwrap().join(", "), "\
+op 0 COL, push column, op 1 LIT, push scalar, op 2 FILTER_EQ, pop column, pop scalar, push column, \
+op 3 COL, push column, op 4 SELECT, pop column, pop column, push column, op 5 SUM, pop column, push scalar");
+        // set_verbose(false) takes the tracer away again
+        vm.set_verbose(false);
+        vm.run(vec![Op::Col(0, 0)]).unwrap();
+        assert_eq!(events.lock().unwrap().len(), 17);
+    }
+}
@@ -0,0 +1,445 @@
+// User-defined functions: Rust closures registered on a VM under a name, and called by
+// Op::CallUdf(id, arity) with the id registration gave back. A function takes the values of
+// one row and returns one value, and is applied to each row in turn; a batch function takes
+// whole columns and returns a column. An aggregate function is an Accumulator, fed every row
+// and called by Op::CallUdaf(id, arity) for a single value. Either way the arguments are the
+// top `arity` stack values, first argument deepest.
+//
+// Functions should work on each row independently of the others - a Cursor runs them a
+// window of rows at a time.
+
+use crate::column::{Column, Scalar};
+use crate::conditional::Branch;
+use crate::core::prelude::*;
+use crate::encoding;
+use crate::errors::VMError;
+use crate::json::{self, JsonColumn};
+use crate::list::ListColumn;
+use crate::nulls::NullableColumn;
+use crate::selection::Selection;
+use crate::vector::VectorColumn;
+
+use alloc::borrow::Cow;
+use core::fmt;
+use alloc::sync::Arc;
+
+// Send + Sync, so a VM can be moved to another thread with its functions
+pub type ScalarUdf = Arc<dyn Fn(&[Scalar]) -> Scalar + Send + Sync>;
+pub type BatchUdf = Arc<dyn Fn(&[&Column]) -> Result<Column, VMError> + Send + Sync>;
+pub type AggregateUdf = Arc<dyn Fn() -> Box<dyn Accumulator> + Send + Sync>;
+
+// Rows an aggregate accumulates before its state is merged into the total
+const PARTIAL_ROWS: usize = 64 * 1024;
+
+// The running state of a user-defined aggregate. One accumulator is made per partition of
+// the rows (or per group), fed that partition's rows, and the partial states merged.
+pub trait Accumulator {
+    // Add a row's arguments
+    fn update(&mut self, args: &[Scalar]);
+    // The state so far, as values merge() can take in
+    fn state(&self) -> Vec<Scalar>;
+    // Fold in the state() of an accumulator that saw other rows
+    fn merge(&mut self, state: &[Scalar]);
+    fn finish(&self) -> Scalar;
+}
+
+#[derive(Clone)]
+pub enum Udf {
+    Scalar(ScalarUdf),
+    Batch(BatchUdf),
+    Aggregate(AggregateUdf)   // makes a fresh accumulator
+}
+
+impl fmt::Debug for Udf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Udf::Scalar(_) => write!(f, "Udf::Scalar(..)"),
+            Udf::Batch(_) => write!(f, "Udf::Batch(..)"),
+            Udf::Aggregate(_) => write!(f, "Udf::Aggregate(..)")
+        }
+    }
+}
+
+pub(crate) enum Output {
+    Scalar(Scalar),
+    Column(Column)
+}
+
+// Rows in the column arguments, which must all have the same number; None if all the
+// arguments are scalars
+fn rows_of(args: &[Branch]) -> Result<Option<usize>, VMError> {
+    let mut rows = None;
+    for arg in args {
+        if let Branch::Column(c) = arg {
+            match rows {
+                Some(n) if n != c.len() => return Err(VMError::LengthMismatch { expected: n, found: c.len() }),
+                _ => rows = Some(c.len())
+            }
+        }
+    }
+    Ok(rows)
+}
+
+// Call `f` with the arguments of each of `rows` rows in turn, scalars repeated for every row
+fn for_each_row<F: FnMut(usize, &[Scalar])>(args: &[Branch], rows: usize, mut f: F) {
+    // decode encoded columns once, rather than on every get()
+    let plain: Vec<Option<Cow<Column>>> = args.iter()
+        .map(|a| if let Branch::Column(c) = a { Some(encoding::plain(c)) } else { None })
+        .collect();
+    let mut row = Vec::with_capacity(args.len());
+    for i in 0 .. rows {
+        row.clear();
+        for (arg, col) in args.iter().zip(plain.iter()) {
+            row.push(match (arg, col) {
+                (_, Some(c)) => c.get(i).expect("columns were checked to have this many rows"),
+                (Branch::Scalar(s), None) => (*s).clone(),
+                (Branch::Column(_), None) => unreachable!()
+            });
+        }
+        f(i, &row);
+    }
+}
+
+// Call `udf` on `args`. With scalar arguments only, a scalar function is called once and
+// gives a scalar; otherwise scalars are repeated for every row, and column arguments must all
+// have the same number of rows.
+pub(crate) fn apply(udf: &Udf, name: &str, args: &[Branch]) -> Result<Output, VMError> {
+    let rows = rows_of(args)?;
+    match (udf, rows) {
+        (Udf::Scalar(f), None) => {
+            let values: Vec<Scalar> = args.iter().filter_map(|a| if let Branch::Scalar(s) = a { Some((*s).clone()) } else { None }).collect();
+            Ok(Output::Scalar(f(&values)))
+        },
+        (Udf::Scalar(f), Some(rows)) => {
+            let mut values = Vec::with_capacity(rows);
+            for_each_row(args, rows, |_, row| values.push(f(row)));
+            Ok(Output::Column(column_of(values, name)?))
+        },
+        (Udf::Batch(f), _) => {
+            let columns = args.iter().map(|a| match a {
+                Branch::Column(c) => Ok(*c),
+                Branch::Scalar(s) => Err(VMError::TypeError(format!("Batch function {} takes columns, got: {:?}", name, s)))
+            }).collect::<Result<Vec<&Column>, _>>()?;
+            let res = f(&columns)?;
+            match rows {
+                Some(n) if res.len() != n => Err(VMError::LengthMismatch { expected: n, found: res.len() }),
+                _ => Ok(Output::Column(res))
+            }
+        },
+        (Udf::Aggregate(_), _) => Err(VMError::TypeError(format!("{} is an aggregate function; call it with CallUdaf", name)))
+    }
+}
+
+// Run the aggregate `udf` over all the rows of `args` (a single row, if they're all scalars).
+// Rows are accumulated PARTIAL_ROWS at a time and the partial states merged, as parallel
+// execution would, so every aggregate is run through merge whatever the data size.
+pub(crate) fn aggregate(udf: &Udf, name: &str, args: &[Branch]) -> Result<Scalar, VMError> {
+    let new = match udf {
+        Udf::Aggregate(new) => new,
+        _ => return Err(VMError::TypeError(format!("{} isn't an aggregate function; call it with CallUdf", name)))
+    };
+    let rows = rows_of(args)?.unwrap_or(1);
+    let mut total = new();
+    let mut part = new();
+    for_each_row(args, rows, |i, row| {
+        part.update(row);
+        if (i + 1) % PARTIAL_ROWS == 0 {
+            total.merge(&part.state());
+            part = new();
+        }
+    });
+    if rows % PARTIAL_ROWS != 0 {
+        total.merge(&part.state());
+    }
+    Ok(total.finish())
+}
+
+// The aggregate `udf` per group: row i of `args` goes to group groups[i], which must be less
+// than `ngroups`. Groups with no rows get the result of an accumulator that saw nothing.
+pub fn aggregate_groups(udf: &Udf, args: &[Branch], groups: &[usize], ngroups: usize) -> Result<Vec<Scalar>, VMError> {
+    let new = match udf {
+        Udf::Aggregate(new) => new,
+        _ => return Err(VMError::TypeError("Expected an aggregate function".to_string()))
+    };
+    let rows = rows_of(args)?.unwrap_or(1);
+    if groups.len() != rows {
+        return Err(VMError::LengthMismatch { expected: rows, found: groups.len() });
+    }
+    let mut accs: Vec<Box<dyn Accumulator>> = (0 .. ngroups).map(|_| new()).collect();
+    for_each_row(args, rows, |i, row| accs[groups[i]].update(row));
+    Ok(accs.iter().map(|a| a.finish()).collect())
+}
+
+// The values a scalar function returned, as a column. They must all be of one type, or null.
+// With no rows, or only nulls, there's no value to take the type from, and the result is a
+// Num column.
+fn column_of(values: Vec<Scalar>, name: &str) -> Result<Column, VMError> {
+    let nulls: Vec<u32> = (0 .. values.len()).filter(|i| matches!(values[*i], Scalar::Null)).map(|i| i as u32).collect();
+    if !nulls.is_empty() {
+        // a value of the others' type stands in for each null, until they're made null
+        let rows = values.len();
+        let filler = values.iter().find(|v| !matches!(v, Scalar::Null)).cloned().unwrap_or(Scalar::Num(f64::NAN));
+        let values = values.into_iter().map(|v| if matches!(v, Scalar::Null) { filler.clone() } else { v }).collect();
+        let col = column_of(values, name)?;
+        return Ok(Column::Nullable(NullableColumn::with_nulls(col, &Selection::from_positions(nulls, rows))?));
+    }
+    let mismatch = |v: &Scalar| VMError::TypeError(format!("Function {} returned values of different types, including: {:?}", name, v));
+    macro_rules! collect {
+        ($variant:path) => {
+            values.iter().map(|v| if let $variant(x) = v { Ok(x.clone()) } else { Err(mismatch(v)) }).collect::<Result<Vec<_>, _>>()?
+        };
+    }
+    Ok(match values.first() {
+        None => Column::from(Vec::<f64>::new()),
+        Some(Scalar::Bool(_)) => Column::from(collect!(Scalar::Bool)),
+        Some(Scalar::Num(_)) => Column::from(collect!(Scalar::Num)),
+        Some(Scalar::Int(_)) => Column::from(collect!(Scalar::Int)),
+        Some(Scalar::Str(_)) => Column::from(collect!(Scalar::Str)),
+        Some(Scalar::Entity(_)) => Column::from(collect!(Scalar::Entity)),
+        Some(Scalar::Duration(_)) => Column::from(collect!(Scalar::Duration)),
+        Some(Scalar::Timestamp(_)) => Column::from(collect!(Scalar::Timestamp)),
+        Some(Scalar::Point(_)) => Column::from(collect!(Scalar::Point)),
+        Some(Scalar::Ipv4(_)) => Column::from(collect!(Scalar::Ipv4)),
+        Some(Scalar::Ipv6(_)) => Column::from(collect!(Scalar::Ipv6)),
+        Some(Scalar::Json(_)) => {
+            let docs = collect!(Scalar::Json);
+            for doc in &docs {
+                json::validate(doc).map_err(|msg| VMError::TypeError(format!("Function {} returned invalid JSON ({}): {}", name, msg, doc)))?;
+            }
+            Column::Json(JsonColumn { docs: docs.iter().map(|d| d.as_str()).collect() })
+        },
+        Some(Scalar::Vector(x)) => {
+            let rows = collect!(Scalar::Vector);
+            Column::Vector(VectorColumn::from_rows(x.len(), &rows)?)
+        },
+        Some(Scalar::List(_)) => {
+            let lists = collect!(Scalar::List);
+            let mut offsets = vec![0];
+            lists.iter().for_each(|l| offsets.push(offsets[offsets.len() - 1] + l.len()));
+            let items = column_of(lists.into_iter().flatten().collect(), name)?;
+            Column::List(ListColumn::new(offsets, items)?)
+        },
+        Some(Scalar::Null) => unreachable!("nulls are handled above"),
+        Some(v @ Scalar::Record(_)) => return Err(VMError::TypeError(format!("Function {} returned a record, which can't go in a column: {:?}", name, v)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoding;
+    use crate::opcode::Op;
+    use crate::vm::{Value, VM};
+    use crate::table::Table;
+
+    fn vm() -> VM {
+        VM::new(Table::from_columns(vec![
+            Column::from(vec![1.0, 2.0, 3.0]),
+            encoding::encode(Column::from(vec!["a", "b", "a"]), Encoding::Dict).unwrap(),
+        ]).unwrap())
+    }
+
+    fn result(vm: &VM) -> Column {
+        vm.column_of(vm.stack().last().unwrap()).unwrap().clone()
+    }
+
+    fn add(args: &[Scalar]) -> Scalar {
+        match args {
+            [Scalar::Num(x), Scalar::Num(y)] => Scalar::Num(x + y),
+            _ => Scalar::Bool(false)
+        }
+    }
+
+    #[test]
+    fn row_functions_run_per_row() {
+        let mut vm = vm();
+        let id = vm.register_udf("add", add);
+        vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Num(10.0)), Op::CallUdf(id, 2)]).unwrap();
+        crate::assert_columns_eq!(result(&vm), Column::from(vec![11.0, 12.0, 13.0]));
+
+        // arguments come first deepest, and encoded columns are read as their values
+        let id = vm.register_udf("concat", |args| match args {
+            [Scalar::Str(a), Scalar::Num(b)] => Scalar::Str(format!("{}{}", a, b)),
+            _ => Scalar::Bool(false)
+        });
+        vm.run(vec![Op::Col(0, 1), Op::Col(0, 0), Op::CallUdf(id, 2)]).unwrap();
+        crate::assert_columns_eq!(result(&vm), Column::from(vec!["a1", "b2", "a3"]));
+    }
+
+    #[test]
+    fn scalar_arguments_alone_give_a_scalar() {
+        let mut vm = vm();
+        let id = vm.register_udf("add", add);
+        vm.run(vec![Op::Lit(Scalar::Num(1.0)), Op::Lit(Scalar::Num(2.0)), Op::CallUdf(id, 2)]).unwrap();
+        assert!(matches!(vm.stack().last(), Some(Value::Scalar(Scalar::Num(x))) if *x == 3.0));
+    }
+
+    #[test]
+    fn batch_functions_take_whole_columns() {
+        let mut vm = vm();
+        let id = vm.register_batch_udf("lens", |cols| Ok(Column::from((0 .. cols[0].len()).map(|i| i as u64).collect::<Vec<_>>())));
+        vm.run(vec![Op::Col(0, 1), Op::CallUdf(id, 1)]).unwrap();
+        crate::assert_columns_eq!(result(&vm), Column::from(vec![0u64, 1, 2]));
+
+        let short = vm.register_batch_udf("short", |_| Ok(Column::from(vec![1.0])));
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::CallUdf(short, 1)]), Err(VMError::LengthMismatch { expected: 3, found: 1 })));
+        assert!(matches!(vm.run(vec![Op::Lit(Scalar::Num(1.0)), Op::CallUdf(id, 1)]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn reregistering_replaces_the_function() {
+        let mut vm = vm();
+        let id = vm.register_udf("f", |_| Scalar::Num(1.0));
+        assert_eq!(vm.register_udf("f", |_| Scalar::Num(2.0)), id);
+        vm.run(vec![Op::Col(0, 0), Op::CallUdf(id, 1)]).unwrap();
+        crate::assert_columns_eq!(result(&vm), Column::from(vec![2.0; 3]));
+        assert_ne!(vm.register_udf("g", |_| Scalar::Num(1.0)), id);
+    }
+
+    #[test]
+    fn bad_calls_and_results_are_errors() {
+        let mut vm = vm();
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::CallUdf(7, 1)]), Err(VMError::UnknownFunction(7))));
+        // mixed result types, and records
+        let mixed = vm.register_udf("mixed", |args| match args {
+            [Scalar::Num(x)] if *x > 1.0 => Scalar::Num(*x),
+            _ => Scalar::Str("small".to_string())
+        });
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::CallUdf(mixed, 1)]), Err(VMError::TypeError(_))));
+        let doc = vm.register_udf("doc", |args| Scalar::Json(format!("{{\"x\": {:?}", args[0])));
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::CallUdf(doc, 1)]), Err(VMError::TypeError(_))));
+        let rec = vm.register_udf("rec", |args| Scalar::Record(args.to_vec()));
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::CallUdf(rec, 1)]), Err(VMError::TypeError(_))));
+        // columns of different lengths: the column, and the rows of it over 1.5
+        let mut vm = VM::new(Table::from_columns(vec![Column::from(vec![1.0, 2.0])]).unwrap());
+        let id = vm.register_udf("add", add);
+        let code = vec![Op::Col(0, 0), Op::Col(0, 0), Op::Lit(Scalar::Num(1.5)), Op::FilterGt, Op::Col(0, 0), Op::Select(1), Op::CallUdf(id, 2)];
+        assert!(matches!(vm.run(code), Err(VMError::LengthMismatch { .. })));
+    }
+
+    #[test]
+    fn subqueries_see_the_same_functions() {
+        let mut vm = vm();
+        let id = vm.register_udf("add", add);
+        let sub = vec![Op::Lit(Scalar::Num(1.0)), Op::Lit(Scalar::Num(1.0)), Op::CallUdf(id, 2)];
+        vm.run(vec![Op::Col(0, 0), Op::ScalarSubquery(sub), Op::FilterEq, Op::Col(0, 1), Op::Select(1)]).unwrap();
+        crate::assert_columns_eq!(result(&vm), Column::from(vec!["b"]));
+    }
+
+    const ROWS: usize = 200_000;
+
+    // sum(x * w) / sum(w), with the two sums as its state
+    #[derive(Default)]
+    struct WeightedMean {
+        total: f64,
+        weight: f64
+    }
+
+    impl Accumulator for WeightedMean {
+        fn update(&mut self, args: &[Scalar]) {
+            if let [Scalar::Num(x), Scalar::Num(w)] = args {
+                self.total += x * w;
+                self.weight += w;
+            }
+        }
+
+        fn state(&self) -> Vec<Scalar> {
+            vec![Scalar::Num(self.total), Scalar::Num(self.weight)]
+        }
+
+        fn merge(&mut self, state: &[Scalar]) {
+            if let [Scalar::Num(total), Scalar::Num(weight)] = state {
+                self.total += total;
+                self.weight += weight;
+            }
+        }
+
+        fn finish(&self) -> Scalar {
+            Scalar::Num(self.total / self.weight)
+        }
+    }
+
+    // rows seen, as an Entity
+    #[derive(Default)]
+    struct Rows(u64);
+
+    impl Accumulator for Rows {
+        fn update(&mut self, _: &[Scalar]) {
+            self.0 += 1;
+        }
+
+        fn state(&self) -> Vec<Scalar> {
+            vec![Scalar::Entity(self.0)]
+        }
+
+        fn merge(&mut self, state: &[Scalar]) {
+            if let [Scalar::Entity(n)] = state {
+                self.0 += n;
+            }
+        }
+
+        fn finish(&self) -> Scalar {
+            Scalar::Entity(self.0)
+        }
+    }
+
+    fn weighted_vm(rows: usize) -> VM {
+        let xs: Vec<f64> = (0 .. rows).map(|i| (i % 97) as f64 * 0.5).collect();
+        let ws: Vec<f64> = (0 .. rows).map(|i| (i % 13 + 1) as f64).collect();
+        VM::new(Table::from_columns(vec![Column::from(xs), Column::from(ws)]).unwrap())
+    }
+
+    fn scalar(vm: &VM) -> Scalar {
+        match vm.stack() {
+            [Value::Scalar(s)] => s.clone(),
+            stack => panic!("not a scalar: {:?}", stack)
+        }
+    }
+
+    #[test]
+    fn weighted_mean_matches_a_direct_computation() {
+        let mut vm = weighted_vm(ROWS);
+        let id = vm.register_udaf("weighted_mean", || Box::new(WeightedMean::default()));
+        vm.run(vec![Op::Col(0, 0), Op::Col(0, 1), Op::CallUdaf(id, 2)]).unwrap();
+        let (total, weight) = (0 .. ROWS).fold((0.0, 0.0), |(t, w), i| {
+            let (x, wi) = ((i % 97) as f64 * 0.5, (i % 13 + 1) as f64);
+            (t + x * wi, w + wi)
+        });
+        match scalar(&vm) {
+            Scalar::Num(mean) => assert!((mean - total / weight).abs() < 1e-9, "{} against {}", mean, total / weight),
+            s => panic!("not a Num: {:?}", s)
+        }
+    }
+
+    #[test]
+    fn every_row_reaches_the_total() {
+        for &rows in &[0, 1, PARTIAL_ROWS - 1, PARTIAL_ROWS, 2 * PARTIAL_ROWS, ROWS] {
+            let mut vm = weighted_vm(rows);
+            let id = vm.register_udaf("rows", || Box::new(Rows::default()));
+            vm.run(vec![Op::Col(0, 0), Op::CallUdaf(id, 1)]).unwrap();
+            assert_eq!(scalar(&vm), Scalar::Entity(rows as u64), "{} rows", rows);
+        }
+    }
+
+    #[test]
+    fn aggregates_per_group() {
+        let udf = Udf::Aggregate(Arc::new(|| Box::new(Rows::default())));
+        let col = Column::from(vec![1.0; 5]);
+        let res = aggregate_groups(&udf, &[Branch::Column(&col)], &[0, 2, 0, 2, 0], 4).unwrap();
+        assert_eq!(res, vec![Scalar::Entity(3), Scalar::Entity(0), Scalar::Entity(2), Scalar::Entity(0)]);
+        assert!(aggregate_groups(&udf, &[Branch::Column(&col)], &[0, 1], 2).is_err());
+    }
+
+    #[test]
+    fn aggregates_and_row_functions_dont_mix() {
+        let mut vm = weighted_vm(10);
+        let agg = vm.register_udaf("rows", || Box::new(Rows::default()));
+        let row = vm.register_udf("double", |args| match args {
+            [Scalar::Num(x)] => Scalar::Num(x * 2.0),
+            _ => Scalar::Bool(false)
+        });
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::CallUdf(agg, 1)]), Err(VMError::TypeError(_))));
+        assert!(matches!(vm.run(vec![Op::Col(0, 0), Op::CallUdaf(row, 1)]), Err(VMError::TypeError(_))));
+        assert!(vm.run(vec![Op::Col(0, 0), Op::CallUdf(row, 1)]).is_ok());
+    }
+}
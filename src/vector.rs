@@ -0,0 +1,610 @@
+// Fixed-size vectors of f32 - embeddings - and nearest-neighbour search over them. A
+// VectorColumn keeps its rows back to back in one buffer, `dim` values each, which is the layout
+// the distance kernels want: each compares a query against one contiguous row. Slices share
+// the buffer, as a PrimitiveColumn's do.
+//
+// Nearest compares every row unless the column has an IvfIndex (see with_index), which
+// clusters the rows around centroids and only compares those in the lists nearest the query:
+// faster, and approximate. Either way it can be limited to some rows, e.g. those a metadata
+// filter selected, which is how a query combines the two.
+
+use crate::bitindex::BitIndex;
+use crate::buffer::Buffer;
+use crate::column::{BoolColumn, Column, ColumnT, NumColumn, Scalar};
+use crate::compare::cmp_f64;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::selection::Selection;
+
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
+use core::cmp::Ordering;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Metric {
+    // 1 - the cosine of the angle between two vectors: 0 for the same direction, 2 for opposite
+    // ones. A zero vector is at distance 1 from everything.
+    #[default]
+    Cosine,
+    // Euclidean distance
+    L2
+}
+
+impl Metric {
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Cosine => cosine(a, b),
+            Metric::L2 => l2(a, b)
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Metric::Cosine => write!(f, "cosine"),
+            Metric::L2 => write!(f, "l2")
+        }
+    }
+}
+
+const LANES: usize = 8;
+
+// Sums `f` over pairs of elements, in LANES separate accumulators the compiler can keep in
+// one vector register
+fn sum_pairs<F: Fn(f32, f32) -> f32>(a: &[f32], b: &[f32], f: F) -> f32 {
+    let mut acc = [0f32; LANES];
+    let (chunks_a, chunks_b) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+    let tail: f32 = chunks_a.remainder().iter().zip(chunks_b.remainder()).map(|(x, y)| f(*x, *y)).sum();
+    for (xs, ys) in chunks_a.zip(chunks_b) {
+        for lane in 0 .. LANES {
+            acc[lane] += f(xs[lane], ys[lane]);
+        }
+    }
+    acc.iter().sum::<f32>() + tail
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    sum_pairs(a, b, |x, y| x * y)
+}
+
+pub fn l2(a: &[f32], b: &[f32]) -> f32 {
+    libm::sqrtf(sum_pairs(a, b, |x, y| (x - y) * (x - y)))
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let norms = libm::sqrtf(dot(a, a)) * libm::sqrtf(dot(b, b));
+    if norms == 0.0 { 1.0 } else { 1.0 - dot(a, b) / norms }
+}
+
+#[derive(Clone)]
+pub struct VectorColumn {
+    pub(crate) dim: usize,
+    pub(crate) data: Buffer<f32>,   // row i is data[i * dim .. (i + 1) * dim]
+    index: Option<Arc<IvfIndex>>
+}
+
+impl VectorColumn {
+    // Rows of `dim` values each from `data`, whose length must be a multiple of it
+    pub fn new(dim: usize, data: Vec<f32>) -> Result<VectorColumn, VMError> {
+        if dim == 0 {
+            return Err(VMError::TypeError("Vectors need at least one dimension".to_string()));
+        }
+        if !data.len().is_multiple_of(dim) {
+            return Err(VMError::LengthMismatch { expected: data.len() / dim * dim + dim, found: data.len() });
+        }
+        Ok(VectorColumn { dim, data: Buffer::from(data), index: None })
+    }
+
+    // Rows given one by one, which must all have `dim` values
+    pub fn from_rows<R: AsRef<[f32]>>(dim: usize, rows: &[R]) -> Result<VectorColumn, VMError> {
+        let mut data = Vec::with_capacity(rows.len() * dim);
+        for row in rows {
+            let row = row.as_ref();
+            if row.len() != dim {
+                return Err(VMError::LengthMismatch { expected: dim, found: row.len() });
+            }
+            data.extend_from_slice(row);
+        }
+        VectorColumn::new(dim, data)
+    }
+
+    // Search with `index`, which must have been built from this column's rows
+    pub fn with_index(mut self, index: Arc<IvfIndex>) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn index(&self) -> Option<&Arc<IvfIndex>> {
+        self.index.as_ref()
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / self.dim
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Row i, which must be in bounds
+    pub fn row(&self, i: usize) -> &[f32] {
+        &self.data[i * self.dim .. (i + 1) * self.dim]
+    }
+
+    pub fn get(&self, i: usize) -> Option<&[f32]> {
+        if i < self.len() { Some(self.row(i)) } else { None }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&[f32]> + '_ {
+        self.data.chunks_exact(self.dim)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.data.memory_usage()
+    }
+
+    // Rows offset .. offset + len, which must be in bounds; shares the buffer. The index
+    // covers other rows, so it isn't kept.
+    pub fn slice(&self, offset: usize, len: usize) -> VectorColumn {
+        VectorColumn { dim: self.dim, data: self.data.slice(offset * self.dim, len * self.dim), index: None }
+    }
+
+    pub fn gather(&self, sel: &Selection) -> VectorColumn {
+        let mut data = Buffer::with_capacity(sel.count_ones() * self.dim);
+        sel.for_each(|i| data.extend_from_slice(self.row(i)));
+        VectorColumn { dim: self.dim, data, index: None }
+    }
+
+    fn expect_query<'a>(&self, query: &'a [f32]) -> Result<&'a [f32], VMError> {
+        if query.len() != self.dim {
+            return Err(VMError::LengthMismatch { expected: self.dim, found: query.len() });
+        }
+        Ok(query)
+    }
+
+    // Each row's distance from `query`
+    pub fn distance_to(&self, query: &[f32], metric: Metric) -> Result<NumColumn, VMError> {
+        let query = self.expect_query(query)?;
+        Ok(NumColumn::new(self.iter().map(|row| metric.distance(row, query) as f64).collect()))
+    }
+
+    // The `k` rows nearest `query`, nearest first, with their distances; of rows at the same
+    // distance, the first ones. Only rows in `among` are considered, if it's given. With an
+    // index for `metric`, only the rows in its nearest lists are compared, falling back to
+    // comparing every row when those hold fewer than `k` of the rows considered.
+    pub fn nearest(&self, query: &[f32], k: usize, metric: Metric, among: Option<&Selection>) -> Result<Vec<(usize, f32)>, VMError> {
+        let query = self.expect_query(query)?;
+        let mut top = TopK::new(k);
+        if let Some(index) = self.index.as_ref().filter(|index| index.metric == metric && index.rows == self.len()) {
+            index.probe(query).for_each(|list| list.iter()
+                .map(|row| *row as usize)
+                .filter(|row| among.is_none_or(|sel| sel.contains(*row)))
+                .for_each(|row| top.push(metric.distance(self.row(row), query), row)));
+            let considered = among.map(|sel| sel.count_ones()).unwrap_or(self.len());
+            if top.len() == k.min(considered) {
+                return Ok(top.into_sorted());
+            }
+            top = TopK::new(k);
+        }
+        match among {
+            Some(sel) => sel.for_each(|row| top.push(metric.distance(self.row(row), query), row)),
+            None => self.iter().enumerate().for_each(|(row, x)| top.push(metric.distance(x, query), row))
+        }
+        Ok(top.into_sorted())
+    }
+
+    // nearest as a mask: over the column's rows or, with `among`, over the rows it selects,
+    // in order (as FilterEq on a view)
+    pub fn nearest_mask(&self, query: &[f32], k: usize, metric: Metric, among: Option<&Selection>) -> Result<BoolColumn, VMError> {
+        let mut rows: Vec<usize> = self.nearest(query, k, metric, among)?.into_iter().map(|(row, _)| row).collect();
+        rows.sort_unstable();
+        let positions = match among {
+            None => rows.iter().map(|row| *row as u32).collect(),
+            Some(sel) => {
+                let (mut positions, mut i) = (Vec::with_capacity(rows.len()), 0);
+                sel.for_each(|row| {
+                    if rows.binary_search(&row).is_ok() { positions.push(i); }
+                    i += 1;
+                });
+                positions
+            }
+        };
+        let len = among.map(|sel| sel.count_ones()).unwrap_or(self.len());
+        Ok(BoolColumn::from_selection(Selection::from_positions(positions, len)))
+    }
+}
+
+impl ColumnT for VectorColumn {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    // Rows equal to the given vector, value by value
+    fn filter(&self, val: Scalar) -> Result<BoolColumn, VMError> {
+        let x = match val {
+            Scalar::Vector(x) => x,
+            _ => return Err(VMError::TypeError(format!("Expected a vector value, got: {:?}", val)))
+        };
+        let x = self.expect_query(&x)?;
+        let mut mask = BitIndex::for_col_len(self.len());
+        self.iter().enumerate().filter(|(_, row)| same(row, x)).for_each(|(i, _)| mask.set(i));
+        Ok(BoolColumn::from_mask(mask))
+    }
+
+    fn select(&self, mask: &BoolColumn) -> Self {
+        self.gather(mask.selection())
+    }
+}
+
+// Equality as for Scalar: NaN equals NaN, and -0.0 equals 0.0
+pub(crate) fn same(a: &[f32], b: &[f32]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| cmp_f64(*x as f64, *y as f64) == Ordering::Equal)
+}
+
+impl PartialEq for VectorColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim && same(&self.data, &other.data)
+    }
+}
+
+impl fmt::Debug for VectorColumn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VectorColumn {{ dim: {}, data: {:?}, indexed: {} }}", self.dim, self.data, self.index.is_some())
+    }
+}
+
+// The `k` nearest candidates seen so far: a max-heap, so the farthest is the one to drop
+#[derive(PartialEq)]
+struct Candidate(f32, usize);
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    // NaN distances after every other, and ties broken by row
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_f64(self.0 as f64, other.0 as f64).then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct TopK {
+    k: usize,
+    heap: BinaryHeap<Candidate>
+}
+
+impl TopK {
+    fn new(k: usize) -> Self {
+        TopK { k, heap: BinaryHeap::new() }
+    }
+
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    fn push(&mut self, distance: f32, row: usize) {
+        let c = Candidate(distance, row);
+        if self.heap.len() < self.k {
+            self.heap.push(c);
+        } else if self.heap.peek().is_some_and(|worst| c < *worst) {
+            self.heap.pop();
+            self.heap.push(c);
+        }
+    }
+
+    fn into_sorted(self) -> Vec<(usize, f32)> {
+        self.heap.into_sorted_vec().into_iter().map(|Candidate(d, row)| (row, d)).collect()
+    }
+}
+
+// An inverted file index: the rows of a VectorColumn clustered around `lists` centroids, by
+// k-means. A search compares the query with the centroids, then only with the rows of the
+// `probes` lists whose centroids are nearest - more probes, better recall, slower search.
+#[derive(Debug, Clone)]
+pub struct IvfIndex {
+    metric: Metric,
+    centroids: VectorColumn,
+    lists: Vec<Vec<u32>>,
+    probes: usize,
+    rows: usize     // of the column it was built from
+}
+
+// Rounds of k-means; the centroids rarely move much after a handful
+const KMEANS_ROUNDS: usize = 10;
+
+impl IvfIndex {
+    // An index of `col` for searches by `metric`, with `lists` lists (fewer, for a column of
+    // fewer rows). Centroids start at evenly spaced rows, so building is deterministic. Searches
+    // probe about the square root of `lists` lists, unless set with with_probes.
+    pub fn build(col: &VectorColumn, lists: usize, metric: Metric) -> Result<IvfIndex, VMError> {
+        let lists = lists.min(col.len());
+        if lists == 0 {
+            return Err(VMError::TypeError("An IVF index needs at least one list, and a row to put in it".to_string()));
+        }
+        let mut centroids = Vec::with_capacity(lists * col.dim);
+        (0 .. lists).for_each(|i| centroids.extend_from_slice(col.row(i * col.len() / lists)));
+        let mut centroids = VectorColumn { dim: col.dim, data: Buffer::from(centroids), index: None };
+        let mut assigned = vec![0u32; col.len()];
+        for _ in 0 .. KMEANS_ROUNDS {
+            for (row, x) in col.iter().enumerate() {
+                assigned[row] = nearest_centroid(&centroids, x, metric) as u32;
+            }
+            let mut sums = vec![0f32; lists * col.dim];
+            let mut counts = vec![0usize; lists];
+            for (row, x) in col.iter().enumerate() {
+                let c = assigned[row] as usize;
+                counts[c] += 1;
+                sums[c * col.dim .. (c + 1) * col.dim].iter_mut().zip(x).for_each(|(s, v)| *s += v);
+            }
+            // a centroid nothing was assigned to stays where it was
+            for (c, count) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+                let mean = sums[c * col.dim .. (c + 1) * col.dim].iter().map(|s| s / *count as f32);
+                centroids.data[c * col.dim .. (c + 1) * col.dim].iter_mut().zip(mean).for_each(|(x, m)| *x = m);
+            }
+        }
+        let mut members = vec![Vec::new(); lists];
+        for (row, x) in col.iter().enumerate() {
+            members[nearest_centroid(&centroids, x, metric)].push(row as u32);
+        }
+        let probes = (libm::sqrt(lists as f64) as usize).max(1);
+        Ok(IvfIndex { metric, centroids, lists: members, probes, rows: col.len() })
+    }
+
+    // Probe `probes` lists per search (at least one)
+    pub fn with_probes(mut self, probes: usize) -> Self {
+        self.probes = probes.clamp(1, self.lists.len());
+        self
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn lists(&self) -> usize {
+        self.lists.len()
+    }
+
+    pub fn probes(&self) -> usize {
+        self.probes
+    }
+
+    // The lists of the `probes` centroids nearest `query`
+    fn probe<'a>(&'a self, query: &[f32]) -> impl Iterator<Item=&'a Vec<u32>> + 'a {
+        let mut top = TopK::new(self.probes);
+        self.centroids.iter().enumerate().for_each(|(c, x)| top.push(self.metric.distance(x, query), c));
+        top.into_sorted().into_iter().map(move |(c, _)| &self.lists[c])
+    }
+}
+
+fn nearest_centroid(centroids: &VectorColumn, x: &[f32], metric: Metric) -> usize {
+    let mut top = TopK::new(1);
+    centroids.iter().enumerate().for_each(|(c, centroid)| top.push(metric.distance(centroid, x), c));
+    top.into_sorted()[0].0
+}
+
+// A vector operand, e.g. the query of Nearest
+pub(crate) fn expect_vector(s: &Scalar) -> Result<&[f32], VMError> {
+    match s {
+        Scalar::Vector(x) => Ok(x),
+        _ => Err(VMError::TypeError(format!("Expected a vector, got: {:?}", s)))
+    }
+}
+
+// The vector column an op works on
+pub(crate) fn expect_vectors(col: &Column) -> Result<&VectorColumn, VMError> {
+    match col {
+        Column::Vector(c) => Ok(c),
+        _ => Err(VMError::TypeError(format!("Expected a Vector column, found a {} column", col.datatype())))
+    }
+}
+
+// Values as written by Scalar's Display - "[0.1, 0.2, 0.3]" - or with the brackets left out,
+// or separated by spaces
+pub fn parse(s: &str) -> Option<Vec<f32>> {
+    let s = s.trim();
+    let s = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    s.split(|c: char| c == ',' || c.is_whitespace()).filter(|x| !x.is_empty()).map(|x| x.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagen::Rng;
+    use crate::opcode::Op;
+    use crate::vm::VM;
+    use crate::table::Table;
+
+    use core::convert::TryFrom;
+
+    // `n` rows of `dim` values, in loose clusters around a few centres, as embeddings tend to be
+    fn clustered(n: usize, dim: usize, seed: u64) -> VectorColumn {
+        let mut rng = Rng::new(seed);
+        let centres: Vec<Vec<f32>> = (0 .. 16).map(|_| (0 .. dim).map(|_| rng.next_f64() as f32 * 2.0 - 1.0).collect()).collect();
+        let mut data = Vec::with_capacity(n * dim);
+        for _ in 0 .. n {
+            let centre = &centres[rng.below(centres.len() as u64) as usize];
+            data.extend(centre.iter().map(|x| x + (rng.next_f64() as f32 - 0.5) * 0.6));
+        }
+        VectorColumn::new(dim, data).unwrap()
+    }
+
+    // The k nearest by comparing every row, ties going to the first rows
+    fn brute_force(col: &VectorColumn, query: &[f32], k: usize, metric: Metric) -> Vec<usize> {
+        let mut all: Vec<(f32, usize)> = col.iter().enumerate().map(|(row, x)| (metric.distance(x, query), row)).collect();
+        all.sort_by(|a, b| cmp_f64(a.0 as f64, b.0 as f64).then(a.1.cmp(&b.1)));
+        all.into_iter().take(k).map(|(_, row)| row).collect()
+    }
+
+    #[test]
+    fn kernels_match_the_textbook_formulas() {
+        let mut rng = Rng::new(988);
+        // lengths around the lane count
+        for len in [0, 1, 7, 8, 9, 16, 33] {
+            let a: Vec<f32> = (0 .. len).map(|_| rng.next_f64() as f32 - 0.5).collect();
+            let b: Vec<f32> = (0 .. len).map(|_| rng.next_f64() as f32 - 0.5).collect();
+            let d: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+            let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+            let e = a.iter().zip(&b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt();
+            assert!((dot(&a, &b) - d).abs() < 1e-5, "{} values", len);
+            assert!((l2(&a, &b) - e).abs() < 1e-5, "{} values", len);
+            if len > 0 {
+                assert!((cosine(&a, &b) - (1.0 - d / (norm(&a) * norm(&b)))).abs() < 1e-5, "{} values", len);
+            }
+        }
+        assert_eq!(cosine(&[1.0, 0.0], &[2.0, 0.0]), 0.0);
+        assert_eq!(cosine(&[1.0, 0.0], &[-1.0, 0.0]), 2.0);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 1.0]), 1.0);
+        assert_eq!(Metric::L2.distance(&[0.0, 0.0], &[3.0, 4.0]), 5.0);
+        assert_eq!(Metric::default().to_string(), "cosine");
+    }
+
+    #[test]
+    fn rows_are_dim_values_each() {
+        let col = VectorColumn::from_rows(2, &[[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]).unwrap();
+        assert_eq!((col.len(), col.dim()), (3, 2));
+        assert_eq!(col.row(1), &[3.0, 4.0]);
+        assert_eq!(col.get(3), None);
+        assert_eq!(col.slice(1, 2), VectorColumn::from_rows(2, &[[3.0, 4.0], [5.0, 6.0]]).unwrap());
+        assert_eq!(col.gather(&Selection::from_positions(vec![0, 2], 3)), VectorColumn::from_rows(2, &[[1.0, 2.0], [5.0, 6.0]]).unwrap());
+        assert!(matches!(VectorColumn::new(0, vec![]), Err(VMError::TypeError(_))));
+        assert!(matches!(VectorColumn::new(2, vec![1.0; 5]), Err(VMError::LengthMismatch { expected: 6, found: 5 })));
+        assert!(matches!(VectorColumn::from_rows(2, &[vec![1.0, 2.0], vec![3.0]]), Err(VMError::LengthMismatch { expected: 2, found: 1 })));
+        assert!(VectorColumn::new(3, vec![]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn filters_rows_equal_as_scalars_are() {
+        let col = VectorColumn::from_rows(2, &[[0.0, f32::NAN], [-0.0, f32::NAN], [0.0, 1.0]]).unwrap();
+        assert_eq!(col.filter(Scalar::Vector(vec![0.0, f32::NAN])).unwrap().selection().positions(), vec![0, 1]);
+        assert!(matches!(col.filter(Scalar::Vector(vec![0.0])), Err(VMError::LengthMismatch { expected: 2, found: 1 })));
+        assert!(matches!(col.filter(Scalar::Num(0.0)), Err(VMError::TypeError(_))));
+        assert_eq!(col.select(&col.filter(Scalar::Vector(vec![0.0, 1.0])).unwrap()).row(0), &[0.0, 1.0]);
+    }
+
+    #[test]
+    fn exact_search_matches_brute_force() {
+        let col = clustered(500, 8, 1);
+        let mut rng = Rng::new(2);
+        for metric in [Metric::Cosine, Metric::L2] {
+            for _ in 0 .. 20 {
+                let query: Vec<f32> = (0 .. 8).map(|_| rng.next_f64() as f32 * 2.0 - 1.0).collect();
+                let found = col.nearest(&query, 10, metric, None).unwrap();
+                assert_eq!(found.iter().map(|(row, _)| *row).collect::<Vec<_>>(), brute_force(&col, &query, 10, metric));
+                assert!(found.windows(2).all(|w| w[0].1 <= w[1].1));
+                let dist = col.distance_to(&query, metric).unwrap();
+                assert_eq!(dist.data[found[0].0], found[0].1 as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn search_breaks_ties_by_row_and_takes_what_there_is() {
+        let col = VectorColumn::from_rows(1, &[[2.0], [1.0], [1.0], [f32::NAN], [3.0]]).unwrap();
+        let found = col.nearest(&[1.0], 2, Metric::L2, None).unwrap();
+        assert_eq!(found, vec![(1, 0.0), (2, 0.0)]);
+        // NaN distances come last
+        assert_eq!(col.nearest(&[0.0], 9, Metric::L2, None).unwrap().iter().map(|r| r.0).collect::<Vec<_>>(), vec![1, 2, 0, 4, 3]);
+        assert!(col.nearest(&[0.0], 0, Metric::L2, None).unwrap().is_empty());
+        assert!(matches!(col.nearest(&[0.0, 1.0], 1, Metric::L2, None), Err(VMError::LengthMismatch { expected: 1, found: 2 })));
+    }
+
+    #[test]
+    fn search_can_be_limited_to_some_rows() {
+        let col = VectorColumn::from_rows(1, &[[0.0], [1.0], [2.0], [3.0], [4.0]]).unwrap();
+        let among = Selection::from_positions(vec![2, 3, 4], 5);
+        let found: Vec<usize> = col.nearest(&[0.0], 2, Metric::L2, Some(&among)).unwrap().into_iter().map(|r| r.0).collect();
+        assert_eq!(found, vec![2, 3]);
+        // as a mask, over the rows it was limited to
+        assert_eq!(col.nearest_mask(&[0.0], 2, Metric::L2, Some(&among)).unwrap().selection().positions(), vec![0, 1]);
+        assert_eq!(col.nearest_mask(&[0.0], 2, Metric::L2, Some(&among)).unwrap().selection().len(), 3);
+        assert_eq!(col.nearest_mask(&[4.0], 2, Metric::L2, None).unwrap().selection().positions(), vec![3, 4]);
+    }
+
+    #[test]
+    fn the_index_recalls_most_of_the_true_neighbours() {
+        let (col, k) = (clustered(4000, 16, 3), 10);
+        let index = IvfIndex::build(&col, 64, Metric::L2).unwrap();
+        assert_eq!((index.lists(), index.probes(), index.metric()), (64, 8, Metric::L2));
+        let indexed = col.clone().with_index(Arc::new(index.clone()));
+        let exhaustive = col.clone().with_index(Arc::new(index.with_probes(1000)));
+
+        let mut rng = Rng::new(4);
+        let (mut hits, mut total) = (0, 0);
+        for _ in 0 .. 50 {
+            // queries near the data, as real ones are
+            let near = col.row(rng.below(col.len() as u64) as usize);
+            let query: Vec<f32> = near.iter().map(|x| x + (rng.next_f64() as f32 - 0.5) * 0.2).collect();
+            let truth = brute_force(&col, &query, k, Metric::L2);
+            let found: Vec<usize> = indexed.nearest(&query, k, Metric::L2, None).unwrap().into_iter().map(|r| r.0).collect();
+            assert_eq!(found.len(), k);
+            hits += found.iter().filter(|row| truth.contains(row)).count();
+            total += k;
+            // probing every list is exact
+            let all: Vec<usize> = exhaustive.nearest(&query, k, Metric::L2, None).unwrap().into_iter().map(|r| r.0).collect();
+            assert_eq!(all, truth);
+        }
+        let recall = hits as f64 / total as f64;
+        assert!(recall >= 0.9, "recall {}", recall);
+    }
+
+    #[test]
+    fn the_index_is_used_only_where_it_applies() {
+        let col = clustered(300, 4, 5);
+        let query = col.row(7).to_vec();
+        let index = Arc::new(IvfIndex::build(&col, 16, Metric::Cosine).unwrap().with_probes(1));
+        let indexed = col.clone().with_index(index.clone());
+        // another metric, or fewer rows than the index covers, search every row
+        assert_eq!(indexed.nearest(&query, 5, Metric::L2, None).unwrap(), col.nearest(&query, 5, Metric::L2, None).unwrap());
+        assert!(indexed.slice(0, 100).index().is_none());
+        // too few rows in the probed lists: every row is compared instead
+        let among = Selection::from_positions((0 .. 300).filter(|i| i % 50 == 0).collect(), 300);
+        assert_eq!(indexed.nearest(&query, 5, Metric::Cosine, Some(&among)).unwrap(), col.nearest(&query, 5, Metric::Cosine, Some(&among)).unwrap());
+        assert!(IvfIndex::build(&VectorColumn::new(4, vec![]).unwrap(), 16, Metric::L2).is_err());
+        assert_eq!(IvfIndex::build(&col.slice(0, 3), 16, Metric::L2).unwrap().lists(), 3);
+        assert_eq!(index.probes(), 1);
+    }
+
+    #[test]
+    fn parses_vectors_as_written() {
+        assert_eq!(parse("[0.5, -1, 2e1]"), Some(vec![0.5, -1.0, 20.0]));
+        assert_eq!(parse(" 1 2\t3 "), Some(vec![1.0, 2.0, 3.0]));
+        assert_eq!(parse("1,2"), Some(vec![1.0, 2.0]));
+        assert_eq!(parse("[]"), Some(vec![]));
+        assert_eq!(parse("[1, x]"), None);
+        assert_eq!(parse(&Scalar::Vector(vec![0.25, 3.0]).to_string()), Some(vec![0.25, 3.0]));
+    }
+
+    #[test]
+    fn the_vm_finds_nearest_rows() {
+        let col = VectorColumn::from_rows(2, &[[0.0, 1.0], [1.0, 0.0], [0.9, 0.1], [-1.0, 0.0]]).unwrap();
+        let run = |code: Vec<Op>| {
+            let mut vm = VM::new(Table::from_columns(vec![Column::Vector(col.clone()), Column::from(vec![10u64, 11, 12, 13]), Column::from(vec![true, true, false, true])]).unwrap());
+            vm.run(code).map(|_| vm.column_of(vm.stack().last().unwrap()).unwrap().clone())
+        };
+        let query = Op::Lit(Scalar::Vector(vec![1.0, 0.0]));
+        let ids = |c: Column| Vec::<u64>::try_from(&c).unwrap();
+        let nearest = run(vec![Op::Col(0, 0), query.clone(), Op::Nearest(2, Metric::Cosine), Op::Col(0, 1), Op::Select(1)]).unwrap();
+        assert_eq!(ids(nearest), vec![11, 12]);
+        // within the rows a filter left, as a mask over them: row 2 is out, so row 0 is second nearest
+        let flags = vec![Op::Col(0, 2), Op::Lit(Scalar::Bool(true)), Op::FilterEq];
+        let code = [flags, vec![Op::Col(0, 0), Op::Select(1), query.clone(), Op::Nearest(2, Metric::L2)]].concat();
+        let filtered = run(code).unwrap();
+        assert_eq!(Vec::<bool>::try_from(&filtered).unwrap(), vec![true, true, false]);
+
+        let dist = run(vec![Op::Col(0, 0), query.clone(), Op::VectorDistance(Metric::L2)]).unwrap();
+        assert_eq!(Vec::<f64>::try_from(&dist).unwrap()[1], 0.0);
+        assert!(matches!(run(vec![Op::Col(0, 1), query, Op::Nearest(1, Metric::L2)]), Err(VMError::TypeError(_))));
+        assert!(matches!(run(vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::VectorDistance(Metric::L2)]), Err(VMError::TypeError(_))));
+    }
+}
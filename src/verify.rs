@@ -0,0 +1,435 @@
+// Checking a program before it runs. validate walks the code once, keeping track of the kind
+// of value each stack slot would hold - a scalar or a column, and its type where that can be
+// known without the data - and turns away programs that would pop an empty stack, load a
+// column that isn't there, or hand an op operands it can't take, such as a Str literal to a
+// filter on a Num column. A program that passes can still fail on its data (a mask of the
+// wrong length, an overflow), but not on its shape, so a query can be refused before it
+// reserves memory or reads a row.
+//
+// What a UDF, a shared sub-plan or a column loaded by name holds isn't known here; the ops
+// they feed are given the benefit of the doubt.
+
+use crate::aggregate;
+use crate::column::Scalar;
+use crate::core::prelude::*;
+use crate::errors::VMError;
+use crate::opcode::Op;
+use crate::schema::Datatype;
+
+// What a stack slot would hold, None where its type isn't known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    Scalar(Option<Datatype>),   // a null literal is a scalar of no particular type
+    Column(Option<Datatype>),
+    Any                         // a scalar or a column
+}
+
+// Check `code` against a VM loaded with one table whose columns have the types in `schema`
+pub fn validate(code: &[Op], schema: &[Datatype]) -> Result<(), VMError> {
+    validate_tables(code, &[schema])
+}
+
+// Check `code` against a VM loaded with a catalog of tables, each given by its column types
+pub fn validate_tables(code: &[Op], tables: &[&[Datatype]]) -> Result<(), VMError> {
+    let mut stack = Vec::new();
+    for (ip, op) in code.iter().enumerate() {
+        let (pops, _) = op.stack_effect();
+        if stack.len() < pops {
+            return Err(VMError::StackUnderflow { ip, pops, depth: stack.len() });
+        }
+        Checker { tables, stack: &mut stack, ip, op }.check()?;
+    }
+    Ok(())
+}
+
+struct Checker<'a> {
+    tables: &'a [&'a [Datatype]],
+    stack: &'a mut Vec<Ty>,
+    ip: usize,
+    op: &'a Op
+}
+
+impl Checker<'_> {
+    fn check(mut self) -> Result<(), VMError> {
+        use Datatype::*;
+        let mask = Ty::Column(Some(Bool));
+        let res = match self.op {
+            Op::Lit(s) => Ty::Scalar(type_of(s)),
+            Op::Col(t, c) => Ty::Column(Some(self.column(*t, *c)?)),
+            Op::ColByName(_) => Ty::Column(None),
+            Op::Select(_) => {
+                let data = self.pop_column()?;
+                self.pop_column_of(&[Bool])?;
+                Ty::Column(data)
+            },
+            Op::FilterEq => {
+                let val = self.pop_scalar()?;
+                match self.stack.pop() {
+                    // two scalars compare whatever their types
+                    Some(Ty::Scalar(_)) => Ty::Scalar(Some(Bool)),
+                    Some(Ty::Column(col)) => {
+                        self.comparable(col, val)?;
+                        mask
+                    },
+                    _ => Ty::Any
+                }
+            },
+            Op::FilterLt | Op::FilterLe | Op::FilterGt | Op::FilterGe | Op::FilterNe => {
+                let val = self.pop_scalar()?;
+                let col = self.pop_column()?;
+                self.comparable(col, val)?;
+                mask
+            },
+            Op::And | Op::Or => {
+                self.pop_column_of(&[Bool])?;
+                self.pop_column_of(&[Bool])?;
+                mask
+            },
+            Op::Not => {
+                self.pop_column_of(&[Bool])?;
+                mask
+            },
+            Op::FilterSelect => {
+                let target = self.pop_column()?;
+                let val = self.pop_scalar()?;
+                let col = self.pop_column()?;
+                self.comparable(col, val)?;
+                Ty::Column(target)
+            },
+            Op::FilterIn => {
+                let set = self.pop_column()?;
+                let col = self.pop_column()?;
+                match (col, set) {
+                    (Some(a), Some(b)) if a != b => return Err(self.mismatch(format!("can't look {} values up in a {} column", b, a))),
+                    // values with no hash to look them up by
+                    (Some(t @ (Point | List | Record)), _) => return Err(self.mismatch(format!("can't look up {} values", t))),
+                    _ => {}
+                }
+                mask
+            },
+            Op::FilterWithinBBox => {
+                self.pop_scalar_of(&[Point])?;
+                self.pop_scalar_of(&[Point])?;
+                self.pop_column_of(&[Point])?;
+                mask
+            },
+            Op::DistanceTo => {
+                self.pop_scalar_of(&[Point])?;
+                self.pop_column_of(&[Point])?;
+                Ty::Column(Some(Num))
+            },
+            Op::JsonExtract(_, dtype) => {
+                self.pop_column_of(&[Json])?;
+                if !matches!(dtype, Num | Entity | Int | Bool | Str | Json) {
+                    return Err(self.mismatch(format!("can't extract {} values from JSON", dtype)));
+                }
+                Ty::Column(Some(*dtype))
+            },
+            Op::FilterInCidr(_) => {
+                let network = self.pop_scalar_of(&[Ipv4, Ipv6])?;
+                let col = self.pop_column_of(&[Ipv4, Ipv6])?;
+                if let (Some(a), Some(b)) = (col, network) {
+                    if a != b {
+                        return Err(self.mismatch(format!("can't find {} networks in a {} column", b, a)));
+                    }
+                }
+                mask
+            },
+            Op::IfElse => {
+                let els = self.pop();
+                let then = self.pop();
+                self.pop_column_of(&[Bool])?;
+                // a list or record branch must be a column, there being no column of one scalar
+                for branch in [then, els] {
+                    if let Ty::Scalar(Some(t @ (List | Record))) = branch {
+                        return Err(self.mismatch(format!("can't make a column of a {} scalar", t)));
+                    }
+                }
+                match (dtype_of(then), dtype_of(els)) {
+                    (Some(a), Some(b)) if a == b => Ty::Column(Some(a)),
+                    (Some(Categorical), Some(Str)) | (Some(Str), Some(Categorical)) => Ty::Column(Some(Categorical)),
+                    (Some(a), Some(b)) => return Err(self.mismatch(format!("can't choose between {} and {} values", a, b))),
+                    _ => Ty::Column(None)
+                }
+            },
+            Op::VectorDistance(_) => {
+                self.pop_scalar_of(&[Vector])?;
+                self.pop_column_of(&[Vector])?;
+                Ty::Column(Some(Num))
+            },
+            Op::Nearest(..) => {
+                self.pop_scalar_of(&[Vector])?;
+                self.pop_column_of(&[Vector])?;
+                mask
+            },
+            Op::ListLen => {
+                self.pop_column_of(&[List])?;
+                Ty::Column(Some(Entity))
+            },
+            Op::FilterContains => {
+                self.pop_scalar()?;
+                self.pop_column_of(&[List])?;
+                mask
+            },
+            // of a List column, a column of one per list
+            Op::Count => match self.pop_column()? {
+                Some(List) => Ty::Column(Some(Int)),
+                Some(_) => Ty::Scalar(Some(Int)),
+                None => Ty::Any
+            },
+            Op::Sum => match self.pop_column_of(&[Num, Int, Duration, List])? {
+                Some(List) => Ty::Column(None),
+                Some(t @ (Int | Duration)) => Ty::Scalar(Some(t)),
+                Some(_) => Ty::Scalar(Some(Num)),
+                None => Ty::Any
+            },
+            Op::Mean => match self.pop_column_of(&[Num, Int, List])? {
+                Some(List) => Ty::Column(None),
+                Some(_) => Ty::Scalar(Some(Num)),
+                None => Ty::Any
+            },
+            Op::Min | Op::Max => match self.pop_column()? {
+                Some(List) => Ty::Column(None),
+                Some(t) if !aggregate::ordered(t) => return Err(self.mismatch(format!("{} values have no order to take the least or greatest by", t))),
+                Some(_) => Ty::Scalar(None),
+                None => Ty::Any
+            },
+            Op::GroupBy(key) => {
+                let keys = self.tables.first().copied().unwrap_or(&[]);
+                let dtype = *keys.get(*key).ok_or(VMError::ColumnIndexOutOfRange { idx: *key, ncols: keys.len() })?;
+                self.pop_column()?;
+                self.stack.push(Ty::Column(Some(dtype)));
+                Ty::Column(Some(List))
+            },
+            Op::HashJoin => {
+                let right = self.pop_column_of(&[Entity, Int])?;
+                let left = self.pop_column_of(&[Entity, Int])?;
+                if let (Some(a), Some(b)) = (left, right) {
+                    if a != b {
+                        return Err(self.mismatch(format!("can't join {} keys to {} keys", a, b)));
+                    }
+                }
+                self.stack.push(Ty::Column(Some(Entity)));
+                Ty::Column(Some(Entity))
+            },
+            Op::ArgSort => {
+                self.pop_column()?;
+                Ty::Column(Some(Entity))
+            },
+            Op::SortBy | Op::Take => {
+                let data = self.pop_column()?;
+                self.pop_column_of(&[Entity])?;
+                Ty::Column(data)
+            },
+            Op::Limit(_) => Ty::Column(self.pop_column()?),
+            Op::TopK(_) => {
+                self.pop_column_of(&[Num, Int])?;
+                Ty::Column(Some(Entity))
+            },
+            Op::Shared(_) => Ty::Any,
+            Op::CallUdf(_, arity) => {
+                self.stack.truncate(self.stack.len() - arity);
+                Ty::Any
+            },
+            Op::CallUdaf(_, arity) => {
+                self.stack.truncate(self.stack.len() - arity);
+                Ty::Scalar(None)
+            },
+            Op::Field(_) => match self.stack.pop() {
+                Some(Ty::Scalar(Some(Record) | None)) => Ty::Scalar(None),
+                Some(Ty::Column(Some(Record) | None)) => Ty::Column(None),
+                Some(Ty::Any) | None => Ty::Any,
+                Some(Ty::Scalar(Some(t)) | Ty::Column(Some(t))) => return Err(self.mismatch(format!("expected a record, found {}", t)))
+            },
+            Op::AddVs | Op::SubVs | Op::MulVs | Op::DivVs => {
+                let val = self.pop_scalar_of(self.arith_types())?;
+                let col = self.pop_column_of(self.arith_types())?;
+                Ty::Column(self.arith(col, val)?)
+            },
+            Op::AddVv | Op::SubVv | Op::MulVv | Op::DivVv => {
+                let b = self.pop_column_of(self.arith_types())?;
+                let a = self.pop_column_of(self.arith_types())?;
+                Ty::Column(self.arith(a, b)?)
+            },
+            Op::ScalarSubquery(code) => {
+                validate_tables(code, self.tables)?;
+                Ty::Scalar(None)
+            }
+        };
+        self.stack.push(res);
+        Ok(())
+    }
+
+    fn column(&self, t: usize, c: usize) -> Result<Datatype, VMError> {
+        let table = self.tables.get(t).ok_or(VMError::TableIndexOutOfRange { idx: t, ntables: self.tables.len() })?;
+        table.get(c).copied().ok_or(VMError::ColumnIndexOutOfRange { idx: c, ncols: table.len() })
+    }
+
+    // The stack has room for the op's pops: validate_tables checked
+    fn pop(&mut self) -> Ty {
+        self.stack.pop().unwrap_or(Ty::Any)
+    }
+
+    fn pop_scalar(&mut self) -> Result<Option<Datatype>, VMError> {
+        match self.pop() {
+            Ty::Scalar(t) => Ok(t),
+            Ty::Any => Ok(None),
+            Ty::Column(_) => Err(self.mismatch("expected a scalar, found a column".to_string()))
+        }
+    }
+
+    fn pop_column(&mut self) -> Result<Option<Datatype>, VMError> {
+        match self.pop() {
+            Ty::Column(t) => Ok(t),
+            Ty::Any => Ok(None),
+            Ty::Scalar(_) => Err(self.mismatch("expected a column, found a scalar".to_string()))
+        }
+    }
+
+    fn pop_scalar_of(&mut self, types: &[Datatype]) -> Result<Option<Datatype>, VMError> {
+        let t = self.pop_scalar()?;
+        self.expect(t, types, "value")
+    }
+
+    fn pop_column_of(&mut self, types: &[Datatype]) -> Result<Option<Datatype>, VMError> {
+        let t = self.pop_column()?;
+        self.expect(t, types, "column")
+    }
+
+    fn expect(&self, t: Option<Datatype>, types: &[Datatype], what: &str) -> Result<Option<Datatype>, VMError> {
+        match t {
+            Some(t) if !types.contains(&t) => {
+                let names: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+                Err(self.mismatch(format!("expected a {} {}, found a {} {}", names.join(" or "), what, t, what)))
+            },
+            t => Ok(t)
+        }
+    }
+
+    // Whether a filter on a `col` column can take a `val` value; a Categorical column is
+    // filtered by its strings
+    fn comparable(&self, col: Option<Datatype>, val: Option<Datatype>) -> Result<(), VMError> {
+        match (col, val) {
+            (Some(Datatype::Categorical), Some(Datatype::Str)) => Ok(()),
+            (Some(a), Some(b)) if a != b => Err(self.mismatch(format!("can't filter a {} column on a {} value", a, b))),
+            _ => Ok(())
+        }
+    }
+
+    // Ints add, subtract and multiply as Ints; anything else is a Num (see Arith)
+    // What the arithmetic ops take: times add and subtract (see Column::arith), but don't
+    // multiply or divide
+    fn arith_types(&self) -> &'static [Datatype] {
+        use Datatype::*;
+        match self.op {
+            Op::AddVs | Op::SubVs | Op::AddVv | Op::SubVv => &[Num, Int, Duration, Timestamp],
+            _ => &[Num, Int]
+        }
+    }
+
+    fn arith(&self, a: Option<Datatype>, b: Option<Datatype>) -> Result<Option<Datatype>, VMError> {
+        use Datatype::*;
+        let add = matches!(self.op, Op::AddVs | Op::AddVv);
+        Ok(match (a, b, self.op) {
+            (Some(Timestamp), Some(Duration), _) => Some(Timestamp),
+            (Some(Duration), Some(Timestamp), _) if add => Some(Timestamp),
+            (Some(Timestamp), Some(Timestamp), _) if !add => Some(Duration),
+            (Some(Duration), Some(Duration), _) => Some(Duration),
+            (Some(a @ (Timestamp | Duration)), Some(b), _) | (Some(a), Some(b @ (Timestamp | Duration)), _) =>
+                return Err(self.mismatch(format!("can't {} {} and {} values", if add { "add" } else { "subtract" }, a, b))),
+            (Some(Int), Some(Int), Op::DivVs | Op::DivVv) => Some(Num),
+            (Some(Int), Some(Int), _) => Some(Int),
+            (Some(Num), _, _) | (_, Some(Num), _) => Some(Num),
+            _ => None
+        })
+    }
+
+    fn mismatch(&self, what: String) -> VMError {
+        VMError::TypeError(format!("{} at {}: {}", self.op.mnemonic(), self.ip, what))
+    }
+}
+
+fn dtype_of(ty: Ty) -> Option<Datatype> {
+    match ty {
+        Ty::Scalar(t) | Ty::Column(t) => t,
+        Ty::Any => None
+    }
+}
+
+fn type_of(s: &Scalar) -> Option<Datatype> {
+    Some(match s {
+        Scalar::Bool(_) => Datatype::Bool,
+        Scalar::Num(_) => Datatype::Num,
+        Scalar::Int(_) => Datatype::Int,
+        Scalar::Str(_) => Datatype::Str,
+        Scalar::Entity(_) => Datatype::Entity,
+        Scalar::Duration(_) => Datatype::Duration,
+        Scalar::Timestamp(_) => Datatype::Timestamp,
+        Scalar::Point(_) => Datatype::Point,
+        Scalar::Ipv4(_) => Datatype::Ipv4,
+        Scalar::Ipv6(_) => Datatype::Ipv6,
+        Scalar::Json(_) => Datatype::Json,
+        Scalar::Record(_) => Datatype::Record,
+        Scalar::Vector(_) => Datatype::Vector,
+        Scalar::List(_) => Datatype::List,
+        Scalar::Null => return None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Column;
+    use crate::table::Table;
+    use crate::vm::VM;
+
+    fn lit(x: f64) -> Op {
+        Op::Lit(Scalar::Num(x))
+    }
+
+    #[test]
+    fn well_formed_programs_pass() {
+        let schema = [Datatype::Num, Datatype::Entity, Datatype::Str];
+        let code = vec![
+            Op::Col(0, 0), lit(2.0), Op::FilterGt, Op::Col(0, 2), Op::Lit(Scalar::Str("a".to_string())), Op::FilterEq, Op::And,
+            Op::Col(0, 1), Op::Select(1), Op::ScalarSubquery(vec![Op::Col(0, 0), Op::Sum]), Op::Lit(Scalar::Null), Op::FilterEq
+        ];
+        validate(&code, &schema).unwrap();
+        // and they run
+        let table = Table::from_columns(vec![Column::from(vec![1.0, 3.0]), Column::from(vec![7u64, 8]), Column::from(vec!["a", "a"])]).unwrap();
+        let mut vm = VM::new(table);
+        vm.run(code).unwrap();
+        validate(&[Op::Col(0, 1), Op::GroupBy(2), Op::Count, Op::ColByName("x".to_string()), Op::Lit(Scalar::Int(1)), Op::AddVs], &schema).unwrap();
+    }
+
+    #[test]
+    fn malformed_programs_are_turned_away() {
+        let schema = [Datatype::Num, Datatype::Str, Datatype::Bool, Datatype::Point, Datatype::List, Datatype::Json];
+        assert!(matches!(validate(&[Op::Col(0, 0), Op::FilterEq], &schema), Err(VMError::StackUnderflow { ip: 1, pops: 2, depth: 1 })));
+        assert!(matches!(validate(&[Op::Col(0, 6)], &schema), Err(VMError::ColumnIndexOutOfRange { idx: 6, ncols: 6 })));
+        assert!(matches!(validate(&[Op::Col(1, 0)], &schema), Err(VMError::TableIndexOutOfRange { idx: 1, ntables: 1 })));
+        assert!(matches!(validate(&[Op::Col(0, 0), Op::GroupBy(7)], &schema), Err(VMError::ColumnIndexOutOfRange { idx: 7, ncols: 6 })));
+        // inside a subquery too
+        assert!(matches!(validate(&[Op::ScalarSubquery(vec![Op::Sum])], &schema), Err(VMError::StackUnderflow { ip: 0, .. })));
+        let type_errors = [
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Str("a".to_string())), Op::FilterEq],
+            vec![Op::Col(0, 1), lit(1.0), Op::FilterLt],
+            vec![lit(1.0), lit(2.0), Op::FilterLt],
+            vec![Op::Col(0, 0), Op::Col(0, 0), Op::Select(1)],
+            vec![Op::Col(0, 1), lit(1.0), Op::AddVs],
+            vec![Op::Col(0, 0), Op::Col(0, 1), Op::FilterIn],
+            vec![Op::Col(0, 0), Op::Sum, Op::Not],
+            vec![Op::Col(0, 0), Op::Count, Op::Not],
+            vec![Op::Col(0, 1), Op::Sum],
+            vec![Op::Col(0, 2), Op::Mean],
+            vec![Op::Col(0, 3), Op::Max],
+            vec![Op::Col(0, 3), Op::Col(0, 3), Op::FilterIn],
+            vec![Op::Col(0, 4), Op::Col(0, 4), Op::FilterIn],
+            vec![Op::Col(0, 2), Op::Col(0, 0), Op::Col(0, 1), Op::IfElse],
+            vec![Op::Col(0, 5), Op::JsonExtract("a".to_string(), Datatype::Point)]
+        ];
+        for code in type_errors {
+            assert!(matches!(validate(&code, &schema), Err(VMError::TypeError(_))), "{:?}", code);
+        }
+    }
+}
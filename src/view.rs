@@ -0,0 +1,268 @@
+// Materialized views: a named query over a SharedTable, with its result kept around and
+// brought up to date on refresh. While a table only grows by appends, the program is run
+// over just the rows appended since:
+//
+// - a row-by-row program (see Op::is_row_local) adds its output to the end of the stored
+//   result
+// - a row-by-row program grouped and then aggregated (Op::GroupBy then Sum, Count, Min or
+//   Max) merges each new group's aggregate into the stored one for its key, or adds the
+//   group if it's new
+//
+// Anything else, or a refresh after rows were deleted or updated, is recomputed from scratch.
+// So is a program with a subquery, whose value changes as rows are added, or a UDF, which
+// needn't give the same output twice.
+
+use crate::aggregate::{self, Agg};
+use crate::column::Column;
+use crate::errors::VMError;
+use crate::list;
+use crate::opcode::Op;
+use crate::result::ResultSet;
+use crate::shared::SharedTable;
+
+use std::time::Instant;
+
+pub struct MaterializedView {
+    pub name: String,
+    code: Vec<Op>,
+    names: Vec<String>,     // for the result columns; "#i" for the i'th stack value otherwise
+    maintenance: Maintenance,
+    data: ResultSet,
+    base_version: u64,      // the table version `data` reflects
+    base_rows: usize,       // and its row count
+    refreshed_at: Instant
+}
+
+// How up to date a view is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewStatus {
+    pub base_version: u64,
+    pub table_version: u64,
+    pub versions_behind: u64,
+    pub refreshed_at: Instant,
+    pub incremental: bool
+}
+
+// How a refresh after appends brings the stored result up to date
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Maintenance {
+    Append,         // the new rows' output goes on the end
+    Regroup(Agg),   // the new rows' groups are merged into the stored ones
+    Recompute
+}
+
+impl Maintenance {
+    fn of(code: &[Op]) -> Maintenance {
+        let row_local = |code: &[Op]| code.iter().all(|op| op.is_row_local() && !matches!(op, Op::ScalarSubquery(_) | Op::CallUdf(..)));
+        if row_local(code) {
+            return Maintenance::Append;
+        }
+        // one column of values, then GroupBy and an aggregate that can be merged
+        let (values, rest) = code.split_at(code.len().saturating_sub(2));
+        let agg = match rest {
+            [Op::GroupBy(_), Op::Sum] => Agg::Sum,
+            [Op::GroupBy(_), Op::Count] => Agg::Count,
+            [Op::GroupBy(_), Op::Min] => Agg::Min,
+            [Op::GroupBy(_), Op::Max] => Agg::Max,
+            _ => return Maintenance::Recompute
+        };
+        let depth = values.iter().try_fold(0usize, |depth, op| {
+            let (pops, pushes) = op.stack_effect();
+            depth.checked_sub(pops).map(|d| d + pushes)
+        });
+        match depth {
+            Some(1) if row_local(values) => Maintenance::Regroup(agg),
+            _ => Maintenance::Recompute
+        }
+    }
+}
+
+impl MaterializedView {
+    // Define the view and compute it over the table as it is now
+    pub fn new(name: &str, code: Vec<Op>, names: &[&str], table: &SharedTable) -> Result<Self, VMError> {
+        let version = table.snapshot();
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let data = version.query(&code, None, &names)?;
+        Ok(MaterializedView {
+            name: name.to_string(), maintenance: Maintenance::of(&code), code, names, data,
+            base_version: version.number, base_rows: version.rows(), refreshed_at: Instant::now()
+        })
+    }
+
+    pub fn data(&self) -> &ResultSet {
+        &self.data
+    }
+
+    pub fn status(&self, table: &SharedTable) -> ViewStatus {
+        let table_version = table.snapshot().number;
+        ViewStatus {
+            base_version: self.base_version, table_version, versions_behind: table_version.saturating_sub(self.base_version),
+            refreshed_at: self.refreshed_at, incremental: self.maintenance != Maintenance::Recompute
+        }
+    }
+
+    // Bring the view up to date with the table's latest version
+    pub fn refresh(&mut self, table: &SharedTable) -> Result<(), VMError> {
+        let version = table.snapshot();
+        if version.number == self.base_version {
+            return Ok(());
+        }
+        let rows = version.rows();
+        // rows deleted or updated since make the stored result unreliable
+        let appended = || version.query(&self.code, Some((self.base_rows, rows - self.base_rows)), &self.names);
+        self.data = match self.maintenance {
+            _ if version.rewritten > self.base_version => version.query(&self.code, None, &self.names)?,
+            Maintenance::Append => {
+                let mut data = ResultSet::new();
+                for ((name, old), new) in self.data.iter().zip(appended()?.columns) {
+                    data.push(name, Column::concat(&[old.clone(), new])?);
+                }
+                data
+            },
+            Maintenance::Regroup(agg) => regroup(&self.data, appended()?, agg)?,
+            Maintenance::Recompute => version.query(&self.code, None, &self.names)?
+        };
+        self.base_version = version.number;
+        self.base_rows = rows;
+        self.refreshed_at = Instant::now();
+        Ok(())
+    }
+}
+
+// The stored groups' keys and aggregates with the new rows' merged in: each key's aggregates
+// are aggregated again (counts summed), and keys stay in order of first appearance
+fn regroup(old: &ResultSet, new: ResultSet, agg: Agg) -> Result<ResultSet, VMError> {
+    let (keys, partials) = match (old.columns.as_slice(), new.columns.as_slice()) {
+        ([keys, aggs], [new_keys, new_aggs]) => (
+            Column::concat(&[keys.clone(), new_keys.clone()])?,
+            Column::concat(&[aggs.clone(), new_aggs.clone()])?
+        ),
+        _ => return Err(VMError::TypeError(format!("A grouped view has keys and aggregates, found {} columns", new.columns.len())))
+    };
+    let (keys, lists) = list::group_by(&keys, &partials)?;
+    let merged = aggregate::reduce_lists(&lists, if agg == Agg::Count { Agg::Sum } else { agg })?;
+    Ok(ResultSet { names: old.names.clone(), columns: vec![keys, merged] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column::Scalar;
+
+    fn rows(first: u64, n: u64) -> ResultSet {
+        let mut rs = ResultSet::new();
+        rs.push("id", Column::from((first .. first + n).collect::<Vec<_>>()));
+        rs.push("x", Column::from((first .. first + n).map(|i| (i % 3) as f64).collect::<Vec<_>>()));
+        rs
+    }
+
+    fn append(table: &SharedTable, first: u64, n: u64) -> u64 {
+        let mut writer = table.write();
+        writer.append(rows(first, n)).unwrap();
+        writer.commit().unwrap()
+    }
+
+    // ids where x = 0
+    fn zeros() -> Vec<Op> {
+        vec![Op::Col(0, 1), Op::Lit(Scalar::Num(0.0)), Op::FilterEq, Op::Col(0, 0), Op::Select(1)]
+    }
+
+    #[test]
+    fn goes_stale_on_commit_and_fresh_on_refresh() {
+        let table = SharedTable::new(rows(0, 10)).unwrap();
+        let mut view = MaterializedView::new("zeros", zeros(), &["id"], &table).unwrap();
+        let fresh = view.status(&table);
+        assert_eq!((fresh.base_version, fresh.table_version, fresh.versions_behind), (0, 0, 0));
+
+        append(&table, 10, 5);
+        append(&table, 15, 5);
+        let stale = view.status(&table);
+        assert_eq!((stale.base_version, stale.table_version, stale.versions_behind), (0, 2, 2));
+        assert_eq!(view.data().rows(), 4);
+
+        view.refresh(&table).unwrap();
+        let status = view.status(&table);
+        assert_eq!((status.base_version, status.versions_behind), (2, 0));
+        assert!(status.refreshed_at >= fresh.refreshed_at);
+        assert_eq!(view.data().column("id").unwrap(), &Column::from(vec![0u64, 3, 6, 9, 12, 15, 18]));
+    }
+
+    #[test]
+    fn incremental_refreshes_match_recomputing() {
+        let table = SharedTable::new(rows(0, 10)).unwrap();
+        let mut view = MaterializedView::new("zeros", zeros(), &["id"], &table).unwrap();
+        assert!(view.status(&table).incremental);
+        for i in 0 .. 5 {
+            append(&table, 10 + i * 7, 7);
+            view.refresh(&table).unwrap();
+        }
+        let recomputed = MaterializedView::new("again", zeros(), &["id"], &table).unwrap();
+        assert_eq!(view.data(), recomputed.data());
+        // refreshing an up-to-date view changes nothing
+        view.refresh(&table).unwrap();
+        assert_eq!(view.data(), recomputed.data());
+    }
+
+    #[test]
+    fn other_programs_are_recomputed() {
+        // ids whose x is among the x values of ids 0 and 1 - FilterIn looks across rows
+        let code = vec![
+            Op::Col(0, 1),
+            Op::Col(0, 0), Op::Lit(Scalar::Entity(1)), Op::FilterEq, Op::Col(0, 1), Op::Select(1),
+            Op::FilterIn, Op::Col(0, 0), Op::Select(1)
+        ];
+        let table = SharedTable::new(rows(0, 6)).unwrap();
+        let mut view = MaterializedView::new("ones", code.clone(), &[], &table).unwrap();
+        assert!(!view.status(&table).incremental);
+        append(&table, 6, 6);
+        view.refresh(&table).unwrap();
+        assert_eq!(view.data().column("#0").unwrap(), &Column::from(vec![1u64, 4, 7, 10]));
+        assert_eq!(view.data(), MaterializedView::new("again", code, &[], &table).unwrap().data());
+    }
+
+    #[test]
+    fn grouped_aggregates_are_merged() {
+        // by x: the count of rows, the largest id, and the sum of x over rows with x > 0
+        let positive = vec![Op::Col(0, 1), Op::Lit(Scalar::Num(0.0)), Op::FilterGt, Op::Col(0, 1), Op::Select(1)];
+        let programs = [
+            vec![Op::Col(0, 0), Op::GroupBy(1), Op::Count],
+            vec![Op::Col(0, 0), Op::GroupBy(1), Op::Max],
+            [positive, vec![Op::GroupBy(1), Op::Sum]].concat()
+        ];
+        for code in programs {
+            // rows 0 and 1 have no x = 2 yet
+            let table = SharedTable::new(rows(0, 2)).unwrap();
+            let mut view = MaterializedView::new("by_x", code.clone(), &["x", "agg"], &table).unwrap();
+            assert!(view.status(&table).incremental);
+            for i in 0 .. 4 {
+                append(&table, 2 + i * 5, 5);
+                view.refresh(&table).unwrap();
+            }
+            assert_eq!(view.data(), MaterializedView::new("again", code, &["x", "agg"], &table).unwrap().data());
+        }
+        // a mean can't be merged from means
+        let table = SharedTable::new(rows(0, 6)).unwrap();
+        let view = MaterializedView::new("mean", vec![Op::Col(0, 1), Op::GroupBy(1), Op::Mean], &["x", "agg"], &table).unwrap();
+        assert!(!view.status(&table).incremental);
+    }
+
+    #[test]
+    fn subqueries_are_recomputed() {
+        // ids whose x is the largest x, which an append can change
+        let code = vec![Op::Col(0, 1), Op::ScalarSubquery(vec![Op::Col(0, 1), Op::Max]), Op::FilterEq, Op::Col(0, 0), Op::Select(1)];
+        let table = SharedTable::new(rows(0, 2)).unwrap();
+        let mut view = MaterializedView::new("largest", code.clone(), &["id"], &table).unwrap();
+        assert!(!view.status(&table).incremental);
+        append(&table, 2, 1);
+        view.refresh(&table).unwrap();
+        assert_eq!(view.data().column("id").unwrap(), &Column::from(vec![2u64]));
+        assert_eq!(view.data(), MaterializedView::new("again", code, &["id"], &table).unwrap().data());
+    }
+
+    #[test]
+    fn results_must_be_columns() {
+        let table = SharedTable::new(rows(0, 3)).unwrap();
+        let res = MaterializedView::new("lit", vec![Op::Lit(Scalar::Num(1.0))], &[], &table);
+        assert!(matches!(res, Err(VMError::TypeError(_))));
+    }
+}
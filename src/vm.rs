@@ -0,0 +1,1848 @@
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use crate::aggregate::{self, Agg};
+use crate::cancel::CancelToken;
+use crate::column::*;
+use crate::compare;
+use crate::conditional::{self, Branch};
+use crate::geo;
+use crate::encoding;
+use crate::ip;
+use crate::join;
+use crate::list;
+use crate::memory::{Consumer, MemoryManager, Reservation};
+use crate::normalize;
+use crate::nulls::{self, NullSemantics};
+use crate::core::prelude::*;
+use crate::opcode::Op;
+use crate::errors::VMError;
+use crate::schema::Schema;
+use crate::table::{self, Table};
+use crate::catalog::Catalog;
+use crate::selection::{FilterPlan, Selection};
+use crate::explain::OpProfile;
+#[cfg(feature = "gpu")]
+use crate::gpu::Gpu;
+#[cfg(feature = "std")]
+use crate::metrics::{Attached, Metrics};
+use crate::snapshot::{ColumnMeta, ValueMeta, VMSnapshot};
+use crate::stats::ExecStats;
+use crate::trace::TraceRecorder;
+use crate::tracer::Tracer;
+#[cfg(feature = "std")]
+use crate::tracer::PrintTracer;
+use crate::udf::{self, Accumulator, Udf};
+use crate::vector;
+
+// TODO
+// - wrap Scalar::Str in rc
+// - profile, try to figure out how bad rc overhead is
+// - consider alternatives to rc, most likely unsafe moving of ptrs, or implementing your own Heap
+// - ... all the language features ...
+
+#[derive(Debug)]
+pub enum Value {
+    // A value on the Stack.
+    Scalar(Scalar),
+    ColumnRef(Arc<Column>),
+    Slot(ColumnSlot),
+    // The rows of a column picked out by a Select, not yet gathered (see VM::pop_column)
+    View(Arc<Column>, Selection)
+}
+
+// An index into the VM's own column store, pushed by Op::Col in ColumnMode::Slots.
+// Only meaningful while the VM is running: any slots left on the stack when run() returns
+// are turned back into ColumnRefs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnSlot(pub usize);
+
+// How Op::Col puts a loaded column on the stack. See benches/rc_overhead.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnMode {
+    Rc,     // clone the column's Arc (default)
+    Slots   // push the column's index and count the borrow; no refcount traffic
+}
+
+// An instruction run by VM::step, and the stack it left
+#[derive(Debug)]
+pub struct Step<'a> {
+    pub ip: usize,
+    pub op: &'a Op,
+    pub stack: &'a [Value]
+}
+
+// Where VM::resume stopped: before the instruction at a breakpoint, or at the end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(usize),
+    Finished
+}
+
+// A popped column operand - shared ownership, a borrow from the column store,
+// or a view that hasn't been gathered yet
+enum ColumnHandle {
+    Shared(Arc<Column>),
+    Slot(usize),
+    View(Arc<Column>, Selection)
+}
+
+// A popped operand that may be either kind of value
+enum Operand {
+    Scalar(Scalar),
+    Column(ColumnHandle)
+}
+
+// A table of the catalog a VM was loaded with: its columns are those of the VM from `first` on
+#[derive(Debug)]
+struct LoadedTable {
+    name: String,
+    schema: Schema,
+    first: usize
+}
+
+pub struct VM {
+    code: Vec<Op>,
+    ip: usize,
+    stack: Vec<Value>,
+    columns: Vec<Arc<Column>>,     // every table's columns, one table after another
+    tables: Arc<Vec<LoadedTable>>,
+    mode: ColumnMode,
+    borrows: Vec<usize>,    // per column: how many Slots referring to it are on the stack
+    window: Option<(usize, usize)>,     // (offset, len): the rows Op::Col loads, if not all of them
+    tracer: Option<Box<dyn Tracer + Send>>,
+    udfs: Vec<(String, Udf)>,   // indexed by function id
+    shared: Vec<Option<Value>>, // indexed by sub-plan id, for Op::Shared; each a scalar or a ColumnRef
+    cancel: Option<CancelToken>,
+    #[cfg(feature = "std")]
+    timeout: Option<Duration>,
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,  // of the current run, from `timeout`
+    trace: Option<TraceRecorder>,
+    #[cfg(feature = "std")]
+    metrics: Option<Attached>,
+    profile: Option<Vec<OpProfile>>,
+    filter_plan: Option<FilterPlan>,  // of the op being run, for its OpProfile
+    nulls: NullSemantics,
+    nfc: bool,
+    memory: Option<Arc<MemoryManager>>,
+    held: Option<Reservation>,  // the current run's intermediate results, against `memory`
+    breakpoints: Vec<usize>,    // ips resume() stops before
+    stats: ExecStats,           // of the current or last run
+    #[cfg(feature = "gpu")]
+    gpu: Option<Arc<Gpu>>
+}
+
+// so what SHOULD be done with the col reference when pushing on stack
+// if we wanted to avoid the overhead of RC?
+// Op::Col can "move" ownership of the ref from `self.columns` to `self.stack` theoretically,
+// but unless we core::mem::take the val out of the vec (or remove it, and shift the rest of the elems)
+// a ref will also remain in the vec too which Rust considers invalid
+// we *think* that only one of these will be used at a time -- because of the serial nature of
+// push/pop off the stack -- and because 1) only Op::Col will refer into `self.columns`, other opcodes
+// (or their helpers) never work with column indices directly, they just pop them off the stack and
+// 2) a correct compiler will never generate two Op::Col(t, i) for the same column, without some other
+// opcode in between that pops that ColumnRef off the stack. But the compiler doesn't know that.
+// so I think we have to use Rc here, or unsafe. (Arc, now, so loaded columns can be shared
+// between threads - see VM::with_shared_columns.)
+// profile and see how big the overhead of refcounting is -- likely not that bad, if it's amortized
+//  over columns.
+
+
+impl VM {
+    // A VM over `table`'s columns, the only table of its catalog
+    pub fn new(table: Table) -> Self {
+        VM::with_column_mode(table, ColumnMode::Rc)
+    }
+
+    pub fn with_column_mode(table: Table, mode: ColumnMode) -> Self {
+        VM::with_catalog(Catalog::from(table), mode)
+    }
+
+    // A VM over every table in `catalog`, for Op::Col(table, column) to load from
+    pub fn with_catalog(catalog: Catalog, mode: ColumnMode) -> Self {
+        let mut tables = Vec::with_capacity(catalog.len());
+        let mut rcs: Vec<Arc<Column>> = Vec::new();
+        for (name, table) in catalog.into_tables() {
+            let (schema, columns) = table.into_parts();
+            tables.push(LoadedTable { name, schema, first: rcs.len() });
+            // take ownership of columns and wrap them in arcs
+            // sorted or repetitive columns get run-length encoded on the way in
+            rcs.extend(columns.into_iter().map(|c| Arc::new(c.auto_encode())));
+        }
+        VM::unchecked(tables, rcs, mode)
+    }
+
+    // A VM over columns loaded once and shared, e.g. by a server running queries on many
+    // threads, each with a VM of its own. The columns are used as they are, not re-encoded,
+    // and make up one table, named as Catalog::from and Table::from_columns would.
+    pub fn with_shared_columns(rcs: Vec<Arc<Column>>, mode: ColumnMode) -> Result<Self, VMError> {
+        table::check_shape(rcs.iter().map(|c| c.as_ref()))?;
+        let schema = table::default_schema(rcs.iter().map(|c| c.as_ref()));
+        Ok(VM::unchecked(vec![LoadedTable { name: "t0".to_string(), schema, first: 0 }], rcs, mode))
+    }
+
+    fn unchecked(tables: Vec<LoadedTable>, rcs: Vec<Arc<Column>>, mode: ColumnMode) -> Self {
+        let borrows = vec![0; rcs.len()];
+        VM {
+            code: Vec::new(), ip: 0, stack: Vec::new(), columns: rcs, tables: Arc::new(tables), mode, borrows, window: None, tracer: None,
+            udfs: Vec::new(), shared: Vec::new(), cancel: None, trace: None, profile: None, filter_plan: None, nulls: NullSemantics::default(), nfc: false, memory: None, held: None, breakpoints: Vec::new(), stats: ExecStats::default(),
+            #[cfg(feature = "std")]
+            timeout: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            #[cfg(feature = "std")]
+            metrics: None,
+            #[cfg(feature = "gpu")]
+            gpu: None
+        }
+    }
+
+    // Call `tracer` around each instruction this VM runs, its subqueries' aside. There's
+    // none by default, and a VM runs silently.
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer + Send>>) {
+        self.tracer = tracer;
+    }
+
+    // Print the stack and each op as it executes, with a PrintTracer. set_verbose(false) takes
+    // away whatever tracer there is, one from set_tracer included. There's nowhere to print
+    // without std.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        #[cfg(feature = "std")]
+        {
+            self.tracer = match verbose {
+                true => Some(Box::new(PrintTracer)),
+                false => None
+            };
+        }
+        #[cfg(not(feature = "std"))]
+        if !verbose {
+            self.tracer = None;
+        }
+    }
+
+    // Make `f` callable by Op::CallUdf, under the id returned. Registering a name again
+    // replaces the function, keeping its id.
+    pub fn register_udf<F>(&mut self, name: &str, f: F) -> usize
+        where F: Fn(&[Scalar]) -> Scalar + Send + Sync + 'static {
+        self.register(name, Udf::Scalar(Arc::new(f)))
+    }
+
+    // Like register_udf, for a function that works a column at a time
+    pub fn register_batch_udf<F>(&mut self, name: &str, f: F) -> usize
+        where F: Fn(&[&Column]) -> Result<Column, VMError> + Send + Sync + 'static {
+        self.register(name, Udf::Batch(Arc::new(f)))
+    }
+
+    // Register an aggregate function for Op::CallUdaf; `new` makes a fresh accumulator
+    pub fn register_udaf<F>(&mut self, name: &str, new: F) -> usize
+        where F: Fn() -> Box<dyn Accumulator> + Send + Sync + 'static {
+        self.register(name, Udf::Aggregate(Arc::new(new)))
+    }
+
+    fn register(&mut self, name: &str, udf: Udf) -> usize {
+        match self.udf_id(name) {
+            Some(id) => {
+                self.udfs[id].1 = udf;
+                id
+            },
+            None => {
+                self.udfs.push((name.to_string(), udf));
+                self.udfs.len() - 1
+            }
+        }
+    }
+
+    pub fn udf_id(&self, name: &str) -> Option<usize> {
+        self.udfs.iter().position(|(n, _)| n == name)
+    }
+
+    // The values Op::Shared pushes, by sub-plan id; None for those not computed
+    pub(crate) fn set_shared(&mut self, shared: Vec<Option<Value>>) {
+        self.shared = shared;
+    }
+
+    pub(crate) fn take_shared(&mut self) -> Vec<Option<Value>> {
+        core::mem::take(&mut self.shared)
+    }
+
+    // Another reference to a value left by a finished run, which is a scalar or a ColumnRef
+    fn share(v: &Value) -> Value {
+        match v {
+            Value::Scalar(s) => Value::Scalar(s.clone()),
+            Value::ColumnRef(c) => Value::ColumnRef(c.clone()),
+            _ => unreachable!("slots and views are released when a run ends")
+        }
+    }
+
+    // Give up on a run with VMError::Cancelled once `token` is cancelled
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) {
+        self.cancel = token;
+    }
+
+    // How nulls compare in FilterEq, FilterSelect and FilterIn, this VM's subqueries included
+    pub fn set_null_semantics(&mut self, nulls: NullSemantics) {
+        self.nulls = nulls;
+    }
+
+    // Hold the columns ops make along the way to `memory`'s budget, failing with
+    // VMError::OutOfMemory when they'd go over it. Loaded columns aren't counted.
+    pub fn set_memory_manager(&mut self, memory: Arc<MemoryManager>) {
+        self.memory = Some(memory);
+    }
+
+    // Run FilterEq on `gpu` for the columns it takes (see Gpu::offloads), this VM's
+    // subqueries included
+    #[cfg(feature = "gpu")]
+    pub fn set_gpu(&mut self, gpu: Arc<Gpu>) {
+        self.gpu = Some(gpu);
+    }
+
+    // Compare strings by their NFC forms in FilterEq, FilterSelect and FilterIn, for columns
+    // that weren't normalized as they were loaded (see normalize.rs)
+    pub fn set_nfc(&mut self, nfc: bool) {
+        self.nfc = nfc;
+    }
+
+    // Give up on any run that takes longer than `timeout`, with VMError::TimedOut
+    #[cfg(feature = "std")]
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // Between instructions: has the query been cancelled, or run out of time?
+    fn check_interrupt(&self) -> Result<(), VMError> {
+        if self.cancel.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Err(VMError::Cancelled);
+        }
+        #[cfg(feature = "std")]
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(VMError::TimedOut);
+        }
+        Ok(())
+    }
+
+    // The id Op::Col knows the loaded table `name` by
+    pub fn table_id(&self, name: &str) -> Option<usize> {
+        self.tables.iter().position(|t| t.name == name)
+    }
+
+    // The names and types of the columns of table `table`
+    pub fn schema(&self, table: usize) -> Option<&Schema> {
+        self.tables.get(table).map(|t| &t.schema)
+    }
+
+    // The loaded columns, every table's in turn, for handing to VM::with_shared_columns
+    pub fn shared_columns(&self) -> Vec<Arc<Column>> {
+        self.columns.clone()
+    }
+
+    // How many Slots referring to column `idx` are currently on the stack
+    pub fn borrows(&self, idx: usize) -> usize {
+        self.borrows[idx]
+    }
+
+    // The column a stack value refers to, if any. Views are only ever on the stack
+    // mid-run, so outside of run() every column value resolves.
+    pub fn column_of<'a>(&'a self, v: &'a Value) -> Option<&'a Column> {
+        VM::as_column(v, &self.columns)
+    }
+
+    fn as_column<'a>(v: &'a Value, columns: &'a [Arc<Column>]) -> Option<&'a Column> {
+        match v {
+            Value::Scalar(_) | Value::View(..) => None,
+            Value::ColumnRef(c) => Some(c),
+            Value::Slot(ColumnSlot(idx)) => Some(&columns[*idx])
+        }
+    }
+
+    fn gather(base: &Column, sel: Selection) -> Column {
+        base.select(&BoolColumn::from_selection(sel))
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    // Rows in the longest loaded column
+    pub fn rows(&self) -> usize {
+        self.columns.iter().map(|c| c.len()).max().unwrap_or(0)
+    }
+
+    // Make Op::Col load only rows offset .. offset + len of each column (clamped to its
+    // length), so a row-by-row program can be run over the data a range at a time; None
+    // goes back to whole columns. Windowed columns are pushed as shared slices, whatever the
+    // ColumnMode.
+    pub fn set_window(&mut self, window: Option<(usize, usize)>) {
+        self.window = window;
+    }
+
+    // Remove and return everything left on the stack, e.g. the results of the last run
+    pub fn take_stack(&mut self) -> Vec<Value> {
+        core::mem::take(&mut self.stack)
+    }
+
+    // Remove and return the one value a single-output program leaves. Anything else on the
+    // stack is a TypeError, and stays there.
+    pub fn take_result(&mut self) -> Result<Value, VMError> {
+        match self.stack.len() {
+            1 => Ok(self.stack.remove(0)),
+            n => Err(VMError::TypeError(format!("Expected one result on the stack, found {}", n)))
+        }
+    }
+
+    // The columns left on the stack, bottom first, for a program whose every output is a
+    // column. Columns nothing else holds are moved out rather than copied.
+    pub fn into_columns(mut self) -> Result<Vec<Column>, VMError> {
+        let stack = self.take_stack();
+        drop(self);
+        stack.into_iter().map(|v| match v {
+            Value::ColumnRef(c) => Ok(Arc::try_unwrap(c).unwrap_or_else(|c| c.as_ref().clone())),
+            v => Err(VMError::TypeError(format!("Query results must be columns, found: {:?}", v)))
+        }).collect()
+    }
+
+    // Total bytes held by the loaded columns; intermediate results on the stack aren't included.
+    pub fn memory_usage(&self) -> usize {
+        self.columns.iter().map(|c| c.memory_usage()).sum()
+    }
+
+    // Start recording a trace of every instruction executed from now on.
+    pub fn record_trace(&mut self) {
+        self.trace = Some(TraceRecorder::new());
+    }
+
+    pub fn take_trace(&mut self) -> Option<TraceRecorder> {
+        self.trace.take()
+    }
+
+    // Collect an OpProfile for every instruction executed from now on (see explain::explain_analyze).
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Vec::new());
+    }
+
+    pub fn take_profile(&mut self) -> Option<Vec<OpProfile>> {
+        self.profile.take()
+    }
+
+    // Rows flowing through the top `n` stack values: the longest column among them,
+    // or for masks produced by filters, the number of rows that passed.
+    fn top_rows(stack: &[Value], columns: &[Arc<Column>], n: usize, masks_as_counts: bool) -> usize {
+        stack[stack.len().saturating_sub(n) ..].iter().map(|v| match (v, VM::as_column(v, columns)) {
+            (_, Some(Column::Bool(b))) if masks_as_counts => b.count_ones(),
+            (_, Some(c)) => c.len(),
+            (Value::View(_, sel), None) => sel.count_ones(),
+            (_, None) => 0
+        }).max().unwrap_or(0)
+    }
+
+    // Report query counts, per-op timings etc. to `metrics`, e.g. for a metrics::serve endpoint.
+    #[cfg(feature = "std")]
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(Attached::new(metrics, self.memory_usage()));
+    }
+
+    fn top_fingerprints(stack: &[Value], columns: &[Arc<Column>], n: usize) -> Vec<u64> {
+        stack[stack.len().saturating_sub(n) ..].iter().map(|v| match (v, VM::as_column(v, columns)) {
+            (_, Some(c)) => c.fingerprint(),
+            (Value::Scalar(s), None) => s.fingerprint(),
+            // must agree with the gathered column, or traces would depend on when views materialize
+            (Value::View(base, sel), None) => VM::gather(base, sel.clone()).fingerprint(),
+            (_, None) => unreachable!()
+        }).collect()
+    }
+
+    pub fn snapshot(&self) -> VMSnapshot {
+        let stack = self.stack.iter().map(|v| match (v, self.column_of(v)) {
+            (_, Some(c)) => ValueMeta::Column(ColumnMeta::of(c)),
+            (Value::Scalar(s), None) => ValueMeta::Scalar(s.clone()),
+            (Value::View(base, sel), None) => ValueMeta::Column(ColumnMeta {
+                kind: "View", dtype: base.datatype(), len: sel.count_ones(), bytes: sel.memory_usage()
+            }),
+            (_, None) => unreachable!()
+        }).collect();
+        VMSnapshot {
+            ip: self.ip,
+            last_op: self.ip.checked_sub(1).and_then(|i| self.code.get(i)).cloned(),
+            code_len: self.code.len(),
+            stack,
+            columns: self.columns.iter().map(|c| ColumnMeta::of(c)).collect()
+        }
+    }
+
+    // Bytes of the columns on the stack that ops made, rather than loaded
+    fn intermediate_bytes(stack: &[Value], columns: &[Arc<Column>]) -> usize {
+        let made = |c: &Arc<Column>| !columns.iter().any(|loaded| Arc::ptr_eq(loaded, c));
+        stack.iter().map(|v| match v {
+            Value::ColumnRef(c) if made(c) => c.memory_usage(),
+            Value::View(base, sel) if made(base) => base.memory_usage() + sel.memory_usage(),
+            Value::View(_, sel) => sel.memory_usage(),
+            _ => 0
+        }).sum()
+    }
+
+    // Row numbers into a column of `rows` rows, from an Entity column of them
+    fn row_numbers(col: &Column, rows: usize) -> Result<Vec<usize>, VMError> {
+        let col = encoding::plain(col);
+        let positions = match col.as_ref() {
+            Column::Entity(c) => c.values(),
+            other => return Err(VMError::TypeError(format!("Expected row numbers in an Entity column, found a {} column", other.datatype())))
+        };
+        match positions.iter().find(|i| **i >= rows as u64) {
+            Some(i) => Err(VMError::RowIndexOutOfRange { idx: *i as usize, nrows: rows }),
+            None => Ok(positions.iter().map(|i| *i as usize).collect())
+        }
+    }
+
+    // An aggregate of a column: a scalar, or for a List column a column of one per list
+    fn aggregate(col: &Column, agg: Agg) -> Result<Value, VMError> {
+        match col {
+            Column::List(lists) => Ok(Value::ColumnRef(Arc::new(aggregate::reduce_lists(lists, agg)?))),
+            col => Ok(Value::Scalar(aggregate::reduce(col, agg)?))
+        }
+    }
+
+    // Associated functions so they can borrow part of self, rather than borrowing all of self as mut
+    fn pop_scalar(stack: &mut Vec<Value>) -> Result<Scalar, VMError> {
+        if let Some(Value::Scalar(s)) = stack.pop() { return Ok(s); }
+        Err(VMError::TypeError("expected a scalar value".to_string()))
+    }
+
+    // Pop a column operand, leaving views as they are - for ops that can work on a view directly
+    fn pop_lazy(stack: &mut Vec<Value>, borrows: &mut [usize]) -> Result<ColumnHandle, VMError> {
+        match stack.pop() {
+            Some(Value::ColumnRef(c)) => Ok(ColumnHandle::Shared(c)),
+            Some(Value::Slot(ColumnSlot(idx))) => {
+                borrows[idx] -= 1;
+                Ok(ColumnHandle::Slot(idx))
+            },
+            Some(Value::View(base, sel)) => Ok(ColumnHandle::View(base, sel)),
+            _ => Err(VMError::TypeError("expected a column value".to_string()))
+        }
+    }
+
+    // Pop a column operand, gathering it if it's a view - for ops that need contiguous data
+    fn pop_column(stack: &mut Vec<Value>, borrows: &mut [usize]) -> Result<ColumnHandle, VMError> {
+        match VM::pop_lazy(stack, borrows)? {
+            ColumnHandle::View(base, sel) => Ok(ColumnHandle::Shared(Arc::new(VM::gather(&base, sel)))),
+            handle => Ok(handle)
+        }
+    }
+
+    fn pop_operand(stack: &mut Vec<Value>, borrows: &mut [usize]) -> Result<Operand, VMError> {
+        match stack.last() {
+            Some(Value::Scalar(_)) => Ok(Operand::Scalar(VM::pop_scalar(stack)?)),
+            _ => Ok(Operand::Column(VM::pop_column(stack, borrows)?))
+        }
+    }
+
+    fn branch<'a>(columns: &'a [Arc<Column>], operand: &'a Operand) -> Branch<'a> {
+        match operand {
+            Operand::Scalar(s) => Branch::Scalar(s),
+            Operand::Column(c) => Branch::Column(VM::resolve(columns, c))
+        }
+    }
+
+    fn resolve<'a>(columns: &'a [Arc<Column>], handle: &'a ColumnHandle) -> &'a Column {
+        match handle {
+            ColumnHandle::Shared(c) => c,
+            ColumnHandle::Slot(idx) => &columns[*idx],
+            ColumnHandle::View(..) => unreachable!("views are gathered by pop_column")
+        }
+    }
+
+    // The rows selected by a boolean column; run-length encoded ones get expanded
+    fn expect_mask(v: &Column) -> Result<Cow<'_, Selection>, VMError> {
+        match v {
+            Column::Bool(inner) => return Ok(Cow::Borrowed(inner.selection())),
+            Column::Rle(inner) => if let Some(sel) = inner.to_selection() {
+                return Ok(Cow::Owned(sel));
+            },
+            _ => {}
+        }
+        Err(VMError::TypeError(format!("Type error: expected a boolean column, found: {:?}", v)))
+    }
+
+    // Turn slots and views left on the stack into ColumnRefs, so results stay valid
+    // after run() returns. This is where the final results get gathered.
+    fn release_values(&mut self) {
+        let columns = &self.columns;
+        self.stack = core::mem::take(&mut self.stack).into_iter().map(|v| match v {
+            Value::Slot(ColumnSlot(idx)) => Value::ColumnRef(columns[idx].clone()),
+            Value::View(base, sel) => Value::ColumnRef(Arc::new(VM::gather(&base, sel))),
+            v => v
+        }).collect();
+        self.borrows.iter_mut().for_each(|b| *b = 0);
+    }
+
+    // Run `code`, and return what's on the stack when it's done: its result. A program leaves
+    // one value per output, in order - a column, or a scalar for an aggregate - and the slots
+    // and views it pushed are resolved, so each is a Scalar or a ColumnRef. The values stay on
+    // the stack too, shared with those returned, until taken with take_stack, take_result or
+    // into_columns.
+    pub fn run(&mut self, code: Vec<Op>) -> Result<Vec<Value>, VMError>  {
+        #[cfg(feature = "std")]
+        let start = Instant::now();
+        let res = self.load(code).and_then(|()| self.execute());
+        self.finish();
+        #[cfg(feature = "std")]
+        {
+            let bytes = self.memory_usage();
+            if let Some(metrics) = &mut self.metrics {
+                metrics.record_query(start.elapsed(), res.is_ok());
+                metrics.set_memory_bytes(bytes);
+            }
+        }
+        res.map(|()| self.stack.iter().map(VM::share).collect())
+    }
+
+    // Run `code` in a VM of its own over the same columns - all their rows, whatever the
+    // window - and return the one value it leaves: a scalar, or the only row of a column.
+    // Cancelling the query, or its running out of time, stops the subquery too.
+    fn run_subquery(&self, code: &[Op]) -> Result<Scalar, VMError> {
+        let columns = &self.columns;
+        let mut vm = VM {
+            code: Vec::new(), ip: 0, stack: Vec::new(), columns: columns.to_vec(), tables: self.tables.clone(), mode: self.mode, borrows: vec![0; columns.len()],
+            window: None, tracer: None, udfs: self.udfs.clone(), cancel: self.cancel.clone(), trace: None, profile: None, filter_plan: None,
+            shared: self.shared.iter().map(|v| v.as_ref().map(VM::share)).collect(),
+            nulls: self.nulls, nfc: self.nfc, memory: self.memory.clone(), held: None, breakpoints: Vec::new(), stats: ExecStats::default(),
+            #[cfg(feature = "std")]
+            timeout: self.deadline.map(|d| d.saturating_duration_since(Instant::now())),
+            #[cfg(feature = "std")]
+            deadline: None,
+            #[cfg(feature = "std")]
+            metrics: None,
+            #[cfg(feature = "gpu")]
+            gpu: self.gpu.clone()
+        };
+        vm.run(code.to_vec())?;
+        let stack = vm.take_stack();
+        let val = match stack.as_slice() {
+            [Value::Scalar(s)] => Some(s.clone()),
+            [v] => vm.column_of(v).filter(|c| c.len() == 1).and_then(|c| c.get(0)),
+            _ => None
+        };
+        val.ok_or_else(|| VMError::TypeError(format!("A scalar subquery must produce exactly one value, got: {:?}", stack)))
+    }
+
+    // The column an Op::Col or Op::ColByName loads, by its place in `self.columns`
+    fn column_index(&self, op: &Op) -> Result<usize, VMError> {
+        let (t, idx) = match op {
+            Op::Col(t, idx) => (*t, *idx),
+            Op::ColByName(name) => self.resolve_name(name)?,
+            _ => unreachable!("only column loads name a column")
+        };
+        let table = self.tables.get(t).ok_or(VMError::TableIndexOutOfRange { idx: t, ntables: self.tables.len() })?;
+        match idx < table.schema.len() {
+            true => Ok(table.first + idx),
+            false => Err(VMError::ColumnIndexOutOfRange { idx, ncols: table.schema.len() })
+        }
+    }
+
+    // How many columns Op::GroupBy can key on: those of the first table, which come first
+    fn key_columns(&self) -> usize {
+        self.tables.first().map_or(0, |t| t.schema.len())
+    }
+
+    // (table id, column id) of "table.column", or of a column name only one table has
+    fn resolve_name(&self, name: &str) -> Result<(usize, usize), VMError> {
+        if let Some((table, col)) = name.split_once('.') {
+            if let Some(t) = self.table_id(table) {
+                return self.tables[t].schema.index_of(col).map(|c| (t, c)).ok_or_else(|| VMError::UnknownColumn(name.to_string()));
+            }
+        }
+        let mut found = self.tables.iter().enumerate().filter_map(|(t, table)| table.schema.index_of(name).map(|c| (t, c)));
+        match (found.next(), found.next()) {
+            (Some(at), None) => Ok(at),
+            (Some(_), Some(_)) => Err(VMError::TypeError(format!("More than one table has a column '{}'; name it as table.column", name))),
+            (None, _) => Err(VMError::UnknownColumn(name.to_string()))
+        }
+    }
+
+    // Set up `code` to be run from its first instruction, by run() all at once or by step()
+    // and resume() as a debugger would
+    pub fn load(&mut self, code: Vec<Op>) -> Result<(), VMError> {
+        self.code = code;
+        self.ip = 0;
+        #[cfg(feature = "std")]
+        {
+            self.deadline = self.timeout.map(|t| Instant::now() + t);
+        }
+        self.stats = ExecStats::default();
+        self.held = self.memory.as_ref().map(|m| m.try_reserve(Consumer::Intermediate, 0)).transpose()?;
+        Ok(())
+    }
+
+    // The end of a run, however it ended
+    fn finish(&mut self) {
+        self.release_values();
+        self.held = None;
+        self.stats.rows_produced = VM::top_rows(&self.stack, &self.columns, self.stack.len(), false);
+    }
+
+    // What the last run did: instructions run by kind, rows scanned and produced, and times
+    pub fn stats(&self) -> &ExecStats {
+        &self.stats
+    }
+
+    // The ip of the next instruction to run
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    // Stop resume() before running the instruction at `ip`
+    pub fn set_breakpoint(&mut self, ip: usize) {
+        if !self.breakpoints.contains(&ip) {
+            self.breakpoints.push(ip);
+        }
+    }
+
+    pub fn clear_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.retain(|b| *b != ip);
+    }
+
+    // Run the next instruction of the program load()ed, and return it with the stack it left
+    // behind; None once the program has finished. After the last instruction, or one that
+    // fails, the stack is left as run() leaves it.
+    pub fn step(&mut self) -> Result<Option<Step<'_>>, VMError> {
+        if self.ip >= self.code.len() {
+            return Ok(None);
+        }
+        let res = self.check_interrupt().and_then(|()| self.execute_op());
+        if res.is_err() || self.ip >= self.code.len() {
+            self.finish();
+        }
+        res?;
+        Ok(Some(Step { ip: self.ip - 1, op: &self.code[self.ip - 1], stack: &self.stack }))
+    }
+
+    // Step until the program finishes or is about to run an instruction with a breakpoint -
+    // at least one instruction, so resuming from a breakpoint moves on
+    pub fn resume(&mut self) -> Result<Stop, VMError> {
+        while self.step()?.is_some() {
+            if self.ip < self.code.len() && self.breakpoints.contains(&self.ip) {
+                return Ok(Stop::Breakpoint(self.ip));
+            }
+        }
+        Ok(Stop::Finished)
+    }
+
+    fn execute(&mut self) -> Result<(), VMError> {
+        while self.ip < self.code.len() {
+            self.check_interrupt()?;
+            self.execute_op()?;
+        }
+        Ok(())
+    }
+
+    // Run the instruction at ip, and move on to the next
+    fn execute_op(&mut self) -> Result<(), VMError> {
+        let op = &self.code[self.ip];
+        self.ip += 1;
+
+        let (pops, pushes) = op.stack_effect();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.on_op(self.ip - 1, op, &self.stack);
+            self.stack[self.stack.len().saturating_sub(pops) ..].iter().for_each(|v| tracer.on_pop(v));
+        }
+        let operands = match self.trace {
+            Some(_) => VM::top_fingerprints(&self.stack, &self.columns, pops),
+            None => Vec::new()
+        };
+        let rows_in = match self.profile {
+            Some(_) => VM::top_rows(&self.stack, &self.columns, pops, false),
+            None => 0
+        };
+        #[cfg(feature = "std")]
+        let op_start = Instant::now();
+
+        match op {
+
+            Op::Lit(s) => self.stack.push(Value::Scalar(s.clone())),
+
+            Op::Col(..) | Op::ColByName(_) => {
+                let idx = self.column_index(op)?;
+                match (self.window, self.mode) {
+                    (Some((offset, len)), _) => self.stack.push(
+                        Value::ColumnRef(Arc::new(self.columns[idx].slice(offset, len)))
+                    ),
+                    (None, ColumnMode::Rc) => self.stack.push(
+                        Value::ColumnRef(self.columns[idx].clone())    // Clone the RC = inc reference
+                    ),
+                    (None, ColumnMode::Slots) => {
+                        self.borrows[idx] += 1;
+                        self.stack.push(Value::Slot(ColumnSlot(idx)))
+                    }
+                }
+            },
+
+            Op::FilterEq if matches!(self.stack.iter().rev().nth(1), Some(Value::Scalar(_))) => {
+                // Two scalars (e.g. records): push whether they're equal
+                let b = VM::pop_scalar(&mut self.stack)?;
+                let a = VM::pop_scalar(&mut self.stack)?;
+                let eq = match (&a, &b) {
+                    (Scalar::Str(x), Scalar::Str(y)) if self.nfc => normalize::nfc(x) == normalize::nfc(y),
+                    _ => nulls::scalars_equal(&a, &b, self.nulls)
+                };
+                self.stack.push(Value::Scalar(Scalar::Bool(eq)));
+            },
+
+            Op::FilterEq => {
+                // TOS is a scalar. TOS-1 is a column.
+                // Push a new column of positions
+                let s = VM::pop_scalar(&mut self.stack)?;
+                let col = VM::pop_lazy(&mut self.stack, &mut self.borrows)?;
+                let nfc = self.nfc && matches!(s, Scalar::Str(_));
+                let mask = match &col {
+                    ColumnHandle::View(base, sel) if nfc => normalize::filter_eq(&VM::gather(base, sel.clone()), s)?,
+                    col if nfc => normalize::filter_eq(VM::resolve(&self.columns, col), s)?,
+                    // a null that matches nulls is left to nulls::filter_eq, on the gathered rows
+                    ColumnHandle::View(base, sel) if self.nulls.nulls_match() && nulls::is_null(&s) => {
+                        nulls::filter_eq(&VM::gather(base, sel.clone()), s, self.nulls)?
+                    },
+                    ColumnHandle::View(base, sel) => base.filter_at(s, sel)?,
+                    col if self.nulls.nulls_match() && nulls::is_null(&s) => nulls::filter_eq(VM::resolve(&self.columns, col), s, self.nulls)?,
+                    #[cfg(feature = "gpu")]
+                    col if self.gpu.as_ref().is_some_and(|gpu| gpu.offloads(VM::resolve(&self.columns, col))) => {
+                        let gpu = self.gpu.as_ref().unwrap();
+                        gpu.filter_eq(VM::resolve(&self.columns, col), s)?
+                    },
+                    col => {
+                        let (mask, plan) = VM::resolve(&self.columns, col).filter_planned(s)?;
+                        self.filter_plan = Some(plan);
+                        mask
+                    }
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(mask))));
+            },
+
+            Op::FilterLt | Op::FilterLe | Op::FilterGt | Op::FilterGe | Op::FilterNe => {
+                let cmp = match op {
+                    Op::FilterLt => Cmp::Lt,
+                    Op::FilterLe => Cmp::Le,
+                    Op::FilterGt => Cmp::Gt,
+                    Op::FilterGe => Cmp::Ge,
+                    _ => Cmp::Ne
+                };
+                let s = VM::pop_scalar(&mut self.stack)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let mask = VM::resolve(&self.columns, &col).filter_cmp(cmp, s)?;
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(mask))));
+            },
+
+            Op::And | Op::Or => {
+                let b = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let a = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let (a, b) = (VM::expect_mask(VM::resolve(&self.columns, &a))?, VM::expect_mask(VM::resolve(&self.columns, &b))?);
+                if a.len() != b.len() {
+                    return Err(VMError::LengthMismatch { expected: a.len(), found: b.len() });
+                }
+                let mask = if matches!(op, Op::And) { a.and(&b) } else { a.or(&b) };
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(BoolColumn::from_selection(mask)))));
+            },
+
+            Op::Not => {
+                let a = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let mask = VM::expect_mask(VM::resolve(&self.columns, &a))?.inverted();
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(BoolColumn::from_selection(mask)))));
+            },
+
+            Op::Select(_) => {
+                // todo: select multiple
+                // Don't gather anything yet: push a view, and let whoever needs the rows
+                // gather them. Selecting from a view just narrows its selection.
+                let data = VM::pop_lazy(&mut self.stack, &mut self.borrows)?;
+                let selector = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let selector = VM::expect_mask(VM::resolve(&self.columns, &selector))?;
+                // a mask for some other column would select rows that don't exist
+                let rows = match &data {
+                    ColumnHandle::View(_, prev) => prev.count_ones(),
+                    data => VM::resolve(&self.columns, data).len()
+                };
+                if selector.len() != rows {
+                    return Err(VMError::LengthMismatch { expected: rows, found: selector.len() });
+                }
+                let view = match data {
+                    ColumnHandle::View(base, prev) => Value::View(base, prev.compose(&selector)),
+                    ColumnHandle::Shared(c) => Value::View(c, selector.into_owned()),
+                    ColumnHandle::Slot(idx) => Value::View(self.columns[idx].clone(), selector.into_owned())
+                };
+                self.stack.push(view);
+            }
+
+            Op::FilterSelect => {
+                let target = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let s = VM::pop_scalar(&mut self.stack)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let (col, target) = (VM::resolve(&self.columns, &col), VM::resolve(&self.columns, &target));
+                let nfc = self.nfc && matches!(s, Scalar::Str(_));
+                let new_col = if nfc || (self.nulls.nulls_match() && nulls::is_null(&s)) {
+                    if col.len() != target.len() {
+                        return Err(VMError::LengthMismatch { expected: col.len(), found: target.len() });
+                    }
+                    let mask = if nfc { normalize::filter_eq(col, s)? } else { nulls::filter_eq(col, s, self.nulls)? };
+                    target.select(&mask)
+                } else {
+                    col.filter_select(s, target)?
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(new_col)));
+            }
+
+            Op::FilterIn => {
+                // TOS is the column of values to look for. TOS-1 is the column to filter.
+                let set = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let (col, set) = (VM::resolve(&self.columns, &col), VM::resolve(&self.columns, &set));
+                let mask = if self.nfc {
+                    normalize::nfc_column(col).filter_in(&normalize::nfc_column(set), self.nulls)?
+                } else {
+                    col.filter_in(set, self.nulls)?
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(mask))));
+            },
+
+            Op::FilterWithinBBox => {
+                let max = geo::expect_point(&VM::pop_scalar(&mut self.stack)?)?;
+                let min = geo::expect_point(&VM::pop_scalar(&mut self.stack)?)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let col = VM::resolve(&self.columns, &col);
+                let mask = nulls::valid_only(col, geo::expect_points(nulls::values_of(col))?.within_bbox(min, max));
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(mask))));
+            },
+
+            Op::DistanceTo => {
+                let to = geo::expect_point(&VM::pop_scalar(&mut self.stack)?)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let col = VM::resolve(&self.columns, &col);
+                let dist = nulls::null_where(col, Column::Num(geo::expect_points(nulls::values_of(col))?.distance_to(to)))?;
+                self.stack.push(Value::ColumnRef(Arc::new(dist)));
+            },
+
+            Op::ListLen => {
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let lengths = list::expect_lists(VM::resolve(&self.columns, &col))?.lengths();
+                self.stack.push(Value::ColumnRef(Arc::new(lengths)));
+            },
+
+            Op::FilterContains => {
+                let val = VM::pop_scalar(&mut self.stack)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let mask = list::expect_lists(VM::resolve(&self.columns, &col))?.filter_contains(val)?;
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(mask))));
+            },
+
+            Op::VectorDistance(metric) => {
+                let query = VM::pop_scalar(&mut self.stack)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let dist = vector::expect_vectors(VM::resolve(&self.columns, &col))?.distance_to(vector::expect_vector(&query)?, *metric)?;
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Num(dist))));
+            },
+
+            Op::Nearest(k, metric) => {
+                let query = VM::pop_scalar(&mut self.stack)?;
+                let query = vector::expect_vector(&query)?;
+                let col = VM::pop_lazy(&mut self.stack, &mut self.borrows)?;
+                // on a view, the nearest of its rows, as a mask over them
+                let mask = match &col {
+                    ColumnHandle::View(base, sel) => vector::expect_vectors(base)?.nearest_mask(query, *k, *metric, Some(sel))?,
+                    col => vector::expect_vectors(VM::resolve(&self.columns, col))?.nearest_mask(query, *k, *metric, None)?
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(mask))));
+            },
+
+            Op::FilterInCidr(prefix) => {
+                let network = VM::pop_scalar(&mut self.stack)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let col = VM::resolve(&self.columns, &col);
+                let mask = nulls::valid_only(col, ip::filter_in_cidr(nulls::values_of(col), &network, *prefix)?);
+                self.stack.push(Value::ColumnRef(Arc::new(Column::Bool(mask))));
+            },
+
+            Op::JsonExtract(path, dtype) => {
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let values = match VM::resolve(&self.columns, &col) {
+                    Column::Json(docs) => docs.extract(path, *dtype)?.0,
+                    other => return Err(VMError::TypeError(format!("Expected a Json column, found a {} column", other.datatype())))
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(values)));
+            },
+
+            Op::AddVs | Op::SubVs | Op::MulVs | Op::DivVs => {
+                let arith = match op {
+                    Op::AddVs => Arith::Add,
+                    Op::SubVs => Arith::Sub,
+                    Op::MulVs => Arith::Mul,
+                    _ => Arith::Div
+                };
+                let s = VM::pop_scalar(&mut self.stack)?;
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let res = VM::resolve(&self.columns, &col).arith_scalar(arith, &s)?;
+                self.stack.push(Value::ColumnRef(Arc::new(res)));
+            },
+
+            Op::AddVv | Op::SubVv | Op::MulVv | Op::DivVv => {
+                let arith = match op {
+                    Op::AddVv => Arith::Add,
+                    Op::SubVv => Arith::Sub,
+                    Op::MulVv => Arith::Mul,
+                    _ => Arith::Div
+                };
+                let b = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let a = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let res = VM::resolve(&self.columns, &a).arith(arith, VM::resolve(&self.columns, &b))?;
+                self.stack.push(Value::ColumnRef(Arc::new(res)));
+            },
+
+            // a Record column projects to a column of the field
+            Op::Field(idx) if !matches!(self.stack.last(), Some(Value::Scalar(_))) => {
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let field = match VM::resolve(&self.columns, &col) {
+                    Column::Record(records) => records.field(*idx)?,
+                    other => return Err(VMError::TypeError(format!("Expected a Record column, found a {} column", other.datatype())))
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(field)));
+            },
+
+            Op::Field(idx) => match VM::pop_scalar(&mut self.stack)? {
+                Scalar::Record(mut fields) if *idx < fields.len() => self.stack.push(Value::Scalar(fields.swap_remove(*idx))),
+                Scalar::Record(fields) => {
+                    return Err(VMError::TypeError(format!("Record has {} fields, so there's no field {}", fields.len(), idx)));
+                },
+                s => return Err(VMError::TypeError(format!("Type error: expected a record, found: {:?}", s)))
+            },
+
+            Op::IfElse => {
+                // TOS is the else branch, TOS-1 the then branch, TOS-2 the mask
+                let els = VM::pop_operand(&mut self.stack, &mut self.borrows)?;
+                let then = VM::pop_operand(&mut self.stack, &mut self.borrows)?;
+                let cond = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let cond = VM::expect_mask(VM::resolve(&self.columns, &cond))?;
+                let res = conditional::if_else(&cond, VM::branch(&self.columns, &then), VM::branch(&self.columns, &els))?;
+                self.stack.push(Value::ColumnRef(Arc::new(res)));
+            },
+
+            Op::Count => {
+                // a view's rows can be counted without gathering them
+                let res = match VM::pop_lazy(&mut self.stack, &mut self.borrows)? {
+                    ColumnHandle::View(base, sel) if !matches!(*base, Column::List(_)) => Value::Scalar(Scalar::Int(sel.count_ones() as i64)),
+                    ColumnHandle::View(base, sel) => VM::aggregate(&VM::gather(&base, sel), Agg::Count)?,
+                    col => VM::aggregate(VM::resolve(&self.columns, &col), Agg::Count)?
+                };
+                self.stack.push(res);
+            },
+
+            Op::Sum | Op::Min | Op::Max | Op::Mean => {
+                let agg = match op {
+                    Op::Sum => Agg::Sum,
+                    Op::Min => Agg::Min,
+                    Op::Max => Agg::Max,
+                    _ => Agg::Mean
+                };
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let res = VM::aggregate(VM::resolve(&self.columns, &col), agg)?;
+                self.stack.push(res);
+            },
+
+            Op::GroupBy(key) if *key >= self.key_columns() => {
+                return Err(VMError::ColumnIndexOutOfRange { idx: *key, ncols: self.key_columns() });
+            },
+
+            Op::GroupBy(key) => {
+                // the keys as Op::Col would load them
+                let keys = match self.window {
+                    Some((offset, len)) => Cow::Owned(self.columns[*key].slice(offset, len)),
+                    None => Cow::Borrowed(self.columns[*key].as_ref())
+                };
+                let (distinct, lists) = match VM::pop_lazy(&mut self.stack, &mut self.borrows)? {
+                    // a view of the table's rows groups by those rows' keys
+                    ColumnHandle::View(base, sel) if sel.len() == keys.len() => {
+                        list::group_by(&VM::gather(&keys, sel.clone()), &VM::gather(&base, sel))?
+                    },
+                    ColumnHandle::View(base, sel) => list::group_by(&keys, &VM::gather(&base, sel))?,
+                    col => list::group_by(&keys, VM::resolve(&self.columns, &col))?
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(distinct)));
+                self.stack.push(Value::ColumnRef(Arc::new(Column::List(lists))));
+            },
+
+            Op::ArgSort => {
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let order = compare::argsort(VM::resolve(&self.columns, &col));
+                self.stack.push(Value::ColumnRef(Arc::new(Column::from(order.into_iter().map(|i| i as u64).collect::<Vec<u64>>()))));
+            },
+
+            Op::SortBy | Op::Take => {
+                // TOS is the column to gather from, TOS-1 the row numbers
+                let data = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let rows = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let data = VM::resolve(&self.columns, &data);
+                let rows = VM::row_numbers(VM::resolve(&self.columns, &rows), data.len())?;
+                // an order has a row per row of the column; Take may pick any rows, any number of times
+                if *op == Op::SortBy && rows.len() != data.len() {
+                    return Err(VMError::LengthMismatch { expected: data.len(), found: rows.len() });
+                }
+                self.stack.push(Value::ColumnRef(Arc::new(conditional::take(data, &rows)?)));
+            },
+
+            Op::Limit(n) => {
+                // a view stays one, of its first n rows; a column is sliced, without a copy
+                let res = match VM::pop_lazy(&mut self.stack, &mut self.borrows)? {
+                    ColumnHandle::View(base, sel) => {
+                        let mut rows = Vec::with_capacity((*n).min(sel.count_ones()));
+                        sel.for_each(|i| if rows.len() < *n { rows.push(i as u32) });
+                        Value::View(base, Selection::from_positions(rows, sel.len()))
+                    },
+                    col => {
+                        let col = VM::resolve(&self.columns, &col);
+                        Value::ColumnRef(Arc::new(col.slice(0, (*n).min(col.len()))))
+                    }
+                };
+                self.stack.push(res);
+            },
+
+            Op::TopK(n) => {
+                let col = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                // nulls aren't ranked: the top of the rest, numbered as rows of the whole column
+                let (col, rows_of) = match VM::resolve(&self.columns, &col) {
+                    Column::Nullable(c) => (Cow::Owned(c.present()), Some(BoolColumn::from_mask(c.validity().clone()).selection().positions())),
+                    col => (Cow::Borrowed(col), None)
+                };
+                let col = encoding::plain(&col);
+                let rows = match col.as_ref() {
+                    Column::Num(c) => compare::top_k(c.values(), *n),
+                    Column::Int(c) => compare::top_k_int(c.values(), *n),
+                    other => return Err(VMError::TypeError(format!("Can only take the top values of a Num or Int column, not a {} column", other.datatype())))
+                };
+                let rows = rows.into_iter().map(|i| rows_of.as_ref().map_or(i, |rows| rows[i]) as u64).collect::<Vec<u64>>();
+                self.stack.push(Value::ColumnRef(Arc::new(Column::from(rows))));
+            },
+
+            Op::HashJoin => {
+                // TOS is the right side's keys, TOS-1 the left's
+                let right = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let left = VM::pop_column(&mut self.stack, &mut self.borrows)?;
+                let (left, right) = (VM::resolve(&self.columns, &left), VM::resolve(&self.columns, &right));
+                let (lvalid, rvalid) = (nulls::validity_of(&[left]), nulls::validity_of(&[right]));
+                let (left, right) = (encoding::plain(nulls::values_of(left)), encoding::plain(nulls::values_of(right)));
+                let (lrows, rrows) = match (left.as_ref(), right.as_ref()) {
+                    (Column::Entity(l), Column::Entity(r)) => join::join_positions_nullable(l.values(), lvalid.as_ref(), r.values(), rvalid.as_ref(), self.nulls),
+                    (Column::Int(l), Column::Int(r)) => join::join_positions_nullable(l.values(), lvalid.as_ref(), r.values(), rvalid.as_ref(), self.nulls),
+                    (l, r) => return Err(VMError::TypeError(format!("Can only join on Entity or Int keys, not {} and {}", l.datatype(), r.datatype())))
+                };
+                self.stack.push(Value::ColumnRef(Arc::new(Column::from(lrows))));
+                self.stack.push(Value::ColumnRef(Arc::new(Column::from(rrows))));
+            },
+
+            Op::CallUdf(id, arity) | Op::CallUdaf(id, arity) => {
+                let (name, f) = self.udfs.get(*id).ok_or(VMError::UnknownFunction(*id))?;
+                if self.stack.len() < *arity {
+                    return Err(VMError::TypeError(format!("{} takes {} arguments, but the stack holds {}", name, arity, self.stack.len())));
+                }
+                let mut args = Vec::with_capacity(*arity);
+                for _ in 0 .. *arity {
+                    args.push(VM::pop_operand(&mut self.stack, &mut self.borrows)?);
+                }
+                args.reverse();
+                let args: Vec<Branch> = args.iter().map(|a| VM::branch(&self.columns, a)).collect();
+                match op {
+                    Op::CallUdaf(..) => self.stack.push(Value::Scalar(udf::aggregate(f, name, &args)?)),
+                    _ => match udf::apply(f, name, &args)? {
+                        udf::Output::Scalar(s) => self.stack.push(Value::Scalar(s)),
+                        udf::Output::Column(c) => self.stack.push(Value::ColumnRef(Arc::new(c)))
+                    }
+                }
+            },
+
+            Op::ScalarSubquery(code) => {
+                let val = self.run_subquery(code)?;
+                self.stack.push(Value::Scalar(val));
+            },
+
+            Op::Shared(id) => match self.shared.get(*id) {
+                Some(Some(v)) => self.stack.push(VM::share(v)),
+                _ => return Err(VMError::UnknownShared(*id))
+            },
+
+        }
+
+        // Stop the clock before the tracing hooks, so they don't count against the op
+        #[cfg(feature = "std")]
+        let elapsed = op_start.elapsed();
+        #[cfg(not(feature = "std"))]
+        let elapsed = Duration::ZERO;   // no clock to read
+        if let Some(tracer) = &mut self.tracer {
+            self.stack[self.stack.len().saturating_sub(pushes) ..].iter().for_each(|v| tracer.on_push(v));
+        }
+        if let Some(recorder) = &mut self.trace {
+            let columns = &self.columns;
+            let rows = match self.stack.last() {
+                Some(Value::Scalar(_)) | None => None,
+                Some(_) if pushes > 0 => Some(VM::top_rows(&self.stack, columns, 1, false)),
+                Some(_) => None
+            };
+            recorder.record(self.ip - 1, op, operands, VM::top_fingerprints(&self.stack, &self.columns, pushes), rows);
+        }
+        let rows_out = VM::top_rows(&self.stack, &self.columns, pushes, true);
+        self.stats.record(op.mnemonic(), rows_out, elapsed);
+        let plan = self.filter_plan.take();
+        if let Some(profile) = &mut self.profile {
+            profile.push(OpProfile { ip: self.ip - 1, rows_in, rows_out, elapsed, plan });
+        }
+        let scanned = match op {
+            Op::Col(..) | Op::ColByName(_) => {
+                let rows = self.columns[self.column_index(op)?].len();
+                match self.window {
+                    Some((offset, len)) => len.min(rows.saturating_sub(offset)),
+                    None => rows
+                }
+            },
+            _ => 0
+        };
+        self.stats.rows_scanned += scanned;
+        #[cfg(feature = "std")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_op(op.mnemonic(), elapsed);
+            if scanned > 0 {
+                metrics.add_rows_scanned(scanned);
+            }
+        }
+        if let Some(held) = &mut self.held {
+            held.resize(VM::intermediate_bytes(&self.stack, &self.columns))?;
+        }
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_column::RecordColumn;
+    use crate::schema::Datatype;
+
+    use core::convert::TryFrom;
+
+    fn columns() -> Vec<Column> {
+        vec![Column::from(vec![1.0, 3.0, 3.0, 2.0]), Column::from(vec![10u64, 11, 12, 13])]
+    }
+
+    // two results: the ids where x = 3, and column 0 as it is
+    fn program() -> Vec<Op> {
+        vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1), Op::Col(0, 0)]
+    }
+
+    fn run(mode: ColumnMode) -> VM {
+        let mut vm = VM::with_column_mode(Table::from_columns(columns()).unwrap(), mode);
+        vm.run(program()).unwrap();
+        vm
+    }
+
+    fn fingerprints(vm: &VM) -> Vec<u64> {
+        vm.stack().iter().map(|v| vm.column_of(v).unwrap().fingerprint()).collect()
+    }
+
+    #[test]
+    fn slots_give_the_same_results() {
+        let (rc, slots) = (run(ColumnMode::Rc), run(ColumnMode::Slots));
+        assert_eq!(fingerprints(&rc), fingerprints(&slots));
+        assert_eq!(fingerprints(&rc)[0], Column::from(vec![11u64, 12]).fingerprint());
+    }
+
+    #[test]
+    fn slots_left_on_the_stack_are_released() {
+        let vm = run(ColumnMode::Slots);
+        assert!(matches!(vm.stack()[1], Value::ColumnRef(_)));
+        assert_eq!((vm.borrows(0), vm.borrows(1)), (0, 0));
+    }
+
+    #[test]
+    fn programs_step_and_stop_at_breakpoints() {
+        let mut vm = VM::with_column_mode(Table::from_columns(columns()).unwrap(), ColumnMode::Slots);
+        vm.load(program()).unwrap();
+        let step = vm.step().unwrap().unwrap();
+        assert_eq!((step.ip, step.op, step.stack.len()), (0, &Op::Col(0, 0), 1));
+        assert!(matches!(step.stack[0], Value::Slot(ColumnSlot(0))));
+        vm.set_breakpoint(4);
+        vm.set_breakpoint(5);
+        vm.clear_breakpoint(5);
+        assert_eq!(vm.resume().unwrap(), Stop::Breakpoint(4));
+        assert_eq!((vm.ip(), vm.stack().len(), vm.borrows(1)), (4, 2, 1));
+        // on to the end, where the slots are let go as run() would
+        assert_eq!(vm.resume().unwrap(), Stop::Finished);
+        assert!(vm.step().unwrap().is_none());
+        assert_eq!(fingerprints(&vm), fingerprints(&run(ColumnMode::Slots)));
+        assert_eq!((vm.borrows(0), vm.borrows(1)), (0, 0));
+        // a failing instruction is an error from step
+        vm.load(vec![Op::Col(0, 0), Op::Select(1)]).unwrap();
+        assert!(vm.step().is_ok());
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn runs_return_their_results() {
+        for mode in [ColumnMode::Rc, ColumnMode::Slots] {
+            let mut vm = VM::with_column_mode(Table::from_columns(columns()).unwrap(), mode);
+            let res = vm.run(program()).unwrap();
+            let cols: Vec<u64> = res.iter().map(|v| vm.column_of(v).unwrap().fingerprint()).collect();
+            assert_eq!(cols, fingerprints(&vm));
+            assert!(matches!(vm.take_result(), Err(VMError::TypeError(_))));
+            assert_eq!(vm.into_columns().unwrap(), vec![Column::from(vec![11u64, 12]), columns().remove(0)]);
+        }
+        let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Sum]).unwrap();
+        assert!(matches!(vm.take_result(), Ok(Value::Scalar(Scalar::Num(x))) if x == 9.0));
+        assert!(vm.stack().is_empty());
+        vm.run(vec![Op::Lit(Scalar::Num(1.0))]).unwrap();
+        assert!(matches!(vm.into_columns(), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn slots_trace_like_refs() {
+        let trace = |mode| {
+            let mut vm = VM::with_column_mode(Table::from_columns(columns()).unwrap(), mode);
+            vm.record_trace();
+            vm.run(program()).unwrap();
+            vm.take_trace().unwrap().into_entries()
+        };
+        assert_eq!(trace(ColumnMode::Rc), trace(ColumnMode::Slots));
+    }
+
+    #[test]
+    fn views_are_gathered_when_the_run_ends() {
+        let vm = run(ColumnMode::Rc);
+        assert!(vm.stack().iter().all(|v| matches!(v, Value::ColumnRef(_))));
+        assert_eq!(vm.snapshot().stack.len(), 2);
+    }
+
+    #[test]
+    fn views_filter_and_narrow_like_gathered_columns() {
+        // ids where x = 3 are [11, 12]; of those, keep id 12, and take x from the same rows
+        let code = vec![
+            Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1),
+            Op::Lit(Scalar::Entity(12)), Op::FilterEq,
+            Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 0), Op::Select(1),
+            Op::Select(1)
+        ];
+        for mode in [ColumnMode::Rc, ColumnMode::Slots] {
+            let mut vm = VM::with_column_mode(Table::from_columns(columns()).unwrap(), mode);
+            vm.run(code.clone()).unwrap();
+            assert_eq!(fingerprints(&vm), vec![Column::from(vec![3.0]).fingerprint()]);
+        }
+    }
+
+    #[test]
+    fn bad_column_indexes_are_errors() {
+        for mode in [ColumnMode::Rc, ColumnMode::Slots] {
+            let mut vm = VM::with_column_mode(Table::from_columns(columns()).unwrap(), mode);
+            assert!(matches!(vm.run(vec![Op::Col(0, 2)]), Err(VMError::ColumnIndexOutOfRange { idx: 2, ncols: 2 })));
+        }
+    }
+
+    #[test]
+    fn columns_load_by_name() {
+        let table = Table::new(vec![("x".to_string(), columns().remove(0)), ("id".to_string(), columns().remove(1))]).unwrap();
+        let named = |name: &str| Op::ColByName(name.to_string());
+        for mode in [ColumnMode::Rc, ColumnMode::Slots] {
+            let mut vm = VM::with_column_mode(table.clone(), mode);
+            vm.run(vec![named("x"), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, named("id"), Op::Select(1), named("x")]).unwrap();
+            assert_eq!(fingerprints(&vm), fingerprints(&run(mode)));
+            assert!(matches!(vm.run(vec![named("y")]), Err(VMError::UnknownColumn(name)) if name == "y"));
+        }
+        // shared columns go by their positions
+        let mut vm = VM::with_shared_columns(run(ColumnMode::Rc).shared_columns(), ColumnMode::Rc).unwrap();
+        vm.run(vec![named("c1")]).unwrap();
+        assert_eq!(fingerprints(&vm), vec![columns()[1].fingerprint()]);
+    }
+
+    #[test]
+    fn ragged_columns_are_turned_away() {
+        let mut cols = columns();
+        cols.push(Column::from(vec![1.0, 2.0]));
+        assert!(matches!(Table::from_columns(cols.clone()), Err(VMError::LengthMismatch { expected: 4, found: 2 })));
+        let shared = cols.into_iter().map(Arc::new).collect();
+        assert!(matches!(VM::with_shared_columns(shared, ColumnMode::Slots), Err(VMError::LengthMismatch { expected: 4, found: 2 })));
+        assert!(VM::new(Table::from_columns(vec![]).unwrap()).schema(0).is_some_and(|s| s.is_empty()));
+    }
+
+    #[test]
+    fn masks_of_the_wrong_length_are_errors() {
+        // a 2-row mask, over the ids where x = 3, applied to a 4-row column and then to a
+        // 1-row view of one
+        let mask = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1), Op::Lit(Scalar::Entity(11)), Op::FilterEq];
+        let programs = vec![
+            ([mask.clone(), vec![Op::Col(0, 0), Op::Select(1)]].concat(), 4),
+            ([mask, vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1), Op::Select(1)]].concat(), 1),
+        ];
+        for (code, rows) in programs {
+            for mode in [ColumnMode::Rc, ColumnMode::Slots] {
+                let mut vm = VM::with_column_mode(Table::from_columns(columns()).unwrap(), mode);
+                let err = vm.run(code.clone()).unwrap_err();
+                assert!(matches!(err, VMError::LengthMismatch { expected, found: 2 } if expected == rows), "{:?}", err);
+            }
+        }
+    }
+
+    fn run_code(cols: Vec<Column>, code: Vec<Op>) -> Result<Vec<Column>, VMError> {
+        let mut vm = VM::new(Table::from_columns(cols)?);
+        vm.run(code)?;
+        Ok(vm.stack().iter().map(|v| vm.column_of(v).unwrap().clone()).collect())
+    }
+
+    #[test]
+    fn filter_in_keeps_rows_found_in_the_other_column() {
+        // ids whose x is one of the x values at id 12 or 13
+        let code = vec![
+            Op::Col(0, 0),
+            Op::Col(0, 1), Op::Lit(Scalar::Entity(13)), Op::FilterEq, Op::Col(0, 0), Op::Select(1),
+            Op::FilterIn, Op::Col(0, 1), Op::Select(1)
+        ];
+        crate::assert_columns_eq!(run_code(columns(), code).unwrap()[0], Column::from(vec![13u64]));
+        let err = run_code(columns(), vec![Op::Col(0, 0), Op::Col(0, 1), Op::FilterIn]).unwrap_err();
+        assert!(matches!(err, VMError::TypeError(_)));
+    }
+
+    #[test]
+    fn scalar_subqueries_push_their_one_value() {
+        // the id of the row where x = 2, found by a subquery and then looked up
+        let sub = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(2.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)];
+        let code = vec![Op::Col(0, 1), Op::ScalarSubquery(sub.clone()), Op::FilterEq, Op::Col(0, 0), Op::Select(1)];
+        crate::assert_columns_eq!(run_code(columns(), code).unwrap()[0], Column::from(vec![2.0]));
+        let lit = vec![Op::Col(0, 0), Op::ScalarSubquery(vec![Op::Lit(Scalar::Num(3.0))]), Op::FilterEq, Op::Col(0, 1), Op::Select(1)];
+        crate::assert_columns_eq!(run_code(columns(), lit).unwrap()[0], Column::from(vec![11u64, 12]));
+
+        // two rows, no value, and two values are all errors
+        let two_rows = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)];
+        for sub in [two_rows, vec![], vec![Op::Lit(Scalar::Num(1.0)), Op::Lit(Scalar::Num(2.0))]] {
+            let err = run_code(columns(), vec![Op::ScalarSubquery(sub)]).unwrap_err();
+            assert!(matches!(err, VMError::TypeError(_)), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn subqueries_see_the_whole_table_from_a_window() {
+        // the largest id, 13, is outside the window, but the subquery still finds it
+        let sub = vec![Op::Col(0, 1), Op::Lit(Scalar::Entity(13)), Op::FilterEq, Op::Col(0, 0), Op::Select(1)];
+        let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+        vm.set_window(Some((0, 2)));
+        vm.run(vec![Op::ScalarSubquery(sub)]).unwrap();
+        assert!(matches!(vm.stack(), [Value::Scalar(Scalar::Num(x))] if *x == 2.0));
+    }
+
+    #[test]
+    fn if_else_picks_per_row() {
+        // CASE WHEN x = 3 THEN id ELSE 0
+        let code = vec![
+            Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Lit(Scalar::Entity(0)), Op::IfElse
+        ];
+        crate::assert_columns_eq!(run_code(columns(), code).unwrap()[0], Column::from(vec![0u64, 11, 12, 0]));
+        // with a view as a branch, its rows must line up with the mask
+        let code = vec![
+            Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq,
+            Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1),
+            Op::Lit(Scalar::Entity(0)), Op::IfElse
+        ];
+        assert!(matches!(run_code(columns(), code), Err(VMError::LengthMismatch { expected: 4, found: 2 })));
+    }
+
+    fn record(fields: Vec<Scalar>) -> Op {
+        Op::Lit(Scalar::Record(fields))
+    }
+
+    #[test]
+    fn filter_eq_compares_two_scalars() {
+        let (a, b) = (Scalar::Str("a".to_string()), Scalar::Num(1.0));
+        let nested = Scalar::Record(vec![b.clone(), Scalar::Num(f64::NAN)]);
+        let code = vec![
+            record(vec![a.clone(), b.clone()]), record(vec![a.clone(), b.clone()]), Op::FilterEq,
+            record(vec![a.clone(), b.clone()]), record(vec![b.clone(), a.clone()]), Op::FilterEq,
+            record(vec![a.clone()]), record(vec![a.clone(), b.clone()]), Op::FilterEq,
+            // a null (NaN) anywhere in a record makes it unequal, unless nulls match
+            record(vec![nested.clone()]), record(vec![nested.clone()]), Op::FilterEq,
+            Op::Lit(Scalar::Num(-0.0)), Op::Lit(Scalar::Num(0.0)), Op::FilterEq,
+            Op::Lit(Scalar::Num(1.0)), Op::Lit(Scalar::Entity(1)), Op::FilterEq
+        ];
+        let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+        vm.run(code).unwrap();
+        let found: Vec<Scalar> = vm.stack().iter().map(|v| match v {
+            Value::Scalar(s) => s.clone(),
+            v => panic!("expected a scalar, found {:?}", v)
+        }).collect();
+        let expected = [true, false, false, false, true, false];
+        assert_eq!(found, expected.iter().map(|b| Scalar::Bool(*b)).collect::<Vec<_>>());
+
+        vm.set_null_semantics(NullSemantics::NullEqualsNull);
+        vm.run(vec![record(vec![nested.clone()]), record(vec![nested]), Op::FilterEq]).unwrap();
+        assert!(matches!(vm.stack().last(), Some(Value::Scalar(Scalar::Bool(true)))));
+    }
+
+    #[test]
+    fn arithmetic_with_a_scalar() {
+        let ops = [(Op::AddVs, vec![3.0, 5.0, 5.0, 4.0]), (Op::SubVs, vec![-1.0, 1.0, 1.0, 0.0]),
+                   (Op::MulVs, vec![2.0, 6.0, 6.0, 4.0]), (Op::DivVs, vec![0.5, 1.5, 1.5, 1.0])];
+        for (op, expected) in ops {
+            let res = run_code(columns(), vec![Op::Col(0, 0), Op::Lit(Scalar::Num(2.0)), op]).unwrap();
+            crate::assert_columns_eq!(res[0], Column::from(expected));
+        }
+        // on the rows of a view, and dividing by zero
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 0), Op::Select(1), Op::Lit(Scalar::Num(0.0)), Op::DivVs];
+        crate::assert_columns_eq!(run_code(columns(), code).unwrap()[0], Column::from(vec![f64::INFINITY, f64::INFINITY]));
+
+        for code in [vec![Op::Col(0, 1), Op::Lit(Scalar::Num(1.0)), Op::AddVs], vec![Op::Col(0, 0), Op::Lit(Scalar::Entity(1)), Op::MulVs]] {
+            assert!(matches!(run_code(columns(), code.clone()), Err(VMError::TypeError(_))), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn arithmetic_between_columns() {
+        let cols = vec![Column::from(vec![1.0, 2.0, 3.0]), Column::from(vec![4.0, 5.0, 6.0])];
+        let ops = [(Op::AddVv, vec![5.0, 7.0, 9.0]), (Op::SubVv, vec![-3.0, -3.0, -3.0]),
+                   (Op::MulVv, vec![4.0, 10.0, 18.0]), (Op::DivVv, vec![0.25, 0.4, 0.5])];
+        for (op, expected) in ops {
+            let res = run_code(cols.clone(), vec![Op::Col(0, 0), Op::Col(0, 1), op]).unwrap();
+            crate::assert_columns_eq!(res[0], Column::from(expected));
+        }
+        // column 1 where x > 2, a row of it
+        let short = vec![Op::Col(0, 0), Op::Col(0, 0), Op::Lit(Scalar::Num(2.0)), Op::FilterGt, Op::Col(0, 1), Op::Select(1), Op::AddVv];
+        assert!(matches!(run_code(cols.clone(), short), Err(VMError::LengthMismatch { expected: 3, found: 1 })));
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 0), Op::Col(0, 1), Op::MulVv]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn ints_stay_exact() {
+        // 2^53 + 1 has no f64 of its own
+        let big = 9_007_199_254_740_993i64;
+        let cols = vec![Column::from(vec![big, -4, 7]), Column::from(vec![1i64, 2, 3]), Column::from(vec![0.5, 1.0, 2.0])];
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Int(big)), Op::FilterEq, Op::Col(0, 1), Op::Select(1)];
+        assert_eq!(run_code(cols.clone(), code).unwrap(), vec![Column::from(vec![1i64])]);
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Int(1)), Op::AddVs, Op::Col(0, 0), Op::Col(0, 1), Op::MulVv];
+        let res = run_code(cols.clone(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![big + 1, -3, 8]), Column::from(vec![big, -8, 21])]);
+        // dividing, or mixing with Nums, gives Nums
+        let code = vec![Op::Col(0, 1), Op::Lit(Scalar::Int(2)), Op::DivVs, Op::Col(0, 1), Op::Col(0, 2), Op::MulVv];
+        let res = run_code(cols.clone(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![0.5, 1.0, 1.5]), Column::from(vec![0.5, 2.0, 6.0])]);
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Int(i64::MAX)), Op::AddVs];
+        assert!(matches!(run_code(cols.clone(), code), Err(VMError::Overflow)));
+        assert!(matches!(run_code(cols, vec![Op::Col(0, 0), Op::Lit(Scalar::Num(7.0)), Op::FilterEq]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn times_add_and_subtract() {
+        use crate::duration::{Nanos, NANOS_PER_MICRO};
+        use crate::timestamp::Micros;
+        let ts = |s: &str| Micros::parse(s).unwrap();
+        let span = |s: &str| Nanos::parse(s).unwrap();
+        let cols = vec![
+            Column::from(vec![ts("2024-03-01T12:00:00Z"), ts("2024-02-28T23:30:00Z")]),
+            Column::from(vec![span("1h30m"), span("1d")]),
+            Column::from(vec![ts("2024-03-01T10:00:00Z"), ts("2024-03-01T00:00:00Z")])
+        ];
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Duration(span("45m"))), Op::AddVs, Op::Col(0, 0), Op::Col(0, 1), Op::SubVv];
+        assert_eq!(run_code(cols.clone(), code).unwrap(), vec![
+            Column::from(vec![ts("2024-03-01T12:45:00Z"), ts("2024-02-29T00:15:00Z")]),
+            Column::from(vec![ts("2024-03-01T10:30:00Z"), ts("2024-02-27T23:30:00Z")])
+        ]);
+        let code = vec![Op::Col(0, 0), Op::Col(0, 2), Op::SubVv, Op::Col(0, 1), Op::Col(0, 1), Op::AddVv];
+        assert_eq!(run_code(cols.clone(), code).unwrap(), vec![Column::from(vec![span("2h"), span("-1d30m")]), Column::from(vec![span("3h"), span("2d")])]);
+        let code = vec![Op::Col(0, 1), Op::Lit(Scalar::Timestamp(ts("2024-01-01"))), Op::AddVs, Op::Col(0, 0), Op::Lit(Scalar::Timestamp(ts("2024-03-01"))), Op::SubVs];
+        assert_eq!(run_code(cols.clone(), code).unwrap(), vec![
+            Column::from(vec![ts("2024-01-01T01:30:00Z"), ts("2024-01-02")]),
+            Column::from(vec![span("12h"), span("-1d30m")])
+        ]);
+        let mut vm = VM::new(Table::from_columns(cols.clone()).unwrap());
+        vm.run(vec![Op::Col(0, 1), Op::Sum]).unwrap();
+        assert!(matches!(vm.stack().last(), Some(Value::Scalar(Scalar::Duration(d))) if *d == span("1d1h30m")));
+        let res = run_code(cols.clone(), vec![Op::Col(0, 0), Op::Lit(Scalar::Null), Op::SubVs]).unwrap();
+        assert_eq!((res[0].datatype(), res[0].get(1)), (Datatype::Timestamp, Some(Scalar::Null)));
+        // parts of a microsecond are dropped
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Duration(Nanos(-1))), Op::AddVs, Op::Col(0, 0), Op::Lit(Scalar::Duration(Nanos(1999))), Op::AddVs];
+        let res = run_code(cols.clone(), code).unwrap();
+        assert_eq!(res[0], Column::from(vec![Micros(ts("2024-03-01T12:00:00Z").0 - 1), Micros(ts("2024-02-28T23:30:00Z").0 - 1)]));
+        assert_eq!(res[1], Column::from(vec![Micros(ts("2024-03-01T12:00:00Z").0 + 1), Micros(ts("2024-02-28T23:30:00Z").0 + 1)]));
+        // out of range, whichever way
+        let far = vec![Column::from(vec![Micros(i64::MAX)]), Column::from(vec![Micros(i64::MIN)]), Column::from(vec![Nanos(i64::MAX)])];
+        for code in [
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Duration(Nanos(NANOS_PER_MICRO))), Op::AddVs],
+            vec![Op::Col(0, 0), Op::Col(0, 1), Op::SubVv],
+            vec![Op::Col(0, 2), Op::Col(0, 2), Op::AddVv],
+            vec![Op::Col(0, 1), Op::Lit(Scalar::Duration(Nanos(NANOS_PER_MICRO))), Op::SubVs]
+        ] {
+            assert!(matches!(run_code(far.clone(), code.clone()), Err(VMError::Overflow)), "{:?}", code);
+        }
+        // times don't multiply, or add to plain numbers or to each other
+        for code in [
+            vec![Op::Col(0, 1), Op::Col(0, 1), Op::MulVv],
+            vec![Op::Col(0, 0), Op::Col(0, 2), Op::AddVv],
+            vec![Op::Col(0, 1), Op::Col(0, 0), Op::SubVv],
+            vec![Op::Col(0, 0), Op::Lit(Scalar::Int(1)), Op::AddVs],
+            vec![Op::Col(0, 1), Op::Lit(Scalar::Duration(span("1s"))), Op::DivVs]
+        ] {
+            assert!(matches!(run_code(cols.clone(), code.clone()), Err(VMError::TypeError(_))), "{:?}", code);
+            let types = [Datatype::Timestamp, Datatype::Duration, Datatype::Timestamp];
+            assert!(matches!(crate::verify::validate(&code, &types), Err(VMError::TypeError(_))), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn comparison_filters() {
+        let mask = |cols: Vec<Column>, lit: Scalar, op: Op| {
+            let res = run_code(cols, vec![Op::Col(0, 0), Op::Lit(lit), op]).unwrap();
+            Vec::<bool>::try_from(&res[0]).unwrap()
+        };
+        let xs = || vec![Column::from(vec![1.0, 3.0, f64::NAN, 2.0])];
+        let two = Scalar::Num(2.0);
+        assert_eq!(mask(xs(), two.clone(), Op::FilterLt), vec![true, false, false, false]);
+        assert_eq!(mask(xs(), two.clone(), Op::FilterLe), vec![true, false, false, true]);
+        assert_eq!(mask(xs(), two.clone(), Op::FilterGt), vec![false, true, false, false]);
+        assert_eq!(mask(xs(), two.clone(), Op::FilterGe), vec![false, true, false, true]);
+        // null is neither equal nor unequal to anything
+        assert_eq!(mask(xs(), two, Op::FilterNe), vec![true, true, false, false]);
+
+        let names = || vec![Column::from(vec!["bob", "Zoe", "al"])];
+        assert_eq!(mask(names(), Scalar::Str("b".to_string()), Op::FilterGe), vec![true, false, false]);
+        let ids = vec![Column::from(vec![10u64, 11, 12])];
+        assert_eq!(mask(ids, Scalar::Entity(11), Op::FilterNe), vec![true, false, true]);
+
+        assert!(matches!(run_code(names(), vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterLt]), Err(VMError::TypeError(_))));
+        let flags = vec![Column::from(vec![true, false])];
+        assert!(matches!(run_code(flags, vec![Op::Col(0, 0), Op::Lit(Scalar::Bool(true)), Op::FilterLt]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn masks_combine() {
+        // x = 3 AND id > 11, x = 3 OR id > 12, NOT x = 3
+        let x_is_3 = || vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq];
+        let id_over = |n| vec![Op::Col(0, 1), Op::Lit(Scalar::Entity(n)), Op::FilterGt];
+        let ids = |code: Vec<Op>| {
+            let code = [code, vec![Op::Col(0, 1), Op::Select(1)]].concat();
+            Vec::<u64>::try_from(&run_code(columns(), code).unwrap()[0]).unwrap()
+        };
+        assert_eq!(ids([x_is_3(), id_over(11), vec![Op::And]].concat()), vec![12]);
+        assert_eq!(ids([x_is_3(), id_over(12), vec![Op::Or]].concat()), vec![11, 12, 13]);
+        assert_eq!(ids([x_is_3(), vec![Op::Not]].concat()), vec![10, 13]);
+
+        // a mask over the 2 rows where x = 3
+        let short = [x_is_3(), vec![Op::Col(0, 1), Op::Select(1), Op::Lit(Scalar::Entity(11)), Op::FilterEq]].concat();
+        let code = [x_is_3(), short, vec![Op::And]].concat();
+        assert!(matches!(run_code(columns(), code), Err(VMError::LengthMismatch { expected: 4, found: 2 })));
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 0), Op::Not]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn aggregates_push_scalars() {
+        // over the ids where x = 3, a view, and over a whole column
+        let of_view = |op| {
+            let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+            vm.run(vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 0), Op::Select(1), op]).unwrap();
+            match vm.take_stack().pop() {
+                Some(Value::Scalar(s)) => s,
+                v => panic!("expected a scalar, found {:?}", v)
+            }
+        };
+        assert_eq!(of_view(Op::Count), Scalar::Int(2));
+        assert_eq!(of_view(Op::Sum), Scalar::Num(6.0));
+        assert_eq!(of_view(Op::Mean), Scalar::Num(3.0));
+        let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+        vm.run(vec![Op::Col(0, 1), Op::Min, Op::Col(0, 0), Op::Max]).unwrap();
+        assert!(matches!(vm.stack(), [Value::Scalar(Scalar::Entity(10)), Value::Scalar(Scalar::Num(x))] if *x == 3.0));
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 1), Op::Sum]), Err(VMError::TypeError(_))));
+        assert!(matches!(run_code(columns(), vec![Op::Lit(Scalar::Num(1.0)), Op::Count]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn sorting_by_a_permutation() {
+        let code = vec![Op::Col(0, 0), Op::ArgSort];
+        assert_eq!(run_code(columns(), code).unwrap(), vec![Column::from(vec![0u64, 3, 1, 2])]);
+        // the ids in order of x, then the x values themselves
+        let code = vec![Op::Col(0, 0), Op::ArgSort, Op::Col(0, 1), Op::SortBy, Op::Col(0, 0), Op::ArgSort, Op::Col(0, 0), Op::SortBy];
+        let res = run_code(columns(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![10u64, 13, 11, 12]), Column::from(vec![1.0, 2.0, 3.0, 3.0])]);
+        // the order must be for a column of the same length, and only hold its row numbers
+        let short = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 1), Op::Select(1), Op::ArgSort, Op::Col(0, 1), Op::SortBy];
+        assert!(matches!(run_code(columns(), short), Err(VMError::LengthMismatch { expected: 4, found: 2 })));
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 1), Op::Col(0, 0), Op::SortBy]), Err(VMError::RowIndexOutOfRange { idx: 10, nrows: 4 })));
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 0), Op::Col(0, 1), Op::SortBy]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn take_gathers_rows_by_number() {
+        // a join, then a side's payload at its matching rows
+        let cols = vec![
+            Column::from(vec![7u64, 8, 7]), Column::from(vec!["a", "b", "c"]),
+            Column::from(vec![8u64, 7, 9]), Column::from(vec![0.5, 1.5, 2.5])
+        ];
+        let code = vec![Op::Col(0, 0), Op::Col(0, 2), Op::HashJoin, Op::Col(0, 3), Op::Take];
+        let res = run_code(cols.clone(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![0u64, 1, 2]), Column::from(vec![1.5, 0.5, 1.5])]);
+        let code = vec![Op::Col(0, 2), Op::Col(0, 0), Op::HashJoin, Op::Col(0, 1), Op::Take];
+        let res = run_code(cols.clone(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![0u64, 1, 1]), Column::from(vec!["b", "a", "c"])]);
+        // rows may repeat, but not run past the end
+        assert!(matches!(run_code(cols.clone(), vec![Op::Col(0, 0), Op::Col(0, 1), Op::Take]), Err(VMError::RowIndexOutOfRange { idx: 7, nrows: 3 })));
+        assert!(matches!(run_code(cols, vec![Op::Col(0, 3), Op::Col(0, 1), Op::Take]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn limit_keeps_the_first_rows() {
+        let code = vec![Op::Col(0, 0), Op::Limit(2), Op::Col(0, 1), Op::Limit(10)];
+        let res = run_code(columns(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![1.0, 3.0]), Column::from(vec![10u64, 11, 12, 13])]);
+        // of a view, the first of the rows it selects
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Num(1.0)), Op::FilterGt, Op::Col(0, 1), Op::Select(1), Op::Limit(2)];
+        assert_eq!(run_code(columns(), code).unwrap(), vec![Column::from(vec![11u64, 12])]);
+        assert_eq!(run_code(columns(), vec![Op::Col(0, 1), Op::Limit(0)]).unwrap(), vec![Column::from(Vec::<u64>::new())]);
+    }
+
+    #[test]
+    fn top_k_then_take() {
+        // the ids of the two largest x, ties going to the first row
+        let code = vec![Op::Col(0, 0), Op::TopK(2), Op::Col(0, 1), Op::Take];
+        assert_eq!(run_code(columns(), code).unwrap(), vec![Column::from(vec![11u64, 12])]);
+        let code = vec![Op::Col(0, 0), Op::TopK(3)];
+        assert_eq!(run_code(columns(), code).unwrap(), vec![Column::from(vec![1u64, 2, 3])]);
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 1), Op::TopK(1)]), Err(VMError::TypeError(_))));
+    }
+
+    // `col` with nulls at `rows`
+    fn with_nulls(col: Column, rows: &[u32]) -> Column {
+        let len = col.len();
+        Column::Nullable(crate::nulls::NullableColumn::with_nulls(col, &Selection::from_positions(rows.to_vec(), len)).unwrap())
+    }
+
+    #[test]
+    fn top_k_passes_over_nulls() {
+        let cols = vec![with_nulls(Column::from(vec![5.0, 9.0, 1.0, 7.0]), &[1]), with_nulls(Column::from(vec![3i64, 8, 6, 2]), &[1, 2])];
+        let res = run_code(cols, vec![Op::Col(0, 0), Op::TopK(2), Op::Col(0, 1), Op::TopK(5)]).unwrap();
+        assert_eq!(res, vec![Column::from(vec![3u64, 0]), Column::from(vec![0u64, 3])]);
+    }
+
+    #[test]
+    fn hash_join_leaves_out_null_keys() {
+        // the null keys' placeholders would match each other
+        let cols = vec![with_nulls(Column::from(vec![1u64, 2, 3]), &[1]), with_nulls(Column::from(vec![2u64, 1, 9]), &[0, 2])];
+        let join = |nulls| {
+            let mut vm = VM::new(Table::from_columns(cols.clone()).unwrap());
+            vm.set_null_semantics(nulls);
+            vm.run(vec![Op::Col(0, 0), Op::Col(0, 1), Op::HashJoin]).unwrap();
+            vm.stack().iter().map(|v| vm.column_of(v).unwrap().clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(join(NullSemantics::Sql), vec![Column::from(vec![0u64]), Column::from(vec![1u64])]);
+        assert_eq!(join(NullSemantics::NullsDistinct), vec![Column::from(vec![0u64]), Column::from(vec![1u64])]);
+        assert_eq!(join(NullSemantics::NullEqualsNull), vec![Column::from(vec![0u64, 1, 1]), Column::from(vec![1u64, 0, 2])]);
+        let ints = vec![with_nulls(Column::from(vec![1i64, 2]), &[0]), Column::from(vec![1i64, 2])];
+        let res = run_code(ints, vec![Op::Col(0, 0), Op::Col(0, 1), Op::HashJoin]).unwrap();
+        assert_eq!(res, vec![Column::from(vec![1u64]), Column::from(vec![1u64])]);
+    }
+
+    #[test]
+    fn point_kernels_pass_over_nulls() {
+        use crate::geo::Point;
+        let points = vec![with_nulls(Column::from(vec![Point::new(0.0, 0.0), Point::new(1.0, 1.0), Point::new(3.0, 4.0)]), &[1])];
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Point(Point::new(-1.0, -1.0))), Op::Lit(Scalar::Point(Point::new(5.0, 5.0))), Op::FilterWithinBBox];
+        assert_eq!(Vec::<bool>::try_from(&run_code(points.clone(), code).unwrap()[0]).unwrap(), vec![true, false, true]);
+        let res = run_code(points, vec![Op::Col(0, 0), Op::Lit(Scalar::Point(Point::new(0.0, 0.0))), Op::DistanceTo]).unwrap();
+        assert_eq!((0 .. 3).map(|i| res[0].get(i).unwrap()).collect::<Vec<_>>(), vec![Scalar::Num(0.0), Scalar::Null, Scalar::Num(5.0)]);
+    }
+
+    #[test]
+    fn cidr_filters_pass_over_nulls() {
+        let addrs = vec![with_nulls(Column::from(vec!["10.0.0.1".parse::<crate::ip::Ipv4Addr>().unwrap(); 3]), &[1])];
+        let code = vec![Op::Col(0, 0), Op::Lit(Scalar::Ipv4("10.0.0.0".parse().unwrap())), Op::FilterInCidr(8)];
+        assert_eq!(Vec::<bool>::try_from(&run_code(addrs, code).unwrap()[0]).unwrap(), vec![true, false, true]);
+    }
+
+    #[test]
+    fn hash_join_pushes_matching_rows() {
+        // column 0's keys on the left, column 1's on the right
+        let cols = vec![Column::from(vec![10u64, 11, 12, 13]), Column::from(vec![12u64, 10, 12, 99])];
+        let res = run_code(cols.clone(), vec![Op::Col(0, 0), Op::Col(0, 1), Op::HashJoin]).unwrap();
+        assert_eq!(res, vec![Column::from(vec![0u64, 2, 2]), Column::from(vec![1u64, 0, 2])]);
+        // a view's rows are numbered within the view
+        let code = vec![Op::Col(0, 1), Op::Lit(Scalar::Entity(50)), Op::FilterLt, Op::Col(0, 1), Op::Select(1), Op::Col(0, 0), Op::HashJoin];
+        let res = run_code(cols, code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![0u64, 1, 2]), Column::from(vec![2u64, 0, 2])]);
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 0), Op::Col(0, 1), Op::HashJoin]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn ints_aggregate_rank_and_join() {
+        let big = 9_007_199_254_740_993i64;     // past f64's exact integers
+        let cols = vec![Column::from(vec![big, 1, -4, 1])];
+        let mut vm = VM::new(Table::from_columns(cols.clone()).unwrap());
+        vm.run(vec![Op::Col(0, 0), Op::Sum, Op::Col(0, 0), Op::Mean, Op::Col(0, 0), Op::Count]).unwrap();
+        assert!(matches!(vm.stack(), [Value::Scalar(Scalar::Int(s)), Value::Scalar(Scalar::Num(_)), Value::Scalar(Scalar::Int(4))] if *s == big - 2));
+        let overflow = vec![Column::from(vec![i64::MAX, 1])];
+        assert!(matches!(run_code(overflow, vec![Op::Col(0, 0), Op::Sum]), Err(VMError::Overflow)));
+        // the rows of the two largest, and the pairs of equal keys
+        assert_eq!(run_code(cols, vec![Op::Col(0, 0), Op::TopK(2)]).unwrap(), vec![Column::from(vec![0u64, 1])]);
+        let pairs = run_code(vec![Column::from(vec![1i64, 5, 1]), Column::from(vec![1i64, 7, 5])], vec![Op::Col(0, 0), Op::Col(0, 1), Op::HashJoin]).unwrap();
+        assert_eq!(pairs, vec![Column::from(vec![0u64, 1, 2]), Column::from(vec![0u64, 2, 0])]);
+        // by key, the count and exact sum of each group
+        let grouped = vec![Column::from(vec![1u64, 2, 1]), Column::from(vec![big, 3, 2])];
+        assert_eq!(run_code(grouped.clone(), vec![Op::Col(0, 1), Op::GroupBy(0), Op::Sum]).unwrap()[1], Column::from(vec![big + 2, 3]));
+        assert_eq!(run_code(grouped, vec![Op::Col(0, 1), Op::GroupBy(0), Op::Count]).unwrap()[1], Column::from(vec![2i64, 1]));
+    }
+
+    #[test]
+    fn group_by_feeds_aggregates() {
+        // the ids of each x, and how many there are
+        let res = run_code(columns(), vec![Op::Col(0, 1), Op::GroupBy(0), Op::Count]).unwrap();
+        assert_eq!(res, vec![Column::from(vec![1.0, 3.0, 2.0]), Column::from(vec![1i64, 2, 1])]);
+        let res = run_code(columns(), vec![Op::Col(0, 1), Op::GroupBy(0), Op::Max]).unwrap();
+        assert_eq!(res[1], Column::from(vec![10u64, 12, 13]));
+        // a view groups by its own rows' keys: the x values of the ids over 10, summed by x
+        let code = vec![Op::Col(0, 1), Op::Lit(Scalar::Entity(10)), Op::FilterGt, Op::Col(0, 0), Op::Select(1), Op::GroupBy(0), Op::Sum];
+        let res = run_code(columns(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec![3.0, 2.0]), Column::from(vec![6.0, 2.0])]);
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 1), Op::GroupBy(2)]), Err(VMError::ColumnIndexOutOfRange { idx: 2, ncols: 2 })));
+        // the counts have a row per group, not per row of the table
+        let counts = vec![Op::Col(0, 1), Op::GroupBy(0), Op::Count, Op::GroupBy(0)];
+        assert!(matches!(run_code(columns(), counts), Err(VMError::LengthMismatch { expected: 4, found: 3 })));
+    }
+
+    #[test]
+    fn list_columns_filter_by_length_and_item() {
+        let lists = list::ListColumn::new(vec![0, 2, 2, 5, 6], Column::from(vec![1.0, 2.0, 2.0, 3.0, 4.0, 1.0])).unwrap();
+        let cols = vec![Column::List(lists), Column::from(vec![10u64, 11, 12, 13])];
+        let ids_where = |filter: Vec<Op>| {
+            let code = [vec![Op::Col(0, 0)], filter, vec![Op::Col(0, 1), Op::Select(1)]].concat();
+            run_code(cols.clone(), code).unwrap()
+        };
+        assert_eq!(ids_where(vec![Op::ListLen, Op::Lit(Scalar::Entity(1)), Op::FilterGt]), vec![Column::from(vec![10u64, 12])]);
+        assert_eq!(ids_where(vec![Op::Lit(Scalar::Num(1.0)), Op::FilterContains]), vec![Column::from(vec![10u64, 13])]);
+        assert_eq!(ids_where(vec![Op::Lit(Scalar::List(vec![])), Op::FilterEq]), vec![Column::from(vec![11u64])]);
+        assert!(matches!(run_code(columns(), vec![Op::Col(0, 0), Op::ListLen]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn field_projects_record_columns() {
+        let rec = |x, name: &str| vec![Scalar::Num(x), Scalar::Str(name.to_string())];
+        let records = RecordColumn::from_records(vec![Datatype::Num, Datatype::Str], &[rec(1.0, "a"), rec(3.0, "b"), rec(3.0, "c")]).unwrap();
+        let cols = vec![Column::Record(records), Column::from(vec![10u64, 11, 12])];
+        // the names of the records with x = 3, and the ids of the record (3, "c")
+        let code = vec![
+            Op::Col(0, 0), Op::Field(0), Op::Lit(Scalar::Num(3.0)), Op::FilterEq, Op::Col(0, 0), Op::Select(1), Op::Field(1),
+            Op::Col(0, 0), Op::Lit(Scalar::Record(rec(3.0, "c"))), Op::FilterEq, Op::Col(0, 1), Op::Select(1)
+        ];
+        let res = run_code(cols.clone(), code).unwrap();
+        assert_eq!(res, vec![Column::from(vec!["b", "c"]), Column::from(vec![12u64])]);
+        assert!(matches!(run_code(cols.clone(), vec![Op::Col(0, 0), Op::Field(2)]), Err(VMError::TypeError(_))));
+        assert!(matches!(run_code(cols, vec![Op::Col(0, 1), Op::Field(0)]), Err(VMError::TypeError(_))));
+    }
+
+    #[test]
+    fn field_picks_from_records() {
+        let fields = [Scalar::Entity(7), Scalar::Record(vec![Scalar::Bool(true)])];
+        let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+        vm.run(vec![record(fields.to_vec()), Op::Field(1), Op::Field(0), record(fields.to_vec()), Op::Field(0)]).unwrap();
+        assert!(matches!(vm.stack(), [Value::Scalar(Scalar::Bool(true)), Value::Scalar(Scalar::Entity(7))]));
+
+        for code in [vec![record(fields.to_vec()), Op::Field(2)], vec![Op::Lit(Scalar::Num(1.0)), Op::Field(0)]].iter() {
+            let mut vm = VM::new(Table::from_columns(columns()).unwrap());
+            assert!(matches!(vm.run(code.clone()), Err(VMError::TypeError(_))), "{:?}", code);
+        }
+    }
+}
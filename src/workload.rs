@@ -0,0 +1,422 @@
+// A TPC-H flavoured mini workload: customers, their orders and the orders' line items,
+// joined by entity keys, plus a suite of queries over them whose answers are worked out
+// independently, by plain loops over the generated rows. Running the suite checks the engine
+// end to end (`collie workload`), and timing it makes a benchmark (benches/workload.rs).
+//
+// Queries that need more than the VM does run the missing steps through the library (a join
+// through join::hash_join, a group-by through udf::aggregate_groups); as those steps become
+// ops, their queries should move onto them, keeping the same answers.
+
+use crate::catalog::Catalog;
+use crate::column::{Column, Scalar};
+use crate::conditional::Branch;
+use crate::datagen::Rng;
+use crate::errors::VMError;
+use crate::join;
+use crate::nulls::NullSemantics;
+use crate::opcode::Op;
+use crate::result::ResultSet;
+use crate::table::Table;
+use crate::udf::{self, Accumulator, Udf};
+use crate::vm::{ColumnMode, VM};
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SEGMENTS: [&str; 5] = ["automobile", "building", "furniture", "household", "machinery"];
+const STATUSES: [&str; 3] = ["open", "shipped", "returned"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Customer {
+    pub id: u64,
+    pub name: String,
+    pub segment: &'static str
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub id: u64,
+    pub customer: u64,
+    pub status: &'static str,
+    pub priority: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lineitem {
+    pub order: u64,
+    pub part: u64,
+    pub quantity: f64,      // whole units
+    pub price: f64          // whole cents, so sums are exact in any order
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    pub customers: Vec<Customer>,
+    pub orders: Vec<Order>,
+    pub lineitems: Vec<Lineitem>
+}
+
+// The same three tables, for the VM: each table's columns, in the order Op::Col numbers them
+const TABLES: [(&str, &[&str]); 3] = [
+    ("customers", &["id", "name", "segment"]),
+    ("orders", &["id", "customer", "status", "priority"]),
+    ("lineitems", &["order", "part", "quantity", "price"])
+];
+
+// Op::Col for "table.column"
+fn col(name: &str) -> Op {
+    let (table, column) = name.split_once('.').expect("a table.column name");
+    let t = TABLES.iter().position(|(n, _)| *n == table).expect("a workload table");
+    Op::Col(t, TABLES[t].1.iter().position(|c| *c == column).expect("a workload column"))
+}
+
+impl Workload {
+    // `customers` customers with four orders each on average, and one to seven line items
+    // per order. Ids count from 1.
+    pub fn generate(customers: usize, seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let customers: Vec<Customer> = (1 ..= customers as u64).map(|id| Customer {
+            id,
+            name: format!("Customer#{:06}", id),
+            segment: SEGMENTS[rng.below(SEGMENTS.len() as u64) as usize]
+        }).collect();
+        let orders: Vec<Order> = (1 ..= 4 * customers.len() as u64).map(|id| Order {
+            id,
+            customer: 1 + rng.below(customers.len().max(1) as u64),
+            status: STATUSES[rng.below(STATUSES.len() as u64) as usize],
+            priority: rng.below(5) == 0
+        }).collect();
+        let mut lineitems = Vec::new();
+        for order in &orders {
+            for _ in 0 ..= rng.below(7) {
+                lineitems.push(Lineitem {
+                    order: order.id,
+                    part: 1 + rng.below(2000),
+                    quantity: (1 + rng.below(50)) as f64,
+                    price: (100 + rng.below(9901)) as f64
+                });
+            }
+        }
+        Workload { customers, orders, lineitems }
+    }
+
+    pub fn customers_table(&self) -> ResultSet {
+        let cs = &self.customers;
+        ResultSet::from(vec![
+            ("id", Column::from(cs.iter().map(|c| c.id).collect::<Vec<_>>())),
+            ("name", Column::from(cs.iter().map(|c| c.name.clone()).collect::<Vec<_>>())),
+            ("segment", Column::from(cs.iter().map(|c| c.segment).collect::<Vec<_>>()))
+        ])
+    }
+
+    pub fn orders_table(&self) -> ResultSet {
+        let os = &self.orders;
+        ResultSet::from(vec![
+            ("id", Column::from(os.iter().map(|o| o.id).collect::<Vec<_>>())),
+            ("customer", Column::from(os.iter().map(|o| o.customer).collect::<Vec<_>>())),
+            ("status", Column::from(os.iter().map(|o| o.status).collect::<Vec<_>>())),
+            ("priority", Column::from(os.iter().map(|o| o.priority).collect::<Vec<_>>()))
+        ])
+    }
+
+    pub fn lineitems_table(&self) -> ResultSet {
+        let ls = &self.lineitems;
+        ResultSet::from(vec![
+            ("order", Column::from(ls.iter().map(|l| l.order).collect::<Vec<_>>())),
+            ("part", Column::from(ls.iter().map(|l| l.part).collect::<Vec<_>>())),
+            ("quantity", Column::from(ls.iter().map(|l| l.quantity).collect::<Vec<_>>())),
+            ("price", Column::from(ls.iter().map(|l| l.price).collect::<Vec<_>>()))
+        ])
+    }
+
+    // (name, table) for each table, e.g. to load into a Db
+    pub fn tables(&self) -> Vec<(String, ResultSet)> {
+        vec![
+            ("customers".to_string(), self.customers_table()),
+            ("orders".to_string(), self.orders_table()),
+            ("lineitems".to_string(), self.lineitems_table())
+        ]
+    }
+
+    // The tables a query's VM is loaded with
+    pub fn catalog(&self) -> Catalog {
+        let mut catalog = Catalog::new();
+        for (name, rs) in self.tables() {
+            catalog.add(&name, Table::try_from(rs).expect("columns of one length")).expect("distinct table names");
+        }
+        catalog
+    }
+
+    pub fn vm(&self) -> VM {
+        let mut vm = VM::with_catalog(self.catalog(), ColumnMode::Rc);
+        vm.register_udaf("max", || Box::new(Max(None)));
+        vm
+    }
+}
+
+// The largest Num argument
+struct Max(Option<f64>);
+
+impl Accumulator for Max {
+    fn update(&mut self, args: &[Scalar]) {
+        if let Some(Scalar::Num(x)) = args.first() {
+            self.0 = Some(self.0.map_or(*x, |m| m.max(*x)));
+        }
+    }
+
+    fn state(&self) -> Vec<Scalar> {
+        self.0.map(Scalar::Num).into_iter().collect()
+    }
+
+    fn merge(&mut self, state: &[Scalar]) {
+        self.update(state);
+    }
+
+    fn finish(&self) -> Scalar {
+        Scalar::Num(self.0.unwrap_or(f64::NAN))
+    }
+}
+
+// Sum of quantity * price
+struct Revenue(f64);
+
+impl Accumulator for Revenue {
+    fn update(&mut self, args: &[Scalar]) {
+        if let [Scalar::Num(quantity), Scalar::Num(price)] = args {
+            self.0 += quantity * price;
+        }
+    }
+
+    fn state(&self) -> Vec<Scalar> {
+        vec![Scalar::Num(self.0)]
+    }
+
+    fn merge(&mut self, state: &[Scalar]) {
+        if let [Scalar::Num(x)] = state {
+            self.0 += x;
+        }
+    }
+
+    fn finish(&self) -> Scalar {
+        Scalar::Num(self.0)
+    }
+}
+
+pub struct Query {
+    pub name: &'static str,
+    pub description: &'static str,
+    run: fn(&Workload) -> Result<ResultSet, VMError>,
+    answer: fn(&Workload) -> ResultSet
+}
+
+impl Query {
+    // The engine's result
+    pub fn run(&self, w: &Workload) -> Result<ResultSet, VMError> {
+        (self.run)(w)
+    }
+
+    // The known answer
+    pub fn answer(&self, w: &Workload) -> ResultSet {
+        (self.answer)(w)
+    }
+}
+
+// Run `code` against the workload's VM, naming what it leaves on the stack `names`
+fn run_vm(w: &Workload, code: Vec<Op>, names: &[&str]) -> Result<ResultSet, VMError> {
+    let mut vm = w.vm();
+    vm.run(code)?;
+    let stack = vm.take_stack();
+    if stack.len() != names.len() {
+        return Err(VMError::LengthMismatch { expected: names.len(), found: stack.len() });
+    }
+    let mut res = ResultSet::new();
+    for (name, v) in names.iter().zip(&stack) {
+        let col = vm.column_of(v).ok_or_else(|| VMError::TypeError(format!("Expected a column, found: {:?}", v)))?;
+        res.push(name, col.clone());
+    }
+    Ok(res)
+}
+
+fn customer_orders(w: &Workload) -> Result<ResultSet, VMError> {
+    let code = vec![col("orders.customer"), Op::Lit(Scalar::Entity(7)), Op::FilterEq, col("orders.id"), Op::Select(1)];
+    run_vm(w, code, &["id"])
+}
+
+fn customer_orders_answer(w: &Workload) -> ResultSet {
+    let ids: Vec<u64> = w.orders.iter().filter(|o| o.customer == 7).map(|o| o.id).collect();
+    ResultSet::from(vec![("id", Column::from(ids))])
+}
+
+fn segment_names(w: &Workload) -> Result<ResultSet, VMError> {
+    let code = vec![
+        col("customers.segment"), Op::Lit(Scalar::Str("machinery".to_string())), col("customers.name"), Op::FilterSelect
+    ];
+    run_vm(w, code, &["name"])
+}
+
+fn segment_names_answer(w: &Workload) -> ResultSet {
+    let names: Vec<&str> = w.customers.iter().filter(|c| c.segment == "machinery").map(|c| c.name.as_str()).collect();
+    ResultSet::from(vec![("name", Column::from(names))])
+}
+
+fn shipped_quantities(w: &Workload) -> Result<ResultSet, VMError> {
+    let code = vec![
+        col("lineitems.order"),
+        col("orders.status"), Op::Lit(Scalar::Str("shipped".to_string())), Op::FilterEq, col("orders.id"), Op::Select(1),
+        Op::FilterIn,
+        col("lineitems.quantity"), Op::Select(1)
+    ];
+    run_vm(w, code, &["quantity"])
+}
+
+fn shipped_quantities_answer(w: &Workload) -> ResultSet {
+    let shipped: Vec<bool> = w.orders.iter().map(|o| o.status == "shipped").collect();
+    let quantities: Vec<f64> = w.lineitems.iter()
+        .filter(|l| shipped[l.order as usize - 1])
+        .map(|l| l.quantity)
+        .collect();
+    ResultSet::from(vec![("quantity", Column::from(quantities))])
+}
+
+fn order_labels(w: &Workload) -> Result<ResultSet, VMError> {
+    let code = vec![col("orders.priority"), Op::Lit(Scalar::Str("urgent".to_string())), col("orders.status"), Op::IfElse];
+    run_vm(w, code, &["label"])
+}
+
+fn order_labels_answer(w: &Workload) -> ResultSet {
+    let labels: Vec<&str> = w.orders.iter().map(|o| if o.priority { "urgent" } else { o.status }).collect();
+    ResultSet::from(vec![("label", Column::from(labels))])
+}
+
+fn largest_lineitems(w: &Workload) -> Result<ResultSet, VMError> {
+    let max = vec![col("lineitems.quantity"), Op::CallUdaf(0, 1)];
+    let code = vec![
+        col("lineitems.quantity"), Op::ScalarSubquery(max), Op::FilterEq, col("lineitems.order"), Op::Select(1)
+    ];
+    run_vm(w, code, &["order"])
+}
+
+fn largest_lineitems_answer(w: &Workload) -> ResultSet {
+    let max = w.lineitems.iter().map(|l| l.quantity).fold(f64::NAN, f64::max);
+    let orders: Vec<u64> = w.lineitems.iter().filter(|l| l.quantity == max).map(|l| l.order).collect();
+    ResultSet::from(vec![("order", Column::from(orders))])
+}
+
+// Revenue per customer, over customers with orders, by customer id
+fn customer_revenue(w: &Workload) -> Result<ResultSet, VMError> {
+    let joined = join::hash_join(&w.lineitems_table(), "order", &w.orders_table(), "id", NullSemantics::default())?;
+    let customers: Vec<u64> = Vec::try_from(joined.column("customer").expect("orders.customer"))?;
+    let mut ids: Vec<u64> = customers.clone();
+    ids.sort_unstable();
+    ids.dedup();
+    let groups: Vec<usize> = customers.iter().map(|c| ids.binary_search(c).expect("a customer id")).collect();
+    let revenue = Udf::Aggregate(Arc::new(|| Box::new(Revenue(0.0)) as Box<dyn Accumulator>));
+    let args = [
+        Branch::Column(joined.column("quantity").expect("lineitems.quantity")),
+        Branch::Column(joined.column("price").expect("lineitems.price"))
+    ];
+    let totals = udf::aggregate_groups(&revenue, &args, &groups, ids.len())?;
+    let totals = totals.into_iter().map(|t| match t {
+        Scalar::Num(x) => Ok(x),
+        t => Err(VMError::TypeError(format!("Expected a Num revenue, got: {:?}", t)))
+    }).collect::<Result<Vec<f64>, _>>()?;
+    Ok(ResultSet::from(vec![("customer", Column::from(ids)), ("revenue", Column::from(totals))]))
+}
+
+fn customer_revenue_answer(w: &Workload) -> ResultSet {
+    let mut revenue: BTreeMap<u64, f64> = BTreeMap::new();
+    for l in &w.lineitems {
+        let order = &w.orders[l.order as usize - 1];
+        *revenue.entry(order.customer).or_insert(0.0) += l.quantity * l.price;
+    }
+    ResultSet::from(vec![
+        ("customer", Column::from(revenue.keys().copied().collect::<Vec<_>>())),
+        ("revenue", Column::from(revenue.values().copied().collect::<Vec<_>>()))
+    ])
+}
+
+pub fn queries() -> Vec<Query> {
+    vec![
+        Query {
+            name: "customer_orders", description: "ids of customer 7's orders",
+            run: customer_orders, answer: customer_orders_answer
+        },
+        Query {
+            name: "segment_names", description: "names of the customers in the machinery segment",
+            run: segment_names, answer: segment_names_answer
+        },
+        Query {
+            name: "shipped_quantities", description: "quantities of the line items of shipped orders",
+            run: shipped_quantities, answer: shipped_quantities_answer
+        },
+        Query {
+            name: "order_labels", description: "\"urgent\" for priority orders, otherwise the order's status",
+            run: order_labels, answer: order_labels_answer
+        },
+        Query {
+            name: "largest_lineitems", description: "orders of the line items with the largest quantity",
+            run: largest_lineitems, answer: largest_lineitems_answer
+        },
+        Query {
+            name: "customer_revenue", description: "revenue per customer, joining line items to orders",
+            run: customer_revenue, answer: customer_revenue_answer
+        }
+    ]
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub query: &'static str,
+    pub result: Result<(), String>,     // Err: how the engine's result differed, or failed
+    pub elapsed: Duration               // running the query, not checking it
+}
+
+// Run every query and compare with its known answer
+pub fn check(w: &Workload) -> Vec<Outcome> {
+    queries().iter().map(|q| {
+        let start = Instant::now();
+        let res = q.run(w);
+        let elapsed = start.elapsed();
+        let result = match res {
+            Ok(res) if res == q.answer(w) => Ok(()),
+            Ok(res) => Err(format!("expected\n{}\ngot\n{}", q.answer(w), res)),
+            Err(e) => Err(format!("{:?}", e))
+        };
+        Outcome { query: q.name, result, elapsed }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_seed_always_generates_the_same_workload() {
+        assert_eq!(Workload::generate(30, 5), Workload::generate(30, 5));
+        assert_ne!(Workload::generate(30, 5), Workload::generate(30, 6));
+    }
+
+    #[test]
+    fn keys_refer_to_existing_rows() {
+        let w = Workload::generate(100, 9);
+        assert_eq!((w.customers.len(), w.orders.len()), (100, 400));
+        assert!(w.orders.iter().all(|o| (1 ..= 100).contains(&o.customer)));
+        for order in &w.orders {
+            let items = w.lineitems.iter().filter(|l| l.order == order.id).count();
+            assert!((1 ..= 7).contains(&items), "order {} has {} line items", order.id, items);
+        }
+    }
+
+    #[test]
+    fn tables_line_up_with_the_vm_columns() {
+        let w = Workload::generate(20, 1);
+        let catalog = w.catalog();
+        assert_eq!(catalog.names().collect::<Vec<_>>(), TABLES.iter().map(|(n, _)| *n).collect::<Vec<_>>());
+        for ((_, table), (name, columns)) in catalog.iter().zip(TABLES.iter()) {
+            assert_eq!(table.names().collect::<Vec<_>>(), columns.to_vec(), "{}", name);
+        }
+        assert_eq!(col("orders.status"), Op::Col(1, 2));
+    }
+}
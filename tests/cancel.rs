@@ -0,0 +1,91 @@
+// Stopping a run from outside: a timeout, and a CancelToken set from another thread. A UDF
+// that sleeps on each call stands in for slow instructions, since the VM only checks
+// between them.
+
+use collie::cancel::CancelToken;
+use collie::cursor::Cursor;
+use collie::vm::VM;
+use collie::*;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NAP: Duration = Duration::from_millis(10);
+const CALLS: usize = 100;
+
+// a VM with a "nap" function, which sleeps for NAP and returns its argument
+fn vm() -> (VM, usize) {
+    let mut vm = VM::new(Table::from_columns(vec![Column::from(vec![1.0, 2.0, 3.0])]).unwrap());
+    let nap = vm.register_udf("nap", |args| {
+        thread::sleep(NAP);
+        args[0].clone()
+    });
+    (vm, nap)
+}
+
+// CALLS naps in a row, a second's worth, leaving 1.0
+fn slow(nap: usize) -> Vec<Op> {
+    let mut code = vec![Op::Lit(Scalar::Num(1.0))];
+    code.extend((0 .. CALLS).map(|_| Op::CallUdf(nap, 1)));
+    code
+}
+
+#[test]
+fn times_out() {
+    let (mut vm, nap) = vm();
+    vm.set_timeout(Some(Duration::from_millis(100)));
+    let start = Instant::now();
+    assert!(matches!(vm.run(slow(nap)), Err(VMError::TimedOut)));
+    let took = start.elapsed();
+    assert!(took >= Duration::from_millis(100) && took < Duration::from_millis(500), "stopped after {:?}", took);
+    // the limit is per run, not per VM
+    assert!(vm.run(vec![Op::Lit(Scalar::Num(1.0)), Op::CallUdf(nap, 1)]).is_ok());
+}
+
+#[test]
+fn cancels_from_another_thread() {
+    let (mut vm, nap) = vm();
+    let token = CancelToken::new();
+    vm.set_cancel_token(Some(token.clone()));
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(150));
+        token.cancel();
+    });
+    let start = Instant::now();
+    assert!(matches!(vm.run(slow(nap)), Err(VMError::Cancelled)));
+    let took = start.elapsed();
+    assert!(took >= Duration::from_millis(150) && took < Duration::from_millis(550), "stopped after {:?}", took);
+    canceller.join().unwrap();
+}
+
+#[test]
+fn a_cancelled_token_stops_runs_until_reset() {
+    let (mut vm, _) = vm();
+    let token = CancelToken::new();
+    token.cancel();
+    vm.set_cancel_token(Some(token.clone()));
+    assert!(matches!(vm.run(vec![Op::Col(0, 0)]), Err(VMError::Cancelled)));
+    token.reset();
+    assert!(vm.run(vec![Op::Col(0, 0)]).is_ok());
+}
+
+#[test]
+fn subqueries_share_the_time_left() {
+    let (mut vm, nap) = vm();
+    vm.set_timeout(Some(Duration::from_millis(100)));
+    let code = vec![Op::Col(0, 0), Op::ScalarSubquery(slow(nap)), Op::FilterEq];
+    let start = Instant::now();
+    assert!(matches!(vm.run(code), Err(VMError::TimedOut)));
+    assert!(start.elapsed() < Duration::from_millis(500), "stopped after {:?}", start.elapsed());
+}
+
+#[test]
+fn cursors_stop_between_windows() {
+    let (mut vm, nap) = vm();
+    let token = CancelToken::new();
+    vm.set_cancel_token(Some(token.clone()));
+    let mut cursor = Cursor::new(vm, vec![Op::Col(0, 0), Op::CallUdf(nap, 1)], 1).unwrap().with_window_rows(1);
+    assert!(cursor.next_batch().unwrap().is_some());
+    token.cancel();
+    assert!(matches!(cursor.next_batch(), Err(VMError::Cancelled)));
+}
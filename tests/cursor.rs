@@ -0,0 +1,90 @@
+// Paging through results with a Cursor, against a single run over the whole table: the
+// batches must join up to the same columns, and any page must match the one iterating gives.
+
+use collie::cursor::Cursor;
+use collie::datagen;
+use collie::*;
+
+const ROWS: usize = 100_000;
+
+fn vm() -> VM {
+    let (schema, columns) = datagen::people(ROWS, 7);
+    VM::new(Table::with_schema(schema, columns).unwrap())
+}
+
+// name and score of the people in the first row's city
+fn program() -> Vec<Op> {
+    let (_, columns) = datagen::people(1, 7);
+    let city = columns[3].get(0).unwrap();
+    let in_city = || vec![Op::Col(0, 3), Op::Lit(city.clone()), Op::FilterEq];
+    let mut code = in_city();
+    code.extend(vec![Op::Col(0, 1), Op::Select(1)]);
+    code.extend(in_city());
+    code.extend(vec![Op::Col(0, 4), Op::Select(1)]);
+    code
+}
+
+fn full_run() -> Vec<Column> {
+    let mut vm = vm();
+    vm.run(program()).unwrap();
+    vm.stack().iter().map(|v| vm.column_of(v).unwrap().clone()).collect()
+}
+
+fn cursor(batch_rows: usize, window_rows: usize) -> Cursor {
+    Cursor::new(vm(), program(), batch_rows).unwrap().with_window_rows(window_rows).with_names(&["name", "score"])
+}
+
+fn concat(batches: &[ResultSet], name: &str) -> Column {
+    Column::concat(&batches.iter().map(|b| b.column(name).unwrap().clone()).collect::<Vec<_>>()).unwrap()
+}
+
+#[test]
+fn batches_join_up_to_a_full_run() {
+    let expected = full_run();
+    assert!(expected[0].len() > 2000);
+    for &(batch_rows, window_rows) in &[(1000, 3000), (4096, 1000), (777, 777), (ROWS, ROWS)] {
+        let batches = cursor(batch_rows, window_rows).collect::<Result<Vec<_>, _>>().unwrap();
+        let (last, full) = batches.split_last().unwrap();
+        assert!(full.iter().all(|b| b.rows() == batch_rows), "{} row batches", batch_rows);
+        assert!(last.rows() > 0 && last.rows() <= batch_rows);
+        assert_columns_eq!(concat(&batches, "name"), expected[0], "{} row batches, {} row windows", batch_rows, window_rows);
+        assert_columns_eq!(concat(&batches, "score"), expected[1], "{} row batches, {} row windows", batch_rows, window_rows);
+    }
+}
+
+#[test]
+fn pages_match_iteration() {
+    let batches = cursor(1000, 3000).collect::<Result<Vec<_>, _>>().unwrap();
+    let mut c = cursor(1000, 3000);
+    for &n in &[3, 4, 0, batches.len() - 1, 2, 2, 1] {
+        assert_columns_eq!(c.page(n).unwrap().unwrap(), batches[n], "page {}", n);
+        assert_eq!(c.position(), n + 1);
+    }
+    assert!(c.page(batches.len()).unwrap().is_none());
+    assert!(c.next_batch().unwrap().is_none());
+    c.rewind();
+    assert_columns_eq!(c.next_batch().unwrap().unwrap(), batches[0]);
+}
+
+#[test]
+fn unnamed_results_are_numbered() {
+    let mut c = Cursor::new(vm(), program(), 10).unwrap();
+    let batch = c.next_batch().unwrap().unwrap();
+    assert_eq!(batch.iter().map(|(name, _)| name).collect::<Vec<_>>(), vec!["#0", "#1"]);
+}
+
+#[test]
+fn pages_an_empty_result() {
+    let code = vec![Op::Col(0, 3), Op::Lit(Scalar::Str("nowhere".to_string())), Op::FilterEq, Op::Col(0, 1), Op::Select(1)];
+    let mut c = Cursor::new(vm(), code, 1000).unwrap().with_window_rows(3000);
+    assert!(c.next_batch().unwrap().is_none());
+    assert!(c.page(0).unwrap().is_none());
+}
+
+#[test]
+fn sizes_must_be_positive() {
+    assert!(matches!(Cursor::new(vm(), program(), 0), Err(VMError::TypeError(_))));
+    // a window of no rows is one of a row
+    let first = cursor(10, 3000).next_batch().unwrap().unwrap();
+    assert_columns_eq!(cursor(10, 0).next_batch().unwrap().unwrap(), first);
+}
@@ -0,0 +1,72 @@
+// One set of loaded columns queried from several threads at once, each through a VM of its
+// own (VM::with_shared_columns), against the same queries run on one thread.
+
+use collie::datagen;
+use collie::*;
+
+use std::sync::Arc;
+use std::thread;
+
+const THREADS: usize = 4;
+
+fn loaded() -> VM {
+    let (schema, columns) = datagen::people(50_000, 11);
+    VM::new(Table::with_schema(schema, columns).unwrap())
+}
+
+// the names and scores of the people of one age
+fn queries(age: f64) -> Vec<Vec<Op>> {
+    let of_age = || vec![Op::Col(0, 2), Op::Lit(Scalar::Num(age)), Op::FilterEq];
+    let mut score = of_age();
+    score.extend(vec![Op::Col(0, 4), Op::Select(1)]);
+    let mut names = of_age();
+    names.extend(vec![Op::Col(0, 1), Op::Select(1)]);
+    vec![score, names]
+}
+
+fn ages(thread: usize) -> impl Iterator<Item=f64> {
+    (0 .. 10).map(move |i| (20 + thread * 10 + i * 3) as f64)
+}
+
+fn run(vm: &mut VM, code: Vec<Op>) -> Vec<Column> {
+    vm.run(code).unwrap();
+    let stack = vm.take_stack();
+    stack.iter().map(|v| vm.column_of(v).unwrap().clone()).collect()
+}
+
+fn answers(vm: &mut VM, thread: usize) -> Vec<u64> {
+    ages(thread).flat_map(queries).map(|code| run(vm, code)[0].fingerprint()).collect()
+}
+
+fn assert_send<T: Send>() {}
+
+#[test]
+fn threads_query_shared_columns() {
+    assert_send::<VM>();
+    let mut base = loaded();
+    let expected: Vec<Vec<u64>> = (0 .. THREADS).map(|t| answers(&mut base, t)).collect();
+    let columns = base.shared_columns();
+    let refs = Arc::strong_count(&columns[0]);
+    let handles: Vec<_> = (0 .. THREADS).map(|t| {
+        let columns = columns.clone();
+        thread::spawn(move || {
+            let mut vm = VM::with_shared_columns(columns, ColumnMode::Rc).unwrap();
+            answers(&mut vm, t)
+        })
+    }).collect();
+    for (t, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), expected[t], "thread {}", t);
+    }
+    // nothing was copied, and the threads' VMs let go of the columns
+    assert_eq!(Arc::strong_count(&columns[0]), refs);
+    assert!(base.shared_columns().iter().zip(&columns).all(|(col, shared)| Arc::ptr_eq(col, shared)));
+}
+
+#[test]
+fn shared_vms_run_in_slots_mode() {
+    let mut base = loaded();
+    let expected = run(&mut base, queries(60.0).pop().unwrap());
+    let mut vm = VM::with_shared_columns(base.shared_columns(), ColumnMode::Slots).unwrap();
+    let res = run(&mut vm, queries(60.0).pop().unwrap());
+    assert_columns_eq!(res[0], expected[0]);
+}
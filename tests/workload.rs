@@ -0,0 +1,60 @@
+// Each workload query (see src/workload.rs) against its known answer, over a few sizes and
+// seeds. The answers are checked to have rows first, so a query can't pass by returning
+// nothing for a workload that happens to have nothing to find.
+
+#![cfg(feature = "std")]
+
+use collie::workload::{self, Workload};
+
+const WORKLOADS: [(usize, u64); 3] = [(50, 1), (200, 42), (1000, 7)];
+
+fn check(name: &str) {
+    let q = workload::queries().into_iter().find(|q| q.name == name).expect("a workload query");
+    for &(customers, seed) in &WORKLOADS {
+        let w = Workload::generate(customers, seed);
+        let answer = q.answer(&w);
+        assert!(answer.rows() > 0, "{} has no rows for {} customers, seed {}", name, customers, seed);
+        let res = q.run(&w).unwrap_or_else(|e| panic!("{} fails for {} customers, seed {}: {:?}", name, customers, seed, e));
+        assert_eq!(res, answer, "{} for {} customers, seed {}", name, customers, seed);
+    }
+}
+
+#[test]
+fn customer_orders() {
+    check("customer_orders");
+}
+
+#[test]
+fn segment_names() {
+    check("segment_names");
+}
+
+#[test]
+fn shipped_quantities() {
+    check("shipped_quantities");
+}
+
+#[test]
+fn order_labels() {
+    check("order_labels");
+}
+
+#[test]
+fn largest_lineitems() {
+    check("largest_lineitems");
+}
+
+#[test]
+fn customer_revenue() {
+    check("customer_revenue");
+}
+
+#[test]
+fn check_covers_every_query() {
+    let w = Workload::generate(100, 3);
+    let outcomes = workload::check(&w);
+    assert_eq!(outcomes.len(), workload::queries().len());
+    for outcome in outcomes {
+        assert_eq!(outcome.result, Ok(()), "{}", outcome.query);
+    }
+}